@@ -0,0 +1,156 @@
+//! End-to-end forwarding through the relay server on localhost:
+//! client egress -> server -> client ingress.
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::sync::{mpsc, Notify};
+
+use ya_relay_client::channels::{ForwardSender, Forwarded};
+use ya_relay_client::model::TransportType;
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+const UNRELIABLE_SIZES: [usize; 3] = [64, 512, 1024];
+const RELIABLE_SIZES: [usize; 4] = [64, 1024, 16384, 65536];
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Relay {
+    _server: ServerWrapper,
+    _clients: (Client, Client),
+    unreliable: ForwardSender,
+    reliable: ForwardSender,
+    echoes: mpsc::Receiver<Forwarded>,
+    received: Rc<Cell<usize>>,
+    notify: Rc<Notify>,
+}
+
+impl Relay {
+    async fn new() -> anyhow::Result<Relay> {
+        let server = init_test_server().await?;
+        let client1 = ClientBuilder::from_url(server.url())
+            .connect(FailFast::Yes)
+            .build()
+            .await?;
+        let client2 = ClientBuilder::from_url(server.url())
+            .connect(FailFast::Yes)
+            .build()
+            .await?;
+
+        // Force relaying through the server instead of p2p.
+        for client in [&client1, &client2] {
+            server.remove_node_endpoints(client.node_id()).await;
+            client.set_public_addr(None).await;
+        }
+
+        let echoes = client1.forward_receiver().await.unwrap();
+        let mut incoming = client2.forward_receiver().await.unwrap();
+        let mut echo = client2.forward_unreliable(client1.node_id()).await?;
+
+        let received = Rc::new(Cell::new(0));
+        let notify = Rc::new(Notify::new());
+
+        // Unreliable packets are sent back for measuring round trip time,
+        // reliable ones are only counted.
+        tokio::task::spawn_local({
+            let received = received.clone();
+            let notify = notify.clone();
+            async move {
+                while let Some(forwarded) = incoming.recv().await {
+                    match forwarded.transport {
+                        TransportType::Unreliable => {
+                            let _ = echo.send(forwarded.payload).await;
+                        }
+                        _ => {
+                            received.set(received.get() + forwarded.payload.len());
+                            notify.notify_one();
+                        }
+                    }
+                }
+            }
+        });
+
+        let unreliable = client1.forward_unreliable(client2.node_id()).await?;
+        let reliable = client1.forward_reliable(client2.node_id()).await?;
+
+        Ok(Relay {
+            _server: server,
+            _clients: (client1, client2),
+            unreliable,
+            reliable,
+            echoes,
+            received,
+            notify,
+        })
+    }
+
+    async fn round_trip(&mut self, payload: &[u8]) -> Duration {
+        let started = Instant::now();
+        self.unreliable.send(payload.to_vec().into()).await.unwrap();
+        tokio::time::timeout(TIMEOUT, self.echoes.recv())
+            .await
+            .expect("echo lost")
+            .unwrap();
+        started.elapsed()
+    }
+
+    async fn transfer(&mut self, payload: &[u8], count: u64) -> Duration {
+        let expected = self.received.get() + payload.len() * count as usize;
+        let started = Instant::now();
+
+        for _ in 0..count {
+            self.reliable.send(payload.to_vec().into()).await.unwrap();
+        }
+        tokio::time::timeout(TIMEOUT, async {
+            while self.received.get() < expected {
+                self.notify.notified().await;
+            }
+        })
+        .await
+        .expect("transfer incomplete");
+
+        started.elapsed()
+    }
+}
+
+fn bench_forwarding(c: &mut Criterion) {
+    let runtime = actix_rt::System::new();
+    let mut relay = runtime.block_on(Relay::new()).unwrap();
+
+    let mut group = c.benchmark_group("forward_unreliable_rtt");
+    for size in UNRELIABLE_SIZES {
+        let payload = vec![0xaa; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        total += relay.round_trip(&payload).await;
+                    }
+                    total
+                })
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("forward_reliable_throughput");
+    for size in RELIABLE_SIZES {
+        let payload = vec![0xaa; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter_custom(|iters| runtime.block_on(relay.transfer(&payload, iters)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = bench_forwarding
+}
+criterion_main!(benches);
@@ -0,0 +1,147 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::Rng;
+
+use ya_relay_client::testing::private::to_ipv6;
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+use ya_relay_server::testing::{issued_session_id, Clock};
+use ya_relay_server::SessionManager;
+
+const SESSION_COUNTS: [usize; 3] = [1_000, 10_000, 100_000];
+const THREAD_COUNTS: [usize; 3] = [1, 4, 8];
+
+fn random_node_id() -> NodeId {
+    NodeId::from(rand::thread_rng().gen::<[u8; 20]>())
+}
+
+fn bench_to_ipv6(c: &mut Criterion) {
+    let node_id = random_node_id();
+    c.bench_function("to_ipv6", |b| {
+        b.iter(|| to_ipv6(black_box(node_id.into_array())))
+    });
+}
+
+fn session_manager(count: usize) -> (std::sync::Arc<SessionManager>, Vec<(SessionId, NodeId)>) {
+    let manager = SessionManager::new();
+    let clock = Clock::now();
+
+    // Ids issued in the same second share their leading bytes, like on a busy relay.
+    let node_ids = (0..count)
+        .map(|i| {
+            let node_id = random_node_id();
+            let peer = SocketAddr::from((Ipv4Addr::from(i as u32), 40));
+            let session_id = issued_session_id(peer);
+            let session = manager
+                .new_session(&clock, session_id, peer, node_id, vec![], vec![], None)
+                .map_err(|_| "duplicated session id")
+                .unwrap();
+            manager.link_session(node_id, &session);
+            (session_id, node_id)
+        })
+        .collect();
+    (manager, node_ids)
+}
+
+fn bench_node_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("node_lookup");
+
+    for count in SESSION_COUNTS {
+        let (manager, sessions) = session_manager(count);
+        let node_ids = sessions
+            .into_iter()
+            .map(|(_, node_id)| node_id)
+            .collect::<Vec<_>>();
+        let mut idx = 0;
+
+        group.bench_function(BenchmarkId::new("node_session", count), |b| {
+            b.iter(|| {
+                idx = (idx + 1) % node_ids.len();
+                manager.node_session(node_ids[idx]).unwrap()
+            })
+        });
+        group.bench_function(BenchmarkId::new("neighbours", count), |b| {
+            b.iter(|| {
+                idx = (idx + 1) % node_ids.len();
+                manager.neighbours(node_ids[idx], 8)
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Runs `per_thread(iters)` calls of `f(thread, i)` on each of `threads` threads.
+/// Returns the time of the slowest thread.
+fn run_threads(threads: usize, iters: u64, f: impl Fn(usize, usize) + Sync) -> Duration {
+    let per_thread = (iters as usize).div_ceil(threads);
+    std::thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|t| {
+                let f = &f;
+                scope.spawn(move || {
+                    let start = Instant::now();
+                    for i in 0..per_thread {
+                        f(t, t * per_thread + i);
+                    }
+                    start.elapsed()
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .max()
+            .unwrap_or(Duration::ZERO)
+    })
+}
+
+/// Forwarding looks up the sender's session and the receiver's Node session for every
+/// packet. Measures the time of `iters` such lookups spread over many threads.
+fn bench_concurrent_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_lookup");
+
+    for count in [1_000, 10_000] {
+        let (manager, sessions) = session_manager(count);
+
+        for threads in THREAD_COUNTS {
+            group.bench_function(
+                BenchmarkId::new(format!("forward_{threads}_threads"), count),
+                |b| {
+                    b.iter_custom(|iters| {
+                        run_threads(threads, iters, |t, i| {
+                            let (session_id, _) = sessions[i % sessions.len()];
+                            let (_, node_id) = sessions[(t + i * 7919) % sessions.len()];
+                            black_box(manager.session(&session_id).unwrap());
+                            black_box(manager.node_session(node_id).unwrap());
+                        })
+                    })
+                },
+            );
+            // Many senders forwarding to one popular Node.
+            group.bench_function(
+                BenchmarkId::new(format!("same_node_{threads}_threads"), count),
+                |b| {
+                    let (_, node_id) = sessions[0];
+                    b.iter_custom(|iters| {
+                        run_threads(threads, iters, |_, i| {
+                            let (session_id, _) = sessions[i % sessions.len()];
+                            black_box(manager.session(&session_id).unwrap());
+                            black_box(manager.node_session(node_id).unwrap());
+                        })
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_to_ipv6,
+    bench_node_lookup,
+    bench_concurrent_lookup
+);
+criterion_main!(benches);
@@ -0,0 +1,120 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use tokio_util::codec::{Decoder, Encoder};
+
+use ya_relay_proto::codec::datagram::Codec;
+use ya_relay_proto::codec::PacketKind;
+use ya_relay_proto::proto::*;
+
+const PAYLOAD_SIZES: [usize; 4] = [64, 512, 1200, 8192];
+const SESSION_ID: [u8; SESSION_ID_SIZE] = [0x0f; SESSION_ID_SIZE];
+
+fn encode(packet: PacketKind) -> BytesMut {
+    let mut buf = BytesMut::new();
+    Codec.encode(packet, &mut buf).unwrap();
+    buf
+}
+
+fn session_request() -> PacketKind {
+    Packet::request(
+        Vec::new(),
+        request::Session {
+            challenge_resp: Some(ChallengeResponse {
+                solution: vec![0x0d; 72],
+                signatures: vec![vec![0x0a; 65]; 2],
+                schemes: vec![],
+            }),
+            identities: vec![Identity {
+                node_id: vec![0x0c; 20],
+                public_key: vec![0x05; 64],
+            }],
+            ..Default::default()
+        },
+    )
+    .into()
+}
+
+fn neighbours_response() -> PacketKind {
+    let node = response::Node {
+        identities: vec![Identity {
+            node_id: vec![0x0c; 20],
+            public_key: vec![0x05; 64],
+        }],
+        endpoints: vec![Endpoint {
+            protocol: Protocol::Udp as i32,
+            address: "1.2.3.4".to_string(),
+            port: 7464,
+        }],
+        seen_ts: 1,
+        slot: 1,
+        supported_encryptions: vec![],
+        properties: None,
+        slot_epoch: 1,
+        hints: Some(response::node::Hints {
+            nat_type: 2,
+            relay_sessions: 1000,
+        }),
+    };
+    Packet::response(
+        1,
+        SESSION_ID.to_vec(),
+        StatusCode::Ok,
+        response::Neighbours {
+            nodes: vec![node; 8],
+        },
+    )
+    .into()
+}
+
+fn forward(size: usize) -> PacketKind {
+    PacketKind::Forward(Forward::new(SESSION_ID, 42, vec![0xaa; size]))
+}
+
+fn bench_packets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet");
+
+    for (name, packet) in [
+        ("session_request", session_request()),
+        ("neighbours_response", neighbours_response()),
+    ] {
+        let encoded = encode(packet.clone());
+        group.throughput(Throughput::Bytes(encoded.len() as u64));
+
+        group.bench_function(BenchmarkId::new("encode", name), |b| {
+            b.iter_batched(|| packet.clone(), encode, BatchSize::SmallInput)
+        });
+        group.bench_function(BenchmarkId::new("decode", name), |b| {
+            b.iter_batched(
+                || encoded.clone(),
+                |mut buf| Codec.decode(&mut buf).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_forward(c: &mut Criterion) {
+    let mut group = c.benchmark_group("forward");
+
+    for size in PAYLOAD_SIZES {
+        let packet = forward(size);
+        let encoded = encode(packet.clone());
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode", size), &packet, |b, packet| {
+            b.iter_batched(|| packet.clone(), encode, BatchSize::SmallInput)
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &encoded, |b, encoded| {
+            b.iter_batched(
+                || encoded.clone(),
+                |mut buf| Codec.decode(&mut buf).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_packets, bench_forward);
+criterion_main!(benches);
@@ -2,8 +2,7 @@ use std::time::Duration;
 use structopt::{clap, StructOpt};
 
 use ya_relay_client::{ClientBuilder, FailFast};
-use ya_relay_core::crypto::FallbackCryptoProvider;
-use ya_relay_core::key::{load_or_generate, Protected};
+use ya_relay_core::key::Protected;
 use ya_relay_core::NodeId;
 
 #[derive(StructOpt)]
@@ -55,9 +54,8 @@ async fn run() -> anyhow::Result<()> {
 
     let address = args.address.clone();
     let builder = if let Some(key_file) = args.key_file {
-        let password = args.key_password.clone();
-        let secret = load_or_generate(&key_file, password);
-        ClientBuilder::from_url(address).crypto(FallbackCryptoProvider::new(secret))
+        let password = args.key_password.clone().unwrap_or_else(|| "".into());
+        ClientBuilder::from_url(address).secret_from_keystore(key_file, password)?
     } else {
         ClientBuilder::from_url(address)
     };
@@ -19,10 +19,7 @@ use structopt::StructOpt;
 use tokio::sync::oneshot;
 use ya_relay_client::{channels::ForwardSender, Client, ClientBuilder, FailFast, GenericSender};
 use ya_relay_core::{
-    crypto::FallbackCryptoProvider,
-    key::{load_or_generate, Protected},
-    server_session::TransportType,
-    NodeId,
+    crypto::FallbackCryptoProvider, key::Protected, server_session::TransportType, NodeId,
 };
 
 use crate::response::{Info, Pong, Transfer};
@@ -462,11 +459,11 @@ async fn forward(
     transport: TransportType,
     node_id: NodeId,
 ) -> Result<ForwardSender> {
-    match transport {
-        TransportType::Unreliable => client.forward_unreliable(node_id).await,
-        TransportType::Reliable => client.forward_reliable(node_id).await,
-        TransportType::Transfer => client.forward_transfer(node_id).await,
-    }
+    Ok(match transport {
+        TransportType::Unreliable => client.forward_unreliable(node_id).await?,
+        TransportType::Reliable => client.forward_reliable(node_id).await?,
+        TransportType::Transfer => client.forward_transfer(node_id).await?,
+    })
 }
 
 async fn run() -> Result<()> {
@@ -546,15 +543,15 @@ async fn build_client(
     session_expiration: Duration,
     session_request_timeout: Duration,
 ) -> Result<Client> {
-    let secret = key_file.map(|key_file| load_or_generate(key_file, password));
-    let provider = if let Some(secret_key) = secret {
-        FallbackCryptoProvider::new(secret_key)
-    } else {
-        FallbackCryptoProvider::default()
+    let builder = ClientBuilder::from_url(relay_addr);
+    let builder = match key_file {
+        Some(key_file) => {
+            let password = password.unwrap_or_else(|| "".into());
+            builder.secret_from_keystore(key_file, password)?
+        }
+        None => builder.crypto(FallbackCryptoProvider::default()),
     };
-
-    let mut builder = ClientBuilder::from_url(relay_addr)
-        .crypto(provider)
+    let mut builder = builder
         .expire_session_after(session_expiration)
         .session_request_timeout(session_request_timeout);
 
@@ -7,9 +7,8 @@ use futures::prelude::*;
 use rand::seq::SliceRandom;
 use structopt::{clap, StructOpt};
 
-use ya_relay_client::{Client, ClientBuilder};
-use ya_relay_core::crypto::FallbackCryptoProvider;
-use ya_relay_core::key::{load_or_generate, Protected};
+use ya_relay_client::{Client, ClientBuilder, ClientError};
+use ya_relay_core::key::Protected;
 
 #[derive(StructOpt)]
 #[structopt(global_setting = clap::AppSettings::ColoredHelp)]
@@ -115,9 +114,8 @@ mod util {
     pub async fn establish_connection(args: &Options) -> anyhow::Result<Client> {
         let address = args.address.clone();
         let builder = if let Some(key_file) = &args.key_file {
-            let password = args.key_password.clone();
-            let secret = load_or_generate(key_file, password);
-            ClientBuilder::from_url(address).crypto(FallbackCryptoProvider::new(secret))
+            let password = args.key_password.clone().unwrap_or_else(|| "".into());
+            ClientBuilder::from_url(address).secret_from_keystore(key_file, password)?
         } else {
             ClientBuilder::from_url(address)
         };
@@ -396,7 +394,7 @@ mod load_test {
                         let resp = client.find_node(client.node_id()).await;
                         if let Err(e) = resp {
                             dropped += 1;
-                            if !e.to_string().contains("timed out") {
+                            if !matches!(e, ClientError::Timeout(_)) {
                                 connection_valid = false;
                             }
                         } else {
@@ -584,7 +582,7 @@ mod relaying {
     use std::sync::atomic::Ordering::SeqCst;
     use std::sync::atomic::{AtomicBool, AtomicUsize};
     use std::sync::Arc;
-    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tokio_stream::wrappers::ReceiverStream;
     use ya_relay_client::model::Payload;
     use ya_relay_core::NodeId;
 
@@ -970,7 +968,7 @@ mod relaying {
 
         let received_ = received.clone();
         tokio::task::spawn_local(async move {
-            UnboundedReceiverStream::new(rx)
+            ReceiverStream::new(rx)
                 .for_each(|item| {
                     let received = received_.clone();
                     let finish_tx = finish_tx.clone();
@@ -16,13 +16,12 @@ use structopt::{clap, StructOpt};
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::RwLock;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::ReceiverStream;
 
 use ya_relay_client::channels::*;
 use ya_relay_client::*;
 
-use ya_relay_core::crypto::FallbackCryptoProvider;
-use ya_relay_core::key::{load_or_generate, Protected};
+use ya_relay_core::key::Protected;
 use ya_relay_core::server_session::TransportType;
 use ya_relay_core::NodeId;
 
@@ -151,7 +150,7 @@ impl State {
 }
 
 fn receive(receiver: ForwardReceiver, state: State) -> impl Future<Output = ()> + 'static {
-    UnboundedReceiverStream::new(receiver).for_each(move |fwd| {
+    ReceiverStream::new(receiver).for_each(move |fwd| {
         let state = state.clone();
         async move {
             let mut inner = state.inner.write().await;
@@ -359,10 +358,9 @@ async fn run() -> anyhow::Result<()> {
 
     let cli: Cli = Cli::from_args();
     let mut builder = if let Some(ref key_file) = cli.key_file {
-        let password = cli.key_password.clone();
-        let secret = load_or_generate(key_file, password);
+        let password = cli.key_password.clone().unwrap_or_else(|| "".into());
         ClientBuilder::from_url(cli.relay)
-            .crypto(FallbackCryptoProvider::new(secret))
+            .secret_from_keystore(key_file, password)?
             .listen(cli.listen)
     } else {
         ClientBuilder::from_url(cli.relay).listen(cli.listen)
@@ -0,0 +1,323 @@
+//! Estimating bandwidth of the path to another Node with packet trains.
+//!
+//! [`crate::Client::estimate_bandwidth`] sends a train of probes back-to-back over the
+//! session currently used with the Node, relayed or P2P. The receiving client measures
+//! the time between the arrival of the first and the last probe and reports it back.
+//! The bottleneck link spreads the train out, so bytes received after the first probe
+//! divided by that time approximate the bandwidth available on the path.
+//!
+//! Probes are unreliable forwards and never reach [`crate::Client::forward_receiver`].
+//! Estimates are rough, since the relay server and other traffic on the path
+//! affect the spacing of probes.
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use ya_relay_core::runtime::SystemTime;
+use ya_relay_core::NodeId;
+
+use crate::client::Forwarded;
+use crate::raw_session::SessionType;
+
+const MAGIC: &[u8; 4] = b"yaBW";
+const KIND_PROBE: u8 = 0;
+const KIND_REPORT: u8 = 1;
+const PROBE_HEADER_SIZE: usize = MAGIC.len() + 1 + 4 + 2 + 2;
+const REPORT_SIZE: usize = MAGIC.len() + 1 + 4 + 2 + 8 + 8;
+
+/// Probes in a train.
+pub const TRAIN_LENGTH: u16 = 16;
+/// Size of each probe, fitting in a single packet.
+pub const PROBE_SIZE: usize = 1024;
+/// Time to wait for the report after sending the train.
+pub const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Trains received from other Nodes are forgotten after that, if their last probe was lost.
+const TRAIN_TTL: Duration = Duration::from_secs(10);
+const MAX_TRAINS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BandwidthEstimate {
+    pub bytes_per_second: f64,
+    /// Path the train was sent over.
+    pub path: SessionType,
+    pub probes_sent: u16,
+    pub probes_received: u16,
+    /// Time between the arrival of the first and the last probe.
+    pub dispersion: Duration,
+}
+
+impl BandwidthEstimate {
+    pub fn bits_per_second(&self) -> f64 {
+        self.bytes_per_second * 8.0
+    }
+
+    /// Fraction of probes lost on the way.
+    pub fn loss(&self) -> f64 {
+        1.0 - self.probes_received as f64 / self.probes_sent.max(1) as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Report {
+    pub train: u32,
+    pub received: u16,
+    /// Bytes of probes received after the first one.
+    pub bytes: u64,
+    pub dispersion: Duration,
+}
+
+struct Train {
+    first: SystemTime,
+    last: SystemTime,
+    received: u16,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u32,
+    pending: HashMap<u32, oneshot::Sender<Report>>,
+    trains: HashMap<(NodeId, u32), Train>,
+}
+
+/// Outcome of dispatching a forward.
+pub(crate) enum Dispatched {
+    /// Not a probe.
+    Other(Forwarded),
+    /// Last probe of a train arrived, report has to be sent back to the Node.
+    Reply(NodeId, Vec<u8>),
+    Done,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Bandwidth {
+    state: Arc<Mutex<State>>,
+}
+
+impl Bandwidth {
+    /// Registers a new train. Its report is delivered to the returned receiver.
+    pub fn start(&self) -> (u32, oneshot::Receiver<Report>) {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.state.lock();
+        state.pending.retain(|_, tx| !tx.is_closed());
+        let id = state.next_id;
+        state.next_id = state.next_id.wrapping_add(1);
+        state.pending.insert(id, tx);
+        (id, rx)
+    }
+
+    pub fn dispatch(&self, forwarded: Forwarded) -> Dispatched {
+        let payload = forwarded.payload.as_ref();
+        if payload.len() <= MAGIC.len() || !payload.starts_with(MAGIC) {
+            return Dispatched::Other(forwarded);
+        }
+        match payload[MAGIC.len()] {
+            KIND_PROBE => match decode_probe(payload) {
+                Some((train, seq, count)) => {
+                    self.on_probe(&forwarded, train, seq, count, payload.len())
+                }
+                None => Dispatched::Done,
+            },
+            KIND_REPORT => {
+                if let Some(report) = decode_report(payload) {
+                    if let Some(tx) = self.state.lock().pending.remove(&report.train) {
+                        tx.send(report).ok();
+                    }
+                }
+                Dispatched::Done
+            }
+            _ => Dispatched::Done,
+        }
+    }
+
+    fn on_probe(
+        &self,
+        forwarded: &Forwarded,
+        train: u32,
+        seq: u16,
+        count: u16,
+        size: usize,
+    ) -> Dispatched {
+        let now = forwarded.received_at;
+        let key = (forwarded.node_id, train);
+        let mut state = self.state.lock();
+        if !state.trains.contains_key(&key) && state.trains.len() >= MAX_TRAINS {
+            state.trains.retain(|_, train| {
+                now.duration_since(train.last)
+                    .map_or(true, |elapsed| elapsed < TRAIN_TTL)
+            });
+            if state.trains.len() >= MAX_TRAINS {
+                return Dispatched::Done;
+            }
+        }
+
+        let entry = state.trains.entry(key).or_insert(Train {
+            first: now,
+            last: now,
+            received: 0,
+            bytes: 0,
+        });
+        if entry.received > 0 {
+            entry.bytes += size as u64;
+        }
+        entry.received = entry.received.saturating_add(1);
+        entry.last = now;
+
+        if seq.saturating_add(1) < count {
+            return Dispatched::Done;
+        }
+        let train = match state.trains.remove(&key) {
+            Some(train) => train,
+            None => return Dispatched::Done,
+        };
+        let report = Report {
+            train: key.1,
+            received: train.received,
+            bytes: train.bytes,
+            dispersion: train.last.duration_since(train.first).unwrap_or_default(),
+        };
+        Dispatched::Reply(forwarded.node_id, encode_report(&report))
+    }
+}
+
+pub(crate) fn encode_probe(train: u32, seq: u16, count: u16, size: usize) -> Vec<u8> {
+    let mut probe = Vec::with_capacity(size.max(PROBE_HEADER_SIZE));
+    probe.extend_from_slice(MAGIC);
+    probe.push(KIND_PROBE);
+    probe.extend_from_slice(&train.to_be_bytes());
+    probe.extend_from_slice(&seq.to_be_bytes());
+    probe.extend_from_slice(&count.to_be_bytes());
+    probe.resize(size.max(PROBE_HEADER_SIZE), 0);
+    probe
+}
+
+fn decode_probe(bytes: &[u8]) -> Option<(u32, u16, u16)> {
+    if bytes.len() < PROBE_HEADER_SIZE {
+        return None;
+    }
+    let at = MAGIC.len() + 1;
+    Some((
+        u32::from_be_bytes(bytes[at..at + 4].try_into().ok()?),
+        u16::from_be_bytes(bytes[at + 4..at + 6].try_into().ok()?),
+        u16::from_be_bytes(bytes[at + 6..at + 8].try_into().ok()?),
+    ))
+}
+
+fn encode_report(report: &Report) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(REPORT_SIZE);
+    packet.extend_from_slice(MAGIC);
+    packet.push(KIND_REPORT);
+    packet.extend_from_slice(&report.train.to_be_bytes());
+    packet.extend_from_slice(&report.received.to_be_bytes());
+    packet.extend_from_slice(&report.bytes.to_be_bytes());
+    packet.extend_from_slice(&(report.dispersion.as_micros() as u64).to_be_bytes());
+    packet
+}
+
+fn decode_report(bytes: &[u8]) -> Option<Report> {
+    if bytes.len() < REPORT_SIZE {
+        return None;
+    }
+    let at = MAGIC.len() + 1;
+    Some(Report {
+        train: u32::from_be_bytes(bytes[at..at + 4].try_into().ok()?),
+        received: u16::from_be_bytes(bytes[at + 4..at + 6].try_into().ok()?),
+        bytes: u64::from_be_bytes(bytes[at + 6..at + 14].try_into().ok()?),
+        dispersion: Duration::from_micros(u64::from_be_bytes(
+            bytes[at + 14..at + 22].try_into().ok()?,
+        )),
+    })
+}
+
+/// Returns `None`, if too few probes arrived to measure their spacing.
+pub(crate) fn estimate(report: &Report, sent: u16, path: SessionType) -> Option<BandwidthEstimate> {
+    if report.received < 2 {
+        return None;
+    }
+    // Probes handled in the same batch arrive at once.
+    let dispersion = report.dispersion.max(Duration::from_micros(1));
+    Some(BandwidthEstimate {
+        bytes_per_second: report.bytes as f64 / dispersion.as_secs_f64(),
+        path,
+        probes_sent: sent,
+        probes_received: report.received,
+        dispersion: report.dispersion,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_core::server_session::TransportType;
+
+    fn forwarded(payload: Vec<u8>, received_at: SystemTime) -> Forwarded {
+        Forwarded {
+            transport: TransportType::Unreliable,
+            node_id: [1; 20].into(),
+            payload: payload.into(),
+            session: None,
+            received_at,
+        }
+    }
+
+    #[test]
+    fn test_train() {
+        let receiver = Bandwidth::default();
+        let sender = Bandwidth::default();
+        let (train, mut rx) = sender.start();
+        let start = SystemTime::now();
+
+        let mut reply = None;
+        // Probe 2 was lost.
+        for seq in [0, 1, 3] {
+            let probe = encode_probe(train, seq, 4, PROBE_SIZE);
+            let at = start + Duration::from_millis(seq as u64 * 10);
+            match receiver.dispatch(forwarded(probe, at)) {
+                Dispatched::Reply(node_id, report) => reply = Some((node_id, report)),
+                Dispatched::Done => (),
+                Dispatched::Other(_) => panic!("probe not recognized"),
+            }
+        }
+
+        let (node_id, report) = reply.unwrap();
+        assert_eq!(node_id, [1; 20].into());
+        assert!(matches!(
+            sender.dispatch(forwarded(report, start)),
+            Dispatched::Done
+        ));
+        let report = rx.try_recv().unwrap();
+        assert_eq!(
+            report,
+            Report {
+                train,
+                received: 3,
+                bytes: 2 * PROBE_SIZE as u64,
+                dispersion: Duration::from_millis(30),
+            }
+        );
+
+        let estimate = estimate(&report, 4, SessionType::Relay).unwrap();
+        assert_eq!(estimate.bytes_per_second, 2048.0 / 0.03);
+        assert_eq!(estimate.loss(), 0.25);
+    }
+
+    #[test]
+    fn test_not_a_probe() {
+        let bandwidth = Bandwidth::default();
+        assert!(matches!(
+            bandwidth.dispatch(forwarded(b"data".to_vec(), SystemTime::now())),
+            Dispatched::Other(_)
+        ));
+
+        let report = Report {
+            train: 1,
+            received: 1,
+            bytes: 0,
+            dispersion: Duration::ZERO,
+        };
+        assert_eq!(estimate(&report, TRAIN_LENGTH, SessionType::P2P), None);
+    }
+}
@@ -0,0 +1,207 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::NodeId;
+
+/// Client for validating a deployed relay server.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Relay server address
+    #[arg(
+        short,
+        long,
+        env = "NET_ADDRESS",
+        default_value = "udp://127.0.0.1:7477"
+    )]
+    address: url::Url,
+    /// Keystore file with the Node identity; random identity is used if not set
+    #[arg(short = 'f', long, env = "CLIENT_KEY_FILE")]
+    key_file: Option<PathBuf>,
+    #[arg(short = 'p', long, env = "CLIENT_KEY_PASSWORD", default_value = "")]
+    key_password: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists Nodes in the neighbourhood of this client
+    ListNeighbours {
+        #[arg(short, long, default_value_t = 5)]
+        count: u32,
+    },
+    /// Measures round trip time to a Node
+    Ping {
+        node: NodeId,
+        #[arg(short, long, default_value_t = 4)]
+        count: u32,
+        #[arg(short, long, default_value = "1s", value_parser = humantime::parse_duration)]
+        interval: Duration,
+    },
+    /// Sends file contents to a Node using reliable transport
+    Send {
+        node: NodeId,
+        file: PathBuf,
+        #[arg(long, default_value_t = 16384)]
+        chunk_size: usize,
+        /// Time to keep the client alive after queueing the last chunk,
+        /// so the transfer can complete
+        #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+        linger: Duration,
+    },
+    /// Prints type of the NAT in front of this client, as seen by the relay server
+    Nat,
+    /// Checks NAT and prints the client diagnostics report as JSON
+    Diagnostics,
+    /// Prints packets forwarded to this client
+    Listen {
+        /// Appends received payloads to `<dir>/<node_id>`
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+    },
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    let mut builder = ClientBuilder::from_url(cli.address.clone());
+    if let Some(key_file) = &cli.key_file {
+        builder = builder.secret_from_keystore(key_file, cli.key_password.clone())?;
+    }
+    let mut client = builder.connect(FailFast::Yes).build().await?;
+
+    log::info!(
+        "Connected to relay server {} as [{}]",
+        cli.address,
+        client.node_id()
+    );
+
+    let result = match cli.command {
+        Command::ListNeighbours { count } => list_neighbours(&client, count).await,
+        Command::Ping {
+            node,
+            count,
+            interval,
+        } => ping(&client, node, count, interval).await,
+        Command::Send {
+            node,
+            file,
+            chunk_size,
+            linger,
+        } => send(&client, node, file, chunk_size, linger).await,
+        Command::Nat => nat(&client).await,
+        Command::Diagnostics => diagnostics(&client).await,
+        Command::Listen { output_dir } => listen(&client, output_dir).await,
+    };
+
+    client.shutdown().await?;
+    result
+}
+
+async fn list_neighbours(client: &Client, count: u32) -> anyhow::Result<()> {
+    for node_id in client.neighbours(count).await? {
+        println!("{node_id}");
+    }
+    Ok(())
+}
+
+async fn ping(client: &Client, node: NodeId, count: u32, interval: Duration) -> anyhow::Result<()> {
+    for seq in 0..count {
+        if seq > 0 {
+            tokio::time::sleep(interval).await;
+        }
+
+        match client.ping(node).await {
+            Ok(rtt) => {
+                let route = match client.is_p2p(node).await {
+                    true => "p2p",
+                    false => "relay",
+                };
+                println!("[{node}] seq={seq} route={route} time={rtt:?}");
+            }
+            Err(e) => println!("[{node}] seq={seq} error: {e}"),
+        }
+    }
+    Ok(())
+}
+
+async fn nat(client: &Client) -> anyhow::Result<()> {
+    let info = client.check_nat().await?;
+    println!("type={:?}", info.nat_type);
+    println!("external_addr={}", info.external_addr);
+    println!("hairpinning={}", info.hairpinning);
+    Ok(())
+}
+
+async fn diagnostics(client: &Client) -> anyhow::Result<()> {
+    if let Err(e) = client.check_nat().await {
+        log::warn!("NAT check failed: {e}");
+    }
+    println!("{}", client.diagnostics().await.to_json()?);
+    Ok(())
+}
+
+async fn send(
+    client: &Client,
+    node: NodeId,
+    file: PathBuf,
+    chunk_size: usize,
+    linger: Duration,
+) -> anyhow::Result<()> {
+    let data = std::fs::read(&file)?;
+    let mut sender = client.forward_reliable(node).await?;
+
+    for chunk in data.chunks(chunk_size.max(1)) {
+        sender.send(chunk.to_vec().into()).await?;
+    }
+    println!(
+        "Queued {} bytes from {} to [{node}]",
+        data.len(),
+        file.display()
+    );
+
+    // Queued data is sent in the background by the virtual TCP stack.
+    tokio::time::sleep(linger).await;
+    Ok(())
+}
+
+async fn listen(client: &Client, output_dir: Option<PathBuf>) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let mut receiver = client
+        .forward_receiver()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Forward receiver already taken"))?;
+
+    println!("Listening as [{}]", client.node_id());
+
+    while let Some(forwarded) = receiver.recv().await {
+        println!(
+            "[{}] {} {} bytes",
+            forwarded.node_id,
+            forwarded.transport,
+            forwarded.payload.len()
+        );
+
+        if let Some(dir) = &output_dir {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(forwarded.node_id.to_string()))?
+                .write_all(forwarded.payload.as_ref())?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let local_set = tokio::task::LocalSet::new();
+    local_set.block_on(&runtime, run(cli))
+}
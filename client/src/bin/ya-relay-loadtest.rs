@@ -0,0 +1,457 @@
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, ValueEnum};
+use futures::StreamExt;
+use rand::Rng;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use ya_relay_client::channels::ForwardSender;
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+
+/// Send timestamp in microseconds since the test start, prepended to every payload.
+const HEADER_SIZE: usize = 8;
+
+/// Server counters reported next to client side results.
+const SERVER_METRICS: &[&str] = &[
+    "ya_relay_packet_forward",
+    "ya_relay_packet_forward_done",
+    "ya_relay_packet_forward_error",
+    "ya_relay_packet_forward_incoming_size",
+    "ya_relay_packet_forward_outgoing_size",
+    "ya_relay_packet_dropped",
+    "ya_relay_session_created",
+    "ya_relay_session_establish_error",
+];
+
+/// Establishes many sessions with a relay server and measures forwarding between them.
+///
+/// Every client sends to the next one, forming a ring.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Relay server address
+    #[arg(
+        short,
+        long,
+        env = "NET_ADDRESS",
+        default_value = "udp://127.0.0.1:7477"
+    )]
+    address: url::Url,
+    /// Number of simulated clients
+    #[arg(short = 'n', long, default_value_t = 100)]
+    clients: usize,
+    /// Number of clients connecting at the same time
+    #[arg(long, default_value_t = 32)]
+    connect_concurrency: usize,
+    #[arg(short, long, default_value = "30s", value_parser = humantime::parse_duration)]
+    duration: Duration,
+    /// Time to wait for in-flight packets after sending stops
+    #[arg(long, default_value = "3s", value_parser = humantime::parse_duration)]
+    drain: Duration,
+    #[arg(short, long, value_enum, default_value_t = Transport::Unreliable)]
+    transport: Transport,
+    /// Traffic pattern of every client: `constant:<packets/s>` or `burst:<packets>@<interval>`
+    #[arg(short, long, default_value = "constant:10")]
+    pattern: Pattern,
+    /// Payload sizes: `fixed:<bytes>` or `pareto:<min bytes>:<shape>[:<max bytes>]`
+    #[arg(short, long, default_value = "fixed:512")]
+    size: PayloadSize,
+    /// Relay server metrics endpoint, e.g. `http://127.0.0.1:9000`
+    #[arg(long)]
+    metrics_url: Option<url::Url>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Transport {
+    Unreliable,
+    Reliable,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Pattern {
+    Constant { rate: f64 },
+    Burst { packets: u32, interval: Duration },
+}
+
+impl Pattern {
+    fn interval(&self) -> Duration {
+        match self {
+            Pattern::Constant { rate } => Duration::from_secs_f64(1.0 / rate),
+            Pattern::Burst { interval, .. } => *interval,
+        }
+    }
+
+    fn packets(&self) -> u32 {
+        match self {
+            Pattern::Constant { .. } => 1,
+            Pattern::Burst { packets, .. } => *packets,
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, args) = s.split_once(':').unwrap_or((s, ""));
+        match kind {
+            "constant" => {
+                let rate: f64 = args.parse().context("invalid rate")?;
+                if rate <= 0.0 {
+                    bail!("rate must be positive");
+                }
+                Ok(Pattern::Constant { rate })
+            }
+            "burst" => {
+                let (packets, interval) = args
+                    .split_once('@')
+                    .ok_or_else(|| anyhow!("expected burst:<packets>@<interval>"))?;
+                Ok(Pattern::Burst {
+                    packets: packets.parse().context("invalid burst size")?,
+                    interval: humantime::parse_duration(interval)?,
+                })
+            }
+            _ => bail!("unknown traffic pattern: {kind}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum PayloadSize {
+    Fixed(usize),
+    /// Heavy tailed sizes: many small packets and occasional large ones.
+    Pareto {
+        min: usize,
+        shape: f64,
+        max: usize,
+    },
+}
+
+impl PayloadSize {
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        let size = match *self {
+            PayloadSize::Fixed(size) => size,
+            PayloadSize::Pareto { min, shape, max } => {
+                let u: f64 = rng.gen_range(f64::EPSILON..1.0);
+                ((min as f64 / u.powf(1.0 / shape)) as usize).min(max)
+            }
+        };
+        size.max(HEADER_SIZE)
+    }
+}
+
+impl FromStr for PayloadSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        match parts.next() {
+            Some("fixed") => Ok(PayloadSize::Fixed(
+                parts.next().unwrap_or_default().parse()?,
+            )),
+            Some("pareto") => {
+                let min = parts.next().unwrap_or_default().parse()?;
+                let shape: f64 = parts.next().unwrap_or_default().parse()?;
+                let max = parts.next().map(str::parse).transpose()?.unwrap_or(65536);
+                if shape <= 0.0 {
+                    bail!("pareto shape must be positive");
+                }
+                Ok(PayloadSize::Pareto { min, shape, max })
+            }
+            _ => bail!("expected fixed:<bytes> or pareto:<min>:<shape>[:<max>]"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    sent: Cell<u64>,
+    sent_bytes: Cell<u64>,
+    send_errors: Cell<u64>,
+    received: Cell<u64>,
+    received_bytes: Cell<u64>,
+    latencies: RefCell<Vec<u64>>,
+}
+
+async fn connect(cli: &Cli) -> anyhow::Result<Vec<Client>> {
+    let started = Instant::now();
+    let failed = Cell::new(0);
+
+    let clients = futures::stream::iter(0..cli.clients)
+        .map(|_| {
+            ClientBuilder::from_url(cli.address.clone())
+                .connect(FailFast::Yes)
+                .build()
+        })
+        .buffer_unordered(cli.connect_concurrency.max(1))
+        .filter_map(|result| {
+            let client = result
+                .map_err(|e| {
+                    log::warn!("Failed to connect client: {e}");
+                    failed.set(failed.get() + 1);
+                })
+                .ok();
+            futures::future::ready(client)
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+    println!(
+        "Connected {} clients in {:.2?} ({} failed)",
+        clients.len(),
+        started.elapsed(),
+        failed.get()
+    );
+    if clients.len() < 2 {
+        bail!("At least 2 connected clients are needed");
+    }
+    Ok(clients)
+}
+
+/// Establishes sessions along the ring before the measurement starts.
+async fn open_senders(
+    clients: &[Client],
+    transport: Transport,
+    concurrency: usize,
+) -> anyhow::Result<Vec<ForwardSender>> {
+    let started = Instant::now();
+    let p2p = Cell::new(0);
+    let failed = Cell::new(0);
+
+    let senders = futures::stream::iter(0..clients.len())
+        .map(|idx| {
+            let client = &clients[idx];
+            let target = clients[(idx + 1) % clients.len()].node_id();
+            let p2p = &p2p;
+            async move {
+                let sender = match transport {
+                    Transport::Unreliable => client.forward_unreliable(target).await?,
+                    Transport::Reliable => client.forward_reliable(target).await?,
+                };
+                client.ping(target).await?;
+                if client.is_p2p(target).await {
+                    p2p.set(p2p.get() + 1);
+                }
+                anyhow::Ok(sender)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .filter_map(|result: anyhow::Result<_>| {
+            let sender = result
+                .map_err(|e| {
+                    log::warn!("Failed to establish session: {e}");
+                    failed.set(failed.get() + 1);
+                })
+                .ok();
+            futures::future::ready(sender)
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+    println!(
+        "Established {} sessions in {:.2?} ({} p2p, {} relayed, {} failed)",
+        senders.len(),
+        started.elapsed(),
+        p2p.get(),
+        senders.len() - p2p.get(),
+        failed.get()
+    );
+    if senders.is_empty() {
+        bail!("No sessions established");
+    }
+    Ok(senders)
+}
+
+fn spawn_send(
+    mut sender: ForwardSender,
+    pattern: Pattern,
+    size: PayloadSize,
+    start: Instant,
+    until: Instant,
+    stats: Rc<Stats>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::task::spawn_local(async move {
+        let mut rng = rand::thread_rng();
+        let mut interval = tokio::time::interval(pattern.interval());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        while Instant::now() < until {
+            interval.tick().await;
+            for _ in 0..pattern.packets() {
+                let mut payload = vec![0u8; size.sample(&mut rng)];
+                let ts = start.elapsed().as_micros() as u64;
+                payload[..HEADER_SIZE].copy_from_slice(&ts.to_be_bytes());
+                let len = payload.len() as u64;
+
+                match sender.send(payload.into()).await {
+                    Ok(_) => {
+                        stats.sent.set(stats.sent.get() + 1);
+                        stats.sent_bytes.set(stats.sent_bytes.get() + len);
+                    }
+                    Err(_) => stats.send_errors.set(stats.send_errors.get() + 1),
+                }
+            }
+        }
+    })
+}
+
+async fn spawn_receive(client: &Client, start: Instant, stats: Rc<Stats>) -> anyhow::Result<()> {
+    let mut receiver = client
+        .forward_receiver()
+        .await
+        .ok_or_else(|| anyhow!("Forward receiver already taken"))?;
+
+    tokio::task::spawn_local(async move {
+        while let Some(forwarded) = receiver.recv().await {
+            let payload = forwarded.payload.into_vec();
+            if payload.len() < HEADER_SIZE {
+                continue;
+            }
+            let mut ts = [0u8; HEADER_SIZE];
+            ts.copy_from_slice(&payload[..HEADER_SIZE]);
+            let sent = u64::from_be_bytes(ts);
+            let now = start.elapsed().as_micros() as u64;
+
+            stats.received.set(stats.received.get() + 1);
+            stats
+                .received_bytes
+                .set(stats.received_bytes.get() + payload.len() as u64);
+            stats.latencies.borrow_mut().push(now.saturating_sub(sent));
+        }
+    });
+    Ok(())
+}
+
+/// Reads counters and gauges exposed in Prometheus text format.
+async fn scrape_metrics(url: &url::Url) -> anyhow::Result<BTreeMap<String, f64>> {
+    let host = url.host_str().context("metrics url without host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let mut stream = tokio::net::TcpStream::connect((host, port)).await?;
+    let request = format!("GET {} HTTP/1.0\r\nHost: {host}\r\n\r\n", url.path());
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let (_, body) = response
+        .split_once("\r\n\r\n")
+        .context("invalid metrics response")?;
+
+    Ok(body
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (name, value) = line.rsplit_once(' ')?;
+            Some((name.to_string(), value.parse().ok()?))
+        })
+        .collect())
+}
+
+fn percentile(sorted: &[u64], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let idx = ((sorted.len() - 1) as f64 * p).round() as usize;
+    Duration::from_micros(sorted[idx])
+}
+
+fn report(stats: &Stats, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let sent = stats.sent.get();
+    let received = stats.received.get();
+    let loss = match sent {
+        0 => 0.0,
+        _ => 100.0 * sent.saturating_sub(received) as f64 / sent as f64,
+    };
+
+    let mut latencies = stats.latencies.borrow_mut();
+    latencies.sort_unstable();
+
+    println!();
+    println!(
+        "sent          {sent} packets, {} bytes",
+        stats.sent_bytes.get()
+    );
+    println!(
+        "received      {received} packets, {} bytes",
+        stats.received_bytes.get()
+    );
+    println!("send errors   {}", stats.send_errors.get());
+    println!("loss          {loss:.2}%");
+    println!(
+        "throughput    {:.1} packets/s, {:.3} MB/s",
+        received as f64 / secs,
+        stats.received_bytes.get() as f64 / secs / 1_000_000.0
+    );
+    println!(
+        "latency       p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.9),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 1.0)
+    );
+}
+
+fn report_server(before: &BTreeMap<String, f64>, after: &BTreeMap<String, f64>) {
+    println!();
+    println!("server metrics (change during the test):");
+    for name in SERVER_METRICS {
+        if let Some(value) = after.get(*name) {
+            let delta = value - before.get(*name).copied().unwrap_or_default();
+            println!("  {name:<40} {delta}");
+        }
+    }
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    let metrics_before = match &cli.metrics_url {
+        Some(url) => Some(scrape_metrics(url).await?),
+        None => None,
+    };
+
+    let mut clients = connect(&cli).await?;
+    let start = Instant::now();
+    let stats = Rc::new(Stats::default());
+
+    for client in &clients {
+        spawn_receive(client, start, stats.clone()).await?;
+    }
+    let senders = open_senders(&clients, cli.transport, cli.connect_concurrency).await?;
+
+    println!(
+        "Sending {:?} of {:?} payloads for {:?}",
+        cli.pattern, cli.size, cli.duration
+    );
+    let until = Instant::now() + cli.duration;
+    let tasks = senders
+        .into_iter()
+        .map(|sender| spawn_send(sender, cli.pattern, cli.size, start, until, stats.clone()))
+        .collect::<Vec<_>>();
+    futures::future::join_all(tasks).await;
+    tokio::time::sleep(cli.drain).await;
+
+    report(&stats, cli.duration);
+    if let (Some(url), Some(before)) = (&cli.metrics_url, metrics_before) {
+        report_server(&before, &scrape_metrics(url).await?);
+    }
+
+    for client in clients.iter_mut() {
+        client.shutdown().await.ok();
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let cli = Cli::parse();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let local_set = tokio::task::LocalSet::new();
+    local_set.block_on(&runtime, run(cli))
+}
@@ -0,0 +1,39 @@
+//! Aborting long-running client operations with the client's `CancellationToken`.
+use futures::future::{select, Either};
+use std::future::Future;
+
+use ya_relay_core::challenge::CancellationToken;
+
+use crate::error::Cancelled;
+
+/// Runs `future` until it completes or the `token` is cancelled, whichever comes first.
+pub(crate) async fn cancellable<F: Future>(
+    token: &CancellationToken,
+    future: F,
+) -> Result<F::Output, Cancelled> {
+    if token.is_cancelled() {
+        return Err(Cancelled);
+    }
+    let cancelled = Box::pin(token.cancelled());
+    match select(Box::pin(future), cancelled).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Cancelled),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancellable() {
+        let token = CancellationToken::new();
+        assert_eq!(cancellable(&token, async { 1 }).await, Ok(1));
+
+        let child = token.child_token();
+        let pending = cancellable(&child, futures::future::pending::<()>());
+        token.cancel();
+        assert_eq!(pending.await, Err(Cancelled));
+        assert_eq!(cancellable(&child, async { 1 }).await, Err(Cancelled));
+    }
+}
@@ -4,30 +4,72 @@ use futures::{FutureExt, TryFutureExt};
 use parking_lot::Mutex;
 use rand::prelude::SliceRandom;
 use rand::thread_rng;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::future::Future;
 use std::iter::zip;
-use std::net::SocketAddr;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::Path;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::thread::sleep;
-use std::time::{Duration, Instant};
-
-use ya_relay_core::utils::spawn_local_abortable;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, watch};
+
+use ya_relay_core::challenge::CancellationToken;
+use ya_relay_core::crypto::{recover_data_signer, sign_data, CryptoProvider};
+use ya_relay_core::properties::{verify_properties, Properties};
+use ya_relay_core::runtime::spawn_abortable;
+use ya_relay_core::runtime::{Instant, SystemTime};
 use ya_relay_proto::proto::Payload;
 
 use crate::metrics::register_metrics;
 
 pub use crate::config::{ClientBuilder, ClientConfig, FailFast};
-pub use crate::error::SessionError;
-pub use crate::model::{SessionDesc, SocketDesc, SocketState};
+pub use crate::error::{
+    ClientError, ClientResult, ConnectError, ConnectionLimit, DropReason, SenderError, SessionError,
+};
+pub use crate::model::SessionDesc;
+#[cfg(feature = "virtual-tcp")]
+pub use crate::model::{SocketDesc, SocketState};
 pub use crate::transport::transport_sender::{ForwardSender, GenericSender};
-pub use crate::transport::{ForwardReceiver, TransportLayer};
-
+#[cfg(feature = "virtual-tcp")]
+pub use crate::transport::ConnectProgress;
+pub use crate::transport::{
+    ForwardOptions, ForwardReceiver, IngressConfig, IngressOverflow, IngressStats, PoolConfig,
+    TransportLayer,
+};
+
+use crate::bandwidth::{self, BandwidthEstimate};
+use crate::cancel::cancellable;
+use crate::diagnostics::{self, Diagnostics};
 use crate::direct_session::DirectSession;
+use crate::dispatch::Handler;
+use crate::error::TcpError;
+use crate::hibernate::{self, Hibernation};
+use crate::key_pins::KeyPins;
+use crate::maintenance::MaintenanceNotice;
+#[cfg(feature = "virtual-tcp")]
 use crate::metrics::ChannelMetrics;
-use crate::model::NodeId;
+use crate::model::{NodeId, SessionId};
+use crate::nat::{ConnectionHints, NatInfo};
+use crate::pubsub::{self, Delivery, Subscription};
+use crate::raw_session::SessionType;
+use crate::relay_selection::RelayStats;
+#[cfg(feature = "virtual-tcp")]
+use crate::resume;
+use crate::retry::is_transient_request_error;
+use crate::rotation::{self, RotationNotice};
+#[cfg(feature = "virtual-tcp")]
+use crate::stream::{ForwardStream, IncomingStreams};
+#[cfg(feature = "virtual-tcp")]
+use crate::transport::tcp_registry::to_ipv6;
+use crate::unsolicited::UnsolicitedForward;
+#[cfg(feature = "virtual-tcp")]
+use crate::watchdog::StalledConnection;
 pub use ya_relay_core::server_session::TransportType;
 
 /// A Hybrid NET client that handles connections, sessions and relay operations.
@@ -76,11 +118,11 @@ impl Client {
     /// Real address on which Client is listening to incoming messages.
     /// If you passed address like `0.0.0.0:0` to `ClientBuilder::listen()`, than this function will
     /// return resolved version of this address.
-    pub async fn bind_addr(&self) -> anyhow::Result<SocketAddr> {
+    pub async fn bind_addr(&self) -> ClientResult<SocketAddr> {
         self.state
             .lock()
             .bind_addr
-            .ok_or_else(|| anyhow!("client not started"))
+            .ok_or_else(|| ClientError::Other("client not started".to_string()))
     }
 
     /// Returns the public address (`SocketAddr`) of the client, as seen by
@@ -88,6 +130,10 @@ impl Client {
     /// initialization process and can be used by other nodes to establish
     /// connections with this client.
     ///
+    /// The address is the one relay server observed our packets coming from and
+    /// managed to reach back. It is refreshed each time the client registers on
+    /// the relay server, use [`Client::public_addr_changes`] to follow it.
+    ///
     /// # Returns
     ///
     /// * `SocketAddr`: The public address of the client. This can be either an IPv4 or an IPv6 address.
@@ -109,7 +155,38 @@ impl Client {
     ///     public address.
     ///
     pub async fn set_public_addr(&self, addr: Option<SocketAddr>) {
-        self.transport.session_layer.set_public_addr(None).await;
+        self.transport.session_layer.set_public_addr(addr).await;
+    }
+
+    /// Notifies about changes of [`Client::public_addr`], e.g. when the relay server
+    /// sees us on a different address after reconnecting, or can't reach us anymore.
+    /// Applications can use it to decide when to re-advertise direct endpoints to peers.
+    pub fn public_addr_changes(&self) -> watch::Receiver<Option<SocketAddr>> {
+        self.transport.session_layer.watch_public_addr()
+    }
+
+    /// Classifies NAT in front of this client with help of the relay server.
+    /// The result is kept and available from [`Client::nat_info`].
+    pub async fn check_nat(&self) -> ClientResult<NatInfo> {
+        Ok(self.transport.session_layer.check_nat().await?)
+    }
+
+    /// Result of the last successful [`Client::check_nat`].
+    pub fn nat_info(&self) -> Option<NatInfo> {
+        self.transport.session_layer.nat_info()
+    }
+
+    /// Collects state of the client into a report, which can be attached to bug reports.
+    /// See [`Diagnostics::to_json`].
+    pub async fn diagnostics(&self) -> Diagnostics {
+        let bind_addr = self.state.lock().bind_addr;
+        diagnostics::collect(&self.transport, self.node_id(), bind_addr).await
+    }
+
+    /// Lists TCP sockets of the virtual network with their state, queues and timers,
+    /// like `ss -t` does for the sockets of the system.
+    pub async fn debug_dump(&self) -> String {
+        diagnostics::format_sockets(&diagnostics::sockets(&self.transport).await)
     }
 
     #[doc(hidden)]
@@ -170,11 +247,94 @@ impl Client {
     /// * `Option<NodeInfo>`: An option containing the `NodeInfo` if found,
     ///     or `None` if the node was not found.
     ///
-    pub async fn find_node(&self, node_id: NodeId) -> anyhow::Result<crate::model::Node> {
+    pub async fn find_node(&self, node_id: NodeId) -> ClientResult<crate::model::Node> {
         let session = self.transport.session_layer.server_session().await?;
-        session.raw.find_node(node_id).await
+        let find = self.config.retry.retry(
+            &self.config.clock,
+            || session.raw.find_node(node_id),
+            is_transient_request_error,
+        );
+        Ok(cancellable(&self.config.cancel, find)
+            .await
+            .map_err(SessionError::from)??)
+    }
+
+    /// Reachability of the Node reported by the relay server. Combined with
+    /// [`Client::nat_info`], tells if connecting directly to the Node is worth trying.
+    pub async fn connection_hints(&self, node_id: NodeId) -> ClientResult<ConnectionHints> {
+        let node = self.find_node(node_id).await?;
+        Ok(ConnectionHints::from(&node))
     }
 
+    /// Properties the Node published on the relay server, empty if there are none.
+    /// The signature is checked, so the relay server can't alter them.
+    pub async fn node_properties(&self, node_id: NodeId) -> ClientResult<Properties> {
+        let node = self.find_node(node_id).await?;
+        let signed = match &node.properties {
+            Some(signed) => signed,
+            None => return Ok(Default::default()),
+        };
+        let ident = node
+            .identities
+            .first()
+            .ok_or_else(|| ClientError::Protocol(format!("Node [{node_id}] has no identities")))?;
+        let default_id = NodeId::try_from(&ident.node_id)
+            .map_err(|e| ClientError::Protocol(format!("Invalid identity of [{node_id}]: {e}")))?;
+        verify_properties(signed, default_id).map_err(|e| ClientError::Protocol(e.to_string()))
+    }
+
+    /// Nodes, which published `property` set to `value`, e.g. all Nodes providing a service.
+    /// Properties are verified, so Nodes with altered or invalid ones are left out.
+    pub async fn find_nodes_by(
+        &self,
+        property: &str,
+        value: &str,
+        limit: u32,
+    ) -> ClientResult<Vec<NodeId>> {
+        let session = self.transport.session_layer.server_session().await?;
+        let found = session.raw.find_nodes(property, value, limit).await?;
+
+        let nodes = found
+            .nodes
+            .into_iter()
+            .filter_map(|node| {
+                let node_id = NodeId::try_from(&node.identities.first()?.node_id).ok()?;
+                let properties = verify_properties(node.properties.as_ref()?, node_id).ok()?;
+                (properties.get(property).map(String::as_str) == Some(value)).then_some(node_id)
+            })
+            .take(limit as usize)
+            .collect();
+        Ok(nodes)
+    }
+
+    /// Nodes closest to `node_id` by XOR distance, closest first, e.g. for Kademlia-like lookups.
+    /// This Node and Nodes, which can't be reached directly, are left out.
+    pub async fn nearest_nodes(&self, node_id: NodeId, count: u32) -> ClientResult<Vec<NodeId>> {
+        let session = self.transport.session_layer.server_session().await?;
+        let nearest = session.raw.nearest(Some(node_id), count).await?;
+
+        let nodes = nearest
+            .nodes
+            .into_iter()
+            .filter_map(|node| NodeId::try_from(&node.identities.first()?.node_id).ok())
+            .collect();
+        Ok(nodes)
+    }
+
+    /// Address of the relay server the Node is connected to, found within the hierarchy
+    /// of edge and core relays. Fails if no relay in the hierarchy knows the Node.
+    pub async fn locate_node(&self, node_id: NodeId) -> ClientResult<SocketAddr> {
+        let session = self.transport.session_layer.server_session().await?;
+        let located = session.raw.locate(node_id).await?;
+
+        match located.relay {
+            Some(relay) => SocketAddr::try_from(relay)
+                .map_err(|e| ClientError::Protocol(format!("Invalid relay address: {e}"))),
+            None => Ok(session.raw.remote),
+        }
+    }
+
+    #[cfg(feature = "virtual-tcp")]
     /// Returns a vector of all currently opened sockets.
     /// Each socket (`SocketInfo`) includes information such as its local and remote addresses,
     /// and the current state of the socket.
@@ -182,6 +342,7 @@ impl Client {
         self.transport.virtual_tcp.sockets()
     }
 
+    #[cfg(feature = "virtual-tcp")]
     /// Returns a set of metrics for all currently active sessions.
     /// Each metric (`SessionMetric`) includes information about the session,
     /// such as the amount of data transferred, the duration of the session,
@@ -221,6 +382,7 @@ impl Client {
         session_metrics
     }
 
+    #[cfg(feature = "virtual-tcp")]
     #[inline]
     pub fn metrics(&self) -> ChannelMetrics {
         self.transport.virtual_tcp.metrics()
@@ -230,6 +392,11 @@ impl Client {
         self.transport.forward_receiver()
     }
 
+    /// Payloads waiting in the forward receiver and ones lost, because it was full.
+    pub fn ingress_stats(&self) -> IngressStats {
+        self.transport.ingress_stats()
+    }
+
     pub(crate) async fn spawn(&mut self) -> anyhow::Result<()> {
         register_metrics();
 
@@ -243,6 +410,21 @@ impl Client {
             drop(g);
         }
 
+        // Relay of the restored session is used, instead of probing all of them.
+        let restored = match self.config.hibernation.clone() {
+            Some(hibernation) => hibernate::restore(self, &hibernation).await,
+            None => false,
+        };
+
+        if self.config.relay_selection.is_some() && !restored {
+            self.transport.session_layer.select_relay().await;
+            log::info!(
+                "[{}] using relay {}",
+                self.node_id(),
+                self.transport.session_layer.relays.current()
+            );
+        }
+
         if self.config.auto_connect {
             // We don't want to exit here, since yagna probably will be able to connect to relay
             // later, so it is very inconvenient for users to exit early.
@@ -257,9 +439,11 @@ impl Client {
 
         // Measure ping from time to time
         let this = self.clone();
-        let ping_handle = spawn_local_abortable(async move {
+        let ping_handle = spawn_abortable(self.config.spawner.as_ref(), async move {
             loop {
-                tokio::time::sleep(this.config.ping_measure_interval).await;
+                ya_relay_core::runtime::sleep(this.config.ping_measure_interval).await;
+                this.transport.session_layer.suspension.resumed().await;
+                this.transport.session_layer.idle.woken().await;
                 this.ping_sessions().await;
             }
         });
@@ -269,36 +453,308 @@ impl Client {
             g.handles.push(ping_handle);
         }
 
+        #[cfg(feature = "virtual-tcp")]
+        if let Some(path) = self.config.resume_state.clone() {
+            let resume_handle = spawn_abortable(
+                self.config.spawner.as_ref(),
+                resume::run(self.clone(), path),
+            );
+            self.state.lock().handles.push(resume_handle);
+        }
+
         log::debug!("[{}] started", self.node_id());
         Ok(())
     }
 
-    pub async fn forward_reliable(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
+    pub async fn forward_reliable(&self, node_id: NodeId) -> ClientResult<ForwardSender> {
         log::trace!(
             "Forward reliable from [{}] to [{}]",
             self.config.node_id,
             node_id
         );
-        self.transport.forward_reliable(node_id).await
+        Ok(self.transport.forward_reliable(node_id).await?)
     }
 
-    pub async fn forward_transfer(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
+    pub async fn forward_transfer(&self, node_id: NodeId) -> ClientResult<ForwardSender> {
         log::trace!(
             "Forward transfer channel from [{}] to [{}]",
             self.config.node_id,
             node_id
         );
 
-        self.transport.forward_transfer(node_id).await
+        Ok(self.transport.forward_transfer(node_id).await?)
+    }
+
+    /// Channel of any transport type to `node_id`. Repeated calls return the same channel,
+    /// as long as its connection is healthy and wasn't idle for longer than allowed by
+    /// [`ClientBuilder::connection_pool`]. Use [`ForwardOptions::force_new`] to replace it.
+    pub async fn forward(
+        &self,
+        node_id: NodeId,
+        transport: TransportType,
+        options: ForwardOptions,
+    ) -> ClientResult<ForwardSender> {
+        log::trace!(
+            "Forward {transport:?} from [{}] to [{node_id}], {options:?}",
+            self.config.node_id,
+        );
+        Ok(self.transport.forward(node_id, transport, options).await?)
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    /// Virtual IPv6 address of `node_id`, used by its virtual TCP connections and the
+    /// [`crate::tun`] bridge. It's derived from a prefix of the NodeId.
+    pub fn virtual_address(&self, node_id: NodeId) -> Ipv6Addr {
+        to_ipv6(node_id)
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    /// Node with the virtual IPv6 `address`. Addresses can't be turned back into NodeIds,
+    /// so only Nodes this client has sessions or virtual TCP connections with are found.
+    pub async fn resolve_address(&self, address: Ipv6Addr) -> Option<NodeId> {
+        self.transport.resolve_address(address).await
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    /// [`Client::forward`] to the Node with the virtual IPv6 `address`,
+    /// see [`Client::resolve_address`].
+    pub async fn forward_to_address(
+        &self,
+        address: Ipv6Addr,
+        transport: TransportType,
+        options: ForwardOptions,
+    ) -> ClientResult<ForwardSender> {
+        let node_id = self.resolve_address(address).await.ok_or_else(|| {
+            ClientError::Other(format!("No known Node with virtual address {address}"))
+        })?;
+        self.forward(node_id, transport, options).await
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    /// Opens a byte stream to `node_id` over the transfer channel, which is established
+    /// right away. See [`crate::stream`].
+    pub async fn open_stream(&self, node_id: NodeId) -> ClientResult<ForwardStream> {
+        let ends = self.transport.streams.open(node_id);
+        let stream = ForwardStream::new(self.transport.clone(), ends);
+        stream.announce().await.map_err(stream_error)?;
+        Ok(stream)
     }
 
-    pub async fn forward_unreliable(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
+    #[cfg(feature = "virtual-tcp")]
+    /// Copies `reader` to a stream opened to `node_id` and closes the stream after the
+    /// last byte. Returns number of bytes sent.
+    pub async fn send_stream(
+        &self,
+        node_id: NodeId,
+        reader: impl AsyncRead + Unpin,
+    ) -> ClientResult<u64> {
+        let mut stream = self.open_stream(node_id).await?;
+        let mut reader = BufReader::with_capacity(stream.chunk_size(), reader);
+        let sent = tokio::io::copy_buf(&mut reader, &mut stream)
+            .await
+            .map_err(stream_error)?;
+        stream.shutdown().await.map_err(stream_error)?;
+        Ok(sent)
+    }
+
+    /// Nodes awaiting approval with [`UnsolicitedPolicy::QueueForApproval`], one event per Node.
+    /// Returns `None`, if already taken.
+    ///
+    /// [`UnsolicitedPolicy::QueueForApproval`]: crate::unsolicited::UnsolicitedPolicy::QueueForApproval
+    pub fn unsolicited_forwards(&self) -> Option<mpsc::UnboundedReceiver<UnsolicitedForward>> {
+        self.transport.session_layer.unsolicited.events()
+    }
+
+    /// Nodes, which sent forwards held until approved or rejected.
+    pub fn pending_nodes(&self) -> Vec<NodeId> {
+        self.transport.session_layer.unsolicited.pending()
+    }
+
+    /// Accepts forwards from the Node and delivers the ones held so far.
+    pub async fn approve_node(&self, node_id: NodeId) {
+        let layer = &self.transport.session_layer;
+        for held in layer.unsolicited.approve(node_id) {
+            let session = held.session.upgrade();
+            if let Some(handle) = layer.clone().on_forward(held.forward, held.from, session) {
+                handle.await;
+            }
+        }
+    }
+
+    /// Drops forwards held for the Node and the ones it sends later, until approved.
+    pub fn reject_node(&self, node_id: NodeId) {
+        self.transport.session_layer.unsolicited.reject(node_id);
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    /// Streams opened by other Nodes. Returns `None`, if already taken.
+    /// Stream data arriving when nobody listens is dropped.
+    pub fn incoming_streams(&self) -> Option<IncomingStreams> {
+        let rx = self.transport.streams.listen()?;
+        Some(IncomingStreams::new(self.transport.clone(), rx))
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    /// Same as [`Client::forward_reliable`], but reports stages of establishing the
+    /// connection to `progress` and fails with an error telling which stage failed.
+    ///
+    /// If the channel is already connected, only [`ConnectProgress::Established`] is reported.
+    pub async fn forward_reliable_with_progress(
+        &self,
+        node_id: NodeId,
+        progress: impl Fn(ConnectProgress) + 'static,
+    ) -> Result<ForwardSender, ConnectError> {
+        self.transport
+            .forward_virtual_tcp(
+                node_id,
+                TransportType::Reliable,
+                Default::default(),
+                Some(Rc::new(progress)),
+            )
+            .await
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    /// Transfer channel counterpart of [`Client::forward_reliable_with_progress`].
+    pub async fn forward_transfer_with_progress(
+        &self,
+        node_id: NodeId,
+        progress: impl Fn(ConnectProgress) + 'static,
+    ) -> Result<ForwardSender, ConnectError> {
+        self.transport
+            .forward_virtual_tcp(
+                node_id,
+                TransportType::Transfer,
+                Default::default(),
+                Some(Rc::new(progress)),
+            )
+            .await
+    }
+
+    pub async fn forward_unreliable(&self, node_id: NodeId) -> ClientResult<ForwardSender> {
         log::trace!(
             "Forward unreliable from [{}] to [{}]",
             self.config.node_id,
             node_id
         );
-        self.transport.forward_unreliable(node_id).await
+        Ok(self.transport.forward_unreliable(node_id).await?)
+    }
+
+    /// Estimates bandwidth of the path currently used to reach `node_id`, relayed or P2P,
+    /// by sending a train of probes. The other Node has to run a client supporting
+    /// [`crate::bandwidth`], otherwise this fails with a timeout. The estimate is used
+    /// for pacing packets to the Node, see [`crate::pacing`].
+    pub async fn estimate_bandwidth(&self, node_id: NodeId) -> ClientResult<BandwidthEstimate> {
+        let path = match self.is_p2p(node_id).await {
+            true => SessionType::P2P,
+            false => SessionType::Relay,
+        };
+        let mut tx = self.transport.forward_unreliable(node_id).await?;
+        let (train, report) = self.transport.bandwidth.start();
+        for seq in 0..bandwidth::TRAIN_LENGTH {
+            let probe =
+                bandwidth::encode_probe(train, seq, bandwidth::TRAIN_LENGTH, bandwidth::PROBE_SIZE);
+            tx.send(probe.into())
+                .await
+                .map_err(|e| ClientError::Other(format!("Sending probe failed: {e}")))?;
+        }
+
+        let report = match ya_relay_core::runtime::timeout(bandwidth::REPORT_TIMEOUT, report).await
+        {
+            Ok(Ok(report)) => report,
+            _ => {
+                return Err(ClientError::Timeout(format!(
+                    "No bandwidth report from [{node_id}]"
+                )))
+            }
+        };
+        let estimate =
+            bandwidth::estimate(&report, bandwidth::TRAIN_LENGTH, path).ok_or_else(|| {
+                ClientError::Other(format!(
+                    "Only {} of {} probes reached [{node_id}]",
+                    report.received,
+                    bandwidth::TRAIN_LENGTH
+                ))
+            })?;
+        #[cfg(feature = "virtual-tcp")]
+        self.transport
+            .virtual_tcp
+            .pacer
+            .set_rate(node_id, estimate.bytes_per_second);
+        Ok(estimate)
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    /// Sets rate of the path to `node_id` used for pacing virtual TCP packets, replacing
+    /// the last estimate. Has no effect without [`crate::ClientBuilder::pacing`].
+    pub fn set_pacing_rate(&self, node_id: NodeId, bytes_per_second: u64) {
+        self.transport
+            .virtual_tcp
+            .pacer
+            .set_rate(node_id, bytes_per_second as f64);
+    }
+
+    /// Measures round trip time on the session used to reach `node_id`, establishing it
+    /// if needed. For relayed connections this is the round trip time to the relay server.
+    pub async fn ping(&self, node_id: NodeId) -> ClientResult<Duration> {
+        let routing = self.transport.session_layer.session(node_id).await?;
+        let session = routing
+            .direct_session()
+            .ok_or_else(|| SessionError::NotFound(format!("Session with [{node_id}] closed")))?;
+
+        let started = Instant::now();
+        session.raw.ping().await?;
+        Ok(started.elapsed())
+    }
+
+    /// Stops keep-alive traffic while the application is suspended, e.g. moved to
+    /// background on a mobile device. Sessions aren't expired and the relay server
+    /// isn't reconnected until [`Client::resume`] is called. Sending data is still possible.
+    pub fn suspend(&self) {
+        if self.transport.session_layer.suspension.set(true) {
+            log::info!("[{}] suspended", self.node_id());
+        }
+    }
+
+    /// Restarts keep-alive traffic stopped by [`Client::suspend`]. Sessions are pinged
+    /// right away if their expiration passed in the meantime, closing those not responding.
+    pub fn resume(&self) {
+        if self.transport.session_layer.suspension.set(false) {
+            log::info!("[{}] resumed", self.node_id());
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.transport.session_layer.suspension.is_suspended()
+    }
+
+    /// Lowers keep-alive traffic while the application is idle, but should stay reachable.
+    /// Sessions are pinged after [`ClientBuilder::expire_idle_session_after`] instead of the
+    /// regular expiration, ping measurement stops and virtual TCP timers are paused.
+    /// Incoming packets are still handled.
+    ///
+    /// NAT mappings may expire sooner than the idle expiration, in which case the Node
+    /// isn't reachable until [`Client::wake`].
+    pub fn idle(&self) {
+        if self.transport.session_layer.idle.set(true) {
+            #[cfg(feature = "virtual-tcp")]
+            self.transport.virtual_tcp.net.set_poll_paused(true);
+            log::info!("[{}] idle", self.node_id());
+        }
+    }
+
+    /// Restores regular keep-alive after [`Client::idle`]. Sessions, which weren't seen
+    /// within the regular expiration, are pinged immediately.
+    pub fn wake(&self) {
+        if self.transport.session_layer.idle.set(false) {
+            #[cfg(feature = "virtual-tcp")]
+            self.transport.virtual_tcp.net.set_poll_paused(false);
+            log::info!("[{}] woken", self.node_id());
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.transport.session_layer.idle.is_idle()
     }
 
     /// TODO: Remove this.
@@ -330,6 +786,22 @@ impl Client {
         zip(ids.into_iter(), aliases).collect()
     }
 
+    /// Relay server in use.
+    pub fn relay(&self) -> SocketAddr {
+        self.transport.session_layer.relays.current()
+    }
+
+    /// Last measurements of the configured relays, see [`crate::relay_selection`].
+    pub fn relays(&self) -> Vec<RelayStats> {
+        self.transport.session_layer.relays.stats()
+    }
+
+    /// Measures the configured relays now, instead of waiting for the probe interval,
+    /// and moves to a better one. Returns the relay moved to.
+    pub async fn select_relay(&self) -> Option<SocketAddr> {
+        self.transport.session_layer.select_relay().await
+    }
+
     pub async fn reconnect_server(&self) {
         if self.transport.session_layer.close_server_session().await {
             log::info!("Reconnecting to Hybrid NET relay server");
@@ -351,6 +823,36 @@ impl Client {
         self.transport.session_layer.default_id(node_id).await
     }
 
+    /// Signs `data` with the default identity of this Client.
+    /// Other Nodes can check the signature using `Client::verify`.
+    pub async fn sign(&self, data: &[u8]) -> ClientResult<Vec<u8>> {
+        let crypto = self.config.crypto.get(self.config.node_id).await?;
+        Ok(sign_data(crypto.as_ref(), data).await?)
+    }
+
+    /// Checks if `signature` over `data` was made by `node_id`.
+    /// Signatures made by other identity of the same Node are accepted, if we know
+    /// the Node's identities from an existing session.
+    pub async fn verify(
+        &self,
+        node_id: NodeId,
+        data: &[u8],
+        signature: &[u8],
+    ) -> ClientResult<bool> {
+        let signer = recover_data_signer(data, signature)?.node_id;
+        if signer == node_id {
+            return Ok(true);
+        }
+
+        let default_id = |id| self.transport.session_layer.default_id(id);
+        Ok(
+            match (default_id(signer).await, default_id(node_id).await) {
+                (Some(signer), Some(node_id)) => signer == node_id,
+                _ => false,
+            },
+        )
+    }
+
     /// Broadcasts a byte array to a certain number of neighbours in the network.
     /// This method sends the same byte array to the specified number of neighbour nodes.
     ///
@@ -361,9 +863,9 @@ impl Client {
     ///
     /// # Returns
     ///
-    /// * `anyhow::Result<()>`: A Result object indicating the success or failure of the broadcast operation.
+    /// * `ClientResult<()>`: A Result object indicating the success or failure of the broadcast operation.
     ///
-    pub async fn broadcast(&self, data: Vec<u8>, count: u32) -> anyhow::Result<()> {
+    pub async fn broadcast(&self, data: Vec<u8>, count: u32) -> ClientResult<()> {
         let zero: NodeId = Default::default();
 
         let mut node_ids: HashSet<NodeId> = {
@@ -381,10 +883,7 @@ impl Client {
 
         if node_ids.len() < count as usize {
             log::debug!("Querying for {} node(s)", count as usize - node_ids.len());
-            let next_node_ids = self
-                .neighbours(count)
-                .await
-                .map_err(|e| anyhow!("Unable to query neighbors: {e}"))?;
+            let next_node_ids = self.neighbours(count).await?;
 
             for node_id in next_node_ids {
                 node_ids.insert(node_id);
@@ -427,6 +926,151 @@ impl Client {
         Ok(())
     }
 
+    /// Tells Nodes with sessions to this Client, that its NodeId changes to `new_id`.
+    /// The notice is signed by the current default identity, so it has to be sent
+    /// before the identity is replaced. Returns the number of Nodes notified.
+    /// See [`crate::rotation`].
+    pub async fn announce_rotation(&self, new_id: NodeId) -> ClientResult<usize> {
+        let old_id = self.config.node_id;
+        let signature = self.sign(&rotation::signed_data(old_id, new_id)).await?;
+        let packet = rotation::encode(old_id, new_id, &signature);
+
+        let zero: NodeId = Default::default();
+        let node_ids: HashSet<NodeId> = self
+            .transport
+            .session_layer
+            .sessions()
+            .await
+            .into_iter()
+            .filter_map(|session| session.upgrade())
+            .map(|session| session.owner.default_id)
+            .filter(|&node_id| node_id != zero)
+            .collect();
+
+        let sent = join_all(node_ids.into_iter().map(|node_id| {
+            let packet = packet.clone();
+            async move {
+                let mut tx = self.transport.forward_unreliable(node_id).await?;
+                tx.send(packet.into()).await?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .map_err(move |e| log::debug!("Failed to announce rotation to [{node_id}]: {e}"))
+        }))
+        .await;
+        Ok(sent.into_iter().filter(Result::is_ok).count())
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    /// Outgoing connections closed by the watchdog, because they stopped making progress,
+    /// see [`crate::watchdog`]. Returns `None`, if already taken.
+    pub fn stalled_connections(&self) -> Option<mpsc::UnboundedReceiver<StalledConnection>> {
+        self.transport.virtual_tcp.stalled_connections()
+    }
+
+    /// NodeId changes announced by other Nodes. Returns `None`, if already taken.
+    pub fn rotation_notices(&self) -> Option<mpsc::UnboundedReceiver<RotationNotice>> {
+        self.transport.rotations.events()
+    }
+
+    /// Shutdowns and restarts announced by the relay server. Returns `None`, if already taken.
+    pub fn maintenance_notices(&self) -> Option<mpsc::UnboundedReceiver<MaintenanceNotice>> {
+        self.transport.session_layer.maintenance.receiver()
+    }
+
+    /// Current NodeId of a Node, following NodeId changes it announced.
+    pub fn rotated_id(&self, node_id: NodeId) -> NodeId {
+        self.transport.rotations.resolve(node_id)
+    }
+
+    /// Joins a named group on the relay server. Packets sent to the group by other Nodes
+    /// are received as unreliable forwards from their senders.
+    /// Returns number of the group members, including this Node.
+    pub async fn join_group(&self, group: &str) -> ClientResult<u32> {
+        let members = self
+            .transport
+            .session_layer
+            .server_session()
+            .await?
+            .raw
+            .join_group(group)
+            .await?;
+
+        log::debug!("Joined group {group:?} with {members} member(s)");
+        Ok(members)
+    }
+
+    pub async fn leave_group(&self, group: &str) -> ClientResult<()> {
+        self.transport
+            .session_layer
+            .server_session()
+            .await?
+            .raw
+            .leave_group(group)
+            .await?;
+        Ok(())
+    }
+
+    /// Sends `data` to all other members of the group with a single packet, which the
+    /// relay server fans out. Unlike [`Client::broadcast`], the sender doesn't need to
+    /// be a member, nor to know the members.
+    pub async fn forward_to_group(&self, group: &str, data: Vec<u8>) -> ClientResult<()> {
+        Ok(self
+            .transport
+            .session_layer
+            .server_session()
+            .await?
+            .forward_to_group(group, data)
+            .await?)
+    }
+
+    /// Receives payloads published to `topic` by other Nodes. The first subscription
+    /// of a topic joins the relay group.
+    pub async fn subscribe(&self, topic: &str) -> ClientResult<Subscription> {
+        pubsub::check_topic(topic)?;
+        let pubsub = &self.transport.pubsub;
+        let (subscription, first) = pubsub.subscribe(topic);
+        if !first || pubsub.delivery() == Delivery::Fanout {
+            return Ok(subscription);
+        }
+
+        let session = self.transport.session_layer.server_session().await?;
+        let group = pubsub::group_name(topic);
+        match ya_relay_core::runtime::timeout(pubsub::JOIN_TIMEOUT, session.raw.join_group(&group))
+            .await
+        {
+            Ok(result) => {
+                result?;
+            }
+            Err(_) => {
+                log::warn!("Relay server doesn't support groups. Publishing to neighbours instead");
+                pubsub.set_delivery(Delivery::Fanout);
+            }
+        }
+        Ok(subscription)
+    }
+
+    /// Ends all subscriptions of `topic` and leaves the relay group.
+    pub async fn unsubscribe(&self, topic: &str) -> ClientResult<()> {
+        let pubsub = &self.transport.pubsub;
+        if pubsub.unsubscribe(topic) && pubsub.delivery() == Delivery::Relay {
+            self.leave_group(&pubsub::group_name(topic)).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends `data` to subscribers of `topic` on other Nodes. Delivery is unreliable.
+    pub async fn publish(&self, topic: &str, data: Vec<u8>) -> ClientResult<()> {
+        pubsub::check_topic(topic)?;
+        let packet = pubsub::encode(topic, &data);
+        match self.transport.pubsub.delivery() {
+            Delivery::Relay => {
+                self.forward_to_group(&pubsub::group_name(topic), packet)
+                    .await
+            }
+            Delivery::Fanout => self.broadcast(packet, pubsub::FANOUT_COUNT).await,
+        }
+    }
+
     /// Retrieves a certain number of neighbour nodes in the network.
     /// This method returns a vector of NodeId objects representing neighbour nodes.
     ///
@@ -436,12 +1080,12 @@ impl Client {
     ///
     /// # Returns
     ///
-    /// * `anyhow::Result<Vec<NodeId>>`: A Result object containing a vector of neighbour NodeIds or an error.
+    /// * `ClientResult<Vec<NodeId>>`: A Result object containing a vector of neighbour NodeIds or an error.
     ///
-    pub async fn neighbours(&self, count: u32) -> anyhow::Result<Vec<NodeId>> {
+    pub async fn neighbours(&self, count: u32) -> ClientResult<Vec<NodeId>> {
         if let Some(neighbours) = { self.state.lock().neighbours.clone() } {
             if neighbours.nodes.len() as u32 >= count
-                && neighbours.updated + self.config.neighbourhood_ttl > Instant::now()
+                && neighbours.updated + self.config.neighbourhood_ttl > self.config.clock.now()
             {
                 return Ok(neighbours.nodes);
             }
@@ -453,8 +1097,7 @@ impl Client {
             .transport
             .session_layer
             .server_session()
-            .await
-            .map_err(|e| anyhow!("Error establishing session with relay: {e}"))?
+            .await?
             .raw
             .neighbours(count, true)
             .await?;
@@ -472,14 +1115,14 @@ impl Client {
         let prev_neighborhood = {
             let mut g = self.state.lock();
             g.neighbours.replace(Neighbourhood {
-                updated: Instant::now(),
+                updated: self.config.clock.now(),
                 nodes: nodes.clone(),
             })
         };
 
         // Compare neighborhood, to see which Nodes could have disappeared.
         if let Some(prev_neighbors) = prev_neighborhood {
-            tokio::task::spawn_local(
+            ya_relay_core::runtime::spawn_local(
                 self.clone()
                     .check_nodes_connection(prev_neighbors, nodes.clone())
                     .map_err(|e| log::debug!("Checking disappeared neighbors failed. {e}")),
@@ -535,8 +1178,52 @@ impl Client {
         Ok(())
     }
 
-    pub async fn shutdown(&mut self) -> anyhow::Result<()> {
+    /// Token cancelled on [`Client::shutdown`]. Cancelling it aborts operations in progress,
+    /// see [`ClientBuilder::cancel_token`].
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.config.cancel.clone()
+    }
+
+    /// Public keys expected from other Nodes, see [`ClientBuilder::pin_key`]. Changes apply
+    /// to sessions established later, existing sessions aren't closed.
+    pub fn key_pins(&self) -> &KeyPins {
+        &self.config.key_pins
+    }
+
+    /// Saves the client to `path` and shuts it down, leaving the relay server session open,
+    /// so [`Client::restore`] can continue it. See [`crate::hibernate`].
+    pub async fn hibernate(mut self, path: impl AsRef<Path>) -> ClientResult<()> {
+        let hibernation = Hibernation::capture(&self).await?;
+        hibernation.save(path.as_ref())?;
+        log::info!(
+            "[{}] hibernated to {}",
+            self.node_id(),
+            path.as_ref().display()
+        );
+
+        self.transport.session_layer.detach_server_session();
+        self.shutdown().await
+    }
+
+    /// Starts the client saved with [`Client::hibernate`]. The secret key in `crypto` has to
+    /// be the one the client was using. Fails, if there is no file at `path`.
+    pub async fn restore(
+        path: impl AsRef<Path>,
+        crypto: impl CryptoProvider + 'static,
+    ) -> ClientResult<Client> {
+        let path = path.as_ref();
+        let hibernation = Hibernation::load(path)?.ok_or_else(|| {
+            ClientError::Other(format!("No hibernated client in {}", path.display()))
+        })?;
+        Ok(ClientBuilder::from_hibernation(hibernation)?
+            .crypto(crypto)
+            .build()
+            .await?)
+    }
+
+    pub async fn shutdown(&mut self) -> ClientResult<()> {
         log::info!("Shutting down Hybrid NET client.");
+        self.config.cancel.cancel();
 
         let handles = {
             let mut g = self.state.lock();
@@ -547,19 +1234,48 @@ impl Client {
             handle.abort();
         }
 
-        self.transport.shutdown().await
+        Ok(self.transport.shutdown().await?)
     }
 }
 
+#[cfg(feature = "virtual-tcp")]
+fn stream_error(e: std::io::Error) -> ClientError {
+    ClientError::VirtualTcp(TcpError::Other(e.to_string()))
+}
+
 #[derive(Clone)]
 pub(crate) struct Neighbourhood {
-    updated: Instant,
+    updated: ya_relay_core::runtime::Instant,
     nodes: Vec<NodeId>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Forwarded {
     pub transport: TransportType,
     pub node_id: NodeId,
     pub payload: Payload,
+    /// Session the data arrived on. For reliable channels it is the session currently
+    /// used with the Node, `None` if it was already closed.
+    pub session: Option<ForwardedSession>,
+    /// For reliable channels, time the data was read from the virtual TCP connection.
+    pub received_at: SystemTime,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForwardedSession {
+    pub id: SessionId,
+    /// `P2P` only if the data came directly from the sending Node.
+    pub session_type: SessionType,
+}
+
+impl Forwarded {
+    pub fn is_p2p(&self) -> bool {
+        matches!(
+            self.session,
+            Some(ForwardedSession {
+                session_type: SessionType::P2P,
+                ..
+            })
+        )
+    }
 }
@@ -1,20 +1,58 @@
+use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Duration;
 use url::Url;
 
+use ya_relay_core::challenge::{CancellationToken, SolveProgress, SolverOptions};
+use ya_relay_core::clock::{system_clock, Clock, ClockRef};
 use ya_relay_core::crypto::{CryptoProvider, FallbackCryptoProvider, PublicKey};
 use ya_relay_core::error::InternalError;
+#[cfg(feature = "virtual-tcp")]
+use ya_relay_core::forward_auth;
+use ya_relay_core::identity::IdentityKey;
+use ya_relay_core::intercept::InterceptorRef;
+use ya_relay_core::key::{keystore, Protected};
+use ya_relay_core::properties::{sign_properties, Properties};
+use ya_relay_core::runtime::{local_spawner, Spawner, SpawnerRef};
+use ya_relay_core::server_identity;
+#[cfg(feature = "virtual-tcp")]
 use ya_relay_core::udp_stream::resolve_max_payload_overhead_size;
+#[cfg(not(target_arch = "wasm32"))]
+use ya_relay_core::udp_stream::UdpTransport;
+use ya_relay_core::udp_stream::{DatagramTransport, DatagramTransportRef};
 use ya_relay_core::utils::parse_udp_url;
+#[cfg(feature = "ws")]
+use ya_relay_core::ws_stream::WebSocketTransport;
 use ya_relay_core::NodeId;
+use ya_relay_proto::proto;
+#[cfg(feature = "virtual-tcp")]
 use ya_relay_proto::proto::{Forward, MAX_TAG_SIZE};
+#[cfg(feature = "virtual-tcp")]
 use ya_relay_stack::StackConfig;
 
 use crate::client::Client;
+use crate::fec::FecConfig;
+#[cfg(feature = "virtual-tcp")]
+use crate::firewall::Firewall;
+use crate::hibernate::Hibernation;
+use crate::key_pins::KeyPins;
+use crate::middleware::{Middleware, MiddlewareRef};
+use crate::multipath::MultipathMode;
+use crate::pacing::PacingConfig;
+use crate::relay_selection::RelaySelectionConfig;
+use crate::retry::RetryPolicy;
+use crate::server_trust::ServerTrust;
 use crate::session::network_view::NetworkViewConfig;
+use crate::transport::{IngressConfig, PoolConfig};
+use crate::unsolicited::UnsolicitedPolicy;
+#[cfg(feature = "virtual-tcp")]
+use crate::watchdog::WatchdogConfig;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum FailFast {
     Yes,
     No,
@@ -23,18 +61,35 @@ pub enum FailFast {
 #[derive(Clone)]
 pub struct ClientConfig {
     pub node_id: NodeId,
-    pub node_pub_key: PublicKey,
+    pub node_pub_key: IdentityKey,
     pub crypto: Rc<dyn CryptoProvider>,
     pub challenge_difficulty: u64,
+    pub challenge_solver: SolverOptions,
 
     pub bind_url: Url,
     pub srv_addr: SocketAddr,
     pub auto_connect: bool,
     pub auto_connect_fail_fast: bool,
     pub session_expiration: Duration,
+    /// Used instead of `session_expiration` while the client is idle.
+    pub idle_session_expiration: Duration,
+    /// File keeping connections to resume after restart.
+    pub resume_state: Option<PathBuf>,
+    /// State saved by [`Client::hibernate`], restored on start.
+    pub hibernation: Option<Arc<Hibernation>>,
+    pub server_trust: ServerTrust,
+    /// Public keys expected from other Nodes during the session handshake.
+    pub key_pins: KeyPins,
+    #[cfg(feature = "virtual-tcp")]
     pub stack_config: StackConfig,
     pub ping_measure_interval: Duration,
-    pub server_session_reconnect_max_interval: Duration,
+    /// Applied to establishing relay server session, finding Nodes and connecting to them.
+    pub retry: RetryPolicy,
+    /// Applied to re-registering on the relay server, after its session was lost.
+    pub reconnect: RetryPolicy,
+    /// Cancelled on shutdown. Aborts connecting, finding Nodes, solving challenges
+    /// and forwarding, which are still in progress.
+    pub cancel: CancellationToken,
 
     pub session_request_timeout: Duration,
     pub challenge_request_timeout: Duration,
@@ -44,6 +99,45 @@ pub struct ClientConfig {
     pub incoming_session_timeout: Duration,
     pub neighbourhood_ttl: Duration,
     pub registry_config: NetworkViewConfig,
+    /// Maximum number of virtual TCP connections, incoming and outgoing, including ones
+    /// being opened or closed. Each of them holds a socket with its buffers.
+    pub max_virt_connections: Option<usize>,
+    /// Maximum number of virtual TCP connections with a single Node.
+    pub max_virt_connections_per_node: Option<usize>,
+    /// Reuse of forward channels requested again for the same Node.
+    pub connection_pool: PoolConfig,
+    /// Capacity of the forward receiver and what happens, when it's full.
+    pub ingress: IngressConfig,
+    /// Use of the relay server together with P2P sessions, see [`crate::multipath`].
+    pub multipath: MultipathMode,
+    /// Forward error correction of unreliable channels, see [`crate::fec`].
+    pub fec: Option<FecConfig>,
+    /// Spacing of virtual TCP packets, see [`crate::pacing`].
+    pub pacing: Option<PacingConfig>,
+    /// Relays to choose from besides `srv_addr`, see [`crate::relay_selection`].
+    pub relay_selection: Option<RelaySelectionConfig>,
+    /// Time connections with Nodes are kept, while the relay server session is lost.
+    pub relay_outage_grace: Duration,
+    /// Recycling of stalled virtual TCP connections, see [`crate::watchdog`].
+    #[cfg(feature = "virtual-tcp")]
+    pub watchdog: Option<WatchdogConfig>,
+    /// Handling of forwards from Nodes, which we never contacted.
+    pub unsolicited: UnsolicitedPolicy,
+    /// Nodes permitted to open virtual TCP connections, see [`crate::firewall`].
+    #[cfg(feature = "virtual-tcp")]
+    pub firewall: Firewall,
+    /// Applied to forwarded payloads, see [`crate::middleware`].
+    pub middleware: Vec<MiddlewareRef>,
+    /// Published on the relay server at registration, signed by the default identity.
+    pub properties: Option<proto::Properties>,
+    /// Time source for session expiration, keep-alive and handshake timeouts.
+    pub clock: ClockRef,
+    /// Test hook applied to all packets received and sent by the session layer.
+    pub interceptor: Option<InterceptorRef>,
+    /// Socket used by the session layer, UDP by default.
+    pub transport: DatagramTransportRef,
+    /// Starts background loops of the client, [`ya_relay_core::runtime::spawn_local`] by default.
+    pub spawner: SpawnerRef,
 }
 
 /// The `ClientBuilder` struct provides a builder pattern for constructing a `Client` object.
@@ -81,12 +175,45 @@ pub struct ClientBuilder {
     auto_connect: bool,
     auto_connect_fail_fast: bool,
     session_expiration: Option<Duration>,
+    idle_session_expiration: Option<Duration>,
+    resume_state: Option<PathBuf>,
+    hibernation: Option<Hibernation>,
+    server_trust: ServerTrust,
+    key_pins: KeyPins,
     session_request_timeout: Option<Duration>,
+    challenge_solver: SolverOptions,
+    #[cfg(feature = "virtual-tcp")]
     stack_config: StackConfig,
+    max_virt_connections: Option<usize>,
+    max_virt_connections_per_node: Option<usize>,
+    connection_pool: PoolConfig,
+    ingress: IngressConfig,
+    multipath: MultipathMode,
+    fec: Option<FecConfig>,
+    pacing: Option<PacingConfig>,
+    relay_selection: Option<RelaySelectionConfig>,
+    relay_outage_grace: Option<Duration>,
+    #[cfg(feature = "virtual-tcp")]
+    watchdog: Option<WatchdogConfig>,
+    unsolicited: UnsolicitedPolicy,
+    #[cfg(feature = "virtual-tcp")]
+    firewall: Firewall,
+    retry: RetryPolicy,
+    reconnect: RetryPolicy,
+    cancel: CancellationToken,
+    middleware: Vec<MiddlewareRef>,
+    properties: Properties,
+    clock: Option<ClockRef>,
+    interceptor: Option<InterceptorRef>,
+    transport: Option<DatagramTransportRef>,
+    #[cfg(feature = "ws")]
+    websocket: Option<Url>,
+    spawner: Option<SpawnerRef>,
 }
 
 impl ClientBuilder {
     pub fn from_url(url: Url) -> ClientBuilder {
+        let cancel = CancellationToken::new();
         ClientBuilder {
             bind_url: None,
             srv_url: url,
@@ -94,8 +221,44 @@ impl ClientBuilder {
             auto_connect: false,
             auto_connect_fail_fast: false,
             session_expiration: None,
+            idle_session_expiration: None,
+            resume_state: None,
+            hibernation: None,
+            server_trust: ServerTrust::Any,
+            key_pins: Default::default(),
             session_request_timeout: None,
+            challenge_solver: SolverOptions::default().cancel_token(cancel.child_token()),
+            #[cfg(feature = "virtual-tcp")]
             stack_config: Default::default(),
+            max_virt_connections: None,
+            max_virt_connections_per_node: None,
+            connection_pool: Default::default(),
+            ingress: Default::default(),
+            multipath: Default::default(),
+            fec: None,
+            pacing: None,
+            relay_selection: None,
+            relay_outage_grace: None,
+            #[cfg(feature = "virtual-tcp")]
+            watchdog: None,
+            unsolicited: Default::default(),
+            #[cfg(feature = "virtual-tcp")]
+            firewall: Default::default(),
+            retry: RetryPolicy::never(),
+            reconnect: RetryPolicy::exponential(
+                Duration::from_millis(500),
+                Duration::from_secs(300),
+            )
+            .jitter(0.99),
+            cancel,
+            middleware: Default::default(),
+            properties: Default::default(),
+            clock: None,
+            interceptor: None,
+            transport: None,
+            #[cfg(feature = "ws")]
+            websocket: None,
+            spawner: None,
         }
     }
 
@@ -104,6 +267,17 @@ impl ClientBuilder {
         self
     }
 
+    /// Uses secret from password protected keyfile at `path`.
+    /// New secret is generated and saved there, if the file doesn't exist.
+    pub fn secret_from_keystore(
+        self,
+        path: impl AsRef<Path>,
+        password: impl Into<Protected>,
+    ) -> anyhow::Result<ClientBuilder> {
+        let secret = keystore::load_or_generate(path, &password.into())?;
+        Ok(self.crypto(FallbackCryptoProvider::new(secret)))
+    }
+
     /// Sets client to auto connect to the server session.
     ///
     /// `fail_fast` argument determines whether to early return with an error when encountering
@@ -129,31 +303,280 @@ impl ClientBuilder {
         self
     }
 
+    /// Keep-alive cadence while [`Client::idle`] is in effect. Should stay below the
+    /// relay server's session purge timeout, otherwise the session is re-established on wake.
+    pub fn expire_idle_session_after(mut self, expiration: Duration) -> Self {
+        self.idle_session_expiration = Some(expiration);
+        self
+    }
+
+    /// Saves connections to `path` and resumes them, when started again with the same
+    /// file. See [`crate::resume`].
+    pub fn resume_state(mut self, path: impl Into<PathBuf>) -> Self {
+        self.resume_state = Some(path.into());
+        self
+    }
+
+    /// Client with the configuration saved by [`Client::hibernate`], which continues
+    /// the saved relay session. The secret key has to be set again. See [`crate::hibernate`].
+    pub fn from_hibernation(hibernation: Hibernation) -> anyhow::Result<ClientBuilder> {
+        let mut builder = hibernation.config.builder()?;
+        builder.hibernation = Some(hibernation);
+        Ok(builder)
+    }
+
+    /// Accepts only the relay server signing handshake responses with `key`,
+    /// see [`ya_relay_core::server_identity`].
+    pub fn server_key(mut self, key: server_identity::PublicKey) -> Self {
+        self.server_trust = ServerTrust::Key(key);
+        self
+    }
+
+    /// Saves the key of the relay server in `path` on the first connection
+    /// and accepts only the same key later.
+    pub fn trust_server_on_first_use(mut self, path: impl Into<PathBuf>) -> Self {
+        self.server_trust = ServerTrust::FirstUse(path.into());
+        self
+    }
+
+    /// Accepts only `key` from the Node during the session handshake. Can be called
+    /// multiple times. Pins can be changed later with [`crate::Client::key_pins`].
+    pub fn pin_key(self, node_id: NodeId, key: IdentityKey) -> Self {
+        self.key_pins.pin(node_id, key);
+        self
+    }
+
     pub fn session_request_timeout(mut self, timeout: Duration) -> Self {
         self.session_request_timeout = Some(timeout);
         self
     }
 
+    /// Number of threads used for solving a single challenge received from other Nodes.
+    pub fn challenge_workers(mut self, workers: usize) -> Self {
+        self.challenge_solver = self.challenge_solver.workers(workers);
+        self
+    }
+
+    /// Called periodically while solving challenges, so the progress and ETA
+    /// of slow handshakes can be shown to the user.
+    pub fn challenge_progress(
+        mut self,
+        progress: impl Fn(&SolveProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.challenge_solver = self.challenge_solver.on_progress(progress);
+        self
+    }
+
+    /// Cancelling the `token` aborts all challenges being solved and fails their handshakes.
+    pub fn challenge_cancel_token(mut self, token: CancellationToken) -> Self {
+        self.challenge_solver = self.challenge_solver.cancel_token(token);
+        self
+    }
+
+    /// Cancelling the `token` aborts operations in progress with [`SessionError::Aborted`],
+    /// so embedders can stop the client cleanly. The token is also cancelled by
+    /// [`Client::shutdown`]. Challenges are aborted as well, unless they have their own token
+    /// set later with [`ClientBuilder::challenge_cancel_token`].
+    ///
+    /// [`SessionError::Aborted`]: crate::SessionError::Aborted
+    pub fn cancel_token(mut self, token: CancellationToken) -> Self {
+        self.challenge_solver = self.challenge_solver.cancel_token(token.child_token());
+        self.cancel = token;
+        self
+    }
+
+    /// Replaces real time used by session timers. Meant for tests driving
+    /// expiration with `ya_relay_core::clock::MockClock`.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Lets tests drop, delay or modify packets exchanged with the server and other Nodes.
+    #[cfg(feature = "test-utils")]
+    pub fn intercept(
+        mut self,
+        interceptor: impl ya_relay_core::intercept::Interceptor + 'static,
+    ) -> Self {
+        self.interceptor = Some(Arc::new(interceptor));
+        self
+    }
+
+    /// Replaces the UDP socket with a custom transport, e.g.
+    /// `ya_relay_core::testing::nat::Nat` in tests.
+    pub fn transport(mut self, transport: impl DatagramTransport + 'static) -> Self {
+        self.transport = Some(Rc::new(transport));
+        self
+    }
+
+    /// Connects to the relay through its WebSocket bridge at `url` instead of UDP, which
+    /// is the only way in browsers. Replaces [`ClientBuilder::transport`]. Other Nodes are
+    /// reached through the relay, see [`ya_relay_core::ws_stream`].
+    #[cfg(feature = "ws")]
+    pub fn websocket(mut self, url: Url) -> Self {
+        self.websocket = Some(url);
+        self
+    }
+
+    /// Uses the default UDP socket, passing it to `hook` after binding, so it can be
+    /// protected from the app's VPN or bound to a network interface on mobile platforms.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn socket_hook(
+        self,
+        hook: impl Fn(&std::net::UdpSocket) -> std::io::Result<()> + 'static,
+    ) -> Self {
+        self.transport(UdpTransport::with_hook(hook))
+    }
+
+    /// Replaces the way background loops of the client are spawned.
+    pub fn spawner(mut self, spawner: impl Spawner + 'static) -> Self {
+        self.spawner = Some(Rc::new(spawner));
+        self
+    }
+
+    #[cfg(feature = "virtual-tcp")]
     pub fn tcp_max_recv_buffer_size(mut self, max: usize) -> anyhow::Result<Self> {
         self.stack_config.tcp_mem.rx.set_max(max)?;
         Ok(self)
     }
 
+    #[cfg(feature = "virtual-tcp")]
     pub fn tcp_max_send_buffer_size(mut self, max: usize) -> anyhow::Result<Self> {
         self.stack_config.tcp_mem.tx.set_max(max)?;
         Ok(self)
     }
 
+    #[cfg(feature = "virtual-tcp")]
     pub fn udp_max_recv_buffer_size(mut self, max: usize) -> anyhow::Result<Self> {
         self.stack_config.udp_mem.rx.set_max(max)?;
         Ok(self)
     }
 
+    #[cfg(feature = "virtual-tcp")]
     pub fn udp_max_send_buffer_size(mut self, max: usize) -> anyhow::Result<Self> {
         self.stack_config.udp_mem.tx.set_max(max)?;
         Ok(self)
     }
 
+    /// Bounds memory used by virtual TCP sockets. Connecting above the limit fails with
+    /// [`crate::ConnectError::TooManyConnections`] and packets from Nodes, which aren't
+    /// connected yet, are dropped.
+    pub fn max_virt_connections(mut self, max: usize) -> Self {
+        self.max_virt_connections = Some(max);
+        self
+    }
+
+    pub fn max_virt_connections_per_node(mut self, max: usize) -> Self {
+        self.max_virt_connections_per_node = Some(max);
+        self
+    }
+
+    /// Health checks and maximum idle time of channels reused by [`Client::forward`].
+    /// By default closed channels are replaced and connections are re-established
+    /// after 5 minutes without sending.
+    pub fn connection_pool(mut self, config: PoolConfig) -> Self {
+        self.connection_pool = config;
+        self
+    }
+
+    /// Payloads waiting in [`Client::forward_receiver`], before reading virtual TCP
+    /// connections is paused or payloads are dropped. By default up to 4096 payloads wait
+    /// and connections are paused, see [`crate::IngressOverflow`].
+    pub fn ingress(mut self, config: IngressConfig) -> Self {
+        self.ingress = config;
+        self
+    }
+
+    /// Sends reliable and transfer channels over the relay server together with P2P
+    /// sessions. Only P2P sessions are used by default, see [`crate::multipath`].
+    pub fn multipath(mut self, mode: MultipathMode) -> Self {
+        self.multipath = mode;
+        self
+    }
+
+    /// Adds parity packets to unreliable channels with Nodes, which agree to that,
+    /// so single packets lost in a group are recovered. See [`crate::fec`].
+    pub fn fec(mut self, config: FecConfig) -> Self {
+        self.fec = Some(config);
+        self
+    }
+
+    /// Releases virtual TCP packets to each Node at the rate of its path, instead
+    /// of sending whole TCP windows at once. See [`crate::pacing`].
+    pub fn pacing(mut self, config: PacingConfig) -> Self {
+        self.pacing = Some(config);
+        self
+    }
+
+    /// Picks the relay with the best round-trip time weighted by its load, out of this
+    /// one and the listed ones, and moves to another one, when it gets better.
+    /// See [`crate::relay_selection`].
+    pub fn relay_selection(mut self, config: RelaySelectionConfig) -> Self {
+        self.relay_selection = Some(config);
+        self
+    }
+
+    /// While the relay server session is being re-established, virtual connections with
+    /// Nodes aren't closed, and forwards from unknown addresses are held until it's back,
+    /// for at most `grace`. 30 seconds by default, zero closes them right away.
+    pub fn relay_outage_grace(mut self, grace: Duration) -> Self {
+        self.relay_outage_grace = Some(grace);
+        self
+    }
+
+    /// Closes outgoing virtual TCP connections, which stopped getting data acknowledged,
+    /// so they are opened again on the next send. See [`crate::watchdog`].
+    #[cfg(feature = "virtual-tcp")]
+    pub fn watchdog(mut self, config: WatchdogConfig) -> Self {
+        self.watchdog = Some(config);
+        self
+    }
+
+    /// What happens to forwards from Nodes, which this client never contacted.
+    /// Accepted by default, see [`crate::unsolicited`].
+    pub fn unsolicited_forwards(mut self, policy: UnsolicitedPolicy) -> Self {
+        self.unsolicited = policy;
+        self
+    }
+
+    /// Restricts Nodes, which can open virtual TCP connections to this client.
+    /// All Nodes are permitted by default, see [`crate::firewall`].
+    #[cfg(feature = "virtual-tcp")]
+    pub fn firewall(mut self, firewall: Firewall) -> Self {
+        self.firewall = firewall;
+        self
+    }
+
+    /// Retries of establishing the relay server session, finding Nodes and connecting
+    /// to them, when they fail with a timeout or network error. By default they aren't retried.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Retries of re-registering on the relay server, after its session was lost.
+    /// By default backs off exponentially from 0.5 s to 5 min, with jitter and without
+    /// a budget, so the client keeps trying to come back.
+    pub fn reconnect_policy(mut self, policy: RetryPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Adds a hook for payloads sent to and received from other Nodes.
+    /// Can be called multiple times, see [`crate::middleware`] for the order.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Rc::new(middleware));
+        self
+    }
+
+    /// Property, e.g. a supported service or version, which other Nodes can read with
+    /// [`Client::node_properties`]. Encoded properties can't exceed
+    /// [`ya_relay_core::properties::MAX_PROPERTIES_SIZE`].
+    pub fn property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties.insert(key.into(), value.into());
+        self
+    }
+
     pub async fn build_config(mut self) -> anyhow::Result<ClientConfig> {
         let bind_url = self
             .bind_url
@@ -163,24 +586,64 @@ impl ClientBuilder {
             .unwrap_or_else(|| Rc::new(FallbackCryptoProvider::default()));
 
         let default_id = crypto.default_id().await?;
-        let default_pub_key = crypto.get(default_id).await?.public_key().await?;
+        if let Some(hibernation) = &self.hibernation {
+            if hibernation.node_id != default_id {
+                anyhow::bail!(
+                    "Hibernated client has identity [{}], but the secret key is of [{default_id}]",
+                    hibernation.node_id
+                );
+            }
+        }
+        let default_crypto = crypto.get(default_id).await?;
+        let default_pub_key = default_crypto.identity_key().await?;
+        let properties = match self.properties.is_empty() {
+            true => None,
+            false => Some(sign_properties(default_crypto.as_ref(), &self.properties).await?),
+        };
 
-        self.stack_config.max_transmission_unit =
-            resolve_max_payload_overhead_size(MAX_TAG_SIZE + Forward::header_size()).await?;
+        let srv_addr = parse_udp_url(&self.srv_url)?.parse()?;
+        #[cfg(feature = "ws")]
+        if let Some(url) = self.websocket.take() {
+            self.transport = Some(Rc::new(WebSocketTransport::new(url, srv_addr)));
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        let transport = self
+            .transport
+            .unwrap_or_else(|| Rc::new(UdpTransport::default()));
+        #[cfg(target_arch = "wasm32")]
+        let transport = self.transport.ok_or_else(|| {
+            anyhow::anyhow!("UDP isn't available in browsers, use `ClientBuilder::websocket`")
+        })?;
+
+        #[cfg(feature = "virtual-tcp")]
+        {
+            self.stack_config.max_transmission_unit = resolve_max_payload_overhead_size(
+                MAX_TAG_SIZE + Forward::header_size() + forward_auth::TAG_SIZE,
+            )
+            .await?;
+        }
 
         Ok(ClientConfig {
             node_id: default_id,
             node_pub_key: default_pub_key,
             crypto,
             challenge_difficulty: 1,
+            challenge_solver: self.challenge_solver,
             bind_url,
-            srv_addr: parse_udp_url(&self.srv_url)?.parse()?,
+            srv_addr,
             auto_connect: self.auto_connect,
             auto_connect_fail_fast: self.auto_connect_fail_fast,
             session_expiration: self
                 .session_expiration
                 .unwrap_or_else(|| Duration::from_secs(25)),
-            server_session_reconnect_max_interval: Duration::from_secs(300),
+            idle_session_expiration: self
+                .idle_session_expiration
+                .unwrap_or_else(|| Duration::from_secs(120)),
+            resume_state: self.resume_state,
+            hibernation: self.hibernation.map(Arc::new),
+            server_trust: self.server_trust,
+            key_pins: self.key_pins,
+            #[cfg(feature = "virtual-tcp")]
             stack_config: self.stack_config,
             ping_measure_interval: Duration::from_secs(300),
             session_request_timeout: self
@@ -192,6 +655,31 @@ impl ClientBuilder {
             incoming_session_timeout: Duration::from_secs(16),
             neighbourhood_ttl: Duration::from_secs(300),
             registry_config: Default::default(),
+            max_virt_connections: self.max_virt_connections,
+            max_virt_connections_per_node: self.max_virt_connections_per_node,
+            connection_pool: self.connection_pool,
+            ingress: self.ingress,
+            multipath: self.multipath,
+            fec: self.fec,
+            pacing: self.pacing,
+            relay_selection: self.relay_selection,
+            relay_outage_grace: self
+                .relay_outage_grace
+                .unwrap_or_else(|| Duration::from_secs(30)),
+            #[cfg(feature = "virtual-tcp")]
+            watchdog: self.watchdog,
+            unsolicited: self.unsolicited,
+            #[cfg(feature = "virtual-tcp")]
+            firewall: self.firewall,
+            retry: self.retry,
+            reconnect: self.reconnect,
+            cancel: self.cancel,
+            middleware: self.middleware,
+            properties,
+            clock: self.clock.unwrap_or_else(system_clock),
+            interceptor: self.interceptor,
+            transport,
+            spawner: self.spawner.unwrap_or_else(local_spawner),
         })
     }
 
@@ -0,0 +1,416 @@
+//! Snapshot of the client state meant to be attached to bug reports.
+//!
+//! Report is built only from state already available in the client. The only traffic
+//! it generates is a single ping to the relay server, so it is safe to collect it
+//! while the problem is happening.
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use ya_relay_core::runtime::Instant;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::SlotId;
+#[cfg(feature = "virtual-tcp")]
+use ya_relay_stack::smoltcp::wire::IpAddress;
+#[cfg(feature = "virtual-tcp")]
+use ya_relay_stack::TcpSocketInfo;
+
+use crate::model::{NatInfo, SessionDesc};
+use crate::transport::{IngressStats, TransportLayer};
+
+pub use crate::session::ErrorEntry;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostics {
+    pub node_id: NodeId,
+    /// `None` if the client is not listening.
+    pub bind_addr: Option<SocketAddr>,
+    pub public_addr: Option<SocketAddr>,
+    pub server: ServerDiagnostics,
+    /// Result of the last [`crate::Client::check_nat`]. NAT isn't re-checked here.
+    pub nat: Option<NatInfo>,
+    /// Sessions established by this Node, including the relay server session.
+    pub sessions: Vec<SessionDiagnostics>,
+    /// Routes of all connected Node identities.
+    pub routes: Vec<RouteDiagnostics>,
+    /// Empty without the `virtual-tcp` feature.
+    pub virtual_nodes: Vec<VirtNodeDiagnostics>,
+    /// Empty without the `virtual-tcp` feature.
+    pub sockets: Vec<SocketDiagnostics>,
+    /// Queued payloads dropped, because their TTL passed before they could be sent.
+    pub expired_payloads: usize,
+    pub ingress: IngressStats,
+    /// Newest first.
+    pub errors: Vec<ErrorEntry>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerDiagnostics {
+    pub addr: SocketAddr,
+    /// `None` if there is no session with the relay server.
+    pub session: Option<SessionDesc>,
+    pub rtt: Option<Duration>,
+    /// Reason of the ping failure.
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionDiagnostics {
+    /// Default id of the remote Node. Relay server is represented by the zero id.
+    pub node_id: NodeId,
+    pub session: SessionDesc,
+    /// Nodes forwarding through this session.
+    pub forwards: Vec<SlotDiagnostics>,
+    /// Delay before each forward, while the relay server reports congestion.
+    pub pacing_delay: Duration,
+    pub congestion_reports: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SlotDiagnostics {
+    pub node_id: NodeId,
+    pub slot: SlotId,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RouteDiagnostics {
+    pub node_id: NodeId,
+    /// Default id of the Node owning the session used for sending. `None` if the
+    /// session was already closed.
+    pub route: Option<NodeId>,
+    pub p2p: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VirtNodeDiagnostics {
+    pub node_id: NodeId,
+    pub address: String,
+    pub channels: Vec<ChannelDiagnostics>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelDiagnostics {
+    pub channel: String,
+    pub state: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SocketDiagnostics {
+    pub protocol: String,
+    pub local: String,
+    pub remote: String,
+    /// Empty for sockets other than TCP.
+    pub state: String,
+    /// Virtual Node the remote address belongs to.
+    pub node_id: Option<NodeId>,
+    /// `None` for sockets other than TCP.
+    pub tcp: Option<TcpDiagnostics>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TcpDiagnostics {
+    /// Bytes received, but not read yet.
+    pub recv_queue: usize,
+    pub recv_capacity: usize,
+    /// Bytes sent, but not acknowledged yet.
+    pub send_queue: usize,
+    pub send_capacity: usize,
+    pub timeout: Option<Duration>,
+    pub keep_alive: Option<Duration>,
+    pub ack_delay: Option<Duration>,
+    pub nagle: bool,
+}
+
+#[cfg(feature = "virtual-tcp")]
+impl From<TcpSocketInfo> for TcpDiagnostics {
+    fn from(info: TcpSocketInfo) -> Self {
+        TcpDiagnostics {
+            recv_queue: info.recv_queue,
+            recv_capacity: info.recv_capacity,
+            send_queue: info.send_queue,
+            send_capacity: info.send_capacity,
+            timeout: info.timeout.map(Into::into),
+            keep_alive: info.keep_alive.map(Into::into),
+            ack_delay: info.ack_delay.map(Into::into),
+            nagle: info.nagle,
+        }
+    }
+}
+
+impl Diagnostics {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+pub(crate) async fn collect(
+    transport: &TransportLayer,
+    node_id: NodeId,
+    bind_addr: Option<SocketAddr>,
+) -> Diagnostics {
+    let layer = &transport.session_layer;
+
+    let sessions = layer
+        .sessions()
+        .await
+        .into_iter()
+        .filter_map(|session| session.upgrade())
+        .map(|session| SessionDiagnostics {
+            node_id: session.owner.default_id,
+            session: SessionDesc::from(session.raw.as_ref()),
+            forwards: session
+                .list()
+                .into_iter()
+                .filter_map(|entry| {
+                    session
+                        .find_slot(&entry.default_id)
+                        .map(|slot| SlotDiagnostics {
+                            node_id: entry.default_id,
+                            slot,
+                        })
+                })
+                .collect(),
+            pacing_delay: session.pacer.delay(),
+            congestion_reports: session.pacer.reports(),
+        })
+        .collect();
+
+    let routes = {
+        let state = layer.state.lock();
+        state
+            .nodes
+            .iter()
+            .map(|(node_id, routing)| {
+                let route = routing.route.upgrade().map(|s| s.owner.default_id);
+                RouteDiagnostics {
+                    node_id: *node_id,
+                    route,
+                    p2p: route == Some(routing.node.default_id.node_id),
+                }
+            })
+            .collect()
+    };
+
+    let virtual_nodes = virtual_nodes(transport).await;
+    let sockets = sockets(transport).await;
+
+    Diagnostics {
+        node_id,
+        bind_addr,
+        public_addr: layer.get_public_addr().await,
+        server: server(transport).await,
+        nat: layer.nat_info(),
+        sessions,
+        routes,
+        virtual_nodes,
+        sockets,
+        #[cfg(feature = "virtual-tcp")]
+        expired_payloads: transport.virtual_tcp.expired(),
+        #[cfg(not(feature = "virtual-tcp"))]
+        expired_payloads: 0,
+        ingress: transport.ingress_stats(),
+        errors: layer.errors.recent(),
+    }
+}
+
+#[cfg(feature = "virtual-tcp")]
+async fn virtual_nodes(transport: &TransportLayer) -> Vec<VirtNodeDiagnostics> {
+    let mut virtual_nodes = Vec::new();
+    for node in transport.virtual_tcp.virt_nodes().await {
+        let mut channels = Vec::with_capacity(node.channels.len());
+        for channel in node.channels.iter() {
+            channels.push(ChannelDiagnostics {
+                channel: channel.channel.to_string(),
+                state: channel.state().await.to_string(),
+            });
+        }
+        virtual_nodes.push(VirtNodeDiagnostics {
+            node_id: node.id(),
+            address: node.address.to_string(),
+            channels,
+        });
+    }
+    virtual_nodes
+}
+
+#[cfg(not(feature = "virtual-tcp"))]
+async fn virtual_nodes(_transport: &TransportLayer) -> Vec<VirtNodeDiagnostics> {
+    Vec::new()
+}
+
+/// Sockets of the virtual network, with TCP state resolved to Nodes.
+#[cfg(feature = "virtual-tcp")]
+pub(crate) async fn sockets(transport: &TransportLayer) -> Vec<SocketDiagnostics> {
+    let nodes: HashMap<IpAddress, NodeId> = transport
+        .virtual_tcp
+        .virt_nodes()
+        .await
+        .into_iter()
+        .map(|node| (node.address, node.id()))
+        .collect();
+    let mut tcp: HashMap<_, _> = transport.virtual_tcp.tcp_sockets().into_iter().collect();
+
+    transport
+        .virtual_tcp
+        .sockets()
+        .into_iter()
+        .map(|(desc, state)| SocketDiagnostics {
+            protocol: desc.protocol.to_string(),
+            local: desc.local.to_string(),
+            remote: desc.remote.to_string(),
+            state: state.to_string(),
+            node_id: desc
+                .remote
+                .ip_endpoint()
+                .ok()
+                .and_then(|endpoint| nodes.get(&endpoint.addr).copied()),
+            tcp: tcp.remove(&desc).map(TcpDiagnostics::from),
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "virtual-tcp"))]
+pub(crate) async fn sockets(_transport: &TransportLayer) -> Vec<SocketDiagnostics> {
+    Vec::new()
+}
+
+/// Formats TCP sockets as a table, in the spirit of `ss -t`.
+pub fn format_sockets(sockets: &[SocketDiagnostics]) -> String {
+    let header = [
+        "State", "Recv-Q", "Send-Q", "Local", "Peer", "Node", "Timers",
+    ];
+    let rows: Vec<[String; 7]> = sockets
+        .iter()
+        .filter_map(|socket| {
+            let tcp = socket.tcp.as_ref()?;
+            Some([
+                socket.state.clone(),
+                tcp.recv_queue.to_string(),
+                tcp.send_queue.to_string(),
+                socket.local.clone(),
+                socket.remote.clone(),
+                socket
+                    .node_id
+                    .map(|node_id| node_id.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                timers(tcp),
+            ])
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    let header = header.map(str::to_string);
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+    table
+}
+
+fn timers(tcp: &TcpDiagnostics) -> String {
+    let mut timers = Vec::new();
+    if let Some(timeout) = tcp.timeout {
+        timers.push(format!("timeout:{timeout:?}"));
+    }
+    if let Some(keep_alive) = tcp.keep_alive {
+        timers.push(format!("keepalive:{keep_alive:?}"));
+    }
+    if let Some(ack_delay) = tcp.ack_delay {
+        timers.push(format!("ack-delay:{ack_delay:?}"));
+    }
+    if tcp.nagle {
+        timers.push("nagle".to_string());
+    }
+    match timers.is_empty() {
+        true => "-".to_string(),
+        false => timers.join(","),
+    }
+}
+
+/// Uses already established session. Reconnecting would hide the problem being diagnosed.
+async fn server(transport: &TransportLayer) -> ServerDiagnostics {
+    let addr = transport.session_layer.relays.current();
+    let session = match transport.session_layer.find_session(addr).await {
+        Some(session) => session,
+        None => {
+            return ServerDiagnostics {
+                addr,
+                session: None,
+                rtt: None,
+                error: Some("No session with the relay server".to_string()),
+            }
+        }
+    };
+
+    let started = Instant::now();
+    let (rtt, error) = match session.raw.ping().await {
+        Ok(_) => (Some(started.elapsed()), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    ServerDiagnostics {
+        addr,
+        session: Some(SessionDesc::from(session.raw.as_ref())),
+        rtt,
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket(state: &str, remote: &str, tcp: Option<TcpDiagnostics>) -> SocketDiagnostics {
+        SocketDiagnostics {
+            protocol: "tcp".to_string(),
+            local: "[::2]:1".to_string(),
+            remote: remote.to_string(),
+            state: state.to_string(),
+            node_id: None,
+            tcp,
+        }
+    }
+
+    #[test]
+    fn test_format_sockets() {
+        let tcp = TcpDiagnostics {
+            recv_queue: 0,
+            recv_capacity: 1024,
+            send_queue: 1200,
+            send_capacity: 1024,
+            timeout: Some(Duration::from_secs(120)),
+            keep_alive: None,
+            ack_delay: Some(Duration::from_millis(40)),
+            nagle: false,
+        };
+        let sockets = vec![
+            socket("ESTABLISHED", "[::3]:1", Some(tcp)),
+            socket("", "*", None),
+        ];
+
+        let table = format_sockets(&sockets);
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "State       Recv-Q Send-Q Local   Peer    Node Timers"
+        );
+        assert_eq!(
+            lines[1],
+            "ESTABLISHED 0      1200   [::2]:1 [::3]:1 -    timeout:120s,ack-delay:40ms"
+        );
+    }
+}
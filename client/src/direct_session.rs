@@ -1,18 +1,26 @@
 use anyhow::anyhow;
 use metrics::{counter, increment_counter};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use ya_relay_core::forward_auth::{self, ForwardKey};
 use ya_relay_core::identity::Identity;
 use ya_relay_core::server_session::TransportType;
-use ya_relay_core::sync::Actuator;
+use ya_relay_core::sync::Gate;
 use ya_relay_core::NodeId;
-use ya_relay_proto::proto::{Forward, Payload, SlotId, FORWARD_SLOT_ID};
+use ya_relay_proto::proto::{self, Forward, Payload, SlotId, FORWARD_SLOT_ID};
 
 use crate::error::SessionError;
 use crate::metrics::{RELAY_ID, SOURCE_ID, TARGET_ID};
 use crate::raw_session::RawSession;
 
+mod pacer;
+
+pub(crate) use self::pacer::Pacer;
+
 /// Describes Node identity.
 /// `IdType` Could be either plain `NodeId` or `Identity` structure containing
 /// public key.
@@ -31,9 +39,26 @@ pub struct NodeEntry<IdType> {
 pub struct AllowedForwards {
     slots: HashMap<SlotId, NodeEntry<NodeId>>,
     nodes: HashMap<NodeId, SlotId>,
+    /// Epoch of the relay slot table, zero until known.
+    epoch: u32,
 }
 
 impl AllowedForwards {
+    /// Records the slot table epoch announced by the relay. If it changed, relay could
+    /// have reassigned slots, so all entries are removed and returned.
+    pub fn update_epoch(&mut self, epoch: u32) -> Vec<NodeEntry<NodeId>> {
+        if epoch == 0 || epoch == self.epoch {
+            return vec![];
+        }
+        let previous = std::mem::replace(&mut self.epoch, epoch);
+        if previous == 0 {
+            return vec![];
+        }
+        log::debug!("[update_epoch]: slot epoch changed from {previous} to {epoch}");
+        self.nodes.clear();
+        self.slots.drain().map(|(_, node)| node).collect()
+    }
+
     pub fn add(&mut self, node: NodeEntry<NodeId>, slot: SlotId) {
         log::trace!("[add]: node {} to slot {}", node.default_id, slot);
         // Remove previous information about node.
@@ -90,6 +115,51 @@ impl AllowedForwards {
     }
 }
 
+/// Keep-alive parameters advertised by the relay server in the handshake response.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionParams {
+    /// Time the relay keeps the session without receiving any packets.
+    pub session_ttl: Option<Duration>,
+    /// Replaces the configured `session_expiration` of the session.
+    pub ping_interval: Option<Duration>,
+    /// Larger Forward payloads are rejected before sending.
+    pub max_forward_size: Option<usize>,
+    /// Forwards sent to the relay keep the session alive there, as pings do.
+    pub forward_keep_alive: bool,
+}
+
+impl SessionParams {
+    /// Interval of checking, if the session is alive. Stays below the session ttl,
+    /// so the relay doesn't forget the session in the meantime.
+    ///
+    /// The relay asks for its ping interval only to hear from the Node. While `forwarding`
+    /// and the relay counts forwards as keep-alive, the configured interval is used instead.
+    pub fn expiration(&self, configured: Duration, idle: bool, forwarding: bool) -> Duration {
+        let expiration = match (idle, self.ping_interval) {
+            (false, Some(_)) if forwarding && self.forward_keep_alive => configured,
+            (false, Some(ping_interval)) => ping_interval,
+            _ => configured,
+        };
+        match self.session_ttl {
+            Some(ttl) => expiration.min(ttl / 2),
+            None => expiration,
+        }
+    }
+}
+
+impl From<&proto::response::Session> for SessionParams {
+    fn from(response: &proto::response::Session) -> Self {
+        let millis = |ms: u32| (ms > 0).then(|| Duration::from_millis(ms.into()));
+        SessionParams {
+            session_ttl: millis(response.session_ttl_ms),
+            ping_interval: millis(response.ping_interval_ms),
+            max_forward_size: (response.max_forward_size > 0)
+                .then_some(response.max_forward_size as usize),
+            forward_keep_alive: response.forward_keep_alive,
+        }
+    }
+}
+
 /// Higher level session abstraction that handles Node identification
 /// and public keys and maps it to low-level `Session`.
 /// `DirectSession` can be used to forward packets to other Nodes,
@@ -107,8 +177,17 @@ pub struct DirectSession {
     /// In case of p2p session these values will be empty. In future we can use other
     /// sessions than Relay to forward packets.
     pub forwards: Arc<std::sync::RwLock<AllowedForwards>>,
-    /// Implements limiting forwarding functionality.
-    pub(crate) forward_pause: Actuator,
+    /// Closed while the other party paused forwarding.
+    pub(crate) forward_gate: Gate,
+    /// Slows down forwarding on relay server request.
+    pub(crate) pacer: Pacer,
+    /// Authenticates Forwards sent to the relay server. Set once the handshake
+    /// with the server is finished.
+    pub(crate) forward_key: Arc<std::sync::OnceLock<ForwardKey>>,
+    /// Set once the handshake with the server is finished, if it advertised any.
+    pub(crate) params: Arc<std::sync::OnceLock<SessionParams>>,
+    /// Limit of Forward payload size learned from rejected Forwards, 0 if none.
+    pub(crate) rejected_max: Arc<AtomicUsize>,
 }
 
 impl DirectSession {
@@ -136,7 +215,11 @@ impl DirectSession {
             },
             raw: session,
             forwards: Arc::new(std::sync::RwLock::new(Default::default())),
-            forward_pause: Default::default(),
+            forward_gate: Default::default(),
+            pacer: Default::default(),
+            forward_key: Default::default(),
+            params: Default::default(),
+            rejected_max: Default::default(),
         }))
     }
 
@@ -154,7 +237,11 @@ impl DirectSession {
             },
             raw: session,
             forwards: Arc::new(std::sync::RwLock::new(Default::default())),
-            forward_pause: Default::default(),
+            forward_gate: Default::default(),
+            pacer: Default::default(),
+            forward_key: Default::default(),
+            params: Default::default(),
+            rejected_max: Default::default(),
         }))
     }
 
@@ -175,6 +262,16 @@ impl DirectSession {
                 )))?
         };
 
+        if let Some(max) = self.max_forward_size() {
+            if packet.len() > max {
+                return Err(SessionError::PayloadTooLarge {
+                    size: packet.len(),
+                    max,
+                }
+                .into());
+            }
+        }
+
         let mut forward = match transport {
             TransportType::Unreliable => Forward::unreliable(self.raw.id, slot, packet),
             TransportType::Reliable => Forward::new(self.raw.id, slot, packet),
@@ -185,14 +282,80 @@ impl DirectSession {
         if encrypted {
             forward.set_encrypted();
         }
+        if let Some(key) = self.forward_key.get() {
+            forward_auth::sign(key, &mut forward);
+        }
 
         self.wait_for_resume().await;
+        self.pacer.pace().await;
         self.raw.send(forward).await?;
+        self.raw.dispatcher.update_forwarded();
 
         self.record_outgoing(target, transport, size);
         Ok(())
     }
 
+    /// Sends the payload to other members of the relay group, see [`RawSession::forward_to_group`].
+    pub async fn forward_to_group(&self, group: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+        let mut forward = proto::control::ForwardToGroup {
+            group: group.to_string(),
+            payload,
+            tag: vec![],
+        };
+        if let Some(key) = self.forward_key.get() {
+            forward_auth::sign_group(key, &self.raw.id.to_array(), &mut forward);
+        }
+        self.raw.forward_to_group(forward).await
+    }
+
+    /// Parameters advertised by the other side, default if none.
+    pub fn params(&self) -> SessionParams {
+        self.params.get().copied().unwrap_or_default()
+    }
+
+    /// Largest Forward payload accepted by the other side. Advertised in the handshake,
+    /// or learned from Forwards it rejected.
+    pub fn max_forward_size(&self) -> Option<usize> {
+        let rejected = Some(self.rejected_max.load(Ordering::Relaxed)).filter(|max| *max > 0);
+        match (self.params().max_forward_size, rejected) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub(crate) fn on_forward_rejected(&self, rejected: &proto::control::ForwardRejected) {
+        let router_id = self.owner.default_id;
+        match rejected.code() {
+            proto::StatusCode::PayloadTooLarge if rejected.max_size > 0 => {
+                log::warn!(
+                    "[{router_id}] rejected Forward of {} B to slot {}, max={}",
+                    rejected.size,
+                    rejected.slot,
+                    rejected.max_size
+                );
+                self.rejected_max
+                    .store(rejected.max_size as usize, Ordering::Relaxed);
+            }
+            code => log::debug!(
+                "[{router_id}] rejected Forward to slot {}: {code:?}",
+                rejected.slot
+            ),
+        }
+    }
+
+    /// [`SessionParams::expiration`] of this session. Forwards sent within the ping
+    /// interval advertised by the relay keep the session alive.
+    pub fn expiration(&self, configured: Duration, idle: bool) -> Duration {
+        let params = self.params();
+        let forwarding = match (params.ping_interval, self.raw.dispatcher.last_forwarded()) {
+            (Some(ping_interval), Some(forwarded)) => {
+                forwarded + ping_interval >= self.raw.dispatcher.clock().now()
+            }
+            _ => false,
+        };
+        params.expiration(configured, idle, forwarding)
+    }
+
     pub fn remove_by_slot(&self, id: SlotId) -> anyhow::Result<NodeId> {
         let mut forwards = self.forwards.write().unwrap();
         forwards.remove_by_slot(id).ok_or(anyhow!(
@@ -221,6 +384,12 @@ impl DirectSession {
         forwards.add(node, slot)
     }
 
+    /// Returns Nodes, whose slots are no longer valid.
+    pub fn update_slot_epoch(&self, epoch: u32) -> Vec<NodeEntry<NodeId>> {
+        let mut forwards = self.forwards.write().unwrap();
+        forwards.update_epoch(epoch)
+    }
+
     pub fn get_by_slot(&self, slot: SlotId) -> Option<NodeEntry<NodeId>> {
         let forwards = self.forwards.read().unwrap();
         forwards.get_by_slot(slot)
@@ -238,12 +407,12 @@ impl DirectSession {
 
     #[inline]
     pub async fn pause_forwarding(&self) {
-        self.forward_pause.enable();
+        self.forward_gate.close();
     }
 
     #[inline]
     pub async fn resume_forwarding(&self) {
-        self.forward_pause.disable();
+        self.forward_gate.open();
     }
 
     /// Other party can pause forwarding for multiple reasons:
@@ -253,14 +422,14 @@ impl DirectSession {
     ///    packets
     #[inline]
     pub async fn wait_for_resume(&self) {
-        if let Some(resumed) = self.forward_pause.next() {
+        if !self.forward_gate.is_open() {
             log::debug!(
                 "Session {} (node = {}) is awaiting a ResumeForwarding message",
                 self.raw.id,
                 self.owner.default_id
             );
 
-            resumed.await;
+            self.forward_gate.opened().await;
         }
     }
 
@@ -329,6 +498,106 @@ mod tests {
         DirectSession::new_relay(*NODE_ID0, raw).unwrap()
     }
 
+    #[test]
+    fn test_session_params_expiration() {
+        let configured = Duration::from_secs(25);
+        let idle = Duration::from_secs(120);
+        assert_eq!(
+            SessionParams::default().expiration(configured, false, false),
+            configured
+        );
+
+        let params = SessionParams::from(&proto::response::Session {
+            session_ttl_ms: 180_000,
+            ping_interval_ms: 10_000,
+            ..Default::default()
+        });
+        assert_eq!(params.max_forward_size, None);
+        assert_eq!(
+            params.expiration(configured, false, false),
+            Duration::from_secs(10)
+        );
+        // Relay doesn't count forwards as keep-alive.
+        assert_eq!(
+            params.expiration(configured, false, true),
+            Duration::from_secs(10)
+        );
+        // Idle clients ping less often, but not so rarely that the relay forgets them.
+        assert_eq!(
+            params.expiration(idle, true, false),
+            Duration::from_secs(90)
+        );
+    }
+
+    #[test]
+    fn test_session_params_forward_keep_alive() {
+        let configured = Duration::from_secs(25);
+        let params = SessionParams::from(&proto::response::Session {
+            session_ttl_ms: 40_000,
+            ping_interval_ms: 10_000,
+            forward_keep_alive: true,
+            ..Default::default()
+        });
+        assert!(params.forward_keep_alive);
+        assert_eq!(
+            params.expiration(configured, false, false),
+            Duration::from_secs(10)
+        );
+        // Still within the session ttl.
+        assert_eq!(
+            params.expiration(configured, false, true),
+            Duration::from_secs(20)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_direct_session_max_forward_size() {
+        let session = mock_session();
+        session
+            .params
+            .set(SessionParams {
+                max_forward_size: Some(4),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let result = session
+            .send(
+                *NODE_ID0,
+                Payload::from(vec![0u8; 5]),
+                TransportType::Unreliable,
+                false,
+            )
+            .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<SessionError>(),
+            Some(SessionError::PayloadTooLarge { size: 5, max: 4 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_direct_session_forward_rejected() {
+        let session = mock_session();
+        assert_eq!(session.max_forward_size(), None);
+
+        session.on_forward_rejected(&proto::control::ForwardRejected {
+            slot: 4,
+            code: proto::StatusCode::PayloadTooLarge.into(),
+            size: 10,
+            max_size: 8,
+        });
+        assert_eq!(session.max_forward_size(), Some(8));
+
+        session
+            .params
+            .set(SessionParams {
+                max_forward_size: Some(16),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(session.max_forward_size(), Some(8));
+    }
+
     #[tokio::test]
     async fn test_direct_session_forwards_add_remove_entry() {
         let session = mock_session();
@@ -447,4 +716,25 @@ mod tests {
         assert_eq!(entry.identities[0], node2.identities[0]);
         assert_eq!(entry.identities[1], node2.identities[1]);
     }
+
+    #[tokio::test]
+    async fn test_direct_session_slot_epoch_change() {
+        let session = mock_session();
+        let node = NodeEntry::<NodeId> {
+            default_id: *NODE_ID1,
+            identities: vec![*NODE_ID1],
+        };
+
+        assert!(session.update_slot_epoch(7).is_empty());
+        session.register(node, 4);
+        assert!(session.update_slot_epoch(0).is_empty());
+        assert!(session.update_slot_epoch(7).is_empty());
+        assert_eq!(session.find_slot(&NODE_ID1).unwrap(), 4);
+
+        let stale = session.update_slot_epoch(8);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].default_id, *NODE_ID1);
+        assert!(session.get_by_slot(4).is_none());
+        assert!(session.find_slot(&NODE_ID1).is_none());
+    }
 }
@@ -0,0 +1,138 @@
+//! Slows down forwarding on a session, while the relay server reports, that forwards
+//! from this Node pile up in its queue.
+//!
+//! Each `Congestion` report increases delay before sending the next packet. Without
+//! reports the delay is halved every [`RECOVERY_INTERVAL`], until it disappears.
+use parking_lot::Mutex;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+use ya_relay_core::runtime::Instant;
+
+use ya_relay_proto::proto::control::Congestion;
+
+const MIN_DELAY: Duration = Duration::from_micros(500);
+const MAX_DELAY: Duration = Duration::from_millis(50);
+const RECOVERY_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Default)]
+pub(crate) struct Pacer {
+    state: Arc<Mutex<PacerState>>,
+}
+
+#[derive(Default)]
+struct PacerState {
+    delay: Duration,
+    updated: Option<Instant>,
+    reports: u64,
+}
+
+impl Pacer {
+    pub fn on_congestion(&self, report: &Congestion) {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        let delay = state.delay_at(now);
+        // Dropped forwards are lost data, so back off faster than for a long queue.
+        let delay = match report.dropped {
+            0 => delay * 3 / 2,
+            _ => delay * 2,
+        };
+
+        state.delay = delay.clamp(MIN_DELAY, MAX_DELAY);
+        state.updated = Some(now);
+        state.reports += 1;
+        log::debug!(
+            "Relay reported congestion (queue: {}, dropped: {}), pacing forwards every {:?}",
+            report.queue_depth,
+            report.dropped,
+            state.delay
+        );
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.state.lock().delay_at(Instant::now())
+    }
+
+    /// Number of `Congestion` reports received.
+    pub fn reports(&self) -> u64 {
+        self.state.lock().reports
+    }
+
+    pub async fn pace(&self) {
+        let delay = self.delay();
+        if !delay.is_zero() {
+            ya_relay_core::runtime::sleep(delay).await;
+        }
+    }
+}
+
+impl PacerState {
+    fn delay_at(&self, now: Instant) -> Duration {
+        let updated = match self.updated {
+            Some(updated) => updated,
+            None => return Duration::ZERO,
+        };
+        let halvings =
+            now.saturating_duration_since(updated).as_millis() / RECOVERY_INTERVAL.as_millis();
+        let delay = u32::try_from(halvings)
+            .ok()
+            .and_then(|halvings| 1u32.checked_shl(halvings))
+            .map(|divisor| self.delay / divisor)
+            .unwrap_or_default();
+
+        match delay < MIN_DELAY {
+            true => Duration::ZERO,
+            false => delay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(dropped: u32) -> Congestion {
+        Congestion {
+            queue_depth: 10,
+            dropped,
+        }
+    }
+
+    #[test]
+    fn test_pacer_backoff() {
+        let pacer = Pacer::default();
+        assert_eq!(pacer.delay(), Duration::ZERO);
+
+        pacer.on_congestion(&report(0));
+        assert_eq!(pacer.delay(), MIN_DELAY);
+        pacer.on_congestion(&report(1));
+        assert_eq!(pacer.delay(), MIN_DELAY * 2);
+        pacer.on_congestion(&report(0));
+        assert_eq!(pacer.delay(), MIN_DELAY * 3);
+
+        for _ in 0..20 {
+            pacer.on_congestion(&report(1));
+        }
+        assert_eq!(pacer.delay(), MAX_DELAY);
+        assert_eq!(pacer.reports(), 23);
+    }
+
+    #[test]
+    fn test_pacer_recovery() {
+        let now = Instant::now();
+        let state = PacerState {
+            delay: MIN_DELAY * 4,
+            updated: Some(now),
+            reports: 1,
+        };
+
+        assert_eq!(state.delay_at(now), MIN_DELAY * 4);
+        assert_eq!(state.delay_at(now + RECOVERY_INTERVAL), MIN_DELAY * 2);
+        assert_eq!(state.delay_at(now + RECOVERY_INTERVAL * 2), MIN_DELAY);
+        assert_eq!(state.delay_at(now + RECOVERY_INTERVAL * 3), Duration::ZERO);
+        assert_eq!(
+            state.delay_at(now + Duration::from_secs(3600)),
+            Duration::ZERO
+        );
+    }
+}
@@ -5,17 +5,19 @@ use std::net::SocketAddr;
 use std::pin::Pin;
 use std::rc::Weak;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use futures::channel::oneshot::{self, Sender};
 use futures::future::{FutureExt, LocalBoxFuture};
 use futures::stream::{Stream, StreamExt};
 use log::log;
-use tokio::task::spawn_local;
-use tokio::time::{Duration, Instant};
+use ya_relay_core::clock::{system_clock, ClockRef};
+use ya_relay_core::runtime::{spawn_local, Instant};
 use ya_relay_core::session::Session;
 
 use crate::direct_session::DirectSession;
 use crate::raw_session::RawSession;
+use crate::relay_selection::RelayLoad;
 
 use crate::session::SessionLayer;
 use ya_relay_proto::codec;
@@ -140,6 +142,16 @@ pub trait Handler {
     ) -> Option<LocalBoxFuture<'static, ()>>;
 }
 
+/// Request was answered with a status code other than `Ok`.
+#[derive(thiserror::Error, Clone, Copy, Debug)]
+#[error("Request failed with code {0}")]
+pub struct StatusError(pub i32);
+
+/// Response didn't come within the timeout.
+#[derive(thiserror::Error, Clone, Copy, Debug)]
+#[error("Request timed out after {} ms", .0.as_millis())]
+pub struct RequestTimeout(pub Duration);
+
 /// Dispatched packet wrapper
 pub struct Dispatched<T> {
     pub session_id: Vec<u8>,
@@ -150,40 +162,74 @@ pub struct Dispatched<T> {
 /// Facility for dispatching and awaiting response packets
 #[derive(Clone)]
 pub struct Dispatcher {
+    clock: ClockRef,
     seen: Arc<Mutex<Instant>>,
+    /// Last Forward sent over the session.
+    forwarded: Arc<Mutex<Option<Instant>>>,
     ping: Arc<Mutex<Duration>>,
+    /// Last load reported by the relay server.
+    load: Arc<Mutex<Option<RelayLoad>>>,
     responses: Arc<Mutex<HashMap<u64, ResponseSender>>>,
     error_handlers: Arc<Mutex<HashMap<i32, ErrorHandler>>>,
 }
 
 impl Default for Dispatcher {
     fn default() -> Self {
+        Self::new(system_clock())
+    }
+}
+
+impl Dispatcher {
+    pub fn new(clock: ClockRef) -> Self {
         Self {
-            seen: Arc::new(Mutex::new(Instant::now())),
+            seen: Arc::new(Mutex::new(clock.now())),
+            forwarded: Default::default(),
             ping: Arc::new(Mutex::new(Duration::MAX)),
+            load: Default::default(),
             responses: Default::default(),
             error_handlers: Default::default(),
+            clock,
         }
     }
-}
 
-impl Dispatcher {
+    pub fn clock(&self) -> &ClockRef {
+        &self.clock
+    }
+
     pub fn update_seen(&self) {
-        *self.seen.lock().unwrap() = Instant::now();
+        *self.seen.lock().unwrap() = self.clock.now();
+    }
+
+    pub fn update_forwarded(&self) {
+        *self.forwarded.lock().unwrap() = Some(self.clock.now());
     }
 
     pub fn update_ping(&self, ping: Duration) {
         *self.ping.lock().unwrap() = ping;
     }
 
+    pub fn update_load(&self, load: Option<&proto::Load>) {
+        if let Some(load) = load {
+            *self.load.lock().unwrap() = Some(load.into());
+        }
+    }
+
     pub fn last_seen(&self) -> Instant {
         *self.seen.lock().unwrap()
     }
 
+    pub fn last_forwarded(&self) -> Option<Instant> {
+        *self.forwarded.lock().unwrap()
+    }
+
     pub fn last_ping(&self) -> Duration {
         *self.ping.lock().unwrap()
     }
 
+    pub fn last_load(&self) -> Option<RelayLoad> {
+        *self.load.lock().unwrap()
+    }
+
     /// Registers a response code handler
     pub fn handle_error<
         F: Fn(i32, SessionLayer, std::sync::Weak<DirectSession>) -> ErrorHandlerResult + 'static,
@@ -249,13 +295,13 @@ impl Dispatcher {
         }
 
         async move {
-            let response = tokio::time::timeout(timeout, rx)
+            let response = ya_relay_core::runtime::timeout(timeout, rx)
                 .await
-                .map_err(|_| anyhow::anyhow!("Request timed out after {} ms", timeout.as_millis()))?
+                .map_err(|_| RequestTimeout(timeout))?
                 .map_err(|_| anyhow::anyhow!("Request cancelled"))?;
 
             if response.code != proto::StatusCode::Ok as i32 {
-                anyhow::bail!("Request failed with code {}", response.code);
+                return Err(StatusError(response.code).into());
             }
 
             let packet: T = response
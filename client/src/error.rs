@@ -1,14 +1,112 @@
 use anyhow::Error;
+use derive_more::Display;
+use std::convert::TryFrom;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use ya_relay_core::clock::Elapsed;
 use ya_relay_core::server_session::SessionId;
 use ya_relay_core::NodeId;
+use ya_relay_proto::proto;
 
+#[cfg(feature = "virtual-tcp")]
 use super::transport::tcp_registry::TcpState;
+use crate::dispatch::{RequestTimeout, StatusError};
 use crate::session::session_state::SessionState;
 
 pub type SessionResult<T> = Result<T, SessionError>;
+pub type ClientResult<T> = Result<T, ClientError>;
+
+/// Error returned by [`Client`](crate::Client) methods. Variants tell apart failures,
+/// which call for different reactions, see [`ClientError::is_transient`].
+#[derive(thiserror::Error, Clone, Debug)]
+pub enum ClientError {
+    /// Relay server or other Node answered with an error or broke the protocol.
+    #[error("{0}")]
+    Protocol(String),
+    /// Session with the relay server or other Node couldn't be established or was lost.
+    #[error("{0}")]
+    Session(#[from] SessionError),
+    /// Virtual TCP connection couldn't be opened or failed.
+    #[error("{0}")]
+    VirtualTcp(#[from] TcpError),
+    /// Relay server refused to handle more requests for now.
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+    /// Response didn't come in time.
+    #[error("Timeout: {0}")]
+    Timeout(String),
+    /// Other failures, e.g. of the local crypto provider or caused by invalid arguments.
+    #[error("{0}")]
+    Other(String),
+}
+
+impl ClientError {
+    /// Failure, which may not happen again, so the operation is worth retrying later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::Session(e) => e.is_transient(),
+            ClientError::VirtualTcp(TcpError::Connect(e)) => match e.as_ref() {
+                ConnectError::Session(_, e) => e.is_transient(),
+                ConnectError::Resolve(..) | ConnectError::SynTimeout(_) => true,
+                ConnectError::Tcp(..) | ConnectError::TooManyConnections(..) => false,
+            },
+            ClientError::RateLimited(_) | ClientError::Timeout(_) => true,
+            ClientError::Protocol(_) | ClientError::VirtualTcp(_) | ClientError::Other(_) => false,
+        }
+    }
+
+    fn from_status(code: i32, msg: String) -> Self {
+        match proto::StatusCode::try_from(code).ok() {
+            Some(proto::StatusCode::TooManyRequests) => ClientError::RateLimited(msg),
+            Some(proto::StatusCode::Timeout | proto::StatusCode::GatewayTimeout) => {
+                ClientError::Timeout(msg)
+            }
+            _ => ClientError::Protocol(msg),
+        }
+    }
+}
+
+impl From<RequestError> for ClientError {
+    fn from(e: RequestError) -> Self {
+        ClientError::Session(e.into())
+    }
+}
+
+impl From<ConnectError> for ClientError {
+    fn from(e: ConnectError) -> Self {
+        ClientError::VirtualTcp(e.into())
+    }
+}
+
+/// Recovers the kind of failures reported by the internals with `anyhow`.
+impl From<anyhow::Error> for ClientError {
+    fn from(e: anyhow::Error) -> Self {
+        if let Some(e) = e.downcast_ref::<ClientError>() {
+            return e.clone();
+        }
+        if let Some(e) = e.downcast_ref::<SessionError>() {
+            return e.clone().into();
+        }
+        if let Some(e) = e.downcast_ref::<ConnectError>() {
+            return e.clone().into();
+        }
+        if let Some(e) = e.downcast_ref::<TcpError>() {
+            return e.clone().into();
+        }
+        if let Some(StatusError(code)) = e.downcast_ref::<StatusError>() {
+            return ClientError::from_status(*code, format!("{e:#}"));
+        }
+        if e.downcast_ref::<RequestTimeout>().is_some()
+            || e.downcast_ref::<Elapsed>().is_some()
+            || e.downcast_ref::<tokio::time::error::Elapsed>().is_some()
+        {
+            return ClientError::Timeout(format!("{e:#}"));
+        }
+        ClientError::Other(format!("{e:#}"))
+    }
+}
 
 /// Error returned on user facing API.
 /// TODO: Temporary implementation. This error should have variants according to potential
@@ -22,6 +120,95 @@ pub enum SenderError {
     Session(#[from] SessionError),
     #[error("{0}")]
     Tcp(#[from] TcpError),
+    /// Payload wasn't sent within its TTL and was dropped.
+    #[error("Payload expired after {0:?}")]
+    Expired(Duration),
+    /// Payload was rejected by a [`Middleware`](crate::middleware::Middleware).
+    #[error("Payload rejected: {0}")]
+    Rejected(String),
+    /// Payload expired, while the Node or the relay server paused forwarding.
+    #[error("Forwarding paused by [{0}]")]
+    Paused(NodeId),
+    /// Client is shutting down.
+    #[error("Client is shutting down")]
+    Shutdown,
+}
+
+/// Why a payload wasn't sent. Tells callers, whether sending it again makes sense.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+pub enum DropReason {
+    /// Session or connection with the Node was lost or couldn't be established.
+    /// Sending again establishes a new one.
+    Disconnected,
+    /// Payload expired waiting for room in the send queue, or a limit of connections
+    /// was reached. Worth sending again later, at a lower rate.
+    RateLimited,
+    /// Other side paused forwarding. Sending again succeeds once it resumes.
+    Paused,
+    /// Client is shutting down, sending again won't succeed.
+    Shutdown,
+    /// Payload itself can't be sent, e.g. it's too large or a middleware rejected it.
+    Refused,
+}
+
+impl SenderError {
+    pub fn reason(&self) -> DropReason {
+        match self {
+            SenderError::Session(e) => session_drop_reason(e),
+            SenderError::Tcp(e) => tcp_drop_reason(e),
+            SenderError::Expired(_) => DropReason::RateLimited,
+            SenderError::Rejected(_) => DropReason::Refused,
+            SenderError::Paused(_) => DropReason::Paused,
+            SenderError::Shutdown => DropReason::Shutdown,
+        }
+    }
+
+    /// Sending the same payload again may succeed.
+    pub fn is_transient(&self) -> bool {
+        !matches!(self.reason(), DropReason::Shutdown | DropReason::Refused)
+    }
+}
+
+fn session_drop_reason(e: &SessionError) -> DropReason {
+    match e {
+        SessionError::PayloadTooLarge { .. } => DropReason::Refused,
+        // Operations are aborted with the cancellation token on shutdown.
+        SessionError::Aborted(_) => DropReason::Shutdown,
+        _ => DropReason::Disconnected,
+    }
+}
+
+fn connect_drop_reason(e: &ConnectError) -> DropReason {
+    match e {
+        ConnectError::Session(_, e) => session_drop_reason(e),
+        ConnectError::TooManyConnections(..) => DropReason::RateLimited,
+        _ => DropReason::Disconnected,
+    }
+}
+
+/// Virtual TCP errors wrap the ones, which caused them.
+fn tcp_drop_reason(e: &TcpError) -> DropReason {
+    match e {
+        TcpError::Connect(e) => connect_drop_reason(e),
+        TcpError::Generic { source, .. } => error_drop_reason(source.as_ref()),
+        _ => DropReason::Disconnected,
+    }
+}
+
+fn error_drop_reason(e: &(dyn std::error::Error + 'static)) -> DropReason {
+    if let Some(e) = e.downcast_ref::<TcpError>() {
+        return tcp_drop_reason(e);
+    }
+    if let Some(e) = e.downcast_ref::<ConnectError>() {
+        return connect_drop_reason(e);
+    }
+    if let Some(e) = e.downcast_ref::<SessionError>() {
+        return session_drop_reason(e);
+    }
+    match e.source() {
+        Some(source) => error_drop_reason(source),
+        None => DropReason::Disconnected,
+    }
 }
 
 /// TODO: Organize this error better. We should be able to make decision
@@ -48,6 +235,8 @@ pub enum SessionError {
     Aborted(String),
     #[error("Not Found: {0}")]
     NotFound(String),
+    #[error("Payload of {size} B is too large, max={max}")]
+    PayloadTooLarge { size: usize, max: usize },
     #[error("Relay error: {0}")]
     Relay(String),
     #[error("Unexpected error: {0}")]
@@ -61,6 +250,19 @@ pub enum SessionError {
     Generic(String),
 }
 
+impl SessionError {
+    /// Failure, which may not happen again, so the operation is worth retrying.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            SessionError::Network(_)
+                | SessionError::Timeout(_)
+                | SessionError::Relay(_)
+                | SessionError::Generic(_)
+        )
+    }
+}
+
 /// Error indicates that other Node failed to stick to protocol.
 #[derive(thiserror::Error, Clone, Debug, PartialEq)]
 pub enum ProtocolError {
@@ -76,6 +278,10 @@ pub enum ProtocolError {
     SessionNotFound(SessionId),
     #[error("Request with invalid SessionId {0:x?}. Error: {1}")]
     InvalidSessionId(Vec<u8>, String),
+    #[error("Untrusted relay server: {0}")]
+    UntrustedServer(String),
+    #[error("Node [{0}] presented a public key different from the pinned one")]
+    KeyMismatch(NodeId),
 }
 
 #[derive(thiserror::Error, Clone, Debug, PartialEq)]
@@ -122,10 +328,58 @@ pub enum TcpError {
     Closed,
     #[error("Programming error: {0}")]
     ProgrammingError(String),
+    /// Boxed, since `TcpError` is stored in every channel state.
+    #[error("{0}")]
+    Connect(Box<ConnectError>),
     #[error("{0}")]
     Other(String),
 }
 
+/// Reason of failed attempt to open virtual TCP connection. Tells apart stages at which
+/// establishing can fail, since they call for different reactions.
+#[derive(thiserror::Error, Clone, Debug, PartialEq)]
+pub enum ConnectError {
+    /// Relay server doesn't know the Node or couldn't be asked about it.
+    #[error("Failed to resolve Node [{0}]: {1}")]
+    Resolve(NodeId, String),
+    /// Neither p2p nor relayed session with the Node could be established.
+    #[error("Failed to establish session with [{0}]: {1}")]
+    Session(NodeId, SessionError),
+    /// Session exists, but the Node didn't answer virtual TCP handshake in time.
+    #[error("Virtual TCP handshake with [{0}] timed out")]
+    SynTimeout(NodeId),
+    #[error("Virtual TCP connection with [{0}] failed: {1}")]
+    Tcp(NodeId, String),
+    /// Connection would exceed one of the limits set in `ClientConfig`.
+    #[error("Too many virtual TCP connections to open one with [{0}]: {1}")]
+    TooManyConnections(NodeId, ConnectionLimit),
+}
+
+/// Limit of virtual TCP connections, which was reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+pub enum ConnectionLimit {
+    #[display(fmt = "limit of {} connections in total reached", _0)]
+    Total(usize),
+    #[display(fmt = "limit of {} connections per Node reached", _0)]
+    PerNode(usize),
+}
+
+impl ConnectError {
+    pub(crate) fn from_tcp(node_id: NodeId, error: TcpError) -> Self {
+        match error {
+            TcpError::Connect(e) => *e,
+            e => ConnectError::Tcp(node_id, e.to_string()),
+        }
+    }
+}
+
+impl From<ConnectError> for TcpError {
+    fn from(e: ConnectError) -> Self {
+        TcpError::Connect(Box::new(e))
+    }
+}
+
+#[cfg(feature = "virtual-tcp")]
 #[derive(thiserror::Error, Clone, Debug)]
 pub enum TcpTransitionError {
     #[error("Connection state transition not allowed from: {0} to {1}")]
@@ -134,6 +388,17 @@ pub enum TcpTransitionError {
     ProgrammingError,
 }
 
+/// Operation was aborted with the client's cancellation token, e.g. during shutdown.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[error("Cancelled")]
+pub struct Cancelled;
+
+impl From<Cancelled> for SessionError {
+    fn from(e: Cancelled) -> Self {
+        SessionError::Aborted(e.to_string())
+    }
+}
+
 impl From<TransitionError> for SessionError {
     fn from(value: TransitionError) -> Self {
         SessionError::Internal(value.to_string())
@@ -175,3 +440,74 @@ impl<T, E> ResultExt<T, E> for Result<T, E> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_client_error_from_anyhow() {
+        let e: ClientError =
+            anyhow::Error::from(StatusError(proto::StatusCode::TooManyRequests as i32))
+                .context("Finding Node")
+                .into();
+        assert!(matches!(e, ClientError::RateLimited(_)), "{e:?}");
+        assert!(e.is_transient());
+
+        let e: ClientError =
+            anyhow::Error::from(StatusError(proto::StatusCode::NotFound as i32)).into();
+        assert!(matches!(e, ClientError::Protocol(_)), "{e:?}");
+        assert!(!e.is_transient());
+
+        let e: ClientError = anyhow::Error::from(RequestTimeout(Duration::from_secs(3))).into();
+        assert!(matches!(e, ClientError::Timeout(_)), "{e:?}");
+
+        let e: ClientError = anyhow::Error::from(SessionError::NotFound("Node".to_string())).into();
+        assert_eq!(
+            e.to_string(),
+            SessionError::NotFound("Node".to_string()).to_string()
+        );
+        assert!(matches!(e, ClientError::Session(SessionError::NotFound(_))));
+
+        let e: ClientError = anyhow::anyhow!("crypto failure").into();
+        assert!(matches!(e, ClientError::Other(_)), "{e:?}");
+    }
+
+    #[test]
+    fn test_drop_reason() {
+        let node_id = NodeId::default();
+        let wrap = |e: ConnectError| TcpError::Generic {
+            msg: "Establishing connection failed".to_string(),
+            source: Arc::new(TcpError::from(e)),
+        };
+
+        let e = SenderError::from(wrap(ConnectError::TooManyConnections(
+            node_id,
+            ConnectionLimit::PerNode(1),
+        )));
+        assert_eq!(e.reason(), DropReason::RateLimited);
+        assert!(e.is_transient());
+
+        let e = SenderError::from(wrap(ConnectError::Session(
+            node_id,
+            SessionError::Aborted("Cancelled".to_string()),
+        )));
+        assert_eq!(e.reason(), DropReason::Shutdown);
+        assert!(!e.is_transient());
+
+        let e = SenderError::from(SessionError::PayloadTooLarge { size: 5, max: 4 });
+        assert_eq!(e.reason(), DropReason::Refused);
+        assert!(!e.is_transient());
+
+        assert_eq!(
+            SenderError::from(TcpError::Closed).reason(),
+            DropReason::Disconnected
+        );
+        assert_eq!(SenderError::Paused(node_id).reason(), DropReason::Paused);
+        assert_eq!(
+            SenderError::Expired(Duration::from_secs(1)).reason(),
+            DropReason::RateLimited
+        );
+    }
+}
@@ -0,0 +1,544 @@
+//! Forward error correction for unreliable forwards.
+//!
+//! With [`crate::ClientBuilder::fec`], packets sent through unreliable channels are grouped
+//! and each group is followed by a parity packet, XOR of all packets in the group. Receiver
+//! recovers a single packet lost in a group without waiting for a retransmission, at the cost
+//! of one additional packet per group. Streaming applications can tolerate some relay loss
+//! this way, trading bandwidth for latency.
+//!
+//! Parameters are negotiated with each Node: the sender offers its group size and the receiver
+//! accepts the smaller of the offered one and its own. Until then, and for Nodes which never
+//! accept, packets are sent as they are. Nodes decode groups regardless of their own config,
+//! but accept offers only with FEC enabled. Data packets are delivered as soon as they arrive,
+//! so order of packets isn't changed, except for the recovered ones.
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ya_relay_core::runtime::Instant;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::Payload;
+
+use crate::client::Forwarded;
+use crate::node_map::NodeMap;
+
+const MAGIC: &[u8; 4] = b"yaFE";
+const KIND_OFFER: u8 = 0;
+const KIND_ACCEPT: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_PARITY: u8 = 3;
+const NEGOTIATION_SIZE: usize = MAGIC.len() + 2;
+const DATA_HEADER_SIZE: usize = MAGIC.len() + 1 + 2 + 1 + 1;
+const PARITY_HEADER_SIZE: usize = DATA_HEADER_SIZE + 2;
+
+pub const MIN_GROUP_SIZE: u8 = 2;
+pub const MAX_GROUP_SIZE: u8 = 32;
+/// Offers are repeated, if not accepted in this time.
+const OFFER_INTERVAL: Duration = Duration::from_secs(1);
+/// Node is assumed not to support FEC after that many offers.
+const MAX_OFFERS: u8 = 3;
+/// Incomplete groups kept per Node. Older ones are forgotten.
+const MAX_GROUPS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FecConfig {
+    /// Data packets protected by a single parity packet. Smaller groups survive
+    /// more loss, but add more overhead. Clamped to [`MIN_GROUP_SIZE`]..=[`MAX_GROUP_SIZE`].
+    pub group_size: u8,
+}
+
+impl Default for FecConfig {
+    fn default() -> Self {
+        FecConfig { group_size: 4 }
+    }
+}
+
+impl FecConfig {
+    fn group_size(&self) -> u8 {
+        self.group_size.clamp(MIN_GROUP_SIZE, MAX_GROUP_SIZE)
+    }
+}
+
+enum Peer {
+    Offered { at: Instant, offers: u8 },
+    Enabled(Encoder),
+    Unsupported,
+}
+
+struct Encoder {
+    group_size: u8,
+    group: u16,
+    index: u8,
+    parity: Vec<u8>,
+    len_parity: u16,
+}
+
+impl Encoder {
+    fn new(group_size: u8) -> Self {
+        Encoder {
+            group_size,
+            group: 0,
+            index: 0,
+            parity: Vec::new(),
+            len_parity: 0,
+        }
+    }
+
+    fn encode(&mut self, mut payload: Payload) -> Vec<Payload> {
+        let len = match u16::try_from(payload.len()) {
+            Ok(len) => len,
+            // Doesn't fit in a single packet anyway.
+            Err(_) => return vec![payload],
+        };
+        xor_into(&mut self.parity, payload.as_ref());
+        self.len_parity ^= len;
+
+        let mut header = header(KIND_DATA, self.group, self.index, self.group_size);
+        payload.prepend(&header);
+        self.index += 1;
+        if self.index < self.group_size {
+            return vec![payload];
+        }
+
+        header[MAGIC.len()] = KIND_PARITY;
+        header[MAGIC.len() + 3] = self.group_size;
+        let mut parity = Vec::with_capacity(PARITY_HEADER_SIZE + self.parity.len());
+        parity.extend_from_slice(&header);
+        parity.extend_from_slice(&self.len_parity.to_be_bytes());
+        parity.append(&mut self.parity);
+
+        self.group = self.group.wrapping_add(1);
+        self.index = 0;
+        self.len_parity = 0;
+        vec![payload, parity.into()]
+    }
+}
+
+struct Group {
+    id: u16,
+    size: u8,
+    data: Vec<Option<Vec<u8>>>,
+    parity: Option<(u16, Vec<u8>)>,
+}
+
+impl Group {
+    fn received(&self) -> usize {
+        self.data.iter().filter(|d| d.is_some()).count()
+    }
+
+    /// Reconstructs the only missing packet.
+    fn recover(&mut self) -> Option<Vec<u8>> {
+        if self.received() + 1 != self.data.len() {
+            return None;
+        }
+        let (mut len, mut packet) = self.parity.take()?;
+        for data in self.data.iter().flatten() {
+            xor_into(&mut packet, data);
+            len ^= data.len() as u16;
+        }
+        let missing = self.data.iter().position(Option::is_none)?;
+        packet.truncate(len as usize);
+        self.data[missing] = Some(packet.clone());
+        Some(packet)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    peers: NodeMap<Peer>,
+    groups: NodeMap<VecDeque<Group>>,
+}
+
+/// Outcome of dispatching a forward.
+pub(crate) enum Decoded {
+    /// Not a FEC packet.
+    Other(Forwarded),
+    /// Data packet, possibly together with a packet recovered from its group.
+    Forwards(Vec<Forwarded>),
+    /// Offer accepted, answer has to be sent back to the Node.
+    Reply(NodeId, Vec<u8>),
+    Done,
+}
+
+#[derive(Clone)]
+pub(crate) struct Fec {
+    config: Option<FecConfig>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Fec {
+    pub fn new(config: Option<FecConfig>) -> Self {
+        Fec {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// Group size negotiated with the Node, if FEC is used for it.
+    pub fn group_size(&self, node_id: NodeId) -> Option<u8> {
+        match self.state.lock().peers.get(&node_id) {
+            Some(Peer::Enabled(encoder)) => Some(encoder.group_size),
+            _ => None,
+        }
+    }
+
+    /// Packets to send in place of `payload`.
+    pub fn encode(&self, node_id: NodeId, payload: Payload) -> Vec<Payload> {
+        let config = match self.config {
+            Some(config) => config,
+            None => return vec![payload],
+        };
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        let peer = state
+            .peers
+            .get_or_insert_with(node_id, || Peer::Offered { at: now, offers: 0 });
+        match peer {
+            Peer::Enabled(encoder) => encoder.encode(payload),
+            Peer::Unsupported => vec![payload],
+            Peer::Offered { at, offers } => {
+                if *offers > 0 && now.duration_since(*at) < OFFER_INTERVAL {
+                    return vec![payload];
+                }
+                if *offers >= MAX_OFFERS {
+                    log::debug!("Node [{node_id}] doesn't support FEC");
+                    *peer = Peer::Unsupported;
+                    return vec![payload];
+                }
+                *at = now;
+                *offers += 1;
+                vec![negotiation(KIND_OFFER, config.group_size()).into(), payload]
+            }
+        }
+    }
+
+    pub fn decode(&self, forwarded: Forwarded) -> Decoded {
+        let payload = forwarded.payload.as_ref();
+        if payload.len() < NEGOTIATION_SIZE || !payload.starts_with(MAGIC) {
+            return Decoded::Other(forwarded);
+        }
+        match payload[MAGIC.len()] {
+            KIND_OFFER => {
+                let offered = payload[MAGIC.len() + 1];
+                // Sender starts numbering groups again.
+                self.state.lock().groups.remove(&forwarded.node_id);
+                match self.config {
+                    Some(config) => {
+                        let size = offered.clamp(MIN_GROUP_SIZE, config.group_size());
+                        Decoded::Reply(forwarded.node_id, negotiation(KIND_ACCEPT, size))
+                    }
+                    None => Decoded::Done,
+                }
+            }
+            KIND_ACCEPT => {
+                let size = payload[MAGIC.len() + 1];
+                self.on_accept(forwarded.node_id, size);
+                Decoded::Done
+            }
+            KIND_DATA => match decode_header(payload) {
+                Some((group, index, size)) => self.on_data(forwarded, group, index, size),
+                None => Decoded::Done,
+            },
+            KIND_PARITY => match decode_parity(payload) {
+                Some((group, size, len, parity)) => {
+                    let parity = (len, parity.to_vec());
+                    match self.on_parity(&forwarded, group, size, parity) {
+                        Some(recovered) => Decoded::Forwards(vec![recovered]),
+                        None => Decoded::Done,
+                    }
+                }
+                None => Decoded::Done,
+            },
+            _ => Decoded::Done,
+        }
+    }
+
+    fn on_accept(&self, node_id: NodeId, size: u8) {
+        let config = match self.config {
+            Some(config) => config,
+            None => return,
+        };
+        let mut state = self.state.lock();
+        match state.peers.get(&node_id) {
+            Some(Peer::Offered { .. }) => {
+                let size = size.clamp(MIN_GROUP_SIZE, config.group_size());
+                log::debug!("Using FEC with Node [{node_id}], group size: {size}");
+                state
+                    .peers
+                    .insert(node_id, Peer::Enabled(Encoder::new(size)));
+            }
+            // Duplicated or unsolicited answer.
+            _ => (),
+        }
+    }
+
+    fn on_data(&self, mut forwarded: Forwarded, group: u16, index: u8, size: u8) -> Decoded {
+        let data = forwarded.payload.as_ref()[DATA_HEADER_SIZE..].to_vec();
+        let mut state = self.state.lock();
+        let group = match group_entry(&mut state, forwarded.node_id, group, size) {
+            Some(group) => group,
+            None => return Decoded::Done,
+        };
+        let slot = match group.data.get_mut(index as usize) {
+            Some(slot) => slot,
+            None => return Decoded::Done,
+        };
+        if slot.is_some() {
+            // Recovered before, or duplicated on the way.
+            return Decoded::Done;
+        }
+        *slot = Some(data.clone());
+        let recovered = group.recover();
+
+        forwarded.payload = data.into();
+        let mut forwards = vec![forwarded.clone()];
+        if let Some(packet) = recovered {
+            forwarded.payload = packet.into();
+            forwards.push(forwarded);
+        }
+        Decoded::Forwards(forwards)
+    }
+
+    fn on_parity(
+        &self,
+        forwarded: &Forwarded,
+        group: u16,
+        size: u8,
+        parity: (u16, Vec<u8>),
+    ) -> Option<Forwarded> {
+        let mut state = self.state.lock();
+        let group = group_entry(&mut state, forwarded.node_id, group, size)?;
+        group.parity = Some(parity);
+        let packet = group.recover()?;
+        let mut recovered = forwarded.clone();
+        recovered.payload = packet.into();
+        Some(recovered)
+    }
+}
+
+/// Finds the group, or starts it forgetting the oldest one. `None` for malformed packets.
+fn group_entry(state: &mut State, node_id: NodeId, id: u16, size: u8) -> Option<&mut Group> {
+    if !(MIN_GROUP_SIZE..=MAX_GROUP_SIZE).contains(&size) {
+        return None;
+    }
+    let groups = state.groups.get_or_default(node_id);
+    let position = match groups.iter().position(|g| g.id == id) {
+        Some(position) => position,
+        None => {
+            if groups.len() >= MAX_GROUPS {
+                groups.pop_front();
+            }
+            groups.push_back(Group {
+                id,
+                size,
+                data: vec![None; size as usize],
+                parity: None,
+            });
+            groups.len() - 1
+        }
+    };
+    let group = &mut groups[position];
+    (group.size == size).then_some(group)
+}
+
+fn xor_into(target: &mut Vec<u8>, data: &[u8]) {
+    if target.len() < data.len() {
+        target.resize(data.len(), 0);
+    }
+    target.iter_mut().zip(data).for_each(|(t, d)| *t ^= d);
+}
+
+fn header(kind: u8, group: u16, index: u8, size: u8) -> [u8; DATA_HEADER_SIZE] {
+    let mut header = [0u8; DATA_HEADER_SIZE];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[MAGIC.len()] = kind;
+    header[MAGIC.len() + 1..MAGIC.len() + 3].copy_from_slice(&group.to_be_bytes());
+    header[MAGIC.len() + 3] = index;
+    header[MAGIC.len() + 4] = size;
+    header
+}
+
+fn negotiation(kind: u8, group_size: u8) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(NEGOTIATION_SIZE);
+    packet.extend_from_slice(MAGIC);
+    packet.push(kind);
+    packet.push(group_size);
+    packet
+}
+
+/// Returns group, index and group size.
+fn decode_header(bytes: &[u8]) -> Option<(u16, u8, u8)> {
+    if bytes.len() < DATA_HEADER_SIZE {
+        return None;
+    }
+    let at = MAGIC.len() + 1;
+    Some((
+        u16::from_be_bytes(bytes[at..at + 2].try_into().ok()?),
+        bytes[at + 2],
+        bytes[at + 3],
+    ))
+}
+
+/// Returns group, group size, XOR of data packet lengths and the parity.
+fn decode_parity(bytes: &[u8]) -> Option<(u16, u8, u16, &[u8])> {
+    if bytes.len() < PARITY_HEADER_SIZE {
+        return None;
+    }
+    let (group, _, size) = decode_header(bytes)?;
+    let len = u16::from_be_bytes(
+        bytes[DATA_HEADER_SIZE..PARITY_HEADER_SIZE]
+            .try_into()
+            .ok()?,
+    );
+    Some((group, size, len, &bytes[PARITY_HEADER_SIZE..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+    use ya_relay_core::server_session::TransportType;
+
+    use crate::node_map::MAX_NODES;
+
+    fn forwarded(node_id: NodeId, payload: Payload) -> Forwarded {
+        Forwarded {
+            transport: TransportType::Unreliable,
+            node_id,
+            payload,
+            session: None,
+            received_at: SystemTime::now(),
+        }
+    }
+
+    fn payloads(decoded: Decoded) -> Vec<Vec<u8>> {
+        match decoded {
+            Decoded::Forwards(forwards) => {
+                forwards.into_iter().map(|f| f.payload.into_vec()).collect()
+            }
+            Decoded::Done => vec![],
+            _ => panic!("unexpected FEC packet"),
+        }
+    }
+
+    /// Negotiates FEC between two Nodes and returns the sender's packets carrying `data`.
+    fn negotiate(sender: &Fec, receiver: &Fec, data: &[u8]) -> Vec<Payload> {
+        let (from, to) = (NodeId::from([1; 20]), NodeId::from([2; 20]));
+        let mut packets = sender.encode(to, data.to_vec().into());
+        assert_eq!(packets.len(), 2);
+        let offer = packets.remove(0);
+        match receiver.decode(forwarded(from, offer)) {
+            Decoded::Reply(node_id, accept) => {
+                assert_eq!(node_id, from);
+                assert!(matches!(
+                    sender.decode(forwarded(to, accept.into())),
+                    Decoded::Done
+                ));
+            }
+            _ => panic!("offer not accepted"),
+        }
+        packets
+    }
+
+    #[test]
+    fn test_recover() {
+        let sender = Fec::new(Some(FecConfig { group_size: 8 }));
+        let receiver = Fec::new(Some(FecConfig { group_size: 3 }));
+        let (from, to) = (NodeId::from([1; 20]), NodeId::from([2; 20]));
+
+        // Sent as it is, before the receiver accepted.
+        let plain = negotiate(&sender, &receiver, b"plain").remove(0);
+        assert_eq!(plain.into_vec(), b"plain".to_vec());
+        assert_eq!(sender.group_size(to), Some(3));
+
+        let mut packets = vec![];
+        for data in [&b"first"[..], b"second packet", b"3rd"] {
+            packets.extend(sender.encode(to, data.to_vec().into()));
+        }
+        assert_eq!(packets.len(), 4);
+
+        // Second packet lost.
+        packets.remove(1);
+        let mut received = vec![];
+        for packet in packets {
+            received.extend(payloads(receiver.decode(forwarded(from, packet))));
+        }
+        assert_eq!(
+            received,
+            vec![
+                b"first".to_vec(),
+                b"3rd".to_vec(),
+                b"second packet".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_late_packet() {
+        let sender = Fec::new(Some(FecConfig::default()));
+        let receiver = Fec::new(None);
+        let (from, to) = (NodeId::from([1; 20]), NodeId::from([2; 20]));
+
+        // Receiver without FEC enabled doesn't accept.
+        let offer = sender.encode(to, b"data".to_vec().into()).remove(0);
+        assert!(matches!(
+            receiver.decode(forwarded(from, offer)),
+            Decoded::Done
+        ));
+        assert_eq!(sender.group_size(to), None);
+
+        let mut encoder = Encoder::new(2);
+        let mut packets = encoder.encode(b"a".to_vec().into());
+        packets.extend(encoder.encode(b"bb".to_vec().into()));
+        let late = packets.remove(0);
+        let mut received = vec![];
+        for packet in packets {
+            received.extend(payloads(receiver.decode(forwarded(from, packet))));
+        }
+        assert_eq!(received, vec![b"bb".to_vec(), b"a".to_vec()]);
+        // Already recovered.
+        assert!(payloads(receiver.decode(forwarded(from, late))).is_empty());
+
+        assert!(matches!(
+            receiver.decode(forwarded(from, b"data".to_vec().into())),
+            Decoded::Other(_)
+        ));
+    }
+
+    #[test]
+    fn test_active_node_survives_overflow() {
+        let receiver = Fec::new(None);
+        let node = |n: usize| {
+            let mut id = [0xff; 20];
+            id[..8].copy_from_slice(&n.to_be_bytes());
+            NodeId::from(id)
+        };
+        let active = NodeId::from([1; 20]);
+        let start_group = |node_id: NodeId| {
+            let packet = Encoder::new(2).encode(b"x".to_vec().into()).remove(0);
+            receiver.decode(forwarded(node_id, packet));
+        };
+
+        for n in 0..MAX_NODES - 1 {
+            start_group(node(n));
+        }
+        let mut encoder = Encoder::new(2);
+        let mut packets = encoder.encode(b"a".to_vec().into());
+        packets.extend(encoder.encode(b"bb".to_vec().into()));
+        let parity = packets.pop().unwrap();
+        assert_eq!(
+            payloads(receiver.decode(forwarded(active, packets.remove(0)))),
+            vec![b"a".to_vec()]
+        );
+
+        // Idle Nodes are forgotten first, the group of the active one is still recovered.
+        start_group(node(MAX_NODES));
+        assert_eq!(
+            payloads(receiver.decode(forwarded(active, parity))),
+            vec![b"bb".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Restricting which Nodes can open virtual TCP connections to this client.
+//!
+//! [`Firewall`] is checked for connection requests (TCP SYN) received from other Nodes,
+//! before they reach the TCP stack, and again for payloads of inbound connections in the
+//! ingress router. Connections this client opens itself are never filtered, so
+//! the application can still connect to Nodes, which can't connect to it.
+//!
+//! A Node is permitted, when it's not denied, the allowlist (if any) contains it
+//! and the predicate (if any) accepts it.
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use ya_relay_core::NodeId;
+use ya_relay_stack::smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket};
+
+pub type FilterFn = Rc<dyn Fn(NodeId) -> bool>;
+
+/// Access control list of Nodes allowed to connect. Open by default.
+///
+/// ```rust
+/// use ya_relay_client::firewall::Firewall;
+/// use ya_relay_core::NodeId;
+///
+/// let trusted: NodeId = [1; 20].into();
+/// let firewall = Firewall::default()
+///     .allow([trusted])
+///     .filter(|node_id| node_id.into_array()[0] != 0);
+/// assert!(firewall.permits(trusted));
+/// ```
+#[derive(Clone, Default)]
+pub struct Firewall {
+    allow: Option<HashSet<NodeId>>,
+    deny: HashSet<NodeId>,
+    filter: Option<FilterFn>,
+}
+
+impl Firewall {
+    /// Permits only Nodes on the allowlist. Can be called multiple times.
+    pub fn allow(mut self, nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        self.allow
+            .get_or_insert_with(Default::default)
+            .extend(nodes);
+        self
+    }
+
+    /// Never permits these Nodes. Takes precedence over the allowlist.
+    pub fn deny(mut self, nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        self.deny.extend(nodes);
+        self
+    }
+
+    /// Permits only Nodes accepted by `filter`. Called for each connection request,
+    /// so it should be cheap.
+    pub fn filter(mut self, filter: impl Fn(NodeId) -> bool + 'static) -> Self {
+        self.filter = Some(Rc::new(filter));
+        self
+    }
+
+    /// No rules, all Nodes are permitted.
+    pub fn is_open(&self) -> bool {
+        self.allow.is_none() && self.deny.is_empty() && self.filter.is_none()
+    }
+
+    pub fn permits(&self, node_id: NodeId) -> bool {
+        !self.deny.contains(&node_id)
+            && self
+                .allow
+                .as_ref()
+                .map_or(true, |allow| allow.contains(&node_id))
+            && self.filter.as_ref().map_or(true, |filter| filter(node_id))
+    }
+
+    /// Whether the IP packet from `node_id` should be dropped, because it opens
+    /// a connection, which the Node is not permitted to.
+    pub(crate) fn rejects(&self, node_id: NodeId, packet: &[u8]) -> bool {
+        !self.is_open() && is_connection_request(packet) && !self.permits(node_id)
+    }
+}
+
+/// TCP SYN without ACK.
+fn is_connection_request(packet: &[u8]) -> bool {
+    let (protocol, payload) = match IpVersion::of_packet(packet) {
+        Ok(IpVersion::Ipv4) => match Ipv4Packet::new_checked(packet) {
+            Ok(ip) => (ip.next_header(), ip.payload()),
+            Err(_) => return false,
+        },
+        Ok(IpVersion::Ipv6) => match Ipv6Packet::new_checked(packet) {
+            Ok(ip) => (ip.next_header(), ip.payload()),
+            Err(_) => return false,
+        },
+        Err(_) => return false,
+    };
+    if protocol != IpProtocol::Tcp {
+        return false;
+    }
+    match TcpPacket::new_checked(payload) {
+        Ok(tcp) => tcp.syn() && !tcp.ack() && !tcp.rst(),
+        Err(_) => false,
+    }
+}
+
+impl std::fmt::Debug for Firewall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Firewall")
+            .field("allow", &self.allow)
+            .field("deny", &self.deny)
+            .field("filter", &self.filter.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_stack::smoltcp::wire::{
+        IpAddress, Ipv6Address, Ipv6Repr, TcpControl, TcpRepr, TcpSeqNumber,
+    };
+
+    fn node(n: u8) -> NodeId {
+        [n; 20].into()
+    }
+
+    fn packet(control: TcpControl, ack: bool) -> Vec<u8> {
+        let src = Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let dst = Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+        let tcp = TcpRepr {
+            src_port: 49152,
+            dst_port: 1,
+            control,
+            seq_number: TcpSeqNumber(1),
+            ack_number: ack.then_some(TcpSeqNumber(1)),
+            window_len: 1024,
+            window_scale: None,
+            max_seg_size: None,
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            payload: &[],
+        };
+        let ip = Ipv6Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Tcp,
+            payload_len: tcp.buffer_len(),
+            hop_limit: 64,
+        };
+        let mut buffer = vec![0u8; ip.buffer_len() + tcp.buffer_len()];
+        let mut ip_packet = Ipv6Packet::new_unchecked(&mut buffer);
+        ip.emit(&mut ip_packet);
+        tcp.emit(
+            &mut TcpPacket::new_unchecked(ip_packet.payload_mut()),
+            &IpAddress::Ipv6(src),
+            &IpAddress::Ipv6(dst),
+            &Default::default(),
+        );
+        buffer
+    }
+
+    #[test]
+    fn test_permits() {
+        assert!(Firewall::default().is_open());
+        assert!(Firewall::default().permits(node(1)));
+
+        let firewall = Firewall::default()
+            .allow([node(1), node(2)])
+            .deny([node(2)]);
+        assert!(firewall.permits(node(1)));
+        assert!(!firewall.permits(node(2)));
+        assert!(!firewall.permits(node(3)));
+
+        let firewall = Firewall::default().filter(|node_id| node_id != node(1));
+        assert!(!firewall.permits(node(1)));
+        assert!(firewall.permits(node(2)));
+
+        let firewall = Firewall::default().allow([]);
+        assert!(!firewall.is_open());
+        assert!(!firewall.permits(node(1)));
+    }
+
+    #[test]
+    fn test_rejects() {
+        let firewall = Firewall::default().deny([node(1)]);
+        let syn = packet(TcpControl::Syn, false);
+        assert!(firewall.rejects(node(1), &syn));
+        assert!(!firewall.rejects(node(2), &syn));
+
+        // Only opening connections is filtered.
+        assert!(!firewall.rejects(node(1), &packet(TcpControl::Syn, true)));
+        assert!(!firewall.rejects(node(1), &packet(TcpControl::None, true)));
+        assert!(!firewall.rejects(node(1), &[0u8; 8]));
+        assert!(!Firewall::default().rejects(node(1), &syn));
+    }
+}
@@ -0,0 +1,320 @@
+//! Saving the client to disk and starting it again later, e.g. by short-lived CLI tools.
+//!
+//! [`Client::hibernate`] stops the client without closing its relay server session and
+//! saves the session, Nodes found on the relay and the configuration to a file.
+//! [`Client::restore`] starts the client listening on the same address and continues
+//! the saved session, so neither the handshake with the relay, nor finding the saved
+//! Nodes is repeated.
+//!
+//! The secret key isn't saved, it has to be given on restore, e.g. from the keystore used
+//! with [`crate::ClientBuilder::secret_from_keystore`]. The file holds the key authenticating
+//! forwards in the relay session, so on Unix it's readable only by its owner.
+//!
+//! The relay accepts the session only from the address it was established from, until
+//! it expires. If the relay doesn't answer a ping in the session, it's established anew.
+//! Saved Nodes are dropped, if the file is older than their info is kept for.
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use url::Url;
+
+use ya_relay_core::forward_auth::ForwardKey;
+use ya_relay_core::runtime::SystemTime;
+use ya_relay_core::server_session::{NodeInfo, SessionId};
+use ya_relay_core::NodeId;
+
+use crate::client::{Client, ClientBuilder, ClientConfig, FailFast};
+use crate::direct_session::SessionParams;
+use crate::fec::FecConfig;
+use crate::multipath::MultipathMode;
+use crate::pacing::PacingConfig;
+use crate::relay_selection::RelaySelectionConfig;
+use crate::unsolicited::UnsolicitedPolicy;
+#[cfg(feature = "virtual-tcp")]
+use crate::watchdog::WatchdogConfig;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Hibernation {
+    /// Identity of the client, the secret key given on restore has to match it.
+    pub node_id: NodeId,
+    #[serde(with = "unix_time")]
+    pub saved_at: SystemTime,
+    pub config: HibernatedConfig,
+    /// Not set, if the client wasn't connected to the relay.
+    pub session: Option<HibernatedSession>,
+    pub nodes: Vec<NodeInfo>,
+}
+
+/// Relay server session, continued after restore.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HibernatedSession {
+    pub relay: SocketAddr,
+    pub session_id: SessionId,
+    pub forward_key: Option<ForwardKey>,
+    pub params: SessionParams,
+    pub public_addr: Option<SocketAddr>,
+}
+
+/// Settings of [`ClientBuilder`], which can be saved. Others, e.g. middleware or
+/// the clock, have to be set again on restore.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HibernatedConfig {
+    pub srv_addr: SocketAddr,
+    /// Address the client listened on, after resolving the configured one.
+    pub bind_addr: SocketAddr,
+    pub auto_connect: Option<FailFast>,
+    pub session_expiration: Duration,
+    pub idle_session_expiration: Duration,
+    pub resume_state: Option<PathBuf>,
+    pub multipath: MultipathMode,
+    pub fec: Option<FecConfig>,
+    pub pacing: Option<PacingConfig>,
+    pub relay_selection: Option<RelaySelectionConfig>,
+    pub relay_outage_grace: Duration,
+    #[cfg(feature = "virtual-tcp")]
+    pub watchdog: Option<WatchdogConfig>,
+    pub unsolicited: UnsolicitedPolicy,
+}
+
+impl HibernatedConfig {
+    pub fn new(config: &ClientConfig, bind_addr: SocketAddr) -> Self {
+        let auto_connect = config
+            .auto_connect
+            .then_some(match config.auto_connect_fail_fast {
+                true => FailFast::Yes,
+                false => FailFast::No,
+            });
+        HibernatedConfig {
+            srv_addr: config.srv_addr,
+            bind_addr,
+            auto_connect,
+            session_expiration: config.session_expiration,
+            idle_session_expiration: config.idle_session_expiration,
+            resume_state: config.resume_state.clone(),
+            multipath: config.multipath,
+            fec: config.fec,
+            pacing: config.pacing,
+            relay_selection: config.relay_selection.clone(),
+            relay_outage_grace: config.relay_outage_grace,
+            #[cfg(feature = "virtual-tcp")]
+            watchdog: config.watchdog,
+            unsolicited: config.unsolicited,
+        }
+    }
+
+    pub fn builder(&self) -> anyhow::Result<ClientBuilder> {
+        let mut builder = ClientBuilder::from_url(Url::parse(&format!("udp://{}", self.srv_addr))?)
+            .listen(Url::parse(&format!("udp://{}", self.bind_addr))?)
+            .expire_session_after(self.session_expiration)
+            .expire_idle_session_after(self.idle_session_expiration)
+            .multipath(self.multipath)
+            .relay_outage_grace(self.relay_outage_grace)
+            .unsolicited_forwards(self.unsolicited);
+        if let Some(fail_fast) = self.auto_connect {
+            builder = builder.connect(fail_fast);
+        }
+        if let Some(path) = &self.resume_state {
+            builder = builder.resume_state(path);
+        }
+        if let Some(fec) = self.fec {
+            builder = builder.fec(fec);
+        }
+        if let Some(pacing) = self.pacing {
+            builder = builder.pacing(pacing);
+        }
+        if let Some(relay_selection) = &self.relay_selection {
+            builder = builder.relay_selection(relay_selection.clone());
+        }
+        #[cfg(feature = "virtual-tcp")]
+        if let Some(watchdog) = self.watchdog {
+            builder = builder.watchdog(watchdog);
+        }
+        Ok(builder)
+    }
+}
+
+impl Hibernation {
+    /// Returns `None` if there is no saved state.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    /// Replaces the file in one step, so crashing while saving keeps the previous state.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::remove_file(&tmp).ok();
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&tmp)?.write_all(&serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub(crate) async fn capture(client: &Client) -> anyhow::Result<Self> {
+        let layer = &client.transport.session_layer;
+        let bind_addr = client.bind_addr().await?;
+        let session = match layer.find_session(layer.relays.current()).await {
+            Some(session) => Some(HibernatedSession {
+                relay: session.raw.remote,
+                session_id: session.raw.id,
+                forward_key: session.forward_key.get().copied(),
+                params: session.params.get().copied().unwrap_or_default(),
+                public_addr: layer.get_public_addr().await,
+            }),
+            None => None,
+        };
+
+        Ok(Hibernation {
+            node_id: client.node_id(),
+            saved_at: SystemTime::now(),
+            config: HibernatedConfig::new(&layer.config, bind_addr),
+            session,
+            nodes: layer.registry.entries().await,
+        })
+    }
+}
+
+/// Restores saved Nodes and the relay server session, before the client connects.
+/// Returns false, if the session wasn't restored.
+pub(crate) async fn restore(client: &Client, hibernation: &Hibernation) -> bool {
+    let layer = &client.transport.session_layer;
+    let age = hibernation.saved_at.elapsed().unwrap_or_default();
+
+    let node_info_ttl = layer.config.registry_config.node_info_ttl.to_std();
+    if matches!(node_info_ttl, Ok(ttl) if age < ttl) {
+        for info in hibernation.nodes.iter().cloned() {
+            let node_id = info.default_node_id();
+            if let Err(e) = layer.registry.update_entry(info).await {
+                log::debug!("[hibernate]: unable to restore Node [{node_id}]: {e}");
+            }
+        }
+    }
+
+    let saved = match &hibernation.session {
+        Some(saved) if layer.relays.addrs().contains(&saved.relay) => saved.clone(),
+        _ => return false,
+    };
+    layer.relays.set_current(saved.relay);
+    match layer.restore_server_session(saved).await {
+        Ok(_) => true,
+        Err(e) => {
+            log::info!("Unable to restore relay session saved {age:?} ago, reconnecting: {e}");
+            false
+        }
+    }
+}
+
+/// Same format as `std::time::SystemTime`, which the browser clock doesn't implement serde for.
+mod unix_time {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::Duration;
+    use ya_relay_core::runtime::SystemTime;
+
+    #[derive(Serialize, Deserialize)]
+    struct Repr {
+        secs_since_epoch: u64,
+        nanos_since_epoch: u32,
+    }
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let since = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        Repr {
+            secs_since_epoch: since.as_secs(),
+            nanos_since_epoch: since.subsec_nanos(),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let repr = Repr::deserialize(deserializer)?;
+        let since = Duration::new(repr.secs_since_epoch, repr.nanos_since_epoch);
+        Ok(SystemTime::UNIX_EPOCH + since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_config() {
+        let config = ClientBuilder::from_url(Url::parse("udp://127.0.0.1:7464").unwrap())
+            .connect(FailFast::No)
+            .expire_session_after(Duration::from_secs(10))
+            .pacing(PacingConfig::default())
+            .relay_selection(RelaySelectionConfig::new(["127.0.0.1:7465"
+                .parse()
+                .unwrap()]))
+            .build_config()
+            .await
+            .unwrap();
+        let saved = HibernatedConfig::new(&config, "127.0.0.1:11500".parse().unwrap());
+        assert_eq!(saved.auto_connect, Some(FailFast::No));
+
+        let restored = saved.builder().unwrap().build_config().await.unwrap();
+        assert_eq!(restored.bind_url.as_str(), "udp://127.0.0.1:11500");
+        assert_eq!(
+            HibernatedConfig::new(&restored, "127.0.0.1:11500".parse().unwrap()),
+            saved
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_load() {
+        let path =
+            std::env::temp_dir().join(format!("ya-relay-hibernate-{}.json", rand::random::<u64>()));
+        assert!(Hibernation::load(&path).unwrap().is_none());
+
+        let config = ClientBuilder::from_url(Url::parse("udp://127.0.0.1:7464").unwrap())
+            .build_config()
+            .await
+            .unwrap();
+        let hibernation = Hibernation {
+            node_id: config.node_id,
+            saved_at: SystemTime::now(),
+            config: HibernatedConfig::new(&config, "127.0.0.1:11500".parse().unwrap()),
+            session: Some(HibernatedSession {
+                relay: config.srv_addr,
+                session_id: SessionId::generate(),
+                forward_key: Some([7; 32]),
+                params: SessionParams {
+                    session_ttl: Some(Duration::from_secs(60)),
+                    ..Default::default()
+                },
+                public_addr: None,
+            }),
+            nodes: vec![],
+        };
+        hibernation.save(&path).unwrap();
+
+        let loaded = Hibernation::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.node_id, hibernation.node_id);
+        assert_eq!(loaded.config, hibernation.config);
+        assert_eq!(loaded.session, hibernation.session);
+        assert_eq!(loaded.saved_at, hibernation.saved_at);
+        // Files saved before are read the same way.
+        let json = serde_json::to_string(&hibernation.saved_at).unwrap();
+        let mut deserializer = serde_json::Deserializer::from_str(&json);
+        let saved_at = unix_time::deserialize(&mut deserializer).unwrap();
+        assert_eq!(saved_at, hibernation.saved_at);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,110 @@
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ya_relay_core::identity::{Identity, IdentityKey};
+use ya_relay_core::NodeId;
+
+use crate::error::ProtocolError;
+
+/// Public keys expected from specific Nodes during the session handshake.
+/// See [`crate::ClientBuilder::pin_key`].
+///
+/// Node presenting a different key for a pinned NodeId, e.g. substituted with help
+/// of a malicious relay, fails the handshake. Nodes without a pinned key are accepted
+/// with any key matching their NodeId. Clones share the pins.
+#[derive(Clone, Default)]
+pub struct KeyPins {
+    pins: Arc<RwLock<HashMap<NodeId, IdentityKey>>>,
+}
+
+impl KeyPins {
+    /// Replaces the key pinned for the Node before.
+    pub fn pin(&self, node_id: NodeId, key: IdentityKey) {
+        self.pins.write().insert(node_id, key);
+    }
+
+    /// Returns `false`, if no key was pinned for the Node.
+    pub fn unpin(&self, node_id: NodeId) -> bool {
+        self.pins.write().remove(&node_id).is_some()
+    }
+
+    pub fn get(&self, node_id: NodeId) -> Option<IdentityKey> {
+        self.pins.read().get(&node_id).cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pins.read().is_empty()
+    }
+
+    /// Checks identities recovered from the challenge response of a Node.
+    pub(crate) fn check(&self, identities: &[Identity]) -> Result<(), ProtocolError> {
+        let pins = self.pins.read();
+        if pins.is_empty() {
+            return Ok(());
+        }
+        for identity in identities {
+            match pins.get(&identity.node_id) {
+                Some(pinned) if !same_key(pinned, &identity.public_key) => {
+                    return Err(ProtocolError::KeyMismatch(identity.node_id));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn same_key(left: &IdentityKey, right: &IdentityKey) -> bool {
+    left.scheme() == right.scheme() && left.to_bytes() == right.to_bytes()
+}
+
+impl std::fmt::Debug for KeyPins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.pins.read().keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_core::crypto::{Crypto, CryptoProvider, FallbackCryptoProvider};
+
+    async fn identity() -> Identity {
+        let crypto = FallbackCryptoProvider::default();
+        let crypto = crypto
+            .get(crypto.default_id().await.unwrap())
+            .await
+            .unwrap();
+        let public_key = crypto.public_key().await.unwrap();
+        Identity {
+            node_id: NodeId::from(public_key.address().as_ref()),
+            public_key: public_key.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_key_pins() {
+        let pins = KeyPins::default();
+        let node = identity().await;
+        let other = identity().await;
+        assert!(pins.check(&[node.clone()]).is_ok());
+
+        pins.pin(node.node_id, node.public_key.clone());
+        assert!(pins.check(&[node.clone(), other.clone()]).is_ok());
+
+        // Same NodeId presenting a different key.
+        let substituted = Identity {
+            node_id: node.node_id,
+            public_key: other.public_key.clone(),
+        };
+        assert_eq!(
+            pins.check(&[other.clone(), substituted]),
+            Err(ProtocolError::KeyMismatch(node.node_id))
+        );
+
+        assert!(pins.unpin(node.node_id));
+        assert!(!pins.unpin(node.node_id));
+        assert!(pins.is_empty());
+    }
+}
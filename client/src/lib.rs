@@ -2,23 +2,83 @@
 #![cfg_attr(not(test), deny(unused_crate_dependencies))]
 //#![deny(missing_docs)]
 
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("Building for wasm32 requires the `wasm` feature");
+#[cfg(all(target_arch = "wasm32", feature = "virtual-tcp"))]
+compile_error!("`virtual-tcp` isn't available on wasm32, build with `--no-default-features`");
+
+// Used only by the binaries.
+#[cfg(feature = "cli")]
+use {clap as _, env_logger as _};
+
+pub mod bandwidth;
+mod cancel;
 mod client;
 mod config;
+pub mod diagnostics;
 mod direct_session;
 mod dispatch;
 mod encryption;
 mod error;
+pub mod fec;
+#[cfg(feature = "virtual-tcp")]
+pub mod firewall;
+pub mod hibernate;
+mod key_pins;
+pub mod maintenance;
+pub mod mesh;
 pub mod metrics;
+pub mod middleware;
+pub mod multipath;
+pub mod naming;
+mod nat;
+mod node_map;
+pub mod pacing;
+pub mod pubsub;
 mod raw_session;
+pub mod relay_selection;
+#[cfg(feature = "virtual-tcp")]
+pub mod resume;
+pub mod retry;
+pub mod rotation;
 mod routing_session;
+mod server_trust;
 mod session;
+#[cfg(feature = "socks")]
+pub mod socks;
+#[cfg(feature = "virtual-tcp")]
+pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
 mod transport;
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub mod tun;
+#[cfg(feature = "virtual-tcp")]
+pub mod tuning;
+pub mod unsolicited;
+#[cfg(feature = "virtual-tcp")]
+pub mod watchdog;
 
-pub use client::{Client, ClientBuilder, FailFast, GenericSender, SessionError};
+#[cfg(feature = "virtual-tcp")]
+pub use client::ConnectProgress;
+pub use client::{
+    Client, ClientBuilder, ClientError, ClientResult, ConnectError, ConnectionLimit, DropReason,
+    FailFast, GenericSender, IngressConfig, IngressOverflow, IngressStats, SenderError,
+    SessionError,
+};
+pub use key_pins::KeyPins;
+pub use server_trust::ServerTrust;
 
 /// This module is a public re-export cryptographic abstractions.
 pub use ya_relay_core::crypto;
 
+/// Proof-of-work solving progress and cancellation, used during the session handshake.
+pub mod challenge {
+    pub use ya_relay_core::challenge::{
+        estimate_duration, expected_attempts, CancellationToken, SolveProgress, SolverOptions,
+    };
+}
+
 #[cfg(any(test, feature = "test-utils"))]
 #[allow(missing_docs)]
 pub mod testing;
@@ -30,6 +90,7 @@ pub mod model {
     #[doc(inline)]
     pub use ya_relay_proto::proto::response::Node;
 
+    #[cfg(feature = "virtual-tcp")]
     #[doc(inline)]
     pub use ya_relay_stack::{SocketDesc, SocketState};
 
@@ -39,7 +100,9 @@ pub mod model {
     #[doc(inline)]
     pub use ya_relay_core::session::Session;
 
-    pub use crate::raw_session::SessionDesc;
+    pub use crate::raw_session::{SessionDesc, SessionType};
+
+    pub use crate::nat::{ConnectionAdvice, ConnectionHints, NatInfo, NatType};
 
     pub use ya_relay_core::server_session::SessionId;
 
@@ -50,7 +113,9 @@ pub mod model {
 /// Re-exports several channel related items from the client module and proto.
 pub mod channels {
     #[doc(inline)]
-    pub use crate::client::{ForwardReceiver, ForwardSender, Forwarded};
+    pub use crate::client::{
+        ForwardOptions, ForwardReceiver, ForwardSender, Forwarded, ForwardedSession, PoolConfig,
+    };
 
     #[doc(inline)]
     pub use ya_relay_proto::codec::forward::PrefixedStream;
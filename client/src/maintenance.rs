@@ -0,0 +1,74 @@
+//! Planned shutdowns and restarts announced by the relay server.
+//!
+//! Operators announce maintenance ahead of time and the server sends a notice to all
+//! connected Nodes. Applications receive them from [`crate::Client::maintenance_notices`]
+//! and can switch to the alternative relay, or wait for the restart, before the session
+//! with the server is lost.
+use std::time::Duration;
+
+use ya_relay_core::runtime::SystemTime;
+use ya_relay_proto::proto::control;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaintenanceKind {
+    Shutdown,
+    Restart,
+}
+
+/// Maintenance announced by the relay server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MaintenanceNotice {
+    pub kind: MaintenanceKind,
+    pub scheduled_at: SystemTime,
+    /// Relay to use in the meantime, e.g. `udp://host:port`.
+    pub alternative_relay: Option<String>,
+    pub message: String,
+}
+
+impl MaintenanceNotice {
+    /// Time left until the maintenance starts. Zero, if it's already due.
+    pub fn remaining(&self) -> Duration {
+        self.scheduled_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+}
+
+impl From<control::Maintenance> for MaintenanceNotice {
+    fn from(maintenance: control::Maintenance) -> Self {
+        let kind = match maintenance.kind() {
+            control::maintenance::Kind::Shutdown => MaintenanceKind::Shutdown,
+            control::maintenance::Kind::Restart => MaintenanceKind::Restart,
+        };
+        MaintenanceNotice {
+            kind,
+            scheduled_at: SystemTime::UNIX_EPOCH
+                + Duration::from_millis(maintenance.scheduled_at_ms),
+            alternative_relay: Some(maintenance.alternative_relay)
+                .filter(|relay| !relay.is_empty()),
+            message: maintenance.message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_control() {
+        let notice = MaintenanceNotice::from(control::Maintenance {
+            kind: control::maintenance::Kind::Restart.into(),
+            scheduled_at_ms: 1_700_000_000_123,
+            alternative_relay: String::new(),
+            message: "upgrade".to_string(),
+        });
+        assert_eq!(notice.kind, MaintenanceKind::Restart);
+        assert_eq!(
+            notice.scheduled_at,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)
+        );
+        assert_eq!(notice.alternative_relay, None);
+        assert_eq!(notice.remaining(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,439 @@
+//! Routing datagrams through other Nodes.
+//!
+//! Nodes which can reach neither each other nor the relay server can still exchange
+//! unreliable packets through a third Node, reachable by both, which runs [`Mesh`] with
+//! [`MeshConfig::forward`] enabled. Packets are signed by their origin, so the destination
+//! and forwarders know who sent them regardless of the route. The hop limit set by the origin
+//! is signed too, and each forwarder counts the hops and drops packets which reached it.
+//! Destination and forwarders accept each packet once, based on its signed sequence
+//! number, so replayed packets are dropped and misconfigured routes can't loop, even
+//! if a forwarder resets the hop count.
+//!
+//! Payloads are encrypted only on each hop, so forwarders can read them.
+use anyhow::{anyhow, bail};
+use futures::future::AbortHandle;
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::rc::Rc;
+use tokio::sync::mpsc;
+
+use ya_relay_core::crypto::recover_data_signer;
+use ya_relay_core::runtime::SystemTime;
+use ya_relay_core::utils::spawn_local_abortable;
+use ya_relay_core::NodeId;
+
+use crate::client::{Client, Forwarded, ForwardedSession, GenericSender, TransportType};
+use crate::raw_session::SessionType;
+use crate::transport::ForwardReceiver;
+
+/// Prefix of routed packets.
+pub const MAGIC: &[u8; 4] = b"yaMR";
+pub const DEFAULT_HOP_LIMIT: u8 = 4;
+
+const NODE_ID_SIZE: usize = 20;
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 8 + 2 * NODE_ID_SIZE + 2;
+/// Packets waiting to be forwarded. Above that, received packets aren't forwarded.
+const FORWARD_QUEUE_SIZE: usize = 256;
+/// Packets of an origin accepted out of order, behind the newest one.
+const REPLAY_WINDOW: u64 = 64;
+/// Origins with tracked sequence numbers. Above that, the least recently seen one is forgotten.
+const MAX_ORIGINS: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct MeshConfig {
+    /// Forward packets between other Nodes.
+    pub forward: bool,
+    /// Number of times packets sent by this Node can be forwarded.
+    pub hop_limit: u8,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        MeshConfig {
+            forward: false,
+            hop_limit: DEFAULT_HOP_LIMIT,
+        }
+    }
+}
+
+/// Stops routing when dropped.
+pub struct Mesh {
+    shared: Rc<Shared>,
+    handles: Vec<AbortHandle>,
+}
+
+impl Mesh {
+    /// Takes over the `Client`'s forward receiver. Routed packets addressed to this Node
+    /// are passed to the returned receiver as unreliable packets from their origin,
+    /// together with all other traffic.
+    pub async fn start(
+        client: &Client,
+        config: MeshConfig,
+    ) -> anyhow::Result<(Mesh, ForwardReceiver)> {
+        let receiver = client
+            .forward_receiver()
+            .await
+            .ok_or_else(|| anyhow!("Forward receiver already taken"))?;
+
+        let shared = Rc::new(Shared {
+            client: client.clone(),
+            config,
+            routes: Default::default(),
+            sequence: Cell::new(initial_sequence()),
+            replay: Default::default(),
+        });
+        let (passthrough_tx, passthrough_rx) = mpsc::channel(receiver.max_capacity());
+        let (forward_tx, forward_rx) = mpsc::channel(FORWARD_QUEUE_SIZE);
+
+        let handles = vec![
+            spawn_local_abortable(shared.clone().ingress(receiver, passthrough_tx, forward_tx)),
+            spawn_local_abortable(shared.clone().egress(forward_rx)),
+        ];
+        Ok((Mesh { shared, handles }, passthrough_rx))
+    }
+
+    /// Sends packets to `destination` through `via`, which has to be a forwarder.
+    pub fn add_route(&self, destination: NodeId, via: NodeId) {
+        self.shared.routes.lock().insert(destination, via);
+    }
+
+    pub fn remove_route(&self, destination: NodeId) -> Option<NodeId> {
+        self.shared.routes.lock().remove(&destination)
+    }
+
+    pub fn route(&self, destination: NodeId) -> Option<NodeId> {
+        self.shared.routes.lock().get(&destination).copied()
+    }
+
+    /// Sends signed unreliable packet to `destination`, through the Node set with
+    /// [`Mesh::add_route`] or directly, if there's no route.
+    pub async fn send(&self, destination: NodeId, payload: &[u8]) -> anyhow::Result<()> {
+        let client = &self.shared.client;
+        let mut packet = Packet {
+            hop_limit: self.shared.config.hop_limit,
+            hops: 0,
+            sequence: self.shared.next_sequence(),
+            origin: client.node_id(),
+            destination,
+            signature: vec![],
+            payload: payload.to_vec(),
+        };
+        packet.signature = client.sign(&packet.signed_data()).await?;
+
+        let next_hop = self.route(destination).unwrap_or(destination);
+        self.shared.send(next_hop, &packet).await
+    }
+}
+
+impl Drop for Mesh {
+    fn drop(&mut self) {
+        self.handles.iter().for_each(|handle| handle.abort());
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Packet {
+    /// Forwarders passing the packet on at most, set by the origin.
+    hop_limit: u8,
+    /// Forwarders which passed the packet on so far.
+    hops: u8,
+    /// Increasing with each packet sent by the origin.
+    sequence: u64,
+    origin: NodeId,
+    destination: NodeId,
+    signature: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut packet =
+            Vec::with_capacity(HEADER_SIZE + self.signature.len() + self.payload.len());
+        packet.extend_from_slice(MAGIC);
+        packet.push(self.hop_limit);
+        packet.push(self.hops);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.origin.into_array());
+        packet.extend_from_slice(&self.destination.into_array());
+        packet.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&self.signature);
+        packet.extend_from_slice(&self.payload);
+        packet
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Packet> {
+        if bytes.len() < HEADER_SIZE || !bytes.starts_with(MAGIC) {
+            return None;
+        }
+        let node_id = |at: usize| NodeId::from(&bytes[at..at + NODE_ID_SIZE]);
+        let sequence = u64::from_be_bytes(bytes[6..14].try_into().ok()?);
+        let origin = node_id(14);
+        let destination = node_id(14 + NODE_ID_SIZE);
+        let sig_len = u16::from_be_bytes(bytes[HEADER_SIZE - 2..HEADER_SIZE].try_into().ok()?);
+        let sig_end = HEADER_SIZE + sig_len as usize;
+
+        Some(Packet {
+            hop_limit: bytes[4],
+            hops: bytes[5],
+            sequence,
+            origin,
+            destination,
+            signature: bytes.get(HEADER_SIZE..sig_end)?.to_vec(),
+            payload: bytes[sig_end..].to_vec(),
+        })
+    }
+
+    /// Hop count is changed by forwarders, so it isn't signed.
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        data.extend_from_slice(MAGIC);
+        data.push(self.hop_limit);
+        data.extend_from_slice(&self.sequence.to_be_bytes());
+        data.extend_from_slice(&self.origin.into_array());
+        data.extend_from_slice(&self.destination.into_array());
+        data.extend_from_slice(&self.payload);
+        data
+    }
+
+    /// Checks if the packet was signed by its origin.
+    fn verify(&self) -> anyhow::Result<()> {
+        let signer = recover_data_signer(&self.signed_data(), &self.signature)?.node_id;
+        if signer != self.origin {
+            bail!("signed by [{signer}] instead of [{}]", self.origin);
+        }
+        Ok(())
+    }
+}
+
+/// Sequence numbers start at the current time in microseconds, so packets sent
+/// after a restart aren't taken for replayed ones.
+fn initial_sequence() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// Sequence numbers of packets recently accepted from a single origin.
+#[derive(Default)]
+struct Window {
+    newest: u64,
+    /// Bit `n` is set, if packet `newest - n` was accepted.
+    accepted: u64,
+    last_seen: u64,
+}
+
+/// Accepts each packet of an origin once.
+#[derive(Default)]
+struct ReplayGuard {
+    windows: HashMap<NodeId, Window>,
+    /// Incremented with each check, orders origins by their last packet.
+    counter: u64,
+}
+
+impl ReplayGuard {
+    fn accept(&mut self, origin: NodeId, sequence: u64) -> bool {
+        self.counter += 1;
+        if !self.windows.contains_key(&origin) && self.windows.len() >= MAX_ORIGINS {
+            let oldest = self
+                .windows
+                .iter()
+                .min_by_key(|(_, window)| window.last_seen)
+                .map(|(node_id, _)| *node_id);
+            if let Some(node_id) = oldest {
+                self.windows.remove(&node_id);
+            }
+        }
+
+        let window = self.windows.entry(origin).or_insert_with(|| Window {
+            newest: sequence,
+            accepted: 0,
+            last_seen: 0,
+        });
+        window.last_seen = self.counter;
+
+        if sequence > window.newest {
+            let shift = sequence - window.newest;
+            window.accepted = match shift < REPLAY_WINDOW {
+                true => (window.accepted << shift) | 1,
+                false => 1,
+            };
+            window.newest = sequence;
+            return true;
+        }
+
+        let age = window.newest - sequence;
+        if age >= REPLAY_WINDOW || window.accepted & (1 << age) != 0 {
+            return false;
+        }
+        window.accepted |= 1 << age;
+        true
+    }
+}
+
+struct Shared {
+    client: Client,
+    config: MeshConfig,
+    routes: Mutex<HashMap<NodeId, NodeId>>,
+    sequence: Cell<u64>,
+    replay: Mutex<ReplayGuard>,
+}
+
+impl Shared {
+    fn next_sequence(&self) -> u64 {
+        let sequence = self.sequence.get();
+        self.sequence.set(sequence + 1);
+        sequence
+    }
+
+    async fn send(&self, next_hop: NodeId, packet: &Packet) -> anyhow::Result<()> {
+        let mut tx = self.client.forward_unreliable(next_hop).await?;
+        tx.send(packet.encode().into()).await?;
+        Ok(())
+    }
+
+    async fn ingress(
+        self: Rc<Self>,
+        mut receiver: ForwardReceiver,
+        passthrough: mpsc::Sender<Forwarded>,
+        forward: mpsc::Sender<Packet>,
+    ) {
+        let node_id = self.client.node_id();
+
+        while let Some(forwarded) = receiver.recv().await {
+            let packet = match forwarded.transport {
+                TransportType::Unreliable => Packet::decode(forwarded.payload.as_ref()),
+                _ => None,
+            };
+            let mut packet = match packet {
+                Some(packet) => packet,
+                None => {
+                    passthrough.send(forwarded).await.ok();
+                    continue;
+                }
+            };
+
+            let from = forwarded.node_id;
+            if packet.destination != node_id && !self.config.forward {
+                log::trace!(
+                    "[Mesh] Not forwarding packet from [{}] to [{}]",
+                    packet.origin,
+                    packet.destination
+                );
+                continue;
+            }
+            if let Err(e) = packet.verify() {
+                log::debug!("[Mesh] Dropping packet from [{from}] with invalid signature: {e}");
+                continue;
+            }
+            if !self.replay.lock().accept(packet.origin, packet.sequence) {
+                log::debug!(
+                    "[Mesh] Dropping packet {} from [{}] received again from [{from}]",
+                    packet.sequence,
+                    packet.origin
+                );
+                continue;
+            }
+
+            if packet.destination == node_id {
+                passthrough
+                    .send(Forwarded {
+                        transport: TransportType::Unreliable,
+                        node_id: packet.origin,
+                        payload: packet.payload.into(),
+                        // Session with the Node, which passed the packet on.
+                        session: forwarded.session.map(|session| ForwardedSession {
+                            session_type: SessionType::Relay,
+                            ..session
+                        }),
+                        received_at: forwarded.received_at,
+                    })
+                    .await
+                    .ok();
+                continue;
+            }
+
+            if packet.hops >= packet.hop_limit {
+                log::debug!(
+                    "[Mesh] Hop limit of packet from [{}] to [{}] exceeded",
+                    packet.origin,
+                    packet.destination
+                );
+                continue;
+            }
+            packet.hops += 1;
+
+            // Establishing session with the next hop can take a while, so packets
+            // are forwarded by another task.
+            if let Err(e) = forward.try_send(packet) {
+                log::debug!("[Mesh] Dropping packet from [{from}]: {e}");
+            }
+        }
+    }
+
+    async fn egress(self: Rc<Self>, mut queue: mpsc::Receiver<Packet>) {
+        while let Some(packet) = queue.recv().await {
+            let next_hop = self.routes.lock().get(&packet.destination).copied();
+            let next_hop = next_hop.unwrap_or(packet.destination);
+            if let Err(e) = self.send(next_hop, &packet).await {
+                log::debug!(
+                    "[Mesh] Failed to forward packet from [{}] to [{}]: {e}",
+                    packet.origin,
+                    packet.destination
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_guard() {
+        let origin = NodeId::from([1u8; 20]);
+        let mut guard = ReplayGuard::default();
+
+        assert!(guard.accept(origin, 100));
+        assert!(!guard.accept(origin, 100));
+        assert!(guard.accept(origin, 102));
+        // Reordered packets are accepted once.
+        assert!(guard.accept(origin, 101));
+        assert!(!guard.accept(origin, 101));
+        // Too old to tell.
+        assert!(!guard.accept(origin, 102 - REPLAY_WINDOW));
+
+        assert!(guard.accept(origin, 1000));
+        assert!(!guard.accept(origin, 102));
+        assert!(guard.accept(NodeId::from([2u8; 20]), 102));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let packet = Packet {
+            hop_limit: 3,
+            hops: 1,
+            sequence: 42,
+            origin: NodeId::from([1u8; 20]),
+            destination: NodeId::from([2u8; 20]),
+            signature: vec![7u8; 65],
+            payload: b"payload".to_vec(),
+        };
+        assert_eq!(Packet::decode(&packet.encode()), Some(packet.clone()));
+
+        // Forwarders can't raise the limit without invalidating the signature.
+        let raised = Packet {
+            hop_limit: 10,
+            ..packet.clone()
+        };
+        assert_ne!(raised.signed_data(), packet.signed_data());
+        let forwarded = Packet {
+            hops: 2,
+            ..packet.clone()
+        };
+        assert_eq!(forwarded.signed_data(), packet.signed_data());
+    }
+}
@@ -24,6 +24,9 @@ pub(crate) fn register_metrics() {
     register_counter!("ya-relay.client.session.established");
     register_counter!("ya-relay.client.session.closed");
     register_gauge!("ya-relay.client.public-address");
+    register_counter!("ya-relay.client.forward.misrouted");
+    register_counter!("ya-relay.client.ingress.dropped");
+    register_counter!("ya-relay.client.ingress.paused");
 
     describe_counter!(
         "ya-relay.packet.tcp.outgoing.size",
@@ -51,6 +54,21 @@ pub(crate) fn register_metrics() {
         "Incremented when session (either p2p or relayed) is established.\
         Metric can be used to track stability of connection."
     );
+    describe_counter!(
+        "ya-relay.client.forward.misrouted",
+        Unit::Count,
+        "Forward packets rejected, because their slot didn't match the session they came from."
+    );
+    describe_counter!(
+        "ya-relay.client.ingress.dropped",
+        Unit::Count,
+        "Forwarded payloads dropped, because the forward receiver was full."
+    );
+    describe_counter!(
+        "ya-relay.client.ingress.paused",
+        Unit::Count,
+        "Incremented when reading virtual TCP connections is paused, because the forward receiver is full."
+    );
 }
 
 pub(crate) fn metric_session_established(node_id: NodeId, method: ConnectionMethod) {
@@ -58,5 +76,10 @@ pub(crate) fn metric_session_established(node_id: NodeId, method: ConnectionMeth
     increment_counter!("ya-relay.client.session.established", TARGET_ID => node_id.to_string());
 }
 
+pub(crate) fn metric_forward_misrouted(relay: NodeId) {
+    increment_counter!("ya-relay.client.forward.misrouted", RELAY_ID => relay.to_string());
+}
+
+#[cfg(feature = "virtual-tcp")]
 #[doc(inline)]
 pub use ya_relay_stack::{ChannelMetrics, Ewma, Metrics, TimeWindow};
@@ -0,0 +1,79 @@
+//! Hooks wrapping the egress and ingress paths of forwarded payloads.
+//!
+//! A [`Middleware`] registered with [`ClientBuilder::middleware`](crate::ClientBuilder::middleware)
+//! sees every payload sent through a [`ForwardSender`](crate::channels::ForwardSender) and
+//! every payload received from other Nodes, before the Client dispatches it to streams,
+//! subscriptions or the [`forward_receiver`](crate::Client::forward_receiver). It can
+//! inspect, count or modify payloads in place, or reject them.
+//!
+//! Egress goes through middleware in the order of registration and ingress in reverse
+//! order, so the first registered middleware is the closest to the application.
+//! Payloads of helper protocols (streams, pub/sub, mesh) carried over forward channels
+//! pass through middleware as well.
+use std::rc::Rc;
+
+use ya_relay_core::server_session::TransportType;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::Payload;
+
+use crate::client::Forwarded;
+use crate::error::SenderError;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    /// Outgoing payloads fail to send with [`SenderError::Rejected`],
+    /// incoming ones are dropped.
+    Reject(String),
+}
+
+pub trait Middleware {
+    /// Called before `payload` is sent to `node_id`, which can be a secondary id
+    /// if the channel was requested for it.
+    fn egress(
+        &self,
+        _node_id: NodeId,
+        _transport: TransportType,
+        _payload: &mut Payload,
+    ) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// Called for a payload received from another Node. Reliable payloads arrive
+    /// in pieces read from the virtual TCP connection, not as sent.
+    fn ingress(&self, _forwarded: &mut Forwarded) -> Verdict {
+        Verdict::Pass
+    }
+}
+
+pub type MiddlewareRef = Rc<dyn Middleware>;
+
+pub(crate) fn egress(
+    middleware: &[MiddlewareRef],
+    node_id: NodeId,
+    transport: TransportType,
+    payload: &mut Payload,
+) -> Result<(), SenderError> {
+    for m in middleware {
+        if let Verdict::Reject(reason) = m.egress(node_id, transport, payload) {
+            log::trace!("[Middleware] rejected {transport} payload to [{node_id}]: {reason}");
+            return Err(SenderError::Rejected(reason));
+        }
+    }
+    Ok(())
+}
+
+/// Returns false for rejected payloads.
+pub(crate) fn ingress(middleware: &[MiddlewareRef], forwarded: &mut Forwarded) -> bool {
+    for m in middleware.iter().rev() {
+        if let Verdict::Reject(reason) = m.ingress(forwarded) {
+            log::trace!(
+                "[Middleware] rejected {} payload from [{}]: {reason}",
+                forwarded.transport,
+                forwarded.node_id
+            );
+            return false;
+        }
+    }
+    true
+}
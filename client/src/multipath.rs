@@ -0,0 +1,85 @@
+//! Forwarding to a Node over its P2P session and the relay server at the same time.
+//!
+//! Applies to reliable and transfer channels of Nodes, which have a P2P session and
+//! still a slot on the relay server, e.g. from before the P2P session was established.
+//! Other Nodes are reached over their single path, as without multipath.
+//!
+//! Packets of these channels are virtual TCP segments, so the receiving TCP stack
+//! puts striped packets back in order. Duplicates are dropped by the receiver before
+//! they reach the stack, so they don't look like retransmissions. The receiver does
+//! that only with [`MultipathMode::Duplicate`] in its own config, so both sides should use it.
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use ya_relay_core::NodeId;
+
+use crate::node_map::NodeMap;
+
+/// Packets remembered per Node for dropping duplicates.
+const DEDUP_WINDOW: usize = 128;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultipathMode {
+    /// Only the P2P session is used, if there is one.
+    #[default]
+    Single,
+    /// Each packet is sent over both paths, so it arrives if either of them works.
+    Duplicate,
+    /// Packets are sent over both paths in turns, adding up their throughput.
+    Stripe,
+}
+
+/// Drops the second copy of packets received from a Node.
+#[derive(Clone, Default)]
+pub(crate) struct Dedup {
+    recent: Arc<Mutex<NodeMap<VecDeque<u64>>>>,
+}
+
+impl Dedup {
+    pub fn is_duplicate(&self, node_id: NodeId, packet: &[u8]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        packet.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut recent = self.recent.lock();
+        let window = recent.get_or_default(node_id);
+        // Each packet is sent at most twice. Only the second copy is dropped, so TCP
+        // retransmissions identical to a packet, which already arrived, get through.
+        if let Some(position) = window.iter().position(|h| *h == hash) {
+            window.remove(position);
+            return true;
+        }
+        if window.len() >= DEDUP_WINDOW {
+            window.pop_front();
+        }
+        window.push_back(hash);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup() {
+        let dedup = Dedup::default();
+        let node = |n: u8| NodeId::from([n; 20]);
+        assert!(!dedup.is_duplicate(node(1), b"segment"));
+        assert!(dedup.is_duplicate(node(1), b"segment"));
+        assert!(!dedup.is_duplicate(node(2), b"segment"));
+        // Retransmission.
+        assert!(!dedup.is_duplicate(node(1), b"segment"));
+
+        for i in 0..DEDUP_WINDOW {
+            dedup.is_duplicate(node(1), &i.to_be_bytes());
+        }
+        // Fell out of the window.
+        assert!(!dedup.is_duplicate(node(1), b"segment"));
+    }
+}
@@ -0,0 +1,428 @@
+//! Resolving names to Node ids through a directory Node.
+//!
+//! A Node running [`NameDirectory`] answers queries for the names registered at it.
+//! Other Nodes ask it with [`NameResolver`] over reliable forwards and cache answers,
+//! including the negative ones, for the time given by the directory.
+//!
+//! Messages are prefixed with [`MAGIC`] and their length. Virtual TCP may split or
+//! merge them, so chunks are put back together per Node. Reliable traffic starting
+//! with anything else is passed through to the returned receiver.
+use anyhow::{anyhow, bail};
+use futures::future::AbortHandle;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use ya_relay_core::runtime::Instant;
+use ya_relay_core::utils::spawn_local_abortable;
+use ya_relay_core::NodeId;
+
+use crate::client::{Client, Forwarded, GenericSender, TransportType};
+use crate::transport::ForwardReceiver;
+
+/// Prefix of naming messages.
+pub const MAGIC: &[u8; 4] = b"yaNS";
+pub const MAX_NAME_LEN: usize = 253;
+/// Time resolvers cache answers of a directory for.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const HEADER_SIZE: usize = MAGIC.len() + 2;
+const NODE_ID_SIZE: usize = 20;
+
+const KIND_QUERY: u8 = 0;
+const KIND_ANSWER: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Message {
+    Query {
+        id: u32,
+        name: String,
+    },
+    Answer {
+        id: u32,
+        node_id: Option<NodeId>,
+        ttl: Duration,
+    },
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            Message::Query { id, name } => {
+                body.push(KIND_QUERY);
+                body.extend_from_slice(&id.to_be_bytes());
+                body.extend_from_slice(name.as_bytes());
+            }
+            Message::Answer { id, node_id, ttl } => {
+                body.push(KIND_ANSWER);
+                body.extend_from_slice(&id.to_be_bytes());
+                body.extend_from_slice(&(ttl.as_secs() as u32).to_be_bytes());
+                if let Some(node_id) = node_id {
+                    body.extend_from_slice(&node_id.into_array());
+                }
+            }
+        }
+
+        let mut message = Vec::with_capacity(HEADER_SIZE + body.len());
+        message.extend_from_slice(MAGIC);
+        message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        message.extend_from_slice(&body);
+        message
+    }
+
+    fn decode(body: &[u8]) -> anyhow::Result<Message> {
+        let (kind, id, rest) = match body {
+            [kind, a, b, c, d, rest @ ..] => (*kind, u32::from_be_bytes([*a, *b, *c, *d]), rest),
+            _ => bail!("message too short"),
+        };
+        match kind {
+            KIND_QUERY => Ok(Message::Query {
+                id,
+                name: String::from_utf8(rest.to_vec())?,
+            }),
+            KIND_ANSWER => {
+                let ttl = rest
+                    .get(..4)
+                    .ok_or_else(|| anyhow!("missing ttl"))?
+                    .try_into()
+                    .map(u32::from_be_bytes)?;
+                let node_id = match &rest[4..] {
+                    [] => None,
+                    node_id if node_id.len() == NODE_ID_SIZE => Some(NodeId::from(node_id)),
+                    _ => bail!("invalid node id"),
+                };
+                Ok(Message::Answer {
+                    id,
+                    node_id,
+                    ttl: Duration::from_secs(ttl as u64),
+                })
+            }
+            _ => bail!("unknown message kind {kind}"),
+        }
+    }
+}
+
+/// Reassembles messages from reliable chunks of each Node.
+#[derive(Default)]
+struct Reassembly {
+    partial: HashMap<NodeId, Vec<u8>>,
+}
+
+impl Reassembly {
+    /// Returns `None` if the chunk isn't a part of naming messages.
+    fn push(&mut self, node_id: NodeId, chunk: &[u8]) -> Option<Vec<Message>> {
+        let mut buf = match self.partial.remove(&node_id) {
+            Some(mut buf) => {
+                buf.extend_from_slice(chunk);
+                buf
+            }
+            None if chunk.starts_with(MAGIC) => chunk.to_vec(),
+            None => return None,
+        };
+
+        let mut messages = Vec::new();
+        let mut offset = 0;
+        while buf.len() - offset >= HEADER_SIZE {
+            if !buf[offset..].starts_with(MAGIC) {
+                log::debug!("[Naming] Dropping malformed data from [{node_id}]");
+                return Some(messages);
+            }
+            let len = u16::from_be_bytes([buf[offset + 4], buf[offset + 5]]) as usize;
+            let end = offset + HEADER_SIZE + len;
+            if buf.len() < end {
+                break;
+            }
+            match Message::decode(&buf[offset + HEADER_SIZE..end]) {
+                Ok(message) => messages.push(message),
+                Err(e) => log::debug!("[Naming] Invalid message from [{node_id}]: {e}"),
+            }
+            offset = end;
+        }
+
+        if offset < buf.len() {
+            self.partial.insert(node_id, buf.split_off(offset));
+        }
+        Some(messages)
+    }
+}
+
+/// Takes over the `Client`'s forward receiver and passes on other traffic.
+async fn take_receiver(client: &Client) -> anyhow::Result<ForwardReceiver> {
+    client
+        .forward_receiver()
+        .await
+        .ok_or_else(|| anyhow!("Forward receiver already taken"))
+}
+
+/// Splits naming messages sent over reliable forwards from other traffic.
+async fn ingress(
+    mut receiver: ForwardReceiver,
+    passthrough: mpsc::Sender<Forwarded>,
+    on_message: impl Fn(NodeId, Message),
+) {
+    let mut reassembly = Reassembly::default();
+
+    while let Some(forwarded) = receiver.recv().await {
+        let messages = match forwarded.transport {
+            TransportType::Reliable => {
+                reassembly.push(forwarded.node_id, forwarded.payload.as_ref())
+            }
+            _ => None,
+        };
+        match messages {
+            Some(messages) => messages
+                .into_iter()
+                .for_each(|message| on_message(forwarded.node_id, message)),
+            None => {
+                passthrough.send(forwarded).await.ok();
+            }
+        }
+    }
+}
+
+async fn send(client: &Client, node_id: NodeId, message: &Message) -> anyhow::Result<()> {
+    let mut tx = client.forward_reliable(node_id).await?;
+    tx.send(message.encode().into()).await?;
+    Ok(())
+}
+
+/// Answers name queries of other Nodes. Stops when dropped.
+pub struct NameDirectory {
+    records: Rc<Mutex<HashMap<String, NodeId>>>,
+    handle: AbortHandle,
+}
+
+impl NameDirectory {
+    /// Answers are cached by resolvers for `ttl`.
+    pub async fn start(
+        client: &Client,
+        ttl: Duration,
+    ) -> anyhow::Result<(NameDirectory, ForwardReceiver)> {
+        let receiver = take_receiver(client).await?;
+        let records: Rc<Mutex<HashMap<String, NodeId>>> = Default::default();
+        let (passthrough_tx, passthrough_rx) = mpsc::channel(receiver.max_capacity());
+
+        let handle = {
+            let client = client.clone();
+            let records = records.clone();
+            spawn_local_abortable(ingress(receiver, passthrough_tx, move |from, message| {
+                let answer = match message {
+                    Message::Query { id, name } => {
+                        let node_id = records.lock().get(&name).copied();
+                        log::trace!("[Naming] [{from}] resolved {name:?} to {node_id:?}");
+                        Message::Answer { id, node_id, ttl }
+                    }
+                    Message::Answer { .. } => return,
+                };
+
+                let client = client.clone();
+                ya_relay_core::runtime::spawn_local(async move {
+                    if let Err(e) = send(&client, from, &answer).await {
+                        log::debug!("[Naming] Unable to answer [{from}]: {e}");
+                    }
+                });
+            }))
+        };
+
+        Ok((NameDirectory { records, handle }, passthrough_rx))
+    }
+
+    /// Returns the Node previously registered under the name.
+    pub fn insert(
+        &self,
+        name: impl Into<String>,
+        node_id: NodeId,
+    ) -> anyhow::Result<Option<NodeId>> {
+        let name = name.into();
+        validate_name(&name)?;
+        Ok(self.records.lock().insert(name, node_id))
+    }
+
+    pub fn remove(&self, name: &str) -> Option<NodeId> {
+        self.records.lock().remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<NodeId> {
+        self.records.lock().get(name).copied()
+    }
+}
+
+impl Drop for NameDirectory {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+type Answer = (Option<NodeId>, Duration);
+
+struct ResolverShared {
+    client: Client,
+    directory: NodeId,
+    cache: Mutex<HashMap<String, (Option<NodeId>, Instant)>>,
+    pending: Mutex<HashMap<u32, oneshot::Sender<Answer>>>,
+    next_id: AtomicU32,
+}
+
+/// Resolves names at a directory Node. Stops when dropped.
+pub struct NameResolver {
+    shared: Rc<ResolverShared>,
+    handle: AbortHandle,
+}
+
+impl NameResolver {
+    pub async fn start(
+        client: &Client,
+        directory: NodeId,
+    ) -> anyhow::Result<(NameResolver, ForwardReceiver)> {
+        let receiver = take_receiver(client).await?;
+        let shared = Rc::new(ResolverShared {
+            client: client.clone(),
+            directory,
+            cache: Default::default(),
+            pending: Default::default(),
+            next_id: AtomicU32::new(rand::random()),
+        });
+        let (passthrough_tx, passthrough_rx) = mpsc::channel(receiver.max_capacity());
+
+        let handle = {
+            let shared = shared.clone();
+            spawn_local_abortable(ingress(receiver, passthrough_tx, move |from, message| {
+                if let (true, Message::Answer { id, node_id, ttl }) =
+                    (from == shared.directory, message)
+                {
+                    if let Some(tx) = shared.pending.lock().remove(&id) {
+                        tx.send((node_id, ttl)).ok();
+                    }
+                }
+            }))
+        };
+
+        Ok((NameResolver { shared, handle }, passthrough_rx))
+    }
+
+    pub fn directory(&self) -> NodeId {
+        self.shared.directory
+    }
+
+    /// Node registered under the name at the directory, or `None` if there's none.
+    pub async fn resolve(&self, name: &str) -> anyhow::Result<Option<NodeId>> {
+        validate_name(name)?;
+        if let Some(node_id) = self.cached(name) {
+            return Ok(node_id);
+        }
+
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.shared.pending.lock().insert(id, tx);
+
+        let query = Message::Query {
+            id,
+            name: name.to_string(),
+        };
+        let result = async {
+            send(&self.shared.client, self.shared.directory, &query).await?;
+            ya_relay_core::runtime::timeout(QUERY_TIMEOUT, rx)
+                .await
+                .map_err(|_| anyhow!("Name query timed out"))?
+                .map_err(|_| anyhow!("Name resolver stopped"))
+        }
+        .await;
+        self.shared.pending.lock().remove(&id);
+
+        let (node_id, ttl) = result?;
+        self.shared
+            .cache
+            .lock()
+            .insert(name.to_string(), (node_id, Instant::now() + ttl));
+        Ok(node_id)
+    }
+
+    /// Queries the directory again on the next [`NameResolver::resolve`].
+    pub fn invalidate(&self, name: &str) {
+        self.shared.cache.lock().remove(name);
+    }
+
+    fn cached(&self, name: &str) -> Option<Option<NodeId>> {
+        let mut cache = self.shared.cache.lock();
+        match cache.get(name) {
+            Some((node_id, expires)) if *expires > Instant::now() => Some(*node_id),
+            Some(_) => {
+                cache.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+impl Drop for NameResolver {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        bail!("Name has to be 1 to {MAX_NAME_LEN} bytes long");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let messages = vec![
+            Message::Query {
+                id: 7,
+                name: "storage.example".to_string(),
+            },
+            Message::Answer {
+                id: 7,
+                node_id: Some(NodeId::from([3u8; 20])),
+                ttl: Duration::from_secs(60),
+            },
+            Message::Answer {
+                id: 8,
+                node_id: None,
+                ttl: Duration::from_secs(60),
+            },
+        ];
+        for message in messages {
+            let encoded = message.encode();
+            assert_eq!(Message::decode(&encoded[HEADER_SIZE..]).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let node_id = NodeId::from([1u8; 20]);
+        let query = |id| Message::Query {
+            id,
+            name: "a".repeat(id as usize),
+        };
+        let mut stream = query(1).encode();
+        stream.extend(query(2).encode());
+        stream.extend(query(3).encode());
+
+        let mut reassembly = Reassembly::default();
+        assert_eq!(reassembly.push(node_id, b"other traffic"), None);
+
+        // Two messages, with the third one split.
+        let (first, second) = stream.split_at(stream.len() - 3);
+        assert_eq!(
+            reassembly.push(node_id, first),
+            Some(vec![query(1), query(2)])
+        );
+        assert_eq!(reassembly.push(node_id, second), Some(vec![query(3)]));
+        assert_eq!(reassembly.push(node_id, b"other traffic"), None);
+    }
+}
@@ -0,0 +1,218 @@
+//! NAT classification with help of the relay server.
+//!
+//! The Node sends `NatCheck` to the server and repeats it on the alternate server port
+//! from the response. The server compares the source addresses of both requests, to tell
+//! if the NAT keeps a single mapping for all destinations, and combines it with the result
+//! of probing the Node from yet another port during registration. Hairpinning is checked
+//! by the Node itself, by pinging its own public address.
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use ya_relay_proto::proto;
+use ya_relay_proto::proto::response::nat_check::Type;
+
+use crate::session::SessionLayer;
+
+const HAIRPIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum NatType {
+    /// Server couldn't tell, e.g. registration didn't finish.
+    Unknown,
+    /// Single external address, reachable from any port. Also used for Nodes without NAT.
+    Cone,
+    /// Single external address, reachable only from ports the Node has sent to.
+    PortRestricted,
+    /// Separate external address for each destination.
+    Symmetric,
+}
+
+impl From<Type> for NatType {
+    fn from(value: Type) -> Self {
+        match value {
+            Type::Unknown => NatType::Unknown,
+            Type::Cone => NatType::Cone,
+            Type::PortRestricted => NatType::PortRestricted,
+            Type::Symmetric => NatType::Symmetric,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct NatInfo {
+    pub nat_type: NatType,
+    /// Address the relay server sees this Node at.
+    pub external_addr: SocketAddr,
+    /// Packets sent to `external_addr` from behind the NAT come back.
+    pub hairpinning: bool,
+}
+
+/// How to reach a remote Node, judging by the relay hints in its `find_node` response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum ConnectionAdvice {
+    /// Node has public endpoints.
+    Direct,
+    /// This Node is reachable, so the remote Node can connect to it.
+    Reverse,
+    /// Both Nodes keep a single NAT mapping, which packets sent to each other can open.
+    HolePunch,
+    /// Use the relay, e.g. for a symmetric NAT on either side.
+    Relay,
+}
+
+/// Reachability of a remote Node as seen by the relay.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ConnectionHints {
+    pub endpoints: Vec<SocketAddr>,
+    pub nat_type: NatType,
+    /// Sessions on the relay, telling its load. Zero if the relay didn't report them.
+    pub relay_sessions: u32,
+}
+
+impl ConnectionHints {
+    /// `own` is the NAT type of this Node, see [`crate::Client::check_nat`].
+    pub fn advice(&self, own: NatType) -> ConnectionAdvice {
+        if !self.endpoints.is_empty() {
+            return ConnectionAdvice::Direct;
+        }
+        match (own, self.nat_type) {
+            (NatType::Cone, _) => ConnectionAdvice::Reverse,
+            (NatType::PortRestricted, NatType::PortRestricted) => ConnectionAdvice::HolePunch,
+            _ => ConnectionAdvice::Relay,
+        }
+    }
+}
+
+impl From<&proto::response::Node> for ConnectionHints {
+    fn from(node: &proto::response::Node) -> Self {
+        let (nat_type, relay_sessions) = match &node.hints {
+            Some(hints) => (hints.nat_type().into(), hints.relay_sessions),
+            None => (NatType::Unknown, 0),
+        };
+        ConnectionHints {
+            endpoints: node
+                .endpoints
+                .iter()
+                .cloned()
+                .filter_map(|endpoint| SocketAddr::try_from(endpoint).ok())
+                .collect(),
+            nat_type,
+            relay_sessions,
+        }
+    }
+}
+
+pub(crate) async fn check_nat(layer: &SessionLayer) -> anyhow::Result<NatInfo> {
+    let server = layer.server_session().await?;
+    let session_id = server.raw.id;
+
+    let first = server.raw.nat_check(session_id).await?;
+    if first.code != proto::StatusCode::Ok as i32 {
+        bail!("NatCheck rejected by the server with code {}", first.code);
+    }
+    let external_addr = observed(first.packet.observed)?;
+    let mut alternate = first
+        .packet
+        .alternate
+        .ok_or_else(|| anyhow!("Server didn't return an alternate address"))
+        .and_then(SocketAddr::try_from)?;
+    if alternate.ip().is_unspecified() {
+        alternate.set_ip(server.raw.remote.ip());
+    }
+
+    let protocol = layer.get_protocol()?;
+    let probe = protocol.temporary_session(&alternate);
+    let second = probe.nat_check(session_id).await;
+    protocol.cleanup_initialization(&probe.id).await;
+    let second = second?;
+    if second.code != proto::StatusCode::Ok as i32 {
+        bail!("NatCheck on {alternate} rejected with code {}", second.code);
+    }
+    let nat_type = NatType::from(second.packet.nat_type());
+
+    let hairpinning = check_hairpinning(layer, external_addr).await?;
+
+    Ok(NatInfo {
+        nat_type,
+        external_addr,
+        hairpinning,
+    })
+}
+
+async fn check_hairpinning(
+    layer: &SessionLayer,
+    external_addr: SocketAddr,
+) -> anyhow::Result<bool> {
+    let protocol = layer.get_protocol()?;
+    let probe = protocol.temporary_session(&external_addr);
+    let result = probe
+        .request::<proto::response::Pong>(
+            proto::request::Ping {}.into(),
+            probe.id.to_vec(),
+            HAIRPIN_TIMEOUT,
+        )
+        .await;
+    protocol.cleanup_initialization(&probe.id).await;
+
+    log::debug!("Hairpinning check on {external_addr}: {}", result.is_ok());
+    Ok(result.is_ok())
+}
+
+fn observed(endpoint: Option<proto::Endpoint>) -> anyhow::Result<SocketAddr> {
+    endpoint
+        .ok_or_else(|| anyhow!("Server didn't return the observed address"))
+        .and_then(SocketAddr::try_from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hints(endpoints: &[&str], nat_type: Type) -> ConnectionHints {
+        ConnectionHints::from(&proto::response::Node {
+            endpoints: endpoints
+                .iter()
+                .map(|addr| addr.parse::<SocketAddr>().unwrap().into())
+                .collect(),
+            hints: Some(proto::response::node::Hints {
+                nat_type: nat_type.into(),
+                relay_sessions: 10,
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_connection_advice() {
+        let public = hints(&["1.2.3.4:7464"], Type::Cone);
+        assert_eq!(public.relay_sessions, 10);
+        assert_eq!(public.advice(NatType::Symmetric), ConnectionAdvice::Direct);
+
+        let restricted = hints(&[], Type::PortRestricted);
+        assert_eq!(restricted.advice(NatType::Cone), ConnectionAdvice::Reverse);
+        assert_eq!(
+            restricted.advice(NatType::PortRestricted),
+            ConnectionAdvice::HolePunch
+        );
+        assert_eq!(
+            restricted.advice(NatType::Symmetric),
+            ConnectionAdvice::Relay
+        );
+
+        let symmetric = hints(&[], Type::Symmetric);
+        assert_eq!(
+            symmetric.advice(NatType::PortRestricted),
+            ConnectionAdvice::Relay
+        );
+
+        let old_relay = ConnectionHints::from(&proto::response::Node::default());
+        assert_eq!(old_relay.nat_type, NatType::Unknown);
+        assert_eq!(
+            old_relay.advice(NatType::PortRestricted),
+            ConnectionAdvice::Relay
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Per-Node state kept for a bounded number of Nodes.
+use std::collections::HashMap;
+
+use ya_relay_core::NodeId;
+
+/// Nodes, which per-Node state is kept for. Forwards may come from any number of Node ids,
+/// so without a limit a peer could make the client grow its state without bound. Nodes
+/// exchanging packets with the client are used recently, so evicting the least recently
+/// used Node forgets only the idle ones, as long as fewer Nodes than that are active.
+pub(crate) const MAX_NODES: usize = 1024;
+
+/// Map of per-Node state, which forgets the least recently used Node above its capacity.
+pub(crate) struct NodeMap<V> {
+    entries: HashMap<NodeId, Entry<V>>,
+    capacity: usize,
+    /// Incremented on every use, so entries are ordered by their last use.
+    uses: u64,
+}
+
+struct Entry<V> {
+    used: u64,
+    value: V,
+}
+
+impl<V> Default for NodeMap<V> {
+    fn default() -> Self {
+        Self::with_capacity(MAX_NODES)
+    }
+}
+
+impl<V> NodeMap<V> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        NodeMap {
+            entries: HashMap::new(),
+            capacity,
+            uses: 0,
+        }
+    }
+
+    /// Doesn't count as a use of the Node.
+    pub fn get(&self, node_id: &NodeId) -> Option<&V> {
+        self.entries.get(node_id).map(|entry| &entry.value)
+    }
+
+    pub fn get_mut(&mut self, node_id: &NodeId) -> Option<&mut V> {
+        self.uses += 1;
+        let used = self.uses;
+        self.entries.get_mut(node_id).map(|entry| {
+            entry.used = used;
+            &mut entry.value
+        })
+    }
+
+    pub fn get_or_insert_with(&mut self, node_id: NodeId, value: impl FnOnce() -> V) -> &mut V {
+        if !self.entries.contains_key(&node_id) {
+            self.insert(node_id, value());
+        }
+        self.get_mut(&node_id).unwrap()
+    }
+
+    pub fn insert(&mut self, node_id: NodeId, value: V) -> Option<V> {
+        if !self.entries.contains_key(&node_id) && self.entries.len() >= self.capacity {
+            self.evict();
+        }
+        self.uses += 1;
+        let entry = Entry {
+            used: self.uses,
+            value,
+        };
+        self.entries.insert(node_id, entry).map(|prev| prev.value)
+    }
+
+    pub fn remove(&mut self, node_id: &NodeId) -> Option<V> {
+        self.entries.remove(node_id).map(|entry| entry.value)
+    }
+
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.used)
+            .map(|(node_id, _)| *node_id);
+        if let Some(node_id) = oldest {
+            self.entries.remove(&node_id);
+        }
+    }
+}
+
+impl<V: Default> NodeMap<V> {
+    pub fn get_or_default(&mut self, node_id: NodeId) -> &mut V {
+        self.get_or_insert_with(node_id, V::default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let node = |n: u8| NodeId::from([n; 20]);
+        let mut map = NodeMap::with_capacity(2);
+        map.insert(node(1), 1);
+        map.insert(node(2), 2);
+        *map.get_or_default(node(1)) += 10;
+
+        // Node 2 wasn't used since Node 1 was, so it goes first.
+        map.insert(node(3), 3);
+        assert_eq!(map.get(&node(1)), Some(&11));
+        assert_eq!(map.get(&node(2)), None);
+        assert_eq!(map.get(&node(3)), Some(&3));
+
+        // Replacing an entry doesn't evict others.
+        assert_eq!(map.insert(node(3), 4), Some(3));
+        assert_eq!(map.remove(&node(1)), Some(11));
+    }
+}
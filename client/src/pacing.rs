@@ -0,0 +1,209 @@
+//! Spacing out virtual TCP packets sent to other Nodes.
+//!
+//! Without pacing, the egress router writes whole TCP windows into the UDP socket at once.
+//! Such bursts overflow the rate limiter of the relay server and buffers along the path,
+//! so packets get lost and retransmitted. With [`crate::ClientBuilder::pacing`], packets to
+//! each Node are released at the rate of the path, allowing bursts up to [`PacingConfig::burst`].
+//!
+//! Rate of the path is taken from the last [`crate::Client::estimate_bandwidth`] for the Node,
+//! multiplied by [`PacingConfig::gain`], so the TCP stack can still probe for more bandwidth.
+//! It can be set with [`crate::Client::set_pacing_rate`] as well. Nodes without a known rate
+//! are paced at [`PacingConfig::default_rate`], or not at all.
+//!
+//! Nodes connected with a [`crate::tuning::TuningProfile`] are paced with its settings,
+//! even if pacing isn't enabled for the client.
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ya_relay_core::runtime::Instant;
+use ya_relay_core::NodeId;
+
+use crate::node_map::NodeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PacingConfig {
+    /// Bytes per second used for Nodes, which path rate is not known.
+    /// Such Nodes are not paced, if not set.
+    pub default_rate: Option<u64>,
+    /// Bytes sent back-to-back, before packets are spaced out.
+    pub burst: usize,
+    /// Multiplier of estimated path rates.
+    pub gain: f64,
+}
+
+impl Default for PacingConfig {
+    fn default() -> Self {
+        PacingConfig {
+            default_rate: None,
+            burst: 16 * 1024,
+            gain: 1.25,
+        }
+    }
+}
+
+struct Bucket {
+    /// Bytes per second.
+    rate: f64,
+    /// Bytes, which can be sent right away. Negative, when packets are waiting.
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    /// Measured or configured rates, before applying the gain.
+    rates: NodeMap<f64>,
+    buckets: NodeMap<Bucket>,
+    /// Pacing of Nodes, which connections use a [`crate::tuning::TuningProfile`].
+    profiles: NodeMap<PacingConfig>,
+}
+
+impl State {
+    fn config(&self, default: Option<PacingConfig>, node_id: &NodeId) -> Option<PacingConfig> {
+        self.profiles.get(node_id).copied().or(default)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Pacer {
+    config: Option<PacingConfig>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Pacer {
+    pub fn new(config: Option<PacingConfig>) -> Self {
+        Pacer {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// Sets rate of the path to the Node, measured or configured.
+    pub fn set_rate(&self, node_id: NodeId, bytes_per_second: f64) {
+        let mut state = self.state.lock();
+        state.rates.insert(node_id, bytes_per_second);
+        if let Some(config) = state.config(self.config, &node_id) {
+            if let Some(bucket) = state.buckets.get_mut(&node_id) {
+                bucket.rate = bytes_per_second * config.gain;
+            }
+        }
+    }
+
+    /// Paces packets to the Node with `config`, instead of the one the client was built with.
+    /// The rate for Nodes without a known one is kept from the latter, if not set.
+    pub fn set_profile(&self, node_id: NodeId, mut config: PacingConfig) {
+        if config.default_rate.is_none() {
+            config.default_rate = self.config.and_then(|config| config.default_rate);
+        }
+        let mut state = self.state.lock();
+        state.profiles.insert(node_id, config);
+        state.buckets.remove(&node_id);
+    }
+
+    /// Rate at which packets to the Node are released, if they are paced.
+    pub fn rate(&self, node_id: NodeId) -> Option<f64> {
+        Self::rate_of(&self.state.lock(), self.config, node_id)
+    }
+
+    fn rate_of(state: &State, default: Option<PacingConfig>, node_id: NodeId) -> Option<f64> {
+        let config = state.config(default, &node_id)?;
+        state
+            .rates
+            .get(&node_id)
+            .map(|rate| rate * config.gain)
+            .or_else(|| config.default_rate.map(|rate| rate as f64))
+    }
+
+    /// Reserves a slot for a packet of `size` bytes to the Node and returns
+    /// the time to wait before sending it. Packets have to be sent in the order
+    /// of their reservations.
+    pub fn delay(&self, node_id: NodeId, size: usize) -> Duration {
+        self.delay_at(node_id, size, Instant::now())
+    }
+
+    fn delay_at(&self, node_id: NodeId, size: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock();
+        let config = match state.config(self.config, &node_id) {
+            Some(config) => config,
+            None => return Duration::ZERO,
+        };
+        let rate = match Self::rate_of(&state, self.config, node_id) {
+            Some(rate) if rate > 0. => rate,
+            _ => return Duration::ZERO,
+        };
+        let burst = config.burst as f64;
+
+        let bucket = state.buckets.get_or_insert_with(node_id, || Bucket {
+            rate,
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(burst) - size as f64;
+        bucket.updated = now;
+
+        match bucket.tokens < 0. {
+            true => Duration::from_secs_f64(-bucket.tokens / bucket.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacing() {
+        let node = NodeId::from([1; 20]);
+        let pacer = Pacer::new(Some(PacingConfig {
+            default_rate: None,
+            burst: 2000,
+            gain: 2.,
+        }));
+        let now = Instant::now();
+        assert_eq!(pacer.delay_at(node, 1000, now), Duration::ZERO);
+
+        pacer.set_rate(node, 5000.);
+        assert_eq!(pacer.rate(node), Some(10_000.));
+        // Burst.
+        assert_eq!(pacer.delay_at(node, 1000, now), Duration::ZERO);
+        assert_eq!(pacer.delay_at(node, 1000, now), Duration::ZERO);
+        assert_eq!(pacer.delay_at(node, 1000, now), Duration::from_millis(100));
+        assert_eq!(pacer.delay_at(node, 1000, now), Duration::from_millis(200));
+
+        // Refilled after waiting.
+        let later = now + Duration::from_millis(500);
+        assert_eq!(pacer.delay_at(node, 1000, later), Duration::ZERO);
+
+        let disabled = Pacer::new(None);
+        disabled.set_rate(node, 5000.);
+        assert_eq!(disabled.delay_at(node, 100_000, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_profile() {
+        let node = NodeId::from([1; 20]);
+        let other = NodeId::from([2; 20]);
+        let pacer = Pacer::new(None);
+        pacer.set_rate(node, 5000.);
+        pacer.set_rate(other, 5000.);
+        pacer.set_profile(
+            node,
+            PacingConfig {
+                default_rate: None,
+                burst: 1000,
+                gain: 2.,
+            },
+        );
+        assert_eq!(pacer.rate(node), Some(10_000.));
+        assert_eq!(pacer.rate(other), None);
+
+        let now = Instant::now();
+        assert_eq!(pacer.delay_at(node, 1000, now), Duration::ZERO);
+        assert_eq!(pacer.delay_at(node, 1000, now), Duration::from_millis(100));
+        assert_eq!(pacer.delay_at(other, 100_000, now), Duration::ZERO);
+    }
+}
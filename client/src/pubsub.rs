@@ -0,0 +1,249 @@
+//! Topic based publish/subscribe over relay server groups.
+//!
+//! Each topic is a relay group. Published payloads are prefixed with the topic name,
+//! so subscribers can tell topics apart, since the relay delivers group packets as
+//! regular unreliable forwards. Packets with the prefix never reach
+//! [`crate::Client::forward_receiver`].
+//!
+//! Relay servers without group support don't answer `JoinGroup`. In that case the
+//! client falls back to sending each published payload to its neighbourhood.
+use futures::Stream;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use ya_relay_core::runtime::SystemTime;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::Payload;
+
+use crate::client::Forwarded;
+
+const MAGIC: &[u8; 4] = b"YRPS";
+const GROUP_PREFIX: &str = "pubsub/";
+
+/// Limited, so the group name fits on the relay server.
+pub const MAX_TOPIC_LEN: usize = 120;
+/// Neighbours receiving published payloads, when the relay server doesn't support groups.
+pub(crate) const FANOUT_COUNT: u32 = 16;
+/// Shorter than the request timeout, so a relay ignoring `JoinGroup` can be told apart
+/// from other failures.
+pub(crate) const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Payload published to a topic.
+#[derive(Clone, Debug)]
+pub struct TopicMessage {
+    pub topic: String,
+    /// Publishing Node.
+    pub node_id: NodeId,
+    pub payload: Payload,
+    pub received_at: SystemTime,
+}
+
+/// Stream of payloads published to a topic. Delivery stops, when all subscriptions of
+/// the topic are dropped, but the relay group is left only by [`crate::Client::unsubscribe`].
+pub struct Subscription {
+    topic: String,
+    rx: mpsc::UnboundedReceiver<TopicMessage>,
+}
+
+impl Subscription {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub async fn recv(&mut self) -> Option<TopicMessage> {
+        self.rx.recv().await
+    }
+}
+
+impl Stream for Subscription {
+    type Item = TopicMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// How published payloads reach subscribers on other Nodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    /// Relay server sends the payload to group members.
+    #[default]
+    Relay,
+    /// Payload is sent to each neighbour separately.
+    Fanout,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct PubSub {
+    state: Arc<Mutex<PubSubState>>,
+}
+
+#[derive(Default)]
+struct PubSubState {
+    topics: HashMap<String, Vec<mpsc::UnboundedSender<TopicMessage>>>,
+    delivery: Delivery,
+}
+
+impl PubSub {
+    /// Returns `true` as the second element, if there were no other active subscriptions
+    /// of the topic.
+    pub fn subscribe(&self, topic: &str) -> (Subscription, bool) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut state = self.state.lock();
+        let subscribers = state.topics.entry(topic.to_string()).or_default();
+        subscribers.retain(|tx| !tx.is_closed());
+        let first = subscribers.is_empty();
+        subscribers.push(tx);
+
+        let subscription = Subscription {
+            topic: topic.to_string(),
+            rx,
+        };
+        (subscription, first)
+    }
+
+    /// Ends all subscriptions of the topic. Returns `false` if there weren't any.
+    pub fn unsubscribe(&self, topic: &str) -> bool {
+        self.state.lock().topics.remove(topic).is_some()
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        self.state.lock().topics.keys().cloned().collect()
+    }
+
+    pub fn delivery(&self) -> Delivery {
+        self.state.lock().delivery
+    }
+
+    pub fn set_delivery(&self, delivery: Delivery) {
+        self.state.lock().delivery = delivery;
+    }
+
+    /// Passes published payloads to subscriptions. Returns packets, which aren't
+    /// published payloads.
+    pub fn dispatch(&self, forwarded: Forwarded) -> Option<Forwarded> {
+        let (topic, data) = match decode(forwarded.payload.as_ref()) {
+            Some(decoded) => decoded,
+            None => return Some(forwarded),
+        };
+
+        let mut state = self.state.lock();
+        let subscribers = match state.topics.get_mut(topic) {
+            Some(subscribers) => subscribers,
+            None => {
+                log::trace!(
+                    "Dropping payload from [{}] published to not subscribed topic {topic:?}",
+                    forwarded.node_id
+                );
+                return None;
+            }
+        };
+
+        let message = TopicMessage {
+            topic: topic.to_string(),
+            node_id: forwarded.node_id,
+            payload: data.to_vec().into(),
+            received_at: forwarded.received_at,
+        };
+        subscribers.retain(|tx| tx.send(message.clone()).is_ok());
+        if subscribers.is_empty() {
+            state.topics.remove(topic);
+        }
+        None
+    }
+}
+
+pub(crate) fn check_topic(topic: &str) -> anyhow::Result<()> {
+    if topic.is_empty() || topic.len() > MAX_TOPIC_LEN {
+        anyhow::bail!(
+            "Topic length {} out of range 1..={MAX_TOPIC_LEN}",
+            topic.len()
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn group_name(topic: &str) -> String {
+    format!("{GROUP_PREFIX}{topic}")
+}
+
+/// Topic is expected to pass [`check_topic`].
+pub(crate) fn encode(topic: &str, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(MAGIC.len() + 1 + topic.len() + data.len());
+    packet.extend_from_slice(MAGIC);
+    packet.push(topic.len() as u8);
+    packet.extend_from_slice(topic.as_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+fn decode(packet: &[u8]) -> Option<(&str, &[u8])> {
+    let packet = packet.strip_prefix(MAGIC)?;
+    let (&len, packet) = packet.split_first()?;
+    let len = len as usize;
+    if len == 0 || packet.len() < len {
+        return None;
+    }
+    let topic = std::str::from_utf8(&packet[..len]).ok()?;
+    Some((topic, &packet[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ya_relay_core::server_session::TransportType;
+
+    fn forwarded(payload: Vec<u8>) -> Forwarded {
+        Forwarded {
+            transport: TransportType::Unreliable,
+            node_id: NodeId::default(),
+            payload: payload.into(),
+            session: None,
+            received_at: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let packet = encode("topic", b"data");
+        assert_eq!(decode(&packet), Some(("topic", &b"data"[..])));
+        assert_eq!(decode(&encode("topic", b"")), Some(("topic", &b""[..])));
+
+        assert_eq!(decode(b"data"), None);
+        assert_eq!(decode(&packet[..MAGIC.len() + 3]), None);
+        assert_eq!(decode(b"YRPS\x00data"), None);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let pubsub = PubSub::default();
+        let (mut sub1, first) = pubsub.subscribe("topic");
+        assert!(first);
+        let (sub2, first) = pubsub.subscribe("topic");
+        assert!(!first);
+
+        assert!(pubsub.dispatch(forwarded(b"data".to_vec())).is_some());
+        assert!(pubsub
+            .dispatch(forwarded(encode("other", b"data")))
+            .is_none());
+
+        drop(sub2);
+        assert!(pubsub
+            .dispatch(forwarded(encode("topic", b"data")))
+            .is_none());
+        let message = sub1.recv().await.unwrap();
+        assert_eq!(message.topic, "topic");
+        assert_eq!(message.payload.as_ref(), b"data");
+
+        drop(sub1);
+        pubsub.dispatch(forwarded(encode("topic", b"data")));
+        assert!(pubsub.topics().is_empty());
+        assert!(pubsub.subscribe("topic").1);
+    }
+}
@@ -1,15 +1,18 @@
 use derive_more::Display;
 use futures::future::LocalBoxFuture;
 use futures::{FutureExt, SinkExt};
+use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::net::SocketAddr;
 use std::process::id;
 use std::sync::{Arc, Mutex};
-use tokio::time::{Duration, Instant};
+use std::time::Duration;
 
 use crate::dispatch::{Dispatched, Dispatcher};
 use crate::error::RequestError;
 
+use ya_relay_core::clock::{system_clock, ClockRef};
+use ya_relay_core::runtime::Instant;
 use ya_relay_core::server_session::SessionId;
 use ya_relay_core::udp_stream::OutStream;
 use ya_relay_core::NodeId;
@@ -21,7 +24,7 @@ const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub type DropHandler = Box<dyn FnOnce() + Send>;
 
-#[derive(Clone, Display, PartialEq, Debug, Copy)]
+#[derive(Clone, Display, PartialEq, Eq, Debug, Copy, Serialize, Deserialize)]
 pub enum SessionType {
     #[display(fmt = "p2p")]
     P2P,
@@ -44,13 +47,33 @@ pub struct RawSession {
     pub(crate) drop_handler: Arc<Mutex<Option<DropHandler>>>,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+/// Serialized `Instant` fields hold time elapsed since the given moment,
+/// since `Instant` has no meaning outside of the process.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct SessionDesc {
     pub remote: SocketAddr,
     pub id: SessionId,
-    pub last_seen: std::time::Instant,
+    #[serde(with = "elapsed")]
+    pub last_seen: Instant,
     pub last_ping: std::time::Duration,
-    pub created: std::time::Instant,
+    #[serde(with = "elapsed")]
+    pub created: Instant,
+}
+
+pub(crate) mod elapsed {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::Duration;
+    use ya_relay_core::runtime::Instant;
+
+    pub fn serialize<S: Serializer>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+        instant.elapsed().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+        let elapsed = Duration::deserialize(deserializer)?;
+        let now = Instant::now();
+        Ok(now.checked_sub(elapsed).unwrap_or(now))
+    }
 }
 
 impl<'a> From<&'a RawSession> for SessionDesc {
@@ -58,23 +81,32 @@ impl<'a> From<&'a RawSession> for SessionDesc {
         SessionDesc {
             remote: session.remote,
             id: session.id,
-            last_seen: session.dispatcher.last_seen().into_std(),
+            last_seen: session.dispatcher.last_seen(),
             last_ping: session.dispatcher.last_ping(),
-            created: session.created.into_std(),
+            created: session.created,
         }
     }
 }
 
 impl RawSession {
     pub fn new(remote_addr: SocketAddr, id: SessionId, sink: OutStream) -> Arc<Self> {
+        Self::with_clock(remote_addr, id, sink, system_clock())
+    }
+
+    pub fn with_clock(
+        remote_addr: SocketAddr,
+        id: SessionId,
+        sink: OutStream,
+        clock: ClockRef,
+    ) -> Arc<Self> {
         log::trace!("Creating new `RawSession` {id} ({remote_addr})");
 
         Arc::new(Self {
             remote: remote_addr,
             id,
             sink,
-            created: Instant::now(),
-            dispatcher: Dispatcher::default(),
+            created: clock.now(),
+            dispatcher: Dispatcher::new(clock),
             drop_handler: Default::default(),
         })
     }
@@ -86,12 +118,17 @@ impl RawSession {
     pub async fn register_endpoints(
         &self,
         endpoints: Vec<proto::Endpoint>,
+        properties: Option<proto::Properties>,
     ) -> Result<Vec<proto::Endpoint>, RequestError> {
         log::info!("Registering endpoints on {}.", self.remote);
 
         let response = self
             .request::<proto::response::Register>(
-                proto::request::Register { endpoints }.into(),
+                proto::request::Register {
+                    endpoints,
+                    properties,
+                }
+                .into(),
                 self.id.to_vec(),
                 DEFAULT_REQUEST_TIMEOUT,
             )
@@ -115,7 +152,16 @@ impl RawSession {
             node_id: node_id.into_array().to_vec(),
             public_key: true,
         };
-        self.find_node_by(packet).await
+        let node = self.find_node_by(packet).await?;
+        let requested = node_id.into_array();
+        if !node
+            .identities
+            .iter()
+            .any(|ident| ident.node_id == requested)
+        {
+            anyhow::bail!("Relay responded with another Node, when asked for [{node_id}]");
+        }
+        Ok(node)
     }
 
     pub async fn find_slot(&self, slot: SlotId) -> anyhow::Result<proto::response::Node> {
@@ -123,9 +169,18 @@ impl RawSession {
             slot,
             public_key: true,
         };
-        self.find_node_by(packet).await
+        let node = self.find_node_by(packet).await?;
+        if node.slot != slot {
+            anyhow::bail!(
+                "Relay responded with Node in slot {}, when asked for slot {slot}",
+                node.slot
+            );
+        }
+        Ok(node)
     }
 
+    /// Responses aren't checked against the request, callers make sure the relay
+    /// answered with the Node they asked for.
     async fn find_node_by(
         &self,
         packet: impl Into<proto::Request>,
@@ -161,20 +216,97 @@ impl RawSession {
         Ok(neighbours)
     }
 
+    /// Returns Nodes, which published property `key` set to `value`. Relay server
+    /// limits the number of returned Nodes further.
+    pub async fn find_nodes(
+        &self,
+        key: &str,
+        value: &str,
+        limit: u32,
+    ) -> anyhow::Result<proto::response::FindNodes> {
+        let packet = proto::request::FindNodes {
+            key: key.to_string(),
+            value: value.to_string(),
+            limit,
+        };
+        let found = self
+            .request::<proto::response::FindNodes>(
+                packet.into(),
+                self.id.to_vec(),
+                DEFAULT_REQUEST_TIMEOUT,
+            )
+            .await?
+            .packet;
+        Ok(found)
+    }
+
+    /// Returns Nodes closest to `node_id` by XOR distance, or to this Node if not set.
+    pub async fn nearest(
+        &self,
+        node_id: Option<NodeId>,
+        count: u32,
+    ) -> anyhow::Result<proto::response::Nearest> {
+        let packet = proto::request::Nearest {
+            node_id: node_id
+                .map(|node_id| node_id.into_array().to_vec())
+                .unwrap_or_default(),
+            count,
+        };
+        let nearest = self
+            .request::<proto::response::Nearest>(
+                packet.into(),
+                self.id.to_vec(),
+                DEFAULT_REQUEST_TIMEOUT,
+            )
+            .await?
+            .packet;
+        Ok(nearest)
+    }
+
+    pub async fn locate(&self, node_id: NodeId) -> anyhow::Result<proto::response::Locate> {
+        let packet = proto::request::Locate {
+            node_id: node_id.into_array().to_vec(),
+        };
+        let located = self
+            .request::<proto::response::Locate>(
+                packet.into(),
+                self.id.to_vec(),
+                DEFAULT_REQUEST_TIMEOUT,
+            )
+            .await?
+            .packet;
+        Ok(located)
+    }
+
     pub async fn ping(&self) -> anyhow::Result<(), RequestError> {
         let packet = proto::request::Ping {};
-        let ping_ts = Instant::now();
+        let clock = self.dispatcher.clock();
+        let ping_ts = clock.now();
 
         let (ping, result) = match self
             .request::<proto::response::Pong>(packet.into(), self.id.to_vec(), DEFAULT_PING_TIMEOUT)
             .await
         {
-            result @ Ok(_) => (ping_ts.elapsed(), result),
-            result @ Err(_) => (self.dispatcher.last_seen().elapsed(), result),
+            result @ Ok(_) => (clock.now() - ping_ts, result),
+            result @ Err(_) => (clock.now() - self.dispatcher.last_seen(), result),
         };
 
         self.dispatcher.update_ping(ping);
-        result.map(|_| ())
+        result.map(|pong| self.dispatcher.update_load(pong.packet.load.as_ref()))
+    }
+
+    /// Sends `NatCheck` request as a part of the server session `session_id`,
+    /// which isn't necessarily `self`.
+    pub async fn nat_check(
+        &self,
+        session_id: SessionId,
+    ) -> Result<Dispatched<proto::response::NatCheck>, RequestError> {
+        self.request::<proto::response::NatCheck>(
+            proto::request::NatCheck {}.into(),
+            session_id.to_vec(),
+            DEFAULT_REQUEST_TIMEOUT,
+        )
+        .await
     }
 
     pub async fn reverse_connection(&self, node_id: NodeId) -> Result<(), RequestError> {
@@ -191,6 +323,49 @@ impl RawSession {
         Ok(())
     }
 
+    /// Returns number of the group members, including this Node.
+    pub async fn join_group(&self, group: &str) -> Result<u32, RequestError> {
+        let packet = proto::request::JoinGroup {
+            group: group.to_string(),
+        };
+        let response = self
+            .request::<proto::response::JoinGroup>(
+                packet.into(),
+                self.id.to_vec(),
+                DEFAULT_REQUEST_TIMEOUT,
+            )
+            .await?
+            .packet;
+
+        Ok(response.members)
+    }
+
+    pub async fn leave_group(&self, group: &str) -> Result<(), RequestError> {
+        let packet = proto::request::LeaveGroup {
+            group: group.to_string(),
+        };
+        self.request::<proto::response::LeaveGroup>(
+            packet.into(),
+            self.id.to_vec(),
+            DEFAULT_REQUEST_TIMEOUT,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Relay server sends the payload to other members of the group. There is no
+    /// confirmation, delivery is as unreliable as a single forward.
+    pub async fn forward_to_group(
+        &self,
+        forward: proto::control::ForwardToGroup,
+    ) -> anyhow::Result<()> {
+        let control_packet = proto::Packet::control(self.id.to_vec(), forward);
+        self.send(control_packet).await?;
+        self.dispatcher.update_forwarded();
+        Ok(())
+    }
+
     /// Check if any packet was seen during expiration period.
     /// If it wasn't, ping will be sent.
     /// Function returns timestamp of last seen packet from remote Node,
@@ -198,14 +373,14 @@ impl RawSession {
     pub async fn keep_alive(&self, expiration: Duration) -> Instant {
         let last_seen = self.dispatcher.last_seen();
 
-        if last_seen + expiration < Instant::now() {
+        if last_seen + expiration < self.dispatcher.clock().now() {
             // Sending 3 pings after each other to avoid lost UDP packets.
             // We need only one response.
             // Note: futures are asynchronous, because we shouldn't wait for ping timeout
             //       in case of lost packets.
             futures::future::select_ok((0..3).map(|i| {
                 async move {
-                    tokio::time::sleep(Duration::from_millis(200 * i)).await;
+                    ya_relay_core::runtime::sleep(Duration::from_millis(200 * i)).await;
                     self.ping().await
                 }
                 .boxed_local()
@@ -272,7 +447,7 @@ impl RawSession {
                 if let Err(e) = self.send(packet.clone()).await {
                     return e;
                 }
-                tokio::time::sleep(timeout / RETRIES).await;
+                ya_relay_core::runtime::sleep(timeout / RETRIES).await;
             }
         };
 
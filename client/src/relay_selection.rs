@@ -0,0 +1,234 @@
+//! Choosing between several relay servers.
+//!
+//! Relays report their load relative to the capacity set by operators, in handshake
+//! and ping responses. With [`crate::ClientBuilder::relay_selection`], the client probes
+//! the configured relays and picks the one with the best round-trip time weighted by
+//! its load. While running, relays are probed every [`RelaySelectionConfig::probe_interval`]
+//! and the client moves to another relay, if it's better by [`RelaySelectionConfig::margin`].
+//!
+//! Moving closes the session with the current relay, so Nodes reached through it are
+//! connected again through the new one.
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use ya_relay_proto::proto;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RelaySelectionConfig {
+    /// Relays to choose from, besides the one the client was built with.
+    pub relays: Vec<SocketAddr>,
+    pub probe_interval: Duration,
+    /// Round-trip time of a relay at full load is multiplied by `1 + load_weight`.
+    pub load_weight: f64,
+    /// Fraction of the weighted round-trip time, by which another relay has to be
+    /// better, before the client moves to it.
+    pub margin: f64,
+}
+
+impl RelaySelectionConfig {
+    pub fn new(relays: impl IntoIterator<Item = SocketAddr>) -> Self {
+        RelaySelectionConfig {
+            relays: relays.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for RelaySelectionConfig {
+    fn default() -> Self {
+        RelaySelectionConfig {
+            relays: Vec::new(),
+            probe_interval: Duration::from_secs(300),
+            load_weight: 2.0,
+            margin: 0.25,
+        }
+    }
+}
+
+/// Load reported by a relay, from 0 when idle, to 1 at its capacity. Above 1 when overloaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RelayLoad {
+    pub sessions: f64,
+    pub bandwidth: f64,
+}
+
+impl RelayLoad {
+    /// The more limiting of both.
+    pub fn factor(&self) -> f64 {
+        self.sessions.max(self.bandwidth)
+    }
+}
+
+impl From<&proto::Load> for RelayLoad {
+    fn from(load: &proto::Load) -> Self {
+        RelayLoad {
+            sessions: f64::from(load.sessions) / 1000.,
+            bandwidth: f64::from(load.bandwidth) / 1000.,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RelayStats {
+    pub addr: SocketAddr,
+    /// Not set, if the relay didn't respond.
+    pub rtt: Option<Duration>,
+    /// Not set, if the relay doesn't report it.
+    pub load: Option<RelayLoad>,
+}
+
+impl RelayStats {
+    /// Round-trip time weighted by the load. Lower is better.
+    pub fn score(&self, load_weight: f64) -> Option<Duration> {
+        let factor = self.load.map(|load| load.factor()).unwrap_or_default();
+        self.rtt.map(|rtt| rtt.mul_f64(1. + load_weight * factor))
+    }
+}
+
+struct State {
+    current: SocketAddr,
+    relays: Vec<RelayStats>,
+}
+
+/// Relay in use and the last measurements of all candidates.
+pub(crate) struct RelaySelector {
+    config: RelaySelectionConfig,
+    state: Mutex<State>,
+}
+
+impl RelaySelector {
+    pub fn new(primary: SocketAddr, config: Option<RelaySelectionConfig>) -> Self {
+        let config = config.unwrap_or_default();
+        let mut relays = vec![primary];
+        relays.extend(config.relays.iter().filter(|addr| **addr != primary));
+        let relays = relays
+            .into_iter()
+            .map(|addr| RelayStats {
+                addr,
+                rtt: None,
+                load: None,
+            })
+            .collect();
+        RelaySelector {
+            config,
+            state: Mutex::new(State {
+                current: primary,
+                relays,
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &RelaySelectionConfig {
+        &self.config
+    }
+
+    pub fn current(&self) -> SocketAddr {
+        self.state.lock().current
+    }
+
+    pub fn set_current(&self, addr: SocketAddr) {
+        self.state.lock().current = addr;
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.state
+            .lock()
+            .relays
+            .iter()
+            .map(|stats| stats.addr)
+            .collect()
+    }
+
+    pub fn stats(&self) -> Vec<RelayStats> {
+        self.state.lock().relays.clone()
+    }
+
+    pub fn record(&self, addr: SocketAddr, rtt: Option<Duration>, load: Option<RelayLoad>) {
+        let mut state = self.state.lock();
+        if let Some(stats) = state.relays.iter_mut().find(|stats| stats.addr == addr) {
+            stats.rtt = rtt;
+            stats.load = load;
+        }
+    }
+
+    /// Relay to move to, if any is better than the current one by the margin,
+    /// or the current one didn't respond.
+    pub fn better(&self) -> Option<SocketAddr> {
+        let weight = self.config.load_weight;
+        let state = self.state.lock();
+        let best = state
+            .relays
+            .iter()
+            .filter(|stats| stats.addr != state.current)
+            .filter_map(|stats| stats.score(weight).map(|score| (stats.addr, score)))
+            .min_by_key(|(_, score)| *score)?;
+        let current = state
+            .relays
+            .iter()
+            .find(|stats| stats.addr == state.current)
+            .and_then(|stats| stats.score(weight));
+        match current {
+            Some(current) if best.1 >= current.mul_f64(1. - self.config.margin) => None,
+            _ => Some(best.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn load(sessions: f64) -> Option<RelayLoad> {
+        Some(RelayLoad {
+            sessions,
+            bandwidth: 0.,
+        })
+    }
+
+    #[test]
+    fn test_score() {
+        let stats = RelayStats {
+            addr: addr(1),
+            rtt: Some(Duration::from_millis(10)),
+            load: load(0.5),
+        };
+        assert_eq!(stats.score(2.), Some(Duration::from_millis(20)));
+        assert_eq!(
+            RelayStats {
+                load: None,
+                ..stats
+            }
+            .score(2.),
+            Some(Duration::from_millis(10))
+        );
+    }
+
+    #[test]
+    fn test_better() {
+        let selector = RelaySelector::new(addr(1), Some(RelaySelectionConfig::new([addr(2)])));
+        assert_eq!(selector.better(), None);
+
+        selector.record(addr(1), Some(Duration::from_millis(10)), load(0.));
+        selector.record(addr(2), Some(Duration::from_millis(8)), load(0.));
+        // Within the margin.
+        assert_eq!(selector.better(), None);
+
+        // Current relay got loaded.
+        selector.record(addr(1), Some(Duration::from_millis(10)), load(1.));
+        assert_eq!(selector.better(), Some(addr(2)));
+
+        // Current relay stopped responding.
+        selector.record(addr(1), None, None);
+        selector.record(addr(2), Some(Duration::from_millis(50)), load(1.));
+        assert_eq!(selector.better(), Some(addr(2)));
+
+        selector.set_current(addr(2));
+        assert_eq!(selector.better(), None);
+    }
+}
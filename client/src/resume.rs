@@ -0,0 +1,203 @@
+//! Restoring virtual connections after the client restarts, e.g. after a crash.
+//!
+//! While running, the client saves its p2p sessions and virtual TCP channels it opened
+//! to the file set with [`crate::ClientBuilder::resume_state`]. On the next start, it
+//! sends `Disconnected` on the saved sessions, so peers drop them right away instead of
+//! waiting for their expiration, and opens the saved channels again.
+//!
+//! TCP sequence state isn't restored, so channels start anew and data in flight during
+//! the restart is lost. Peers accept `Disconnected` only from the address of the closed
+//! session, so the client should listen on the same address after restart.
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+
+use ya_relay_core::server_session::{SessionId, TransportType};
+use ya_relay_core::NodeId;
+
+use crate::client::{Client, GenericSender};
+use crate::transport::tcp_registry::{ChannelDirection, ChannelType, TcpState};
+
+/// Saving is skipped, if nothing changed since the last time.
+pub(crate) const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeState {
+    pub sessions: Vec<ResumeSession>,
+    pub channels: Vec<ResumeChannel>,
+}
+
+/// P2p session, which peer should close after the restart.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeSession {
+    pub node_id: NodeId,
+    pub session_id: SessionId,
+    pub remote: SocketAddr,
+}
+
+/// Outgoing virtual TCP channel, opened again after the restart.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeChannel {
+    pub node_id: NodeId,
+    pub transport: TransportType,
+}
+
+impl ResumeState {
+    /// Returns `None` if there is no saved state.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    /// Replaces the file in one step, so crashing while saving keeps the previous state.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub(crate) async fn capture(client: &Client) -> Self {
+        let layer = &client.transport.session_layer;
+        let relays = layer.relays.addrs();
+        let sessions = layer
+            .sessions()
+            .await
+            .into_iter()
+            .filter_map(|session| session.upgrade())
+            .filter(|session| !relays.contains(&session.raw.remote))
+            .map(|session| ResumeSession {
+                node_id: session.owner.default_id,
+                session_id: session.raw.id,
+                remote: session.raw.remote,
+            })
+            .collect();
+
+        let mut channels = Vec::new();
+        for node in client.transport.virtual_tcp.virt_nodes().await {
+            for channel in node.channels.iter() {
+                if channel.channel.1 != ChannelDirection::Out {
+                    continue;
+                }
+                if let TcpState::Connected(_) = channel.state().await {
+                    let transport = match channel.channel.0 {
+                        ChannelType::Messages => TransportType::Reliable,
+                        ChannelType::Transfer => TransportType::Transfer,
+                    };
+                    channels.push(ResumeChannel {
+                        node_id: node.id(),
+                        transport,
+                    });
+                }
+            }
+        }
+
+        ResumeState { sessions, channels }
+    }
+}
+
+/// Resumes connections from the saved state, then keeps saving the current one.
+pub(crate) async fn run(client: Client, path: PathBuf) {
+    let mut saved = match ResumeState::load(&path) {
+        Ok(Some(state)) => {
+            resume(&client, &state).await;
+            Some(state)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            log::warn!("Unable to load resume state from {}: {e}", path.display());
+            None
+        }
+    };
+
+    loop {
+        ya_relay_core::runtime::sleep(SAVE_INTERVAL).await;
+
+        let state = ResumeState::capture(&client).await;
+        if saved.as_ref() == Some(&state) {
+            continue;
+        }
+        match state.save(&path) {
+            Ok(_) => saved = Some(state),
+            Err(e) => log::warn!("Unable to save resume state to {}: {e}", path.display()),
+        }
+    }
+}
+
+async fn resume(client: &Client, state: &ResumeState) {
+    log::info!(
+        "Resuming {} sessions and {} channels from before restart",
+        state.sessions.len(),
+        state.channels.len()
+    );
+
+    let layer = &client.transport.session_layer;
+    for session in &state.sessions {
+        if let Err(e) = layer
+            .send_disconnect(session.session_id, session.remote)
+            .await
+        {
+            log::debug!(
+                "Unable to close previous session {} with [{}]: {e}",
+                session.session_id,
+                session.node_id
+            );
+        }
+    }
+
+    let reconnects = state.channels.iter().map(|channel| async move {
+        let result = async {
+            let mut sender = match channel.transport {
+                TransportType::Transfer => client.forward_transfer(channel.node_id).await?,
+                _ => client.forward_reliable(channel.node_id).await?,
+            };
+            sender.connect().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            log::info!(
+                "Unable to resume {} channel with [{}]: {e}",
+                channel.transport,
+                channel.node_id
+            );
+        }
+    });
+    futures::future::join_all(reconnects).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let path =
+            std::env::temp_dir().join(format!("ya-relay-resume-{}.json", rand::random::<u64>()));
+        assert_eq!(ResumeState::load(&path).unwrap(), None);
+
+        let state = ResumeState {
+            sessions: vec![ResumeSession {
+                node_id: NodeId::default(),
+                session_id: SessionId::generate(),
+                remote: "127.0.0.1:7464".parse().unwrap(),
+            }],
+            channels: vec![ResumeChannel {
+                node_id: NodeId::default(),
+                transport: TransportType::Transfer,
+            }],
+        };
+        state.save(&path).unwrap();
+        assert_eq!(ResumeState::load(&path).unwrap(), Some(state));
+
+        fs::write(&path, b"{").unwrap();
+        assert!(ResumeState::load(&path).is_err());
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,266 @@
+//! Retrying of session setup, Node lookups, connecting to Nodes and re-registration
+//! on the relay server.
+//!
+//! Only transient failures, like timeouts or network errors, are retried. Errors
+//! answered by the other side, e.g. unknown Node or untrusted relay, are returned at once.
+use rand::Rng;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use ya_relay_core::clock::ClockRef;
+
+use crate::dispatch::StatusError;
+
+/// Delay before the next attempt, before adding jitter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backoff {
+    /// Same delay before each retry.
+    Fixed(Duration),
+    /// Delay starts at `initial` and grows `multiplier` times with each retry, up to `max`.
+    Exponential {
+        initial: Duration,
+        max: Duration,
+        multiplier: f64,
+    },
+}
+
+/// How many times and how often failed operations are attempted again.
+///
+/// Without `max_attempts` or `max_elapsed` budget, operation is retried until it succeeds.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use ya_relay_client::retry::RetryPolicy;
+///
+/// let policy = RetryPolicy::exponential(Duration::from_millis(200), Duration::from_secs(5))
+///     .jitter(0.5)
+///     .max_attempts(5)
+///     .max_elapsed(Duration::from_secs(30));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    backoff: Backoff,
+    jitter: f64,
+    max_attempts: Option<u32>,
+    max_elapsed: Option<Duration>,
+}
+
+impl RetryPolicy {
+    pub fn new(backoff: Backoff) -> Self {
+        RetryPolicy {
+            backoff,
+            jitter: 0.0,
+            max_attempts: None,
+            max_elapsed: None,
+        }
+    }
+
+    /// Single attempt, failures are returned to the caller.
+    pub fn never() -> Self {
+        Self::fixed(Duration::ZERO).max_attempts(1)
+    }
+
+    pub fn fixed(delay: Duration) -> Self {
+        Self::new(Backoff::Fixed(delay))
+    }
+
+    /// Delay doubled with each retry.
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        Self::new(Backoff::Exponential {
+            initial,
+            max,
+            multiplier: 2.0,
+        })
+    }
+
+    /// Randomizes each delay by up to `factor` (0.0 - 1.0) of it in both directions,
+    /// so clients failing at the same time don't retry at the same time.
+    pub fn jitter(mut self, factor: f64) -> Self {
+        self.jitter = factor.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Attempts in total, including the first one.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts.max(1));
+        self
+    }
+
+    /// Time after the first attempt, after which no retries are started.
+    pub fn max_elapsed(mut self, elapsed: Duration) -> Self {
+        self.max_elapsed = Some(elapsed);
+        self
+    }
+
+    /// Delay after `attempt` (counting from 1) failed, before adding jitter.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential {
+                initial,
+                max,
+                multiplier,
+            } => {
+                let exp = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+                let secs = initial.as_secs_f64() * multiplier.max(1.0).powi(exp);
+                Duration::from_secs_f64(secs.min(max.as_secs_f64()))
+            }
+        }
+    }
+
+    /// Delay before the next attempt or `None`, if the budget is exhausted.
+    pub fn next_delay(&self, attempt: u32, elapsed: Duration) -> Option<Duration> {
+        if self.max_attempts.map_or(false, |max| attempt >= max) {
+            return None;
+        }
+        let delay = self.with_jitter(self.delay(attempt));
+        match self.max_elapsed {
+            Some(max) if elapsed + delay > max => None,
+            _ => Some(delay),
+        }
+    }
+
+    fn with_jitter(&self, delay: Duration) -> Duration {
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + self.jitter * rand::thread_rng().gen_range(-1.0..=1.0);
+        delay.mul_f64(factor)
+    }
+
+    /// Calls `op` until it succeeds, fails with an error not accepted by `retryable`
+    /// or the budget is exhausted. Returns the last error in the latter cases.
+    pub(crate) async fn retry<T, E, F, Fut>(
+        &self,
+        clock: &ClockRef,
+        mut op: F,
+        retryable: impl Fn(&E) -> bool,
+    ) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Display,
+    {
+        let start = clock.now();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let error = match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            if !retryable(&error) {
+                return Err(error);
+            }
+            let delay = match self.next_delay(attempt, clock.now() - start) {
+                Some(delay) => delay,
+                None => return Err(error),
+            };
+
+            log::debug!("Attempt {attempt} failed: {error}. Retrying in {delay:?}.");
+            clock.sleep(delay).await;
+        }
+    }
+}
+
+/// Requests answered by the other side with an error status are not retried.
+pub(crate) fn is_transient_request_error(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<StatusError>().is_none()
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::never()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use ya_relay_core::clock::{Clock, MockClock};
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(500), Duration::from_secs(3));
+        let delays: Vec<_> = (1..=5).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 3000, 3000].map(Duration::from_millis)
+        );
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(3));
+
+        let policy = RetryPolicy::fixed(Duration::from_secs(1));
+        assert_eq!(policy.delay(10), Duration::from_secs(1));
+
+        let policy = RetryPolicy::fixed(Duration::from_secs(1)).jitter(0.5);
+        for attempt in 1..100 {
+            let delay = policy.next_delay(attempt, Duration::ZERO).unwrap();
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1500));
+        }
+    }
+
+    #[test]
+    fn test_budget() {
+        let policy = RetryPolicy::never();
+        assert_eq!(policy.next_delay(1, Duration::ZERO), None);
+
+        let policy = RetryPolicy::fixed(Duration::from_secs(1))
+            .max_attempts(3)
+            .max_elapsed(Duration::from_secs(10));
+        assert_eq!(
+            policy.next_delay(2, Duration::ZERO),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(policy.next_delay(3, Duration::ZERO), None);
+        assert_eq!(policy.next_delay(1, Duration::from_millis(9500)), None);
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let mock = MockClock::new();
+        let clock: ClockRef = Arc::new(mock.clone());
+        let start = clock.now();
+        let policy = RetryPolicy::fixed(Duration::from_secs(2)).max_attempts(5);
+
+        let attempts = Cell::new(0);
+        let retry = policy.retry(
+            &clock,
+            || {
+                attempts.set(attempts.get() + 1);
+                let result = match attempts.get() {
+                    3 => Ok(attempts.get()),
+                    _ => Err("timeout"),
+                };
+                async move { result }
+            },
+            |_| true,
+        );
+        let advance = async {
+            for _ in 0..2 {
+                mock.wait_for_sleepers(1).await;
+                mock.advance(Duration::from_secs(2));
+            }
+        };
+        let (result, _) = futures::join!(retry, advance);
+        assert_eq!(result, Ok(3));
+        assert_eq!(clock.now() - start, Duration::from_secs(4));
+
+        // Not retryable errors are returned at once.
+        attempts.set(0);
+        let result: Result<(), _> = policy
+            .retry(
+                &clock,
+                || {
+                    attempts.set(attempts.get() + 1);
+                    async { Err("not found") }
+                },
+                |e| *e != "not found",
+            )
+            .await;
+        assert_eq!(result, Err("not found"));
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,232 @@
+//! Announcing planned NodeId changes to connected Nodes.
+//!
+//! Before switching to a new identity, a Node sends [`crate::Client::announce_rotation`]
+//! to Nodes it has sessions with. The notice names the old and the new NodeId and is
+//! signed by the old key, so only the owner of the old identity can redirect its traffic.
+//!
+//! Receivers remember the new NodeId. Channels requested for the old NodeId afterwards are
+//! opened to the new one, so applications can keep their logical sessions with the Node.
+//! Notices are sent as unreliable forwards and never reach [`crate::Client::forward_receiver`].
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use ya_relay_core::crypto::recover_data_signer;
+use ya_relay_core::runtime::SystemTime;
+use ya_relay_core::NodeId;
+use ya_relay_util::Channel;
+
+use crate::client::Forwarded;
+
+const MAGIC: &[u8; 4] = b"yaRN";
+const NODE_ID_SIZE: usize = 20;
+const HEADER_SIZE: usize = MAGIC.len() + 2 * NODE_ID_SIZE;
+/// Rotations remembered at once. The oldest ones are forgotten above that.
+const MAX_ROTATIONS: usize = 1024;
+/// Longest chain of rotations followed, so a cycle can't loop forever.
+const MAX_CHAIN: usize = 8;
+
+/// NodeId change announced by another Node.
+#[derive(Clone, Debug)]
+pub struct RotationNotice {
+    pub old_id: NodeId,
+    pub new_id: NodeId,
+    pub received_at: SystemTime,
+}
+
+#[derive(Default)]
+struct State {
+    rotated: HashMap<NodeId, NodeId>,
+    /// Old ids in the order of notices, for forgetting the oldest ones.
+    order: Vec<NodeId>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Rotations {
+    state: Arc<Mutex<State>>,
+    events: Channel<RotationNotice>,
+}
+
+impl Rotations {
+    pub fn events(&self) -> Option<mpsc::UnboundedReceiver<RotationNotice>> {
+        self.events.receiver()
+    }
+
+    /// Current NodeId of a Node, which might have rotated its identity.
+    pub fn resolve(&self, node_id: NodeId) -> NodeId {
+        let state = self.state.lock();
+        let mut current = node_id;
+        for _ in 0..MAX_CHAIN {
+            match state.rotated.get(&current) {
+                Some(next) => current = *next,
+                None => break,
+            }
+        }
+        current
+    }
+
+    /// Takes out a rotation notice. Other forwards are returned untouched.
+    pub fn dispatch(&self, forwarded: Forwarded) -> Result<Option<RotationNotice>, Forwarded> {
+        let (old_id, new_id, signature) = match decode(forwarded.payload.as_ref()) {
+            Some(decoded) => decoded,
+            None => return Err(forwarded),
+        };
+
+        if forwarded.node_id != old_id {
+            log::debug!(
+                "Dropping rotation notice of [{old_id}] sent by [{}]",
+                forwarded.node_id
+            );
+            return Ok(None);
+        }
+        match recover_data_signer(&signed_data(old_id, new_id), signature) {
+            Ok(signer) if signer.node_id == old_id => (),
+            _ => {
+                log::debug!("Dropping rotation notice of [{old_id}] with invalid signature");
+                return Ok(None);
+            }
+        }
+        if old_id == new_id {
+            return Ok(None);
+        }
+
+        log::info!("Node [{old_id}] rotates its NodeId to [{new_id}]");
+        self.insert(old_id, new_id);
+        let notice = RotationNotice {
+            old_id,
+            new_id,
+            received_at: forwarded.received_at,
+        };
+        self.events.tx.send(notice.clone()).ok();
+        Ok(Some(notice))
+    }
+
+    fn insert(&self, old_id: NodeId, new_id: NodeId) {
+        let mut state = self.state.lock();
+        if state.rotated.insert(old_id, new_id).is_none() {
+            state.order.push(old_id);
+        }
+        // Rotating back to an older identity must not create a cycle.
+        state.rotated.remove(&new_id);
+        if state.order.len() > MAX_ROTATIONS {
+            let State { rotated, order } = &mut *state;
+            order.retain(|id| rotated.contains_key(id));
+            let excess = order.len().saturating_sub(MAX_ROTATIONS);
+            for id in order.drain(..excess) {
+                rotated.remove(&id);
+            }
+        }
+    }
+}
+
+/// Data signed by the old identity.
+pub(crate) fn signed_data(old_id: NodeId, new_id: NodeId) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_SIZE);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&old_id.into_array());
+    data.extend_from_slice(&new_id.into_array());
+    data
+}
+
+pub(crate) fn encode(old_id: NodeId, new_id: NodeId, signature: &[u8]) -> Vec<u8> {
+    let mut packet = signed_data(old_id, new_id);
+    packet.extend_from_slice(signature);
+    packet
+}
+
+fn decode(bytes: &[u8]) -> Option<(NodeId, NodeId, &[u8])> {
+    if bytes.len() <= HEADER_SIZE || !bytes.starts_with(MAGIC) {
+        return None;
+    }
+    let node_id = |at: usize| NodeId::from(&bytes[at..at + NODE_ID_SIZE]);
+    Some((
+        node_id(MAGIC.len()),
+        node_id(MAGIC.len() + NODE_ID_SIZE),
+        &bytes[HEADER_SIZE..],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_core::crypto::{sign_data, CryptoProvider, FallbackCryptoProvider};
+    use ya_relay_core::server_session::TransportType;
+
+    fn forwarded(node_id: NodeId, payload: Vec<u8>) -> Forwarded {
+        Forwarded {
+            transport: TransportType::Unreliable,
+            node_id,
+            payload: payload.into(),
+            session: None,
+            received_at: SystemTime::now(),
+        }
+    }
+
+    async fn notice(crypto: &FallbackCryptoProvider, new_id: NodeId) -> (NodeId, Vec<u8>) {
+        let old_id = crypto.default_id().await.unwrap();
+        let signer = crypto.get(old_id).await.unwrap();
+        let signature = sign_data(signer.as_ref(), &signed_data(old_id, new_id))
+            .await
+            .unwrap();
+        (old_id, encode(old_id, new_id, &signature))
+    }
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let rotations = Rotations::default();
+        let mut events = rotations.events().unwrap();
+        let crypto = FallbackCryptoProvider::default();
+        let new_id: NodeId = [7; 20].into();
+        let (old_id, packet) = notice(&crypto, new_id).await;
+
+        // Forged by another Node.
+        let other: NodeId = [1; 20].into();
+        assert!(matches!(
+            rotations.dispatch(forwarded(other, packet.clone())),
+            Ok(None)
+        ));
+        assert_eq!(rotations.resolve(old_id), old_id);
+
+        let notice = rotations
+            .dispatch(forwarded(old_id, packet))
+            .unwrap()
+            .unwrap();
+        assert_eq!((notice.old_id, notice.new_id), (old_id, new_id));
+        assert_eq!(events.try_recv().unwrap().new_id, new_id);
+        assert_eq!(rotations.resolve(old_id), new_id);
+
+        // Other payloads are passed on.
+        assert!(rotations
+            .dispatch(forwarded(old_id, b"data".to_vec()))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_signature() {
+        let rotations = Rotations::default();
+        let crypto = FallbackCryptoProvider::default();
+        let (old_id, mut packet) = notice(&crypto, [7; 20].into()).await;
+        // Redirected to another NodeId.
+        packet[MAGIC.len() + NODE_ID_SIZE] ^= 1;
+        assert!(matches!(
+            rotations.dispatch(forwarded(old_id, packet)),
+            Ok(None)
+        ));
+        assert_eq!(rotations.resolve(old_id), old_id);
+    }
+
+    #[test]
+    fn test_resolve_chain() {
+        let rotations = Rotations::default();
+        let node = |n: u8| NodeId::from([n; 20]);
+        rotations.insert(node(1), node(2));
+        rotations.insert(node(2), node(3));
+        assert_eq!(rotations.resolve(node(1)), node(3));
+
+        // Back to the first identity.
+        rotations.insert(node(3), node(1));
+        assert_eq!(rotations.resolve(node(3)), node(1));
+        assert_eq!(rotations.resolve(node(1)), node(1));
+    }
+}
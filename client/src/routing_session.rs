@@ -1,3 +1,4 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
 
 use ya_relay_core::identity::Identity;
@@ -5,9 +6,11 @@ use ya_relay_core::server_session::{SessionId, TransportType};
 use ya_relay_core::NodeId;
 use ya_relay_proto::proto::Payload;
 
+use crate::client::ClientConfig;
 use crate::direct_session::{DirectSession, NodeEntry};
 use crate::encryption::Encryption;
 use crate::error::SessionError;
+use crate::multipath::MultipathMode;
 use crate::raw_session::SessionType;
 use crate::session::SessionLayer;
 
@@ -27,6 +30,8 @@ pub struct NodeRouting {
     /// `DirectSession` contains all info (for example SlotID) required to send packets using this session.  
     pub route: Weak<DirectSession>,
     encryption: Encryption,
+    /// Packets striped so far, see [`MultipathMode::Stripe`].
+    striped: AtomicUsize,
 }
 
 impl NodeRouting {
@@ -39,6 +44,7 @@ impl NodeRouting {
             node,
             route: Arc::downgrade(&session),
             encryption,
+            striped: AtomicUsize::new(0),
         })
     }
 
@@ -50,33 +56,70 @@ impl NodeRouting {
         transport: TransportType,
     ) -> Result<(), SessionError> {
         if let Some(direct) = self.route.upgrade() {
-            log::trace!(
-                "Forwarding message ({}) to [{}] through [{}] ({}) (session id: {})",
-                transport,
-                self.node.default_id.node_id,
-                direct.owner.default_id,
-                direct.raw.remote,
-                direct.raw.id
-            );
-
-            let packet = self
-                .encryption
-                .encrypt(packet)
-                .await
-                .map_err(|e| SessionError::Internal(e.to_string()))?;
-
-            direct
-                .send(self.node.default_id.node_id, packet, transport, false)
-                .await
-                .map_err(|e| {
-                    SessionError::Network(format!("Sending packet to p2p routing session: {e}"))
-                })?;
-            return Ok(());
+            return self.send_via(&direct, packet, transport).await;
         }
         Err(SessionError::Unexpected(
             "Routing session closed unexpectedly.".to_string(),
         ))
     }
+
+    /// Sends over the P2P session and the `relay` session, according to `mode`.
+    /// Fails only if no path accepted the packet.
+    pub async fn send_multipath(
+        &self,
+        relay: &DirectSession,
+        mode: MultipathMode,
+        packet: Payload,
+        transport: TransportType,
+    ) -> Result<(), SessionError> {
+        match mode {
+            MultipathMode::Single => self.send(packet, transport).await,
+            MultipathMode::Stripe => match self.striped.fetch_add(1, Ordering::Relaxed) % 2 {
+                0 => self.send(packet, transport).await,
+                _ => self.send_via(relay, packet, transport).await,
+            },
+            MultipathMode::Duplicate => {
+                let (direct, relayed) = futures::join!(
+                    self.send(packet.clone(), transport),
+                    self.send_via(relay, packet, transport)
+                );
+                direct.or(relayed)
+            }
+        }
+    }
+
+    async fn send_via(
+        &self,
+        direct: &DirectSession,
+        packet: Payload,
+        transport: TransportType,
+    ) -> Result<(), SessionError> {
+        log::trace!(
+            "Forwarding message ({}) to [{}] through [{}] ({}) (session id: {})",
+            transport,
+            self.node.default_id.node_id,
+            direct.owner.default_id,
+            direct.raw.remote,
+            direct.raw.id
+        );
+
+        let packet = self
+            .encryption
+            .encrypt(packet)
+            .await
+            .map_err(|e| SessionError::Internal(e.to_string()))?;
+
+        direct
+            .send(self.node.default_id.node_id, packet, transport, false)
+            .await
+            .map_err(|e| match e.downcast::<SessionError>() {
+                Ok(e) => e,
+                Err(e) => {
+                    SessionError::Network(format!("Sending packet to p2p routing session: {e}"))
+                }
+            })?;
+        Ok(())
+    }
 }
 
 /// Interface structure for sending packets to other Nodes.
@@ -118,6 +161,10 @@ impl RoutingSender {
         }
     }
 
+    pub(crate) fn config(&self) -> &ClientConfig {
+        &self.layer.config
+    }
+
     /// Sends Payload to target Node. Creates session if it didn't exist.
     /// `transport` is only declaration which will be used to set flags in
     /// `Forward` packet.
@@ -148,6 +195,15 @@ impl RoutingSender {
                 }
             },
         };
+
+        let mode = self.layer.config.multipath;
+        if mode != MultipathMode::Single && transport != TransportType::Unreliable {
+            if let Some(relay) = self.layer.relay_route(&routing) {
+                return routing
+                    .send_multipath(&relay, mode, packet, transport)
+                    .await;
+            }
+        }
         routing.send(packet, transport).await
     }
 
@@ -172,6 +228,28 @@ impl RoutingSender {
         self.target
     }
 
+    /// Default id of the target Node, once routing to it is known.
+    pub(crate) fn default_id(&self) -> NodeId {
+        self.node_routing
+            .upgrade()
+            .map(|routing| routing.node.default_id)
+            .unwrap_or(self.target)
+    }
+
+    /// Sends Payload through unreliable channel, adding parity packets of
+    /// forward error correction, if it's used with the Node. See [`crate::fec`].
+    pub(crate) async fn send_unreliable(&mut self, packet: Payload) -> Result<(), SessionError> {
+        for packet in self.layer.fec.encode(self.default_id(), packet) {
+            self.send(packet, TransportType::Unreliable).await?;
+        }
+        Ok(())
+    }
+
+    /// False if the session used for forwarding is gone. `send` will establish a new one.
+    pub fn is_connected(&self) -> bool {
+        self.direct_session().is_some()
+    }
+
     pub fn route(&self) -> NodeId {
         if let Some(routing) = self.node_routing.upgrade() {
             if let Some(route) = routing.route.upgrade() {
@@ -191,6 +269,12 @@ impl RoutingSender {
         unimplemented!()
     }
 
+    /// Session used to forward packets: either direct session with the target
+    /// Node or session with the relay server.
+    pub(crate) fn direct_session(&self) -> Option<Arc<DirectSession>> {
+        self.node_routing.upgrade()?.route.upgrade()
+    }
+
     pub fn session_type(&self) -> SessionType {
         if let Some(routing) = self.node_routing.upgrade() {
             if let Some(route) = routing.route.upgrade() {
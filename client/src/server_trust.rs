@@ -0,0 +1,118 @@
+use anyhow::bail;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use ya_relay_core::server_identity::{self, PublicKey};
+
+/// Verification of the relay server identity during the session handshake.
+/// See [`crate::ClientBuilder::server_key`].
+#[derive(Clone, Debug, Default)]
+pub enum ServerTrust {
+    /// Responses aren't verified.
+    #[default]
+    Any,
+    /// Responses have to be signed with this key.
+    Key(PublicKey),
+    /// Key used by the server the first time is saved in the file and is expected later.
+    FirstUse(PathBuf),
+}
+
+impl ServerTrust {
+    pub(crate) fn is_enabled(&self) -> bool {
+        !matches!(self, ServerTrust::Any)
+    }
+
+    /// Checks the key, which signed handshake responses of the server at `addr`.
+    pub(crate) fn check(&self, addr: SocketAddr, key: &PublicKey) -> anyhow::Result<()> {
+        match self {
+            ServerTrust::Any => Ok(()),
+            ServerTrust::Key(expected) if expected == key => Ok(()),
+            ServerTrust::Key(expected) => bail!(
+                "Server {addr} signed with key {}, expected {}",
+                hex::encode(key.as_bytes()),
+                hex::encode(expected.as_bytes())
+            ),
+            ServerTrust::FirstUse(path) => {
+                let mut known = load_known_servers(path)?;
+                match known.get(&addr.to_string()) {
+                    Some(expected) => {
+                        let expected = server_identity::public_key_from_hex(expected)?;
+                        if expected != *key {
+                            bail!(
+                                "Server {addr} signed with key {}, known as {}",
+                                hex::encode(key.as_bytes()),
+                                hex::encode(expected.as_bytes())
+                            );
+                        }
+                    }
+                    None => {
+                        log::info!(
+                            "Trusting key {} of server {addr} on first use",
+                            hex::encode(key.as_bytes())
+                        );
+                        known.insert(addr.to_string(), hex::encode(key.as_bytes()));
+                        save_known_servers(path, &known)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Hex encoded keys by server address.
+fn load_known_servers(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    match fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save_known_servers(path: &Path, known: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(known)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_core::crypto::ed25519;
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let path = std::env::temp_dir().join(format!(
+            "ya-relay-known-servers-{}.json",
+            rand::random::<u64>()
+        ));
+        let trust = ServerTrust::FirstUse(path.clone());
+        let addr1 = "127.0.0.1:7464".parse().unwrap();
+        let addr2 = "127.0.0.1:7465".parse().unwrap();
+        let key1 = ed25519::generate().verifying_key();
+        let key2 = ed25519::generate().verifying_key();
+
+        trust.check(addr1, &key1).unwrap();
+        trust.check(addr1, &key1).unwrap();
+        assert!(trust.check(addr1, &key2).is_err());
+        trust.check(addr2, &key2).unwrap();
+
+        assert_eq!(load_known_servers(&path).unwrap().len(), 2);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_expected_key() {
+        let key = ed25519::generate().verifying_key();
+        let trust = ServerTrust::Key(key);
+        let addr = "127.0.0.1:7464".parse().unwrap();
+
+        trust.check(addr, &key).unwrap();
+        assert!(trust
+            .check(addr, &ed25519::generate().verifying_key())
+            .is_err());
+    }
+}
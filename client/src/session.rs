@@ -1,9 +1,13 @@
+mod error_log;
 mod expire;
 mod keep_alive;
 pub mod network_view;
+mod outage;
+mod relays;
 pub mod session_initializer;
 pub mod session_state;
 pub mod session_traits;
+mod suspend;
 
 use anyhow::{anyhow, bail};
 use async_trait::async_trait;
@@ -19,41 +23,56 @@ use std::iter::FromIterator;
 use std::net::SocketAddr;
 use std::sync::{Arc, Weak};
 use std::thread::sleep;
-use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{watch, RwLock};
 
+pub use self::error_log::ErrorEntry;
+use self::error_log::ErrorLog;
 use self::expire::track_sessions_expiration;
 use self::keep_alive::keep_alive_server_session;
 use self::network_view::{NetworkView, SessionLock, SessionPermit, Validity};
+use self::outage::RelayOutage;
+use self::relays::track_relays;
 use self::session_state::{RelayedState, ReverseState, SessionState};
-use crate::client::{ClientConfig, Forwarded};
+use self::suspend::{Idle, Suspension};
+use crate::cancel::cancellable;
+use crate::client::{ClientConfig, Forwarded, ForwardedSession};
 use crate::direct_session::{DirectSession, NodeEntry};
 use crate::dispatch::{dispatch, Handler};
 use crate::encryption::Encryption;
 use crate::error::{
     ProtocolError, ResultExt, SessionError, SessionInitError, SessionResult, TransitionError,
 };
-use crate::metrics::{metric_session_established, TARGET_ID};
+use crate::fec::Fec;
+use crate::hibernate::HibernatedSession;
+use crate::maintenance::MaintenanceNotice;
+use crate::metrics::{metric_forward_misrouted, metric_session_established, TARGET_ID};
+use crate::nat::{self, NatInfo};
 use crate::raw_session::{RawSession, SessionType};
+use crate::relay_selection::RelaySelector;
+use crate::retry::is_transient_request_error;
 use crate::routing_session::{NodeRouting, RoutingSender};
 use crate::session::session_initializer::SessionInitializer;
-use crate::transport::ForwardReceiver;
+use crate::unsolicited::{Held, Unsolicited, Verdict};
 
 use crate::error::SenderError::Session;
 use crate::session::session_state::SessionState::{Closed, FailedEstablish};
 use crate::session::session_traits::{SessionDeregistration, SessionRegistration};
 use crate::SessionError::Network;
 use ya_relay_core::identity::Identity;
+use ya_relay_core::intercept::{intercept_sink, intercept_stream};
+use ya_relay_core::runtime::spawn_abortable;
+use ya_relay_core::runtime::{Instant, SystemTime};
 use ya_relay_core::server_session::{Endpoint, NodeInfo, SessionId, TransportType};
-use ya_relay_core::udp_stream::{udp_bind, OutStream};
-use ya_relay_core::utils::spawn_local_abortable;
+use ya_relay_core::udp_stream::OutStream;
 use ya_relay_core::{challenge, NodeId};
 use ya_relay_proto::codec::PacketKind;
 use ya_relay_proto::proto;
 use ya_relay_proto::proto::control::disconnected::By;
 use ya_relay_proto::proto::control::ReverseConnection;
 use ya_relay_proto::proto::{is_direct_message, Forward, RequestId, SlotId};
-use ya_relay_stack::Channel;
+use ya_relay_util::Channel;
 
 type ReqFingerprint = (Vec<u8>, u64);
 
@@ -98,16 +117,29 @@ pub struct SessionLayer {
 
     pub(crate) registry: NetworkView,
     ingress_channel: Channel<Forwarded>,
+    pub(crate) maintenance: Channel<MaintenanceNotice>,
+    /// Relay server in use, out of the configured ones.
+    pub(crate) relays: Arc<RelaySelector>,
 
     // TODO: Could be per `Session`?
     processed_requests: Arc<Mutex<VecDeque<ReqFingerprint>>>,
+
+    pub(crate) suspension: Suspension,
+    pub(crate) idle: Idle,
+    pub(crate) outage: RelayOutage,
+    pub(crate) errors: ErrorLog,
+    pub(crate) unsolicited: Unsolicited,
+    pub(crate) fec: Fec,
+
+    /// If address is None after registering endpoints on Server, that means
+    /// we don't have public IP.
+    public_addr: Arc<watch::Sender<Option<SocketAddr>>>,
 }
 
 #[derive(Default)]
 pub struct SessionLayerState {
-    /// If address is None after registering endpoints on Server, that means
-    /// we don't have public IP.
-    public_addr: Option<SocketAddr>,
+    /// Result of the last `check_nat`.
+    nat: Option<NatInfo>,
     /// Equals to `None` when not listening
     pub(crate) bind_addr: Option<SocketAddr>,
 
@@ -134,7 +166,8 @@ impl SessionRegistration for SessionLayer {
     ) -> anyhow::Result<Arc<DirectSession>> {
         log::trace!("Calling register_session {id} [{node_id}] ({addr})");
 
-        let session = RawSession::new(addr, id, self.out_stream()?);
+        let session =
+            RawSession::with_clock(addr, id, self.out_stream()?, self.config.clock.clone());
 
         // We need a check this, because relay doesn't return identities list
         // and we don't have its public key (because relay doesn't have one).
@@ -303,6 +336,9 @@ impl SessionDeregistration for SessionLayer {
         session.raw.disconnect().await.ok();
 
         if session.owner.default_id == NodeId::default() {
+            if self.outage.lost() {
+                log::info!("Lost session with relay server ({})", session.raw.remote);
+            }
             let f = session.list();
             log::trace!(
                 "[close_session]: lost session with server - remove {} forwards",
@@ -373,11 +409,23 @@ impl SessionLayer {
 
         SessionLayer {
             sink: Arc::new(Mutex::new(None)),
+            unsolicited: Unsolicited::new(config.unsolicited),
+            fec: Fec::new(config.fec),
+            relays: Arc::new(RelaySelector::new(
+                config.srv_addr,
+                config.relay_selection.clone(),
+            )),
+            outage: RelayOutage::new(config.relay_outage_grace),
             config,
             state: Arc::new(Mutex::new(state)),
             registry: Default::default(),
             ingress_channel: Default::default(),
+            maintenance: Default::default(),
             processed_requests: Arc::new(Mutex::new(VecDeque::new())),
+            suspension: Default::default(),
+            idle: Default::default(),
+            errors: Default::default(),
+            public_addr: Arc::new(watch::channel(None).0),
         }
     }
 
@@ -390,24 +438,34 @@ impl SessionLayer {
         &mut self,
         handler: impl Handler + Clone + 'static,
     ) -> anyhow::Result<SocketAddr> {
-        let (stream, sink, bind_addr) = udp_bind(&self.config.bind_url).await?;
+        let (mut stream, mut sink, bind_addr) =
+            self.config.transport.bind(&self.config.bind_url).await?;
+        if let Some(interceptor) = &self.config.interceptor {
+            stream = intercept_stream(stream, interceptor.clone());
+            sink = intercept_sink(sink, interceptor.clone());
+        }
 
         {
             *self.sink.lock() = Some(sink.clone());
         }
 
+        let spawner = self.config.spawner.as_ref();
         let mut handles: Vec<AbortHandle> = Vec::from([
-            spawn_local_abortable(dispatch(handler, stream)),
-            spawn_local_abortable(track_sessions_expiration(self.clone())),
+            spawn_abortable(spawner, dispatch(handler, stream)),
+            spawn_abortable(spawner, track_sessions_expiration(self.clone())),
         ]);
 
         if self.config.auto_connect && !self.config.auto_connect_fail_fast {
-            handles.push(spawn_local_abortable(keep_alive_server_session(
-                self.clone(),
-            )));
+            handles.push(spawn_abortable(
+                spawner,
+                keep_alive_server_session(self.clone()),
+            ));
         } else {
             log::debug!("Keep alive server session not started");
         };
+        if self.config.relay_selection.is_some() {
+            handles.push(spawn_abortable(spawner, track_relays(self.clone())));
+        }
 
         {
             let mut state = self.state.lock();
@@ -464,6 +522,23 @@ impl SessionLayer {
         Ok(())
     }
 
+    /// Relay server session, which can still forward packets to the Node reached
+    /// over a P2P session, see [`crate::multipath`].
+    pub(crate) fn relay_route(&self, routing: &NodeRouting) -> Option<Arc<DirectSession>> {
+        let node_id = routing.node.default_id.node_id;
+        let direct = routing.route.upgrade()?;
+        if direct.owner.default_id != node_id {
+            return None;
+        }
+        let relay = self
+            .state
+            .lock()
+            .p2p_sessions
+            .get(&self.relays.current())
+            .cloned()?;
+        relay.find_slot(&node_id).map(|_| relay)
+    }
+
     pub async fn is_p2p(&self, node_id: NodeId) -> bool {
         match self.get_node_routing(node_id).await {
             None => false,
@@ -490,18 +565,60 @@ impl SessionLayer {
     }
 
     pub async fn get_public_addr(&self) -> Option<SocketAddr> {
-        self.state.lock().public_addr
+        *self.public_addr.borrow()
     }
 
+    /// Subscribers are notified only if the address differs from the previous one.
     pub async fn set_public_addr(&self, addr: Option<SocketAddr>) {
-        self.state.lock().public_addr = addr;
+        let changed = self.public_addr.send_if_modified(|current| {
+            if *current == addr {
+                return false;
+            }
+            *current = addr;
+            true
+        });
+        if changed {
+            log::info!(
+                "[{}] public address changed to {addr:?}",
+                self.config.node_id
+            );
+        }
+    }
+
+    pub fn watch_public_addr(&self) -> watch::Receiver<Option<SocketAddr>> {
+        self.public_addr.subscribe()
+    }
+
+    pub async fn check_nat(&self) -> anyhow::Result<NatInfo> {
+        let info = nat::check_nat(self).await?;
+        self.state.lock().nat = Some(info.clone());
+        Ok(info)
+    }
+
+    /// Session currently used to exchange packets with `node_id`.
+    pub(crate) fn forwarded_session(&self, node_id: NodeId) -> Option<ForwardedSession> {
+        let state = self.state.lock();
+        let routing = state.nodes.get(&node_id)?;
+        let route = routing.route.upgrade()?;
+        let session_type = match route.owner.default_id == routing.node.default_id.node_id {
+            true => SessionType::P2P,
+            false => SessionType::Relay,
+        };
+        Some(ForwardedSession {
+            id: route.raw.id,
+            session_type,
+        })
+    }
+
+    pub fn nat_info(&self) -> Option<NatInfo> {
+        self.state.lock().nat.clone()
     }
 
     pub fn get_local_addr(&self) -> Option<SocketAddr> {
         self.state.lock().bind_addr
     }
 
-    pub fn receiver(&self) -> Option<ForwardReceiver> {
+    pub fn receiver(&self) -> Option<UnboundedReceiver<Forwarded>> {
         self.ingress_channel.receiver()
     }
 
@@ -539,15 +656,23 @@ impl SessionLayer {
         }
 
         log::trace!("Querying Node [{node_id}] info, because it might be outdated.");
+        self.fetch_node_info(node_id).await
+    }
 
+    /// Queries information about Node from relay server, ignoring cached `NetworkView` entry.
+    async fn fetch_node_info(&self, node_id: NodeId) -> anyhow::Result<NodeInfo> {
         let server_session = self
             .server_session()
             .await
             .map_err(|e| anyhow!("Failed to get relay server session: {e}"))?;
-        let node = server_session
-            .raw
-            .find_node(node_id)
+        let find = self.config.retry.retry(
+            &self.config.clock,
+            || server_session.raw.find_node(node_id),
+            is_transient_request_error,
+        );
+        let node = cancellable(&self.config.cancel, find)
             .await
+            .map_err(SessionError::from)?
             .map_err(|e| anyhow!("Failed to find Node on relay server: {e}"))?;
 
         let info =
@@ -560,6 +685,25 @@ impl SessionLayer {
         Ok(info)
     }
 
+    /// Establishes routing to Node again, after sending through the previous one failed.
+    /// Routing without a working session is closed first, so the new one is built from
+    /// endpoints and slot queried from relay server, instead of the cached ones.
+    pub async fn reresolve(&self, node_id: NodeId) -> Result<RoutingSender, SessionError> {
+        match self.get_node_routing(node_id).await {
+            Some(routing) if routing.direct_session().is_some() => return Ok(routing),
+            Some(_) => {
+                log::debug!("[reresolve]: Routing to [{node_id}] lost its session. Closing.");
+                self.disconnect(node_id).await?;
+            }
+            None => {}
+        }
+
+        self.fetch_node_info(node_id)
+            .await
+            .map_err(|e| SessionError::NotFound(format!("Error querying node {node_id}: {e}")))?;
+        self.session(node_id).await
+    }
+
     /// Disconnects from provided Node and all secondary identities.
     /// If we had p2p session with Node, it will be closed.
     /// TODO: Function should be abort-safe
@@ -580,7 +724,7 @@ impl SessionLayer {
         let myself = self.clone();
         // Makes function abort-safe. Dropping this future won't stop execution
         // of closing function.
-        tokio::task::spawn_local(async move {
+        ya_relay_core::runtime::spawn_local(async move {
             let entry = myself.registry.guard(session.owner.default_id, &[]).await;
 
             entry.transition(SessionState::Closing).await?;
@@ -601,9 +745,16 @@ impl SessionLayer {
         false
     }
 
+    /// Like [`SessionLayer::session_filtered_connection_methods`], but retries according
+    /// to the client's retry policy, until cancelled with the client's token.
     pub async fn session(&self, node_id: NodeId) -> Result<RoutingSender, SessionError> {
-        self.session_filtered_connection_methods(node_id, vec![])
-            .await
+        self.unsolicited.contacted(node_id);
+        let session = self.config.retry.retry(
+            &self.config.clock,
+            || self.session_filtered_connection_methods(node_id, vec![]),
+            SessionError::is_transient,
+        );
+        cancellable(&self.config.cancel, session).await?
     }
 
     /// Returns `RoutingSender` which can be used to send packets to desired Node.
@@ -673,14 +824,19 @@ impl SessionLayer {
                 // Caller of `SessionLayer:session` can drop function execution at any time, but
                 // other threads can be waiting for initialization as well. That's why we initialize
                 // session in other task and wait for finish.
-                tokio::task::spawn_local(async move {
+                ya_relay_core::runtime::spawn_local(async move {
                     permit
                         .collect_results(
                             permit
                                 .run_abortable(myself.resolve(remote_id, &permit, &dont_use))
                                 .await,
                         )
-                        .on_err(|e| log::info!("Failed init session with {remote_id}. Error: {e}"))
+                        .on_err(|e| {
+                            log::info!("Failed init session with {remote_id}. Error: {e}");
+                            myself
+                                .errors
+                                .record(format!("Failed init session with [{remote_id}]: {e}"));
+                        })
                 })
                 .await
                 .map_err(|e| SessionError::Unexpected(e.to_string()))?
@@ -721,8 +877,7 @@ impl SessionLayer {
         // A little bit dirty hack, that we give default NodeId (0x00) for relay server.
         // TODO: In the future relays should have regular NodeId
         let remote_id = NodeId::default();
-        let addr = self.config.srv_addr;
-        let this = self.clone();
+        let addr = self.relays.current();
 
         log::trace!("Requested Relay server session with [{remote_id}] ({addr}).");
 
@@ -733,14 +888,51 @@ impl SessionLayer {
 
         log::trace!("Relay [{remote_id}] not found. Trying to establish session...");
 
-        let session = match self.registry.lock_outgoing(remote_id, &[addr], this).await {
+        let establish = self.config.retry.retry(
+            &self.config.clock,
+            || self.establish_server_session(remote_id, addr),
+            SessionError::is_transient,
+        );
+        let session = cancellable(&self.config.cancel, establish)
+            .await?
+            .map_err(|e| SessionError::Generic(e.to_string()))?;
+        self.relay_restored();
+
+        session.raw.dispatcher.handle_error(
+            proto::StatusCode::Unauthorized as i32,
+            true,
+            self.clone(),
+            Arc::downgrade(&session),
+            Self::error_handler(),
+        );
+
+        // TODO: Make sure this functionality is replaced in new code.
+        // let fast_lane = self.virtual_tcp_fast_lane.clone();
+        // session.raw.on_drop(move || {
+        //     fast_lane.borrow_mut().clear();
+        // });
+
+        Ok(session)
+    }
+
+    /// Single attempt to establish relay server session, or wait for the one being established.
+    async fn establish_server_session(
+        &self,
+        remote_id: NodeId,
+        addr: SocketAddr,
+    ) -> Result<Arc<DirectSession>, SessionError> {
+        match self
+            .registry
+            .lock_outgoing(remote_id, &[addr], self.clone())
+            .await
+        {
             SessionLock::Permit(mut permit) => {
                 let myself = self.clone();
 
                 // Caller of `SessionLayer:session` can drop function execution at any time, but
                 // other threads can be waiting for initialization as well. That's why we initialize
                 // session in other task and wait for finish.
-                tokio::task::spawn_local(async move {
+                ya_relay_core::runtime::spawn_local(async move {
                     permit
                         .collect_results(
                             permit
@@ -750,7 +942,10 @@ impl SessionLayer {
                         .on_err(|e| {
                             log::info!(
                                 "Failed init relay session with {remote_id} ({addr}). Error: {e}"
-                            )
+                            );
+                            myself
+                                .errors
+                                .record(format!("Failed init relay session ({addr}): {e}"));
                         })
                 })
                 .await
@@ -758,7 +953,34 @@ impl SessionLayer {
             }
             SessionLock::Wait(mut waiter) => waiter.await_for_finish().await,
         }
-        .map_err(|e| SessionError::Generic(e.to_string()))?;
+    }
+
+    /// Continues relay server session saved by [`crate::Client::hibernate`]. Fails, if
+    /// the relay doesn't answer a ping in the session, e.g. because it already expired.
+    pub(crate) async fn restore_server_session(
+        &self,
+        saved: HibernatedSession,
+    ) -> Result<Arc<DirectSession>, SessionError> {
+        let remote_id = NodeId::default();
+        let session = match self
+            .registry
+            .lock_outgoing(remote_id, &[saved.relay], self.clone())
+            .await
+        {
+            SessionLock::Permit(mut permit) => {
+                let myself = self.clone();
+                ya_relay_core::runtime::spawn_local(async move {
+                    permit.collect_results(
+                        permit
+                            .run_abortable(myself.try_restored_server_session(&saved, &permit))
+                            .await,
+                    )
+                })
+                .await
+                .map_err(|e| SessionError::Unexpected(e.to_string()))??
+            }
+            SessionLock::Wait(mut waiter) => return waiter.await_for_finish().await,
+        };
 
         session.raw.dispatcher.handle_error(
             proto::StatusCode::Unauthorized as i32,
@@ -767,16 +989,18 @@ impl SessionLayer {
             Arc::downgrade(&session),
             Self::error_handler(),
         );
-
-        // TODO: Make sure this functionality is replaced in new code.
-        // let fast_lane = self.virtual_tcp_fast_lane.clone();
-        // session.raw.on_drop(move || {
-        //     fast_lane.borrow_mut().clear();
-        // });
-
+        self.set_public_addr(saved.public_addr).await;
+        self.relay_restored();
         Ok(session)
     }
 
+    /// Forgets the relay server session without closing it, so the relay keeps it after
+    /// shutdown, see [`crate::Client::hibernate`].
+    pub(crate) fn detach_server_session(&self) -> Option<Arc<DirectSession>> {
+        let addr = self.relays.current();
+        self.state.lock().p2p_sessions.remove(&addr)
+    }
+
     /// Resolves connection to target Node using the best method available.
     /// First tries to establish p2p session and uses relayed connection as a fallback.
     /// You can list (`dont_use` field) methods that shouldn't be attempted.
@@ -871,23 +1095,39 @@ impl SessionLayer {
             Err(SessionInitError::Relay(_, e)) | Err(SessionInitError::P2P(_, e)) => return Err(e),
         };
 
-        let endpoints = session.raw.register_endpoints(vec![]).await?;
+        let endpoints = session
+            .raw
+            .register_endpoints(vec![], self.config.properties.clone())
+            .await?;
 
         // If there is any (correct) endpoint on the list, that means we have public IP.
-        if let Some(addr) = endpoints
+        // Address could change since the previous registration, e.g. after NAT remapped us.
+        let public_addr = endpoints
             .into_iter()
-            .find_map(|endpoint| endpoint.try_into().ok())
-        {
-            gauge!("ya-relay.client.public-address", 1.0);
-            self.set_public_addr(Some(addr)).await;
-        } else {
-            gauge!("ya-relay.client.public-address", 0.0);
-        }
+            .find_map(|endpoint| endpoint.try_into().ok());
+        match public_addr {
+            Some(_) => gauge!("ya-relay.client.public-address", 1.0),
+            None => gauge!("ya-relay.client.public-address", 0.0),
+        };
+        self.set_public_addr(public_addr).await;
 
         gauge!("ya-relay.client.session.type", ConnectionMethod::Direct.metric(), TARGET_ID => node_id.to_string());
         Ok(session)
     }
 
+    async fn try_restored_server_session(
+        &self,
+        saved: &HibernatedSession,
+        permit: &SessionPermit,
+    ) -> SessionResult<Arc<DirectSession>> {
+        let session = self
+            .get_protocol()?
+            .restore_server_session(saved, permit)
+            .await?;
+        session.raw.ping().await?;
+        Ok(session)
+    }
+
     pub async fn try_direct_session(
         &self,
         node_id: NodeId,
@@ -953,7 +1193,7 @@ impl SessionLayer {
         // We don't want to wait for connection finish, because we would need longer timeout for this.
         // But if we won't receive any message from other Node, we would like to exit early,
         // to try out different connection methods.
-        tokio::time::timeout(
+        ya_relay_core::runtime::timeout(
             self.config.reverse_connection_tmp_timeout,
             awaiting.await_handshake(),
         )
@@ -967,7 +1207,7 @@ impl SessionLayer {
 
         // If we have first handshake message from other node, we can wait with
         // longer timeout now, because we can hope, that this node is responsive.
-        let result = tokio::time::timeout(
+        let result = ya_relay_core::runtime::timeout(
             self.config.reverse_connection_real_timeout,
             awaiting.await_reverse_finish(),
         )
@@ -1026,6 +1266,20 @@ impl SessionLayer {
 
         log::info!("Using relay server [{server_id}] ({addr}) to forward packets to [{node_id}] (slot {slot})");
 
+        // Relay reassigned slots, so forwarding to other Nodes would reach wrong destinations.
+        for stale in server.update_slot_epoch(node.slot_epoch) {
+            if stale.default_id == node_id {
+                continue;
+            }
+            log::info!(
+                "Slot of Node [{}] on relay [{server_id}] is outdated. Disconnecting..",
+                stale.default_id
+            );
+            let this = self.clone();
+            ya_relay_core::runtime::spawn_local(async move {
+                this.disconnect(stale.default_id).await.ok()
+            });
+        }
         server.register(ids.clone().into(), slot);
 
         let routing = NodeRouting::new(
@@ -1142,7 +1396,7 @@ impl SessionLayer {
             request_id,
             session_id,
             proto::StatusCode::Ok,
-            proto::response::Pong {},
+            proto::response::Pong::default(),
         );
 
         if let Err(e) = self.send(packet, from).await {
@@ -1294,10 +1548,19 @@ impl SessionLayer {
         }
     }
 
-    async fn send_disconnect(&self, session_id: SessionId, addr: SocketAddr) -> anyhow::Result<()> {
+    pub(crate) async fn send_disconnect(
+        &self,
+        session_id: SessionId,
+        addr: SocketAddr,
+    ) -> anyhow::Result<()> {
         // Don't use temporary session, because we don't want to initialize session
         // with this address, nor receive the response.
-        let session = RawSession::new(addr, session_id, self.out_stream()?);
+        let session = RawSession::with_clock(
+            addr,
+            session_id,
+            self.out_stream()?,
+            self.config.clock.clone(),
+        );
         session.disconnect().await
     }
 
@@ -1310,8 +1573,8 @@ impl SessionLayer {
 
     fn filter_own_addresses(&self, endpoints: &[Endpoint]) -> Vec<SocketAddr> {
         let own_addrs: Vec<_> = {
-            let state = self.state.lock();
-            vec![state.bind_addr, state.public_addr]
+            let bind_addr = self.state.lock().bind_addr;
+            vec![bind_addr, *self.public_addr.borrow()]
                 .into_iter()
                 .flatten()
                 .collect()
@@ -1347,7 +1610,7 @@ impl Handler for SessionLayer {
             let fut = match kind {
                 ya_relay_proto::proto::control::Kind::ReverseConnection(message) => {
                     let myself = self;
-                    tokio::task::spawn_local(async move {
+                    ya_relay_core::runtime::spawn_local(async move {
                         myself
                             .on_reverse_connection(session_id, from, message)
                             .await
@@ -1383,6 +1646,35 @@ impl Handler for SessionLayer {
                     }
                 }
                 .boxed_local(),
+                ya_relay_proto::proto::control::Kind::Congestion(report) => async move {
+                    match self.find_session(from).await {
+                        Some(session) => session.pacer.on_congestion(&report),
+                        None => log::debug!("Congestion reported for unknown session with {from}"),
+                    }
+                }
+                .boxed_local(),
+                ya_relay_proto::proto::control::Kind::ForwardRejected(rejected) => async move {
+                    match self.find_session(from).await {
+                        Some(session) => session.on_forward_rejected(&rejected),
+                        None => log::debug!("Forward rejected by unknown session with {from}"),
+                    }
+                }
+                .boxed_local(),
+                ya_relay_proto::proto::control::Kind::Maintenance(maintenance) => {
+                    if from != self.relays.current() {
+                        log::debug!("Ignoring maintenance notice from {from}");
+                        return None;
+                    }
+                    let notice = MaintenanceNotice::from(maintenance);
+                    log::info!(
+                        "Relay server announced {:?} in {:?}: {}",
+                        notice.kind,
+                        notice.remaining(),
+                        notice.message
+                    );
+                    self.maintenance.tx.send(notice).ok();
+                    return None;
+                }
                 ya_relay_proto::proto::control::Kind::Disconnected(
                     proto::control::Disconnected { by: Some(by) },
                 ) => {
@@ -1455,10 +1747,12 @@ impl Handler for SessionLayer {
         from: SocketAddr,
         session: Option<Arc<DirectSession>>,
     ) -> Option<LocalBoxFuture<'static, ()>> {
+        let received_at = SystemTime::now();
         let reliable = forward.is_reliable();
         let _encrypted = forward.is_encrypted();
         let slot = forward.slot;
         let channel = self.ingress_channel.clone();
+        let errors = self.errors.clone();
 
         let myself = self;
         let fut = async move {
@@ -1470,6 +1764,12 @@ impl Handler for SessionLayer {
             );
 
             let session = match session {
+                None if myself.outage.is_active() => {
+                    // Sender may be known again, once the relay session is back.
+                    log::trace!("Holding forward from unknown address {from} until relay session is restored");
+                    myself.outage.hold(Held { forward, from, session: Weak::new() });
+                    return Ok(());
+                }
                 None => {
                     // In this case we can't establish session, because we don't have
                     // neither NodeId nor SlotId.
@@ -1481,21 +1781,56 @@ impl Handler for SessionLayer {
                 Some(session) => session,
             };
 
+            let relay = session.owner.default_id;
             let sender = if is_direct_message(slot) {
-                session.owner.default_id
+                // Relay server doesn't send its own data in forward packets.
+                if relay == NodeId::default() {
+                    return Err(misrouted(relay, format!("direct forward packet from relay server [{from}]")));
+                }
+                relay
             } else {
                 // Messages forwarded through relay server or other relay Node.
                 match { session.get_by_slot(slot) } {
                     Some(node) => node.default_id,
+                    // Only a relay server can forward packets from Nodes we don't know yet.
+                    // Packets from peers claiming to forward for others can't be trusted.
+                    None if relay != NodeId::default() => {
+                        return Err(misrouted(relay, format!("unregistered slot {slot} in session with [{relay}]")));
+                    }
                     None => {
+                        let arrived_on = Arc::downgrade(&session);
+                        let held = |forward: Forward| Held { forward, from, session: arrived_on.clone() };
+                        if let Some((node_id, verdict)) = myself.unsolicited.by_slot(slot) {
+                            match verdict {
+                                Verdict::Hold => myself.unsolicited.hold(node_id, held(forward)),
+                                _ => log::trace!("Dropping unsolicited forward from [{node_id}]"),
+                            }
+                            return Ok(());
+                        }
+
                         log::debug!(
                             "Forwarding from unknown Node (slot {slot}) through session [{from}]. Resolving.."
                         );
 
                         let session = myself.server_session().await?;
                         let node = session.raw.find_slot(slot).await?;
+                        if node.slot != slot {
+                            return Err(misrouted(relay, format!("relay resolved slot {slot} to Node with slot {}", node.slot)));
+                        }
                         let ident = Identity::try_from(&node)?;
 
+                        match myself.unsolicited.by_node(ident.node_id, slot) {
+                            Verdict::Accept => {}
+                            Verdict::Hold => {
+                                myself.unsolicited.hold(ident.node_id, held(forward));
+                                return Ok(());
+                            }
+                            Verdict::Drop => {
+                                log::debug!("Dropping unsolicited forward from [{}]", ident.node_id);
+                                return Ok(());
+                            }
+                        }
+
                         // TODO: Consider just adding node to `DirectSession` forwards list. If the other Node couldn't
                         //       establish p2p session with us, we won't be able to do this anyway.
                         log::debug!("Attempting to establish connection to Node {} (slot {})", ident.node_id, node.slot);
@@ -1516,10 +1851,19 @@ impl Handler for SessionLayer {
                 true => TransportType::Reliable,
                 false => TransportType::Unreliable,
             };
+            let session_type = match is_direct_message(slot) {
+                true => SessionType::P2P,
+                false => SessionType::Relay,
+            };
             let packet = Forwarded {
                 transport,
                 node_id: sender,
                 payload: forward.payload,
+                session: Some(ForwardedSession {
+                    id: session.raw.id,
+                    session_type,
+                }),
+                received_at,
             };
 
             channel.tx.send(packet).map_err(|e| anyhow!("SessionLayer can't pass packet to other layers: {e}"))?;
@@ -1527,7 +1871,10 @@ impl Handler for SessionLayer {
             session.record_incoming(sender, transport, size);
             anyhow::Result::<()>::Ok(())
         }
-        .map_err(move |e| log::debug!("Forward from {from} failed: {e}"))
+        .map_err(move |e| {
+            log::debug!("Forward from {from} failed: {e}");
+            errors.record(format!("Forward from {from} failed: {e}"));
+        })
         .map(|_| ());
 
         Some(fut.boxed_local())
@@ -1543,3 +1890,10 @@ impl ConnectionMethod {
         0.0
     }
 }
+
+/// Flags forward packet, which arrived on a slot not matching its session.
+fn misrouted(relay: NodeId, reason: String) -> anyhow::Error {
+    metric_forward_misrouted(relay);
+    log::warn!("Rejecting misrouted forward packet: {reason}");
+    anyhow!(reason)
+}
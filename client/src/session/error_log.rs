@@ -0,0 +1,41 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use ya_relay_core::runtime::Instant;
+
+const MAX_ENTRIES: usize = 32;
+
+/// Error, which occurred in background of the session layer.
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorEntry {
+    /// Serialized as time elapsed since the error.
+    #[serde(with = "crate::raw_session::elapsed")]
+    pub time: Instant,
+    pub message: String,
+}
+
+/// Keeps the most recent failures of session initialization and packet handling,
+/// which otherwise end up only in logs.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorLog {
+    entries: Arc<Mutex<VecDeque<ErrorEntry>>>,
+}
+
+impl ErrorLog {
+    pub fn record(&self, message: impl ToString) {
+        let mut entries = self.entries.lock();
+        if entries.len() >= MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(ErrorEntry {
+            time: Instant::now(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Newest entries first.
+    pub fn recent(&self) -> Vec<ErrorEntry> {
+        self.entries.lock().iter().rev().cloned().collect()
+    }
+}
@@ -1,13 +1,10 @@
 use crate::client::SessionError;
-use backoff::future::retry;
-use backoff::Error::Transient;
-use backoff::{Error, ExponentialBackoff};
 use std::cmp::min;
 use std::future::Future;
 use std::sync::Arc;
 use std::thread::sleep;
 use std::time::Duration;
-use tokio::time::Instant;
+use ya_relay_core::runtime::Instant;
 use ya_relay_core::{server_session, NodeId};
 
 use crate::direct_session::DirectSession;
@@ -17,9 +14,20 @@ use crate::session::session_traits::SessionDeregistration;
 use crate::session::SessionLayer;
 
 pub async fn track_sessions_expiration(layer: SessionLayer) {
-    let expiration = layer.config.session_expiration;
+    let clock = layer.config.clock.clone();
 
     loop {
+        // Sessions couldn't respond to pings while suspended, so wait with
+        // checking them until resumed.
+        layer.suspension.resumed().await;
+
+        let mut idle = layer.idle.watch();
+        let is_idle = *idle.borrow_and_update();
+        let expiration = match is_idle {
+            true => layer.config.idle_session_expiration,
+            false => layer.config.session_expiration,
+        };
+
         log::trace!("[expire]: Checking, if all sessions are alive. Removing not active sessions.");
 
         let sessions = layer
@@ -28,13 +36,21 @@ pub async fn track_sessions_expiration(layer: SessionLayer) {
             .into_iter()
             .filter_map(|session| session.upgrade())
             .collect::<Vec<_>>();
-        let now = Instant::now();
+        let now = clock.now();
+
+        // Relay server may advertise its own ping interval and session ttl. Sessions
+        // forwarding through a relay, which counts forwards as keep-alive, skip its ping interval.
+        let expirations = sessions
+            .iter()
+            .map(|session| session.expiration(expiration, is_idle))
+            .collect::<Vec<_>>();
 
         // Collect futures in vector and execute asynchronously, because pinging
         // can last a few seconds especially in case of inactive sessions.
         let ping_futures = sessions
             .iter()
-            .map(|session| session.raw.keep_alive(expiration))
+            .zip(&expirations)
+            .map(|(session, expiration)| session.raw.keep_alive(*expiration))
             .collect::<Vec<_>>();
 
         let last_seen = futures::future::join_all(ping_futures).await;
@@ -42,23 +58,35 @@ pub async fn track_sessions_expiration(layer: SessionLayer) {
         // Collect indices of Sessions to close.
         let expired_idx = last_seen
             .iter()
+            .zip(&expirations)
             .enumerate()
-            .filter_map(|(i, timestamp)| match *timestamp + expiration < now {
-                true => Some(i),
-                false => None,
-            })
+            .filter_map(
+                |(i, (timestamp, expiration))| match *timestamp + *expiration < now {
+                    true => Some(i),
+                    false => None,
+                },
+            )
             .collect::<Vec<_>>();
 
         log::trace!("Closing {} expired sessions.", expired_idx.len());
         close_sessions(layer.clone(), sessions, expired_idx).await;
 
-        let first_to_expiring = last_seen.iter().min().cloned().unwrap_or(now) + expiration;
+        let first_to_expiring = last_seen
+            .iter()
+            .zip(&expirations)
+            .map(|(timestamp, expiration)| *timestamp + *expiration)
+            .min()
+            .unwrap_or(now + expiration);
 
         log::trace!(
             "Next sessions cleanup: {:?}",
-            first_to_expiring.saturating_duration_since(Instant::now())
+            first_to_expiring.saturating_duration_since(clock.now())
         );
-        tokio::time::sleep_until(first_to_expiring).await;
+        // Waking up shortens the expiration, so sessions are checked again right away.
+        tokio::select! {
+            _ = clock.sleep_until(first_to_expiring) => (),
+            _ = idle.changed() => log::trace!("[expire]: Idle state changed."),
+        }
     }
 }
 
@@ -3,47 +3,43 @@ use crate::session::network_view::{NodeAwaiting, NodeView};
 use crate::session::session_state::SessionState;
 use crate::session::session_traits::SessionDeregistration;
 use crate::session::SessionLayer;
-use backoff::backoff::Backoff;
-use backoff::Error::Transient;
-use backoff::{Error, ExponentialBackoff};
 use futures::future::err;
 use log::trace;
 use std::time::Duration;
 use ya_relay_core::NodeId;
 
+use crate::cancel::cancellable;
+use crate::client::SessionError;
+use crate::retry::RetryPolicy;
+
 #[derive(Clone)]
 struct ServerSessionAnchor {
-    backoff_strategy: ExponentialBackoff,
+    policy: RetryPolicy,
 }
 
 impl ServerSessionAnchor {
-    pub fn new(max_interval: Duration) -> ServerSessionAnchor {
-        ServerSessionAnchor {
-            backoff_strategy: ExponentialBackoff {
-                multiplier: 2.0,
-                max_interval,
-                max_elapsed_time: None,
-                randomization_factor: 0.99,
-                ..Default::default()
-            },
-        }
+    pub fn new(policy: RetryPolicy) -> ServerSessionAnchor {
+        ServerSessionAnchor { policy }
     }
 
     async fn establish_server_session(&self, layer: &SessionLayer) {
-        let mut backoff_strategy = self.backoff_strategy.clone();
-        backoff_strategy.reset();
-
-        let mut establish_server_session_once = || async {
-            let server_session = layer.server_session().await;
-            Ok(server_session?)
-        };
-
-        let mut notify = |error, duration| {
-            log::trace!("Backoff: error={:?}, duration={:?}", error, duration);
+        let establish_server_session_once = || async {
+            layer.suspension.resumed().await;
+            layer.server_session().await
         };
 
-        backoff::future::retry_notify(backoff_strategy, establish_server_session_once, notify)
-            .await;
+        let establish = self.policy.retry(
+            &layer.config.clock,
+            establish_server_session_once,
+            SessionError::is_transient,
+        );
+        match cancellable(&layer.config.cancel, establish).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                log::debug!("[keep-alive]: giving up re-establishing server session: {e}")
+            }
+            Err(_) => log::debug!("[keep-alive]: re-establishing server session cancelled"),
+        }
     }
 
     async fn get_awaiting_notifier(&self, layer: &SessionLayer) -> Option<NodeAwaiting> {
@@ -54,14 +50,14 @@ impl ServerSessionAnchor {
                 return entry.as_ref().map(|entry| entry.awaiting_notifier());
             }
             //Wait for server session entry to be created
-            tokio::time::sleep(Duration::from_millis(100)).await;
+            ya_relay_core::runtime::sleep(Duration::from_millis(100)).await;
         }
     }
 }
 
 pub async fn keep_alive_server_session(layer: SessionLayer) {
     let mut awaiting_notifier: Option<NodeAwaiting> = None;
-    let mut anchor = ServerSessionAnchor::new(layer.config.server_session_reconnect_max_interval);
+    let mut anchor = ServerSessionAnchor::new(layer.config.reconnect);
 
     loop {
         // Get awaiting notifier for server session, this will poll with sleep if needed
@@ -77,7 +73,10 @@ pub async fn keep_alive_server_session(layer: SessionLayer) {
             .await;
 
         log::trace!("[keep-alive]: establishing server session");
-        //Re-establish server session using retry policy with exponential backoff.
+        //Re-establish server session using the reconnect policy.
         let server_session = anchor.establish_server_session(&layer).await;
+        if layer.config.cancel.is_cancelled() {
+            break;
+        }
     }
 }
@@ -1,7 +1,7 @@
 use anyhow::bail;
 use chrono::Utc;
 use futures::future::{AbortHandle, Abortable};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -136,6 +136,28 @@ impl NetworkView {
         Ok(())
     }
 
+    /// Information about Nodes, which doesn't need to be updated yet.
+    pub async fn entries(&self) -> Vec<NodeInfo> {
+        let views = {
+            let state = self.state.read().await;
+            state.by_node_id.values().cloned().collect::<Vec<_>>()
+        };
+
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        for view in views {
+            if view.id == NodeId::default() || !seen.insert(view.id) {
+                continue;
+            }
+            if let Validity::UpToDate(info) = view.info().await {
+                if !info.identities.is_empty() {
+                    entries.push(info);
+                }
+            }
+        }
+        entries
+    }
+
     pub async fn get_entry(&self, node_id: NodeId) -> Option<NodeView> {
         let state = self.state.read().await;
         state.find(node_id, &[])
@@ -260,6 +282,8 @@ pub struct NodeViewState {
     /// In the future we should store here slot assigned by us, that can be used to forward packets
     /// through our Node.
     slot: SlotId,
+    /// Epoch of the relay slot table `slot` comes from.
+    slot_epoch: u32,
 
     /// Handle to abort initialization that's currently in progress.
     /// We will have 2 abort handles during `ReverseConnection` initialization.
@@ -358,6 +382,7 @@ impl NodeView {
         let mut state = self.state.write().await;
 
         state.slot = info.slot;
+        state.slot_epoch = info.slot_epoch;
         state.supported_encryption = info.supported_encryption;
         // TODO: What should we do if identity lists differ? Is new list always better?
         state.node = info.identities;
@@ -421,6 +446,7 @@ impl NodeViewState {
         NodeInfo {
             identities: self.node.clone(),
             slot: self.slot,
+            slot_epoch: self.slot_epoch,
             endpoints: self
                 .addresses
                 .iter()
@@ -447,6 +473,7 @@ impl NodeView {
                 supported_encryption: vec![],
                 state: SessionState::Closed,
                 slot: FORWARD_SLOT_ID,
+                slot_epoch: 0,
                 abort_handle: vec![],
             })),
             state_notifier: Arc::new(notify_msg),
@@ -679,7 +706,7 @@ impl Drop for SessionPermit {
             "Dropping{is_reverse} `SessionPermit` for [{}].",
             self.registry.id
         );
-        tokio::task::spawn_local(SessionPermit::async_drop(
+        ya_relay_core::runtime::spawn_local(SessionPermit::async_drop(
             self.registry.clone(),
             self.layer.clone(),
             self.final_state(),
@@ -913,7 +940,7 @@ mod tests {
         let public_key = crypto.public_key().await.unwrap();
         let identity = Identity {
             node_id,
-            public_key,
+            public_key: public_key.into(),
         };
 
         let (sink, _) = futures::channel::mpsc::channel::<(PacketKind, SocketAddr)>(1);
@@ -0,0 +1,167 @@
+//! Outages of the relay server session.
+//!
+//! P2P sessions exchange packets without the relay server, but finding a new route to
+//! a Node and resolving senders of unknown forwards need it. While the relay session is
+//! being re-established, for [`crate::ClientBuilder::relay_outage_grace`]:
+//! - sending to a Node waits for the relay, instead of closing virtual connections with it,
+//! - forwards, which can't be resolved without the relay, are held and delivered once it's back.
+//!
+//! Brief relay outages don't break long-lived streams between directly connected Nodes.
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use ya_relay_core::runtime::Instant;
+
+use crate::dispatch::Handler;
+use crate::session::SessionLayer;
+use crate::unsolicited::Held;
+
+/// Forwards held during an outage. The oldest ones are dropped above that.
+const MAX_HELD: usize = 256;
+
+#[derive(Clone)]
+pub(crate) struct RelayOutage {
+    grace: Duration,
+    /// Since when the relay session is lost.
+    lost: Arc<watch::Sender<Option<Instant>>>,
+    held: Arc<Mutex<VecDeque<Held>>>,
+}
+
+impl RelayOutage {
+    pub fn new(grace: Duration) -> Self {
+        RelayOutage {
+            grace,
+            lost: Arc::new(watch::channel(None).0),
+            held: Default::default(),
+        }
+    }
+
+    /// Returns false if the session was already lost.
+    pub fn lost(&self) -> bool {
+        self.lost.send_if_modified(|lost| match lost {
+            Some(_) => false,
+            None => {
+                *lost = Some(Instant::now());
+                true
+            }
+        })
+    }
+
+    /// Returns forwards held during the outage.
+    pub fn restored(&self) -> Vec<Held> {
+        self.lost.send_replace(None);
+        self.held.lock().drain(..).collect()
+    }
+
+    /// True while the relay session is lost, within the grace period.
+    pub fn is_active(&self) -> bool {
+        self.remaining().is_some()
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        let lost = (*self.lost.borrow())?;
+        self.grace
+            .checked_sub(lost.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub fn hold(&self, held: Held) {
+        let mut queue = self.held.lock();
+        if queue.len() >= MAX_HELD {
+            queue.pop_front();
+        }
+        queue.push_back(held);
+    }
+
+    /// Waits until the relay session is restored, or the grace period ends.
+    /// Returns false in the latter case.
+    pub async fn wait(&self) -> bool {
+        let remaining = match self.remaining() {
+            Some(remaining) => remaining,
+            None => return self.lost.borrow().is_none(),
+        };
+        let mut rx = self.lost.subscribe();
+        // Sender is owned by `self`, so it can't be dropped while waiting.
+        ya_relay_core::runtime::timeout(remaining, rx.wait_for(Option::is_none))
+            .await
+            .is_ok()
+    }
+}
+
+impl SessionLayer {
+    /// Delivers forwards held while the relay session was lost.
+    pub(crate) fn relay_restored(&self) {
+        let held = self.outage.restored();
+        if held.is_empty() {
+            return;
+        }
+
+        log::debug!(
+            "Relay session restored, delivering {} held forwards",
+            held.len()
+        );
+        let myself = self.clone();
+        ya_relay_core::runtime::spawn_local(async move {
+            for held in held {
+                let session = match held.session.upgrade() {
+                    Some(session) => Some(session),
+                    None => myself.find_session(held.from).await,
+                };
+                if let Some(handle) = myself.clone().on_forward(held.forward, held.from, session) {
+                    handle.await;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Weak;
+    use ya_relay_proto::proto::Forward;
+
+    fn held(payload: u8) -> Held {
+        Held {
+            forward: Forward::unreliable([0; 16], 7, vec![payload]),
+            from: "127.0.0.1:7464".parse().unwrap(),
+            session: Weak::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outage() {
+        let outage = RelayOutage::new(Duration::from_secs(60));
+        assert!(!outage.is_active());
+        assert!(outage.wait().await);
+
+        assert!(outage.lost());
+        assert!(!outage.lost());
+        assert!(outage.is_active());
+        for i in 0..=MAX_HELD {
+            outage.hold(held(i as u8));
+        }
+
+        let (restored, held) = futures::join!(outage.wait(), async {
+            tokio::task::yield_now().await;
+            outage.restored()
+        });
+        assert!(restored);
+        assert!(!outage.is_active());
+        assert_eq!(held.len(), MAX_HELD);
+        assert_eq!(held[0].forward.payload.as_ref(), &[1]);
+        assert!(outage.restored().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_grace_period() {
+        let outage = RelayOutage::new(Duration::from_millis(50));
+        outage.lost();
+        assert!(outage.is_active());
+        assert!(!outage.wait().await);
+        assert!(!outage.is_active());
+        assert!(!outage.wait().await);
+    }
+}
@@ -0,0 +1,85 @@
+use std::net::SocketAddr;
+
+use crate::session::SessionLayer;
+
+/// Probes the configured relays from time to time and moves to a better one.
+pub async fn track_relays(layer: SessionLayer) {
+    let interval = layer.relays.config().probe_interval;
+    loop {
+        ya_relay_core::runtime::sleep(interval).await;
+        layer.suspension.resumed().await;
+        layer.idle.woken().await;
+
+        if let Some(addr) = layer.select_relay().await {
+            log::info!("[relays]: moved to relay {addr}");
+        }
+    }
+}
+
+impl SessionLayer {
+    /// Measures all configured relays and moves to the best one, if it's better
+    /// than the current one by the configured margin. Returns the relay moved to.
+    pub async fn select_relay(&self) -> Option<SocketAddr> {
+        let current = self.relays.current();
+        let connected = { self.state.lock().p2p_sessions.get(&current).cloned() };
+
+        for addr in self.relays.addrs() {
+            match &connected {
+                // Session in use is measured by pinging, instead of starting a handshake.
+                Some(session) if addr == current => {
+                    let rtt = match session.raw.ping().await {
+                        Ok(()) => Some(session.raw.dispatcher.last_ping()),
+                        Err(_) => None,
+                    };
+                    self.relays
+                        .record(addr, rtt, session.raw.dispatcher.last_load());
+                }
+                _ => {
+                    let probe = match self.get_protocol() {
+                        Ok(protocol) => protocol.probe_relay(addr).await,
+                        Err(e) => Err(e),
+                    };
+                    match probe {
+                        Ok((rtt, load)) => self.relays.record(addr, Some(rtt), load),
+                        Err(e) => {
+                            log::debug!("[relays]: probing relay {addr} failed: {e}");
+                            self.relays.record(addr, None, None);
+                        }
+                    }
+                }
+            }
+        }
+
+        let better = self.relays.better()?;
+        match self.switch_relay(better).await {
+            true => Some(better),
+            false => None,
+        }
+    }
+
+    /// Closes the session with the current relay and connects to `addr`. Stays
+    /// with the current relay, if that fails.
+    async fn switch_relay(&self, addr: SocketAddr) -> bool {
+        let previous = self.relays.current();
+        let session = { self.state.lock().p2p_sessions.get(&previous).cloned() };
+        self.relays.set_current(addr);
+
+        let session = match session {
+            Some(session) => session,
+            // Not connected yet, the relay will be used on connecting.
+            None => return true,
+        };
+
+        log::info!("Moving from relay {previous} to {addr}");
+        self.close_session(session).await.ok();
+        match self.server_session().await {
+            Ok(_) => true,
+            Err(e) => {
+                log::warn!("Moving to relay {addr} failed, staying with {previous}: {e}");
+                self.relays.set_current(previous);
+                self.server_session().await.ok();
+                false
+            }
+        }
+    }
+}
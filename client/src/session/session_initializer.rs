@@ -1,17 +1,20 @@
 use anyhow::bail;
-use futures::channel::mpsc;
 use futures::future::{AbortHandle, Abortable, LocalBoxFuture};
-use futures::{FutureExt, SinkExt, StreamExt, TryFutureExt};
+use futures::{FutureExt, SinkExt, TryFutureExt};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
-use tokio::sync::Semaphore;
-use tokio::time::timeout;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
 
 use ya_relay_core::challenge::{self, ChallengeDigest, RawChallenge};
+use ya_relay_core::clock;
 use ya_relay_core::crypto::Crypto;
+use ya_relay_core::forward_auth::KeyExchange;
+use ya_relay_core::identity::Identity;
+use ya_relay_core::server_identity;
 use ya_relay_core::server_session::SessionId;
 use ya_relay_core::udp_stream::OutStream;
 use ya_relay_proto::proto;
@@ -19,9 +22,11 @@ use ya_relay_proto::proto::RequestId;
 
 use super::network_view::SessionPermit;
 use crate::client::ClientConfig;
-use crate::direct_session::DirectSession;
+use crate::direct_session::{DirectSession, SessionParams};
 use crate::error::{ProtocolError, RequestError, SessionError, SessionInitError, SessionResult};
+use crate::hibernate::HibernatedSession;
 use crate::raw_session::RawSession;
+use crate::relay_selection::RelayLoad;
 use crate::session::session_state::InitState;
 use crate::session::session_traits::SessionRegistration;
 
@@ -76,7 +81,12 @@ impl SessionInitializer {
         let mut state = self.state.lock().unwrap();
         match state.tmp_sessions.get(addr) {
             None => {
-                let session = RawSession::new(*addr, SessionId::generate(), sink);
+                let session = RawSession::with_clock(
+                    *addr,
+                    SessionId::generate(),
+                    sink,
+                    self.config.clock.clone(),
+                );
                 state.tmp_sessions.insert(*addr, session.clone());
                 session
             }
@@ -90,6 +100,36 @@ impl SessionInitializer {
         state.tmp_sessions.get(addr).cloned()
     }
 
+    /// Starts a handshake with the relay server without finishing it, to measure
+    /// the round-trip time and read the load it reports. The relay doesn't keep
+    /// any state for sessions, which didn't answer the challenge.
+    pub(crate) async fn probe_relay(
+        &self,
+        addr: SocketAddr,
+    ) -> SessionResult<(Duration, Option<RelayLoad>)> {
+        if self.get_temporary_session(&addr).is_some() {
+            return Err(SessionError::NotApplicable(format!(
+                "Session with {addr} is being initialized"
+            )));
+        }
+        let tmp_session = self.temporary_session(&addr);
+        let (request, _) = self.prepare_session_request(false).await?;
+
+        let start = self.config.clock.now();
+        let response = tmp_session
+            .request::<proto::response::Session>(
+                request.into(),
+                vec![],
+                self.config.session_request_timeout,
+            )
+            .await;
+        let rtt = self.config.clock.now() - start;
+        self.cleanup_initialization(&tmp_session.id).await;
+
+        let load = response?.packet.load.as_ref().map(RelayLoad::from);
+        Ok((rtt, load))
+    }
+
     /// External layer is responsible for acquiring `SessionPermit` to make sure,
     /// that we are not processing 2 session initializations at the same time.
     ///
@@ -117,7 +157,13 @@ impl SessionInitializer {
 
         log::debug!("[{this_id}] initializing session with [{node_id}] ({addr})");
 
-        let (request, raw_challenge) = self.prepare_session_request(challenge).await?;
+        let (mut request, raw_challenge) = self.prepare_session_request(challenge).await?;
+        // Relay server signs its responses together with the nonce.
+        let nonce = match !challenge && config.server_trust.is_enabled() {
+            true => server_identity::generate_nonce(),
+            false => Vec::new(),
+        };
+        request.nonce.clone_from(&nonce);
         let response = tmp_session
             .request::<proto::response::Session>(
                 request.into(),
@@ -137,10 +183,27 @@ impl SessionInitializer {
                 "Expected ChallengeRequest while initializing session with {addr}".to_string(),
             )
         })?;
+        let server_key = match nonce.is_empty() {
+            true => None,
+            false => {
+                let message = server_identity::challenge_message(
+                    &nonce,
+                    &response.session_id,
+                    &challenge_req,
+                );
+                let key = server_identity::verify(&response.packet.server_signature, &message)
+                    .and_then(|key| config.server_trust.check(addr, &key).map(|_| key))
+                    .map_err(|e| ProtocolError::UntrustedServer(e.to_string()))?;
+                Some(key)
+            }
+        };
         let challenge_handle = self.solve_challenge(challenge_req).await;
 
         log::trace!("Solving challenge while establishing session with: [{node_id}] ({addr})");
 
+        // Relay server derives the key authenticating our Forwards.
+        let key_exchange = (!challenge).then(KeyExchange::generate);
+
         // with the current ECDSA scheme the public key
         // can be recovered from challenge signature
         let packet = proto::request::Session {
@@ -149,6 +212,11 @@ impl SessionInitializer {
                     .await
                     .map_err(|e| SessionError::Internal(e.to_string()))?,
             ),
+            nonce: nonce.clone(),
+            key_exchange: key_exchange
+                .as_ref()
+                .map(|key_exchange| key_exchange.public_key().to_vec())
+                .unwrap_or_default(),
             ..Default::default()
         };
 
@@ -174,6 +242,44 @@ impl SessionInitializer {
             .into());
         }
 
+        if let Some(server_key) = server_key {
+            let message = server_identity::session_message(
+                &nonce,
+                &response.session_id,
+                &response.packet.key_exchange,
+            );
+            match server_identity::verify(&response.packet.server_signature, &message) {
+                Ok(key) if key == server_key => (),
+                Ok(_) => {
+                    let _ = tmp_session.disconnect().await;
+                    return Err(ProtocolError::UntrustedServer(
+                        "Server key changed during the handshake".to_string(),
+                    )
+                    .into());
+                }
+                Err(e) => {
+                    let _ = tmp_session.disconnect().await;
+                    return Err(ProtocolError::UntrustedServer(e.to_string()).into());
+                }
+            }
+        }
+
+        let params = SessionParams::from(&response.packet);
+
+        // Older servers don't authenticate Forwards.
+        let forward_key = match key_exchange {
+            Some(key_exchange) if !response.packet.key_exchange.is_empty() => {
+                match key_exchange.derive(&response.packet.key_exchange, &response.session_id) {
+                    Ok(forward_key) => Some(forward_key),
+                    Err(e) => {
+                        let _ = tmp_session.disconnect().await;
+                        return Err(ProtocolError::InvalidResponse(e.to_string()).into());
+                    }
+                }
+            }
+            _ => None,
+        };
+
         let (remote_id, identities) = match {
             if challenge {
                 log::trace!("Validating challenge from: [{node_id}] ({addr})");
@@ -206,6 +312,10 @@ impl SessionInitializer {
             ))
             .into());
         }
+        if let Err(e) = config.key_pins.check(&identities) {
+            let _ = tmp_session.disconnect().await;
+            return Err(e.into());
+        }
 
         // We should be ready to receive messages from other party immediately
         // after we send ResumeForwarding. That's why we register session before.
@@ -217,6 +327,18 @@ impl SessionInitializer {
                 SessionError::Internal(format!("Failed to register session. Error: {e}"))
             })?;
 
+        if let Some(forward_key) = forward_key {
+            session.forward_key.set(forward_key).ok();
+        }
+        session
+            .raw
+            .dispatcher
+            .update_load(response.packet.load.as_ref());
+        if params != SessionParams::default() {
+            log::debug!("[{this_id}] session {session_id} parameters: {params:?}");
+            session.params.set(params).ok();
+        }
+
         guard
             .transition_outgoing(InitState::SessionRegistered)
             .await?;
@@ -238,7 +360,7 @@ impl SessionInitializer {
         // Otherwise we will have very big response time, when querying this information
         // directly after establishing session.
         let session_ = session.clone();
-        tokio::task::spawn_local(async move {
+        ya_relay_core::runtime::spawn_local(async move {
             session_.raw.ping().await.ok();
         });
 
@@ -296,6 +418,50 @@ impl SessionInitializer {
         Ok(session)
     }
 
+    /// Registers relay server session saved by [`crate::Client::hibernate`], skipping
+    /// the handshake. Caller should check, if the relay still knows the session.
+    pub(crate) async fn restore_server_session(
+        &self,
+        saved: &HibernatedSession,
+        permit: &SessionPermit,
+    ) -> SessionResult<Arc<DirectSession>> {
+        let guard = permit.registry.clone();
+        for state in [
+            InitState::Initializing,
+            InitState::ChallengeHandshake,
+            InitState::HandshakeResponse,
+            InitState::ChallengeVerified,
+        ] {
+            guard.transition_outgoing(state).await?;
+        }
+
+        let session = self
+            .layer
+            .register_session(saved.relay, saved.session_id, guard.id, vec![])
+            .await
+            .map_err(|e| {
+                SessionError::Internal(format!("Failed to register session. Error: {e}"))
+            })?;
+        if let Some(forward_key) = saved.forward_key {
+            session.forward_key.set(forward_key).ok();
+        }
+        if saved.params != SessionParams::default() {
+            session.params.set(saved.params).ok();
+        }
+
+        guard
+            .transition_outgoing(InitState::SessionRegistered)
+            .await?;
+        guard.transition_outgoing(InitState::Ready).await?;
+
+        log::info!(
+            "Restored session {} with NET relay server ({})",
+            saved.session_id,
+            saved.relay
+        );
+        Ok(session)
+    }
+
     /// External layer is responsible for acquiring `SessionPermit` to make sure,
     /// that we are not processing 2 session initializations at the same time.
     ///
@@ -324,7 +490,7 @@ impl SessionInitializer {
         let this = self.clone();
         let this1 = this.clone();
         let session = Abortable::new(
-            timeout(self.config.incoming_session_timeout, async move {
+            clock::timeout(&*self.config.clock, self.config.incoming_session_timeout, async move {
                 this.init_session_handler(
                     with, request_id, session_id, permit, request, receiver,
                 ).await
@@ -360,7 +526,7 @@ impl SessionInitializer {
         _from: SocketAddr,
         request: proto::request::Session,
     ) -> Result<(), SessionError> {
-        let mut sender = {
+        let sender = {
             match {
                 self.state
                     .lock()
@@ -429,7 +595,7 @@ impl SessionInitializer {
             None => futures::future::ok(proto::ChallengeResponse::default()).boxed_local(),
         };
 
-        if let Some((request_id, session)) = rc.next().await {
+        if let Some((request_id, session)) = rc.recv().await {
             log::debug!("Got challenge response from Node [{remote_id}] at address: {with}");
 
             guard
@@ -446,6 +612,7 @@ impl SessionInitializer {
                     None,
                 )
                 .map_err(|e| ProtocolError::InvalidChallenge(e.to_string()))?;
+            config.key_pins.check(&identities)?;
 
             log::debug!("Challenge from Node: [{node_id}], address: {with} verified.");
 
@@ -500,7 +667,7 @@ impl SessionInitializer {
 
             // Send ping to measure response time.
             let session_ = session.clone();
-            tokio::task::spawn_local(async move {
+            ya_relay_core::runtime::spawn_local(async move {
                 session_.raw.ping().await.ok();
             });
 
@@ -548,11 +715,16 @@ impl SessionInitializer {
         request: proto::ChallengeRequest,
     ) -> LocalBoxFuture<'a, anyhow::Result<proto::ChallengeResponse>> {
         let crypto_vec = match self.list_crypto().await {
+            Ok(crypto) => supported_crypto(crypto, request.caps),
+            Err(e) => Err(e),
+        };
+        let crypto_vec = match crypto_vec {
             Ok(crypto) => crypto,
             Err(e) => return Box::pin(futures::future::err(e)),
         };
 
         let limit = self.simultaneous_challenges.clone();
+        let options = self.config.challenge_solver.clone();
 
         // Compute challenge in different thread to avoid blocking runtime.
         // Note: computing starts here, not after awaiting.
@@ -561,10 +733,11 @@ impl SessionInitializer {
             // As tokio documentation for `spawn_blocking` states, number of blocking threads
             // can be very high, so for CPU consuming task, we need to manage number of threads ourselves.
             let _permit = limit.acquire().await?;
-            challenge::solve::<ChallengeDigest, _>(
+            challenge::solve_with::<ChallengeDigest, _>(
                 request.challenge,
                 request.difficulty,
                 crypto_vec,
+                options,
             )
             .await
         }
@@ -585,14 +758,11 @@ impl SessionInitializer {
 
         let mut identities = vec![];
         for id in crypto {
-            let pub_key = id
-                .public_key()
+            let key = id
+                .identity_key()
                 .await
                 .map_err(|e| SessionError::Internal(e.to_string()))?;
-            identities.push(proto::Identity {
-                node_id: pub_key.address().to_vec(),
-                public_key: pub_key.bytes().to_vec(),
-            })
+            identities.push(Identity::from(key).into())
         }
 
         request.identities = identities;
@@ -622,3 +792,23 @@ impl SessionInitializer {
         Ok(crypto_vec)
     }
 }
+
+/// Identities signing in a scheme accepted by the peer, according to `ChallengeRequest::caps`.
+/// Aliases the peer can't verify are left out, the default identity is required.
+fn supported_crypto(
+    crypto_vec: Vec<Rc<dyn Crypto>>,
+    caps: u64,
+) -> anyhow::Result<Vec<Rc<dyn Crypto>>> {
+    let mut supported = Vec::with_capacity(crypto_vec.len());
+    for (idx, crypto) in crypto_vec.into_iter().enumerate() {
+        let scheme = crypto.scheme();
+        if challenge::supports_scheme(caps, scheme) {
+            supported.push(crypto);
+        } else if idx == 0 {
+            bail!("Peer doesn't accept {scheme:?} signatures of the default identity");
+        } else {
+            log::debug!("Skipping {scheme:?} alias, not accepted by the peer");
+        }
+    }
+    Ok(supported)
+}
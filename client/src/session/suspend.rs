@@ -0,0 +1,78 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Set while the application is suspended, e.g. a mobile app moved to background.
+///
+/// Keep-alive, ping and expiration loops wait for resume, instead of sending packets
+/// and closing sessions, which couldn't respond during the suspension.
+#[derive(Clone)]
+pub(crate) struct Suspension {
+    state: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Suspension {
+    fn default() -> Self {
+        Suspension {
+            state: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Suspension {
+    /// Returns false if the state didn't change.
+    pub fn set(&self, suspended: bool) -> bool {
+        self.state.send_replace(suspended) != suspended
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    /// Completes immediately, if not suspended.
+    pub async fn resumed(&self) {
+        let mut rx = self.state.subscribe();
+        // Sender is owned by `self`, so it can't be dropped while waiting.
+        rx.wait_for(|suspended| !suspended).await.ok();
+    }
+}
+
+/// Set while the application is idle, but should stay reachable, e.g. a mobile app
+/// in background expecting incoming connections.
+///
+/// Sessions are kept alive with `idle_session_expiration` instead of the regular
+/// expiration, so fewer packets are sent.
+#[derive(Clone)]
+pub(crate) struct Idle {
+    state: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Idle {
+    fn default() -> Self {
+        Idle {
+            state: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Idle {
+    /// Returns false if the state didn't change.
+    pub fn set(&self, idle: bool) -> bool {
+        self.state.send_replace(idle) != idle
+    }
+
+    pub fn is_idle(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    /// Current state is marked as seen, so `changed` on the receiver completes on
+    /// the next change.
+    pub fn watch(&self) -> watch::Receiver<bool> {
+        self.state.subscribe()
+    }
+
+    /// Completes immediately, if not idle.
+    pub async fn woken(&self) {
+        let mut rx = self.state.subscribe();
+        rx.wait_for(|idle| !idle).await.ok();
+    }
+}
@@ -0,0 +1,658 @@
+//! Local SOCKS5 proxy into the relay network.
+//!
+//! Connections requested to `<node_id>.ya:<port>`, or to the virtual IPv6 address of a Node
+//! known to the client (see [`Client::resolve_address`]), are carried over the transfer
+//! channel to the target Node, which has to run the proxy as well and expose the port in
+//! [`SocksConfig::expose`]. The proxy takes the transfer channel for itself, multiplexing
+//! all connections to a Node over it. Reliable and unreliable packets are passed through
+//! to the receiver returned from [`SocksProxy::start`].
+//!
+//! Each end of a connection grants credit to the other as it writes received data to the
+//! local connection, like [`crate::stream`]. Nodes sending more than granted have the
+//! connection closed, and Nodes sending frames above the size used by the proxy have all
+//! their connections closed, so a peer can't make the proxy buffer without a limit.
+use anyhow::{anyhow, bail};
+use bytes::BytesMut;
+use futures::future::AbortHandle;
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+
+use ya_relay_core::utils::spawn_local_abortable;
+use ya_relay_core::NodeId;
+use ya_relay_proto::codec::forward::decode;
+
+use crate::client::{Client, Forwarded, GenericSender, TransportType};
+use crate::transport::ForwardReceiver;
+
+/// Top level domain of Node addresses.
+pub const DOMAIN: &str = "ya";
+/// Prefix of proxy frames.
+pub const MAGIC: &[u8; 4] = b"yaS5";
+
+const OPEN_TIMEOUT: Duration = Duration::from_secs(15);
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 1 + 4;
+/// Frames are sent with a length prefix, and carry a single read at most.
+const MAX_MESSAGE_SIZE: usize = 4 + HEADER_SIZE + READ_BUFFER_SIZE;
+/// Bytes an end of a connection can send, before the other one grants more credit.
+const WINDOW: u64 = 256 * 1024;
+/// Credit is granted in batches, instead of after every write.
+const CREDIT_BATCH: u64 = WINDOW / 4;
+
+const SOCKS_VERSION: u8 = 5;
+const CMD_CONNECT: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const NO_AUTH: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+/// SOCKS5 reply codes, also used for reporting why the stream was closed.
+mod reply {
+    pub const SUCCEEDED: u8 = 0;
+    pub const GENERAL_FAILURE: u8 = 1;
+    pub const NOT_ALLOWED: u8 = 2;
+    pub const HOST_UNREACHABLE: u8 = 4;
+    pub const CONNECTION_REFUSED: u8 = 5;
+    pub const TTL_EXPIRED: u8 = 6;
+    pub const COMMAND_NOT_SUPPORTED: u8 = 7;
+    pub const ADDRESS_NOT_SUPPORTED: u8 = 8;
+}
+
+#[derive(Clone, Debug)]
+pub struct SocksConfig {
+    pub listen: SocketAddr,
+    /// Ports other Nodes can connect to, mapped to local addresses serving them.
+    pub expose: HashMap<u16, SocketAddr>,
+}
+
+impl Default for SocksConfig {
+    fn default() -> Self {
+        SocksConfig {
+            listen: ([127, 0, 0, 1], 1080).into(),
+            expose: Default::default(),
+        }
+    }
+}
+
+/// Stops the proxy when dropped.
+pub struct SocksProxy {
+    addr: SocketAddr,
+    handles: Vec<AbortHandle>,
+}
+
+impl SocksProxy {
+    /// Starts listening for SOCKS5 connections and takes over the `Client`'s forward receiver.
+    pub async fn start(
+        client: &Client,
+        config: SocksConfig,
+    ) -> anyhow::Result<(SocksProxy, ForwardReceiver)> {
+        let receiver = client
+            .forward_receiver()
+            .await
+            .ok_or_else(|| anyhow!("Forward receiver already taken"))?;
+
+        let listener = TcpListener::bind(config.listen).await?;
+        let addr = listener.local_addr()?;
+        log::info!("[Socks] Listening on {addr}");
+
+        let shared = Rc::new(Shared {
+            client: client.clone(),
+            expose: config.expose,
+            next_id: AtomicU32::new(1),
+            streams: Default::default(),
+        });
+        let (passthrough_tx, passthrough_rx) = mpsc::channel(receiver.max_capacity());
+
+        let handles = vec![
+            spawn_local_abortable(shared.clone().accept(listener)),
+            spawn_local_abortable(shared.ingress(receiver, passthrough_tx)),
+        ];
+        Ok((SocksProxy { addr, handles }, passthrough_rx))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for SocksProxy {
+    fn drop(&mut self) {
+        self.handles.iter().for_each(|handle| handle.abort());
+    }
+}
+
+/// Parses `<node_id>.ya` host name.
+pub fn parse_host(host: &str) -> Option<NodeId> {
+    let (node_id, domain) = host.rsplit_once('.')?;
+    if !domain.eq_ignore_ascii_case(DOMAIN) {
+        return None;
+    }
+    node_id.parse().ok()
+}
+
+/// Target of a SOCKS request.
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    Node(NodeId),
+    /// Virtual IPv6 address of a Node.
+    Address(Ipv6Addr),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Initiator {
+    Local,
+    Remote,
+}
+
+type StreamKey = (NodeId, Initiator, u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Open = 1,
+    Accept = 2,
+    Data = 3,
+    /// Sender won't write to the stream anymore. Carries a reply code.
+    Close = 4,
+    /// Sender wrote data to its local connection. Carries the number of bytes.
+    Credit = 5,
+}
+
+#[derive(Debug)]
+enum Event {
+    Accepted,
+    Data(BytesMut),
+    Closed(u8),
+}
+
+struct Frame {
+    kind: Kind,
+    /// Whether the stream was opened by the sender of the frame.
+    sender_opened: bool,
+    stream: u32,
+    body: BytesMut,
+}
+
+impl Frame {
+    fn encode(kind: Kind, sender_opened: bool, stream: u32, body: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_SIZE + body.len());
+        frame.extend_from_slice(MAGIC);
+        frame.push(kind as u8);
+        frame.push(sender_opened as u8);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn decode(mut bytes: BytesMut) -> Option<Frame> {
+        if bytes.len() < HEADER_SIZE || !bytes.starts_with(MAGIC) {
+            return None;
+        }
+        let header = bytes.split_to(HEADER_SIZE);
+        let kind = match header[4] {
+            1 => Kind::Open,
+            2 => Kind::Accept,
+            3 => Kind::Data,
+            4 => Kind::Close,
+            5 => Kind::Credit,
+            _ => return None,
+        };
+        Some(Frame {
+            kind,
+            sender_opened: header[5] != 0,
+            stream: u32::from_be_bytes(header[6..10].try_into().ok()?),
+            body: bytes,
+        })
+    }
+}
+
+/// Credit of both directions of a stream.
+struct Flow {
+    /// Bytes the local end can send.
+    credit: Cell<u64>,
+    granted: Notify,
+    /// Set when the other Node won't read from the stream anymore.
+    reset: Cell<bool>,
+    /// Bytes the other Node can send.
+    receive_window: Cell<u64>,
+}
+
+impl Flow {
+    fn new() -> Rc<Self> {
+        Rc::new(Flow {
+            credit: Cell::new(WINDOW),
+            granted: Notify::new(),
+            reset: Cell::new(false),
+            receive_window: Cell::new(WINDOW),
+        })
+    }
+
+    /// Waits for credit and takes up to `max` bytes of it. Returns `None` after a reset.
+    async fn take(&self, max: usize) -> Option<usize> {
+        loop {
+            if self.reset.get() {
+                return None;
+            }
+            let credit = self.credit.get();
+            if credit > 0 {
+                let n = credit.min(max as u64);
+                self.credit.set(credit - n);
+                return Some(n as usize);
+            }
+            self.granted.notified().await;
+        }
+    }
+
+    fn grant(&self, credit: u64) {
+        self.credit.set(self.credit.get() + credit);
+        self.granted.notify_one();
+    }
+
+    fn reset(&self) {
+        self.reset.set(true);
+        self.granted.notify_one();
+    }
+
+    /// Returns `false` if the other Node sent more than it was granted.
+    fn receive(&self, len: usize) -> bool {
+        match self.receive_window.get().checked_sub(len as u64) {
+            Some(window) => {
+                self.receive_window.set(window);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+type StreamEnd = (mpsc::UnboundedSender<Event>, Rc<Flow>);
+
+struct Shared {
+    client: Client,
+    expose: HashMap<u16, SocketAddr>,
+    next_id: AtomicU32,
+    streams: Mutex<HashMap<StreamKey, StreamEnd>>,
+}
+
+impl Shared {
+    fn register(&self, key: StreamKey) -> (mpsc::UnboundedReceiver<Event>, Rc<Flow>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let flow = Flow::new();
+        self.streams.lock().insert(key, (tx, flow.clone()));
+        (rx, flow)
+    }
+
+    /// Closes all streams with `node_id`.
+    fn drop_node(&self, node_id: NodeId) {
+        self.streams
+            .lock()
+            .retain(|(stream_node, _, _), (_, flow)| {
+                if *stream_node != node_id {
+                    return true;
+                }
+                flow.reset();
+                false
+            });
+    }
+
+    async fn accept(self: Rc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("[Socks] Accepting connection failed: {e}");
+                    continue;
+                }
+            };
+            let myself = self.clone();
+            ya_relay_core::runtime::spawn_local(async move {
+                if let Err(e) = myself.handle_client(stream).await {
+                    log::debug!("[Socks] Connection from {peer} failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle_client(self: Rc<Self>, mut tcp: TcpStream) -> anyhow::Result<()> {
+        let (target, port) = match socks_handshake(&mut tcp).await? {
+            Ok(target) => target,
+            Err(code) => {
+                socks_reply(&mut tcp, code).await?;
+                bail!("rejected request, reply code {code}");
+            }
+        };
+        let node_id = match target {
+            Target::Node(node_id) => node_id,
+            Target::Address(address) => match self.client.resolve_address(address).await {
+                Some(node_id) => node_id,
+                None => {
+                    socks_reply(&mut tcp, reply::HOST_UNREACHABLE).await?;
+                    bail!("no known Node with virtual address {address}");
+                }
+            },
+        };
+        log::debug!("[Socks] Connecting to [{node_id}]:{port}");
+
+        let stream = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let key = (node_id, Initiator::Local, stream);
+        let (mut rx, flow) = self.register(key);
+
+        let opened = self
+            .send(node_id, Kind::Open, true, stream, &port.to_be_bytes())
+            .await;
+        let code = match opened {
+            Ok(_) => match ya_relay_core::runtime::timeout(OPEN_TIMEOUT, rx.recv()).await {
+                Ok(Some(Event::Accepted)) => reply::SUCCEEDED,
+                Ok(Some(Event::Closed(code))) => code,
+                Ok(_) => reply::GENERAL_FAILURE,
+                Err(_) => reply::TTL_EXPIRED,
+            },
+            Err(e) => {
+                log::debug!("[Socks] Can't reach [{node_id}]: {e}");
+                reply::HOST_UNREACHABLE
+            }
+        };
+
+        socks_reply(&mut tcp, code).await?;
+        if code != reply::SUCCEEDED {
+            self.streams.lock().remove(&key);
+            bail!("connecting to [{node_id}]:{port} failed, reply code {code}");
+        }
+
+        self.pump(key, tcp, rx, flow).await;
+        Ok(())
+    }
+
+    async fn handle_open(self: Rc<Self>, node_id: NodeId, stream: u32, body: BytesMut) {
+        let port = match body.as_ref().try_into() {
+            Ok(port) => u16::from_be_bytes(port),
+            Err(_) => return,
+        };
+        let key = (node_id, Initiator::Remote, stream);
+
+        let tcp = match self.expose.get(&port) {
+            Some(addr) => {
+                let stream_end = self.register(key);
+                TcpStream::connect(addr)
+                    .await
+                    .map(|tcp| (tcp, stream_end))
+                    .map_err(|e| {
+                        log::debug!("[Socks] Connecting to {addr} for [{node_id}] failed: {e}");
+                        reply::CONNECTION_REFUSED
+                    })
+            }
+            None => {
+                log::debug!("[Socks] [{node_id}] requested not exposed port {port}");
+                Err(reply::NOT_ALLOWED)
+            }
+        };
+
+        match tcp {
+            Ok((tcp, (rx, flow))) => {
+                if self
+                    .send(node_id, Kind::Accept, false, stream, &[])
+                    .await
+                    .is_ok()
+                {
+                    self.pump(key, tcp, rx, flow).await;
+                }
+            }
+            Err(code) => {
+                self.send(node_id, Kind::Close, false, stream, &[code])
+                    .await
+                    .ok();
+            }
+        }
+        self.streams.lock().remove(&key);
+    }
+
+    /// Copies data between the local connection and the stream, until both sides are closed.
+    async fn pump(
+        &self,
+        key: StreamKey,
+        tcp: TcpStream,
+        mut rx: mpsc::UnboundedReceiver<Event>,
+        flow: Rc<Flow>,
+    ) {
+        let (node_id, initiator, stream) = key;
+        let opened = initiator == Initiator::Local;
+        let (mut reader, mut writer) = tcp.into_split();
+
+        let outgoing = async {
+            let mut buf = vec![0u8; READ_BUFFER_SIZE];
+            let code = loop {
+                let max = match flow.take(buf.len()).await {
+                    Some(max) => max,
+                    None => break reply::GENERAL_FAILURE,
+                };
+                match reader.read(&mut buf[..max]).await {
+                    Ok(0) if flow.reset.get() => break reply::GENERAL_FAILURE,
+                    Ok(0) => break reply::SUCCEEDED,
+                    Ok(len) => {
+                        flow.grant((max - len) as u64);
+                        let sent = self
+                            .send(node_id, Kind::Data, opened, stream, &buf[..len])
+                            .await;
+                        if let Err(e) = sent {
+                            log::debug!("[Socks] Sending to [{node_id}] failed: {e}");
+                            return;
+                        }
+                    }
+                    Err(_) => break reply::GENERAL_FAILURE,
+                }
+            };
+            self.send(node_id, Kind::Close, opened, stream, &[code])
+                .await
+                .ok();
+        };
+
+        let incoming = async {
+            let mut consumed = 0;
+            while let Some(event) = rx.recv().await {
+                match event {
+                    Event::Data(data) => {
+                        if writer.write_all(&data).await.is_err() {
+                            break;
+                        }
+                        consumed += data.len() as u64;
+                        if consumed >= CREDIT_BATCH {
+                            flow.receive_window
+                                .set(flow.receive_window.get() + consumed);
+                            let credit = (consumed as u32).to_be_bytes();
+                            consumed = 0;
+                            self.send(node_id, Kind::Credit, opened, stream, &credit)
+                                .await
+                                .ok();
+                        }
+                    }
+                    Event::Closed(_) => break,
+                    Event::Accepted => {}
+                }
+            }
+            writer.shutdown().await.ok();
+        };
+
+        futures::join!(outgoing, incoming);
+        self.streams.lock().remove(&key);
+        log::debug!("[Socks] Stream {stream} with [{node_id}] closed");
+    }
+
+    async fn send(
+        &self,
+        node_id: NodeId,
+        kind: Kind,
+        sender_opened: bool,
+        stream: u32,
+        body: &[u8],
+    ) -> anyhow::Result<()> {
+        let frame = Frame::encode(kind, sender_opened, stream, body);
+        let mut sender = self.client.forward_transfer(node_id).await?.framed();
+        Ok(sender.send(frame.into()).await?)
+    }
+
+    async fn ingress(
+        self: Rc<Self>,
+        mut receiver: ForwardReceiver,
+        passthrough: mpsc::Sender<Forwarded>,
+    ) {
+        let mut buffers = HashMap::<NodeId, BytesMut>::new();
+
+        while let Some(forwarded) = receiver.recv().await {
+            if forwarded.transport != TransportType::Transfer {
+                passthrough.send(forwarded).await.ok();
+                continue;
+            }
+
+            let node_id = forwarded.node_id;
+            let buf = buffers.entry(node_id).or_default();
+            buf.extend_from_slice(forwarded.payload.as_ref());
+
+            let mut oversized = false;
+            loop {
+                if matches!(message_size(buf), Some(size) if size > MAX_MESSAGE_SIZE) {
+                    oversized = true;
+                    break;
+                }
+                let bytes = match decode(buf) {
+                    Ok(bytes) => bytes,
+                    Err(()) => break,
+                };
+                match Frame::decode(bytes) {
+                    Some(frame) => self.dispatch(node_id, frame),
+                    None => log::trace!("[Socks] Dropping invalid frame from [{node_id}]"),
+                }
+            }
+
+            if oversized {
+                log::debug!(
+                    "[Socks] [{node_id}] sent a message above {MAX_MESSAGE_SIZE} B, closing its streams"
+                );
+                buffers.remove(&node_id);
+                self.drop_node(node_id);
+            }
+        }
+    }
+
+    fn dispatch(self: &Rc<Self>, node_id: NodeId, frame: Frame) {
+        if frame.kind == Kind::Open {
+            if frame.sender_opened {
+                let open = self.clone().handle_open(node_id, frame.stream, frame.body);
+                ya_relay_core::runtime::spawn_local(open);
+            }
+            return;
+        }
+
+        let initiator = match frame.sender_opened {
+            true => Initiator::Remote,
+            false => Initiator::Local,
+        };
+        let key = (node_id, initiator, frame.stream);
+        let mut streams = self.streams.lock();
+        let (tx, flow) = match streams.get(&key) {
+            Some((tx, flow)) => (tx.clone(), flow.clone()),
+            None => {
+                log::trace!("[Socks] Frame for unknown stream {key:?}");
+                return;
+            }
+        };
+
+        let event = match frame.kind {
+            Kind::Accept => Event::Accepted,
+            Kind::Data if flow.receive(frame.body.len()) => Event::Data(frame.body),
+            Kind::Data => {
+                log::debug!("[Socks] [{node_id}] sent more than granted, closing stream {key:?}");
+                flow.reset();
+                streams.remove(&key);
+                return;
+            }
+            Kind::Close => {
+                let code = frame.body.first().copied().unwrap_or_default();
+                // Other Node failed, so it won't read anymore.
+                if code != reply::SUCCEEDED {
+                    flow.reset();
+                }
+                Event::Closed(code)
+            }
+            Kind::Credit => {
+                if let Ok(credit) = frame.body.as_ref().try_into() {
+                    flow.grant(u32::from_be_bytes(credit) as u64);
+                }
+                return;
+            }
+            Kind::Open => return,
+        };
+        tx.send(event).ok();
+    }
+}
+
+/// Size of the length prefixed message at the start of `buf`, once the prefix is received.
+fn message_size(buf: &[u8]) -> Option<usize> {
+    let prefix = buf.get(..4)?.try_into().ok()?;
+    Some(4 + u32::from_be_bytes(prefix) as usize)
+}
+
+/// Reads the greeting and the request. Returns the target or a reply code to reject it with.
+async fn socks_handshake<S>(stream: &mut S) -> anyhow::Result<Result<(Target, u16), u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        bail!("unsupported SOCKS version {}", header[0]);
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    if !methods.contains(&NO_AUTH) {
+        stream
+            .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHODS])
+            .await?;
+        bail!("no supported authentication method");
+    }
+    stream.write_all(&[SOCKS_VERSION, NO_AUTH]).await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, command, _, address_type] = request;
+    if version != SOCKS_VERSION {
+        bail!("unsupported SOCKS version {version}");
+    }
+    let target = match address_type {
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await? as usize;
+            let mut host = vec![0u8; len];
+            stream.read_exact(&mut host).await?;
+            parse_host(&String::from_utf8_lossy(&host)).map(Target::Node)
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Some(Target::Address(octets.into()))
+        }
+        _ => return Ok(Err(reply::ADDRESS_NOT_SUPPORTED)),
+    };
+    let port = stream.read_u16().await?;
+
+    if command != CMD_CONNECT {
+        return Ok(Err(reply::COMMAND_NOT_SUPPORTED));
+    }
+    match target {
+        Some(target) => Ok(Ok((target, port))),
+        None => Ok(Err(reply::HOST_UNREACHABLE)),
+    }
+}
+
+async fn socks_reply<S: AsyncWrite + Unpin>(stream: &mut S, code: u8) -> anyhow::Result<()> {
+    // Bound address isn't meaningful here, so it's always 0.0.0.0:0.
+    let reply = [SOCKS_VERSION, code, 0, 1, 0, 0, 0, 0, 0, 0];
+    Ok(stream.write_all(&reply).await?)
+}
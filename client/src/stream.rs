@@ -0,0 +1,640 @@
+//! Byte streams between Nodes over the transfer channel.
+//!
+//! [`Client::open_stream`](crate::Client::open_stream) returns a [`ForwardStream`]
+//! implementing `AsyncRead` and `AsyncWrite` of both `tokio` and `futures`, so protocol
+//! libraries can run on top of it as on a TCP connection. The other Node gets its end
+//! from [`IncomingStreams::accept`]. [`Client::send_stream`](crate::Client::send_stream)
+//! copies a reader to a Node and closes the stream, e.g. for sending files.
+//!
+//! Any number of streams with a Node can be open at once. They are multiplexed over
+//! a single virtual TCP connection in each direction.
+//!
+//! Writes are split into chunks filling whole virtual TCP segments, see [`chunk_size`].
+//! The reading side grants credit as the application consumes data, so a slow reader
+//! stops the writer after [`WINDOW`] bytes, instead of buffering the whole transfer.
+//!
+//! Frames are prefixed with [`MAGIC`] and taken out of the transfer channel before
+//! they reach [`Client::forward_receiver`](crate::Client::forward_receiver). Other transfer traffic is passed through,
+//! but it can't be mixed with a stream to the same Node, e.g. by the SOCKS proxy.
+use anyhow::bail;
+use futures::future::LocalBoxFuture;
+use futures::{ready, FutureExt};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+use ya_relay_core::NodeId;
+
+use crate::client::GenericSender;
+use crate::transport::TransportLayer;
+
+/// Prefix of stream frames.
+pub const MAGIC: &[u8; 4] = b"yaST";
+/// Bytes a writer can send before the reader grants more credit.
+pub const WINDOW: u64 = 1024 * 1024;
+
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 1 + 4 + 4;
+const MAX_DATA_LEN: usize = WINDOW as usize;
+/// Reader grants credit in batches, instead of after every read.
+const CREDIT_BATCH: u64 = WINDOW / 4;
+const SEGMENTS_PER_CHUNK: usize = 16;
+/// IPv6 and TCP headers of each virtual TCP segment.
+const SEGMENT_OVERHEAD: usize = 40 + 20;
+
+const KIND_DATA: u8 = 0;
+const KIND_END: u8 = 1;
+const KIND_CREDIT: u8 = 2;
+const KIND_OPEN: u8 = 3;
+const KIND_RESET: u8 = 4;
+
+/// Size of data in a single frame, so that the frame fills whole segments of virtual TCP
+/// with the given MTU.
+pub fn chunk_size(mtu: usize) -> usize {
+    let mss = mtu.saturating_sub(SEGMENT_OVERHEAD).max(HEADER_SIZE + 1);
+    mss * SEGMENTS_PER_CHUNK - HEADER_SIZE
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Initiator {
+    Local,
+    Remote,
+}
+
+type StreamKey = (NodeId, Initiator, u32);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Frame {
+    Open,
+    Data(Vec<u8>),
+    /// No more data will be written, but the stream can still be read.
+    End,
+    Credit(u32),
+    /// The stream was dropped, nothing will be read or written anymore.
+    Reset,
+}
+
+impl Frame {
+    /// `sender_opened` tells whether the stream was opened by the sender of the frame.
+    fn encode(&self, sender_opened: bool, stream: u32) -> Vec<u8> {
+        let (kind, value, body): (u8, u32, &[u8]) = match self {
+            Frame::Open => (KIND_OPEN, 0, &[]),
+            Frame::Data(data) => (KIND_DATA, data.len() as u32, data),
+            Frame::End => (KIND_END, 0, &[]),
+            Frame::Credit(credit) => (KIND_CREDIT, *credit, &[]),
+            Frame::Reset => (KIND_RESET, 0, &[]),
+        };
+
+        let mut frame = Vec::with_capacity(HEADER_SIZE + body.len());
+        frame.extend_from_slice(MAGIC);
+        frame.push(kind);
+        frame.push(sender_opened as u8);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(&value.to_be_bytes());
+        frame.extend_from_slice(body);
+        frame
+    }
+}
+
+/// Frame as received, with the header fields identifying its stream.
+#[derive(Debug, PartialEq, Eq)]
+struct Received {
+    sender_opened: bool,
+    stream: u32,
+    frame: Frame,
+}
+
+/// Reassembles frames from transfer channel chunks of each Node.
+#[derive(Default)]
+struct Reassembly {
+    partial: HashMap<NodeId, Vec<u8>>,
+}
+
+impl Reassembly {
+    /// Returns `None` if the chunk isn't a part of a stream.
+    fn push(&mut self, node_id: NodeId, chunk: &[u8]) -> Option<Vec<Received>> {
+        let mut buf = match self.partial.remove(&node_id) {
+            Some(mut buf) => {
+                buf.extend_from_slice(chunk);
+                buf
+            }
+            None if chunk.starts_with(MAGIC) => chunk.to_vec(),
+            None => return None,
+        };
+
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while buf.len() - offset >= HEADER_SIZE {
+            match Self::header(&buf[offset..]) {
+                Ok((kind, sender_opened, stream, value)) => {
+                    let start = offset + HEADER_SIZE;
+                    let end = start + if kind == KIND_DATA { value as usize } else { 0 };
+                    if buf.len() < end {
+                        break;
+                    }
+                    let frame = match kind {
+                        KIND_OPEN => Frame::Open,
+                        KIND_DATA => Frame::Data(buf[start..end].to_vec()),
+                        KIND_END => Frame::End,
+                        KIND_CREDIT => Frame::Credit(value),
+                        _ => Frame::Reset,
+                    };
+                    frames.push(Received {
+                        sender_opened,
+                        stream,
+                        frame,
+                    });
+                    offset = end;
+                }
+                Err(e) => {
+                    log::debug!("[Stream] Dropping malformed data from [{node_id}]: {e}");
+                    return Some(frames);
+                }
+            }
+        }
+
+        if offset < buf.len() {
+            self.partial.insert(node_id, buf.split_off(offset));
+        }
+        Some(frames)
+    }
+
+    fn header(buf: &[u8]) -> anyhow::Result<(u8, bool, u32, u32)> {
+        if !buf.starts_with(MAGIC) {
+            bail!("missing magic");
+        }
+        let kind = buf[4];
+        let sender_opened = buf[5] != 0;
+        let stream = u32::from_be_bytes([buf[6], buf[7], buf[8], buf[9]]);
+        let value = u32::from_be_bytes([buf[10], buf[11], buf[12], buf[13]]);
+        match kind {
+            KIND_DATA if value as usize > MAX_DATA_LEN => bail!("frame of {value} B too long"),
+            KIND_OPEN | KIND_DATA | KIND_END | KIND_CREDIT | KIND_RESET => {
+                Ok((kind, sender_opened, stream, value))
+            }
+            _ => bail!("unknown frame kind {kind}"),
+        }
+    }
+}
+
+/// Bytes the writer is allowed to send.
+struct Credit {
+    state: Mutex<CreditState>,
+}
+
+struct CreditState {
+    available: u64,
+    reset: bool,
+    waker: Option<Waker>,
+}
+
+impl Credit {
+    fn new() -> Arc<Self> {
+        Arc::new(Credit {
+            state: Mutex::new(CreditState {
+                available: WINDOW,
+                reset: false,
+                waker: None,
+            }),
+        })
+    }
+
+    fn poll_take(&self, cx: &mut Context<'_>, max: usize) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock();
+        if state.reset {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "Stream closed by the other Node",
+            )));
+        }
+        if state.available == 0 {
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = state.available.min(max as u64);
+        state.available -= n;
+        Poll::Ready(Ok(n as usize))
+    }
+
+    fn grant(&self, credit: u64) {
+        let mut state = self.state.lock();
+        state.available += credit;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        state.reset = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Both ends of a stream registered in [`Streams`].
+pub(crate) struct StreamEnds {
+    key: StreamKey,
+    rx: mpsc::UnboundedReceiver<Frame>,
+    credit: Arc<Credit>,
+}
+
+#[derive(Default)]
+struct StreamsState {
+    reassembly: Reassembly,
+    /// Reading ends of open streams.
+    readers: HashMap<StreamKey, mpsc::UnboundedSender<Frame>>,
+    /// Credit of writing ends of open streams.
+    writers: HashMap<StreamKey, Weak<Credit>>,
+    incoming: Option<mpsc::UnboundedSender<StreamEnds>>,
+}
+
+impl StreamsState {
+    fn register(&mut self, key: StreamKey) -> StreamEnds {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let credit = Credit::new();
+        self.readers.insert(key, tx);
+        self.writers.insert(key, Arc::downgrade(&credit));
+        StreamEnds { key, rx, credit }
+    }
+
+    fn deliver(&mut self, key: StreamKey, frame: Frame) {
+        let (node_id, _, stream) = key;
+        match frame {
+            Frame::Open if key.1 == Initiator::Remote => self.accept(key),
+            Frame::Open => (),
+            Frame::Credit(credit) => {
+                if let Some(credit_ref) = self.writers.get(&key).and_then(Weak::upgrade) {
+                    credit_ref.grant(credit as u64);
+                }
+            }
+            Frame::End => {
+                if let Some(reader) = self.readers.remove(&key) {
+                    reader.send(Frame::End).ok();
+                }
+            }
+            Frame::Reset => {
+                if let Some(reader) = self.readers.remove(&key) {
+                    reader.send(Frame::End).ok();
+                }
+                if let Some(credit) = self.writers.remove(&key).and_then(|w| w.upgrade()) {
+                    credit.reset();
+                }
+            }
+            frame => match self.readers.get(&key) {
+                Some(reader) => {
+                    if reader.send(frame).is_err() {
+                        self.readers.remove(&key);
+                    }
+                }
+                None => log::debug!(
+                    "[Stream] Dropping data from [{node_id}], stream {stream} isn't open"
+                ),
+            },
+        }
+    }
+
+    fn accept(&mut self, key: StreamKey) {
+        let incoming = match &self.incoming {
+            Some(incoming) => incoming.clone(),
+            None => {
+                log::debug!("[Stream] Ignoring stream from [{}], not listening", key.0);
+                return;
+            }
+        };
+        let ends = self.register(key);
+        if incoming.send(ends).is_err() {
+            self.incoming = None;
+            self.close(key);
+            return;
+        }
+        log::debug!("[Stream] Accepted stream {} from [{}]", key.2, key.0);
+    }
+
+    fn close(&mut self, key: StreamKey) {
+        self.readers.remove(&key);
+        self.writers.remove(&key);
+    }
+}
+
+/// Routes stream frames from the transfer channel to open streams.
+#[derive(Clone, Default)]
+pub(crate) struct Streams {
+    state: Arc<Mutex<StreamsState>>,
+    next_id: Arc<AtomicU32>,
+}
+
+impl Streams {
+    pub fn open(&self, node_id: NodeId) -> StreamEnds {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.state.lock().register((node_id, Initiator::Local, id))
+    }
+
+    /// Streams opened by other Nodes from now on. `None` if already listening.
+    pub fn listen(&self) -> Option<mpsc::UnboundedReceiver<StreamEnds>> {
+        let mut state = self.state.lock();
+        if matches!(&state.incoming, Some(tx) if !tx.is_closed()) {
+            return None;
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        state.incoming = Some(tx);
+        Some(rx)
+    }
+
+    /// Returns false, if the chunk isn't a part of a stream and should be passed on.
+    pub fn dispatch(&self, node_id: NodeId, chunk: &[u8]) -> bool {
+        let mut state = self.state.lock();
+        let frames = match state.reassembly.push(node_id, chunk) {
+            Some(frames) => frames,
+            None => return false,
+        };
+
+        for received in frames {
+            let initiator = match received.sender_opened {
+                true => Initiator::Remote,
+                false => Initiator::Local,
+            };
+            state.deliver((node_id, initiator, received.stream), received.frame);
+        }
+        true
+    }
+
+    fn close(&self, key: StreamKey) {
+        self.state.lock().close(key);
+    }
+}
+
+/// Streams opened by other Nodes, see [`Client::incoming_streams`](crate::Client::incoming_streams).
+pub struct IncomingStreams {
+    transport: TransportLayer,
+    rx: mpsc::UnboundedReceiver<StreamEnds>,
+}
+
+impl IncomingStreams {
+    pub(crate) fn new(transport: TransportLayer, rx: mpsc::UnboundedReceiver<StreamEnds>) -> Self {
+        Self { transport, rx }
+    }
+
+    pub async fn accept(&mut self) -> Option<ForwardStream> {
+        let ends = self.rx.recv().await?;
+        Some(ForwardStream::new(self.transport.clone(), ends))
+    }
+}
+
+/// Byte stream with another Node, carried by its virtual TCP connection.
+///
+/// Shutting the stream down tells the other Node there is nothing more to read, while
+/// the stream can still be read here. Dropping it closes it on both sides.
+pub struct ForwardStream {
+    key: StreamKey,
+    transport: TransportLayer,
+    chunk_size: usize,
+    rx: mpsc::UnboundedReceiver<Frame>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    eof: bool,
+    consumed: u64,
+    credit: Arc<Credit>,
+    sending: Option<LocalBoxFuture<'static, io::Result<()>>>,
+    shutdown: bool,
+}
+
+impl ForwardStream {
+    pub(crate) fn new(transport: TransportLayer, ends: StreamEnds) -> Self {
+        let chunk_size = chunk_size(transport.config.stack_config.max_transmission_unit);
+        ForwardStream {
+            key: ends.key,
+            transport,
+            chunk_size,
+            rx: ends.rx,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            eof: false,
+            consumed: 0,
+            credit: ends.credit,
+            sending: None,
+            shutdown: false,
+        }
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.key.0
+    }
+
+    /// Announces the stream to the other Node, before any data is written.
+    pub(crate) async fn announce(&self) -> io::Result<()> {
+        self.send_frame(Frame::Open).await
+    }
+
+    /// Largest write sent as a single frame. Buffering writes to this size avoids
+    /// partially filled virtual TCP segments.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn send_frame(&self, frame: Frame) -> LocalBoxFuture<'static, io::Result<()>> {
+        let transport = self.transport.clone();
+        let (node_id, initiator, stream) = self.key;
+        let frame = frame.encode(initiator == Initiator::Local, stream);
+        async move {
+            let mut tx = transport
+                .forward_transfer(node_id)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))?;
+            tx.send(frame.into())
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))
+        }
+        .boxed_local()
+    }
+
+    fn start_sending(&mut self, frame: Frame, cx: &mut Context<'_>) -> io::Result<()> {
+        let mut sending = self.send_frame(frame);
+        match sending.poll_unpin(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => {
+                self.sending = Some(sending);
+                Ok(())
+            }
+        }
+    }
+
+    fn poll_sending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(sending) = &mut self.sending {
+            let result = ready!(sending.poll_unpin(cx));
+            self.sending = None;
+            return Poll::Ready(result);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.read_pos += n;
+        self.consumed += n as u64;
+        if self.consumed >= CREDIT_BATCH {
+            let credit = Frame::Credit(self.consumed as u32);
+            self.consumed = 0;
+            let node_id = self.key.0;
+            let sending = self.send_frame(credit);
+            ya_relay_core::runtime::spawn_local(async move {
+                if let Err(e) = sending.await {
+                    log::debug!("[Stream] Unable to grant credit to [{node_id}]: {e}");
+                }
+            });
+        }
+    }
+}
+
+impl AsyncRead for ForwardStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.consume(n);
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.rx.poll_recv(cx)) {
+                Some(Frame::Data(data)) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                }
+                _ => this.eof = true,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for ForwardStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_sending(cx))?;
+        if this.shutdown {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Stream already shut down",
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = ready!(this.credit.poll_take(cx, buf.len().min(this.chunk_size)))?;
+        this.start_sending(Frame::Data(buf[..n].to_vec()), cx)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_sending(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_sending(cx))?;
+        if !this.shutdown {
+            this.shutdown = true;
+            this.start_sending(Frame::End, cx)?;
+        }
+        this.poll_sending(cx)
+    }
+}
+
+impl futures::io::AsyncRead for ForwardStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(buf);
+        ready!(AsyncRead::poll_read(self, cx, &mut buf))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+impl futures::io::AsyncWrite for ForwardStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(self, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(self, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(self, cx)
+    }
+}
+
+impl Drop for ForwardStream {
+    fn drop(&mut self) {
+        self.transport.streams.close(self.key);
+        let sending = self.send_frame(Frame::Reset);
+        ya_relay_core::runtime::spawn_local(async move {
+            sending.await.ok();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_fills_segments() {
+        let mtu = 1400;
+        let mss = mtu - SEGMENT_OVERHEAD;
+        assert_eq!((chunk_size(mtu) + HEADER_SIZE) % mss, 0);
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let node_id = NodeId::from([1u8; 20]);
+        let mut reassembly = Reassembly::default();
+
+        let received = |sender_opened, stream, frame| Received {
+            sender_opened,
+            stream,
+            frame,
+        };
+
+        let mut bytes = Frame::Data(vec![7u8; 100]).encode(true, 1);
+        bytes.extend(Frame::Credit(42).encode(false, 2));
+        bytes.extend(Frame::End.encode(true, 1));
+
+        assert_eq!(reassembly.push(node_id, &bytes[..50]), Some(vec![]));
+        assert_eq!(
+            reassembly.push(node_id, &bytes[50..120]),
+            Some(vec![received(true, 1, Frame::Data(vec![7u8; 100]))])
+        );
+        assert_eq!(
+            reassembly.push(node_id, &bytes[120..]),
+            Some(vec![
+                received(false, 2, Frame::Credit(42)),
+                received(true, 1, Frame::End)
+            ])
+        );
+        assert_eq!(reassembly.push(node_id, b"other"), None);
+    }
+}
@@ -12,5 +12,6 @@ pub mod private {
     pub use crate::session::session_initializer::SessionInitializer;
     pub use crate::session::session_state::SessionState;
     pub use crate::session::SessionLayer;
-    pub use crate::transport::tcp_registry::VirtNode;
+    #[cfg(feature = "virtual-tcp")]
+    pub use crate::transport::tcp_registry::{fallback_ipv6, to_ipv6, TcpRegistry, VirtNode};
 }
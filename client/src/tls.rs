@@ -0,0 +1,644 @@
+//! TLS over virtual connections, authenticated with Node identities.
+//!
+//! [`TlsConfig`] holds an ephemeral Ed25519 key with a self-signed X.509 certificate.
+//! The certificate carries an extension with a signature of the key made by the Node's
+//! identity, see [`Client::sign`]. The peer recovers the signer from it, so no certificate
+//! authority is involved: a handshake succeeds only if the other side is the expected Node.
+//! Both sides present certificates, and only TLS 1.3 is offered.
+//!
+//! Any `tokio` byte stream can be wrapped, typically a [`ForwardStream`](crate::stream::ForwardStream):
+//!
+//! ```ignore
+//! let tls = TlsConfig::new(&client).await?;
+//! let stream = client.open_stream(node_id).await?;
+//! let mut stream = tls.connect(stream, node_id).await?;
+//! ```
+use anyhow::anyhow;
+use futures::ready;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use rustls::client::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    Certificate, CertificateError, ClientConfig, ClientConnection, Connection,
+    DigitallySignedStruct, DistinguishedName, PrivateKey, ServerConfig, ServerConnection,
+    ServerName, SignatureScheme,
+};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use ya_relay_core::crypto::recover_data_signer;
+use ya_relay_core::NodeId;
+
+use crate::client::Client;
+
+/// Prefix of the data signed by the Node, binding the certificate key to its identity.
+const BINDING_DOMAIN: &[u8] = b"ya-relay:tls-key:";
+/// Name the client sends in the handshake. Peers are verified by Node id instead.
+const SERVER_NAME: &str = "node.ya";
+
+/// Object identifier `2.25.180438282389224915985840043432771728554`
+/// of the certificate extension with the identity signature.
+const OID_BINDING: &[u8] = &[
+    0x69, 0x82, 0x8f, 0xbf, 0x93, 0x8b, 0x90, 0xb6, 0xfa, 0x82, 0x83, 0xa6, 0xd8, 0xd7, 0xac, 0xed,
+    0x9f, 0x98, 0xe9, 0x2a,
+];
+/// Object identifier `1.3.101.112` of Ed25519.
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+/// Object identifier `2.5.4.3` of the common name attribute.
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Certificate and key presented to peers.
+#[derive(Clone)]
+pub struct TlsConfig {
+    node_id: NodeId,
+    certificate: Certificate,
+    key: PrivateKey,
+}
+
+impl TlsConfig {
+    /// Generates a certificate bound to the default identity of `client`.
+    pub async fn new(client: &Client) -> anyhow::Result<Self> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow!("Failed to generate TLS key"))?;
+        let key_pair =
+            Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|_| anyhow!("Invalid TLS key"))?;
+
+        let public_key = key_pair.public_key().as_ref();
+        let binding = client.sign(&binding_data(public_key)).await?;
+        let certificate = build_certificate(&key_pair, client.node_id(), &binding);
+
+        Ok(Self {
+            node_id: client.node_id(),
+            certificate: Certificate(certificate),
+            key: PrivateKey(pkcs8.as_ref().to_vec()),
+        })
+    }
+
+    /// Identity the certificate is bound to.
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Starts the handshake as TLS client. Fails unless the other side proves
+    /// to be `node_id`, which has to be the default id of the Node.
+    pub async fn connect<IO>(&self, io: IO, node_id: NodeId) -> io::Result<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(invalid_data)?
+            .with_custom_certificate_verifier(Arc::new(NodeVerifier(node_id)))
+            .with_client_auth_cert(vec![self.certificate.clone()], self.key.clone())
+            .map_err(invalid_data)?;
+        let server_name = ServerName::try_from(SERVER_NAME).map_err(invalid_data)?;
+        let conn = ClientConnection::new(Arc::new(config), server_name).map_err(invalid_data)?;
+        TlsStream::handshake(io, conn.into(), node_id).await
+    }
+
+    /// Waits for the handshake as TLS server. Fails unless the other side proves
+    /// to be `node_id`, e.g. the one returned by [`ForwardStream::node_id`](crate::stream::ForwardStream::node_id).
+    pub async fn accept<IO>(&self, io: IO, node_id: NodeId) -> io::Result<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(invalid_data)?
+            .with_client_cert_verifier(Arc::new(NodeVerifier(node_id)))
+            .with_single_cert(vec![self.certificate.clone()], self.key.clone())
+            .map_err(invalid_data)?;
+        let conn = ServerConnection::new(Arc::new(config)).map_err(invalid_data)?;
+        TlsStream::handshake(io, conn.into(), node_id).await
+    }
+}
+
+/// Encrypted stream with an authenticated Node.
+///
+/// Shutting down sends the TLS close notification and shuts the inner stream down.
+pub struct TlsStream<IO> {
+    io: IO,
+    conn: Connection,
+    peer_id: NodeId,
+    closing: bool,
+}
+
+impl<IO> TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn handshake(io: IO, conn: Connection, peer_id: NodeId) -> io::Result<Self> {
+        let mut stream = Self {
+            io,
+            conn,
+            peer_id,
+            closing: false,
+        };
+        futures::future::poll_fn(|cx| stream.poll_handshake(cx)).await?;
+        Ok(stream)
+    }
+
+    /// Node verified during the handshake.
+    pub fn peer_id(&self) -> NodeId {
+        self.peer_id
+    }
+
+    pub fn get_ref(&self) -> &IO {
+        &self.io
+    }
+
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.conn.is_handshaking() {
+            ready!(self.poll_write_tls(cx))?;
+            if !self.conn.is_handshaking() {
+                break;
+            }
+            if ready!(self.poll_read_tls(cx))? == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        // Last flight of the client.
+        self.poll_write_tls(cx)
+    }
+
+    /// Reads records from the inner stream. Returns 0 at its end.
+    fn poll_read_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut io = SyncIo {
+            io: &mut self.io,
+            cx,
+        };
+        let read = match self.conn.read_tls(&mut io) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+
+        if let Err(e) = self.conn.process_new_packets() {
+            // Best effort to deliver the alert.
+            let _ = self.poll_write_tls(cx);
+            return Poll::Ready(Err(invalid_data(e)));
+        }
+        Poll::Ready(Ok(read))
+    }
+
+    /// Writes all pending records to the inner stream.
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.conn.wants_write() {
+            let mut io = SyncIo {
+                io: &mut self.io,
+                cx,
+            };
+            match self.conn.write_tls(&mut io) {
+                Ok(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO> AsyncRead for TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.conn.reader().read(buf.initialize_unfilled()) {
+                Ok(read) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            // Key updates need a reply, it's sent with the next write if the inner stream is busy.
+            if let Poll::Ready(Err(e)) = this.poll_write_tls(cx) {
+                return Poll::Ready(Err(e));
+            }
+            ready!(this.poll_read_tls(cx))?;
+        }
+    }
+}
+
+impl<IO> AsyncWrite for TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let written = this.conn.writer().write(buf)?;
+            let flushed = this.poll_write_tls(cx)?;
+            if written > 0 || buf.is_empty() {
+                return Poll::Ready(Ok(written));
+            }
+            // Buffer of the connection is full.
+            ready!(flushed);
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.conn.writer().flush()?;
+        ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            this.conn.send_close_notify();
+            this.closing = true;
+        }
+        ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+/// Blocking IO interface of rustls over a polled stream.
+/// `Pending` is reported as `WouldBlock`, with the waker registered.
+struct SyncIo<'a, 'b, IO> {
+    io: &'a mut IO,
+    cx: &'a mut Context<'b>,
+}
+
+impl<IO: AsyncRead + Unpin> Read for SyncIo<'_, '_, IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> Write for SyncIo<'_, '_, IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// Accepts certificates bound to a single Node, for both sides of the handshake.
+struct NodeVerifier(NodeId);
+
+impl NodeVerifier {
+    fn verify(&self, certificate: &Certificate) -> Result<(), rustls::Error> {
+        let parsed = parse_certificate(&certificate.0).ok_or(rustls::Error::InvalidCertificate(
+            CertificateError::BadEncoding,
+        ))?;
+        let signer = recover_data_signer(&binding_data(parsed.public_key), parsed.binding)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadSignature))?;
+
+        if signer.node_id != self.0 {
+            log::debug!(
+                "[Tls] Certificate of [{}] presented instead of [{}]",
+                signer.node_id,
+                self.0
+            );
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(())
+    }
+
+    fn verify_signature(
+        &self,
+        message: &[u8],
+        certificate: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let bad_signature = rustls::Error::InvalidCertificate(CertificateError::BadSignature);
+        if dss.scheme != SignatureScheme::ED25519 {
+            return Err(bad_signature);
+        }
+        let parsed = parse_certificate(&certificate.0).ok_or(rustls::Error::InvalidCertificate(
+            CertificateError::BadEncoding,
+        ))?;
+        UnparsedPublicKey::new(&ED25519, parsed.public_key)
+            .verify(message, dss.signature())
+            .map_err(|_| bad_signature)?;
+        Ok(HandshakeSignatureValid::assertion())
+    }
+}
+
+impl ServerCertVerifier for NodeVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &Certificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(rustls::Error::General("TLS 1.2 is not supported".into()))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+
+    fn request_scts(&self) -> bool {
+        false
+    }
+}
+
+impl ClientCertVerifier for NodeVerifier {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.verify(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &Certificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(rustls::Error::General("TLS 1.2 is not supported".into()))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+fn binding_data(public_key: &[u8]) -> Vec<u8> {
+    [BINDING_DOMAIN, public_key].concat()
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+mod tag {
+    pub const INTEGER: u8 = 0x02;
+    pub const BIT_STRING: u8 = 0x03;
+    pub const OCTET_STRING: u8 = 0x04;
+    pub const OID: u8 = 0x06;
+    pub const UTF8_STRING: u8 = 0x0c;
+    pub const UTC_TIME: u8 = 0x17;
+    pub const GENERALIZED_TIME: u8 = 0x18;
+    pub const SEQUENCE: u8 = 0x30;
+    pub const SET: u8 = 0x31;
+    pub const VERSION: u8 = 0xa0;
+    pub const EXTENSIONS: u8 = 0xa3;
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn der_seq(items: &[&[u8]]) -> Vec<u8> {
+    der(tag::SEQUENCE, &items.concat())
+}
+
+/// Self-signed certificate of `key_pair`, valid forever. Subject is the Node id.
+fn build_certificate(key_pair: &Ed25519KeyPair, node_id: NodeId, binding: &[u8]) -> Vec<u8> {
+    let ed25519 = der_seq(&[&der(tag::OID, OID_ED25519)]);
+    let name = der_seq(&[&der(
+        tag::SET,
+        &der_seq(&[
+            &der(tag::OID, OID_COMMON_NAME),
+            &der(tag::UTF8_STRING, node_id.to_string().as_bytes()),
+        ]),
+    )]);
+    let validity = der_seq(&[
+        &der(tag::UTC_TIME, b"700101000000Z"),
+        &der(tag::GENERALIZED_TIME, b"99991231235959Z"),
+    ]);
+    let public_key = der_seq(&[
+        &ed25519,
+        &der(
+            tag::BIT_STRING,
+            &[&[0], key_pair.public_key().as_ref()].concat(),
+        ),
+    ]);
+    let extension = der_seq(&[
+        &der(tag::OID, OID_BINDING),
+        &der(tag::OCTET_STRING, binding),
+    ]);
+
+    let serial: [u8; 8] = rand::random();
+    let tbs = der_seq(&[
+        &der(tag::VERSION, &der(tag::INTEGER, &[2])),
+        // Leading byte keeps the number positive.
+        &der(tag::INTEGER, &[&[1], &serial[..]].concat()),
+        &ed25519,
+        &name,
+        &validity,
+        &name,
+        &public_key,
+        &der(tag::EXTENSIONS, &der_seq(&[&extension])),
+    ]);
+    let signature = key_pair.sign(&tbs);
+    der_seq(&[
+        &tbs,
+        &ed25519,
+        &der(tag::BIT_STRING, &[&[0], signature.as_ref()].concat()),
+    ])
+}
+
+struct ParsedCertificate<'a> {
+    public_key: &'a [u8],
+    binding: &'a [u8],
+}
+
+/// Reads a single DER element, returning its tag, content and the remaining input.
+fn read_der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > std::mem::size_of::<usize>() || input.len() < count {
+            return None;
+        }
+        let (bytes, rest) = input.split_at(count);
+        input = rest;
+        bytes.iter().fold(0usize, |len, b| (len << 8) | *b as usize)
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (content, rest) = input.split_at(len);
+    Some((tag, content, rest))
+}
+
+fn read_expected(input: &[u8], expected: u8) -> Option<(&[u8], &[u8])> {
+    match read_der(input)? {
+        (tag, content, rest) if tag == expected => Some((content, rest)),
+        _ => None,
+    }
+}
+
+/// Extracts the Ed25519 key and the identity signature from a certificate
+/// made by [`build_certificate`]. The rest of the certificate is not checked.
+fn parse_certificate(certificate: &[u8]) -> Option<ParsedCertificate> {
+    let (certificate, _) = read_expected(certificate, tag::SEQUENCE)?;
+    let (tbs, _) = read_expected(certificate, tag::SEQUENCE)?;
+
+    let (_version, tbs) = read_expected(tbs, tag::VERSION)?;
+    let (_serial, tbs) = read_expected(tbs, tag::INTEGER)?;
+    let (_signature, tbs) = read_expected(tbs, tag::SEQUENCE)?;
+    let (_issuer, tbs) = read_expected(tbs, tag::SEQUENCE)?;
+    let (_validity, tbs) = read_expected(tbs, tag::SEQUENCE)?;
+    let (_subject, tbs) = read_expected(tbs, tag::SEQUENCE)?;
+
+    let (public_key_info, tbs) = read_expected(tbs, tag::SEQUENCE)?;
+    let (algorithm, public_key_info) = read_expected(public_key_info, tag::SEQUENCE)?;
+    let (oid, _) = read_expected(algorithm, tag::OID)?;
+    if oid != OID_ED25519 {
+        return None;
+    }
+    let (public_key, _) = read_expected(public_key_info, tag::BIT_STRING)?;
+    let public_key = match public_key.split_first()? {
+        (0, key) => key,
+        _ => return None,
+    };
+
+    let (extensions, _) = read_expected(tbs, tag::EXTENSIONS)?;
+    let (mut extensions, _) = read_expected(extensions, tag::SEQUENCE)?;
+    while !extensions.is_empty() {
+        let (extension, rest) = read_expected(extensions, tag::SEQUENCE)?;
+        extensions = rest;
+        let (oid, extension) = read_expected(extension, tag::OID)?;
+        if oid == OID_BINDING {
+            let (binding, _) = read_expected(extension, tag::OCTET_STRING)?;
+            return Some(ParsedCertificate {
+                public_key,
+                binding,
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_core::crypto::{sign_data, FallbackCrypto};
+    use ya_relay_core::key::generate;
+
+    #[actix_rt::test]
+    async fn test_certificate_binding() {
+        let secret = generate();
+        let node_id = NodeId::from(*secret.public().address());
+        let crypto = FallbackCrypto::from(secret);
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let binding = sign_data(&crypto, &binding_data(key_pair.public_key().as_ref()))
+            .await
+            .unwrap();
+        let certificate = Certificate(build_certificate(&key_pair, node_id, &binding));
+
+        let parsed = parse_certificate(&certificate.0).unwrap();
+        assert_eq!(parsed.public_key, key_pair.public_key().as_ref());
+        assert!(NodeVerifier(node_id).verify(&certificate).is_ok());
+        assert!(NodeVerifier(NodeId::from([1u8; 20]))
+            .verify(&certificate)
+            .is_err());
+
+        // Key replaced by one not signed by the Node.
+        let other = Ed25519KeyPair::from_pkcs8(
+            Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .unwrap()
+                .as_ref(),
+        )
+        .unwrap();
+        let forged = Certificate(build_certificate(&other, node_id, &binding));
+        assert!(NodeVerifier(node_id).verify(&forged).is_err());
+
+        assert!(parse_certificate(&certificate.0[..certificate.0.len() / 2]).is_none());
+    }
+}
@@ -1,29 +1,50 @@
+#[cfg(feature = "virtual-tcp")]
+mod fair_queue;
+mod ingress;
+mod pool;
+#[cfg(feature = "virtual-tcp")]
 pub(crate) mod tcp_registry;
 pub mod transport_sender;
+#[cfg(feature = "virtual-tcp")]
 mod virtual_layer;
 
-use anyhow::{bail, Context};
-use futures::StreamExt;
+use anyhow::Context;
+use futures::{FutureExt, StreamExt};
 use parking_lot::Mutex;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::RwLock;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
+use ya_relay_core::runtime::Instant;
 use ya_relay_core::server_session::TransportType;
 use ya_relay_core::NodeId;
-use ya_relay_stack::Channel;
 
-use self::tcp_registry::ChannelType;
+use self::ingress::Ingress;
+pub use self::ingress::{ForwardReceiver, IngressConfig, IngressOverflow, IngressStats};
+use self::pool::{ConnectionPool, Pooled};
+pub use self::pool::{ForwardOptions, PoolConfig};
+#[cfg(feature = "virtual-tcp")]
+pub use self::tcp_registry::ConnectProgress;
+#[cfg(feature = "virtual-tcp")]
+use self::tcp_registry::{ChannelType, ProgressFn};
+#[cfg(feature = "virtual-tcp")]
 use self::virtual_layer::TcpLayer;
+use crate::bandwidth::{Bandwidth, Dispatched};
+use crate::cancel::cancellable;
 use crate::client::{ClientConfig, ForwardSender, Forwarded, GenericSender};
+use crate::error::ConnectError;
+use crate::fec::Decoded;
+use crate::middleware;
+use crate::multipath::{Dedup, MultipathMode};
+use crate::pubsub::PubSub;
+use crate::rotation::Rotations;
 use crate::session::SessionLayer;
-
-/// TODO: Consider using bounded channel. Tcp could have impression that we are receiving
-///       messages, despite we are only putting them into channel.
-pub type ForwardReceiver = tokio::sync::mpsc::UnboundedReceiver<Forwarded>;
+#[cfg(feature = "virtual-tcp")]
+use crate::stream::Streams;
 
 /// Responsible for sending data. Handles different kinds of transport types:
 /// - Unreliable [`TransportLayer::forward_unreliable`] - send raw packets without any delivery
@@ -33,49 +54,68 @@ pub type ForwardReceiver = tokio::sync::mpsc::UnboundedReceiver<Forwarded>;
 /// - Transfer [`TransportLayer::forward_transfer`] - uses the same transport as reliable protocol,
 ///   but should be used for heavier transfers. Packets are sent using separate channel, what helps
 ///   with avoiding blocking more important messages in sending queue.
+///
+/// Channels are pooled and reused by later calls for the same Node, according
+/// to [`ClientConfig::connection_pool`].
 #[derive(Clone)]
 pub struct TransportLayer {
     pub config: Arc<ClientConfig>,
 
     pub session_layer: SessionLayer,
+    #[cfg(feature = "virtual-tcp")]
     pub virtual_tcp: TcpLayer,
+    pub(crate) pubsub: PubSub,
+    pub(crate) rotations: Rotations,
+    pub(crate) bandwidth: Bandwidth,
+    dedup: Dedup,
+    #[cfg(feature = "virtual-tcp")]
+    pub(crate) streams: Streams,
 
     state: Arc<Mutex<TransportLayerState>>,
 
-    /// Shared channel with TcpLayer for sending processed packets to external layers.
-    ingress_channel: Channel<Forwarded>,
+    /// Shared with TcpLayer for sending processed packets to external layers.
+    ingress: Ingress,
 }
 
 #[derive(Default)]
 struct TransportLayerState {
-    /// Every default and secondary NodeId has separate entry here.
-    forward_unreliable: HashMap<NodeId, ForwardSender>,
-    forward_transfer: HashMap<NodeId, ForwardSender>,
-    forward_reliable: HashMap<NodeId, ForwardSender>,
+    pool: ConnectionPool,
 }
 
 impl TransportLayer {
     pub fn new(config: Arc<ClientConfig>) -> TransportLayer {
-        let out = Channel::<Forwarded>::default();
+        let ingress = Ingress::new(config.ingress);
         let session_layer = SessionLayer::new(config.clone());
+        #[cfg(feature = "virtual-tcp")]
+        let streams = Streams::default();
+        #[cfg(feature = "virtual-tcp")]
         let virtual_tcp = TcpLayer::new(
             &config.node_pub_key,
             &config.stack_config,
-            &out,
+            &ingress,
             session_layer.clone(),
+            streams.clone(),
         );
 
         TransportLayer {
             config,
             session_layer,
+            #[cfg(feature = "virtual-tcp")]
             virtual_tcp,
+            pubsub: Default::default(),
+            rotations: Default::default(),
+            bandwidth: Default::default(),
+            dedup: Default::default(),
+            #[cfg(feature = "virtual-tcp")]
+            streams,
             state: Default::default(),
-            ingress_channel: out,
+            ingress,
         }
     }
 
     pub(crate) async fn spawn(&mut self) -> anyhow::Result<SocketAddr> {
         let bind_addr = self.session_layer.spawn().await?;
+        #[cfg(feature = "virtual-tcp")]
         self.virtual_tcp
             .spawn(self.session_layer.config.node_id)
             .await?;
@@ -85,36 +125,43 @@ impl TransportLayer {
     }
 
     pub async fn shutdown(&mut self) -> anyhow::Result<()> {
-        let channels = {
-            let mut state = self.state.lock();
-
-            let unreliable = state.forward_unreliable.drain().collect::<Vec<_>>();
-            let transfer = state.forward_transfer.drain().collect::<Vec<_>>();
-            let reliable = state.forward_reliable.drain().collect::<Vec<_>>();
-
-            unreliable
-                .into_iter()
-                .chain(transfer.into_iter())
-                .chain(reliable.into_iter())
-                .collect::<Vec<_>>()
-        };
-        for (_, mut channel) in channels {
+        let channels = self.state.lock().pool.drain();
+        for mut channel in channels {
             channel.disconnect().await.ok();
         }
 
+        #[cfg(feature = "virtual-tcp")]
         self.virtual_tcp
             .shutdown(self.session_layer.config.node_id)
             .await;
 
         // After Tcp shutdown will return, we are sending last Tcp packet to notify other Node,
         // that connection is closed. We shouldn't close sessions before we give them chance to be sent.
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        ya_relay_core::runtime::sleep(Duration::from_millis(100)).await;
 
         self.session_layer.shutdown().await
     }
 
     pub fn forward_receiver(&self) -> Option<ForwardReceiver> {
-        self.ingress_channel.receiver()
+        self.ingress.receiver()
+    }
+
+    /// Finds a Node known to this client, which virtual IPv6 address is `address`.
+    #[cfg(feature = "virtual-tcp")]
+    pub async fn resolve_address(&self, address: Ipv6Addr) -> Option<NodeId> {
+        if let Some(node_id) = self.virtual_tcp.resolve_address(address).await {
+            return Some(node_id);
+        }
+        let state = self.session_layer.state.lock();
+        state
+            .nodes
+            .keys()
+            .find(|node_id| tcp_registry::to_ipv6(*node_id) == address)
+            .copied()
+    }
+
+    pub fn ingress_stats(&self) -> IngressStats {
+        self.ingress.stats()
     }
 
     async fn dispatch(&self, packet: Forwarded) {
@@ -124,18 +171,100 @@ impl TransportLayer {
             packet.transport
         );
 
+        if self.config.multipath == MultipathMode::Duplicate
+            && packet.transport != TransportType::Unreliable
+            && self
+                .dedup
+                .is_duplicate(packet.node_id, packet.payload.as_ref())
+        {
+            log::trace!(
+                "[TransportLayer] Dropping duplicate from [{}]",
+                packet.node_id
+            );
+            return;
+        }
+
         match &packet.transport {
             TransportType::Unreliable => self.dispatch_unreliable(packet).await,
+            #[cfg(feature = "virtual-tcp")]
             TransportType::Reliable => self.virtual_tcp.dispatch(packet).await,
             // Currently `SessionLayer` responds only with `Unreliable` and `Reliable`, because only TcpLayer
             // can distinguish packets between `Reliable` and `Transfer`.
             // Nevertheless this function will work correctly even when getting `Transfer` variant.
+            #[cfg(feature = "virtual-tcp")]
             TransportType::Transfer => self.virtual_tcp.dispatch(packet).await,
+            #[cfg(not(feature = "virtual-tcp"))]
+            TransportType::Reliable | TransportType::Transfer => log::trace!(
+                "[TransportLayer] Virtual TCP disabled, dropping packet from [{}]",
+                packet.node_id
+            ),
         }
     }
 
     pub async fn dispatch_unreliable(&self, forward: Forwarded) {
-        self.ingress_channel.tx.send(forward).ok();
+        // Parity is computed over payloads after egress middleware of the sender.
+        match self.session_layer.fec.decode(forward) {
+            Decoded::Other(forward) => self.dispatch_decoded(forward),
+            Decoded::Forwards(forwards) => forwards
+                .into_iter()
+                .for_each(|forward| self.dispatch_decoded(forward)),
+            Decoded::Reply(node_id, accept) => {
+                let layer = self.session_layer.clone();
+                self.config.spawner.spawn(
+                    async move {
+                        if let Ok(mut tx) = layer.session(node_id).await {
+                            tx.send(accept.into(), TransportType::Unreliable).await.ok();
+                        }
+                    }
+                    .boxed_local(),
+                );
+            }
+            Decoded::Done => (),
+        }
+    }
+
+    fn dispatch_decoded(&self, mut forward: Forwarded) {
+        if !middleware::ingress(&self.config.middleware, &mut forward) {
+            return;
+        }
+        let forward = match self.pubsub.dispatch(forward) {
+            Some(forward) => forward,
+            None => return,
+        };
+        let forward = match self.bandwidth.dispatch(forward) {
+            Dispatched::Other(forward) => forward,
+            Dispatched::Reply(node_id, report) => {
+                let myself = self.clone();
+                self.config.spawner.spawn(
+                    async move {
+                        if let Ok(mut tx) = myself.forward_unreliable(node_id).await {
+                            tx.send(report.into()).await.ok();
+                        }
+                    }
+                    .boxed_local(),
+                );
+                return;
+            }
+            Dispatched::Done => return,
+        };
+        match self.rotations.dispatch(forward) {
+            Err(forward) => {
+                self.ingress.try_send(forward);
+            }
+            Ok(Some(notice)) => {
+                // Channels with the old NodeId are not reused, new ones are opened
+                // to the Node's new identity.
+                let mut state = self.state.lock();
+                for transport in [
+                    TransportType::Unreliable,
+                    TransportType::Reliable,
+                    TransportType::Transfer,
+                ] {
+                    state.pool.remove(notice.old_id, transport);
+                }
+            }
+            Ok(None) => (),
+        }
     }
 
     async fn spawn_ingress_handler(&self) -> anyhow::Result<()> {
@@ -144,11 +273,13 @@ impl TransportLayer {
             .receiver()
             .ok_or_else(|| anyhow::anyhow!("Ingress traffic receiver already spawned"))?;
 
-        tokio::task::spawn_local(self.clone().ingress_handler(ingress_rx));
+        self.config
+            .spawner
+            .spawn(self.clone().ingress_handler(ingress_rx).boxed_local());
         Ok(())
     }
 
-    async fn ingress_handler(self, ingress_rx: ForwardReceiver) {
+    async fn ingress_handler(self, ingress_rx: UnboundedReceiver<Forwarded>) {
         UnboundedReceiverStream::new(ingress_rx)
             .for_each(move |forwarded| {
                 let myself = self.clone();
@@ -159,95 +290,169 @@ impl TransportLayer {
             .await
     }
 
-    fn get_forward_channel(
-        &self,
-        node_id: NodeId,
-        channel: TransportType,
-    ) -> Option<ForwardSender> {
-        let state = self.state.lock();
-        match channel {
-            TransportType::Reliable => state.forward_reliable.get(&node_id).cloned(),
-            TransportType::Transfer => state.forward_transfer.get(&node_id).cloned(),
-            TransportType::Unreliable => state.forward_unreliable.get(&node_id).cloned(),
-        }
+    fn get_forward_channel(&self, node_id: NodeId, channel: TransportType) -> Pooled {
+        self.state.lock().pool.get(
+            &self.config.connection_pool,
+            node_id,
+            channel,
+            Instant::now(),
+        )
     }
 
     fn set_forward_channel(&self, node_id: NodeId, channel: TransportType, tx: ForwardSender) {
-        let mut state = self.state.lock();
-        match channel {
-            TransportType::Reliable => state.forward_reliable.insert(node_id, tx),
-            TransportType::Transfer => state.forward_transfer.insert(node_id, tx),
-            TransportType::Unreliable => state.forward_unreliable.insert(node_id, tx),
-        };
+        self.state.lock().pool.insert(node_id, channel, tx);
     }
 
     pub async fn forward_reliable(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
-        self.forward_virtual_tcp(node_id, TransportType::Reliable)
+        self.forward(node_id, TransportType::Reliable, Default::default())
             .await
-            .context("Fail to open reliable channel")
     }
 
     pub async fn forward_transfer(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
-        self.forward_virtual_tcp(node_id, TransportType::Transfer)
+        self.forward(node_id, TransportType::Transfer, Default::default())
             .await
-            .context("Fail to open transport channel")
+    }
+
+    pub async fn forward(
+        &self,
+        node_id: NodeId,
+        channel: TransportType,
+        options: ForwardOptions,
+    ) -> anyhow::Result<ForwardSender> {
+        match channel {
+            TransportType::Unreliable => self.forward_unreliable(node_id).await,
+            #[cfg(feature = "virtual-tcp")]
+            TransportType::Reliable => self
+                .forward_virtual_tcp(node_id, channel, options, None)
+                .await
+                .context("Fail to open reliable channel"),
+            #[cfg(feature = "virtual-tcp")]
+            TransportType::Transfer => self
+                .forward_virtual_tcp(node_id, channel, options, None)
+                .await
+                .context("Fail to open transport channel"),
+            #[cfg(not(feature = "virtual-tcp"))]
+            TransportType::Reliable | TransportType::Transfer => {
+                anyhow::bail!("{} channel requires the `virtual-tcp` feature", channel)
+            }
+        }
     }
 
     /// NodeId can be either default or secondary.
     /// TODO: Make this function resistant to dropping future
-    pub async fn forward_virtual_tcp(
+    #[cfg(feature = "virtual-tcp")]
+    pub(crate) async fn forward_virtual_tcp(
         &self,
         node_id: NodeId,
         channel: TransportType,
-    ) -> anyhow::Result<ForwardSender> {
-        match self.get_forward_channel(node_id, channel) {
-            // If connection was closed in the meantime, it will be initialized on demand.
-            // It will be problematic in some cases, because this can last up to a few seconds.
-            // In worst case scenario initialization will fail and we will wait 5s until timeout.
-            // Since user uses channel, sending will return immediately after item will be taken from
-            // queue, so he won't find out, but the response he expects won't come.
-            // This is argument for changing channels API to `TcpSender`.
-            Some(tx) => Ok(tx),
-            None => {
-                // Check if this isn't secondary identity. TcpLayer should always get default id.
-                // TODO: Consider how to handle changing identities.
-                // TODO: Maybe we should call `self.session_layer::session` and pass it to `connect`.
-                let info = self.session_layer.query_node_info(node_id).await?;
-
-                if let Some(tx) = self.get_forward_channel(info.default_node_id(), channel) {
+        options: ForwardOptions,
+        progress: Option<ProgressFn>,
+    ) -> Result<ForwardSender, ConnectError> {
+        let node_id = self.rotations.resolve(node_id);
+        let established = |tx: ForwardSender| {
+            if let Some(progress) = &progress {
+                progress(ConnectProgress::Established);
+            }
+            tx
+        };
+
+        let channel_port = match channel {
+            TransportType::Reliable => ChannelType::Messages,
+            TransportType::Transfer => ChannelType::Transfer,
+            _ => return Err(ConnectError::Tcp(node_id, "Programming error: `forward_generic` shouldn't been used for unreliable connection.".to_string())),
+        };
+
+        // Without health checks, connection closed in the meantime will be initialized on demand.
+        // It will be problematic in some cases, because this can last up to a few seconds.
+        // In worst case scenario initialization will fail and we will wait 5s until timeout.
+        // Since user uses channel, sending will return immediately after item will be taken from
+        // queue, so he won't find out, but the response he expects won't come.
+        // This is argument for changing channels API to `TcpSender`.
+        // Connections established with another profile are replaced.
+        let profile_changed = |id| {
+            matches!(options.profile, Some(profile)
+                if self.virtual_tcp.profile(id, channel_port) != Some(profile))
+        };
+        let mut reconnect = options.force_new;
+        if !reconnect && !profile_changed(node_id) {
+            match self.get_forward_channel(node_id, channel) {
+                Pooled::Ready(tx) => return Ok(established(tx)),
+                Pooled::Idle(_) => reconnect = true,
+                Pooled::Missing => (),
+            }
+        }
+
+        // Check if this isn't secondary identity. TcpLayer should always get default id.
+        // TODO: Consider how to handle changing identities.
+        // TODO: Maybe we should call `self.session_layer::session` and pass it to `connect`.
+        if let Some(progress) = &progress {
+            progress(ConnectProgress::Resolving);
+        }
+        let info = self
+            .session_layer
+            .query_node_info(node_id)
+            .await
+            .map_err(|e| ConnectError::Resolve(node_id, e.to_string()))?;
+        let default_id = info.default_node_id();
+        if let Some(profile) = options.profile {
+            reconnect |= self
+                .virtual_tcp
+                .set_profile(default_id, channel_port, profile);
+        }
+
+        if !reconnect {
+            match self.get_forward_channel(default_id, channel) {
+                Pooled::Ready(tx) => {
                     self.set_forward_channel(node_id, channel, tx.clone());
-                    return Ok(tx);
+                    return Ok(established(tx));
                 }
-
-                let channel_port = match channel {
-                    TransportType::Reliable => ChannelType::Messages,
-                    TransportType::Transfer => ChannelType::Transfer,
-                    _ => bail!("Programming error: `forward_generic` shouldn't been used for unreliable connection.")
-                };
-
-                let sender: ForwardSender = self
-                    .virtual_tcp
-                    .connect(info.default_node_id(), channel_port)
-                    .await
-                    .with_context(|| {
-                        format!("Failed to connect to {node_id} on channel {channel_port}")
-                    })?
-                    .into();
-
-                self.set_forward_channel(node_id, channel, sender.clone());
-                Ok(sender)
+                Pooled::Idle(_) => reconnect = true,
+                Pooled::Missing => (),
+            }
+        }
+        if reconnect {
+            log::debug!("Replacing {channel:?} connection to [{default_id}]");
+            {
+                let mut state = self.state.lock();
+                state.pool.remove(node_id, channel);
+                state.pool.remove(default_id, channel);
             }
+            self.virtual_tcp
+                .close_channel(default_id, channel_port)
+                .await;
         }
+
+        // Already reported above.
+        let progress = progress.map(|progress| {
+            Rc::new(move |stage| {
+                if stage != ConnectProgress::Resolving {
+                    progress(stage)
+                }
+            }) as ProgressFn
+        });
+
+        let connect = self
+            .virtual_tcp
+            .connect_with_progress(default_id, channel_port, progress);
+        let sender: ForwardSender = cancellable(&self.config.cancel, connect)
+            .await
+            .map_err(|e| ConnectError::Session(default_id, e.into()))?
+            .map_err(|e| ConnectError::from_tcp(default_id, e))?
+            .into();
+
+        self.set_forward_channel(node_id, channel, sender.clone());
+        Ok(sender)
     }
 
     /// NodeId can be either default or secondary.
     /// TODO: Make this function resistant to dropping future
     pub async fn forward_unreliable(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
+        let node_id = self.rotations.resolve(node_id);
         // This will return fast, if we already have this channel.
         // These lines are not necessary, because code below would do the job,
         // but this way we avoid querying write lock and asking session layer for `RoutingSender`
         // on every attempt to send message.
-        if let Some(tx) = self.get_forward_channel(node_id, TransportType::Unreliable) {
+        if let Pooled::Ready(tx) = self.get_forward_channel(node_id, TransportType::Unreliable) {
             return Ok(tx);
         }
 
@@ -255,10 +460,17 @@ impl TransportLayer {
 
         let routing = {
             let mut state = self.state.lock();
-            match state.forward_unreliable.get(&node_id) {
-                Some(tx) => return Ok(tx.clone()),
-                None => {
-                    state.forward_unreliable.insert(node_id, routing.clone());
+            match state.pool.get(
+                &self.config.connection_pool,
+                node_id,
+                TransportType::Unreliable,
+                Instant::now(),
+            ) {
+                Pooled::Ready(tx) => return Ok(tx),
+                _ => {
+                    state
+                        .pool
+                        .insert(node_id, TransportType::Unreliable, routing.clone());
                     routing
                 }
             }
@@ -0,0 +1,87 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+/// Packets queued per destination, drained in round-robin order.
+///
+/// Each destination has at most a single packet in flight. Packets of a destination keep
+/// their order, and a destination with a long queue takes turns with the others, instead
+/// of delaying all packets queued after its own.
+pub(crate) struct FairQueue<K, T> {
+    queues: HashMap<K, VecDeque<T>>,
+    /// Destinations with queued packets and nothing in flight.
+    ready: VecDeque<K>,
+    /// Destinations with a packet in flight.
+    busy: HashSet<K>,
+}
+
+impl<K, T> Default for FairQueue<K, T> {
+    fn default() -> Self {
+        Self {
+            queues: Default::default(),
+            ready: Default::default(),
+            busy: Default::default(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, T> FairQueue<K, T> {
+    pub fn push(&mut self, key: K, item: T) {
+        let queue = self.queues.entry(key.clone()).or_default();
+        queue.push_back(item);
+        if queue.len() == 1 && !self.busy.contains(&key) {
+            self.ready.push_back(key);
+        }
+    }
+
+    /// Takes the next packet of the next destination and marks it as in flight,
+    /// until [`FairQueue::done`] is called.
+    pub fn pop(&mut self) -> Option<(K, T)> {
+        let key = self.ready.pop_front()?;
+        let queue = self.queues.get_mut(&key)?;
+        let item = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        }
+        self.busy.insert(key.clone());
+        Some((key, item))
+    }
+
+    /// Packet of the destination is no longer in flight.
+    pub fn done(&mut self, key: K) {
+        if self.busy.remove(&key) && self.queues.contains_key(&key) {
+            self.ready.push_back(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_robin() {
+        let mut queue = FairQueue::default();
+        for i in 0..3 {
+            queue.push("bulk", i);
+        }
+        queue.push("interactive", 10);
+
+        assert_eq!(queue.pop(), Some(("bulk", 0)));
+        assert_eq!(queue.pop(), Some(("interactive", 10)));
+        // Both destinations have a packet in flight.
+        assert_eq!(queue.pop(), None);
+
+        queue.done("bulk");
+        queue.done("interactive");
+        queue.push("interactive", 11);
+        assert_eq!(queue.pop(), Some(("bulk", 1)));
+        assert_eq!(queue.pop(), Some(("interactive", 11)));
+        assert_eq!(queue.pop(), None);
+
+        queue.done("interactive");
+        queue.done("bulk");
+        assert_eq!(queue.pop(), Some(("bulk", 2)));
+        queue.done("bulk");
+        assert_eq!(queue.pop(), None);
+    }
+}
@@ -0,0 +1,213 @@
+use metrics::increment_counter;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::client::Forwarded;
+
+/// Receiver of payloads forwarded to this client.
+pub type ForwardReceiver = Receiver<Forwarded>;
+
+/// What happens to reliable and transfer payloads, when the [`ForwardReceiver`] is full.
+/// Unreliable payloads are always dropped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IngressOverflow {
+    /// Stops reading virtual TCP connections until the receiver catches up. Their receive
+    /// windows shrink, so Nodes stop sending instead of payloads being lost.
+    #[default]
+    Backpressure,
+    /// Drops payloads, which don't fit. Reliable channels lose data without
+    /// the other Node knowing about it.
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IngressConfig {
+    /// Payloads waiting in the [`ForwardReceiver`].
+    pub capacity: usize,
+    pub overflow: IngressOverflow,
+}
+
+impl Default for IngressConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 4096,
+            overflow: IngressOverflow::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct IngressStats {
+    /// Payloads waiting in the receiver.
+    pub queued: usize,
+    /// Payloads dropped, because the receiver was full.
+    pub dropped: u64,
+    /// Times reading virtual TCP connections was paused, because the receiver was full.
+    pub paused: u64,
+}
+
+/// Queue of forwarded payloads, shared by the transport layers.
+#[derive(Clone)]
+pub(crate) struct Ingress {
+    tx: Sender<Forwarded>,
+    rx: Rc<RefCell<Option<ForwardReceiver>>>,
+    overflow: IngressOverflow,
+    dropped: Rc<Cell<u64>>,
+    paused: Rc<Cell<u64>>,
+}
+
+impl Ingress {
+    pub fn new(config: IngressConfig) -> Self {
+        let (tx, rx) = channel(config.capacity.max(1));
+        Self {
+            tx,
+            rx: Rc::new(RefCell::new(Some(rx))),
+            overflow: config.overflow,
+            dropped: Default::default(),
+            paused: Default::default(),
+        }
+    }
+
+    pub fn receiver(&self) -> Option<ForwardReceiver> {
+        self.rx.borrow_mut().take()
+    }
+
+    /// Queues an unreliable payload, or drops it if the receiver is full.
+    pub fn try_send(&self, forwarded: Forwarded) {
+        match self.tx.try_send(forwarded) {
+            Ok(()) => (),
+            Err(TrySendError::Full(forwarded)) => self.discard(&forwarded),
+            Err(TrySendError::Closed(forwarded)) => log::trace!(
+                "Ingress receiver closed, dropping payload from [{}]",
+                forwarded.node_id
+            ),
+        }
+    }
+
+    /// Queues a reliable or transfer payload according to [`IngressOverflow`].
+    /// `pause` is called with `true` before waiting for the receiver and with `false` after.
+    pub async fn send(&self, forwarded: Forwarded, pause: impl Fn(bool)) {
+        let forwarded = match self.tx.try_send(forwarded) {
+            Ok(()) => return,
+            Err(TrySendError::Full(forwarded)) => forwarded,
+            Err(TrySendError::Closed(forwarded)) => {
+                log::trace!(
+                    "Ingress receiver closed, dropping payload from [{}]",
+                    forwarded.node_id
+                );
+                return;
+            }
+        };
+
+        match self.overflow {
+            IngressOverflow::Drop => self.discard(&forwarded),
+            IngressOverflow::Backpressure => {
+                log::debug!("Ingress receiver full, pausing virtual TCP connections");
+                self.paused.set(self.paused.get() + 1);
+                increment_counter!("ya-relay.client.ingress.paused");
+
+                pause(true);
+                self.tx.send(forwarded).await.ok();
+                pause(false);
+            }
+        }
+    }
+
+    fn discard(&self, forwarded: &Forwarded) {
+        log::debug!(
+            "Ingress receiver full, dropping {} B {} payload from [{}]",
+            forwarded.payload.len(),
+            forwarded.transport,
+            forwarded.node_id
+        );
+        self.dropped.set(self.dropped.get() + 1);
+        increment_counter!("ya-relay.client.ingress.dropped");
+    }
+
+    pub fn stats(&self) -> IngressStats {
+        IngressStats {
+            queued: self.tx.max_capacity() - self.tx.capacity(),
+            dropped: self.dropped.get(),
+            paused: self.paused.get(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+    use ya_relay_core::server_session::TransportType;
+    use ya_relay_core::NodeId;
+
+    fn forwarded(transport: TransportType) -> Forwarded {
+        Forwarded {
+            transport,
+            node_id: NodeId::default(),
+            payload: vec![0u8; 8].into(),
+            session: None,
+            received_at: SystemTime::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop() {
+        let ingress = Ingress::new(IngressConfig {
+            capacity: 2,
+            overflow: IngressOverflow::Drop,
+        });
+        let mut rx = ingress.receiver().unwrap();
+
+        ingress.try_send(forwarded(TransportType::Unreliable));
+        ingress
+            .send(forwarded(TransportType::Reliable), |_| ())
+            .await;
+        ingress
+            .send(forwarded(TransportType::Reliable), |_| ())
+            .await;
+        ingress.try_send(forwarded(TransportType::Unreliable));
+
+        let stats = ingress.stats();
+        assert_eq!(stats.queued, 2);
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(stats.paused, 0);
+
+        rx.recv().await.unwrap();
+        assert_eq!(ingress.stats().queued, 1);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let ingress = Ingress::new(IngressConfig {
+            capacity: 1,
+            overflow: IngressOverflow::Backpressure,
+        });
+        let mut rx = ingress.receiver().unwrap();
+        let paused = Rc::new(RefCell::new(Vec::new()));
+        let pause = {
+            let paused = paused.clone();
+            move |p| paused.borrow_mut().push(p)
+        };
+
+        ingress
+            .send(forwarded(TransportType::Reliable), &pause)
+            .await;
+        assert!(paused.borrow().is_empty());
+
+        let send = ingress.send(forwarded(TransportType::Transfer), &pause);
+        let recv = async {
+            tokio::task::yield_now().await;
+            rx.recv().await.unwrap()
+        };
+        let (_, received) = futures::join!(send, recv);
+        assert_eq!(received.transport, TransportType::Reliable);
+        assert_eq!(*paused.borrow(), vec![true, false]);
+
+        let stats = ingress.stats();
+        assert_eq!(stats.queued, 1);
+        assert_eq!(stats.dropped, 0);
+        assert_eq!(stats.paused, 1);
+    }
+}
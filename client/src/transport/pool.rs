@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ya_relay_core::runtime::Instant;
+use ya_relay_core::server_session::TransportType;
+use ya_relay_core::NodeId;
+
+use super::transport_sender::ForwardSender;
+#[cfg(feature = "virtual-tcp")]
+use crate::tuning::TuningProfile;
+
+/// Policy of reusing forward channels, when the same Node is requested again.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Virtual TCP connections without a send for this long are closed, when requested
+    /// again, and a new connection is established instead. `None` disables the limit.
+    pub max_idle: Option<Duration>,
+    /// Replaces channels, which session or connection was closed in the meantime,
+    /// instead of returning them to be re-established on the first send.
+    pub health_check: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: Some(Duration::from_secs(300)),
+            health_check: true,
+        }
+    }
+}
+
+/// Options of a single [`Client::forward`](crate::Client::forward) call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ForwardOptions {
+    /// Closes the pooled connection and establishes a new one, even if it was healthy.
+    /// Only virtual TCP channels are affected, unreliable ones always reuse the session.
+    pub force_new: bool,
+    /// Settings of the virtual TCP connection. Pooled connections established with
+    /// another profile are replaced, `None` keeps the pooled connection as it is.
+    #[cfg(feature = "virtual-tcp")]
+    pub profile: Option<TuningProfile>,
+}
+
+impl ForwardOptions {
+    pub fn force_new() -> Self {
+        Self {
+            force_new: true,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "virtual-tcp")]
+    pub fn profile(profile: TuningProfile) -> Self {
+        Self {
+            profile: Some(profile),
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) enum Pooled {
+    Ready(ForwardSender),
+    /// Removed from the pool, its connection should be closed before reconnecting.
+    Idle(ForwardSender),
+    Missing,
+}
+
+/// Forward channels by the Node id they were requested for. Secondary ids have
+/// separate entries, sharing connection with the default id.
+#[derive(Default)]
+pub(crate) struct ConnectionPool {
+    senders: HashMap<(NodeId, TransportType), ForwardSender>,
+}
+
+impl ConnectionPool {
+    pub fn get(
+        &mut self,
+        config: &PoolConfig,
+        node_id: NodeId,
+        transport: TransportType,
+        now: Instant,
+    ) -> Pooled {
+        let key = (node_id, transport);
+        let sender = match self.senders.get(&key) {
+            Some(sender) => sender,
+            None => return Pooled::Missing,
+        };
+
+        if config.health_check && !sender.is_connected() {
+            log::trace!("[ConnectionPool] {transport:?} channel to [{node_id}] closed, replacing");
+            self.senders.remove(&key);
+            return Pooled::Missing;
+        }
+
+        let idle = match (config.max_idle, sender.last_used()) {
+            (Some(max_idle), Some(last_used)) => {
+                now.saturating_duration_since(last_used) >= max_idle
+            }
+            _ => false,
+        };
+        if idle {
+            log::trace!("[ConnectionPool] {transport:?} channel to [{node_id}] idle, replacing");
+            return self
+                .senders
+                .remove(&key)
+                .map(Pooled::Idle)
+                .unwrap_or(Pooled::Missing);
+        }
+        Pooled::Ready(sender.clone())
+    }
+
+    pub fn insert(&mut self, node_id: NodeId, transport: TransportType, sender: ForwardSender) {
+        self.senders.insert((node_id, transport), sender);
+    }
+
+    pub fn remove(&mut self, node_id: NodeId, transport: TransportType) -> Option<ForwardSender> {
+        self.senders.remove(&(node_id, transport))
+    }
+
+    pub fn drain(&mut self) -> Vec<ForwardSender> {
+        self.senders.drain().map(|(_, sender)| sender).collect()
+    }
+}
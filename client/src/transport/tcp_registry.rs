@@ -3,17 +3,23 @@ use derive_more::Display;
 use educe::Educe;
 use std::collections::HashMap;
 use std::net::Ipv6Addr;
+use std::rc::Rc;
 use std::sync::{Arc, Weak};
+use std::time::Duration;
 use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::{broadcast, RwLock};
 
+use ya_relay_core::runtime::Instant;
 use ya_relay_core::NodeId;
 use ya_relay_proto::proto::Payload;
-use ya_relay_stack::smoltcp::wire::{IpAddress, IpEndpoint};
+use ya_relay_stack::smoltcp::wire::{
+    IpAddress, IpEndpoint, IpProtocol, Ipv6Packet, TcpPacket, UdpPacket,
+};
 use ya_relay_stack::Connection;
 
 use super::virtual_layer::TcpLayer;
-use crate::error::{ResultExt, TcpError, TcpTransitionError};
+use crate::direct_session::DirectSession;
+use crate::error::{ResultExt, SenderError, TcpError, TcpTransitionError};
 use crate::routing_session::RoutingSender;
 use crate::session::SessionLayer;
 
@@ -42,6 +48,21 @@ pub enum ChannelDirection {
 #[display(fmt = "{}-{}", "_0", "_1")]
 pub struct ChannelDesc(pub ChannelType, pub ChannelDirection);
 
+/// Stages of opening virtual TCP connection, reported in this order.
+/// Callers joining an attempt already in progress see only the last stage.
+#[derive(Clone, Copy, Display, Debug, PartialEq, Eq)]
+pub enum ConnectProgress {
+    /// Querying relay server about the Node and establishing session with it.
+    Resolving,
+    /// Session with the Node is ready, either p2p or relayed.
+    Registered,
+    /// Virtual TCP handshake started.
+    SynSent,
+    Established,
+}
+
+pub(crate) type ProgressFn = Rc<dyn Fn(ConnectProgress)>;
+
 /// Information about virtual node in TCP network built over UDP protocol.
 #[derive(Clone)]
 pub struct VirtNode {
@@ -115,6 +136,17 @@ impl VirtNode {
         self.routing.target()
     }
 
+    /// Address assigned to the Node, when its virtual address collided with the address of
+    /// another Node. Packets of the Node are translated between both addresses.
+    pub fn translated(&self) -> Option<Ipv6Addr> {
+        match self.address {
+            IpAddress::Ipv6(address) => {
+                Some(Ipv6Addr::from(address)).filter(|address| *address != to_ipv6(self.id()))
+            }
+            _ => None,
+        }
+    }
+
     pub async fn transition(
         &self,
         channel: ChannelDesc,
@@ -149,6 +181,35 @@ struct TcpRegistryState {
     ips: HashMap<NodeId, Box<[u8]>>,
 }
 
+impl TcpRegistryState {
+    /// Address of the Node, or the one it would be assigned. Nodes whose virtual address
+    /// is taken by another Node get the first free [`fallback_ipv6`] address.
+    fn address(&self, node_id: NodeId) -> Ipv6Addr {
+        let known = self.ips.get(&node_id).and_then(|ip| self.nodes.get(ip));
+        if let Some(IpAddress::Ipv6(address)) = known.map(|node| node.address) {
+            return address.into();
+        }
+
+        let free = |address: Ipv6Addr| match self.nodes.get(address.octets().as_ref()) {
+            Some(node) => node.id() == node_id,
+            None => true,
+        };
+        let address = to_ipv6(node_id);
+        if free(address) {
+            return address;
+        }
+
+        let mut attempt = 0;
+        loop {
+            let fallback = fallback_ipv6(node_id, attempt);
+            if free(fallback) {
+                return fallback;
+            }
+            attempt += 1;
+        }
+    }
+}
+
 impl TcpRegistry {
     pub fn new(layer: SessionLayer) -> TcpRegistry {
         TcpRegistry {
@@ -195,9 +256,12 @@ impl TcpRegistry {
     }
 
     pub async fn resolve_ip(&self, node: NodeId) -> Box<[u8]> {
-        IpAddress::from(to_ipv6(node.into_array()))
-            .as_bytes()
-            .into()
+        self.address(node).await.octets().as_ref().into()
+    }
+
+    /// Virtual address of the Node, or the one it would be assigned.
+    pub async fn address(&self, node: NodeId) -> Ipv6Addr {
+        self.state.read().await.address(node)
     }
 
     async fn close_channel(&self, node: &VirtNode, channel: ChannelDesc) {
@@ -215,6 +279,22 @@ impl TcpRegistry {
         }
     }
 
+    /// Closes outgoing channel to the Node, returning connection which was using it.
+    pub async fn close_out_channel(
+        &self,
+        node_id: NodeId,
+        channel: ChannelType,
+    ) -> Option<Arc<TcpConnection>> {
+        let node = self.resolve_node(node_id).await.ok()?;
+        let channel = (channel, ChannelDirection::Out).into();
+        let connection = match node.channel(channel).state().await {
+            TcpState::Connected(connection) => connection,
+            _ => return None,
+        };
+        self.close_channel(&node, channel).await;
+        Some(connection)
+    }
+
     pub async fn remove_node(&self, node_id: NodeId) {
         // Removing all channels. Consider if we should remove channels one by one.
         log::trace!("[remove_node] Removing node: {node_id}", node_id = node_id);
@@ -233,15 +313,36 @@ impl TcpRegistry {
         // }
     }
 
+    pub async fn virt_nodes(&self) -> Vec<VirtNode> {
+        let state = self.state.read().await;
+        state.nodes.values().cloned().collect()
+    }
+
+    /// Replaces routing of existing virtual node. Open TCP connections are kept.
+    pub async fn update_routing(&self, node_id: NodeId, routing: RoutingSender) {
+        let mut state = self.state.write().await;
+        let TcpRegistryState { nodes, ips } = &mut *state;
+        if let Some(node) = ips.get(&node_id).and_then(|ip| nodes.get_mut(ip)) {
+            node.routing = routing;
+        }
+    }
+
     pub async fn get_by_address(&self, addr: &[u8]) -> Option<VirtNode> {
         let state = self.state.read().await;
         state.nodes.get(addr).cloned()
     }
 
     pub async fn add_virt_node(&self, node_id: NodeId) -> VirtNode {
-        let node = VirtNode::new(node_id, self.layer.clone());
+        let mut node = VirtNode::new(node_id, self.layer.clone());
         {
             let mut state = self.state.write().await;
+            node.address = state.address(node_id).into();
+            if let Some(address) = node.translated() {
+                log::warn!(
+                    "[VirtualTcp] Virtual address {} of Node [{node_id}] is taken, using {address}",
+                    to_ipv6(node_id)
+                );
+            }
             let ip: Box<[u8]> = node.address.as_bytes().into();
 
             state.nodes.insert(ip.clone(), node.clone());
@@ -287,11 +388,19 @@ impl TcpState {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct TcpConnection {
     pub id: NodeId,
     pub conn: Connection,
     pub channel: ChannelDesc,
+    /// Updated by every `TcpSender` sending through this connection.
+    pub(crate) last_used: parking_lot::Mutex<Instant>,
+}
+
+impl TcpConnection {
+    pub(crate) fn touch(&self) {
+        *self.last_used.lock() = Instant::now();
+    }
 }
 
 /// Structure giving you exclusive right to initialize connection.
@@ -362,7 +471,7 @@ pub(crate) async fn async_drop(
 impl Drop for TcpPermit {
     fn drop(&mut self) {
         log::trace!("[TcpPermit]: Dropping for {}.", self.node.id());
-        tokio::task::spawn_local(async_drop(
+        ya_relay_core::runtime::spawn_local(async_drop(
             self.node.clone(),
             self.channel,
             self.result.take(),
@@ -381,6 +490,7 @@ impl TcpAwaiting {
         match self.channel.state().await {
             TcpState::Connected(result) => return Ok(result),
             TcpState::Closed => return Err(TcpError::Closed),
+            TcpState::Failed(e @ TcpError::Connect(_)) => return Err(e),
             TcpState::Failed(e) => {
                 return Err(TcpError::Generic {
                     msg: "Wait for Finish".into(),
@@ -431,8 +541,65 @@ impl TcpSender {
     /// TODO: We should use channel-like error where you can recover your payload
     ///       from error message.
     pub async fn send(&mut self, packet: Payload) -> Result<(), TcpError> {
-        let routing = match self.connection.upgrade() {
-            Some(conn) => conn,
+        let routing = self.connection().await?;
+        routing.touch();
+        self.layer
+            .send(packet, routing.conn)
+            .await
+            .map_err(|e| TcpError::Generic {
+                msg: "Failed to send".to_string(),
+                source: Box::<dyn std::error::Error + Sync + Send>::from(e).into(),
+            })
+    }
+
+    /// Same as [`TcpSender::send`], but drops the packet, if the connection isn't
+    /// established or stalls for longer than `ttl`.
+    pub async fn send_with_ttl(
+        &mut self,
+        packet: Payload,
+        ttl: Duration,
+    ) -> Result<(), SenderError> {
+        let deadline = Instant::now() + ttl;
+        let routing = ya_relay_core::runtime::timeout(ttl, self.connection())
+            .await
+            .map_err(|_| SenderError::Expired(ttl))??;
+        routing.touch();
+        match self.layer.send_until(packet, routing.conn, deadline).await {
+            Ok(()) => Ok(()),
+            Err(ya_relay_stack::Error::Expired) => Err(SenderError::Expired(ttl)),
+            Err(e) => Err(TcpError::Generic {
+                msg: "Failed to send".to_string(),
+                source: Arc::new(e),
+            }
+            .into()),
+        }
+    }
+
+    /// Session used to reach the Node, if it's connected.
+    pub(crate) async fn direct_session(&self) -> Option<Arc<DirectSession>> {
+        self.layer
+            .resolve_node(self.target)
+            .await
+            .ok()?
+            .routing
+            .direct_session()
+    }
+
+    /// False if the connection was closed and will be re-initialized on the next send.
+    pub fn is_connected(&self) -> bool {
+        self.connection.strong_count() > 0
+    }
+
+    /// Time of the last send through the connection, by this or any other sender.
+    pub fn last_used(&self) -> Option<Instant> {
+        self.connection
+            .upgrade()
+            .map(|connection| *connection.last_used.lock())
+    }
+
+    async fn connection(&mut self) -> Result<Arc<TcpConnection>, TcpError> {
+        match self.connection.upgrade() {
+            Some(conn) => Ok(conn),
             None => match self
                 .layer
                 .connect(self.target, self.channel.0)
@@ -446,18 +613,11 @@ impl TcpSender {
             {
                 Some(routing) => {
                     self.connection = Arc::downgrade(&routing);
-                    routing
+                    Ok(routing)
                 }
-                None => return Err(TcpError::Closed),
+                None => Err(TcpError::Closed),
             },
-        };
-        self.layer
-            .send(packet, routing.conn)
-            .await
-            .map_err(|e| TcpError::Generic {
-                msg: "Failed to send".to_string(),
-                source: Box::<dyn std::error::Error + Sync + Send>::from(e).into(),
-            })
+        }
     }
 
     pub async fn connect(&mut self) -> Result<(), TcpError> {
@@ -488,7 +648,7 @@ pub(crate) fn channel_endpoint(id: NodeId, channel: ChannelType) -> IpEndpoint {
     (to_ipv6(id), channel as u16).into()
 }
 
-pub(crate) fn to_ipv6(bytes: impl AsRef<[u8]>) -> Ipv6Addr {
+pub fn to_ipv6(bytes: impl AsRef<[u8]>) -> Ipv6Addr {
     const IPV6_ADDRESS_LEN: usize = 16;
 
     let bytes = bytes.as_ref();
@@ -507,6 +667,53 @@ pub(crate) fn to_ipv6(bytes: impl AsRef<[u8]>) -> Ipv6Addr {
     Ipv6Addr::from(ipv6_bytes)
 }
 
+/// Address of a Node, whose [`to_ipv6`] address is taken by another Node. It's built from
+/// the NodeId bytes which [`to_ipv6`] leaves out, so Nodes sharing a prefix get distinct
+/// addresses. Consecutive `attempt`s give further candidates, if that one is taken too.
+pub fn fallback_ipv6(node_id: NodeId, attempt: u32) -> Ipv6Addr {
+    let bytes = node_id.into_array();
+    let mut ipv6_bytes = [0u8; 16];
+    ipv6_bytes.copy_from_slice(&bytes[bytes.len() - ipv6_bytes.len()..]);
+    for (byte, mask) in ipv6_bytes[12..].iter_mut().zip(attempt.to_be_bytes()) {
+        *byte ^= mask;
+    }
+    to_ipv6(ipv6_bytes)
+}
+
+/// Replaces source and destination addresses of an IPv6 packet and updates the TCP or UDP
+/// checksum. Other packets are left intact. Returns whether the packet was rewritten.
+pub(crate) fn rewrite_ipv6(
+    packet: &mut [u8],
+    src: Option<Ipv6Addr>,
+    dst: Option<Ipv6Addr>,
+) -> bool {
+    let mut packet = match Ipv6Packet::new_checked(packet) {
+        Ok(packet) if packet.version() == 6 => packet,
+        _ => return false,
+    };
+    if let Some(src) = src {
+        packet.set_src_addr(src.into());
+    }
+    if let Some(dst) = dst {
+        packet.set_dst_addr(dst.into());
+    }
+
+    let src = IpAddress::Ipv6(packet.src_addr());
+    let dst = IpAddress::Ipv6(packet.dst_addr());
+    match packet.next_header() {
+        IpProtocol::Tcp => match TcpPacket::new_checked(packet.payload_mut()) {
+            Ok(mut tcp) => tcp.fill_checksum(&src, &dst),
+            Err(_) => return false,
+        },
+        IpProtocol::Udp => match UdpPacket::new_checked(packet.payload_mut()) {
+            Ok(mut udp) => udp.fill_checksum(&src, &dst),
+            Err(_) => return false,
+        },
+        _ => (),
+    }
+    true
+}
+
 impl From<(ChannelType, ChannelDirection)> for ChannelDesc {
     fn from(value: (ChannelType, ChannelDirection)) -> Self {
         ChannelDesc(value.0, value.1)
@@ -527,3 +734,108 @@ impl ChannelDesc {
         self.0 as u16
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_stack::smoltcp::phy::ChecksumCapabilities;
+    use ya_relay_stack::smoltcp::wire::{Ipv6Address, Ipv6Repr, TcpControl, TcpRepr, TcpSeqNumber};
+
+    fn node_id(prefix: &[u8], suffix: &[u8]) -> NodeId {
+        let mut bytes = [0u8; 20];
+        bytes[..prefix.len()].copy_from_slice(prefix);
+        bytes[20 - suffix.len()..].copy_from_slice(suffix);
+        NodeId::from(bytes)
+    }
+
+    #[test]
+    fn test_to_ipv6_mutations() {
+        // Multicast first byte is replaced, so it collides with the zero byte.
+        let multicast = node_id(&[0xff, 1], &[0xa1]);
+        let zero = node_id(&[0x00, 1], &[0xa2]);
+        assert!(!to_ipv6(multicast).is_multicast());
+        assert_eq!(to_ipv6(multicast), to_ipv6(zero));
+
+        // Unspecified and localhost addresses are replaced with `::2`.
+        let unspecified = node_id(&[], &[0xa3]);
+        let localhost = node_id(&[0; 15], &[1, 0, 0, 0, 0xa4]);
+        let two = node_id(&[0; 15], &[2, 0, 0, 0, 0xa5]);
+        assert_eq!(to_ipv6(unspecified), Ipv6Addr::from(2u128));
+        assert_eq!(to_ipv6(localhost), Ipv6Addr::from(2u128));
+        assert_eq!(to_ipv6(two), Ipv6Addr::from(2u128));
+        assert_ne!(
+            to_ipv6(node_id(&[0; 15], &[3, 0, 0, 0, 0])),
+            Ipv6Addr::from(2u128)
+        );
+    }
+
+    #[test]
+    fn test_fallback_ipv6() {
+        let colliding = [
+            node_id(&[0xff, 1], &[0xa1]),
+            node_id(&[0x00, 1], &[0xa2]),
+            node_id(&[], &[0xa3]),
+            node_id(&[0; 15], &[1, 0, 0, 0, 0xa4]),
+            node_id(&[0; 15], &[2, 0, 0, 0, 0xa5]),
+        ];
+        for (i, a) in colliding.iter().enumerate() {
+            for b in &colliding[i + 1..] {
+                assert_ne!(fallback_ipv6(*a, 0), fallback_ipv6(*b, 0), "{a} vs {b}");
+            }
+        }
+
+        let node = colliding[0];
+        assert_eq!(fallback_ipv6(node, 1), fallback_ipv6(node, 1));
+        assert_ne!(fallback_ipv6(node, 0), fallback_ipv6(node, 1));
+        // Mutations apply to fallback addresses as well.
+        assert!(!fallback_ipv6(node_id(&[0, 0, 0, 0, 0xff], &[]), 0).is_multicast());
+        assert_eq!(fallback_ipv6(node_id(&[], &[]), 0), Ipv6Addr::from(2u128));
+    }
+
+    #[test]
+    fn test_rewrite_ipv6() {
+        let src = Ipv6Address::from(Ipv6Addr::from(0x10u128));
+        let dst = Ipv6Address::from(Ipv6Addr::from(0x20u128));
+        let tcp = TcpRepr {
+            src_port: 1234,
+            dst_port: ChannelType::Messages as u16,
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(1),
+            ack_number: None,
+            window_len: 1024,
+            window_scale: None,
+            max_seg_size: None,
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            payload: b"payload",
+        };
+        let ip = Ipv6Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Tcp,
+            payload_len: tcp.buffer_len(),
+            hop_limit: 64,
+        };
+        let mut buffer = vec![0u8; ip.buffer_len() + tcp.buffer_len()];
+        let mut packet = Ipv6Packet::new_unchecked(&mut buffer);
+        ip.emit(&mut packet);
+        tcp.emit(
+            &mut TcpPacket::new_unchecked(packet.payload_mut()),
+            &src.into(),
+            &dst.into(),
+            &ChecksumCapabilities::default(),
+        );
+
+        let translated = Ipv6Addr::from(0x30u128);
+        assert!(rewrite_ipv6(&mut buffer, Some(translated), None));
+
+        let packet = Ipv6Packet::new_checked(&buffer).unwrap();
+        assert_eq!(Ipv6Addr::from(packet.src_addr()), translated);
+        assert_eq!(packet.dst_addr(), dst);
+        let tcp = TcpPacket::new_checked(packet.payload()).unwrap();
+        assert!(tcp.verify_checksum(&translated.into(), &dst.into()));
+        assert_eq!(tcp.payload(), b"payload");
+
+        assert!(!rewrite_ipv6(&mut [0u8; 8], Some(translated), None));
+    }
+}
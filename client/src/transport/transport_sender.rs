@@ -1,11 +1,20 @@
 use async_trait::async_trait;
 use derive_more::From;
+use std::sync::Arc;
+use std::time::Duration;
 
-use super::tcp_registry::TcpSender;
+#[cfg(feature = "virtual-tcp")]
+use super::tcp_registry::{ChannelType, TcpSender};
+use crate::client::ClientConfig;
+use crate::direct_session::DirectSession;
 use crate::error::SenderError;
+use crate::middleware;
 use crate::routing_session::RoutingSender;
 
+use ya_relay_core::runtime::Instant;
 use ya_relay_core::server_session::TransportType;
+use ya_relay_core::NodeId;
+#[cfg(feature = "virtual-tcp")]
 use ya_relay_proto::codec::forward::encode;
 use ya_relay_proto::proto::Payload;
 
@@ -25,6 +34,7 @@ pub trait GenericSender: Sized {
 
 /// `TcpSender` processes packets as stream of bytes. `FramedSender` adds frames
 /// abstraction to the stream, to distinguish separate packets.
+#[cfg(feature = "virtual-tcp")]
 #[derive(Clone)]
 pub struct FramedSender {
     sender: TcpSender,
@@ -34,13 +44,130 @@ pub struct FramedSender {
 #[derive(From, Clone)]
 pub enum ForwardSender {
     Unreliable(RoutingSender),
+    #[cfg(feature = "virtual-tcp")]
     Reliable(TcpSender),
+    #[cfg(feature = "virtual-tcp")]
     Framed(FramedSender),
 }
 
 impl ForwardSender {
+    /// Sends Payload, unless it waits for longer than `ttl` for the connection to be
+    /// established or to make room in the send queue, in which case it is dropped.
+    /// Payloads already queued are dropped as well, if their TTL passes before they can
+    /// be written to the virtual TCP connection.
+    ///
+    /// Meant for real-time data, which is useless when delivered late after a stall.
+    /// Payloads expiring while forwarding is paused fail with [`SenderError::Paused`].
+    pub async fn send_with_ttl(
+        &mut self,
+        mut packet: Payload,
+        ttl: Duration,
+    ) -> Result<(), SenderError> {
+        self.check_shutdown()?;
+        self.egress(&mut packet)?;
+        let result = match self {
+            ForwardSender::Unreliable(sender) => {
+                match ya_relay_core::runtime::timeout(ttl, sender.send_unreliable(packet)).await {
+                    Ok(result) => result.map_err(SenderError::from),
+                    Err(_) => Err(SenderError::Expired(ttl)),
+                }
+            }
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Reliable(sender) => sender.send_with_ttl(packet, ttl).await,
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Framed(FramedSender { sender }) => {
+                sender.send_with_ttl(encode(packet), ttl).await
+            }
+        };
+        match result {
+            Err(SenderError::Expired(ttl)) => match self.paused_by().await {
+                Some(node_id) => Err(SenderError::Paused(node_id)),
+                None => Err(SenderError::Expired(ttl)),
+            },
+            result => result.map_err(|e| self.on_failure(e)),
+        }
+    }
+
+    /// False if the underlying session or connection was closed in the meantime.
+    /// Sending will re-establish it, which can take a while.
+    pub fn is_connected(&self) -> bool {
+        match self {
+            ForwardSender::Unreliable(sender) => sender.is_connected(),
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Reliable(sender) => sender.is_connected(),
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Framed(FramedSender { sender }) => sender.is_connected(),
+        }
+    }
+
+    /// Time of the last send through the underlying virtual TCP connection.
+    /// Not tracked for unreliable senders, which expire together with their session.
+    pub fn last_used(&self) -> Option<Instant> {
+        match self {
+            ForwardSender::Unreliable(_) => None,
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Reliable(sender) => sender.last_used(),
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Framed(FramedSender { sender }) => sender.last_used(),
+        }
+    }
+
+    fn config(&self) -> &ClientConfig {
+        match self {
+            ForwardSender::Unreliable(sender) => sender.config(),
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Reliable(sender) | ForwardSender::Framed(FramedSender { sender }) => {
+                sender.layer.config()
+            }
+        }
+    }
+
+    fn check_shutdown(&self) -> Result<(), SenderError> {
+        match self.config().cancel.is_cancelled() {
+            true => Err(SenderError::Shutdown),
+            false => Ok(()),
+        }
+    }
+
+    /// Failures during shutdown are reported as such, whatever failed underneath.
+    fn on_failure(&self, e: SenderError) -> SenderError {
+        self.check_shutdown().err().unwrap_or(e)
+    }
+
+    /// Node or relay server, which paused forwarding on the session used to reach the target.
+    async fn paused_by(&self) -> Option<NodeId> {
+        let session: Option<Arc<DirectSession>> = match self {
+            ForwardSender::Unreliable(sender) => sender.direct_session(),
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Reliable(sender) | ForwardSender::Framed(FramedSender { sender }) => {
+                sender.direct_session().await
+            }
+        };
+        session
+            .filter(|session| !session.forward_gate.is_open())
+            .map(|session| session.owner.default_id)
+    }
+
+    fn egress(&self, packet: &mut Payload) -> Result<(), SenderError> {
+        let (config, node_id, transport) = match self {
+            ForwardSender::Unreliable(sender) => {
+                (sender.config(), sender.target(), TransportType::Unreliable)
+            }
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Reliable(sender) | ForwardSender::Framed(FramedSender { sender }) => {
+                let transport = match sender.channel.0 {
+                    ChannelType::Messages => TransportType::Reliable,
+                    ChannelType::Transfer => TransportType::Transfer,
+                };
+                (sender.layer.config(), sender.target, transport)
+            }
+        };
+        middleware::egress(&config.middleware, node_id, transport, packet)
+    }
+
     pub fn framed(self) -> ForwardSender {
         match self {
+            #[cfg(feature = "virtual-tcp")]
             ForwardSender::Reliable(sender) => FramedSender { sender }.into(),
             // `SenderKind::Unreliable` won't be converted.
             // `SenderKind::Framed` is already ok.
@@ -51,20 +178,28 @@ impl ForwardSender {
 
 #[async_trait(?Send)]
 impl GenericSender for ForwardSender {
-    async fn send(&mut self, packet: Payload) -> Result<(), SenderError> {
-        match self {
-            ForwardSender::Unreliable(sender) => {
-                Ok(sender.send(packet, TransportType::Unreliable).await?)
-            }
-            ForwardSender::Reliable(sender) => Ok(sender.send(packet).await?),
+    async fn send(&mut self, mut packet: Payload) -> Result<(), SenderError> {
+        self.check_shutdown()?;
+        self.egress(&mut packet)?;
+        let result = match self {
+            ForwardSender::Unreliable(sender) => sender
+                .send_unreliable(packet)
+                .await
+                .map_err(SenderError::from),
+            #[cfg(feature = "virtual-tcp")]
+            ForwardSender::Reliable(sender) => sender.send(packet).await.map_err(SenderError::from),
+            #[cfg(feature = "virtual-tcp")]
             ForwardSender::Framed(sender) => sender.send(packet).await,
-        }
+        };
+        result.map_err(|e| self.on_failure(e))
     }
 
     async fn connect(&mut self) -> Result<(), SenderError> {
         match self {
             ForwardSender::Unreliable(sender) => Ok(sender.connect().await?),
+            #[cfg(feature = "virtual-tcp")]
             ForwardSender::Reliable(sender) => Ok(sender.connect().await?),
+            #[cfg(feature = "virtual-tcp")]
             ForwardSender::Framed(sender) => sender.connect().await,
         }
     }
@@ -72,12 +207,15 @@ impl GenericSender for ForwardSender {
     async fn disconnect(&mut self) -> Result<(), SenderError> {
         match self {
             ForwardSender::Unreliable(sender) => Ok(sender.disconnect().await?),
+            #[cfg(feature = "virtual-tcp")]
             ForwardSender::Reliable(sender) => Ok(sender.disconnect().await?),
+            #[cfg(feature = "virtual-tcp")]
             ForwardSender::Framed(sender) => sender.disconnect().await,
         }
     }
 }
 
+#[cfg(feature = "virtual-tcp")]
 #[async_trait(?Send)]
 impl GenericSender for FramedSender {
     async fn send(&mut self, packet: Payload) -> Result<(), SenderError> {
@@ -2,16 +2,18 @@ use anyhow::Context;
 use futures::{FutureExt, StreamExt};
 use log::Level::Trace;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
+use std::net::Ipv6Addr;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use ya_relay_core::crypto::PublicKey;
+use ya_relay_core::identity::IdentityKey;
+use ya_relay_core::runtime::{Instant, SystemTime};
 use ya_relay_core::server_session::TransportType;
 use ya_relay_core::NodeId;
 use ya_relay_proto::proto::Payload;
@@ -21,19 +23,32 @@ use ya_relay_stack::smoltcp::wire::{IpAddress, IpCidr, IpEndpoint};
 use ya_relay_stack::socket::{SocketEndpoint, TCP_CONN_TIMEOUT, TCP_DISCONN_TIMEOUT};
 use ya_relay_stack::{
     Channel, ChannelMetrics, Connection, EgressEvent, IngressEvent, Network, Protocol, SocketDesc,
-    SocketState, Stack, StackConfig,
+    SocketState, Stack, StackConfig, TcpProgress, TcpSocketInfo,
 };
 
+use super::fair_queue::FairQueue;
+use super::ingress::Ingress;
 use super::tcp_registry::{
-    channel_endpoint, to_ipv6, ChannelDesc, ChannelDirection, ChannelType, TcpConnection, TcpLock,
-    TcpPermit, TcpRegistry, TcpSender, VirtNode,
+    channel_endpoint, rewrite_ipv6, to_ipv6, ChannelDesc, ChannelDirection, ChannelType,
+    ConnectProgress, ProgressFn, TcpConnection, TcpLock, TcpPermit, TcpRegistry, TcpSender,
+    TcpState, VirtNode,
 };
-use crate::client::Forwarded;
-use crate::error::TcpError;
+use crate::cancel::cancellable;
+use crate::client::{ClientConfig, Forwarded};
+use crate::error::{ConnectError, ConnectionLimit, SessionError, TcpError};
+use crate::middleware;
+use crate::pacing::Pacer;
 use crate::session::SessionLayer;
+use crate::stream::Streams;
 use crate::transport::ForwardReceiver;
+use crate::tuning::TuningProfile;
+use crate::watchdog::{StalledConnection, Watchdog, WatchdogConfig};
 
 const IPV6_DEFAULT_CIDR: u8 = 0;
+/// Number of attempts to find new route to Node, after sending packet failed.
+const REROUTE_ATTEMPTS: u32 = 3;
+/// Delay before each attempt grows linearly with its number.
+const REROUTE_BACKOFF: Duration = Duration::from_millis(500);
 
 /// Client implements TCP protocol over underlying UDP.
 /// To use TCP we need to create virtual network, so that TCP stack appears to
@@ -46,16 +61,26 @@ pub struct TcpLayer {
 
     registry: TcpRegistry,
 
-    ingress: Channel<Forwarded>,
-    virtual_tcp_fast_lane: Rc<RefCell<HashSet<NodeId>>>,
+    ingress: Ingress,
+    /// Nodes known to the registry, with their translated addresses.
+    virtual_tcp_fast_lane: Rc<RefCell<HashMap<NodeId, Option<Ipv6Addr>>>>,
+    /// Connections closed with `close_channel`, which disconnection shouldn't
+    /// remove the whole Node.
+    closing: Rc<RefCell<HashSet<SocketDesc>>>,
+    /// Tuning of outgoing connections, applied whenever they are established.
+    profiles: Rc<RefCell<HashMap<(NodeId, ChannelType), TuningProfile>>>,
+    streams: Streams,
+    pub(crate) pacer: Pacer,
+    stalled: Channel<StalledConnection>,
 }
 
 impl TcpLayer {
     pub fn new(
-        key: &PublicKey,
+        key: &IdentityKey,
         config: &StackConfig,
-        ingress: &Channel<Forwarded>,
+        ingress: &Ingress,
         session_layer: SessionLayer,
+        streams: Streams,
     ) -> TcpLayer {
         let pcap = config.pcap_path.clone().map(|p| match pcap_writer(p) {
             Ok(pcap) => pcap,
@@ -68,10 +93,19 @@ impl TcpLayer {
             ingress: ingress.clone(),
             registry: TcpRegistry::new(session_layer.clone()),
             virtual_tcp_fast_lane: Rc::new(RefCell::new(Default::default())),
+            closing: Default::default(),
+            profiles: Default::default(),
+            pacer: Pacer::new(session_layer.config.pacing),
+            stalled: Default::default(),
             session_layer,
+            streams,
         }
     }
 
+    pub(crate) fn config(&self) -> &ClientConfig {
+        &self.session_layer.config
+    }
+
     fn net_id(&self) -> String {
         self.net.name.as_ref().clone()
     }
@@ -90,6 +124,9 @@ impl TcpLayer {
 
         self.spawn_ingress_router().await?;
         self.spawn_egress_router().await?;
+        if let Some(config) = self.config().watchdog {
+            self.spawn_watchdog(config);
+        }
         Ok(())
     }
 
@@ -97,6 +134,69 @@ impl TcpLayer {
         self.registry.resolve_node(node).await
     }
 
+    /// Node with a virtual TCP connection from the virtual `address`.
+    pub async fn resolve_address(&self, address: Ipv6Addr) -> Option<NodeId> {
+        self.registry
+            .get_by_address(&address.octets())
+            .await
+            .map(|node| node.id())
+    }
+
+    pub async fn virt_nodes(&self) -> Vec<VirtNode> {
+        self.registry.virt_nodes().await
+    }
+
+    /// Registers Node, which is not known yet, unless the limit of connections is reached.
+    pub async fn add_virt_node(&self, node_id: NodeId) -> Result<VirtNode, ConnectError> {
+        let address = IpAddress::from(self.registry.address(node_id).await);
+        self.check_limits(node_id, &address, 1)?;
+        Ok(self.registry.add_virt_node(node_id).await)
+    }
+
+    /// Fails if `new` more connections with Node at `address` would exceed the limits
+    /// from `ClientConfig`. Listening sockets are not counted.
+    fn check_limits(
+        &self,
+        node_id: NodeId,
+        address: &IpAddress,
+        new: usize,
+    ) -> Result<(), ConnectError> {
+        let config = &self.session_layer.config;
+        if config.max_virt_connections.is_none() && config.max_virt_connections_per_node.is_none() {
+            return Ok(());
+        }
+
+        let (total, per_node) = {
+            let bindings = self.net.bindings();
+            self.net
+                .sockets_meta()
+                .into_iter()
+                .filter(|(handle, desc, _)| {
+                    desc.protocol == Protocol::Tcp && !bindings.contains(handle)
+                })
+                .fold((0, 0), |(total, per_node), (_, desc, _)| {
+                    let same_node =
+                        matches!(desc.remote.ip_endpoint(), Ok(e) if &e.addr == address);
+                    (total + 1, per_node + same_node as usize)
+                })
+        };
+
+        let exceeded = |count: usize, max: Option<usize>| max.filter(|max| count + new > *max);
+        if let Some(max) = exceeded(total, config.max_virt_connections) {
+            return Err(ConnectError::TooManyConnections(
+                node_id,
+                ConnectionLimit::Total(max),
+            ));
+        }
+        if let Some(max) = exceeded(per_node, config.max_virt_connections_per_node) {
+            return Err(ConnectError::TooManyConnections(
+                node_id,
+                ConnectionLimit::PerNode(max),
+            ));
+        }
+        Ok(())
+    }
+
     pub async fn remove_node(&self, node_id: NodeId) {
         log::trace!("[VirtualTcp]: Removing Node {node_id}");
 
@@ -108,6 +208,41 @@ impl TcpLayer {
         self.registry.remove_node(node_id).await;
     }
 
+    /// Closes a single outgoing channel to the Node, leaving its other connections open.
+    /// The next `connect` on this channel will establish a new connection.
+    pub async fn close_channel(&self, node_id: NodeId, channel: ChannelType) {
+        log::trace!("[VirtualTcp]: Closing channel {channel} to Node {node_id}");
+
+        if let Some(connection) = self.registry.close_out_channel(node_id, channel).await {
+            self.closing
+                .borrow_mut()
+                .insert(connection.conn.meta.into());
+            self.net
+                .disconnect(connection.conn, TCP_DISCONN_TIMEOUT)
+                .await;
+        }
+    }
+
+    /// Profile used by outgoing connections on the channel to the Node.
+    pub fn profile(&self, node_id: NodeId, channel: ChannelType) -> Option<TuningProfile> {
+        self.profiles.borrow().get(&(node_id, channel)).copied()
+    }
+
+    /// Sets the profile of outgoing connections on the channel to the Node, taking effect
+    /// when the next one is established. Returns false, if the profile didn't change.
+    pub fn set_profile(
+        &self,
+        node_id: NodeId,
+        channel: ChannelType,
+        profile: TuningProfile,
+    ) -> bool {
+        self.pacer.set_profile(node_id, profile.pacing());
+        self.profiles
+            .borrow_mut()
+            .insert((node_id, channel), profile)
+            != Some(profile)
+    }
+
     /// Connects to other Node and returns `TcpSender` for sending data.
     /// TODO: We need to ensure that only one single connection can be established
     ///       at the same time and rest of attempts will wait for finish.
@@ -117,6 +252,16 @@ impl TcpLayer {
         &self,
         node_id: NodeId,
         channel: ChannelType,
+    ) -> Result<TcpSender, TcpError> {
+        self.connect_with_progress(node_id, channel, None).await
+    }
+
+    /// Same as [`TcpLayer::connect`], reporting stages of the connection to `progress`.
+    pub(crate) async fn connect_with_progress(
+        &self,
+        node_id: NodeId,
+        channel: ChannelType,
+        progress: Option<ProgressFn>,
     ) -> Result<TcpSender, TcpError> {
         if log::log_enabled!(Trace) {
             print_sockets(&self.net);
@@ -129,8 +274,9 @@ impl TcpLayer {
                 log::debug!("[VirtualTcp] Connecting to node [{node_id}], channel: {channel}.");
 
                 // Spawning task protects us from dropping future during initialization.
-                tokio::task::spawn_local(async move {
-                    permit.finish(myself.connect_internal(channel, &permit).await)
+                let progress = progress.clone();
+                ya_relay_core::runtime::spawn_local(async move {
+                    permit.finish(myself.connect_internal(channel, &permit, progress).await)
                 })
                 .await
                 .map_err(|e| TcpError::Generic {
@@ -138,17 +284,19 @@ impl TcpLayer {
                     source: Arc::new(e),
                 })?
             }
-            TcpLock::Wait(mut waiter) => {
-                waiter
-                    .await_for_finish()
-                    .await
-                    .map_err(|e| TcpError::Generic {
-                        msg: "error when waiting".to_string(),
-                        source: Arc::new(e),
-                    })
-            }
+            TcpLock::Wait(mut waiter) => waiter.await_for_finish().await.map_err(|e| match e {
+                e @ TcpError::Connect(_) => e,
+                e => TcpError::Generic {
+                    msg: "error when waiting".to_string(),
+                    source: Arc::new(e),
+                },
+            }),
         }?;
 
+        if let Some(progress) = progress {
+            progress(ConnectProgress::Established);
+        }
+
         Ok(TcpSender {
             target: connection.id,
             channel,
@@ -161,35 +309,46 @@ impl TcpLayer {
         &self,
         channel: ChannelDesc,
         permit: &TcpPermit,
+        progress: Option<ProgressFn>,
     ) -> Result<Arc<TcpConnection>, TcpError> {
-        log::trace!(
-            "[VirtualTcp] Connecting to node [{node_id}], channel: {channel}.",
-            node_id = permit.node.id(),
-            channel = channel
-        );
+        let node_id = permit.node.id();
+        log::trace!("[VirtualTcp] Connecting to node [{node_id}], channel: {channel}.");
         let endpoint = IpEndpoint::new(permit.node.address, channel.port());
+        let report = |stage| {
+            if let Some(progress) = &progress {
+                progress(stage);
+            }
+        };
 
         // Make sure we have session with the Node. This allows us to
         // exit early if target Node is unreachable.
+        report(ConnectProgress::Resolving);
         self.session_layer
-            .session(permit.node.id())
+            .session(node_id)
             .await
-            .map_err(|e| TcpError::Generic {
-                msg: "Failed to create session".to_string(),
-                source: Arc::new(e),
+            .map_err(|e| match e {
+                SessionError::NotFound(msg) => ConnectError::Resolve(node_id, msg),
+                e => ConnectError::Session(node_id, e),
             })?;
+        report(ConnectProgress::Registered);
+
+        // Checked right before creating the socket, so concurrent attempts can't exceed limits.
+        self.check_limits(node_id, &permit.node.address, 1)?;
+        let tuning = self
+            .profile(node_id, channel.0)
+            .map(|profile| profile.tcp());
+        let connect = self.net.connect_tuned(endpoint, TCP_CONN_TIMEOUT, tuning);
+        report(ConnectProgress::SynSent);
+        let conn = connect.await.map_err(|e| match e {
+            ya_relay_stack::Error::ConnectionTimeout => ConnectError::SynTimeout(node_id),
+            e => ConnectError::Tcp(node_id, e.to_string()),
+        })?;
 
         Ok(Arc::new(TcpConnection {
-            id: permit.node.id(),
-            conn: self
-                .net
-                .connect(endpoint, TCP_CONN_TIMEOUT)
-                .await
-                .map_err(|e| TcpError::Generic {
-                    msg: "establishing Tcp connection".to_string(),
-                    source: Arc::new(e),
-                })?,
+            id: node_id,
+            conn,
             channel,
+            last_used: parking_lot::Mutex::new(Instant::now()),
         }))
     }
 
@@ -198,6 +357,11 @@ impl TcpLayer {
         self.net.sockets()
     }
 
+    #[inline]
+    pub fn tcp_sockets(&self) -> Vec<(SocketDesc, TcpSocketInfo)> {
+        self.net.tcp_sockets()
+    }
+
     #[inline]
     pub fn metrics(&self) -> ChannelMetrics {
         self.net.metrics()
@@ -218,42 +382,153 @@ impl TcpLayer {
         Ok(self.net.send(data, connection).await?)
     }
 
+    /// Fails with `ya_relay_stack::Error::Expired`, if `data` can't be queued before
+    /// `deadline`, see [`Network::send_until`].
+    pub async fn send_until(
+        &self,
+        data: impl Into<Payload>,
+        connection: Connection,
+        deadline: Instant,
+    ) -> Result<(), ya_relay_stack::Error> {
+        let data: Payload = data.into();
+
+        ya_packet_trace::packet_trace_maybe!("TcpLayer::Send", {
+            &ya_packet_trace::try_extract_from_ip_frame(data.as_ref())
+        });
+
+        self.net.send_until(data, connection, deadline).await
+    }
+
+    /// Connections recycled by the watchdog, see [`crate::watchdog`].
+    pub fn stalled_connections(&self) -> Option<UnboundedReceiver<StalledConnection>> {
+        self.stalled.receiver()
+    }
+
+    /// Sending progress of outgoing connections.
+    async fn out_progress(&self) -> Vec<(NodeId, TransportType, TcpProgress)> {
+        let mut progress = Vec::new();
+        for node in self.virt_nodes().await {
+            for (channel, transport) in [
+                (ChannelType::Messages, TransportType::Reliable),
+                (ChannelType::Transfer, TransportType::Transfer),
+            ] {
+                let channel = (channel, ChannelDirection::Out).into();
+                if let TcpState::Connected(connection) = node.channel(channel).state().await {
+                    if let Some(current) = self.net.tcp_progress(&connection.conn) {
+                        progress.push((node.id(), transport, current));
+                    }
+                }
+            }
+        }
+        progress
+    }
+
+    fn spawn_watchdog(&self, config: WatchdogConfig) {
+        let myself = self.clone();
+        ya_relay_core::runtime::spawn_local(async move {
+            let mut watchdog = Watchdog::default();
+            loop {
+                ya_relay_core::runtime::sleep(config.check_interval).await;
+                let progress = myself.out_progress().await;
+                for stalled in watchdog.check(progress, config.stall_timeout, Instant::now()) {
+                    log::warn!(
+                        "[VirtualTcp] Recycling {} connection to [{}]: nothing acknowledged for {:?}, \
+                         {} B queued, socket {}",
+                        stalled.channel,
+                        stalled.node_id,
+                        stalled.stalled_for,
+                        stalled.queued,
+                        stalled.state,
+                    );
+                    let channel = match stalled.channel {
+                        TransportType::Transfer => ChannelType::Transfer,
+                        _ => ChannelType::Messages,
+                    };
+                    myself.close_channel(stalled.node_id, channel).await;
+                    myself.stalled.tx.send(stalled).ok();
+                }
+            }
+        });
+    }
+
+    /// Number of queued payloads dropped after their TTL passed.
+    pub fn expired(&self) -> usize {
+        self.net.expired()
+    }
+
     pub async fn dispatch(&self, packet: Forwarded) {
         log::trace!("[dispatch]: from {}", packet.node_id);
         let node_id = packet.node_id;
-        let exists = {
-            // Optimisation to avoid resolving Node if possible.
-            let fast_lane = self.virtual_tcp_fast_lane.borrow();
-            fast_lane.contains(&node_id)
-        };
+        // Optimisation to avoid resolving Node if possible.
+        let known = self.virtual_tcp_fast_lane.borrow().get(&node_id).copied();
 
-        if exists {
-            self.inject(packet.payload);
+        if let Some(translated) = known {
+            if self.firewall_rejects(node_id, &packet.payload) {
+                return;
+            }
+            self.inject_from(packet.payload, translated);
             return;
         }
 
-        self.receive(node_id, packet.payload).await;
-        self.virtual_tcp_fast_lane.borrow_mut().insert(node_id);
+        if let Some(node) = self.receive(node_id, packet.payload).await {
+            self.virtual_tcp_fast_lane
+                .borrow_mut()
+                .insert(node_id, node.translated());
+        }
     }
 
-    pub async fn receive(&self, node: NodeId, payload: Payload) {
+    /// Passes the packet to the TCP stack, returning the Node it came from,
+    /// unless the packet was dropped.
+    pub async fn receive(&self, node: NodeId, payload: Payload) -> Option<VirtNode> {
         log::trace!("[receive]: from {}", node);
         ya_packet_trace::packet_trace_maybe!("TcpLayer::Receive", {
             &ya_packet_trace::try_extract_from_ip_frame(payload.as_ref())
         });
 
+        if self.firewall_rejects(node, &payload) {
+            return None;
+        }
+
         // TODO: Since we distinguish between outgoing and incoming connections
         //       We should change incoming connection state to Established. Current code doesn't handle
         //       this correctly.
-        if self.registry.resolve_node(node).await.is_err() {
-            log::debug!(
-                "[VirtualTcp::receive] Incoming message from new Node [{node}]. Adding connection."
-            );
-            self.registry.add_virt_node(node).await;
+        let virt_node = match self.registry.resolve_node(node).await {
+            Ok(virt_node) => virt_node,
+            Err(_) => {
+                log::debug!(
+                    "[VirtualTcp::receive] Incoming message from new Node [{node}]. Adding connection."
+                );
+                match self.add_virt_node(node).await {
+                    Ok(virt_node) => virt_node,
+                    Err(e) => {
+                        log::debug!("[VirtualTcp::receive] Dropping packet from [{node}]: {e}");
+                        return None;
+                    }
+                }
+            }
+        };
+        self.inject_from(payload, virt_node.translated());
+        Some(virt_node)
+    }
+
+    /// Injects packet of a Node, replacing its source with the `translated` address.
+    fn inject_from(&self, mut payload: Payload, translated: Option<Ipv6Addr>) {
+        if let Some(address) = translated {
+            rewrite_ipv6(payload.as_mut(), Some(address), None);
         }
         self.inject(payload);
     }
 
+    /// Connection requests from Nodes not permitted by [`crate::firewall::Firewall`]
+    /// are dropped before reaching the TCP stack.
+    fn firewall_rejects(&self, node_id: NodeId, payload: &Payload) -> bool {
+        let rejected = self.config().firewall.rejects(node_id, payload.as_ref());
+        if rejected {
+            log::debug!("[VirtualTcp] Firewall rejected connection from [{node_id}]");
+        }
+        rejected
+    }
+
     #[inline]
     pub fn inject(&self, payload: Payload) {
         log::trace!(
@@ -306,7 +581,7 @@ impl TcpLayer {
             .ingress_receiver()
             .ok_or_else(|| anyhow::anyhow!("Ingress traffic router already spawned"))?;
 
-        tokio::task::spawn_local(self.clone().ingress_router(ingress_rx));
+        ya_relay_core::runtime::spawn_local(self.clone().ingress_router(ingress_rx));
         Ok(())
     }
 
@@ -316,7 +591,7 @@ impl TcpLayer {
             .egress_receiver()
             .ok_or_else(|| anyhow::anyhow!("Egress traffic router already spawned"))?;
 
-        tokio::task::spawn_local(self.clone().egress_router(egress_rx));
+        ya_relay_core::runtime::spawn_local(self.clone().egress_router(egress_rx));
         Ok(())
     }
 
@@ -344,6 +619,9 @@ impl TcpLayer {
                                 desc.remote,
                             );
 
+                            if myself.closing.borrow_mut().remove(&desc) {
+                                return;
+                            }
                             if let Ok(endpoint)= desc.remote.ip_endpoint() {
                                 if let Some(node) = myself.registry.get_by_address(endpoint.addr.as_bytes()).await {
                                     myself.remove_node(node.id()).await;
@@ -386,30 +664,47 @@ impl TcpLayer {
                     match {
                         // Nodes are populated via `VirtualLayer::dispatch`
                         myself.registry.get_by_address(remote_address.as_bytes()).await
-                            .map(|node| (node.id(), myself.ingress.tx.clone()))
+                            .map(|node| (node.id(), myself.ingress.clone()))
                     } {
-                        Some((node_id, tx)) => {
+                        Some((node_id, ingress)) => {
+                            // Inbound connections are accepted on channel ports.
+                            let inbound = local_port == ChannelType::Messages as u16
+                                || local_port == ChannelType::Transfer as u16;
+                            if inbound && !myself.config().firewall.permits(node_id) {
+                                log::debug!(
+                                    "[{}] ingress router: firewall rejected payload from [{node_id}]",
+                                    myself.net_id()
+                                );
+                                return;
+                            }
+
                             let payload_len = payload.len();
-                            let payload = Forwarded {
-                                transport: match ChannelType::from(local_port) {
-                                    ChannelType::Messages => TransportType::Reliable,
-                                    ChannelType::Transfer => TransportType::Transfer,
-                                },
+                            let transport = match ChannelType::from(local_port) {
+                                ChannelType::Messages => TransportType::Reliable,
+                                ChannelType::Transfer => TransportType::Transfer,
+                            };
+                            let mut payload = Forwarded {
+                                transport,
                                 node_id,
                                 payload: payload.into(),
+                                session: myself.session_layer.forwarded_session(node_id),
+                                received_at: SystemTime::now(),
                             };
-
-                            if tx.send(payload).is_err() {
-                                log::trace!(
-                                    "[{}] ingress router: ingress handler closed for node {node_id}",
-                                    myself.net_id()
-                                );
-                            } else {
-                                log::trace!(
-                                    "[{}] ingress router: forwarded {payload_len} B",
-                                    myself.net_id()
-                                );
+                            if !middleware::ingress(&myself.config().middleware, &mut payload) {
+                                return;
                             }
+                            if transport == TransportType::Transfer
+                                && myself.streams.dispatch(node_id, payload.payload.as_ref())
+                            {
+                                return;
+                            }
+
+                            let net = myself.net.clone();
+                            ingress.send(payload, |paused| net.set_recv_paused(paused)).await;
+                            log::trace!(
+                                "[{}] ingress router: forwarded {payload_len} B",
+                                myself.net_id()
+                            );
                         }
                         _ => log::trace!(
                             "[{}] ingress router: unknown remote address {remote_address}",
@@ -421,60 +716,144 @@ impl TcpLayer {
             .await
     }
 
-    async fn egress_router(self, egress_rx: UnboundedReceiver<EgressEvent>) {
-        UnboundedReceiverStream::new(egress_rx)
-            .for_each(move |egress| {
+    /// Resolves Node again, replaces routing in its `VirtNode` and resends the packet.
+    /// Sessions can die without TCP connections on top of them noticing, so they are
+    /// closed only when Node stays unreachable after all attempts.
+    async fn reroute(&self, node_id: NodeId, payload: Payload) -> Result<(), SessionError> {
+        let mut attempt = 1;
+        loop {
+            ya_relay_core::runtime::sleep(REROUTE_BACKOFF * attempt).await;
+            log::debug!(
+                "[{}] egress router: re-resolving [{node_id}] ({attempt}/{REROUTE_ATTEMPTS})",
+                self.net_id()
+            );
+
+            let result = match self.session_layer.reresolve(node_id).await {
+                Ok(mut routing) => {
+                    self.registry.update_routing(node_id, routing.clone()).await;
+                    routing.send(payload.clone(), TransportType::Reliable).await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                // Re-resolving needs the relay server, so attempts don't count until
+                // its session is back, or the grace period ends.
+                Err(e) if self.session_layer.outage.is_active() => {
+                    log::debug!(
+                        "[{}] egress router: waiting for relay session to reach [{node_id}]: {e}",
+                        self.net_id()
+                    );
+                    self.session_layer.outage.wait().await;
+                    continue;
+                }
+                Err(e) if attempt >= REROUTE_ATTEMPTS => return Err(e),
+                Err(e) => log::debug!(
+                    "[{}] egress router: attempt to reach [{node_id}] failed: {e}",
+                    self.net_id()
+                ),
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Queues packets per connection and sends them in round-robin order, so a bulk
+    /// transfer doesn't hold back packets of the other connections.
+    async fn egress_router(self, mut egress_rx: UnboundedReceiver<EgressEvent>) {
+        let (done_tx, mut done_rx) = unbounded_channel();
+        let mut queue = FairQueue::default();
+
+        loop {
+            tokio::select! {
+                egress = egress_rx.recv() => match egress {
+                    Some(egress) => {
+                        let key = (egress.remote.clone(), egress.desc.map(|(desc, _)| desc));
+                        queue.push(key, egress);
+                    }
+                    None => break,
+                },
+                Some(key) = done_rx.recv() => queue.done(key),
+            }
+
+            while let Some((key, egress)) = queue.pop() {
                 let myself = self.clone();
-                async move {
-                    let mut node = match myself.registry.get_by_address(&egress.remote).await {
-                        Some(node) => node,
-                        None => {
-                            log::trace!(
-                                "[{}] egress router: unknown address {:02x?}",
-                                myself.net_id(),
-                                egress.remote
-                            );
-                            return;
-                        }
-                    };
+                let done_tx = done_tx.clone();
+                ya_relay_core::runtime::spawn_local(async move {
+                    myself.forward_egress(egress).await;
+                    let _ = done_tx.send(key);
+                });
+            }
+        }
+    }
 
-                    log::trace!("[egress_router]: node: {}", node.id());
+    async fn forward_egress(self, egress: EgressEvent) {
+        let mut node = match self.registry.get_by_address(&egress.remote).await {
+            Some(node) => node,
+            None => {
+                log::trace!(
+                    "[{}] egress router: unknown address {:02x?}",
+                    self.net_id(),
+                    egress.remote
+                );
+                return;
+            }
+        };
 
-                    // `RoutingSender::send` will lazily create session with target Node.
-                    // In most cases session will exist, but if not, we need to protect from
-                    // blocking the rest of packets in the stream.
-                    //
-                    // Note that thanks to lazy sessions, even if we have unstable connection,
-                    // TCP sessions are able to survive disconnection on lower layer. In previous
-                    // implementation we disconnected TCP and all GSB messages in queue were lost.
-                    tokio::task::spawn_local(async move {
-                        log::trace!(
-                            "[{}] egress router: forwarding to [{}]",
-                            myself.net_id(),
-                            node.id()
-                        );
-                        if let Err(error) = node
-                            .routing
-                            .send(egress.payload.into(), TransportType::Reliable)
-                            .await
-                        {
-                            // TODO: In case of failure it would be nice to somehow send this error
-                            //       back to message sender. In current scenario GSB messages will
-                            //       wait until timeout. This makes error messages from this library
-                            //       really poor, because everything from outside looks like a timeout.
-                            log::trace!(
-                                "[{}] egress router: forward to [{}] failed: {}",
-                                myself.net_id(),
-                                node.id(),
-                                error
-                            );
+        log::trace!("[egress_router]: node: {}", node.id());
+        let delay = self.pacer.delay(node.id(), egress.payload.len());
+        if !delay.is_zero() {
+            ya_relay_core::runtime::sleep(delay).await;
+        }
 
-                            myself.remove_node(node.id()).await;
-                        }
-                    });
-                }
-            })
-            .await
+        // `RoutingSender::send` will lazily create session with target Node.
+        // In most cases session will exist, but if not, only packets of this connection
+        // wait for it, since each connection has its own queue.
+        //
+        // Note that thanks to lazy sessions, even if we have unstable connection,
+        // TCP sessions are able to survive disconnection on lower layer. In previous
+        // implementation we disconnected TCP and all GSB messages in queue were lost.
+        log::trace!(
+            "[{}] egress router: forwarding to [{}]",
+            self.net_id(),
+            node.id()
+        );
+        let mut payload: Payload = egress.payload.into();
+        if node.translated().is_some() {
+            // The Node knows only its own virtual address.
+            rewrite_ipv6(payload.as_mut(), None, Some(to_ipv6(node.id())));
+        }
+        let send = node.routing.send(payload.clone(), TransportType::Reliable);
+        let result = match cancellable(&self.config().cancel, send).await {
+            Ok(result) => result,
+            // Client is shutting down, no point in rerouting.
+            Err(_) => return,
+        };
+        if let Err(error) = result {
+            log::debug!(
+                "[{}] egress router: forward to [{}] failed: {}",
+                self.net_id(),
+                node.id(),
+                error
+            );
+
+            if let Err(error) = self.reroute(node.id(), payload).await {
+                // TODO: In case of failure it would be nice to somehow send this error
+                //       back to message sender. In current scenario GSB messages will
+                //       wait until timeout. This makes error messages from this library
+                //       really poor, because everything from outside looks like a timeout.
+                log::info!(
+                    "[{}] egress router: unable to reach [{}], closing connections: {}",
+                    self.net_id(),
+                    node.id(),
+                    error
+                );
+                self.session_layer.errors.record(format!(
+                    "Forward to [{}] failed after {REROUTE_ATTEMPTS} re-resolve attempts: {error}",
+                    node.id()
+                ));
+                self.remove_node(node.id()).await;
+            }
+        }
     }
 }
 
@@ -501,11 +880,11 @@ fn pcap_writer(path: PathBuf) -> anyhow::Result<Box<dyn Write>> {
 }
 
 fn default_network(
-    key: PublicKey,
+    key: IdentityKey,
     config: Rc<StackConfig>,
     pcap: Option<Box<dyn Write>>,
 ) -> Network {
-    let address = key.address();
+    let address = key.node_id().into_array();
     let ipv6_addr = to_ipv6(address);
     let ipv6_cidr = IpCidr::new(IpAddress::from(ipv6_addr), IPV6_DEFAULT_CIDR);
     let mut iface = match pcap {
@@ -0,0 +1,395 @@
+//! Attaches the virtual network to a TUN interface, so that unmodified applications can reach
+//! other Nodes with ordinary sockets, using addresses derived from their `NodeId`s.
+//!
+//! Packets read from the interface are routed to the Node owning the destination address and
+//! sent over the unreliable channel. Unreliable packets carrying IPv6 frames between the sender
+//! and this Node are written back to the interface; other incoming packets are passed through
+//! to the receiver returned from [`TunBridge::start`].
+//!
+//! Creating the interface requires `CAP_NET_ADMIN`.
+use anyhow::{anyhow, Context};
+use futures::future::AbortHandle;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::net::Ipv6Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+
+use ya_relay_core::utils::spawn_local_abortable;
+use ya_relay_core::NodeId;
+
+use crate::client::{Client, ForwardSender, Forwarded, GenericSender, TransportType};
+use crate::transport::tcp_registry::to_ipv6;
+use crate::transport::ForwardReceiver;
+
+/// Minimal MTU required by IPv6, small enough to fit in a single `Forward` packet.
+pub const DEFAULT_TUN_MTU: u16 = 1280;
+const IPV6_HEADER_SIZE: usize = 40;
+
+#[derive(Clone, Debug)]
+pub struct TunConfig {
+    /// Interface name. `%d` is replaced by the kernel with the first free index.
+    pub name: String,
+    pub mtu: u16,
+}
+
+impl Default for TunConfig {
+    fn default() -> Self {
+        TunConfig {
+            name: "yarelay%d".to_string(),
+            mtu: DEFAULT_TUN_MTU,
+        }
+    }
+}
+
+/// Keeps the interface alive. The interface is removed when the bridge is dropped.
+pub struct TunBridge {
+    shared: Arc<Shared>,
+    handles: Vec<AbortHandle>,
+}
+
+struct Shared {
+    device: Device,
+    address: Ipv6Addr,
+    peers: Mutex<HashMap<Ipv6Addr, NodeId>>,
+}
+
+impl TunBridge {
+    /// Creates the interface and takes over the `Client`'s forward receiver.
+    pub async fn start(
+        client: &Client,
+        config: TunConfig,
+    ) -> anyhow::Result<(TunBridge, ForwardReceiver)> {
+        let receiver = client
+            .forward_receiver()
+            .await
+            .ok_or_else(|| anyhow!("Forward receiver already taken"))?;
+
+        let address = to_ipv6(client.node_id());
+        let device = Device::open(&config.name).context("Failed to create TUN interface")?;
+        device
+            .configure(address, config.mtu)
+            .with_context(|| format!("Failed to configure interface {}", device.name))?;
+
+        log::info!("[Tun] Interface {} bound to {address}", device.name);
+
+        let shared = Arc::new(Shared {
+            device,
+            address,
+            peers: Default::default(),
+        });
+        let (passthrough_tx, passthrough_rx) = mpsc::channel(receiver.max_capacity());
+
+        let handles = vec![
+            spawn_local_abortable(egress(shared.clone(), client.clone(), config.mtu)),
+            spawn_local_abortable(ingress(shared.clone(), receiver, passthrough_tx)),
+        ];
+        Ok((TunBridge { shared, handles }, passthrough_rx))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.shared.device.name
+    }
+
+    /// Address assigned to the interface, derived from our `NodeId`.
+    pub fn address(&self) -> Ipv6Addr {
+        self.shared.address
+    }
+
+    /// Adds a route to the Node. Nodes sending us packets are added automatically.
+    pub fn add_peer(&self, node_id: NodeId) -> anyhow::Result<Ipv6Addr> {
+        Ok(self.shared.add_peer(node_id)?)
+    }
+
+    pub fn remove_peer(&self, node_id: NodeId) -> anyhow::Result<()> {
+        let address = to_ipv6(node_id);
+        if self.shared.peers.lock().remove(&address).is_some() {
+            self.shared.device.route(libc::SIOCDELRT, address)?;
+        }
+        Ok(())
+    }
+
+    pub fn peers(&self) -> Vec<(Ipv6Addr, NodeId)> {
+        let peers = self.shared.peers.lock();
+        peers.iter().map(|(ip, node_id)| (*ip, *node_id)).collect()
+    }
+}
+
+impl Drop for TunBridge {
+    fn drop(&mut self) {
+        self.handles.iter().for_each(|handle| handle.abort());
+    }
+}
+
+impl Shared {
+    fn add_peer(&self, node_id: NodeId) -> io::Result<Ipv6Addr> {
+        let address = to_ipv6(node_id);
+        if self.peers.lock().insert(address, node_id).is_none() {
+            log::debug!("[Tun] Adding route to [{node_id}] ({address})");
+
+            if let Err(e) = self.device.route(libc::SIOCADDRT, address) {
+                self.peers.lock().remove(&address);
+                return Err(e);
+            }
+        }
+        Ok(address)
+    }
+}
+
+async fn egress(shared: Arc<Shared>, client: Client, mtu: u16) {
+    let mut senders = HashMap::<NodeId, ForwardSender>::new();
+    let mut buf = vec![0u8; mtu as usize];
+
+    loop {
+        let len = match shared.device.read(&mut buf).await {
+            Ok(len) => len,
+            Err(e) => {
+                log::warn!("[Tun] Reading from {} failed: {e}", shared.device.name);
+                break;
+            }
+        };
+
+        let Some((_, dst)) = ipv6_addresses(&buf[..len]) else {
+            continue;
+        };
+        let Some(node_id) = shared.peers.lock().get(&dst).copied() else {
+            log::trace!("[Tun] No Node for address {dst}, dropping packet");
+            continue;
+        };
+
+        let sender = match senders.get_mut(&node_id) {
+            Some(sender) => sender,
+            None => match client.forward_unreliable(node_id).await {
+                Ok(sender) => senders.entry(node_id).or_insert(sender),
+                Err(e) => {
+                    log::debug!("[Tun] Can't forward to [{node_id}]: {e}");
+                    continue;
+                }
+            },
+        };
+
+        if let Err(e) = sender.send(buf[..len].to_vec().into()).await {
+            log::debug!("[Tun] Forwarding to [{node_id}] failed: {e}");
+            senders.remove(&node_id);
+        }
+    }
+}
+
+async fn ingress(
+    shared: Arc<Shared>,
+    mut receiver: ForwardReceiver,
+    passthrough: mpsc::Sender<Forwarded>,
+) {
+    while let Some(forwarded) = receiver.recv().await {
+        let payload = forwarded.payload.as_ref();
+        let addressed_to_us = match ipv6_addresses(payload) {
+            Some((src, dst)) => src == to_ipv6(forwarded.node_id) && dst == shared.address,
+            None => false,
+        };
+
+        if forwarded.transport != TransportType::Unreliable || !addressed_to_us {
+            passthrough.send(forwarded).await.ok();
+            continue;
+        }
+
+        if let Err(e) = shared.add_peer(forwarded.node_id) {
+            log::warn!("[Tun] Failed to add route to [{}]: {e}", forwarded.node_id);
+        }
+        if let Err(e) = shared.device.write(payload).await {
+            log::warn!("[Tun] Writing to {} failed: {e}", shared.device.name);
+        }
+    }
+}
+
+/// Returns source and destination address of an IPv6 packet.
+fn ipv6_addresses(packet: &[u8]) -> Option<(Ipv6Addr, Ipv6Addr)> {
+    if packet.len() < IPV6_HEADER_SIZE || packet[0] >> 4 != 6 {
+        return None;
+    }
+    let src: [u8; 16] = packet[8..24].try_into().ok()?;
+    let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+    Some((src.into(), dst.into()))
+}
+
+struct Device {
+    fd: AsyncFd<OwnedFd>,
+    /// Socket used for configuring the interface.
+    control: OwnedFd,
+    name: String,
+    index: libc::c_int,
+}
+
+impl Device {
+    fn open(name: &str) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::open(
+                b"/dev/net/tun\0".as_ptr() as *const libc::c_char,
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut req = ifreq(name)?;
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut req)?;
+        let name = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        let control =
+            unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if control < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let control = unsafe { OwnedFd::from_raw_fd(control) };
+
+        let mut req = ifreq(&name)?;
+        ioctl(control.as_raw_fd(), libc::SIOCGIFINDEX, &mut req)?;
+        let index = unsafe { req.ifr_ifru.ifru_ifindex };
+
+        Ok(Device {
+            fd: AsyncFd::new(fd)?,
+            control,
+            name,
+            index,
+        })
+    }
+
+    fn configure(&self, address: Ipv6Addr, mtu: u16) -> io::Result<()> {
+        let control = self.control.as_raw_fd();
+
+        let mut req = ifreq(&self.name)?;
+        req.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+        ioctl(control, libc::SIOCSIFMTU, &mut req)?;
+
+        // There are no other hosts on the link, so address can be used immediately.
+        let dad = format!("/proc/sys/net/ipv6/conf/{}/accept_dad", self.name);
+        if let Err(e) = std::fs::write(dad, "0") {
+            log::debug!("[Tun] Can't disable DAD on {}: {e}", self.name);
+        }
+
+        let mut req = ifreq(&self.name)?;
+        ioctl(control, libc::SIOCGIFFLAGS, &mut req)?;
+        unsafe { req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short };
+        ioctl(control, libc::SIOCSIFFLAGS, &mut req)?;
+
+        let mut req = libc::in6_ifreq {
+            ifr6_addr: libc::in6_addr {
+                s6_addr: address.octets(),
+            },
+            ifr6_prefixlen: 128,
+            ifr6_ifindex: self.index,
+        };
+        ioctl(control, libc::SIOCSIFADDR, &mut req)
+    }
+
+    /// Adds or removes a host route to `address` through the interface.
+    fn route(&self, request: libc::Ioctl, address: Ipv6Addr) -> io::Result<()> {
+        let mut msg = In6RtMsg {
+            rtmsg_dst: libc::in6_addr {
+                s6_addr: address.octets(),
+            },
+            rtmsg_dst_len: 128,
+            rtmsg_metric: 1,
+            rtmsg_flags: (libc::RTF_UP | libc::RTF_HOST) as u32,
+            rtmsg_ifindex: self.index,
+            ..unsafe { mem::zeroed() }
+        };
+
+        match ioctl(self.control.as_raw_fd(), request, &mut msg) {
+            Err(e) if request == libc::SIOCADDRT && e.raw_os_error() == Some(libc::EEXIST) => {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                let len = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                match len {
+                    len if len < 0 => Err(io::Error::last_os_error()),
+                    len => Ok(len as usize),
+                }
+            });
+            if let Ok(result) = result {
+                return result;
+            }
+        }
+    }
+
+    async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            let result = guard.try_io(|fd| {
+                let len = unsafe {
+                    libc::write(
+                        fd.as_raw_fd(),
+                        buf.as_ptr() as *const libc::c_void,
+                        buf.len(),
+                    )
+                };
+                match len {
+                    len if len < 0 => Err(io::Error::last_os_error()),
+                    len => Ok(len as usize),
+                }
+            });
+            if let Ok(result) = result {
+                return result;
+            }
+        }
+    }
+}
+
+/// `struct in6_rtmsg` from `linux/ipv6_route.h`. Fields of the `libc` version aren't public.
+#[repr(C)]
+struct In6RtMsg {
+    rtmsg_dst: libc::in6_addr,
+    rtmsg_src: libc::in6_addr,
+    rtmsg_gateway: libc::in6_addr,
+    rtmsg_type: u32,
+    rtmsg_dst_len: u16,
+    rtmsg_src_len: u16,
+    rtmsg_metric: u32,
+    rtmsg_info: libc::c_ulong,
+    rtmsg_flags: u32,
+    rtmsg_ifindex: libc::c_int,
+}
+
+fn ifreq(name: &str) -> io::Result<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Interface name too long: {name}"),
+        ));
+    }
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(req)
+}
+
+fn ioctl<T>(fd: RawFd, request: libc::Ioctl, arg: &mut T) -> io::Result<()> {
+    match unsafe { libc::ioctl(fd, request, arg as *mut T) } {
+        result if result < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,126 @@
+//! Presets of virtual TCP settings for typical uses of a connection.
+//!
+//! Buffer sizes, TCP timers and pacing depend on each other: large windows without
+//! pacing end up in bursts, which lossy paths drop, and delayed acknowledgements
+//! hold back small messages regardless of buffers. A [`TuningProfile`] sets all of
+//! them at once, for a single virtual connection, with
+//! [`ForwardOptions::profile`](crate::channels::ForwardOptions::profile).
+//!
+//! Pacing applies to all packets sent to a Node, so the profile chosen last for
+//! the Node is used for its other connections as well. smoltcp computes the
+//! retransmission timeout from the measured round-trip time, profiles only set how
+//! long retransmitting lasts before the connection is aborted.
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use strum_macros::{Display, EnumString};
+
+use ya_relay_stack::socket::{Memory, SocketMemory};
+use ya_relay_stack::TcpTuning;
+
+use crate::pacing::PacingConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Display, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum TuningProfile {
+    /// Small messages delivered right away: no Nagle's algorithm or delayed
+    /// acknowledgements, short queues and small bursts.
+    LowLatency,
+    /// Throughput of large transfers: large windows, coalescing small writes
+    /// and bursts paced close to the path rate.
+    Bulk,
+    /// Paths dropping packets: moderate windows, prompt acknowledgements so losses
+    /// are detected early, small bursts and patient retransmissions.
+    LossyNetwork,
+}
+
+impl TuningProfile {
+    /// Settings of the virtual TCP socket.
+    pub fn tcp(&self) -> TcpTuning {
+        match self {
+            TuningProfile::LowLatency => TcpTuning {
+                mem: memory(64 * 1024, 32 * 1024),
+                nagle: false,
+                timeout: Some(Duration::from_secs(30)),
+                keep_alive: Some(Duration::from_secs(10)),
+                ack_delay: None,
+            },
+            TuningProfile::Bulk => TcpTuning {
+                mem: memory(4 * 1024 * 1024, 1024 * 1024),
+                nagle: true,
+                timeout: Some(Duration::from_secs(120)),
+                keep_alive: Some(Duration::from_secs(30)),
+                ack_delay: Some(Duration::from_millis(40)),
+            },
+            TuningProfile::LossyNetwork => TcpTuning {
+                mem: memory(1024 * 1024, 256 * 1024),
+                nagle: false,
+                timeout: Some(Duration::from_secs(300)),
+                keep_alive: Some(Duration::from_secs(15)),
+                ack_delay: Some(Duration::from_millis(10)),
+            },
+        }
+    }
+
+    /// Pacing of packets sent to the Node, see [`crate::pacing`].
+    pub fn pacing(&self) -> PacingConfig {
+        let (burst, gain) = match self {
+            TuningProfile::LowLatency => (4 * 1024, 1.5),
+            TuningProfile::Bulk => (64 * 1024, 1.25),
+            TuningProfile::LossyNetwork => (8 * 1024, 1.1),
+        };
+        PacingConfig {
+            default_rate: None,
+            burst,
+            gain,
+        }
+    }
+}
+
+fn memory(rx: usize, tx: usize) -> SocketMemory {
+    let bounds = |max: usize| Memory::new(4 * 1024, max, max).expect("Invalid buffer bounds");
+    SocketMemory {
+        rx: bounds(rx),
+        tx: bounds(tx),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_profile_names() {
+        for profile in [
+            TuningProfile::LowLatency,
+            TuningProfile::Bulk,
+            TuningProfile::LossyNetwork,
+        ] {
+            let name = profile.to_string();
+            assert_eq!(TuningProfile::from_str(&name).unwrap(), profile);
+            assert_eq!(
+                serde_json::to_string(&profile).unwrap(),
+                format!("\"{name}\"")
+            );
+        }
+        assert_eq!(TuningProfile::Bulk.to_string(), "bulk");
+        assert_eq!(
+            TuningProfile::from_str("lossy-network").unwrap(),
+            TuningProfile::LossyNetwork
+        );
+        assert!(TuningProfile::from_str("fast").is_err());
+    }
+
+    #[test]
+    fn test_profiles_coherent() {
+        let latency = TuningProfile::LowLatency;
+        let bulk = TuningProfile::Bulk;
+        let lossy = TuningProfile::LossyNetwork;
+
+        assert!(!latency.tcp().nagle && latency.tcp().ack_delay.is_none());
+        assert!(latency.pacing().burst < bulk.pacing().burst);
+        assert!(lossy.pacing().burst < bulk.pacing().burst);
+        assert!(lossy.tcp().timeout > bulk.tcp().timeout);
+    }
+}
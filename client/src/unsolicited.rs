@@ -0,0 +1,280 @@
+//! Forwards from Nodes, which this client never contacted.
+//!
+//! Relay server passes on packets from any Node knowing our NodeId. By default the client
+//! resolves such a sender and establishes a session with it, like with any other Node.
+//! Clients, which must not accept inbound traffic from strangers, can drop these forwards
+//! or hold them until the application approves the sender.
+//!
+//! Nodes this client requested a session with are never treated as unsolicited.
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use tokio::sync::mpsc;
+
+use ya_relay_core::runtime::SystemTime;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::{Forward, SlotId};
+use ya_relay_util::Channel;
+
+use crate::direct_session::DirectSession;
+
+/// Forwards held for a single Node awaiting approval. The oldest ones are dropped above that.
+pub const MAX_HELD: usize = 64;
+/// Nodes awaiting approval at once. Forwards from other Nodes are dropped above that.
+pub const MAX_PENDING: usize = 256;
+/// Slots of pending and rejected Nodes remembered, so their forwards are handled
+/// without asking the relay server about the sender again.
+const MAX_SLOTS: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnsolicitedPolicy {
+    /// Sender is resolved and its forwards delivered.
+    #[default]
+    Accept,
+    /// Forwards are dropped without telling the sender.
+    RejectSilently,
+    /// Forwards are held and [`UnsolicitedForward`] is emitted, until the sender is
+    /// approved with [`crate::Client::approve_node`] or rejected with
+    /// [`crate::Client::reject_node`].
+    QueueForApproval,
+}
+
+/// Node awaiting approval, emitted on its first forward.
+#[derive(Clone, Debug)]
+pub struct UnsolicitedForward {
+    pub node_id: NodeId,
+    pub received_at: SystemTime,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Accept,
+    Drop,
+    Hold,
+}
+
+/// Forward waiting for approval of its sender.
+pub(crate) struct Held {
+    pub forward: Forward,
+    pub from: SocketAddr,
+    /// Session the forward arrived on.
+    pub session: Weak<DirectSession>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Nodes we contacted or the application approved.
+    approved: HashSet<NodeId>,
+    rejected: HashSet<NodeId>,
+    pending: HashMap<NodeId, VecDeque<Held>>,
+    slots: HashMap<SlotId, NodeId>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Unsolicited {
+    policy: UnsolicitedPolicy,
+    state: Arc<Mutex<State>>,
+    events: Channel<UnsolicitedForward>,
+}
+
+impl Unsolicited {
+    pub fn new(policy: UnsolicitedPolicy) -> Self {
+        Unsolicited {
+            policy,
+            state: Default::default(),
+            events: Default::default(),
+        }
+    }
+
+    pub fn policy(&self) -> UnsolicitedPolicy {
+        self.policy
+    }
+
+    pub fn events(&self) -> Option<mpsc::UnboundedReceiver<UnsolicitedForward>> {
+        self.events.receiver()
+    }
+
+    /// Called when we request a session with the Node ourselves.
+    pub fn contacted(&self, node_id: NodeId) {
+        if self.policy == UnsolicitedPolicy::Accept {
+            return;
+        }
+        let mut state = self.state.lock();
+        state.rejected.remove(&node_id);
+        state.slots.retain(|_, id| *id != node_id);
+        state.approved.insert(node_id);
+    }
+
+    /// Verdict for a forward on the `slot`, if the sender is already known to be
+    /// pending or rejected.
+    pub fn by_slot(&self, slot: SlotId) -> Option<(NodeId, Verdict)> {
+        if self.policy == UnsolicitedPolicy::Accept {
+            return None;
+        }
+        let state = self.state.lock();
+        let node_id = *state.slots.get(&slot)?;
+        match state.pending.contains_key(&node_id) {
+            true => Some((node_id, Verdict::Hold)),
+            false => Some((node_id, Verdict::Drop)),
+        }
+    }
+
+    /// Verdict for a forward from resolved sender.
+    pub fn by_node(&self, node_id: NodeId, slot: SlotId) -> Verdict {
+        if self.policy == UnsolicitedPolicy::Accept {
+            return Verdict::Accept;
+        }
+        let mut state = self.state.lock();
+        if state.approved.contains(&node_id) {
+            return Verdict::Accept;
+        }
+        let verdict = match self.policy {
+            UnsolicitedPolicy::QueueForApproval if !state.rejected.contains(&node_id) => {
+                Verdict::Hold
+            }
+            _ => Verdict::Drop,
+        };
+        if state.slots.len() >= MAX_SLOTS {
+            let State { slots, pending, .. } = &mut *state;
+            slots.retain(|_, id| pending.contains_key(id));
+        }
+        state.slots.insert(slot, node_id);
+        verdict
+    }
+
+    /// Keeps the forward until the Node is approved or rejected.
+    pub fn hold(&self, node_id: NodeId, held: Held) {
+        let mut state = self.state.lock();
+        let first = !state.pending.contains_key(&node_id);
+        if first && state.pending.len() >= MAX_PENDING {
+            log::debug!("Too many Nodes awaiting approval, dropping forward from [{node_id}]");
+            return;
+        }
+
+        let queue = state.pending.entry(node_id).or_default();
+        if queue.len() >= MAX_HELD {
+            queue.pop_front();
+        }
+        queue.push_back(held);
+
+        if first {
+            log::debug!("Forward from [{node_id}] awaits approval");
+            self.events
+                .tx
+                .send(UnsolicitedForward {
+                    node_id,
+                    received_at: SystemTime::now(),
+                })
+                .ok();
+        }
+    }
+
+    /// Allows forwards from the Node. Returns forwards held so far.
+    pub fn approve(&self, node_id: NodeId) -> Vec<Held> {
+        let mut state = self.state.lock();
+        state.rejected.remove(&node_id);
+        state.slots.retain(|_, id| *id != node_id);
+        state.approved.insert(node_id);
+        state
+            .pending
+            .remove(&node_id)
+            .map(Vec::from)
+            .unwrap_or_default()
+    }
+
+    /// Drops forwards from the Node, including the held ones, until it's approved.
+    pub fn reject(&self, node_id: NodeId) {
+        let mut state = self.state.lock();
+        state.approved.remove(&node_id);
+        state.pending.remove(&node_id);
+        state.rejected.insert(node_id);
+    }
+
+    pub fn pending(&self) -> Vec<NodeId> {
+        self.state.lock().pending.keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(n: u8) -> NodeId {
+        [n; 20].into()
+    }
+
+    fn held(payload: u8) -> Held {
+        Held {
+            forward: Forward::unreliable([0; 16], 7, vec![payload]),
+            from: "127.0.0.1:7464".parse().unwrap(),
+            session: Weak::new(),
+        }
+    }
+
+    #[test]
+    fn test_queue_for_approval() {
+        let unsolicited = Unsolicited::new(UnsolicitedPolicy::QueueForApproval);
+        let mut events = unsolicited.events().unwrap();
+
+        assert_eq!(unsolicited.by_slot(7), None);
+        assert_eq!(unsolicited.by_node(node(1), 7), Verdict::Hold);
+        unsolicited.hold(node(1), held(1));
+        assert_eq!(unsolicited.by_slot(7), Some((node(1), Verdict::Hold)));
+        unsolicited.hold(node(1), held(2));
+
+        // Single event per Node.
+        assert_eq!(events.try_recv().unwrap().node_id, node(1));
+        assert!(events.try_recv().is_err());
+
+        let held = unsolicited.approve(node(1));
+        let payloads: Vec<_> = held
+            .iter()
+            .map(|held| held.forward.payload.as_ref()[0])
+            .collect();
+        assert_eq!(payloads, [1, 2]);
+        assert_eq!(unsolicited.by_slot(7), None);
+        assert_eq!(unsolicited.by_node(node(1), 7), Verdict::Accept);
+
+        unsolicited.by_node(node(2), 8);
+        unsolicited.hold(node(2), self::held(3));
+        unsolicited.reject(node(2));
+        assert!(unsolicited.pending().is_empty());
+        assert_eq!(unsolicited.by_slot(8), Some((node(2), Verdict::Drop)));
+        assert_eq!(unsolicited.by_node(node(2), 8), Verdict::Drop);
+    }
+
+    #[test]
+    fn test_reject_silently() {
+        let unsolicited = Unsolicited::new(UnsolicitedPolicy::RejectSilently);
+        assert_eq!(unsolicited.by_node(node(1), 7), Verdict::Drop);
+        assert_eq!(unsolicited.by_slot(7), Some((node(1), Verdict::Drop)));
+
+        unsolicited.contacted(node(1));
+        assert_eq!(unsolicited.by_slot(7), None);
+        assert_eq!(unsolicited.by_node(node(1), 7), Verdict::Accept);
+
+        let accept = Unsolicited::new(UnsolicitedPolicy::Accept);
+        assert_eq!(accept.by_node(node(1), 7), Verdict::Accept);
+    }
+
+    #[test]
+    fn test_limits() {
+        let unsolicited = Unsolicited::new(UnsolicitedPolicy::QueueForApproval);
+        for i in 0..MAX_HELD + 1 {
+            unsolicited.hold(node(1), held(i as u8));
+        }
+        let held = unsolicited.approve(node(1));
+        assert_eq!(held.len(), MAX_HELD);
+        assert_eq!(held[0].forward.payload.as_ref()[0], 1);
+
+        for i in 0..MAX_PENDING + 1 {
+            let mut id = [0u8; 20];
+            id[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            unsolicited.hold(id.into(), self::held(0));
+        }
+        assert_eq!(unsolicited.pending().len(), MAX_PENDING);
+    }
+}
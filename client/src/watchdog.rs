@@ -0,0 +1,154 @@
+//! Recycling virtual TCP connections, which stopped making progress.
+//!
+//! Data written to a connection is acknowledged by the other Node over time. A connection
+//! with data waiting for acknowledgement, none of which was acknowledged within
+//! [`WatchdogConfig::stall_timeout`], is stalled, e.g. because the other Node lost its state
+//! without the session noticing. With [`crate::ClientBuilder::watchdog`] outgoing channels
+//! over stalled connections are closed, so the next send opens a new connection, and each
+//! of them is reported with [`crate::Client::stalled_connections`].
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ya_relay_core::runtime::{Instant, SystemTime};
+use ya_relay_core::server_session::TransportType;
+use ya_relay_core::NodeId;
+use ya_relay_stack::TcpProgress;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchdogConfig {
+    /// Connections without acknowledged data for that long are recycled.
+    pub stall_timeout: Duration,
+    /// Interval of checking connections.
+    pub check_interval: Duration,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            stall_timeout: Duration::from_secs(60),
+            check_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Connection closed by the watchdog.
+#[derive(Clone, Debug)]
+pub struct StalledConnection {
+    pub node_id: NodeId,
+    /// Either `Reliable` or `Transfer`.
+    pub channel: TransportType,
+    /// TCP state of the socket, when it was closed.
+    pub state: String,
+    /// Bytes written to the connection.
+    pub written: u64,
+    /// Bytes written, which were never acknowledged.
+    pub queued: usize,
+    /// Time since data was acknowledged for the last time.
+    pub stalled_for: Duration,
+    pub closed_at: SystemTime,
+}
+
+struct Progress {
+    acked: u64,
+    since: Instant,
+}
+
+/// Progress of connections seen by previous checks.
+#[derive(Default)]
+pub(crate) struct Watchdog {
+    connections: HashMap<(NodeId, TransportType), Progress>,
+}
+
+impl Watchdog {
+    /// Takes progress of all connections being watched and returns the stalled ones.
+    /// Connections missing from `current` are forgotten.
+    pub fn check(
+        &mut self,
+        current: Vec<(NodeId, TransportType, TcpProgress)>,
+        stall_timeout: Duration,
+        now: Instant,
+    ) -> Vec<StalledConnection> {
+        let mut connections = HashMap::with_capacity(current.len());
+        let mut stalled = Vec::new();
+
+        for (node_id, channel, progress) in current {
+            let key = (node_id, channel);
+            let acked = progress.acked();
+            let since = match self.connections.remove(&key) {
+                // Idle connections aren't expected to make progress.
+                _ if progress.queued == 0 => now,
+                // Socket of a new connection starts counting from zero.
+                Some(previous) if acked != previous.acked => now,
+                Some(previous) => previous.since,
+                None => now,
+            };
+
+            let stalled_for = now.saturating_duration_since(since);
+            if stalled_for >= stall_timeout {
+                stalled.push(StalledConnection {
+                    node_id,
+                    channel,
+                    state: format!("{:?}", progress.state),
+                    written: progress.written,
+                    queued: progress.queued,
+                    stalled_for,
+                    closed_at: SystemTime::now(),
+                });
+            } else {
+                connections.insert(key, Progress { acked, since });
+            }
+        }
+
+        self.connections = connections;
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_stack::smoltcp::socket::tcp::State;
+
+    fn progress(written: u64, queued: usize) -> TcpProgress {
+        TcpProgress {
+            state: State::Established,
+            written,
+            queued,
+        }
+    }
+
+    #[test]
+    fn test_stalled() {
+        let node = NodeId::from([1; 20]);
+        let timeout = Duration::from_secs(10);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut watchdog = Watchdog::default();
+
+        let check = |watchdog: &mut Watchdog, written, queued, now| {
+            watchdog.check(
+                vec![(node, TransportType::Transfer, progress(written, queued))],
+                timeout,
+                now,
+            )
+        };
+
+        // Queue full all the time, but acknowledgements keep coming.
+        assert!(check(&mut watchdog, 1000, 500, at(0)).is_empty());
+        assert!(check(&mut watchdog, 2000, 500, at(8)).is_empty());
+        assert!(check(&mut watchdog, 2000, 500, at(16)).is_empty());
+        let stalled = check(&mut watchdog, 2000, 500, at(18));
+        assert_eq!(stalled.len(), 1);
+        assert_eq!(stalled[0].stalled_for, Duration::from_secs(10));
+        assert_eq!(stalled[0].queued, 500);
+
+        // Nothing to acknowledge.
+        assert!(check(&mut watchdog, 2000, 0, at(20)).is_empty());
+        assert!(check(&mut watchdog, 2000, 0, at(40)).is_empty());
+
+        // Forgotten after the connection was closed.
+        assert!(watchdog.check(vec![], timeout, at(41)).is_empty());
+        assert!(check(&mut watchdog, 3000, 100, at(60)).is_empty());
+    }
+}
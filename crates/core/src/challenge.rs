@@ -1,4 +1,5 @@
-use crate::crypto::Crypto;
+use crate::crypto::ed25519::Ed25519Crypto;
+use crate::crypto::{ed25519, Crypto, SignatureScheme};
 use anyhow::bail;
 use std::convert::TryFrom;
 
@@ -6,17 +7,29 @@ use digest::{Digest, Output};
 use ethsign::PublicKey;
 use futures::{Future, StreamExt, TryStreamExt};
 use rand::Rng;
+use tokio_util::sync::DropGuard;
 
 use crate::identity::Identity;
 use ya_client_model::NodeId;
 use ya_relay_proto::proto;
 
+mod solver;
+
+pub use solver::{
+    estimate_duration, expected_attempts, CancellationToken, ProgressFn, SolveProgress,
+    SolverOptions,
+};
+
 pub const SIGNATURE_SIZE: usize = std::mem::size_of::<ethsign::Signature>();
 pub const PREFIX_SIZE: usize = std::mem::size_of::<u64>();
 
 pub const CHALLENGE_SIZE: usize = 16;
 pub const CHALLENGE_DIFFICULTY: u64 = 16;
 
+/// `ChallengeRequest::caps` bit set by peers accepting Ed25519 signatures.
+pub const CAP_ED25519: u64 = 0x01;
+pub const SUPPORTED_CAPS: u64 = CAP_ED25519;
+
 pub type RawChallenge = [u8; CHALLENGE_SIZE];
 
 pub type ChallengeDigest = sha3::Sha3_512;
@@ -26,14 +39,36 @@ pub fn solve<'a, D: Digest, C: Crypto + 'a>(
     difficulty: u64,
     crypto_vec: Vec<C>,
 ) -> impl Future<Output = anyhow::Result<proto::ChallengeResponse>> + 'a {
-    // Compute challenge in different thread to avoid blocking the runtime.
-    // Note: computing starts here, not after awaiting.
-    let challenge_handle =
-        tokio::task::spawn_blocking(move || solve_challenge::<D>(challenge.as_slice(), difficulty));
+    solve_with::<D, C>(challenge, difficulty, crypto_vec, SolverOptions::default())
+}
+
+/// Same as [`solve`], with number of worker threads, progress reporting and cancellation
+/// configured by `options`. Dropping the returned future stops the computation.
+/// Identities sign in their own [`SignatureScheme`], check [`supports_scheme`] against
+/// the request capabilities before using non-secp256k1 ones.
+pub fn solve_with<'a, D: Digest, C: Crypto + 'a>(
+    challenge: Vec<u8>,
+    difficulty: u64,
+    crypto_vec: Vec<C>,
+    options: SolverOptions,
+) -> impl Future<Output = anyhow::Result<proto::ChallengeResponse>> + 'a {
+    let (challenge_handle, guard) = spawn_solver::<D>(challenge, difficulty, options);
 
     async move {
+        let _guard = guard;
         let solution = challenge_handle.await??;
         let message = sha2::Sha256::digest(solution.as_slice());
+        // Peers without Ed25519 support expect secp256k1 signatures without schemes.
+        let schemes = match crypto_vec
+            .iter()
+            .all(|crypto| crypto.scheme() == SignatureScheme::Secp256k1)
+        {
+            true => vec![],
+            false => crypto_vec
+                .iter()
+                .map(|crypto| proto::SignatureScheme::from(crypto.scheme()) as i32)
+                .collect(),
+        };
         let signatures: anyhow::Result<Vec<_>> = futures::stream::iter(crypto_vec)
             .then(|crypto| sign(message.as_slice(), crypto))
             .try_collect()
@@ -42,29 +77,76 @@ pub fn solve<'a, D: Digest, C: Crypto + 'a>(
         Ok(proto::ChallengeResponse {
             solution,
             signatures: signatures?,
+            schemes,
         })
     }
 }
 
-fn solve_challenge<D: Digest>(challenge: &[u8], difficulty: u64) -> anyhow::Result<Vec<u8>> {
-    let mut counter: u64 = 0;
-    loop {
-        let prefix = counter.to_be_bytes();
-        let result = digest::<D>(&prefix, challenge);
-
-        if leading_zeros(&result) >= difficulty {
-            let mut response = prefix.to_vec();
-            response.reserve(result.len());
-            response.extend(result.into_iter());
-            return Ok(response);
-        }
+/// Solves the challenge and signs it with Ed25519 identities, default identity first.
+/// Check [`supports_scheme`] against the request capabilities before using it.
+pub fn solve_ed25519<'a, D: Digest>(
+    challenge: Vec<u8>,
+    difficulty: u64,
+    crypto_vec: Vec<Ed25519Crypto>,
+    options: SolverOptions,
+) -> impl Future<Output = anyhow::Result<proto::ChallengeResponse>> + 'a {
+    solve_with::<D, _>(challenge, difficulty, crypto_vec, options)
+}
 
-        counter = counter.checked_add(1).ok_or_else(|| {
-            anyhow::anyhow!("Could not find a hash for difficulty {}", difficulty)
-        })?;
+pub fn supports_scheme(caps: u64, scheme: SignatureScheme) -> bool {
+    match scheme {
+        SignatureScheme::Secp256k1 => true,
+        SignatureScheme::Ed25519 => caps & CAP_ED25519 != 0,
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+type SolverHandle = tokio::task::JoinHandle<anyhow::Result<Vec<u8>>>;
+#[cfg(target_arch = "wasm32")]
+type SolverHandle =
+    futures::future::LocalBoxFuture<'static, anyhow::Result<anyhow::Result<Vec<u8>>>>;
+
+/// Computes challenge in different thread(s) to avoid blocking the runtime.
+/// Note: computing starts here, not after awaiting.
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_solver<D: Digest>(
+    challenge: Vec<u8>,
+    difficulty: u64,
+    mut options: SolverOptions,
+) -> (SolverHandle, DropGuard) {
+    let cancel = options.cancel.child_token();
+    options.cancel = cancel.clone();
+
+    let handle = tokio::task::spawn_blocking(move || {
+        solver::solve_parallel::<D>(challenge.as_slice(), difficulty, &options)
+    });
+    (handle, cancel.drop_guard())
+}
+
+/// Browsers have no threads, so the challenge is computed on the event loop,
+/// see [`solver::solve_local`].
+#[cfg(target_arch = "wasm32")]
+fn spawn_solver<D: Digest>(
+    challenge: Vec<u8>,
+    difficulty: u64,
+    mut options: SolverOptions,
+) -> (SolverHandle, DropGuard) {
+    use futures::FutureExt;
+
+    let cancel = options.cancel.child_token();
+    options.cancel = cancel.clone();
+
+    let handle = solver::solve_local(challenge, difficulty, options, digest_vec::<D>)
+        .map(Ok)
+        .boxed_local();
+    (handle, cancel.drop_guard())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn digest_vec<D: Digest>(nonce: &[u8], input: &[u8]) -> Vec<u8> {
+    digest::<D>(nonce, input).to_vec()
+}
+
 pub fn verify<D: Digest>(
     challenge: &[u8],
     difficulty: u64,
@@ -125,14 +207,26 @@ pub fn recover_identities_from_challenge<D: Digest>(
     }
 
     let message = sha2::Sha256::digest(&response.solution);
+    let recover_identity = |idx: usize, sig: &Vec<u8>| -> anyhow::Result<Identity> {
+        let scheme = match response.schemes.get(idx) {
+            Some(&scheme) => proto::SignatureScheme::try_from(scheme)
+                .map_err(|_| anyhow::anyhow!("Unknown signature scheme: {scheme}"))?
+                .into(),
+            None => SignatureScheme::Secp256k1,
+        };
+        Ok(match scheme {
+            SignatureScheme::Secp256k1 => recover(sig.as_slice(), &message)?.into(),
+            SignatureScheme::Ed25519 => ed25519::recover(sig.as_slice(), &message)?.into(),
+        })
+    };
+
     let default_ident = {
         let sig = response
             .signatures
             .get(0)
             .ok_or_else(|| anyhow::anyhow!("Missing signature"))?;
 
-        let key = recover(sig.as_slice(), message.as_slice())?;
-        Identity::from(key)
+        recover_identity(0, sig)?
     };
 
     let default_id = default_ident.node_id;
@@ -143,10 +237,14 @@ pub fn recover_identities_from_challenge<D: Digest>(
     }
 
     let identities = std::iter::once(Ok(default_ident))
-        .chain(response.signatures.iter().skip(1).map(|sig| {
-            let key = recover(sig.as_slice(), message.as_slice())?;
-            Ok(Identity::from(key))
-        }))
+        .chain(
+            response
+                .signatures
+                .iter()
+                .enumerate()
+                .skip(1)
+                .map(|(idx, sig)| recover_identity(idx, sig)),
+        )
         .collect::<anyhow::Result<_>>()?;
 
     Ok((default_id, identities))
@@ -160,17 +258,18 @@ pub fn recover_default_node_id(request: &proto::request::Session) -> anyhow::Res
 }
 
 async fn sign(message: &[u8], crypto: impl Crypto) -> anyhow::Result<Vec<u8>> {
-    let sig = crypto.sign(message).await?;
+    crypto.sign_encoded(message).await
+}
 
+pub(crate) fn encode_signature(sig: &ethsign::Signature) -> Vec<u8> {
     let mut result = Vec::with_capacity(SIGNATURE_SIZE);
     result.push(sig.v);
     result.extend_from_slice(&sig.r[..]);
     result.extend_from_slice(&sig.s[..]);
-
-    Ok(result)
+    result
 }
 
-fn recover(sig: &[u8], message: &[u8]) -> anyhow::Result<PublicKey> {
+pub(crate) fn recover(sig: &[u8], message: &[u8]) -> anyhow::Result<PublicKey> {
     let len = sig.len();
     if len != SIGNATURE_SIZE {
         anyhow::bail!(
@@ -214,7 +313,7 @@ pub fn prepare_challenge(difficulty: u64) -> (proto::ChallengeRequest, RawChalle
     let raw_challenge = rand::thread_rng().gen::<RawChallenge>();
     let request = proto::ChallengeRequest {
         version: "0.0.1".to_string(),
-        caps: 0,
+        caps: SUPPORTED_CAPS,
         kind: proto::challenge_request::Kind::Sha3512LeadingZeros as i32,
         difficulty,
         challenge: raw_challenge.to_vec(),
@@ -243,6 +342,9 @@ pub fn prepare_challenge_response(difficulty: u64) -> (proto::response::Session,
 #[cfg(test)]
 mod tests {
     use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     use anyhow;
     use ethsign::PublicKey;
@@ -250,8 +352,10 @@ mod tests {
     use rand::Rng;
 
     use crate::challenge::ChallengeDigest;
-    use crate::crypto::{Crypto, CryptoProvider, FallbackCryptoProvider};
+    use crate::crypto::ed25519::{self, Ed25519Crypto};
+    use crate::crypto::{Crypto, CryptoProvider, FallbackCryptoProvider, SignatureScheme};
     use ya_client_model::NodeId;
+    use ya_relay_proto::proto;
 
     async fn gen_crypto(n: usize) -> anyhow::Result<(Vec<PublicKey>, Vec<Rc<dyn Crypto>>)> {
         let pairs: Vec<_> = futures::stream::iter((0..n).map(anyhow::Ok))
@@ -300,4 +404,145 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn sign_verify_recover_ed25519() -> anyhow::Result<()> {
+        const DIFFICULTY: u64 = 2;
+
+        let crypto_vec: Vec<Ed25519Crypto> = (0..3).map(|_| ed25519::generate().into()).collect();
+        let challenge: Vec<u8> = (0..16).collect();
+
+        let response = super::solve_ed25519::<ChallengeDigest>(
+            challenge.clone(),
+            DIFFICULTY,
+            crypto_vec.clone(),
+            Default::default(),
+        )
+        .await?;
+
+        let (node_id, identities) = super::recover_identities_from_challenge::<ChallengeDigest>(
+            challenge.as_slice(),
+            DIFFICULTY,
+            Some(response.clone()),
+            None,
+        )?;
+
+        assert_eq!(node_id, crypto_vec[0].node_id());
+        for (identity, crypto) in identities.iter().zip(crypto_vec.iter()) {
+            assert_eq!(identity.node_id, crypto.node_id());
+            assert_eq!(identity.public_key.scheme(), SignatureScheme::Ed25519);
+        }
+
+        // Without schemes the signatures are treated as secp256k1 and rejected.
+        let legacy = proto::ChallengeResponse {
+            schemes: vec![],
+            ..response
+        };
+        assert!(super::recover_identities_from_challenge::<ChallengeDigest>(
+            challenge.as_slice(),
+            DIFFICULTY,
+            Some(legacy),
+            None,
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sign_verify_recover_mixed() -> anyhow::Result<()> {
+        const DIFFICULTY: u64 = 2;
+
+        let (_, mut crypto_vec) = gen_crypto(1).await?;
+        let alias = Ed25519Crypto::from(ed25519::generate());
+        crypto_vec.push(Rc::new(alias.clone()));
+        let challenge: Vec<u8> = (0..16).collect();
+
+        let response =
+            super::solve::<ChallengeDigest, _>(challenge.clone(), DIFFICULTY, crypto_vec.clone())
+                .await?;
+
+        let (_, identities) = super::recover_identities_from_challenge::<ChallengeDigest>(
+            challenge.as_slice(),
+            DIFFICULTY,
+            Some(response),
+            None,
+        )?;
+
+        assert_eq!(identities.len(), 2);
+        assert_eq!(
+            identities[0].public_key.scheme(),
+            SignatureScheme::Secp256k1
+        );
+        assert_eq!(identities[1].node_id, alias.node_id());
+        assert_eq!(identities[1].public_key.scheme(), SignatureScheme::Ed25519);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn solve_parallel_verify() -> anyhow::Result<()> {
+        const DIFFICULTY: u64 = 8;
+
+        let (_, crypto_vec) = gen_crypto(1).await?;
+        let challenge: Vec<u8> = (0..16).collect();
+        let options = super::SolverOptions::default().workers(4);
+
+        let response = super::solve_with::<ChallengeDigest, _>(
+            challenge.clone(),
+            DIFFICULTY,
+            crypto_vec,
+            options,
+        )
+        .await?;
+
+        assert!(super::verify::<ChallengeDigest>(
+            &challenge,
+            DIFFICULTY,
+            &response.solution
+        )?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn solve_progress_cancel() -> anyhow::Result<()> {
+        // Practically unsolvable.
+        const DIFFICULTY: u64 = 64;
+
+        let (_, crypto_vec) = gen_crypto(1).await?;
+        let challenge: Vec<u8> = (0..16).collect();
+
+        let cancel = super::CancellationToken::new();
+        let reports = Arc::new(AtomicUsize::new(0));
+        let mut options = super::SolverOptions::default().workers(2).on_progress({
+            let cancel = cancel.clone();
+            let reports = reports.clone();
+            move |progress| {
+                assert_eq!(progress.difficulty, DIFFICULTY);
+                assert!(progress.eta().is_some());
+                reports.fetch_add(1, Ordering::SeqCst);
+                cancel.cancel();
+            }
+        });
+        options.progress_interval = Duration::from_millis(200);
+        options = options.cancel_token(cancel);
+
+        let result =
+            super::solve_with::<ChallengeDigest, _>(challenge, DIFFICULTY, crypto_vec, options)
+                .await;
+
+        assert!(result.is_err());
+        assert_eq!(reports.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn estimate_duration() {
+        assert_eq!(super::expected_attempts(10), 1024.0);
+        assert_eq!(
+            super::estimate_duration(10, 512.0),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(super::estimate_duration(10, 0.0), None);
+    }
 }
@@ -0,0 +1,248 @@
+//! Multi-threaded proof-of-work solver.
+//!
+//! Workers search disjoint counter sequences (`worker`, `worker + workers`, ...),
+//! so any of them can produce the solution and no work is duplicated. Browsers have
+//! no threads, there a single worker runs on the event loop, see `solve_local`.
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+pub use tokio_util::sync::CancellationToken;
+
+use super::{digest, leading_zeros};
+use crate::runtime::Instant;
+
+/// Number of hashes computed by a worker between checks of shared state.
+const BATCH_SIZE: u64 = 1024;
+
+pub type ProgressFn = Arc<dyn Fn(&SolveProgress) + Send + Sync>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SolveProgress {
+    pub difficulty: u64,
+    pub attempts: u64,
+    pub elapsed: Duration,
+}
+
+impl SolveProgress {
+    pub fn expected_attempts(&self) -> f64 {
+        expected_attempts(self.difficulty)
+    }
+
+    /// Hashes per second observed so far.
+    pub fn hash_rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.attempts as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimated time left, based on the observed hash rate.
+    /// The search is random, so the real time can be both shorter and longer.
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = (self.expected_attempts() - self.attempts as f64).max(0.0);
+        estimate_duration_at(remaining, self.hash_rate())
+    }
+}
+
+#[derive(Clone)]
+pub struct SolverOptions {
+    /// Number of threads used for solving a single challenge.
+    pub workers: usize,
+    pub progress: Option<ProgressFn>,
+    pub progress_interval: Duration,
+    /// Aborts solving when cancelled.
+    pub cancel: CancellationToken,
+}
+
+impl SolverOptions {
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    pub fn on_progress(
+        mut self,
+        progress: impl Fn(&SolveProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    pub fn cancel_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+}
+
+impl Default for SolverOptions {
+    fn default() -> Self {
+        Self {
+            workers: 1,
+            progress: None,
+            progress_interval: Duration::from_millis(250),
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+/// Average number of hashes needed to find a solution with `difficulty` leading zero bits.
+pub fn expected_attempts(difficulty: u64) -> f64 {
+    2f64.powf(difficulty as f64)
+}
+
+/// Expected solving time for hardware computing `hash_rate` hashes per second.
+pub fn estimate_duration(difficulty: u64, hash_rate: f64) -> Option<Duration> {
+    estimate_duration_at(expected_attempts(difficulty), hash_rate)
+}
+
+fn estimate_duration_at(attempts: f64, hash_rate: f64) -> Option<Duration> {
+    if hash_rate > 0.0 {
+        Some(Duration::from_secs_f64(attempts / hash_rate))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(super) fn solve_parallel<D: Digest>(
+    challenge: &[u8],
+    difficulty: u64,
+    options: &SolverOptions,
+) -> anyhow::Result<Vec<u8>> {
+    let workers = options.workers.max(1) as u64;
+    let started = Instant::now();
+    let attempts = AtomicU64::new(0);
+    let done = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+
+    let result = std::thread::scope(|scope| {
+        for worker in 0..workers {
+            let tx = tx.clone();
+            let attempts = &attempts;
+            let done = &done;
+            let cancel = &options.cancel;
+
+            scope.spawn(move || {
+                let mut counter = worker;
+                let mut batch = 0;
+                loop {
+                    let prefix = counter.to_be_bytes();
+                    let result = digest::<D>(&prefix, challenge);
+
+                    if leading_zeros(&result) >= difficulty {
+                        let mut response = prefix.to_vec();
+                        response.extend_from_slice(&result);
+                        tx.send(Ok(response)).ok();
+                        break;
+                    }
+
+                    batch += 1;
+                    if batch == BATCH_SIZE {
+                        attempts.fetch_add(batch, Ordering::Relaxed);
+                        batch = 0;
+                        if done.load(Ordering::Relaxed) || cancel.is_cancelled() {
+                            break;
+                        }
+                    }
+
+                    counter = match counter.checked_add(workers) {
+                        Some(counter) => counter,
+                        None => {
+                            tx.send(Err(anyhow::anyhow!(
+                                "Could not find a hash for difficulty {}",
+                                difficulty
+                            )))
+                            .ok();
+                            break;
+                        }
+                    };
+                }
+            });
+        }
+        drop(tx);
+
+        let result = loop {
+            match rx.recv_timeout(options.progress_interval) {
+                Ok(result) => break result,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if options.cancel.is_cancelled() {
+                        break Err(anyhow::anyhow!("Challenge solving cancelled"));
+                    }
+                    if let Some(progress) = &options.progress {
+                        progress(&SolveProgress {
+                            difficulty,
+                            attempts: attempts.load(Ordering::Relaxed),
+                            elapsed: started.elapsed(),
+                        });
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    break Err(anyhow::anyhow!("Challenge solving cancelled"))
+                }
+            }
+        };
+        done.store(true, Ordering::Relaxed);
+        result
+    });
+
+    log::trace!(
+        "Challenge (difficulty {difficulty}) solved in {:?}",
+        started.elapsed()
+    );
+    result
+}
+
+/// Single worker giving control back to the event loop after every batch of hashes,
+/// so the page stays responsive. `hash` digests the prefix and the challenge.
+#[cfg(target_arch = "wasm32")]
+pub(super) async fn solve_local(
+    challenge: Vec<u8>,
+    difficulty: u64,
+    options: SolverOptions,
+    hash: fn(&[u8], &[u8]) -> Vec<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let started = Instant::now();
+    let mut reported = started;
+    let mut counter: u64 = 0;
+
+    loop {
+        for _ in 0..BATCH_SIZE {
+            let prefix = counter.to_be_bytes();
+            let result = hash(&prefix, &challenge);
+
+            if leading_zeros(&result) >= difficulty {
+                let mut response = prefix.to_vec();
+                response.extend_from_slice(&result);
+                log::trace!(
+                    "Challenge (difficulty {difficulty}) solved in {:?}",
+                    started.elapsed()
+                );
+                return Ok(response);
+            }
+
+            counter = counter.checked_add(1).ok_or_else(|| {
+                anyhow::anyhow!("Could not find a hash for difficulty {}", difficulty)
+            })?;
+        }
+
+        if options.cancel.is_cancelled() {
+            anyhow::bail!("Challenge solving cancelled");
+        }
+        if reported.elapsed() >= options.progress_interval {
+            reported = Instant::now();
+            if let Some(progress) = &options.progress {
+                progress(&SolveProgress {
+                    difficulty,
+                    attempts: counter,
+                    elapsed: started.elapsed(),
+                });
+            }
+        }
+        crate::runtime::sleep(Duration::ZERO).await;
+    }
+}
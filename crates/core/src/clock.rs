@@ -0,0 +1,224 @@
+//! Time source for session timers.
+//!
+//! Expiration, keep-alive and handshake timeouts read the time and sleep through
+//! [`Clock`], so tests can replace real time with [`MockClock`] and move it forward
+//! explicitly instead of waiting. Request retransmissions and response timeouts
+//! pace real network traffic, so they stay on [`crate::runtime`] timers.
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{BoxFuture, Either};
+use futures::FutureExt;
+
+use crate::runtime::Instant;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+pub type ClockRef = Arc<dyn Clock>;
+
+/// Real time, backed by [`crate::runtime`] timers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let sleep = crate::runtime::sleep_until(deadline);
+        // Browser timers aren't `Send`, so the sleep is completed from a local task.
+        #[cfg(target_arch = "wasm32")]
+        let sleep = {
+            let (tx, rx) = futures::channel::oneshot::channel();
+            crate::runtime::spawn_local(async move {
+                crate::runtime::sleep_until(deadline).await;
+                tx.send(()).ok();
+            });
+            rx.map(|_| ())
+        };
+        sleep.boxed()
+    }
+}
+
+pub fn system_clock() -> ClockRef {
+    Arc::new(SystemClock)
+}
+
+/// Error returned by [`timeout`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Equivalent of `tokio::time::timeout` measured by `clock`.
+pub fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> impl Future<Output = Result<F::Output, Elapsed>> {
+    let sleep = clock.sleep(duration);
+    async move {
+        futures::pin_mut!(future);
+        match futures::future::select(future, sleep).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Elapsed),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+pub use mock::MockClock;
+
+#[cfg(any(test, feature = "test-utils"))]
+mod mock {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use futures::channel::oneshot;
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+
+    use super::Clock;
+    use crate::runtime::Instant;
+
+    /// Clock standing still until [`MockClock::advance`] is called.
+    /// Sleeps complete as soon as the clock passes their deadline.
+    #[derive(Clone)]
+    pub struct MockClock {
+        state: Arc<Mutex<State>>,
+    }
+
+    struct State {
+        now: Instant,
+        sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+    }
+
+    impl MockClock {
+        pub fn new() -> Self {
+            Self::starting_at(Instant::now())
+        }
+
+        pub fn starting_at(now: Instant) -> Self {
+            MockClock {
+                state: Arc::new(Mutex::new(State {
+                    now,
+                    sleepers: vec![],
+                })),
+            }
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            let mut state = self.state.lock().unwrap();
+            state.now += duration;
+
+            let now = state.now;
+            let (ready, pending) = std::mem::take(&mut state.sleepers)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+            state.sleepers = pending;
+            drop(state);
+
+            for (_, tx) in ready {
+                tx.send(()).ok();
+            }
+        }
+
+        /// Number of sleeps waiting for the clock to advance.
+        pub fn sleepers(&self) -> usize {
+            let mut state = self.state.lock().unwrap();
+            state.sleepers.retain(|(_, tx)| !tx.is_canceled());
+            state.sleepers.len()
+        }
+
+        /// Waits until code under test registers at least `count` sleeps,
+        /// so advancing the clock doesn't race with it.
+        pub async fn wait_for_sleepers(&self, count: usize) {
+            while self.sleepers() < count {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.state.lock().unwrap().now
+        }
+
+        fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+            let mut state = self.state.lock().unwrap();
+            if deadline <= state.now {
+                return futures::future::ready(()).boxed();
+            }
+
+            let (tx, rx) = oneshot::channel();
+            state.sleepers.push((deadline, tx));
+            rx.map(|_| ()).boxed()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mock_clock_sleep() {
+        let clock = MockClock::new();
+        let start = clock.now();
+
+        let sleep = tokio::spawn(clock.sleep(Duration::from_secs(10)));
+        clock.wait_for_sleepers(1).await;
+
+        clock.advance(Duration::from_secs(9));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+        assert_eq!(clock.sleepers(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        sleep.await.unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+        assert_eq!(clock.sleepers(), 0);
+    }
+
+    #[tokio::test]
+    async fn mock_clock_timeout() {
+        let clock = MockClock::new();
+
+        let pending = timeout(
+            &clock,
+            Duration::from_secs(5),
+            futures::future::pending::<()>(),
+        );
+        let pending = tokio::spawn(pending);
+        clock.wait_for_sleepers(1).await;
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(pending.await.unwrap(), Err(Elapsed));
+
+        let ready = timeout(&clock, Duration::from_secs(5), async { 7 }).await;
+        assert_eq!(ready, Ok(7));
+    }
+}
@@ -4,11 +4,46 @@ use std::rc::Rc;
 pub use ethsign::{PublicKey, SecretKey, Signature};
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
+use sha2::Digest;
 
 use ya_client_model::NodeId;
+use ya_relay_proto::proto;
 
+use crate::challenge;
+use crate::identity::{Identity, IdentityKey};
 use crate::key::generate;
 
+pub mod ed25519;
+
+/// Prefix of the digest signed by [`sign_data`]. Keeps application signatures
+/// from being usable as handshake signatures, which sign a bare SHA-256 digest.
+const SIGNED_DATA_DOMAIN: &[u8] = b"ya-relay:signed-data:";
+
+/// Signature algorithm backing an identity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SignatureScheme {
+    Secp256k1,
+    Ed25519,
+}
+
+impl From<SignatureScheme> for proto::SignatureScheme {
+    fn from(scheme: SignatureScheme) -> Self {
+        match scheme {
+            SignatureScheme::Secp256k1 => proto::SignatureScheme::Secp256k1,
+            SignatureScheme::Ed25519 => proto::SignatureScheme::Ed25519,
+        }
+    }
+}
+
+impl From<proto::SignatureScheme> for SignatureScheme {
+    fn from(scheme: proto::SignatureScheme) -> Self {
+        match scheme {
+            proto::SignatureScheme::Secp256k1 => SignatureScheme::Secp256k1,
+            proto::SignatureScheme::Ed25519 => SignatureScheme::Ed25519,
+        }
+    }
+}
+
 pub trait CryptoProvider {
     fn default_id<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<NodeId>>;
     fn aliases<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<Vec<NodeId>>>;
@@ -23,6 +58,23 @@ pub trait Crypto {
         message: &'a [u8],
         remote_key: &'a PublicKey,
     ) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>>;
+
+    fn scheme(&self) -> SignatureScheme {
+        SignatureScheme::Secp256k1
+    }
+
+    /// Public key sent to peers in the session handshake.
+    fn identity_key<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<IdentityKey>> {
+        let public_key = self.public_key();
+        async move { Ok(public_key.await?.into()) }.boxed_local()
+    }
+
+    /// Signature of `message` in the format of [`Crypto::scheme`], as sent
+    /// in the session handshake and returned by [`sign_data`].
+    fn sign_encoded<'a>(&self, message: &'a [u8]) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>> {
+        let sig = self.sign(message);
+        async move { Ok(challenge::encode_signature(&sig.await?)) }.boxed_local()
+    }
 }
 
 impl<C: CryptoProvider + ?Sized> CryptoProvider for Rc<C> {
@@ -55,6 +107,18 @@ impl<C: Crypto + ?Sized> Crypto for Rc<C> {
     ) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>> {
         (**self).encrypt(message, remote_key)
     }
+
+    fn scheme(&self) -> SignatureScheme {
+        (**self).scheme()
+    }
+
+    fn identity_key<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<IdentityKey>> {
+        (**self).identity_key()
+    }
+
+    fn sign_encoded<'a>(&self, message: &'a [u8]) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>> {
+        (**self).sign_encoded(message)
+    }
 }
 
 #[derive(Clone)]
@@ -146,3 +210,27 @@ impl Crypto for FallbackCrypto {
         unimplemented!()
     }
 }
+
+pub fn signed_data_digest(data: &[u8]) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(SIGNED_DATA_DOMAIN);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Signs application `data` with `crypto`.
+/// Signature is encoded the same way as in the session handshake, see [`Crypto::sign_encoded`].
+pub async fn sign_data(crypto: &dyn Crypto, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let digest = signed_data_digest(data);
+    crypto.sign_encoded(&digest).await
+}
+
+/// Returns identity which produced `signature` over `data`.
+/// Accepts signatures from [`sign_data`] and Ed25519 signatures prefixed with the public key.
+pub fn recover_data_signer(data: &[u8], signature: &[u8]) -> anyhow::Result<Identity> {
+    let digest = signed_data_digest(data);
+    if signature.len() == ed25519::ENCODED_SIGNATURE_SIZE {
+        return Ok(ed25519::recover(signature, &digest)?.into());
+    }
+    Ok(challenge::recover(signature, &digest)?.into())
+}
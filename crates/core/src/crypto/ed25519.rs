@@ -0,0 +1,169 @@
+//! Ed25519 identities.
+//!
+//! Ed25519 public keys can't be recovered from a signature, so signatures
+//! exchanged during the session handshake are prefixed with the signer's public key
+//! (see [`encode_signature`] and [`recover`]).
+//!
+//! NodeId of an Ed25519 identity is derived the same way as an Ethereum address:
+//! the last 20 bytes of the Keccak-256 digest. The digest input is prefixed with
+//! [`NODE_ID_DOMAIN`], so Ed25519 and secp256k1 keys never hash the same input.
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use anyhow::anyhow;
+pub use ed25519_dalek::{Signature, SigningKey as SecretKey, VerifyingKey as PublicKey};
+use ed25519_dalek::{Signer, SECRET_KEY_LENGTH};
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use rand::Rng;
+use sha3::{Digest, Keccak256};
+
+use ya_client_model::NodeId;
+
+use crate::crypto::{self, Crypto, CryptoProvider, SignatureScheme};
+use crate::identity::IdentityKey;
+
+pub const PUBLIC_KEY_SIZE: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
+pub const SECRET_KEY_SIZE: usize = SECRET_KEY_LENGTH;
+pub const SIGNATURE_SIZE: usize = ed25519_dalek::SIGNATURE_LENGTH;
+/// Size of a signature prefixed with the public key.
+pub const ENCODED_SIGNATURE_SIZE: usize = PUBLIC_KEY_SIZE + SIGNATURE_SIZE;
+
+pub const NODE_ID_DOMAIN: &[u8] = b"ya-relay:ed25519";
+
+pub fn generate() -> SecretKey {
+    let bytes = rand::thread_rng().gen::<[u8; SECRET_KEY_LENGTH]>();
+    SecretKey::from_bytes(&bytes)
+}
+
+pub fn node_id(public_key: &PublicKey) -> NodeId {
+    let mut hasher = Keccak256::new();
+    hasher.update(NODE_ID_DOMAIN);
+    hasher.update(public_key.as_bytes());
+    let digest = hasher.finalize();
+    NodeId::from(&digest[12..])
+}
+
+/// Signs `message` and returns the signature prefixed with the public key.
+pub fn encode_signature(secret: &SecretKey, message: &[u8]) -> Vec<u8> {
+    let signature = secret.sign(message);
+
+    let mut result = Vec::with_capacity(ENCODED_SIGNATURE_SIZE);
+    result.extend_from_slice(secret.verifying_key().as_bytes());
+    result.extend_from_slice(&signature.to_bytes());
+    result
+}
+
+/// Verifies a signature produced by [`encode_signature`] and returns the signer's public key.
+pub fn recover(sig: &[u8], message: &[u8]) -> anyhow::Result<PublicKey> {
+    let len = sig.len();
+    if len != ENCODED_SIGNATURE_SIZE {
+        anyhow::bail!(
+            "Invalid ed25519 signature size: {} vs {} B (response)",
+            ENCODED_SIGNATURE_SIZE,
+            len,
+        );
+    }
+
+    let public_key = public_key_from_slice(&sig[..PUBLIC_KEY_SIZE])?;
+    let signature = Signature::from_slice(&sig[PUBLIC_KEY_SIZE..])?;
+    public_key.verify_strict(message, &signature)?;
+
+    Ok(public_key)
+}
+
+pub fn public_key_from_slice(slice: &[u8]) -> anyhow::Result<PublicKey> {
+    let bytes = <[u8; PUBLIC_KEY_SIZE]>::try_from(slice)
+        .map_err(|_| anyhow!("Invalid ed25519 public key size: {} B", slice.len()))?;
+    Ok(PublicKey::from_bytes(&bytes)?)
+}
+
+#[derive(Clone)]
+pub struct Ed25519Crypto {
+    id: NodeId,
+    secret: SecretKey,
+}
+
+impl Ed25519Crypto {
+    pub fn node_id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.secret.verifying_key()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.secret.sign(message)
+    }
+
+    /// Signature in the format expected by the session handshake.
+    pub fn encoded_signature(&self, message: &[u8]) -> Vec<u8> {
+        encode_signature(&self.secret, message)
+    }
+}
+
+impl From<SecretKey> for Ed25519Crypto {
+    fn from(secret: SecretKey) -> Self {
+        let id = node_id(&secret.verifying_key());
+        Self { id, secret }
+    }
+}
+
+impl Crypto for Ed25519Crypto {
+    /// Ed25519 identities have no secp256k1 key, use [`Crypto::identity_key`].
+    fn public_key<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<crypto::PublicKey>> {
+        let id = self.id;
+        futures::future::err(anyhow!("No secp256k1 public key for Ed25519 identity {id}"))
+            .boxed_local()
+    }
+
+    /// Ed25519 identities can't produce secp256k1 signatures, use [`Crypto::sign_encoded`].
+    fn sign<'a>(
+        &self,
+        _message: &'a [u8],
+    ) -> LocalBoxFuture<'a, anyhow::Result<crypto::Signature>> {
+        let id = self.id;
+        futures::future::err(anyhow!("No secp256k1 signatures for Ed25519 identity {id}"))
+            .boxed_local()
+    }
+
+    fn encrypt<'a>(
+        &self,
+        _message: &'a [u8],
+        _remote_key: &'a crypto::PublicKey,
+    ) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>> {
+        unimplemented!()
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        SignatureScheme::Ed25519
+    }
+
+    fn identity_key<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<IdentityKey>> {
+        futures::future::ok(IdentityKey::Ed25519(Ed25519Crypto::public_key(self))).boxed_local()
+    }
+
+    fn sign_encoded<'a>(&self, message: &'a [u8]) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>> {
+        futures::future::ok(self.encoded_signature(message)).boxed_local()
+    }
+}
+
+/// Single Ed25519 identity without aliases.
+impl CryptoProvider for Ed25519Crypto {
+    fn default_id<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<NodeId>> {
+        futures::future::ok(self.id).boxed_local()
+    }
+
+    fn aliases<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<Vec<NodeId>>> {
+        futures::future::ok(Vec::new()).boxed_local()
+    }
+
+    fn get<'a>(&self, node_id: NodeId) -> LocalBoxFuture<'a, anyhow::Result<Rc<dyn Crypto>>> {
+        let result = match node_id == self.id {
+            true => Ok(Rc::new(self.clone()) as Rc<dyn Crypto>),
+            false => Err(anyhow!("unknown node id: {}", node_id)),
+        };
+        futures::future::ready(result).boxed_local()
+    }
+}
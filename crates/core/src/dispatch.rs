@@ -5,12 +5,13 @@ use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::time::Duration;
 
 use futures::channel::oneshot::{self, Sender};
 use futures::future::{FutureExt, LocalBoxFuture};
 use futures::stream::{Stream, StreamExt};
-use tokio::task::spawn_local;
-use tokio::time::{Duration, Instant};
+
+use crate::runtime::{spawn_local, Instant};
 
 use ya_relay_proto::codec;
 use ya_relay_proto::proto::{self, RequestId};
@@ -198,7 +199,7 @@ impl Dispatcher {
         }
 
         async move {
-            let response = tokio::time::timeout(timeout, rx)
+            let response = crate::runtime::timeout(timeout, rx)
                 .await
                 .map_err(|_| anyhow::anyhow!("Request timed out after {} ms", timeout.as_millis()))?
                 .map_err(|_| anyhow::anyhow!("Request cancelled"))?;
@@ -0,0 +1,204 @@
+//! Authentication of Forward packets sent to the relay server.
+//!
+//! Knowing the session id and the source address is otherwise enough to inject Forwards
+//! into somebody's session. During the handshake, the client and the server exchange
+//! X25519 public keys and derive a key of the session. Clients append a tag computed
+//! with this key to Forwards, and the server drops Forwards with an invalid tag.
+use anyhow::bail;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use sha2::Sha256;
+use sha3::{Digest, Keccak256};
+use std::convert::TryFrom;
+
+use ya_relay_proto::codec::BytesMut;
+use ya_relay_proto::proto::control::ForwardToGroup;
+use ya_relay_proto::proto::{Forward, AUTHENTICATED_FLAG};
+
+pub const KEY_EXCHANGE_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 8;
+
+const KEY_DOMAIN: &[u8] = b"ya-relay:forward-key";
+/// Keeps tags of group forwards from being valid for a Forward, and the other way round.
+const GROUP_DOMAIN: &[u8] = b"ya-relay:forward-to-group";
+
+pub type ForwardKey = [u8; 32];
+
+/// One side of the X25519 key exchange.
+pub struct KeyExchange {
+    secret: [u8; 32],
+}
+
+impl KeyExchange {
+    pub fn generate() -> Self {
+        Self::from_secret(rand::random())
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        Self { secret }
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_EXCHANGE_SIZE] {
+        MontgomeryPoint::mul_base_clamped(self.secret).to_bytes()
+    }
+
+    /// Derives the key of the session from the public key of the other side.
+    pub fn derive(&self, remote: &[u8], session_id: &[u8]) -> anyhow::Result<ForwardKey> {
+        let remote = <[u8; KEY_EXCHANGE_SIZE]>::try_from(remote)
+            .map_err(|_| anyhow::anyhow!("Invalid key exchange size: {} B", remote.len()))?;
+        let shared = MontgomeryPoint(remote).mul_clamped(self.secret);
+        // Low order points give the same shared secret regardless of our key.
+        if shared.to_bytes() == [0u8; 32] {
+            bail!("Invalid key exchange public key");
+        }
+
+        let digest = Sha256::new()
+            .chain(KEY_DOMAIN)
+            .chain(shared.as_bytes())
+            .chain(session_id)
+            .finalize();
+        Ok(digest.into())
+    }
+}
+
+fn tag(key: &ForwardKey, forward: &Forward, payload: &[u8]) -> [u8; TAG_SIZE] {
+    let digest = Keccak256::new()
+        .chain(key)
+        .chain(forward.session_id)
+        .chain(forward.slot.to_be_bytes())
+        .chain(forward.flags.to_be_bytes())
+        .chain(payload)
+        .finalize();
+    <[u8; TAG_SIZE]>::try_from(&digest[..TAG_SIZE]).unwrap()
+}
+
+/// Appends the tag to the payload.
+pub fn sign(key: &ForwardKey, forward: &mut Forward) {
+    forward.flags |= AUTHENTICATED_FLAG;
+    let tag = tag(key, forward, forward.payload.as_ref());
+    forward.payload.extend(BytesMut::from(&tag[..]));
+}
+
+/// Checks and removes the tag. Returns `false` and leaves the Forward unchanged,
+/// if the tag is missing or invalid.
+pub fn verify(key: &ForwardKey, forward: &mut Forward) -> bool {
+    let len = match forward.payload.len().checked_sub(TAG_SIZE) {
+        Some(len) if forward.flags & AUTHENTICATED_FLAG != 0 => len,
+        _ => return false,
+    };
+
+    let (payload, received) = forward.payload.as_ref().split_at(len);
+    let expected = tag(key, forward, payload);
+    // Compares in constant time.
+    let diff = expected
+        .iter()
+        .zip(received)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    if diff != 0 {
+        return false;
+    }
+
+    forward.payload.truncate(len);
+    forward.flags &= !AUTHENTICATED_FLAG;
+    true
+}
+
+fn group_tag(key: &ForwardKey, session_id: &[u8], forward: &ForwardToGroup) -> [u8; TAG_SIZE] {
+    let digest = Keccak256::new()
+        .chain(key)
+        .chain(GROUP_DOMAIN)
+        .chain(session_id)
+        .chain((forward.group.len() as u64).to_be_bytes())
+        .chain(forward.group.as_bytes())
+        .chain(&forward.payload)
+        .finalize();
+    <[u8; TAG_SIZE]>::try_from(&digest[..TAG_SIZE]).unwrap()
+}
+
+/// Sets the tag of a group forward sent in session `session_id`.
+pub fn sign_group(key: &ForwardKey, session_id: &[u8], forward: &mut ForwardToGroup) {
+    forward.tag = group_tag(key, session_id, forward).to_vec();
+}
+
+/// Checks the tag of a group forward received in session `session_id`.
+pub fn verify_group(key: &ForwardKey, session_id: &[u8], forward: &ForwardToGroup) -> bool {
+    if forward.tag.len() != TAG_SIZE {
+        return false;
+    }
+    let expected = group_tag(key, session_id, forward);
+    // Compares in constant time.
+    let diff = expected
+        .iter()
+        .zip(&forward.tag)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_exchange() {
+        let session_id = [3u8; 16];
+        let client = KeyExchange::generate();
+        let server = KeyExchange::generate();
+
+        let key = client.derive(&server.public_key(), &session_id).unwrap();
+        assert_eq!(
+            key,
+            server.derive(&client.public_key(), &session_id).unwrap()
+        );
+        assert_ne!(
+            key,
+            client.derive(&server.public_key(), &[4u8; 16]).unwrap()
+        );
+
+        assert!(client.derive(&[0u8; 32], &session_id).is_err());
+        assert!(client.derive(&[1u8; 8], &session_id).is_err());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let key = rand::random::<ForwardKey>();
+        let mut forward = Forward::new([1u8; 16], 7, vec![1, 2, 3]);
+        sign(&key, &mut forward);
+        assert_eq!(forward.payload.len(), 3 + TAG_SIZE);
+
+        let mut tampered = forward.clone();
+        tampered.slot = 8;
+        assert!(!verify(&key, &mut tampered));
+        assert!(!verify(&rand::random::<ForwardKey>(), &mut forward.clone()));
+
+        assert!(verify(&key, &mut forward));
+        assert_eq!(forward.payload.as_ref(), &[1, 2, 3]);
+        assert_eq!(forward.flags, 0);
+
+        // Forwards without the tag aren't accepted.
+        assert!(!verify(&key, &mut forward));
+    }
+
+    #[test]
+    fn test_sign_verify_group() {
+        let key = rand::random::<ForwardKey>();
+        let session_id = [1u8; 16];
+        let mut forward = ForwardToGroup {
+            group: "group".to_string(),
+            payload: vec![1, 2, 3],
+            tag: vec![],
+        };
+        assert!(!verify_group(&key, &session_id, &forward));
+
+        sign_group(&key, &session_id, &mut forward);
+        assert!(verify_group(&key, &session_id, &forward));
+        assert!(!verify_group(&key, &[2u8; 16], &forward));
+        assert!(!verify_group(
+            &rand::random::<ForwardKey>(),
+            &session_id,
+            &forward
+        ));
+
+        let mut tampered = forward.clone();
+        tampered.group = "other".to_string();
+        assert!(!verify_group(&key, &session_id, &tampered));
+    }
+}
@@ -3,14 +3,111 @@ use std::hash::{Hash, Hasher};
 
 use anyhow::{anyhow, bail};
 use ethsign::PublicKey;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use ya_client_model::NodeId;
 use ya_relay_proto::proto;
 
-#[derive(Clone)]
+use crate::crypto::{ed25519, SignatureScheme};
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "SerializedIdentity")]
 pub struct Identity {
     pub node_id: NodeId,
-    pub public_key: PublicKey,
+    pub public_key: IdentityKey,
+}
+
+/// Public key of an identity, in one of the supported signature schemes.
+#[derive(Clone)]
+pub enum IdentityKey {
+    Secp256k1(PublicKey),
+    Ed25519(ed25519::PublicKey),
+}
+
+impl IdentityKey {
+    /// Recognizes the scheme by key length: 32 B keys are Ed25519, the rest is parsed as secp256k1.
+    pub fn from_slice(slice: &[u8]) -> anyhow::Result<Self> {
+        if slice.len() == ed25519::PUBLIC_KEY_SIZE {
+            return Ok(IdentityKey::Ed25519(ed25519::public_key_from_slice(slice)?));
+        }
+        let public_key = PublicKey::from_slice(slice).map_err(|_| anyhow!("Invalid public key"))?;
+        Ok(IdentityKey::Secp256k1(public_key))
+    }
+
+    pub fn scheme(&self) -> SignatureScheme {
+        match self {
+            IdentityKey::Secp256k1(_) => SignatureScheme::Secp256k1,
+            IdentityKey::Ed25519(_) => SignatureScheme::Ed25519,
+        }
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        match self {
+            IdentityKey::Secp256k1(key) => NodeId::from(key.address().as_ref()),
+            IdentityKey::Ed25519(key) => ed25519::node_id(key),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            IdentityKey::Secp256k1(key) => key.bytes().to_vec(),
+            IdentityKey::Ed25519(key) => key.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn as_secp256k1(&self) -> Option<&PublicKey> {
+        match self {
+            IdentityKey::Secp256k1(key) => Some(key),
+            IdentityKey::Ed25519(_) => None,
+        }
+    }
+}
+
+impl From<PublicKey> for IdentityKey {
+    fn from(public_key: PublicKey) -> Self {
+        IdentityKey::Secp256k1(public_key)
+    }
+}
+
+impl From<ed25519::PublicKey> for IdentityKey {
+    fn from(public_key: ed25519::PublicKey) -> Self {
+        IdentityKey::Ed25519(public_key)
+    }
+}
+
+/// Keys are serialized as hex strings.
+impl Serialize for IdentityKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.to_bytes()))
+    }
+}
+
+impl<'de> Deserialize<'de> for IdentityKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        hex::decode(key.trim_start_matches("0x"))
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| IdentityKey::from_slice(&bytes))
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Deserialized form of `Identity`, before checking that `node_id` belongs to the key.
+#[derive(Deserialize)]
+struct SerializedIdentity {
+    node_id: NodeId,
+    public_key: IdentityKey,
+}
+
+impl TryFrom<SerializedIdentity> for Identity {
+    type Error = anyhow::Error;
+
+    fn try_from(ident: SerializedIdentity) -> Result<Self, Self::Error> {
+        if ident.public_key.node_id() != ident.node_id {
+            bail!("Mismatched NodeId");
+        }
+        Ok(ident.public_key.into())
+    }
 }
 
 impl Hash for Identity {
@@ -32,7 +129,7 @@ impl From<(NodeId, PublicKey)> for Identity {
     fn from(tuple: (NodeId, PublicKey)) -> Self {
         Self {
             node_id: tuple.0,
-            public_key: tuple.1,
+            public_key: tuple.1.into(),
         }
     }
 }
@@ -40,7 +137,7 @@ impl From<(NodeId, PublicKey)> for Identity {
 impl From<Identity> for proto::Identity {
     fn from(ident: Identity) -> Self {
         proto::Identity {
-            public_key: ident.public_key.bytes().to_vec(),
+            public_key: ident.public_key.to_bytes(),
             node_id: ident.node_id.into_array().to_vec(),
         }
     }
@@ -49,15 +146,15 @@ impl From<Identity> for proto::Identity {
 impl<'a> From<&'a Identity> for proto::Identity {
     fn from(ident: &'a Identity) -> Self {
         proto::Identity {
-            public_key: ident.public_key.bytes().to_vec(),
+            public_key: ident.public_key.to_bytes(),
             node_id: ident.node_id.into_array().to_vec(),
         }
     }
 }
 
-impl From<PublicKey> for Identity {
-    fn from(public_key: PublicKey) -> Self {
-        let node_id = NodeId::from(public_key.address().as_ref());
+impl From<IdentityKey> for Identity {
+    fn from(public_key: IdentityKey) -> Self {
+        let node_id = public_key.node_id();
         Self {
             public_key,
             node_id,
@@ -65,17 +162,23 @@ impl From<PublicKey> for Identity {
     }
 }
 
+impl From<PublicKey> for Identity {
+    fn from(public_key: PublicKey) -> Self {
+        IdentityKey::from(public_key).into()
+    }
+}
+
+impl From<ed25519::PublicKey> for Identity {
+    fn from(public_key: ed25519::PublicKey) -> Self {
+        IdentityKey::from(public_key).into()
+    }
+}
+
 impl<'a> TryFrom<&'a [u8]> for Identity {
     type Error = anyhow::Error;
 
     fn try_from(slice: &'a [u8]) -> Result<Self, Self::Error> {
-        let public_key = PublicKey::from_slice(slice).map_err(|_| anyhow!("Invalid public key"))?;
-        let node_id = NodeId::from(public_key.address().as_ref());
-
-        Ok(Self {
-            node_id,
-            public_key,
-        })
+        Ok(IdentityKey::from_slice(slice)?.into())
     }
 }
 
@@ -83,9 +186,8 @@ impl<'a> TryFrom<&'a proto::Identity> for Identity {
     type Error = anyhow::Error;
 
     fn try_from(ident: &'a proto::Identity) -> Result<Self, Self::Error> {
-        let public_key =
-            PublicKey::from_slice(&ident.public_key).map_err(|_| anyhow!("Invalid public key"))?;
-        let node_id = NodeId::from(public_key.address().as_ref());
+        let public_key = IdentityKey::from_slice(&ident.public_key)?;
+        let node_id = public_key.node_id();
 
         if node_id.as_ref() != ident.node_id {
             bail!("Mismatched NodeId");
@@ -0,0 +1,166 @@
+//! Hooks for tampering with packets in tests.
+//!
+//! An [`Interceptor`] sees every packet right after it was decoded or right before it
+//! is encoded and decides whether it goes through, is dropped or is held back for a while.
+//! Packets can be modified in place, which allows tests to e.g. duplicate session ids
+//! or corrupt handshake fields.
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+
+use ya_relay_proto::codec::PacketKind;
+
+use crate::udp_stream::{InStream, OutStream};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Drop,
+    /// Handles the packet after given time. Packets following it are not held back,
+    /// so delaying can be used to reorder them.
+    Delay(Duration),
+}
+
+pub trait Interceptor: Send + Sync {
+    /// `peer` is the address the packet came from or is sent to.
+    fn intercept(&self, direction: Direction, peer: SocketAddr, packet: &mut PacketKind)
+        -> Verdict;
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(Direction, SocketAddr, &mut PacketKind) -> Verdict + Send + Sync,
+{
+    fn intercept(
+        &self,
+        direction: Direction,
+        peer: SocketAddr,
+        packet: &mut PacketKind,
+    ) -> Verdict {
+        self(direction, peer, packet)
+    }
+}
+
+pub type InterceptorRef = Arc<dyn Interceptor>;
+
+/// Applies `interceptor` to packets read from `stream`.
+/// Must be called within `LocalSet`, since delayed packets are re-injected by local tasks.
+pub fn intercept_stream(stream: InStream, interceptor: InterceptorRef) -> InStream {
+    let (delayed_tx, delayed_rx) = mpsc::unbounded();
+
+    let passed = stream.filter_map(move |(mut packet, from, timestamp)| {
+        let verdict = interceptor.intercept(Direction::Incoming, from, &mut packet);
+        let delayed_tx = delayed_tx.clone();
+
+        async move {
+            match verdict {
+                Verdict::Pass => Some((packet, from, timestamp)),
+                Verdict::Drop => {
+                    log::trace!("[intercept] dropped packet from {from}");
+                    None
+                }
+                Verdict::Delay(delay) => {
+                    crate::runtime::spawn_local(async move {
+                        crate::runtime::sleep(delay).await;
+                        delayed_tx.unbounded_send((packet, from, timestamp)).ok();
+                    });
+                    None
+                }
+            }
+        }
+    });
+
+    Box::pin(futures::stream::select(passed, delayed_rx))
+}
+
+/// Returns a sink applying `interceptor` to packets before passing them to `sink`.
+/// Must be called within `LocalSet`.
+pub fn intercept_sink(sink: OutStream, interceptor: InterceptorRef) -> OutStream {
+    let (tx, mut rx) = mpsc::channel::<(PacketKind, SocketAddr)>(1);
+
+    crate::runtime::spawn_local(async move {
+        while let Some((mut packet, to)) = rx.next().await {
+            let mut sink = sink.clone();
+            match interceptor.intercept(Direction::Outgoing, to, &mut packet) {
+                Verdict::Pass => {
+                    sink.send((packet, to)).await.ok();
+                }
+                Verdict::Drop => log::trace!("[intercept] dropped packet to {to}"),
+                Verdict::Delay(delay) => {
+                    crate::runtime::spawn_local(async move {
+                        crate::runtime::sleep(delay).await;
+                        sink.send((packet, to)).await.ok();
+                    });
+                }
+            }
+        }
+    });
+
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_proto::proto::Forward;
+
+    fn forward(slot: u32) -> PacketKind {
+        PacketKind::Forward(Forward {
+            session_id: [0u8; 16],
+            slot,
+            flags: 0,
+            payload: Default::default(),
+        })
+    }
+
+    fn slot(packet: &PacketKind) -> u32 {
+        match packet {
+            PacketKind::Forward(forward) => forward.slot,
+            _ => panic!("expected Forward"),
+        }
+    }
+
+    #[tokio::test]
+    async fn intercept_stream_verdicts() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let from: SocketAddr = "127.0.0.1:7464".parse().unwrap();
+                let packets = (1..=4)
+                    .map(|slot| (forward(slot), from, chrono::Utc::now()))
+                    .collect::<Vec<_>>();
+                let stream: InStream =
+                    Box::pin(futures::stream::iter(packets).chain(futures::stream::pending()));
+
+                let interceptor =
+                    |_: Direction, _: SocketAddr, packet: &mut PacketKind| match slot(packet) {
+                        1 => Verdict::Delay(Duration::from_millis(50)),
+                        2 => Verdict::Drop,
+                        3 => {
+                            if let PacketKind::Forward(forward) = packet {
+                                forward.slot = 30;
+                            }
+                            Verdict::Pass
+                        }
+                        _ => Verdict::Pass,
+                    };
+
+                let received = intercept_stream(stream, Arc::new(interceptor))
+                    .take(3)
+                    .map(|(packet, _, _)| slot(&packet))
+                    .collect::<Vec<_>>()
+                    .await;
+                assert_eq!(received, vec![30, 4, 1]);
+            })
+            .await;
+    }
+}
@@ -1,9 +1,14 @@
 use ethsign::keyfile::Bytes;
-pub use ethsign::{KeyFile, Protected, SecretKey};
+pub use ethsign::{KeyFile, Protected, PublicKey, SecretKey};
 use rand::Rng;
 use std::fs::File;
 use std::io::Write;
 
+use ya_client_model::NodeId;
+
+pub mod keystore;
+pub mod vanity;
+
 const KEY_ITERATIONS: u32 = 2;
 const KEYSTORE_VERSION: u64 = 3;
 
@@ -12,6 +17,16 @@ pub fn generate() -> SecretKey {
     SecretKey::from_raw(random_bytes.as_ref()).unwrap()
 }
 
+/// NodeId is the Ethereum address of the key: last 20 bytes of Keccak-256
+/// digest of the uncompressed public key (without `0x04` tag).
+pub fn node_id_from_public_key(public_key: &PublicKey) -> NodeId {
+    NodeId::from(*public_key.address())
+}
+
+pub fn node_id(secret: &SecretKey) -> NodeId {
+    node_id_from_public_key(&secret.public())
+}
+
 pub fn load_or_generate(path: &str, password: Option<Protected>) -> SecretKey {
     log::debug!("load_or_generate({}, {:?})", path, &password);
 
@@ -53,3 +68,56 @@ fn save_to_file(path: &str, secret: &SecretKey, password: &Protected) {
     let pretty_key_file_str = serde_json::to_string_pretty(&key_file).unwrap();
     file.write_all(pretty_key_file_str.as_ref()).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::str::FromStr;
+
+    use super::vanity::{generate_with_prefix_on, NodeIdPrefix};
+    use super::*;
+    use crate::crypto::ed25519;
+
+    #[test]
+    fn node_id_test_vectors() {
+        let secret = SecretKey::from_raw(
+            &hex::decode("4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318")
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            node_id(&secret),
+            NodeId::from_str("0x2c7536e3605d9c16a7a3d7b1898e529396a65c23").unwrap()
+        );
+
+        // RFC 8032, test 1
+        let secret = ed25519::SecretKey::from_bytes(
+            &hex::decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(
+            hex::encode(secret.verifying_key().as_bytes()),
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+        );
+        assert_eq!(
+            ed25519::node_id(&secret.verifying_key()),
+            NodeId::from_str("0x8ee11074cd9c06fe45538dea7af59fac304a0632").unwrap()
+        );
+    }
+
+    #[test]
+    fn vanity_prefix() {
+        let prefix = NodeIdPrefix::from_str("0xA5").unwrap();
+        let (secret, attempts) = generate_with_prefix_on(&prefix, 2);
+
+        assert!(node_id(&secret).to_string().starts_with("0xa5"));
+        assert!(attempts > 0);
+
+        assert!(NodeIdPrefix::from_str("xyz").is_err());
+        assert!(NodeIdPrefix::from_str("")
+            .unwrap()
+            .matches(&NodeId::default()));
+    }
+}
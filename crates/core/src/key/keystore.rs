@@ -0,0 +1,373 @@
+//! Password protected keyfiles.
+//!
+//! Secret is encrypted with AES-256-GCM using a key derived from the password
+//! with scrypt. Node address is used as additional authenticated data, so a
+//! keyfile can't be tampered with to impersonate other identity.
+//!
+//! Keyfiles written by [`super::load_or_generate`] are still accepted by [`load`].
+use std::convert::TryInto;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use ethsign::keyfile::Kdf;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{KeyFile, Protected, SecretKey};
+
+const KEYSTORE_VERSION: u32 = 1;
+const KDF_SCRYPT: &str = "scrypt";
+const CIPHER_AES_GCM: &str = "aes-256-gcm";
+
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+// Limits of KDF parameters read from keyfiles. Derivation runs before the password
+// can be checked, so a crafted keyfile could otherwise make it take gigabytes of
+// memory or hours of CPU.
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R_P: u64 = 64;
+const MAX_SCRYPT_MEMORY: u64 = 1 << 30;
+const MAX_PBKDF2_ROUNDS: u32 = 10_000_000;
+
+const SECRET_SIZE: usize = 32;
+const DERIVED_KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeystoreError {
+    #[error("Keystore IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid keystore format: {0}")]
+    Format(String),
+    #[error("Unsupported keystore: {0}")]
+    Unsupported(String),
+    #[error("Invalid keystore password")]
+    InvalidPassword,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Keystore {
+    version: u32,
+    address: String,
+    kdf: KdfParams,
+    cipher: CipherParams,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KdfParams {
+    name: String,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CipherParams {
+    name: String,
+    nonce: String,
+}
+
+/// Loads secret from `path`, or generates a new one and saves it there if the file is missing.
+pub fn load_or_generate(
+    path: impl AsRef<Path>,
+    password: &Protected,
+) -> Result<SecretKey, KeystoreError> {
+    let path = path.as_ref();
+    if path.exists() {
+        let secret = load(path, password)?;
+        log::info!("Loaded key. path={}", path.display());
+        return Ok(secret);
+    }
+
+    let raw = Protected::from(rand::thread_rng().gen::<[u8; SECRET_SIZE]>().to_vec());
+    let secret = save(path, &raw, password)?;
+
+    log::info!("Generated new key. path={}", path.display());
+    Ok(secret)
+}
+
+pub fn load(path: impl AsRef<Path>, password: &Protected) -> Result<SecretKey, KeystoreError> {
+    let content = fs::read(path)?;
+    let value: serde_json::Value =
+        serde_json::from_slice(&content).map_err(|e| KeystoreError::Format(e.to_string()))?;
+
+    if value.get("crypto").is_some() {
+        let key_file: KeyFile =
+            serde_json::from_value(value).map_err(|e| KeystoreError::Format(e.to_string()))?;
+        check_legacy_kdf(&key_file.crypto.kdf)?;
+        return key_file
+            .to_secret_key(password)
+            .map_err(|_| KeystoreError::InvalidPassword);
+    }
+
+    let keystore: Keystore =
+        serde_json::from_value(value).map_err(|e| KeystoreError::Format(e.to_string()))?;
+    decrypt(&keystore, password)
+}
+
+/// Encrypts raw 32 byte `secret` and writes it to `path`.
+/// The file is replaced atomically and, on unix, readable only by the owner.
+pub fn save(
+    path: impl AsRef<Path>,
+    secret: &Protected,
+    password: &Protected,
+) -> Result<SecretKey, KeystoreError> {
+    let path = path.as_ref();
+    let (keystore, secret) = encrypt(secret, password)?;
+    let content =
+        serde_json::to_vec_pretty(&keystore).map_err(|e| KeystoreError::Format(e.to_string()))?;
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+
+    Ok(secret)
+}
+
+fn encrypt(
+    secret: &Protected,
+    password: &Protected,
+) -> Result<(Keystore, SecretKey), KeystoreError> {
+    let secret_key = SecretKey::from_raw(secret.as_ref())
+        .map_err(|e| KeystoreError::Format(format!("invalid secret: {e}")))?;
+    let address = secret_key.public().address().to_vec();
+
+    let mut rng = rand::thread_rng();
+    let salt = rng.gen::<[u8; SALT_SIZE]>();
+    let nonce = rng.gen::<[u8; NONCE_SIZE]>();
+
+    let key = derive_key(password, &salt, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)?;
+    let ciphertext = Aes256Gcm::new_from_slice(key.as_ref())
+        .map_err(|e| KeystoreError::Format(e.to_string()))?
+        .encrypt(
+            &Nonce::from(nonce),
+            Payload {
+                msg: secret.as_ref(),
+                aad: &address,
+            },
+        )
+        .map_err(|_| KeystoreError::Format("encryption failed".to_string()))?;
+
+    let keystore = Keystore {
+        version: KEYSTORE_VERSION,
+        address: hex::encode(&address),
+        kdf: KdfParams {
+            name: KDF_SCRYPT.to_string(),
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: hex::encode(salt),
+        },
+        cipher: CipherParams {
+            name: CIPHER_AES_GCM.to_string(),
+            nonce: hex::encode(nonce),
+        },
+        ciphertext: hex::encode(ciphertext),
+    };
+    Ok((keystore, secret_key))
+}
+
+fn decrypt(keystore: &Keystore, password: &Protected) -> Result<SecretKey, KeystoreError> {
+    if keystore.version != KEYSTORE_VERSION {
+        return Err(KeystoreError::Unsupported(format!(
+            "version {}",
+            keystore.version
+        )));
+    }
+    if keystore.kdf.name != KDF_SCRYPT {
+        return Err(KeystoreError::Unsupported(format!(
+            "kdf {}",
+            keystore.kdf.name
+        )));
+    }
+    if keystore.cipher.name != CIPHER_AES_GCM {
+        return Err(KeystoreError::Unsupported(format!(
+            "cipher {}",
+            keystore.cipher.name
+        )));
+    }
+
+    let address = decode_hex(&keystore.address, "address")?;
+    let salt = decode_hex(&keystore.kdf.salt, "salt")?;
+    let nonce: [u8; NONCE_SIZE] = decode_hex(&keystore.cipher.nonce, "nonce")?
+        .as_slice()
+        .try_into()
+        .map_err(|_| KeystoreError::Format("invalid nonce size".to_string()))?;
+    let ciphertext = decode_hex(&keystore.ciphertext, "ciphertext")?;
+
+    let key = derive_key(
+        password,
+        &salt,
+        keystore.kdf.log_n,
+        keystore.kdf.r,
+        keystore.kdf.p,
+    )?;
+    let secret = Aes256Gcm::new_from_slice(key.as_ref())
+        .map_err(|e| KeystoreError::Format(e.to_string()))?
+        .decrypt(
+            &Nonce::from(nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: &address,
+            },
+        )
+        .map(Protected::from)
+        .map_err(|_| KeystoreError::InvalidPassword)?;
+
+    let secret_key = SecretKey::from_raw(secret.as_ref())
+        .map_err(|e| KeystoreError::Format(format!("invalid secret: {e}")))?;
+    if &secret_key.public().address()[..] != address.as_slice() {
+        return Err(KeystoreError::Format("address mismatch".to_string()));
+    }
+    Ok(secret_key)
+}
+
+fn derive_key(
+    password: &Protected,
+    salt: &[u8],
+    log_n: u8,
+    r: u32,
+    p: u32,
+) -> Result<Protected, KeystoreError> {
+    check_scrypt_params(log_n, r, p)?;
+    let params = scrypt::Params::new(log_n, r, p, DERIVED_KEY_SIZE)
+        .map_err(|e| KeystoreError::Unsupported(format!("scrypt params: {e}")))?;
+    let mut key = vec![0u8; DERIVED_KEY_SIZE];
+    scrypt::scrypt(password.as_ref(), salt, &params, &mut key)
+        .map_err(|e| KeystoreError::Format(e.to_string()))?;
+    Ok(Protected::from(key))
+}
+
+fn check_scrypt_params(log_n: u8, r: u32, p: u32) -> Result<(), KeystoreError> {
+    let exceeded = log_n > MAX_SCRYPT_LOG_N
+        || u64::from(r) * u64::from(p) > MAX_SCRYPT_R_P
+        || (128 * u64::from(r)) << log_n > MAX_SCRYPT_MEMORY;
+    if exceeded {
+        return Err(KeystoreError::Unsupported(format!(
+            "scrypt params above limits: log_n={log_n}, r={r}, p={p}"
+        )));
+    }
+    Ok(())
+}
+
+/// Keyfiles in the format of [`super::load_or_generate`] carry KDF params of their own.
+fn check_legacy_kdf(kdf: &Kdf) -> Result<(), KeystoreError> {
+    match kdf {
+        Kdf::Pbkdf2(params) if params.c > MAX_PBKDF2_ROUNDS => Err(KeystoreError::Unsupported(
+            format!("pbkdf2 rounds above limit: c={}", params.c),
+        )),
+        Kdf::Pbkdf2(_) => Ok(()),
+        Kdf::Scrypt(params) if !params.n.is_power_of_two() => Err(KeystoreError::Format(format!(
+            "scrypt n not a power of two: {}",
+            params.n
+        ))),
+        Kdf::Scrypt(params) => {
+            check_scrypt_params(params.n.trailing_zeros() as u8, params.r, params.p)
+        }
+    }
+}
+
+fn decode_hex(value: &str, field: &str) -> Result<Vec<u8>, KeystoreError> {
+    hex::decode(value).map_err(|e| KeystoreError::Format(format!("{field}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("ya-relay-keystore-{}.json", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn save_load_roundtrip() {
+        let path = temp_path();
+        let password = Protected::from("secret password");
+
+        let generated = load_or_generate(&path, &password).unwrap();
+        let loaded = load_or_generate(&path, &password).unwrap();
+        assert_eq!(generated.public().address(), loaded.public().address());
+
+        assert!(matches!(
+            load(&path, &Protected::from("wrong")),
+            Err(KeystoreError::InvalidPassword)
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_oversized_kdf_params() {
+        let path = temp_path();
+        let password = Protected::from("secret password");
+        let raw = Protected::from(vec![7u8; SECRET_SIZE]);
+        let (mut keystore, _) = encrypt(&raw, &password).unwrap();
+
+        for (log_n, r, p) in [
+            (30, 8, 1),
+            (SCRYPT_LOG_N, 1 << 20, 1),
+            (SCRYPT_LOG_N, 8, 1 << 20),
+        ] {
+            keystore.kdf.log_n = log_n;
+            keystore.kdf.r = r;
+            keystore.kdf.p = p;
+            fs::write(&path, serde_json::to_vec(&keystore).unwrap()).unwrap();
+
+            let err = load(&path, &password).unwrap_err();
+            assert!(
+                matches!(&err, KeystoreError::Unsupported(msg) if msg.contains("scrypt params")),
+                "{err}"
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn loads_legacy_keyfile() {
+        let path = temp_path();
+        let password = Protected::from("secret password");
+        let secret = super::super::generate();
+        super::super::save_to_file(path.to_str().unwrap(), &secret, &password);
+
+        let loaded = load(&path, &password).unwrap();
+        assert_eq!(secret.public().address(), loaded.public().address());
+
+        // Params of legacy keyfiles are limited as well.
+        let mut key_file: KeyFile = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        key_file.crypto.kdf = Kdf::Scrypt(ethsign::keyfile::Scrypt {
+            dklen: DERIVED_KEY_SIZE as u32,
+            p: 1,
+            n: 1 << 30,
+            r: 8,
+            salt: ethsign::keyfile::Bytes(vec![0; SALT_SIZE]),
+        });
+        fs::write(&path, serde_json::to_vec(&key_file).unwrap()).unwrap();
+        assert!(matches!(
+            load(&path, &password),
+            Err(KeystoreError::Unsupported(_))
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
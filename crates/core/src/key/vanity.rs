@@ -0,0 +1,114 @@
+//! Generating keys with a chosen NodeId prefix.
+//!
+//! Useful for building test topologies, where Nodes have to fall into the
+//! prefix based selectors used by the relay server (e.g. `/nodes/{prefix}`).
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail};
+
+use ya_client_model::NodeId;
+
+use super::{generate, node_id, SecretKey};
+
+const NODE_ID_NIBBLES: usize = 40;
+
+/// Hex prefix of a NodeId, matched nibble by nibble from the most significant one.
+/// `0x` prefix is optional and letters are case insensitive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeIdPrefix {
+    nibbles: Vec<u8>,
+}
+
+impl NodeIdPrefix {
+    pub fn len(&self) -> usize {
+        self.nibbles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nibbles.is_empty()
+    }
+
+    pub fn matches(&self, node_id: &NodeId) -> bool {
+        let bytes = node_id.into_array();
+        self.nibbles.iter().enumerate().all(|(idx, nibble)| {
+            let byte = bytes[idx / 2];
+            let value = if idx % 2 == 0 { byte >> 4 } else { byte & 0x0f };
+            value == *nibble
+        })
+    }
+
+    /// Average number of keys to generate before finding a match.
+    pub fn expected_attempts(&self) -> f64 {
+        16f64.powi(self.nibbles.len() as i32)
+    }
+}
+
+impl FromStr for NodeIdPrefix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_start_matches("0x");
+        if s.len() > NODE_ID_NIBBLES {
+            bail!("NodeId prefix too long: {} > {NODE_ID_NIBBLES}", s.len());
+        }
+
+        let nibbles = s
+            .chars()
+            .map(|c| {
+                c.to_digit(16)
+                    .map(|d| d as u8)
+                    .ok_or_else(|| anyhow!("Invalid hex character in NodeId prefix: {c}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(NodeIdPrefix { nibbles })
+    }
+}
+
+/// Generates a key with NodeId starting with `prefix`, using all available cores.
+/// Every additional hex digit makes the search 16 times longer.
+pub fn generate_with_prefix(prefix: &NodeIdPrefix) -> SecretKey {
+    let workers = std::thread::available_parallelism()
+        .map(usize::from)
+        .unwrap_or(1);
+    generate_with_prefix_on(prefix, workers).0
+}
+
+/// Same as [`generate_with_prefix`] with explicit number of worker threads.
+/// Returns the key together with the total number of generated keys.
+pub fn generate_with_prefix_on(prefix: &NodeIdPrefix, workers: usize) -> (SecretKey, u64) {
+    let found = Arc::new(AtomicBool::new(false));
+    let attempts = Arc::new(AtomicU64::new(0));
+    let (tx, rx) = mpsc::channel();
+
+    let handles = (0..workers.max(1))
+        .map(|_| {
+            let prefix = prefix.clone();
+            let found = found.clone();
+            let attempts = attempts.clone();
+            let tx = tx.clone();
+
+            std::thread::spawn(move || {
+                while !found.load(Ordering::Relaxed) {
+                    let secret = generate();
+                    attempts.fetch_add(1, Ordering::Relaxed);
+                    if prefix.matches(&node_id(&secret)) {
+                        found.store(true, Ordering::Relaxed);
+                        tx.send(secret).ok();
+                        break;
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(tx);
+
+    // At least one worker sends a key before all of them finish.
+    let secret = rx.recv().expect("vanity key workers exited without result");
+    for handle in handles {
+        handle.join().ok();
+    }
+    (secret, attempts.load(Ordering::Relaxed))
+}
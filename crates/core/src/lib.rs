@@ -1,9 +1,15 @@
 pub mod challenge;
+pub mod clock;
 pub mod crypto;
 pub mod dispatch;
 pub mod error;
+pub mod forward_auth;
 pub mod identity;
+pub mod intercept;
 pub mod key;
+pub mod properties;
+pub mod runtime;
+pub mod server_identity;
 pub mod server_session;
 pub mod session;
 pub mod sync;
@@ -11,6 +17,8 @@ pub mod sync;
 pub mod testing;
 pub mod udp_stream;
 pub mod utils;
+#[cfg(feature = "ws")]
+pub mod ws_stream;
 
 pub use ya_client_model::NodeId;
 
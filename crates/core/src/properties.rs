@@ -0,0 +1,111 @@
+//! Key-value properties, which Nodes publish on the relay server at registration.
+//!
+//! Properties are signed by the default identity of the Node, so Nodes receiving them
+//! from the relay server don't have to trust it.
+use anyhow::{anyhow, bail};
+use std::collections::BTreeMap;
+
+use ya_relay_proto::proto;
+use ya_relay_proto::proto::Message;
+
+use crate::crypto::{recover_data_signer, sign_data, Crypto};
+use crate::NodeId;
+
+/// Upper bound of encoded properties size, so they fit in a single packet with registration.
+pub const MAX_PROPERTIES_SIZE: usize = 512;
+
+pub type Properties = BTreeMap<String, String>;
+
+pub async fn sign_properties(
+    crypto: &dyn Crypto,
+    properties: &Properties,
+) -> anyhow::Result<proto::Properties> {
+    let list = proto::properties::List {
+        properties: properties
+            .iter()
+            .map(|(key, value)| proto::properties::Property {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect(),
+    };
+    let data = list.encode_to_vec();
+    if data.len() > MAX_PROPERTIES_SIZE {
+        bail!(
+            "Properties take {} B, above the limit of {MAX_PROPERTIES_SIZE} B",
+            data.len()
+        );
+    }
+
+    let signature = sign_data(crypto, &data).await?;
+    Ok(proto::Properties { data, signature })
+}
+
+/// Checks that properties were signed by `node_id` and decodes them.
+pub fn verify_properties(
+    signed: &proto::Properties,
+    node_id: NodeId,
+) -> anyhow::Result<Properties> {
+    if signed.data.len() > MAX_PROPERTIES_SIZE {
+        bail!(
+            "Properties take {} B, above the limit of {MAX_PROPERTIES_SIZE} B",
+            signed.data.len()
+        );
+    }
+    let signer = recover_data_signer(&signed.data, &signed.signature)?.node_id;
+    if signer != node_id {
+        bail!("Properties of [{node_id}] signed by [{signer}]");
+    }
+
+    let list = proto::properties::List::decode(signed.data.as_slice())
+        .map_err(|e| anyhow!("Invalid properties: {e}"))?;
+    let mut properties = Properties::new();
+    for property in list.properties {
+        if properties
+            .insert(property.key.clone(), property.value)
+            .is_some()
+        {
+            bail!("Duplicated property {:?}", property.key);
+        }
+    }
+    Ok(properties)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::FallbackCrypto;
+    use crate::key::generate;
+
+    fn new_crypto() -> (FallbackCrypto, NodeId) {
+        let secret = generate();
+        let node_id = NodeId::from(*secret.public().address());
+        (FallbackCrypto::from(secret), node_id)
+    }
+
+    #[tokio::test]
+    async fn test_sign_verify() {
+        let (crypto, node_id) = new_crypto();
+        let properties = Properties::from([
+            ("service".to_string(), "storage".to_string()),
+            ("version".to_string(), "1.2.0".to_string()),
+        ]);
+
+        let signed = sign_properties(&crypto, &properties).await.unwrap();
+        assert_eq!(verify_properties(&signed, node_id).unwrap(), properties);
+
+        let (_, other) = new_crypto();
+        assert!(verify_properties(&signed, other).is_err());
+
+        let mut tampered = signed.clone();
+        tampered.data[2] ^= 0xff;
+        assert!(verify_properties(&tampered, node_id).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_size_limit() {
+        let (crypto, _) = new_crypto();
+        let properties = Properties::from([("blob".to_string(), "x".repeat(MAX_PROPERTIES_SIZE))]);
+        assert!(sign_properties(&crypto, &properties).await.is_err());
+    }
+}
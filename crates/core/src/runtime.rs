@@ -0,0 +1,119 @@
+//! Spawning of background tasks and timers.
+//!
+//! Long running loops of the client (packet dispatch, session expiration, keep-alive)
+//! are started through [`Spawner`]. Applications embedding the client, e.g. in a mobile
+//! app, can run them on their own `LocalSet` or keep track of them. Short lived tasks
+//! serving a single request are spawned on the current thread with [`spawn_local`].
+//!
+//! The client uses tasks, timers and [`Instant`] from here instead of tokio, so it also
+//! runs on `wasm32-unknown-unknown`. There is no tokio runtime in browsers, so tasks
+//! run on `wasm-bindgen-futures` and timers on the JavaScript event loop.
+use std::future::Future;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::future::{AbortHandle, Abortable, LocalBoxFuture};
+use futures::FutureExt;
+
+pub use crate::clock::Elapsed;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::{Instant, SystemTime};
+#[cfg(target_arch = "wasm32")]
+pub use web_time::{Instant, SystemTime};
+
+pub trait Spawner {
+    fn spawn(&self, future: LocalBoxFuture<'static, ()>);
+}
+
+pub type SpawnerRef = Rc<dyn Spawner>;
+
+/// Spawns tasks with [`spawn_local`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalSpawner;
+
+impl Spawner for LocalSpawner {
+    fn spawn(&self, future: LocalBoxFuture<'static, ()>) {
+        spawn_local(future);
+    }
+}
+
+pub fn local_spawner() -> SpawnerRef {
+    Rc::new(LocalSpawner)
+}
+
+/// Equivalent of [`crate::utils::spawn_local_abortable`] using `spawner`.
+pub fn spawn_abortable<F>(spawner: &dyn Spawner, future: F) -> AbortHandle
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+
+    spawner.spawn(
+        Abortable::new(future, abort_registration)
+            .map(|_| ())
+            .boxed_local(),
+    );
+    abort_handle
+}
+
+/// Runs `future` on the current thread. Has to be called within tokio `LocalSet`,
+/// except in browsers. The output is dropped.
+pub fn spawn_local<F>(future: F)
+where
+    F: Future + 'static,
+{
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::task::spawn_local(future.map(|_| ()));
+    #[cfg(target_arch = "wasm32")]
+    wasm_bindgen_futures::spawn_local(future.map(|_| ()));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    tokio::time::sleep(duration)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    gloo_timers::future::sleep(duration)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn sleep_until(deadline: Instant) -> impl Future<Output = ()> {
+    tokio::time::sleep_until(deadline.into())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn sleep_until(deadline: Instant) -> impl Future<Output = ()> {
+    sleep(deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Equivalent of `tokio::time::timeout`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> impl Future<Output = Result<F::Output, Elapsed>> {
+    tokio::time::timeout(duration, future).map(|result| result.map_err(|_| Elapsed))
+}
+
+/// Equivalent of `tokio::time::timeout`.
+#[cfg(target_arch = "wasm32")]
+pub fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> impl Future<Output = Result<F::Output, Elapsed>> {
+    use futures::future::Either;
+
+    let sleep = sleep(duration);
+    async move {
+        futures::pin_mut!(future);
+        futures::pin_mut!(sleep);
+        match futures::future::select(future, sleep).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Elapsed),
+        }
+    }
+}
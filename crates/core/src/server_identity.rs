@@ -0,0 +1,118 @@
+//! Long-term identity of the relay server.
+//!
+//! The server signs its session handshake responses with an Ed25519 key. Signed messages
+//! include a nonce chosen by the client, so clients knowing the server key can tell
+//! the responses come from the server and weren't replayed.
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+
+use ya_relay_proto::proto;
+
+use crate::crypto::ed25519::{self, SECRET_KEY_SIZE};
+pub use crate::crypto::ed25519::{PublicKey, SecretKey};
+
+/// Size of the nonce clients send with session requests.
+pub const NONCE_SIZE: usize = 16;
+
+const CHALLENGE_DOMAIN: &[u8] = b"ya-relay:server-challenge";
+const SESSION_DOMAIN: &[u8] = b"ya-relay:server-session";
+
+pub fn generate_nonce() -> Vec<u8> {
+    rand::random::<[u8; NONCE_SIZE]>().to_vec()
+}
+
+/// Message signed in the response with the challenge.
+pub fn challenge_message(
+    nonce: &[u8],
+    session_id: &[u8],
+    challenge: &proto::ChallengeRequest,
+) -> Vec<u8> {
+    Sha256::new()
+        .chain(CHALLENGE_DOMAIN)
+        .chain(nonce)
+        .chain(session_id)
+        .chain(&challenge.challenge)
+        .chain(challenge.difficulty.to_be_bytes())
+        .finalize()
+        .to_vec()
+}
+
+/// Message signed in the response accepting the challenge solution.
+pub fn session_message(nonce: &[u8], session_id: &[u8], key_exchange: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain(SESSION_DOMAIN)
+        .chain(nonce)
+        .chain(session_id)
+        .chain(key_exchange)
+        .finalize()
+        .to_vec()
+}
+
+pub fn sign(secret: &SecretKey, message: &[u8]) -> Vec<u8> {
+    ed25519::encode_signature(secret, message)
+}
+
+/// Returns the key of the server, which signed `message`.
+pub fn verify(signature: &[u8], message: &[u8]) -> anyhow::Result<PublicKey> {
+    if signature.is_empty() {
+        anyhow::bail!("Response isn't signed by the server");
+    }
+    ed25519::recover(signature, message)
+}
+
+pub fn public_key_from_hex(hex_str: &str) -> anyhow::Result<PublicKey> {
+    ed25519::public_key_from_slice(&hex::decode(hex_str)?)
+}
+
+pub fn secret_key_from_hex(hex_str: &str) -> anyhow::Result<SecretKey> {
+    let bytes = <[u8; SECRET_KEY_SIZE]>::try_from(hex::decode(hex_str)?.as_slice())
+        .map_err(|_| anyhow::anyhow!("Invalid ed25519 secret key size"))?;
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let secret = ed25519::generate();
+        let nonce = generate_nonce();
+        let session_id = [7u8; 16];
+        let message = session_message(&nonce, &session_id, &[]);
+
+        let signature = sign(&secret, &message);
+        assert_eq!(
+            verify(&signature, &message).unwrap(),
+            secret.verifying_key()
+        );
+        assert!(verify(
+            &signature,
+            &session_message(&generate_nonce(), &session_id, &[])
+        )
+        .is_err());
+        assert!(verify(&[], &message).is_err());
+
+        let challenge = proto::ChallengeRequest {
+            challenge: vec![1, 2, 3],
+            difficulty: 16,
+            ..Default::default()
+        };
+        let message = challenge_message(&nonce, &session_id, &challenge);
+        assert_ne!(message, session_message(&nonce, &session_id, &[]));
+    }
+
+    #[test]
+    fn test_keys_from_hex() {
+        let secret = ed25519::generate();
+        let parsed = secret_key_from_hex(&hex::encode(secret.to_bytes())).unwrap();
+        assert_eq!(parsed.to_bytes(), secret.to_bytes());
+
+        let public = secret.verifying_key();
+        assert_eq!(
+            public_key_from_hex(&hex::encode(public.as_bytes())).unwrap(),
+            public
+        );
+        assert!(public_key_from_hex("00").is_err());
+    }
+}
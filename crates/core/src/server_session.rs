@@ -32,16 +32,20 @@ impl SessionId {
     }
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Endpoint {
+    #[serde(with = "protocol_name")]
     pub protocol: proto::Protocol,
     pub address: SocketAddr,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NodeInfo {
     pub identities: Vec<Identity>,
     pub slot: SlotId,
+    /// Epoch of the relay slot table `slot` comes from. Zero if unknown.
+    #[serde(default)]
+    pub slot_epoch: u32,
 
     /// Endpoints registered by Node.
     pub endpoints: Vec<Endpoint>,
@@ -56,11 +60,27 @@ impl NodeInfo {
     pub fn public_key(&self) -> Vec<u8> {
         self.identities
             .get(0)
-            .map(|ident| ident.public_key.bytes().to_vec())
+            .map(|ident| ident.public_key.to_bytes())
             .unwrap()
     }
 }
 
+/// Serializes `Protocol` by its protobuf name instead of the numeric value.
+mod protocol_name {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use ya_relay_proto::proto::Protocol;
+
+    pub fn serialize<S: Serializer>(protocol: &Protocol, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(protocol.as_str_name())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Protocol, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Protocol::from_str_name(&name)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid protocol: {name}")))
+    }
+}
+
 #[derive(Clone)]
 pub struct LastSeen {
     last_seen: Arc<Mutex<DateTime<Utc>>>,
@@ -235,6 +255,7 @@ impl TryFrom<proto::response::Node> for NodeInfo {
         Ok(NodeInfo {
             identities,
             slot: value.slot,
+            slot_epoch: value.slot_epoch,
             endpoints: value
                 .endpoints
                 .into_iter()
@@ -244,3 +265,47 @@ impl TryFrom<proto::response::Node> for NodeInfo {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{ed25519, SignatureScheme};
+
+    #[test]
+    fn node_info_serde() {
+        let key = ed25519::generate();
+        let identity = Identity::from(key.verifying_key());
+        let info = NodeInfo {
+            identities: vec![identity.clone()],
+            slot: 7,
+            slot_epoch: 1,
+            endpoints: vec![Endpoint {
+                protocol: proto::Protocol::Udp,
+                address: "1.2.3.4:7464".parse().unwrap(),
+            }],
+            supported_encryption: vec!["aes-gcm".to_string()],
+        };
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["endpoints"][0]["protocol"], "UDP");
+        assert_eq!(
+            json["identities"][0]["node_id"],
+            identity.node_id.to_string()
+        );
+
+        let decoded: NodeInfo = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(decoded.default_node_id(), info.default_node_id());
+        assert_eq!(decoded.public_key(), info.public_key());
+        assert_eq!(
+            decoded.identities[0].public_key.scheme(),
+            SignatureScheme::Ed25519
+        );
+        assert!(decoded.endpoints == info.endpoints);
+        assert_eq!(decoded.slot, 7);
+
+        // Identity, which doesn't match the key.
+        let mut json = json;
+        json["identities"][0]["node_id"] = NodeId::default().to_string().into();
+        assert!(serde_json::from_value::<NodeInfo>(json).is_err());
+    }
+}
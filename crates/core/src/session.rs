@@ -6,11 +6,12 @@ use std::fmt;
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::Arc;
-use tokio::time::{Duration, Instant};
+use std::time::Duration;
 
 use crate::dispatch::{Dispatched, Dispatcher};
+use crate::runtime::Instant;
 use crate::server_session::SessionId;
-use crate::sync::Actuator;
+use crate::sync::Gate;
 use crate::udp_stream::OutStream;
 use crate::NodeId;
 
@@ -35,16 +36,16 @@ pub struct Session {
     sink: OutStream,
     pub(crate) dispatcher: Dispatcher,
     pub(crate) drop_handler: Rc<RefCell<Option<DropHandler>>>,
-    pub forward_pause: Actuator,
+    pub forward_gate: Gate,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct SessionDesc {
     pub remote: SocketAddr,
     pub id: SessionId,
-    pub last_seen: std::time::Instant,
+    pub last_seen: Instant,
     pub last_ping: std::time::Duration,
-    pub created: std::time::Instant,
+    pub created: Instant,
 }
 
 impl<'a> From<&'a Session> for SessionDesc {
@@ -52,9 +53,9 @@ impl<'a> From<&'a Session> for SessionDesc {
         SessionDesc {
             remote: session.remote,
             id: session.id,
-            last_seen: session.dispatcher.last_seen().into_std(),
+            last_seen: session.dispatcher.last_seen(),
             last_ping: session.dispatcher.last_ping(),
-            created: session.created.into_std(),
+            created: session.created,
         }
     }
 }
@@ -68,7 +69,7 @@ impl Session {
             created: Instant::now(),
             dispatcher: Dispatcher::default(),
             drop_handler: Default::default(),
-            forward_pause: Default::default(),
+            forward_gate: Default::default(),
         })
     }
 
@@ -84,7 +85,11 @@ impl Session {
 
         let response = self
             .request::<proto::response::Register>(
-                proto::request::Register { endpoints }.into(),
+                proto::request::Register {
+                    endpoints,
+                    properties: None,
+                }
+                .into(),
                 self.id.to_vec(),
                 DEFAULT_REQUEST_TIMEOUT,
             )
@@ -181,12 +186,12 @@ impl Session {
 
     #[inline]
     pub async fn pause_forwarding(&self) {
-        self.forward_pause.enable();
+        self.forward_gate.close();
     }
 
     #[inline]
     pub async fn resume_forwarding(&self) {
-        self.forward_pause.disable();
+        self.forward_gate.open();
     }
 
     /// Will send `Disconnect` message to other Node, to close end Session
@@ -228,7 +233,7 @@ impl Session {
                 if let Err(e) = self.send(packet.clone()).await {
                     return e;
                 }
-                tokio::time::sleep(timeout / RETRIES).await;
+                crate::runtime::sleep(timeout / RETRIES).await;
             }
         };
 
@@ -1,73 +1,109 @@
-use std::ops::Not;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-
-use futures::channel::oneshot;
-use futures::future::{BoxFuture, Shared};
-use futures::FutureExt;
-
-#[derive(Clone, Default)]
-pub struct Actuator {
-    enabled: Arc<AtomicBool>,
-    state: Arc<Mutex<State>>,
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Gate which tasks wait on, while it's closed. Opening it wakes all of them at once.
+///
+/// Clones share the state of the gate.
+#[derive(Clone)]
+pub struct Gate {
+    open: Arc<watch::Sender<bool>>,
 }
 
-impl Actuator {
+impl Gate {
     #[inline]
-    pub fn next(&self) -> Option<BoxFuture<'static, ()>> {
-        let state = self.state.lock().unwrap();
-        self.is_enabled().then(|| state.future())
+    pub fn is_open(&self) -> bool {
+        *self.open.borrow()
     }
 
     #[inline]
-    pub fn is_enabled(&self) -> bool {
-        self.enabled.load(Ordering::SeqCst)
+    pub fn open(&self) {
+        self.open.send_replace(true);
     }
 
     #[inline]
-    pub fn enable(&self) {
-        let _state = self.state.lock().unwrap();
-        if self.is_enabled().not() {
-            self.enabled.store(true, Ordering::SeqCst);
-        }
+    pub fn close(&self) {
+        self.open.send_replace(false);
     }
 
-    #[inline]
-    pub fn disable(&self) {
-        let mut state = self.state.lock().unwrap();
-        if self.is_enabled() {
-            *state = State::default();
-            self.enabled.store(false, Ordering::SeqCst);
-        }
+    /// Resolves immediately if the gate is open, otherwise when it's opened.
+    pub async fn opened(&self) {
+        let mut rx = self.open.subscribe();
+        // Fails only when the sender is dropped, but `self` holds it.
+        let _ = rx.wait_for(|open| *open).await;
     }
 }
 
-struct State {
-    tx: Option<oneshot::Sender<()>>,
-    rx: Shared<BoxFuture<'static, ()>>,
-}
-
-impl State {
-    #[inline]
-    fn future(&self) -> BoxFuture<'static, ()> {
-        self.rx.clone().boxed()
+impl Default for Gate {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(true);
+        Self { open: Arc::new(tx) }
     }
 }
 
-impl Default for State {
-    fn default() -> Self {
-        let (tx, rx) = oneshot::channel();
-        Self {
-            tx: Some(tx),
-            rx: rx.then(|_| futures::future::ready(())).boxed().shared(),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_gate() {
+        let gate = Gate::default();
+        assert!(gate.is_open());
+        assert!(gate.opened().now_or_never().is_some());
+
+        gate.close();
+        gate.close();
+        assert!(!gate.is_open());
+        assert!(gate.opened().now_or_never().is_none());
+
+        gate.open();
+        assert!(gate.opened().now_or_never().is_some());
     }
-}
 
-impl Drop for State {
-    fn drop(&mut self) {
-        if let Some(tx) = self.tx.take() {
-            let _ = tx.send(());
-        }
+    #[tokio::test]
+    async fn test_waiters_under_load() {
+        const WAITERS: usize = 100;
+
+        let gate = Gate::default();
+        let passed = Arc::new(AtomicUsize::new(0));
+        gate.close();
+
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let waiters: Vec<_> = (0..WAITERS)
+                    .map(|_| {
+                        let gate = gate.clone();
+                        let passed = passed.clone();
+                        tokio::task::spawn_local(async move {
+                            gate.opened().await;
+                            passed.fetch_add(1, Ordering::SeqCst);
+                        })
+                    })
+                    .collect();
+
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                assert_eq!(passed.load(Ordering::SeqCst), 0);
+
+                // Waiters which didn't get to run before the gate closed again
+                // keep waiting, instead of missing the wake up.
+                gate.open();
+                gate.close();
+                tokio::task::yield_now().await;
+                assert_eq!(passed.load(Ordering::SeqCst), 0);
+
+                gate.open();
+                for waiter in waiters {
+                    tokio::time::timeout(Duration::from_secs(1), waiter)
+                        .await
+                        .unwrap()
+                        .unwrap();
+                }
+                assert_eq!(passed.load(Ordering::SeqCst), WAITERS);
+            })
+            .await;
     }
 }
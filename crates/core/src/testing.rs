@@ -1,3 +1,5 @@
+pub mod nat;
+
 use futures::future::LocalBoxFuture;
 use url::Url;
 use ya_client_model::NodeId;
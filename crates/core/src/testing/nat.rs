@@ -0,0 +1,218 @@
+//! Simulated NAT in front of a test client's UDP socket.
+//!
+//! Client packets leave through external sockets chosen by the NAT mapping rules,
+//! and packets received on those sockets reach the client only if the filtering
+//! rules allow it. All addresses stay on the loopback interface, so behavior of
+//! each NAT type can be tested without real routers.
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc;
+use futures::future::LocalBoxFuture;
+use futures::{FutureExt, SinkExt, StreamExt};
+use tokio::net::UdpSocket;
+use tokio_util::codec::Encoder;
+
+use ya_relay_proto::codec::datagram::Codec;
+use ya_relay_proto::codec::{BytesMut, PacketKind};
+
+use crate::udp_stream::{udp_stream, DatagramTransport, InStream, OutStream};
+use crate::utils::parse_udp_url;
+
+/// Address the client believes it is bound to.
+const PRIVATE_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatType {
+    /// Single external address, accepts packets from anyone.
+    FullCone,
+    /// Single external address, accepts packets from hosts the client has sent to.
+    AddressRestricted,
+    /// Single external address, accepts packets from `ip:port` pairs the client has sent to.
+    PortRestricted,
+    /// Separate external address for each destination, accepting packets only from it.
+    Symmetric,
+}
+
+/// Shared handle to the NAT state, so tests can inspect it while the client is running.
+#[derive(Clone)]
+pub struct Nat {
+    nat_type: NatType,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Cone NATs keep a single mapping under `None`.
+    mappings: HashMap<Option<SocketAddr>, Arc<UdpSocket>>,
+    /// Destinations the client has sent packets to, by external address.
+    sent_to: HashSet<(SocketAddr, SocketAddr)>,
+    dropped: usize,
+    receivers: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl Nat {
+    pub fn new(nat_type: NatType) -> Self {
+        Nat {
+            nat_type,
+            state: Default::default(),
+        }
+    }
+
+    pub fn nat_type(&self) -> NatType {
+        self.nat_type
+    }
+
+    /// External addresses assigned to the client so far.
+    pub fn external_addrs(&self) -> Vec<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        let mut addrs = state
+            .mappings
+            .values()
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect::<Vec<_>>();
+        addrs.sort();
+        addrs.dedup();
+        addrs
+    }
+
+    /// Number of incoming packets rejected by the NAT filter.
+    pub fn dropped(&self) -> usize {
+        self.state.lock().unwrap().dropped
+    }
+
+    /// Forgets all mappings, like a restarted NAT. Following packets leave through
+    /// new external addresses and packets sent to the old ones are lost.
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.mappings.clear();
+        state.sent_to.clear();
+        state.receivers.drain(..).for_each(|handle| handle.abort());
+    }
+
+    /// Returns a private address instead of the external one. Must be called within `LocalSet`.
+    async fn bind_nat(&self, addr: &url::Url) -> anyhow::Result<(InStream, OutStream, SocketAddr)> {
+        let ip = parse_udp_url(addr)?.parse::<SocketAddr>()?.ip();
+        let (in_tx, in_rx) = mpsc::unbounded();
+
+        // The first mapping is created upfront to pick the private port.
+        let primary = self.map(ip, in_tx.clone()).await?;
+        let private_addr = SocketAddr::new(PRIVATE_IP.into(), primary.local_addr()?.port());
+        let mut primary = match self.nat_type {
+            NatType::Symmetric => Some(primary),
+            _ => {
+                self.state.lock().unwrap().mappings.insert(None, primary);
+                None
+            }
+        };
+
+        let (out_tx, mut out_rx) = mpsc::channel::<(PacketKind, SocketAddr)>(100);
+        let nat = self.clone();
+
+        tokio::task::spawn_local(async move {
+            let mut codec = Codec;
+            let mut buf = BytesMut::new();
+
+            while let Some((packet, target)) = out_rx.next().await {
+                let key = match nat.nat_type {
+                    NatType::Symmetric => Some(target),
+                    _ => None,
+                };
+
+                let socket = nat.state.lock().unwrap().mappings.get(&key).cloned();
+                let socket = match socket {
+                    Some(socket) => socket,
+                    None => {
+                        let socket = match primary.take() {
+                            Some(socket) => socket,
+                            None => match nat.map(ip, in_tx.clone()).await {
+                                Ok(socket) => socket,
+                                Err(e) => {
+                                    log::warn!("[nat] unable to map {target}: {e}");
+                                    continue;
+                                }
+                            },
+                        };
+                        let mut state = nat.state.lock().unwrap();
+                        state.mappings.insert(key, socket.clone());
+                        socket
+                    }
+                };
+
+                if let Ok(external) = socket.local_addr() {
+                    nat.state.lock().unwrap().sent_to.insert((external, target));
+                }
+
+                buf.clear();
+                if let Err(e) = codec.encode(packet, &mut buf) {
+                    log::warn!("[nat] error encoding packet for {target}: {e}");
+                    continue;
+                }
+                if let Err(e) = socket.send_to(&buf, target).await {
+                    log::warn!("[nat] error sending packet to {target}: {e}");
+                }
+            }
+
+            // Client closed its socket.
+            let mut state = nat.state.lock().unwrap();
+            state.mappings.clear();
+            state.receivers.drain(..).for_each(|handle| handle.abort());
+        });
+
+        Ok((Box::pin(in_rx), out_tx, private_addr))
+    }
+
+    async fn map(
+        &self,
+        ip: IpAddr,
+        inbound: mpsc::UnboundedSender<(PacketKind, SocketAddr, chrono::DateTime<chrono::Utc>)>,
+    ) -> anyhow::Result<Arc<UdpSocket>> {
+        let socket = Arc::new(UdpSocket::bind((ip, 0)).await?);
+        let external = socket.local_addr()?;
+        log::debug!("[nat] new {:?} mapping {external}", self.nat_type);
+
+        let nat = self.clone();
+        let mut stream = Box::pin(udp_stream(socket.clone()));
+        let mut inbound = inbound;
+
+        let receiver = tokio::task::spawn_local(async move {
+            while let Some((packet, from, timestamp)) = stream.next().await {
+                if !nat.accepts(external, from) {
+                    log::trace!("[nat] {external} rejected packet from {from}");
+                    nat.state.lock().unwrap().dropped += 1;
+                    continue;
+                }
+                if inbound.send((packet, from, timestamp)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        self.state.lock().unwrap().receivers.push(receiver);
+
+        Ok(socket)
+    }
+
+    fn accepts(&self, external: SocketAddr, from: SocketAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        match self.nat_type {
+            NatType::FullCone => true,
+            NatType::AddressRestricted => state
+                .sent_to
+                .iter()
+                .any(|(ext, dst)| *ext == external && dst.ip() == from.ip()),
+            NatType::PortRestricted | NatType::Symmetric => {
+                state.sent_to.contains(&(external, from))
+            }
+        }
+    }
+}
+
+impl DatagramTransport for Nat {
+    fn bind<'a>(
+        &'a self,
+        addr: &'a url::Url,
+    ) -> LocalBoxFuture<'a, anyhow::Result<(InStream, OutStream, SocketAddr)>> {
+        self.bind_nat(addr).boxed_local()
+    }
+}
@@ -1,11 +1,14 @@
 use anyhow::bail;
 use chrono::Utc;
 use futures::channel::mpsc;
+use futures::future::LocalBoxFuture;
 use futures::prelude::*;
 use metrics::counter;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::net::UdpSocket;
 use tokio_util::codec::{Decoder, Encoder};
 
@@ -22,8 +25,81 @@ pub type InStream =
     Pin<Box<dyn Stream<Item = (PacketKind, SocketAddr, chrono::DateTime<chrono::Utc>)>>>;
 pub type OutStream = mpsc::Sender<(PacketKind, SocketAddr)>;
 
+/// Datagram socket the client session layer sends and receives packets through.
+///
+/// Implementations decode incoming datagrams into [`InStream`] and encode packets
+/// taken from [`OutStream`], so the rest of the client doesn't depend on the socket type.
+pub trait DatagramTransport {
+    /// Returns packet streams and the local address of the socket.
+    fn bind<'a>(
+        &'a self,
+        addr: &'a url::Url,
+    ) -> LocalBoxFuture<'a, anyhow::Result<(InStream, OutStream, SocketAddr)>>;
+}
+
+pub type DatagramTransportRef = Rc<dyn DatagramTransport>;
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Called with every socket bound by [`UdpTransport`], before any packet is sent.
+pub type SocketHook = Rc<dyn Fn(&std::net::UdpSocket) -> std::io::Result<()>>;
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Default transport using an OS UDP socket.
+#[derive(Clone, Default)]
+pub struct UdpTransport {
+    hook: Option<SocketHook>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl UdpTransport {
+    /// Runs `hook` on the socket right after binding it. On mobile platforms this is
+    /// the place to exclude the socket from the app's own VPN (`VpnService.protect`
+    /// on Android) or bind it to a particular network. Binding fails if `hook` does.
+    pub fn with_hook(hook: impl Fn(&std::net::UdpSocket) -> std::io::Result<()> + 'static) -> Self {
+        UdpTransport {
+            hook: Some(Rc::new(hook)),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl DatagramTransport for UdpTransport {
+    fn bind<'a>(
+        &'a self,
+        addr: &'a url::Url,
+    ) -> LocalBoxFuture<'a, anyhow::Result<(InStream, OutStream, SocketAddr)>> {
+        match &self.hook {
+            None => udp_bind(addr).boxed_local(),
+            Some(hook) => udp_bind_with_hook(addr, hook.clone()).boxed_local(),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
 pub async fn udp_bind(addr: &url::Url) -> anyhow::Result<(InStream, OutStream, SocketAddr)> {
-    let sock = Arc::new(UdpSocket::bind(&parse_udp_url(addr)?).await?);
+    let sock = UdpSocket::bind(&parse_udp_url(addr)?).await?;
+    udp_streams(sock)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn udp_bind_with_hook(
+    addr: &url::Url,
+    hook: SocketHook,
+) -> anyhow::Result<(InStream, OutStream, SocketAddr)> {
+    let addr = tokio::net::lookup_host(parse_udp_url(addr)?)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Unable to resolve bind address"))?;
+
+    let sock = std::net::UdpSocket::bind(addr)?;
+    hook(&sock)?;
+    sock.set_nonblocking(true)?;
+    udp_streams(UdpSocket::from_std(sock)?)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn udp_streams(sock: UdpSocket) -> anyhow::Result<(InStream, OutStream, SocketAddr)> {
+    let sock = Arc::new(sock);
     let addr = sock.local_addr()?;
 
     log::info!("Server listening on: {}", addr);
@@ -34,6 +110,7 @@ pub async fn udp_bind(addr: &url::Url) -> anyhow::Result<(InStream, OutStream, S
     Ok((stream, sink, addr))
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn udp_stream(
     socket: Arc<UdpSocket>,
 ) -> impl Stream<Item = (PacketKind, SocketAddr, chrono::DateTime<chrono::Utc>)> {
@@ -74,6 +151,7 @@ pub fn udp_stream(
     })
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn udp_sink(socket: Arc<UdpSocket>) -> anyhow::Result<mpsc::Sender<(PacketKind, SocketAddr)>> {
     let (tx, mut rx) = mpsc::channel(100);
 
@@ -35,7 +35,7 @@ where
 {
     let (abort_handle, abort_registration) = AbortHandle::new_pair();
 
-    tokio::task::spawn_local(Abortable::new(future, abort_registration));
+    crate::runtime::spawn_local(Abortable::new(future, abort_registration));
     abort_handle
 }
 
@@ -0,0 +1,151 @@
+//! Relay connection over WebSocket, for peers which can't send UDP datagrams, e.g. browsers.
+//!
+//! Every binary message carries a single datagram of the relay protocol. The relay server
+//! bridges WebSocket connections to its UDP socket (`--ws-listen-addr`), so for the server
+//! such a peer is a Node behind NAT, with the same handshake and sessions. Other Nodes
+//! can't be reached directly, so the client forwards to them through the relay.
+use chrono::Utc;
+use futures::channel::mpsc;
+use futures::future::LocalBoxFuture;
+use futures::prelude::*;
+use std::fmt::Display;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio_util::codec::{Decoder, Encoder};
+use url::Url;
+
+use ya_relay_proto::codec::datagram::Codec;
+use ya_relay_proto::codec::{BytesMut, MAX_PACKET_SIZE};
+
+use crate::udp_stream::{DatagramTransport, InStream, OutStream};
+
+/// Transport sending packets addressed to the relay through a WebSocket connection.
+#[derive(Clone, Debug)]
+pub struct WebSocketTransport {
+    url: Url,
+    relay: SocketAddr,
+}
+
+impl WebSocketTransport {
+    /// `relay` is the address the client is built with. Packets to it go through
+    /// the WebSocket at `url`, packets to other addresses are dropped.
+    pub fn new(url: Url, relay: SocketAddr) -> Self {
+        WebSocketTransport { url, relay }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn connect(&self) -> anyhow::Result<(InStream, OutStream, SocketAddr)> {
+        use tokio_tungstenite::tungstenite::Message;
+        use tokio_tungstenite::MaybeTlsStream;
+
+        let (ws, _) = tokio_tungstenite::connect_async(self.url.as_str()).await?;
+        let local = match ws.get_ref() {
+            MaybeTlsStream::Plain(stream) => stream.local_addr()?,
+            _ => unspecified(),
+        };
+
+        let (sink, stream) = ws.split();
+        let incoming = stream.filter_map(|message| {
+            future::ready(match message {
+                Ok(Message::Binary(data)) => Some(data),
+                Ok(_) => None,
+                Err(e) => {
+                    log::warn!("WebSocket error: {e}");
+                    None
+                }
+            })
+        });
+        let outgoing = sink.with(|data: Vec<u8>| {
+            future::ready(Ok::<_, tokio_tungstenite::tungstenite::Error>(
+                Message::Binary(data),
+            ))
+        });
+        Ok(self.streams(local, incoming, outgoing))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn connect(&self) -> anyhow::Result<(InStream, OutStream, SocketAddr)> {
+        use gloo_net::websocket::futures::WebSocket;
+        use gloo_net::websocket::Message;
+
+        let ws = WebSocket::open(self.url.as_str())
+            .map_err(|e| anyhow::anyhow!("Unable to open WebSocket {}: {e}", self.url))?;
+
+        let (sink, stream) = ws.split();
+        let incoming = stream.filter_map(|message| {
+            future::ready(match message {
+                Ok(Message::Bytes(data)) => Some(data),
+                Ok(Message::Text(_)) => None,
+                Err(e) => {
+                    log::warn!("WebSocket error: {e}");
+                    None
+                }
+            })
+        });
+        let outgoing = sink.with(|data: Vec<u8>| {
+            future::ready(Ok::<_, gloo_net::websocket::WebSocketError>(
+                Message::Bytes(data),
+            ))
+        });
+        Ok(self.streams(unspecified(), incoming, outgoing))
+    }
+
+    fn streams<Si>(
+        &self,
+        local: SocketAddr,
+        incoming: impl Stream<Item = Vec<u8>> + 'static,
+        mut outgoing: Si,
+    ) -> (InStream, OutStream, SocketAddr)
+    where
+        Si: Sink<Vec<u8>> + Unpin + 'static,
+        Si::Error: Display,
+    {
+        let relay = self.relay;
+        log::info!("Connected to relay {relay} over WebSocket {}", self.url);
+
+        let stream = incoming.filter_map(move |data| {
+            let mut buf = BytesMut::from(data.as_slice());
+            future::ready(match Codec.decode(&mut buf) {
+                Ok(Some(packet)) => Some((packet, relay, Utc::now())),
+                Ok(None) => None,
+                Err(e) => {
+                    log::warn!("Failed to decode WebSocket packet. Error: {e}");
+                    None
+                }
+            })
+        });
+
+        let (tx, mut rx) = mpsc::channel(100);
+        crate::runtime::spawn_local(async move {
+            let mut buf = BytesMut::with_capacity(MAX_PACKET_SIZE as usize);
+            while let Some((packet, target)) = rx.next().await {
+                if target != relay {
+                    log::trace!("Dropping packet to {target}, only the relay is reachable");
+                    continue;
+                }
+
+                buf.clear();
+                if let Err(e) = Codec.encode(packet, &mut buf) {
+                    log::warn!("Error encoding packet for: {target}. Error: {e}");
+                } else if let Err(e) = outgoing.send(buf.to_vec()).await {
+                    log::warn!("WebSocket closed: {e}");
+                    break;
+                }
+            }
+        });
+
+        (Box::pin(stream), tx, local)
+    }
+}
+
+impl DatagramTransport for WebSocketTransport {
+    fn bind<'a>(
+        &'a self,
+        _addr: &'a Url,
+    ) -> LocalBoxFuture<'a, anyhow::Result<(InStream, OutStream, SocketAddr)>> {
+        self.connect().boxed_local()
+    }
+}
+
+fn unspecified() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+}
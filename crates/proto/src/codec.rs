@@ -13,6 +13,7 @@ pub mod stream;
 pub const MAX_PACKET_SIZE: u32 = 2097151;
 pub const MAX_PARSE_MESSAGE_SIZE: usize = 600;
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, PartialEq, From)]
 pub enum PacketKind {
     /// Protobuf packet
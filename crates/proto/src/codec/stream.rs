@@ -182,8 +182,10 @@ where
                             self.transition(State::AwaitingPrefix);
                             self.maybe_wake(cx);
                         }
-                        let fwd = crate::proto::Forward::decode(bytes).unwrap();
-                        return Poll::Ready(Some(Ok(PacketKind::Forward(fwd))));
+                        return match crate::proto::Forward::decode(bytes) {
+                            Ok(fwd) => Poll::Ready(Some(Ok(PacketKind::Forward(fwd)))),
+                            Err(err) => Poll::Ready(Some(Err(err.into()))),
+                        };
                     }
                     Err(e) => {
                         self.transition(State::AwaitingPrefix);
@@ -346,6 +348,7 @@ mod tests {
                             vec![0x0b, 0x0e, 0x0a, 0x0d, 0x0b, 0x0e, 0x0e, 0x0f],
                             vec![0x0c, 0x0e, 0x0a, 0x0d, 0x0b, 0x0e, 0x0e, 0x0f],
                         ],
+                        schemes: vec![],
                     }),
                     identities: vec![Identity {
                         node_id: vec![0x0c, 0x00, 0x0f, 0x0f, 0x0e, 0x0e],
@@ -363,6 +366,7 @@ mod tests {
                         address: "1.2.3.4".to_string(),
                         port: 12345,
                     }],
+                    properties: None,
                 },
             )
             .into(),
@@ -416,6 +420,7 @@ mod tests {
                         challenge_resp: Some(ChallengeResponse {
                             solution: vec![0u8; MAX_PACKET_SIZE as usize - 128],
                             signatures: vec![],
+                            schemes: vec![],
                         }),
                         identities: vec![Identity {
                             node_id: vec![0x0c, 0x00, 0x0f, 0x0f, 0x0e, 0x0e],
@@ -431,4 +436,15 @@ mod tests {
 
         assert_eq!(expected, forward(packets.clone(), 5).await);
     }
+
+    #[tokio::test]
+    async fn receive_truncated_forward() {
+        // Size prefix followed by a Forward tag and a malformed header.
+        let data = b"\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\x0c\n";
+        let mut stream = DecoderStream::new(futures::stream::iter(vec![Ok::<_, Error>(
+            BytesMut::from(&data[..]),
+        )]));
+
+        assert!(matches!(stream.next().await, Some(Err(_))));
+    }
 }
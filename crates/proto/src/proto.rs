@@ -5,6 +5,7 @@ use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering::SeqCst;
+use std::sync::OnceLock;
 
 use bytes::BytesMut;
 use prost::encoding::{decode_key, encode_key, WireType};
@@ -24,8 +25,13 @@ pub const SESSION_ID_SIZE: usize = 16;
 pub const KEY_SIZE: usize = 1;
 pub const UNRELIABLE_FLAG: u16 = 0x01;
 pub const ENCRYPTED_FLAG: u16 = 0x02;
+/// Payload ends with a tag authenticating the sender to the relay server.
+pub const AUTHENTICATED_FLAG: u16 = 0x04;
 
 static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+/// Request ids start at a random value, so a restarted process continuing its relay
+/// session doesn't repeat ids of requests, which the relay may still remember.
+static REQUEST_ID_BASE: OnceLock<u64> = OnceLock::new();
 
 pub type RequestId = u64;
 pub type SlotId = u32;
@@ -290,7 +296,7 @@ impl Packet {
                 code: code.into(),
                 // Probably we should send here packet response type matching request that we got.
                 // We send at least anything, because client doesn't handle errors with None here.
-                kind: Some(response::Kind::Pong(response::Pong {})),
+                kind: Some(response::Kind::Pong(response::Pong::default())),
             })),
         }
     }
@@ -311,7 +317,9 @@ where
 {
     fn from(t: T) -> Self {
         Request {
-            request_id: REQUEST_ID.fetch_add(1, SeqCst),
+            request_id: REQUEST_ID_BASE
+                .get_or_init(rand::random)
+                .wrapping_add(REQUEST_ID.fetch_add(1, SeqCst)),
             kind: Some(t.into()),
         }
     }
@@ -328,6 +336,16 @@ impl TryFrom<Endpoint> for SocketAddr {
     }
 }
 
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint {
+            protocol: Protocol::Udp.into(),
+            address: addr.ip().to_string(),
+            port: addr.port().into(),
+        }
+    }
+}
+
 macro_rules! impl_convert_kind {
     ($module:ident, $ident:ident) => {
         impl From<$crate::proto::$module::$ident> for $crate::proto::$module::Kind {
@@ -356,18 +374,36 @@ impl_convert_kind!(request, Register);
 impl_convert_kind!(request, Node);
 impl_convert_kind!(request, Slot);
 impl_convert_kind!(request, Neighbours);
+impl_convert_kind!(request, FindNodes);
+impl_convert_kind!(request, Nearest);
+impl_convert_kind!(request, Locate);
 impl_convert_kind!(request, ReverseConnection);
+impl_convert_kind!(request, NatCheck);
+impl_convert_kind!(request, JoinGroup);
+impl_convert_kind!(request, LeaveGroup);
 impl_convert_kind!(request, Ping);
+impl_convert_kind!(request, EdgeSummary);
 
 impl_convert_kind!(response, Session);
 impl_convert_kind!(response, Register);
 impl_convert_kind!(response, Node);
 impl_convert_kind!(response, Neighbours);
+impl_convert_kind!(response, FindNodes);
+impl_convert_kind!(response, Nearest);
+impl_convert_kind!(response, Locate);
 impl_convert_kind!(response, ReverseConnection);
+impl_convert_kind!(response, NatCheck);
+impl_convert_kind!(response, JoinGroup);
+impl_convert_kind!(response, LeaveGroup);
 impl_convert_kind!(response, Pong);
+impl_convert_kind!(response, EdgeSummary);
 
 impl_convert_kind!(control, ReverseConnection);
 impl_convert_kind!(control, PauseForwarding);
 impl_convert_kind!(control, ResumeForwarding);
 impl_convert_kind!(control, StopForwarding);
 impl_convert_kind!(control, Disconnected);
+impl_convert_kind!(control, Congestion);
+impl_convert_kind!(control, Maintenance);
+impl_convert_kind!(control, ForwardRejected);
+impl_convert_kind!(control, ForwardToGroup);
@@ -2,7 +2,7 @@ use derive_more::Display;
 use smoltcp::iface::SocketHandle;
 use smoltcp::socket::*;
 use smoltcp::wire::IpEndpoint;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::convert::TryFrom;
 use std::future::Future;
 use std::net::SocketAddr;
@@ -233,6 +233,8 @@ pub struct Send<'a> {
     iface: Rc<RefCell<CaptureInterface<'a>>>,
     /// Send completion callback; there may as well have been no data sent
     sent: Box<dyn Fn()>,
+    /// Bytes written to the socket, shared by all sends through the connection.
+    written: Rc<Cell<u64>>,
 }
 
 impl<'a> Send<'a> {
@@ -241,6 +243,7 @@ impl<'a> Send<'a> {
         connection: Connection,
         iface: Rc<RefCell<CaptureInterface<'a>>>,
         sent: F,
+        written: Rc<Cell<u64>>,
     ) -> Self {
         log::trace!("[Send::new]: {:?}", connection);
         Self {
@@ -249,6 +252,7 @@ impl<'a> Send<'a> {
             connection,
             iface,
             sent: Box::new(sent),
+            written,
         }
     }
 }
@@ -282,6 +286,7 @@ impl<'a> Future for Send<'a> {
                     return match result {
                         Ok(count) => {
                             self.offset += count;
+                            self.written.set(self.written.get() + count as u64);
                             if self.offset >= self.data.len() {
                                 Poll::Ready(Ok(()))
                             } else {
@@ -54,6 +54,8 @@ pub enum Error {
     ConnectionError(String),
     #[error("Connection timed out")]
     ConnectionTimeout,
+    #[error("Deadline for sending data passed")]
+    Expired,
     #[error("Forbidden")]
     Forbidden,
     #[error("Cancelled")]
@@ -74,3 +76,9 @@ impl From<SendError> for Error {
         Self::Cancelled
     }
 }
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for Error {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        Self::Cancelled
+    }
+}
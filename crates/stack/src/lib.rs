@@ -21,7 +21,7 @@ pub use network::{
 pub use port::Allocator as PortAllocator;
 pub use protocol::Protocol;
 pub use smoltcp;
-pub use socket::{SocketDesc, SocketState};
+pub use socket::{SocketDesc, SocketState, TcpProgress, TcpSocketInfo, TcpTuning};
 pub use stack::Stack;
 
 pub type Result<T> = std::result::Result<T, Error>;
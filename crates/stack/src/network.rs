@@ -1,28 +1,33 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use futures::channel::mpsc;
 use futures::future::{Either, LocalBoxFuture};
-use futures::{Future, FutureExt, SinkExt, StreamExt, TryFutureExt};
+use futures::{Future, FutureExt, TryFutureExt};
 use smoltcp::iface::SocketHandle;
+use smoltcp::socket::Socket;
 use smoltcp::wire::IpEndpoint;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::Notify;
 use tokio::task::spawn_local;
 use tokio::time::MissedTickBehavior;
 
-use crate::connection::{Connection, ConnectionMeta};
+use crate::connection::{Connection, ConnectionMeta, Disconnect};
 use crate::packet::{
     ip_ntoh, ArpField, ArpPacket, EtherFrame, IpPacket, PeekPacket, TcpPacket, UdpPacket,
 };
 use crate::protocol::Protocol;
-use crate::socket::{SocketDesc, SocketEndpoint, SocketExt, SocketMemory, SocketState};
+use crate::socket::{
+    SocketDesc, SocketEndpoint, SocketExt, SocketMemory, SocketState, TcpProgress, TcpSocketExt,
+    TcpSocketInfo, TcpTuning,
+};
 use crate::stack::Stack;
 use crate::{ChannelMetrics, Error, Result};
 
+pub use ya_relay_util::Channel;
 use ya_relay_util::Payload;
 
 pub const PCAP_FILE_ENV_VAR: &str = "YA_NET_PCAP_FILE";
@@ -94,6 +99,7 @@ pub struct Network {
     pub bindings: Rc<RefCell<HashSet<SocketHandle>>>,
     pub connections: Rc<RefCell<HashMap<ConnectionMeta, Connection>>>,
     pub handles: Rc<RefCell<HashMap<SocketHandle, ConnectionMeta>>>,
+    recv_paused: Rc<Cell<bool>>,
     ingress: Channel<IngressEvent>,
     egress: Channel<EgressEvent>,
 }
@@ -117,6 +123,7 @@ impl Network {
             bindings: Default::default(),
             connections: Default::default(),
             handles: Default::default(),
+            recv_paused: Default::default(),
             ingress: Default::default(),
             egress: Default::default(),
         };
@@ -173,11 +180,21 @@ impl Network {
         &self,
         remote: impl Into<IpEndpoint>,
         timeout: impl Into<Duration>,
+    ) -> LocalBoxFuture<Result<Connection>> {
+        self.connect_tuned(remote, timeout, None)
+    }
+
+    /// Initiate a TCP connection with socket settings other than the configured ones
+    pub fn connect_tuned(
+        &self,
+        remote: impl Into<IpEndpoint>,
+        timeout: impl Into<Duration>,
+        tuning: Option<TcpTuning>,
     ) -> LocalBoxFuture<Result<Connection>> {
         let remote = remote.into();
         let timeout = timeout.into();
 
-        let connect = match self.stack.connect(remote) {
+        let connect = match self.stack.connect_tuned(remote, tuning) {
             Ok(fut) => fut,
             Err(err) => return futures::future::err(err).boxed_local(),
         };
@@ -215,14 +232,32 @@ impl Network {
                 .map(|conn| (conn.handle, self.stack.disconnect(conn.handle)))
                 .unzip()
         };
+        self.await_disconnect(handles, futs, timeout.into())
+    }
 
+    /// Close a single TCP connection
+    pub fn disconnect(
+        &self,
+        connection: Connection,
+        timeout: impl Into<Duration>,
+    ) -> LocalBoxFuture<()> {
+        let handle = connection.handle;
+        let fut = self.stack.disconnect(handle);
+        self.await_disconnect(vec![handle], vec![fut], timeout.into())
+    }
+
+    fn await_disconnect(
+        &self,
+        handles: Vec<SocketHandle>,
+        futs: Vec<Disconnect<'static>>,
+        timeout: Duration,
+    ) -> LocalBoxFuture<'static, ()> {
         if futs.is_empty() {
             return futures::future::ready(()).boxed_local();
         }
 
         self.poll();
 
-        let timeout = timeout.into();
         let net = self.clone();
 
         async move {
@@ -291,6 +326,25 @@ impl Network {
             .collect()
     }
 
+    /// All TCP sockets, including listening ones.
+    pub fn tcp_sockets(&self) -> Vec<(SocketDesc, TcpSocketInfo)> {
+        let iface_rfc = self.stack.iface();
+        let iface = iface_rfc.borrow();
+
+        iface
+            .sockets()
+            .filter_map(|(_, s)| match s {
+                Socket::Tcp(tcp) => Some((s.desc(), tcp.info())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Sending progress of a TCP connection, see [`Stack::tcp_progress`].
+    pub fn tcp_progress(&self, connection: &Connection) -> Option<TcpProgress> {
+        self.stack.tcp_progress(connection.handle)
+    }
+
     pub fn metrics(&self) -> ChannelMetrics {
         let iface_rfc = self.stack.iface();
         let iface = iface_rfc.borrow();
@@ -338,7 +392,25 @@ impl Network {
         data: impl Into<Payload>,
         connection: Connection,
     ) -> impl Future<Output = Result<()>> + 'a {
-        self.sender.send(data.into(), connection)
+        self.sender.send(data.into(), connection, None)
+    }
+
+    /// Same as [`Network::send`], but gives up with [`Error::Expired`] if `data` can't be
+    /// queued before `deadline`. Data already queued is dropped, if the socket doesn't
+    /// start sending it in time.
+    #[inline(always)]
+    pub fn send_until<'a>(
+        &self,
+        data: impl Into<Payload>,
+        connection: Connection,
+        deadline: Instant,
+    ) -> impl Future<Output = Result<()>> + 'a {
+        self.sender.send(data.into(), connection, Some(deadline))
+    }
+
+    /// Number of queued payloads dropped, because their deadline passed.
+    pub fn expired(&self) -> usize {
+        self.sender.expired.get()
     }
 
     /// Inject received data into the stack
@@ -358,6 +430,32 @@ impl Network {
         self.poller.clone().spawn(Duration::from_millis(interval));
     }
 
+    /// Stops polling the stack periodically, so timers like retransmissions and TCP
+    /// keep-alive don't fire. Received and sent packets are still processed.
+    pub fn set_poll_paused(&self, paused: bool) {
+        if self.poller.paused.replace(paused) && !paused {
+            self.poller.resumed.notify_waiters();
+            // Catch up with timers, which expired while paused.
+            self.poll();
+        }
+    }
+
+    pub fn is_poll_paused(&self) -> bool {
+        self.poller.paused.get()
+    }
+
+    /// Stops reading received data from sockets. Data is kept in receive buffers of the
+    /// sockets, so TCP windows shrink and remote ends stop sending, once buffers are full.
+    pub fn set_recv_paused(&self, paused: bool) {
+        if self.recv_paused.replace(paused) && !paused {
+            self.poll();
+        }
+    }
+
+    pub fn is_recv_paused(&self) -> bool {
+        self.recv_paused.get()
+    }
+
     /// Polls the inner network stack
     pub fn poll(&self) {
         loop {
@@ -392,6 +490,7 @@ impl Network {
         let mut events = Vec::new();
         let mut remove = Vec::new();
         let mut rebind = None;
+        let recv_paused = self.recv_paused.get();
 
         for (handle, socket) in iface.sockets_mut() {
             let mut desc = socket.desc();
@@ -426,7 +525,7 @@ impl Network {
 
             let mut received = 0;
 
-            while socket.can_recv() {
+            while !recv_paused && socket.can_recv() {
                 let (remote, payload) = match socket.recv() {
                     Ok(Some(tuple)) => tuple,
                     Ok(None) => break,
@@ -639,16 +738,20 @@ impl EgressEvent {
 struct StackSender {
     inner: Rc<RefCell<StackSenderInner>>,
     net: Rc<RefCell<Option<Network>>>,
+    expired: Rc<Cell<usize>>,
 }
 
+type Queued = (Payload, Connection, Option<Instant>);
+
 impl StackSender {
     #[inline]
     pub fn send<'a>(
         &self,
         data: Payload,
         conn: Connection,
+        deadline: Option<Instant>,
     ) -> impl Future<Output = Result<()>> + 'a {
-        let mut sender = {
+        let sender = {
             match {
                 let inner = self.inner.borrow();
                 inner.map.get(&conn.handle).cloned()
@@ -657,22 +760,34 @@ impl StackSender {
                 None => self.spawn(conn.handle),
             }
         };
-        async move { sender.send((data, conn)).map_err(Error::from).await }
+        async move {
+            let send = sender.send((data, conn, deadline)).map_err(Error::from);
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline.into(), send)
+                    .await
+                    .map_err(|_| Error::Expired)?,
+                None => send.await,
+            }
+        }
     }
 
-    fn spawn(&self, handle: SocketHandle) -> mpsc::Sender<(Payload, Connection)> {
+    fn spawn(&self, handle: SocketHandle) -> mpsc::Sender<Queued> {
         let net = self.net.borrow().clone().expect("Network not initialized");
-        let (tx, rx) = mpsc::channel(1);
+        let expired = self.expired.clone();
+        let (tx, mut rx) = mpsc::channel::<Queued>(1);
 
         spawn_local(async move {
-            rx.for_each(|(vec, conn)| {
-                let net = net.clone();
-                let stack = net.stack.clone();
-                async move {
-                    let _ = stack.send(vec, conn, move || net.poll()).await;
+            while let Some((vec, conn, deadline)) = rx.recv().await {
+                // Payload partially written to the socket can't be dropped without
+                // breaking the stream, so the deadline is only checked up to this point.
+                if deadline.map_or(false, |deadline| deadline <= Instant::now()) {
+                    log::debug!("{}: dropping expired {} B payload", net.name, vec.len());
+                    expired.set(expired.get() + 1);
+                    continue;
                 }
-            })
-            .await;
+                let poll = net.clone();
+                let _ = net.stack.send(vec, conn, move || poll.poll()).await;
+            }
         });
 
         let mut inner = self.inner.borrow_mut();
@@ -683,22 +798,21 @@ impl StackSender {
 
     pub fn remove(&self, handle: &SocketHandle) {
         let mut inner = self.inner.borrow_mut();
-        if let Some(mut tx) = inner.map.remove(handle) {
-            spawn_local(async move {
-                let _ = tx.close().await;
-            });
-        }
+        // Queue task of the socket exits after sending payloads queued so far.
+        inner.map.remove(handle);
     }
 }
 
 #[derive(Default)]
 struct StackSenderInner {
-    map: HashMap<SocketHandle, mpsc::Sender<(Payload, Connection)>>,
+    map: HashMap<SocketHandle, mpsc::Sender<Queued>>,
 }
 
 #[derive(Clone, Default)]
 struct StackPoller {
     net: Rc<RefCell<Option<Network>>>,
+    paused: Rc<Cell<bool>>,
+    resumed: Rc<Notify>,
 }
 
 impl StackPoller {
@@ -709,34 +823,15 @@ impl StackPoller {
             interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
             loop {
                 interval.tick().await;
+                while poller.paused.get() {
+                    poller.resumed.notified().await;
+                }
                 poller.net.borrow().as_ref().unwrap().poll();
             }
         });
     }
 }
 
-#[derive(Clone)]
-pub struct Channel<T> {
-    pub tx: UnboundedSender<T>,
-    rx: Rc<RefCell<Option<UnboundedReceiver<T>>>>,
-}
-
-impl<T> Channel<T> {
-    pub fn receiver(&self) -> Option<UnboundedReceiver<T>> {
-        self.rx.borrow_mut().take()
-    }
-}
-
-impl<T> Default for Channel<T> {
-    fn default() -> Self {
-        let (tx, rx) = unbounded_channel();
-        Self {
-            tx,
-            rx: Rc::new(RefCell::new(Some(rx))),
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use std::fmt::Debug;
@@ -63,6 +63,39 @@ impl SocketDesc {
     }
 }
 
+/// Progress of sending data over a TCP socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TcpProgress {
+    pub state: tcp::State,
+    /// Bytes written to the socket since it was created.
+    pub written: u64,
+    /// Bytes written, but not acknowledged by the remote end yet.
+    pub queued: usize,
+}
+
+impl TcpProgress {
+    /// Bytes acknowledged by the remote end.
+    pub fn acked(&self) -> u64 {
+        self.written.saturating_sub(self.queued as u64)
+    }
+}
+
+/// State, buffers and timers of a TCP socket, much like `ss -t` shows for kernel sockets.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TcpSocketInfo {
+    pub state: tcp::State,
+    /// Bytes received, but not read by the application yet.
+    pub recv_queue: usize,
+    pub recv_capacity: usize,
+    /// Bytes written, but not acknowledged by the remote end yet.
+    pub send_queue: usize,
+    pub send_capacity: usize,
+    pub timeout: Option<Duration>,
+    pub keep_alive: Option<Duration>,
+    pub ack_delay: Option<Duration>,
+    pub nagle: bool,
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SocketState<T> {
     Tcp { state: tcp::State, inner: T },
@@ -406,6 +439,8 @@ impl<'a> SocketExt for Socket<'a> {
 
 pub trait TcpSocketExt {
     fn set_defaults(&mut self);
+    fn set_tuning(&mut self, tuning: &TcpTuning);
+    fn info(&self) -> TcpSocketInfo;
 }
 
 impl<'a> TcpSocketExt for tcp::Socket<'a> {
@@ -415,6 +450,27 @@ impl<'a> TcpSocketExt for tcp::Socket<'a> {
         self.set_keep_alive(*TCP_KEEP_ALIVE);
         self.set_ack_delay(*TCP_ACK_DELAY);
     }
+
+    fn set_tuning(&mut self, tuning: &TcpTuning) {
+        self.set_nagle_enabled(tuning.nagle);
+        self.set_timeout(tuning.timeout);
+        self.set_keep_alive(tuning.keep_alive);
+        self.set_ack_delay(tuning.ack_delay);
+    }
+
+    fn info(&self) -> TcpSocketInfo {
+        TcpSocketInfo {
+            state: self.state(),
+            recv_queue: self.recv_queue(),
+            recv_capacity: self.recv_capacity(),
+            send_queue: self.send_queue(),
+            send_capacity: self.send_capacity(),
+            timeout: self.timeout(),
+            keep_alive: self.keep_alive(),
+            ack_delay: self.ack_delay(),
+            nagle: self.nagle_enabled(),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -447,6 +503,18 @@ impl SocketMemory {
     }
 }
 
+/// Settings of a single TCP socket, used instead of the stack defaults.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpTuning {
+    pub mem: SocketMemory,
+    pub nagle: bool,
+    /// Time without an acknowledgement, after which retransmitting stops and
+    /// the connection is aborted.
+    pub timeout: Option<Duration>,
+    pub keep_alive: Option<Duration>,
+    pub ack_delay: Option<Duration>,
+}
+
 /// Buffer size bounds used in auto tuning.
 /// Currently, only `max` is used; other values are reserved for future use
 #[derive(Clone, Copy, Debug)]
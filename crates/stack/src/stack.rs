@@ -1,4 +1,4 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::rc::Rc;
 
@@ -22,6 +22,8 @@ use ya_relay_util::Payload;
 pub struct Stack<'a> {
     iface: Rc<RefCell<CaptureInterface<'a>>>,
     metrics: Rc<RefCell<HashMap<SocketDesc, ChannelMetrics>>>,
+    /// Bytes written to each TCP socket, for telling acknowledged data from queued.
+    written: Rc<RefCell<HashMap<SocketHandle, Rc<Cell<u64>>>>>,
     ports: Rc<RefCell<port::Allocator>>,
     config: Rc<StackConfig>,
 }
@@ -31,6 +33,7 @@ impl<'a> Stack<'a> {
         Self {
             iface: Rc::new(RefCell::new(iface)),
             metrics: Default::default(),
+            written: Default::default(),
             ports: Default::default(),
             config,
         }
@@ -170,13 +173,25 @@ impl<'a> Stack<'a> {
     }
 
     pub fn connect(&self, remote: IpEndpoint) -> Result<Connect<'a>> {
+        self.connect_tuned(remote, None)
+    }
+
+    /// Same as [`Stack::connect`], with socket settings other than the configured ones.
+    pub fn connect_tuned(
+        &self,
+        remote: IpEndpoint,
+        tuning: Option<TcpTuning>,
+    ) -> Result<Connect<'a>> {
         let ip = self.address()?.address();
+        let mem = tuning
+            .map(|tuning| tuning.mem)
+            .unwrap_or(self.config.tcp_mem);
 
         let mut iface = self.iface.borrow_mut();
         let mut ports = self.ports.borrow_mut();
 
         let protocol = Protocol::Tcp;
-        let handle = iface.add_socket(tcp_socket(self.config.tcp_mem.rx, self.config.tcp_mem.tx));
+        let handle = iface.add_socket(tcp_socket(mem.rx, mem.tx));
         let port = ports.next(protocol)?;
         let local: IpEndpoint = (ip, port).into();
 
@@ -184,9 +199,10 @@ impl<'a> Stack<'a> {
             let (socket, ctx) = iface.get_socket_and_context::<tcp::Socket>(handle);
             socket.connect(ctx, remote, local).map(|_| socket)
         } {
-            Ok(socket) => {
-                socket.set_defaults();
-            }
+            Ok(socket) => match tuning {
+                Some(tuning) => socket.set_tuning(&tuning),
+                None => socket.set_defaults(),
+            },
             Err(e) => {
                 log::error!("connection error: {:?}", e);
                 iface.remove_socket(handle);
@@ -235,6 +251,7 @@ impl<'a> Stack<'a> {
             log::trace!("Removing connection: {meta}. Socket handle: {handle}");
 
             metrics.remove(&socket.desc());
+            self.written.borrow_mut().remove(&handle);
             iface.remove_socket(handle);
             ports.free(meta.protocol, meta.local.port);
         }
@@ -247,7 +264,32 @@ impl<'a> Stack<'a> {
         conn: Connection,
         f: F,
     ) -> Send<'a> {
-        Send::new(data.into(), conn, self.iface.clone(), f)
+        let written = self
+            .written
+            .borrow_mut()
+            .entry(conn.handle)
+            .or_default()
+            .clone();
+        Send::new(data.into(), conn, self.iface.clone(), f, written)
+    }
+
+    /// Sending progress of the TCP socket. `None` if there is no such TCP socket.
+    pub fn tcp_progress(&self, handle: SocketHandle) -> Option<TcpProgress> {
+        let iface = self.iface.borrow();
+        let socket = match iface.sockets().find(|(h, _)| *h == handle)? {
+            (_, Socket::Tcp(socket)) => socket,
+            _ => return None,
+        };
+        let written = self
+            .written
+            .borrow()
+            .get(&handle)
+            .map_or(0, |written| written.get());
+        Some(TcpProgress {
+            state: socket.state(),
+            written,
+            queued: socket.send_queue(),
+        })
     }
 
     #[inline]
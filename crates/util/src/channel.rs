@@ -0,0 +1,27 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Unbounded channel, which receiver can be taken only once.
+#[derive(Clone)]
+pub struct Channel<T> {
+    pub tx: UnboundedSender<T>,
+    rx: Rc<RefCell<Option<UnboundedReceiver<T>>>>,
+}
+
+impl<T> Channel<T> {
+    pub fn receiver(&self) -> Option<UnboundedReceiver<T>> {
+        self.rx.borrow_mut().take()
+    }
+}
+
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        let (tx, rx) = unbounded_channel();
+        Self {
+            tx,
+            rx: Rc::new(RefCell::new(Some(rx))),
+        }
+    }
+}
@@ -1,3 +1,7 @@
+#[cfg(feature = "channel")]
+mod channel;
 mod payload;
 
+#[cfg(feature = "channel")]
+pub use channel::Channel;
 pub use payload::Payload;
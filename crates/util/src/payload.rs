@@ -34,6 +34,14 @@ impl Payload {
         }
     }
 
+    #[inline]
+    pub fn truncate(&mut self, len: usize) {
+        match self {
+            Self::BytesMut(b) => b.truncate(len),
+            Self::Vec(b) => b.truncate(len),
+        }
+    }
+
     pub fn extend(&mut self, bytes: BytesMut) {
         match std::mem::take(self) {
             Self::BytesMut(mut b) => {
@@ -175,6 +183,49 @@ impl Iterator for IntoIter {
     }
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for Payload {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.as_ref())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Payload {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PayloadVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for PayloadVisitor {
+            type Value = Payload;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a byte array")
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Payload, E> {
+                Ok(Payload::Vec(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Payload, E> {
+                Ok(Payload::Vec(v))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Payload, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(Payload::Vec(bytes))
+            }
+        }
+
+        deserializer.deserialize_bytes(PayloadVisitor)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Payload;
@@ -199,4 +250,13 @@ mod tests {
         payload.prepend(&[1, 2, 3]);
         assert_eq!(&[1, 2, 3, 4, 5, 6, 7, 8], payload.as_ref());
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let payload = Payload::BytesMut(BytesMut::from_iter(1..=4u8));
+        let json = serde_json::to_string(&payload).unwrap();
+        assert_eq!(json, "[1,2,3,4]");
+        assert_eq!(serde_json::from_str::<Payload>(&json).unwrap(), payload);
+    }
 }
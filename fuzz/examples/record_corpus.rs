@@ -0,0 +1,75 @@
+//! Records traffic of a test server and two clients into seed corpora.
+//!
+//! ```sh
+//! cargo run --example record_corpus
+//! ```
+//!
+//! Client to server datagrams seed `server_dispatch`, datagrams from both
+//! directions seed `proto_datagram`.
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::network::{Direction, NetworkConditions, NetworkSimulator};
+use ya_relay_server::testing::server::TestServerBuilder;
+
+fn corpus_dir(target: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("corpus")
+        .join(target)
+}
+
+fn save(dir: &Path, data: &[u8]) {
+    let name = hex::encode(Sha256::digest(data));
+    std::fs::write(dir.join(name), data).expect("failed to write corpus entry");
+}
+
+#[actix_rt::main]
+async fn main() -> anyhow::Result<()> {
+    let dispatch = Arc::new(corpus_dir("server_dispatch"));
+    let datagram = Arc::new(corpus_dir("proto_datagram"));
+    std::fs::create_dir_all(dispatch.as_ref())?;
+    std::fs::create_dir_all(datagram.as_ref())?;
+
+    let wrapper = TestServerBuilder::new().build().await?;
+    let network =
+        NetworkSimulator::start(wrapper.server.bind_addr(), NetworkConditions::default()).await?;
+    network.set_tap(move |direction, data| {
+        if direction == Direction::ClientToServer {
+            save(&dispatch, data);
+        }
+        save(&datagram, data);
+    });
+
+    let client1 = ClientBuilder::from_url(network.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(network.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    // Relay all traffic through the server.
+    for client in [&client1, &client2] {
+        wrapper.remove_node_endpoints(client.node_id()).await;
+        client.set_public_addr(None).await;
+    }
+
+    client1.neighbours(5).await?;
+    client1.find_node(client2.node_id()).await?;
+    client1.ping_sessions().await;
+
+    let mut unreliable = client1.forward_unreliable(client2.node_id()).await?;
+    unreliable.send(vec![1u8; 16].into()).await?;
+    let mut reliable = client2.forward_reliable(client1.node_id()).await?;
+    reliable.send(vec![2u8; 512].into()).await?;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    println!("Recorded {:?}", network.stats());
+    Ok(())
+}
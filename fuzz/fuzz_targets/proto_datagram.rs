@@ -0,0 +1,30 @@
+//! Decodes a single UDP datagram, the way both the server and clients do.
+//! Successfully decoded packets have to survive an encode-decode roundtrip.
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::{Decoder, Encoder};
+
+use ya_relay_proto::codec::datagram::Codec;
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = Codec;
+    let mut buf = BytesMut::from(data);
+
+    if let Ok(Some(packet)) = codec.decode(&mut buf) {
+        let mut encoded = BytesMut::new();
+        codec
+            .encode(packet.clone(), &mut encoded)
+            .expect("decoded packet failed to encode");
+        if encoded.is_empty() {
+            // Packet with all fields set to defaults, not a valid datagram on its own.
+            return;
+        }
+
+        let decoded = codec
+            .decode(&mut encoded)
+            .expect("encoded packet failed to decode");
+        assert_eq!(decoded, Some(packet));
+    }
+});
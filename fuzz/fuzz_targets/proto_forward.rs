@@ -0,0 +1,14 @@
+//! Decodes length prefixed messages carried by reliable Forward channels.
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+use ya_relay_proto::codec::forward;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    while let Ok(message) = forward::decode(&mut buf) {
+        assert!(message.len() <= data.len());
+    }
+});
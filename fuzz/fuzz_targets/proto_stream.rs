@@ -0,0 +1,25 @@
+//! Decodes a TCP-like byte stream, split into chunks of arbitrary sizes.
+//! The first input byte selects the chunk size.
+#![no_main]
+
+use bytes::BytesMut;
+use futures::StreamExt;
+use libfuzzer_sys::fuzz_target;
+
+use ya_relay_proto::codec::stream::DecoderStream;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((chunk_size, data)) = data.split_first() else {
+        return;
+    };
+    let chunk_size = (*chunk_size as usize).max(1);
+
+    let chunks = data
+        .chunks(chunk_size)
+        .map(|chunk| Ok::<_, std::io::Error>(BytesMut::from(chunk)))
+        .collect::<Vec<_>>();
+    let mut stream = DecoderStream::new(futures::stream::iter(chunks));
+
+    // Framing errors leave the stream out of sync, consumers drop it on the first one.
+    futures::executor::block_on(async { while let Some(Ok(_)) = stream.next().await {} });
+});
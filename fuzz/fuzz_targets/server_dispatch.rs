@@ -0,0 +1,111 @@
+//! Sends arbitrary datagrams to a test server and checks that it keeps serving
+//! well-formed requests afterwards.
+//!
+//! All inputs are sent from the same address, so later inputs can hit sessions
+//! created by the earlier ones.
+#![no_main]
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+use libfuzzer_sys::fuzz_target;
+use tokio::net::UdpSocket;
+
+use ya_relay_core::challenge::prepare_challenge_request;
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_core::utils::parse_udp_url;
+use ya_relay_proto::codec::datagram::Codec;
+use ya_relay_proto::codec::{BytesMut, PacketKind};
+use ya_relay_proto::proto;
+use ya_relay_server::testing::server::{ServerWrapper, TestServerBuilder};
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Harness {
+    runtime: actix_rt::SystemRunner,
+    _server: ServerWrapper,
+    socket: UdpSocket,
+    probe: Vec<u8>,
+}
+
+impl Harness {
+    fn new() -> Harness {
+        let runtime = actix_rt::System::new();
+        let (server, socket) = runtime.block_on(async {
+            let server = TestServerBuilder::new()
+                .ip_check(Duration::from_millis(10), 0, Duration::from_millis(10))
+                .build()
+                .await
+                .expect("failed to start server");
+            let addr = parse_udp_url(&server.url()).unwrap();
+
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.connect(addr).await.unwrap();
+            (server, socket)
+        });
+
+        Harness {
+            runtime,
+            _server: server,
+            socket,
+            probe: probe_packet(),
+        }
+    }
+
+    fn run(&self, data: &[u8]) {
+        self.runtime.block_on(async {
+            let _ = self.socket.send(data).await;
+
+            // Packets from a single address are processed in order, so the response
+            // to the probe proves that the fuzzed packet didn't bring the server down.
+            self.socket.send(&self.probe).await.unwrap();
+
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let size = tokio::time::timeout(RESPONSE_TIMEOUT, self.socket.recv(&mut buf))
+                    .await
+                    .expect("server stopped responding")
+                    .unwrap();
+                if is_probe_response(&buf[..size]) {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Session request without a session id, answered with a challenge.
+fn probe_packet() -> Vec<u8> {
+    let (request, _) = prepare_challenge_request(1);
+    let packet = proto::Packet::request(vec![], request);
+    let mut buf = BytesMut::new();
+    tokio_util::codec::Encoder::encode(&mut Codec, PacketKind::Packet(packet), &mut buf).unwrap();
+    buf.to_vec()
+}
+
+fn is_probe_response(data: &[u8]) -> bool {
+    let mut buf = BytesMut::from(data);
+    matches!(
+        tokio_util::codec::Decoder::decode(&mut Codec, &mut buf),
+        Ok(Some(PacketKind::Packet(proto::Packet {
+            kind: Some(proto::packet::Kind::Response(proto::Response {
+                kind: Some(proto::response::Kind::Session(_)),
+                ..
+            })),
+            ..
+        })))
+    )
+}
+
+thread_local! {
+    static HARNESS: RefCell<Option<Harness>> = const { RefCell::new(None) };
+}
+
+fuzz_target!(|data: &[u8]| {
+    HARNESS.with(|harness| {
+        harness
+            .borrow_mut()
+            .get_or_insert_with(Harness::new)
+            .run(data);
+    });
+});
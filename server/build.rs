@@ -0,0 +1,15 @@
+fn main() {
+    #[cfg(feature = "grpc-admin")]
+    {
+        if std::env::var("PROTOC").is_err() {
+            let (protoc_bin, _) = protoc_prebuilt::init("24.0").unwrap();
+            std::env::set_var("PROTOC", protoc_bin);
+        }
+
+        println!("cargo:rerun-if-changed=protobuf/admin.proto");
+        tonic_build::configure()
+            .protoc_arg("--experimental_allow_proto3_optional")
+            .compile(&["protobuf/admin.proto"], &["protobuf/"])
+            .unwrap();
+    }
+}
@@ -129,6 +129,9 @@ async fn main() -> anyhow::Result<()> {
                                                     challenge_resp: _,
                                                     supported_encryptions: _,
                                                     identities: _,
+                                                    server_signature: _,
+                                                    key_exchange: _,
+                                                    ..
                                                 })),
                                         })),
                                 } => {
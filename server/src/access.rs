@@ -0,0 +1,191 @@
+//! Access control of the HTTP endpoints exposing metrics and relay topology.
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+
+/// IP network in CIDR notation. A plain address matches only itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack sockets report IPv4 peers as mapped IPv6 addresses.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let bytes = prefix as usize / 8;
+    let bits = prefix % 8;
+    if net[..bytes] != ip[..bytes] {
+        return false;
+    }
+    if bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - bits);
+    net[bytes] & mask == ip[bytes] & mask
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            bail!("invalid prefix length /{prefix} of {addr}");
+        }
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// Peer address isn't allowed.
+    Forbidden,
+    /// Missing or wrong bearer token.
+    Unauthorized,
+}
+
+/// Requests have to come from an allowed network and carry the token, if any is set.
+#[derive(Clone, Default)]
+pub struct AccessPolicy {
+    pub token: Option<String>,
+    /// Empty allows all addresses.
+    pub allow: Vec<IpNetwork>,
+}
+
+impl AccessPolicy {
+    pub fn new(token: Option<String>, allow: Vec<IpNetwork>) -> Self {
+        AccessPolicy { token, allow }
+    }
+
+    /// Checks a request from `peer` with the value of its `Authorization` header.
+    pub fn check(&self, peer: Option<IpAddr>, authorization: Option<&str>) -> Result<(), Denied> {
+        if !self.allow.is_empty() {
+            match peer {
+                Some(ip) if self.allow.iter().any(|net| net.contains(ip)) => (),
+                _ => return Err(Denied::Forbidden),
+            }
+        }
+        if let Some(token) = &self.token {
+            let given = authorization
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or(Denied::Unauthorized)?;
+            if !constant_time_eq(given.trim().as_bytes(), token.as_bytes()) {
+                return Err(Denied::Unauthorized);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for AccessPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessPolicy")
+            .field("token", &self.token.as_ref().map(|_| "***"))
+            .field("allow", &self.allow)
+            .finish()
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub(crate) fn parse_token(s: &str) -> anyhow::Result<String> {
+    if s.is_empty() || s.chars().any(char::is_whitespace) {
+        return Err(anyhow!("token can't be empty or contain whitespace"));
+    }
+    Ok(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_ip_network() {
+        let net: IpNetwork = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.200.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.1".parse().unwrap()));
+
+        let net: IpNetwork = "192.168.0.128/25".parse().unwrap();
+        assert!(net.contains("192.168.0.200".parse().unwrap()));
+        assert!(!net.contains("192.168.0.100".parse().unwrap()));
+
+        let net: IpNetwork = "::1".parse().unwrap();
+        assert!(net.contains("::1".parse().unwrap()));
+        assert!(!net.contains("127.0.0.1".parse().unwrap()));
+
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_access_policy() {
+        assert_eq!(AccessPolicy::default().check(None, None), Ok(()));
+
+        let policy = AccessPolicy::new(
+            Some("secret".to_string()),
+            vec!["127.0.0.0/8".parse().unwrap()],
+        );
+        assert_eq!(policy.check(ip("127.0.0.1"), Some("Bearer secret")), Ok(()));
+        assert_eq!(
+            policy.check(ip("10.0.0.1"), Some("Bearer secret")),
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(
+            policy.check(None, Some("Bearer secret")),
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(
+            policy.check(ip("127.0.0.1"), Some("Bearer secreT")),
+            Err(Denied::Unauthorized)
+        );
+        assert_eq!(
+            policy.check(ip("127.0.0.1"), Some("Basic secret")),
+            Err(Denied::Unauthorized)
+        );
+        assert_eq!(
+            policy.check(ip("127.0.0.1"), None),
+            Err(Denied::Unauthorized)
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Versioned gRPC control interface, see `protobuf/admin.proto`.
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use futures::{Stream, StreamExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+
+use crate::access::{AccessPolicy, Denied};
+use crate::capture::{Capture, CaptureFile, CaptureStatus};
+use crate::state::Limits;
+use crate::{
+    AddrStatus, Maintenance, MaintenanceKind, MaintenanceNotice, Selector, Session, SessionEvent,
+    SessionEventKind, SessionManager,
+};
+
+pub mod proto {
+    #![allow(clippy::all)]
+    tonic::include_proto!("ya_relay.admin.v1");
+}
+
+use proto::admin_server::{Admin, AdminServer};
+
+const DEFAULT_LIST_LIMIT: usize = 100;
+const CAPTURE_CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct AdminService {
+    session_manager: Arc<SessionManager>,
+    limits: Arc<Limits>,
+    maintenance: Arc<Maintenance>,
+    capture: Arc<Capture>,
+    access: AccessPolicy,
+}
+
+impl AdminService {
+    pub fn new(
+        session_manager: Arc<SessionManager>,
+        limits: Arc<Limits>,
+        maintenance: Arc<Maintenance>,
+        capture: Arc<Capture>,
+    ) -> Self {
+        AdminService {
+            session_manager,
+            limits,
+            maintenance,
+            capture,
+            access: Default::default(),
+        }
+    }
+
+    /// Requests have to carry the bearer token of `access` in the `authorization`
+    /// metadata, if it's set.
+    pub fn access(mut self, access: AccessPolicy) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Serves the admin interface on `addr` until the returned handle is aborted.
+    /// Addresses other than loopback ones require a token.
+    pub async fn start(
+        self,
+        addr: SocketAddr,
+    ) -> anyhow::Result<(SocketAddr, JoinHandle<Result<(), tonic::transport::Error>>)> {
+        if self.access.token.is_none() && !addr.ip().is_loopback() {
+            anyhow::bail!("Admin gRPC interface on {addr} requires a token");
+        }
+
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        log::info!("Admin gRPC interface listening on: {addr}");
+
+        let access = self.access.clone();
+        let service =
+            AdminServer::with_interceptor(self, move |request| authorize(&access, request));
+        let handle = tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        Ok((addr, handle))
+    }
+
+    fn limits(&self) -> proto::Limits {
+        proto::Limits {
+            challenge_difficulty: self.limits.challenge_difficulty(),
+            session_purge_timeout_ms: self.limits.session_purge_timeout().as_millis() as u64,
+        }
+    }
+}
+
+impl From<&Session> for proto::Session {
+    fn from(session: &Session) -> Self {
+        let addr_status = match &*session.addr_status.lock() {
+            AddrStatus::Unknown => proto::AddrStatus::Unknown,
+            AddrStatus::Pending(_) => proto::AddrStatus::Pending,
+            AddrStatus::Valid(_) => proto::AddrStatus::Valid,
+            AddrStatus::Invalid(_) => proto::AddrStatus::Invalid,
+        };
+        proto::Session {
+            session_id: session.session_id.to_string(),
+            node_id: session.node_id.to_string(),
+            peer: session.peer.to_string(),
+            last_seen_ms: session.ts.age().as_millis() as u64,
+            addr_status: addr_status.into(),
+            supported_encryptions: session.supported_encryptions.clone(),
+        }
+    }
+}
+
+impl From<SessionEvent> for proto::Event {
+    fn from(event: SessionEvent) -> Self {
+        let kind = match event.kind {
+            SessionEventKind::Created => proto::event::Kind::Created,
+            SessionEventKind::Removed => proto::event::Kind::Removed,
+            SessionEventKind::Purged => proto::event::Kind::Purged,
+            SessionEventKind::Reachable => proto::event::Kind::Reachable,
+        };
+        proto::Event {
+            kind: kind.into(),
+            session_id: event.session_id.to_string(),
+            node_id: event.node_id.to_string(),
+            peer: event.peer.to_string(),
+        }
+    }
+}
+
+impl From<CaptureStatus> for proto::CaptureStatus {
+    fn from(status: CaptureStatus) -> Self {
+        proto::CaptureStatus {
+            node_id: status.node_id.to_string(),
+            started_at_ms: status.started_at.timestamp_millis().max(0) as u64,
+            max_payload: status.max_payload.map(|max| max as u32),
+            packets: status.packets,
+            bytes: status.bytes,
+            dropped: status.dropped,
+        }
+    }
+}
+
+impl From<CaptureFile> for proto::CaptureFile {
+    fn from(file: CaptureFile) -> Self {
+        proto::CaptureFile {
+            name: file.name,
+            size: file.size,
+        }
+    }
+}
+
+impl TryFrom<proto::AnnounceMaintenanceRequest> for MaintenanceNotice {
+    type Error = Status;
+
+    fn try_from(request: proto::AnnounceMaintenanceRequest) -> Result<Self, Self::Error> {
+        let kind = match request.kind() {
+            proto::announce_maintenance_request::Kind::Shutdown => MaintenanceKind::Shutdown,
+            proto::announce_maintenance_request::Kind::Restart => MaintenanceKind::Restart,
+        };
+        let scheduled_at = i64::try_from(request.scheduled_at_ms)
+            .ok()
+            .and_then(|ms| Utc.timestamp_millis_opt(ms).single())
+            .ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "invalid scheduled time {}",
+                    request.scheduled_at_ms
+                ))
+            })?;
+        let alternative_relay = Some(request.alternative_relay).filter(|relay| !relay.is_empty());
+
+        Ok(MaintenanceNotice {
+            kind,
+            scheduled_at,
+            alternative_relay,
+            message: request.message,
+        })
+    }
+}
+
+fn authorize(access: &AccessPolicy, request: Request<()>) -> Result<Request<()>, Status> {
+    let peer = request.remote_addr().map(|addr| addr.ip());
+    let authorization = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok());
+    match access.check(peer, authorization) {
+        Ok(()) => Ok(request),
+        Err(denied) => {
+            log::debug!("[admin] {denied:?} request from {peer:?}");
+            Err(match denied {
+                Denied::Forbidden => Status::permission_denied("forbidden"),
+                Denied::Unauthorized => Status::unauthenticated("unauthorized"),
+            })
+        }
+    }
+}
+
+fn parse_node_id(node_id: &str) -> Result<NodeId, Status> {
+    node_id
+        .parse()
+        .map_err(|e| Status::invalid_argument(format!("invalid node id {node_id}: {e}")))
+}
+
+fn parse_session_id(session_id: &str) -> Result<SessionId, Status> {
+    hex::decode(session_id)
+        .ok()
+        .and_then(|bytes| SessionId::try_from(bytes).ok())
+        .ok_or_else(|| Status::invalid_argument(format!("invalid session id {session_id}")))
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn list_sessions(
+        &self,
+        request: Request<proto::ListSessionsRequest>,
+    ) -> Result<Response<proto::ListSessionsResponse>, Status> {
+        let request = request.into_inner();
+        let selector = match request.node_prefix.trim_start_matches("0x") {
+            "" => Selector::All,
+            prefix => prefix
+                .parse()
+                .map_err(|e| Status::invalid_argument(format!("invalid prefix: {e}")))?,
+        };
+        let limit = match request.limit {
+            0 => DEFAULT_LIST_LIMIT,
+            limit => limit as usize,
+        };
+
+        let snapshot = self.session_manager.snapshot();
+        let sessions = snapshot
+            .sessions(&selector, limit)
+            .iter()
+            .map(|session| proto::Session::from(session.as_ref()))
+            .collect();
+        Ok(Response::new(proto::ListSessionsResponse {
+            total: snapshot.len() as u64,
+            sessions,
+        }))
+    }
+
+    async fn get_node(
+        &self,
+        request: Request<proto::GetNodeRequest>,
+    ) -> Result<Response<proto::GetNodeResponse>, Status> {
+        let node_id = parse_node_id(&request.into_inner().node_id)?;
+        let sessions = self
+            .session_manager
+            .snapshot()
+            .node_sessions(node_id)
+            .iter()
+            .map(|session| proto::Session::from(session.as_ref()))
+            .collect::<Vec<_>>();
+
+        if sessions.is_empty() {
+            return Err(Status::not_found(format!(
+                "Node {node_id} is not connected"
+            )));
+        }
+        Ok(Response::new(proto::GetNodeResponse { sessions }))
+    }
+
+    async fn disconnect(
+        &self,
+        request: Request<proto::DisconnectRequest>,
+    ) -> Result<Response<proto::DisconnectResponse>, Status> {
+        use proto::disconnect_request::Target;
+
+        let session_ids = match request.into_inner().target {
+            Some(Target::SessionId(session_id)) => vec![parse_session_id(&session_id)?],
+            Some(Target::NodeId(node_id)) => self
+                .session_manager
+                .node_sessions(parse_node_id(&node_id)?)
+                .iter()
+                .map(|session| session.session_id)
+                .collect(),
+            None => return Err(Status::invalid_argument("missing disconnect target")),
+        };
+
+        let removed = session_ids
+            .iter()
+            .filter_map(|session_id| self.session_manager.remove_session(session_id))
+            .count();
+        Ok(Response::new(proto::DisconnectResponse {
+            removed: removed as u32,
+        }))
+    }
+
+    async fn get_limits(
+        &self,
+        _request: Request<proto::GetLimitsRequest>,
+    ) -> Result<Response<proto::Limits>, Status> {
+        Ok(Response::new(self.limits()))
+    }
+
+    async fn set_limits(
+        &self,
+        request: Request<proto::SetLimitsRequest>,
+    ) -> Result<Response<proto::Limits>, Status> {
+        let request = request.into_inner();
+        if let Some(difficulty) = request.challenge_difficulty {
+            log::info!("[admin] setting challenge difficulty to {difficulty}");
+            self.limits.set_challenge_difficulty(difficulty);
+        }
+        if let Some(timeout) = request.session_purge_timeout_ms {
+            let timeout = Duration::from_millis(timeout);
+            log::info!("[admin] setting session purge timeout to {timeout:?}");
+            self.limits.set_session_purge_timeout(timeout);
+        }
+        Ok(Response::new(self.limits()))
+    }
+
+    type StreamEventsStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+    async fn stream_events(
+        &self,
+        _request: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let events = BroadcastStream::new(self.session_manager.subscribe()).map(|event| {
+            event
+                .map(proto::Event::from)
+                .map_err(|e| Status::data_loss(e.to_string()))
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn announce_maintenance(
+        &self,
+        request: Request<proto::AnnounceMaintenanceRequest>,
+    ) -> Result<Response<proto::AnnounceMaintenanceResponse>, Status> {
+        let notice = MaintenanceNotice::try_from(request.into_inner())?;
+        log::info!(
+            "[admin] announcing {:?} scheduled at {}",
+            notice.kind,
+            notice.scheduled_at
+        );
+        self.maintenance.announce(notice);
+        Ok(Response::new(proto::AnnounceMaintenanceResponse {
+            sessions: self.session_manager.num_sessions() as u64,
+        }))
+    }
+
+    async fn start_capture(
+        &self,
+        request: Request<proto::StartCaptureRequest>,
+    ) -> Result<Response<proto::CaptureStatus>, Status> {
+        let request = request.into_inner();
+        let node_id = parse_node_id(&request.node_id)?;
+        log::info!("[admin] starting packet capture for [{node_id}]");
+        self.capture
+            .start(node_id, request.max_payload.map(|max| max as usize))
+            .map_err(|e| Status::internal(format!("{e:#}")))?;
+        let status = self
+            .capture
+            .status()
+            .ok_or_else(|| Status::aborted("capture stopped"))?;
+        Ok(Response::new(status.into()))
+    }
+
+    async fn stop_capture(
+        &self,
+        _request: Request<proto::StopCaptureRequest>,
+    ) -> Result<Response<proto::CaptureStatus>, Status> {
+        match self.capture.stop() {
+            Some(status) => Ok(Response::new(status.into())),
+            None => Err(Status::not_found("no capture is running")),
+        }
+    }
+
+    async fn get_capture(
+        &self,
+        _request: Request<proto::GetCaptureRequest>,
+    ) -> Result<Response<proto::GetCaptureResponse>, Status> {
+        let files = self
+            .capture
+            .files()
+            .map_err(|e| Status::internal(format!("{e:#}")))?;
+        Ok(Response::new(proto::GetCaptureResponse {
+            running: self.capture.status().map(Into::into),
+            files: files.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    type DownloadCaptureStream =
+        Pin<Box<dyn Stream<Item = Result<proto::CaptureChunk, Status>> + Send>>;
+
+    async fn download_capture(
+        &self,
+        request: Request<proto::DownloadCaptureRequest>,
+    ) -> Result<Response<Self::DownloadCaptureStream>, Status> {
+        let name = request.into_inner().name;
+        let capture = self.capture.clone();
+        let data = tokio::task::spawn_blocking(move || capture.read(&name))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::not_found(format!("{e:#}")))?;
+
+        let chunks = data
+            .chunks(CAPTURE_CHUNK_SIZE)
+            .map(|chunk| {
+                Ok(proto::CaptureChunk {
+                    data: chunk.to_vec(),
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(Response::new(Box::pin(futures::stream::iter(chunks))))
+    }
+}
@@ -3,17 +3,61 @@ use std::collections::HashMap;
 use std::future;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
 use actix_web::{get, web, Responder};
+use futures::future::Either;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 
+use ya_relay_core::properties::Properties;
 use ya_relay_core::NodeId;
+use ya_relay_server::access::{AccessPolicy, Denied};
 use ya_relay_server::metrics::register_metrics;
-use ya_relay_server::{AddrStatus, Config, Selector, SessionManager};
+use ya_relay_server::sse::SseClients;
+use ya_relay_server::{
+    AddrStatus, Config, ForwardLimiter, Selector, Session, SessionHistory, SessionManager,
+    TrafficMatrix,
+};
 
 #[get("/sessions")]
 async fn sessions_list(sm: web::Data<Arc<SessionManager>>) -> impl Responder {
-    format!("sessions: {}", sm.num_sessions())
+    format!("sessions: {}", sm.snapshot().len())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionInfo {
+    session_id: String,
+    peer: SocketAddr,
+    seen: String,
+    supported_encryptions: Vec<String>,
+    addr_status: String,
+    properties: Option<Properties>,
+}
+
+impl From<&Session> for SessionInfo {
+    fn from(session_ref: &Session) -> Self {
+        SessionInfo {
+            session_id: session_ref.session_id.to_string(),
+            peer: session_ref.peer,
+            seen: format!("{:?}", session_ref.ts.age()),
+            supported_encryptions: session_ref.supported_encryptions.clone(),
+            addr_status: match &*session_ref.addr_status.lock() {
+                AddrStatus::Unknown => "Unknown".to_owned(),
+                AddrStatus::Pending(ts) => format!("pending({:?})", ts.elapsed()),
+                AddrStatus::Invalid(ts) => format!("invalid({:?})", ts.elapsed()),
+                AddrStatus::Valid(ts) => format!("valid({:?})", ts.elapsed()),
+            },
+            properties: session_ref
+                .properties
+                .lock()
+                .as_ref()
+                .map(|p| p.values.clone()),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -25,42 +69,20 @@ async fn nodes_list_prefix(
     sm: web::Data<Arc<SessionManager>>,
     query: web::Path<SessionsQuery>,
 ) -> Result<impl Responder, actix_web::Error> {
-    #[derive(Serialize)]
-    #[serde(rename_all = "camelCase")]
-    struct SessionInfo {
-        session_id: String,
-        peer: SocketAddr,
-        seen: String,
-        supported_encryptions: Vec<String>,
-        addr_status: String,
-    }
-
     let selector: Selector = query
         .prefix
         .parse()
         .map_err(actix_web::error::ErrorBadRequest)?;
-    let nodes: HashMap<NodeId, Vec<Option<SessionInfo>>> = sm
-        .nodes_for(selector, 50)
+    let nodes: HashMap<NodeId, Vec<SessionInfo>> = sm
+        .snapshot()
+        .nodes_for(&selector, 50)
         .into_iter()
         .map(|(node_id, sessions)| {
             (
                 node_id,
                 sessions
-                    .into_iter()
-                    .map(|session_ref| {
-                        session_ref.upgrade().map(|session_ref| SessionInfo {
-                            session_id: session_ref.session_id.to_string(),
-                            peer: session_ref.peer,
-                            seen: format!("{:?}", session_ref.ts.age()),
-                            supported_encryptions: session_ref.supported_encryptions.clone(),
-                            addr_status: match &*session_ref.addr_status.lock() {
-                                AddrStatus::Unknown => "Unknown".to_owned(),
-                                AddrStatus::Pending(ts) => format!("pending({:?})", ts.elapsed()),
-                                AddrStatus::Invalid(ts) => format!("invalid({:?})", ts.elapsed()),
-                                AddrStatus::Valid(ts) => format!("valid({:?})", ts.elapsed()),
-                            },
-                        })
-                    })
+                    .iter()
+                    .map(|session_ref| SessionInfo::from(session_ref.as_ref()))
                     .collect(),
             )
         })
@@ -68,6 +90,237 @@ async fn nodes_list_prefix(
     Ok(web::Json(nodes))
 }
 
+/// Recent sessions of the Node, oldest first.
+#[get("/nodes/{node_id}/history")]
+async fn node_history(
+    history: web::Data<Arc<SessionHistory>>,
+    node_id: web::Path<String>,
+) -> Result<impl Responder, actix_web::Error> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct HistoryInfo {
+        session_id: String,
+        peer: SocketAddr,
+        connected: String,
+        disconnected: Option<String>,
+        reason: Option<String>,
+    }
+
+    let node_id: NodeId = node_id.parse().map_err(actix_web::error::ErrorBadRequest)?;
+    let entries: Vec<HistoryInfo> = history
+        .node(node_id)
+        .into_iter()
+        .map(|entry| HistoryInfo {
+            session_id: entry.session_id.to_string(),
+            peer: entry.peer,
+            connected: entry.connected.to_rfc3339(),
+            disconnected: entry.disconnected.map(|ts| ts.to_rfc3339()),
+            reason: entry.reason.map(|kind| format!("{kind:?}").to_lowercase()),
+        })
+        .collect();
+    Ok(web::Json(entries))
+}
+
+/// State of forward queues of the Node's sessions.
+#[get("/nodes/{node_id}/limits")]
+async fn node_limits(
+    sm: web::Data<Arc<SessionManager>>,
+    limiter: web::Data<Arc<ForwardLimiter>>,
+    node_id: web::Path<String>,
+) -> Result<impl Responder, actix_web::Error> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct QueueInfo {
+        session_id: String,
+        peer: SocketAddr,
+        /// Per worker, as each one throttles forwards separately.
+        capacity: usize,
+        pending: usize,
+        available: usize,
+        workers: usize,
+        dropped: u32,
+        reported: Option<String>,
+    }
+
+    let node_id: NodeId = node_id.parse().map_err(actix_web::error::ErrorBadRequest)?;
+    let queues: Vec<QueueInfo> = sm
+        .snapshot()
+        .node_sessions(node_id)
+        .iter()
+        .map(|session_ref| {
+            let queue = limiter.queue(&session_ref.session_id);
+            QueueInfo {
+                session_id: session_ref.session_id.to_string(),
+                peer: session_ref.peer,
+                capacity: limiter.capacity(),
+                pending: queue.pending,
+                available: queue.available,
+                workers: queue.workers,
+                dropped: queue.dropped,
+                reported: queue.reported.map(|ago| format!("{ago:?}")),
+            }
+        })
+        .collect();
+    if queues.is_empty() {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "Node {node_id} is not connected"
+        )));
+    }
+    Ok(web::Json(queues))
+}
+
+/// Totals of the forward queues of all sessions.
+#[get("/limits")]
+async fn limits_stats(limiter: web::Data<Arc<ForwardLimiter>>) -> impl Responder {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct LimiterInfo {
+        capacity: usize,
+        max_payload: Option<usize>,
+        admitted: u64,
+        dropped: u64,
+        oversized: u64,
+        reports: u64,
+        congested_sessions: usize,
+    }
+
+    let stats = limiter.stats();
+    web::Json(LimiterInfo {
+        capacity: stats.capacity,
+        max_payload: stats.max_payload,
+        admitted: stats.admitted,
+        dropped: stats.dropped,
+        oversized: stats.oversized,
+        reports: stats.reports,
+        congested_sessions: stats.congested_sessions,
+    })
+}
+
+/// Session events as server-sent events. Resumes after the `Last-Event-ID`.
+#[get("/events")]
+async fn events_stream(
+    sse: web::Data<Arc<SseClients>>,
+    req: actix_web::HttpRequest,
+) -> impl Responder {
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let events = sse.connect(last_event_id).map(Ok::<_, actix_web::Error>);
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(events)
+}
+
+#[derive(Deserialize)]
+struct NearestQuery {
+    count: Option<usize>,
+}
+
+/// Nodes closest to the given one by XOR distance, without Nodes unreachable directly.
+#[get("/nearest/{node_id}")]
+async fn nearest_list(
+    sm: web::Data<Arc<SessionManager>>,
+    node_id: web::Path<String>,
+    query: web::Query<NearestQuery>,
+) -> Result<impl Responder, actix_web::Error> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct NearestNode {
+        node_id: NodeId,
+        #[serde(flatten)]
+        session: SessionInfo,
+    }
+
+    let node_id: NodeId = node_id.parse().map_err(actix_web::error::ErrorBadRequest)?;
+    let count = query.count.unwrap_or(10).min(50);
+    let nodes: Vec<NearestNode> = sm
+        .nearest(node_id, count, Some(node_id))
+        .iter()
+        .map(|session_ref| NearestNode {
+            node_id: session_ref.node_id,
+            session: SessionInfo::from(session_ref.as_ref()),
+        })
+        .collect();
+    Ok(web::Json(nodes))
+}
+
+#[derive(Deserialize)]
+struct TopQuery {
+    window: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Node pairs with the most bytes forwarded within the `window`, e.g. `60s`.
+#[get("/stats/top")]
+async fn top_talkers(
+    traffic: web::Data<Arc<TrafficMatrix>>,
+    query: web::Query<TopQuery>,
+) -> Result<impl Responder, actix_web::Error> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Talker {
+        src: NodeId,
+        dst: NodeId,
+        bytes: u64,
+        packets: u64,
+    }
+
+    let window = match &query.window {
+        Some(window) => {
+            humantime::parse_duration(window).map_err(actix_web::error::ErrorBadRequest)?
+        }
+        None => Duration::from_secs(60),
+    };
+    let limit = query.limit.unwrap_or(20).min(1000);
+    let top: Vec<Talker> = traffic
+        .top(window, limit)
+        .into_iter()
+        .map(|pair| Talker {
+            src: pair.src,
+            dst: pair.dst,
+            bytes: pair.bytes,
+            packets: pair.packets,
+        })
+        .collect();
+    Ok(web::Json(top))
+}
+
+/// Rejects requests not allowed by `policy` before they reach the handlers.
+#[allow(clippy::type_complexity)]
+fn access_guard<S, B>(
+    policy: AccessPolicy,
+) -> impl Fn(
+    ServiceRequest,
+    &S,
+) -> Either<S::Future, future::Ready<Result<ServiceResponse<B>, actix_web::Error>>>
+       + Clone
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    move |req, srv| {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let authorization = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        match policy.check(peer, authorization) {
+            Ok(()) => Either::Left(srv.call(req)),
+            Err(denied) => {
+                log::debug!("{denied:?} HTTP request from {peer:?} to {}", req.path());
+                let e = match denied {
+                    Denied::Forbidden => actix_web::error::ErrorForbidden("forbidden"),
+                    Denied::Unauthorized => actix_web::error::ErrorUnauthorized("unauthorized"),
+                };
+                Either::Right(future::ready(Err(e)))
+            }
+        }
+    }
+}
+
 #[actix_rt::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
@@ -82,22 +335,108 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Config::parse();
 
+    let problems = ya_relay_server::check::check(&args);
+    for problem in &problems {
+        log::error!("{problem}");
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("invalid configuration, {} problem(s) found", problems.len());
+    }
+    if args.check {
+        log::info!("configuration is valid");
+        return Ok(());
+    }
+
     let handle = register_metrics();
 
     let server = ya_relay_server::run(&args).await?;
 
+    #[cfg(feature = "grpc-admin")]
+    let _admin = match args.admin_grpc_addr {
+        Some(addr) => Some(
+            ya_relay_server::admin::AdminService::new(
+                server.sessions(),
+                server.limits(),
+                server.maintenance(),
+                server.capture(),
+            )
+            .access(args.admin_grpc_access())
+            .start(addr)
+            .await?,
+        ),
+        None => None,
+    };
+
     let sessions = web::Data::new(server.sessions());
+    let traffic = web::Data::new(server.traffic());
+    let history = web::Data::new(server.history());
+    let forward_limiter = web::Data::new(server.forward_limiter());
+    let sse = SseClients::new(args.sse.clone());
+    let _sse_task = sse.start(server.sessions().subscribe());
+    let _sse_notices_task = sse.start_notices(server.maintenance().subscribe());
+    let sse = web::Data::new(sse);
+
+    // Topology is listed with the metrics, unless it has its own address.
+    if let Some(admin_addr) = args.admin_http_addr {
+        let admin_access = args.admin_http_access();
+        let sessions = sessions.clone();
+        let traffic = traffic.clone();
+        let history = history.clone();
+        let forward_limiter = forward_limiter.clone();
+        let sse = sse.clone();
+        let admin_server = actix_web::HttpServer::new(move || {
+            actix_web::App::new()
+                .wrap_fn(access_guard(admin_access.clone()))
+                .app_data(sessions.clone())
+                .app_data(traffic.clone())
+                .app_data(history.clone())
+                .app_data(forward_limiter.clone())
+                .app_data(sse.clone())
+                .service(events_stream)
+                .service(top_talkers)
+                .service(limits_stats)
+                .service(node_history)
+                .service(node_limits)
+                .service(nodes_list_prefix)
+                .service(nearest_list)
+                .service(sessions_list)
+        })
+        .workers(1)
+        .worker_max_blocking_threads(1)
+        .disable_signals()
+        .bind(admin_addr)?
+        .run();
+
+        actix_rt::spawn(admin_server);
+    }
 
+    let list_sessions = args.admin_http_addr.is_none();
+    let metrics_access = args.metrics_access();
     let web_server = actix_web::HttpServer::new(move || {
         use actix_web::*;
 
         let handle = handle.clone();
 
-        App::new()
-            .app_data(sessions.clone())
-            .service(nodes_list_prefix)
-            .service(sessions_list)
-            .route("/", web::get().to(move || future::ready(handle.render())))
+        let app = App::new()
+            .wrap_fn(access_guard(metrics_access.clone()))
+            .route("/", web::get().to(move || future::ready(handle.render())));
+        match list_sessions {
+            true => app
+                .app_data(sessions.clone())
+                .app_data(traffic.clone())
+                .app_data(history.clone())
+                .app_data(forward_limiter.clone())
+                .app_data(sse.clone())
+                .service(events_stream)
+                .service(top_talkers)
+                .service(limits_stats)
+                .service(node_history)
+                .service(node_limits)
+                .service(nodes_list_prefix)
+                .service(nearest_list)
+                .service(sessions_list),
+            false => app,
+        }
     })
     .workers(1)
     .worker_max_blocking_threads(1)
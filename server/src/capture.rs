@@ -0,0 +1,679 @@
+//! Capture of packets exchanged with a single Node, for debugging production incidents.
+//!
+//! Started from the admin interface for one NodeId. Packets received from and sent to
+//! addresses of the Node's sessions are written to pcap files with synthetic IP and UDP
+//! headers, so they open in Wireshark. Payloads can be truncated to keep only the relay
+//! protocol headers. Files are rotated and the oldest ones are removed.
+//!
+//! Workers check packets against the Node's addresses without locking and hand captured
+//! ones to a writer thread, so disk writes don't delay forwarding. Packets are dropped
+//! and counted, when the writer can't keep up.
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+
+use ya_relay_core::intercept::Direction;
+use ya_relay_core::NodeId;
+
+use crate::SessionManager;
+
+/// Addresses of the captured Node are looked up again after that time, to follow
+/// its new sessions.
+const PEERS_REFRESH: Duration = Duration::from_secs(1);
+/// Packets waiting for the writer. Above that, packets aren't captured.
+const QUEUE_SIZE: usize = 4096;
+const EXTENSION: &str = "pcap";
+const LINKTYPE_RAW: u32 = 101;
+const UDP_HEADER_LEN: usize = 8;
+const IPV4_HEADERS_LEN: usize = 20 + UDP_HEADER_LEN;
+const IPV6_HEADERS_LEN: usize = 40 + UDP_HEADER_LEN;
+
+#[derive(clap::Args, Clone, Debug)]
+#[command(next_help_heading = "Packet capture options")]
+pub struct CaptureConfig {
+    /// Directory of packet capture files. `capture` in the state directory,
+    /// or in the temporary one, if not set
+    #[arg(long, env)]
+    pub capture_dir: Option<PathBuf>,
+    /// Size of a capture file in bytes, above which the next one is started
+    #[arg(long, env, default_value = "16777216")]
+    pub capture_file_size: u64,
+    /// Capture files kept. The oldest ones are removed above that
+    #[arg(long, env, default_value = "4")]
+    pub capture_files: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            capture_dir: None,
+            capture_file_size: 16 * 1024 * 1024,
+            capture_files: 4,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureStatus {
+    pub node_id: NodeId,
+    pub started_at: DateTime<Utc>,
+    /// Payload bytes kept of each packet, whole packets if `None`.
+    pub max_payload: Option<usize>,
+    pub packets: u64,
+    pub bytes: u64,
+    /// Packets not captured, because the writer couldn't keep up.
+    pub dropped: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureFile {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Selects packets of the captured Node and hands them to the writer.
+struct Filter {
+    /// Replaced by the writer, when the Node's sessions change.
+    peers: Arc<ArcSwap<HashSet<SocketAddr>>>,
+    max_payload: Option<usize>,
+    queue: SyncSender<Command>,
+    dropped: AtomicU64,
+}
+
+enum Command {
+    Record(Packet),
+    /// Answered, when packets written so far reach the file.
+    Flush(SyncSender<()>),
+}
+
+struct Packet {
+    ts: SystemTime,
+    src: SocketAddr,
+    dst: SocketAddr,
+    len: usize,
+    kept: Vec<u8>,
+}
+
+struct Active {
+    status: Arc<Mutex<CaptureStatus>>,
+    filter: Arc<Filter>,
+    writer: JoinHandle<()>,
+}
+
+impl Active {
+    fn status(&self) -> CaptureStatus {
+        let mut status = self.status.lock().clone();
+        status.dropped = self.filter.dropped.load(Ordering::Relaxed);
+        status
+    }
+
+    fn flush(&self) {
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        // Fails only if the writer stopped, after flushing the file.
+        if self.filter.queue.send(Command::Flush(done_tx)).is_ok() {
+            done_rx.recv().ok();
+        }
+    }
+
+    /// Waits for the writer to write queued packets and close the file.
+    fn finish(self) -> CaptureStatus {
+        let Active {
+            status,
+            filter,
+            writer,
+        } = self;
+        let dropped = filter.dropped.load(Ordering::Relaxed);
+        // Writer stops, when the queue is closed.
+        drop(filter);
+        if writer.join().is_err() {
+            log::warn!("[capture] writer panicked");
+        }
+
+        let mut status = status.lock().clone();
+        status.dropped = dropped;
+        status
+    }
+}
+
+/// Capture files of the server.
+struct Files {
+    config: CaptureConfig,
+    dir: PathBuf,
+    /// Files written since the server started, oldest first.
+    written: Mutex<VecDeque<PathBuf>>,
+}
+
+impl Files {
+    fn create(&self, status: &CaptureStatus, seq: usize) -> anyhow::Result<BufWriter<File>> {
+        let name = format!(
+            "{}-{}-{seq}.{EXTENSION}",
+            status.node_id,
+            status.started_at.format("%Y%m%dT%H%M%S")
+        );
+        let path = self.dir.join(name);
+        let mut writer = BufWriter::new(
+            File::create(&path)
+                .with_context(|| format!("creating capture file {}", path.display()))?,
+        );
+        writer.write_all(&file_header())?;
+
+        let mut written = self.written.lock();
+        written.push_back(path);
+        while written.len() > self.config.capture_files.max(1) {
+            if let Some(oldest) = written.pop_front() {
+                if let Err(e) = std::fs::remove_file(&oldest) {
+                    log::debug!("[capture] failed to remove {}: {e}", oldest.display());
+                }
+            }
+        }
+        Ok(writer)
+    }
+}
+
+/// Writes packets handed by workers, runs on its own thread.
+struct Writer {
+    files: Arc<Files>,
+    session_manager: Arc<SessionManager>,
+    status: Arc<Mutex<CaptureStatus>>,
+    peers: Arc<ArcSwap<HashSet<SocketAddr>>>,
+    peers_refreshed: Instant,
+    file: BufWriter<File>,
+    file_size: u64,
+    seq: usize,
+}
+
+impl Writer {
+    fn run(mut self, queue: Receiver<Command>) {
+        let node_id = self.status.lock().node_id;
+        loop {
+            match queue.recv_timeout(PEERS_REFRESH) {
+                Ok(Command::Record(packet)) => {
+                    if let Err(e) = self.write(packet) {
+                        log::warn!("[capture] failed to write, stopping: {e}");
+                        break;
+                    }
+                }
+                Ok(Command::Flush(done)) => {
+                    if let Err(e) = self.file.flush() {
+                        log::warn!("[capture] failed to flush: {e}");
+                    }
+                    done.send(()).ok();
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if self.peers_refreshed.elapsed() > PEERS_REFRESH {
+                self.peers
+                    .store(Arc::new(peers(&self.session_manager, node_id)));
+                self.peers_refreshed = Instant::now();
+            }
+        }
+
+        // Nothing more is captured, if writing failed.
+        self.peers.store(Default::default());
+        if let Err(e) = self.file.flush() {
+            log::warn!("[capture] failed to flush: {e}");
+        }
+    }
+
+    fn write(&mut self, packet: Packet) -> anyhow::Result<()> {
+        let record = encode_record(packet.ts, packet.src, packet.dst, packet.len, &packet.kept);
+        self.file.write_all(&record)?;
+        self.file_size += record.len() as u64;
+
+        {
+            let mut status = self.status.lock();
+            status.packets += 1;
+            status.bytes += packet.len as u64;
+        }
+        if self.file_size >= self.files.config.capture_file_size {
+            self.file.flush()?;
+            self.seq += 1;
+            let status = self.status.lock().clone();
+            self.file = self.files.create(&status, self.seq)?;
+            self.file_size = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Packet capture of the server, idle until started.
+pub struct Capture {
+    files: Arc<Files>,
+    session_manager: Arc<SessionManager>,
+    /// Checked on each packet without locking, so idle capture costs nothing.
+    filter: ArcSwapOption<Filter>,
+    active: Mutex<Option<Active>>,
+}
+
+impl Capture {
+    pub fn new(
+        config: CaptureConfig,
+        state_dir: Option<&Path>,
+        session_manager: &Arc<SessionManager>,
+    ) -> Arc<Self> {
+        let dir = match (&config.capture_dir, state_dir) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(state_dir)) => state_dir.join("capture"),
+            (None, None) => std::env::temp_dir().join("ya-relay-capture"),
+        };
+        Arc::new(Capture {
+            files: Arc::new(Files {
+                config,
+                dir,
+                written: Default::default(),
+            }),
+            session_manager: session_manager.clone(),
+            filter: Default::default(),
+            active: Default::default(),
+        })
+    }
+
+    /// Starts capturing packets of `node_id`, replacing the running capture.
+    pub fn start(&self, node_id: NodeId, max_payload: Option<usize>) -> anyhow::Result<()> {
+        let dir = &self.files.dir;
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating capture directory {}", dir.display()))?;
+
+        let status = CaptureStatus {
+            node_id,
+            started_at: Utc::now(),
+            max_payload,
+            packets: 0,
+            bytes: 0,
+            dropped: 0,
+        };
+        let file = self.files.create(&status, 0)?;
+        let status = Arc::new(Mutex::new(status));
+        let peers = Arc::new(ArcSwap::from_pointee(peers(&self.session_manager, node_id)));
+        let (queue_tx, queue_rx) = mpsc::sync_channel(QUEUE_SIZE);
+
+        let writer = Writer {
+            files: self.files.clone(),
+            session_manager: self.session_manager.clone(),
+            status: status.clone(),
+            peers: peers.clone(),
+            peers_refreshed: Instant::now(),
+            file,
+            file_size: 0,
+            seq: 0,
+        };
+        let writer = std::thread::Builder::new()
+            .name("capture-writer".into())
+            .spawn(move || writer.run(queue_rx))
+            .context("starting capture writer")?;
+        log::info!("[capture] started for [{node_id}], max payload: {max_payload:?}");
+
+        let filter = Arc::new(Filter {
+            peers,
+            max_payload,
+            queue: queue_tx,
+            dropped: Default::default(),
+        });
+        let previous = {
+            let mut active = self.active.lock();
+            self.filter.store(Some(filter.clone()));
+            active.replace(Active {
+                status,
+                filter,
+                writer,
+            })
+        };
+        if let Some(previous) = previous {
+            previous.finish();
+        }
+        Ok(())
+    }
+
+    /// Stops the running capture. Returns `None`, if there was none.
+    pub fn stop(&self) -> Option<CaptureStatus> {
+        let active = {
+            let mut active = self.active.lock();
+            self.filter.store(None);
+            active.take()?
+        };
+        let status = active.finish();
+        log::info!(
+            "[capture] stopped for [{}], {} packets captured, {} dropped",
+            status.node_id,
+            status.packets,
+            status.dropped
+        );
+        Some(status)
+    }
+
+    pub fn status(&self) -> Option<CaptureStatus> {
+        self.active.lock().as_ref().map(Active::status)
+    }
+
+    /// Capture files in the directory, including ones left from before the restart.
+    pub fn files(&self) -> anyhow::Result<Vec<CaptureFile>> {
+        self.flush();
+        let entries = match std::fs::read_dir(&self.files.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                files.push(CaptureFile {
+                    name: name.to_string(),
+                    size: entry.metadata()?.len(),
+                });
+            }
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    /// Contents of a capture file listed by [`Capture::files`].
+    pub fn read(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let is_file_name = Path::new(name).file_name().map(|n| n == name) == Some(true);
+        if !is_file_name || !name.ends_with(EXTENSION) {
+            anyhow::bail!("invalid capture file name {name}");
+        }
+        self.flush();
+        std::fs::read(self.files.dir.join(name))
+            .with_context(|| format!("reading capture file {name}"))
+    }
+
+    /// Records a datagram exchanged with `peer`, if it's an address of the captured Node.
+    pub fn record(&self, direction: Direction, local: SocketAddr, peer: SocketAddr, data: &[u8]) {
+        let filter = self.filter.load();
+        let Some(filter) = filter.as_ref() else {
+            return;
+        };
+        if !filter.peers.load().contains(&peer) {
+            return;
+        }
+
+        let (src, dst) = match direction {
+            Direction::Incoming => (peer, local),
+            Direction::Outgoing => (local, peer),
+        };
+        let kept = match filter.max_payload {
+            Some(max) => &data[..data.len().min(max)],
+            None => data,
+        };
+        let packet = Packet {
+            ts: SystemTime::now(),
+            src,
+            dst,
+            len: data.len(),
+            kept: kept.to_vec(),
+        };
+        if let Err(TrySendError::Full(_)) = filter.queue.try_send(Command::Record(packet)) {
+            filter.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(active) = self.active.lock().as_ref() {
+            active.flush();
+        }
+    }
+}
+
+fn peers(session_manager: &SessionManager, node_id: NodeId) -> HashSet<SocketAddr> {
+    session_manager
+        .node_sessions(node_id)
+        .iter()
+        .map(|session| session.peer)
+        .collect()
+}
+
+/// pcap file header, little endian, for raw IP packets.
+fn file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // Time zone and timestamp accuracy.
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&(u16::MAX as u32).to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+/// pcap record of a UDP datagram of `len` bytes, of which `kept` were captured.
+/// IPv4 addresses are mapped to IPv6, if the other one is IPv6.
+fn encode_record(
+    ts: SystemTime,
+    src: SocketAddr,
+    dst: SocketAddr,
+    len: usize,
+    kept: &[u8],
+) -> Vec<u8> {
+    let ts = ts.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let udp_len = UDP_HEADER_LEN + len;
+
+    let mut udp = Vec::with_capacity(UDP_HEADER_LEN + kept.len());
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len.min(u16::MAX as usize) as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(kept);
+
+    let ip = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            // UDP checksum is optional over IPv4.
+            ipv4_header(src, dst, udp_len)
+        }
+        (src, dst) => {
+            let (src, dst) = (to_ipv6(src), to_ipv6(dst));
+            // Checksum is required over IPv6, but can't be computed over a truncated payload.
+            if kept.len() == len {
+                let mut data = ipv6_pseudo_header(src, dst, udp_len);
+                data.extend_from_slice(&udp);
+                let checksum = match checksum(&data) {
+                    0 => 0xffff,
+                    checksum => checksum,
+                };
+                udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+            }
+            ipv6_header(src, dst, udp_len)
+        }
+    };
+    let orig_len = ip.len() + udp_len;
+    let incl_len = ip.len() + udp.len();
+
+    let mut record = Vec::with_capacity(16 + incl_len);
+    record.extend_from_slice(&(ts.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&ts.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(incl_len as u32).to_le_bytes());
+    record.extend_from_slice(&(orig_len as u32).to_le_bytes());
+    record.extend_from_slice(&ip);
+    record.extend_from_slice(&udp);
+    record
+}
+
+fn ipv4_header(src: Ipv4Addr, dst: Ipv4Addr, udp_len: usize) -> Vec<u8> {
+    let mut ip = vec![0u8; IPV4_HEADERS_LEN - UDP_HEADER_LEN];
+    ip[0] = 0x45;
+    let total_len = ip.len() + udp_len;
+    ip[2..4].copy_from_slice(&(total_len.min(u16::MAX as usize) as u16).to_be_bytes());
+    // Don't fragment.
+    ip[6] = 0x40;
+    ip[8] = 64;
+    ip[9] = 17;
+    ip[12..16].copy_from_slice(&src.octets());
+    ip[16..20].copy_from_slice(&dst.octets());
+    let checksum = checksum(&ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    ip
+}
+
+fn ipv6_header(src: Ipv6Addr, dst: Ipv6Addr, udp_len: usize) -> Vec<u8> {
+    let mut ip = vec![0u8; IPV6_HEADERS_LEN - UDP_HEADER_LEN];
+    ip[0] = 0x60;
+    ip[4..6].copy_from_slice(&(udp_len.min(u16::MAX as usize) as u16).to_be_bytes());
+    ip[6] = 17;
+    ip[7] = 64;
+    ip[8..24].copy_from_slice(&src.octets());
+    ip[24..40].copy_from_slice(&dst.octets());
+    ip
+}
+
+fn ipv6_pseudo_header(src: Ipv6Addr, dst: Ipv6Addr, udp_len: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(40);
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+    header.extend_from_slice(&(udp_len as u32).to_be_bytes());
+    header.extend_from_slice(&[0, 0, 0, 17]);
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Internet checksum, odd length `data` is padded with zero.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let src: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:7477".parse().unwrap();
+        let ts = UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_123);
+
+        let record = encode_record(ts, src, dst, 100, &[1, 2, 3]);
+        assert_eq!(record.len(), 16 + IPV4_HEADERS_LEN + 3);
+        assert_eq!(&record[0..4], &1_700_000_000u32.to_le_bytes());
+        assert_eq!(&record[4..8], &123u32.to_le_bytes());
+        assert_eq!(&record[8..12], &31u32.to_le_bytes());
+        assert_eq!(&record[12..16], &128u32.to_le_bytes());
+
+        let ip = &record[16..36];
+        assert_eq!(checksum(ip), 0);
+        assert_eq!(&ip[12..16], &[10, 0, 0, 1]);
+        let udp = &record[36..44];
+        assert_eq!(&udp[0..2], &1234u16.to_be_bytes());
+        assert_eq!(&udp[4..6], &108u16.to_be_bytes());
+        assert_eq!(&record[44..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_record_ipv6() {
+        let src: SocketAddr = "[2001:db8::1]:1234".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:7477".parse().unwrap();
+
+        let record = encode_record(UNIX_EPOCH, src, dst, 3, &[1, 2, 3]);
+        assert_eq!(record.len(), 16 + IPV6_HEADERS_LEN + 3);
+        assert_eq!(&record[8..12], &51u32.to_le_bytes());
+        assert_eq!(&record[12..16], &51u32.to_le_bytes());
+
+        let ip = &record[16..56];
+        assert_eq!(ip[0] >> 4, 6);
+        assert_eq!(&ip[4..6], &11u16.to_be_bytes());
+        assert_eq!(ip[6], 17);
+        assert_eq!(
+            &ip[8..24],
+            &"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(
+            &ip[24..40],
+            &"::ffff:10.0.0.2".parse::<Ipv6Addr>().unwrap().octets()
+        );
+
+        // Checksum over the pseudo header and the datagram.
+        let src = to_ipv6(src.ip());
+        let dst = to_ipv6(dst.ip());
+        let mut data = ipv6_pseudo_header(src, dst, 11);
+        data.extend_from_slice(&record[56..]);
+        assert_eq!(checksum(&data), 0);
+
+        // Unknown over a truncated payload.
+        let record = encode_record(
+            UNIX_EPOCH,
+            "[::1]:1".parse().unwrap(),
+            "[::2]:2".parse().unwrap(),
+            100,
+            &[1],
+        );
+        assert_eq!(&record[62..64], &[0, 0]);
+        assert_eq!(&record[12..16], &148u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir =
+            std::env::temp_dir().join(format!("ya-relay-capture-test-{}", std::process::id()));
+        let config = CaptureConfig {
+            capture_dir: Some(dir.clone()),
+            capture_file_size: 1,
+            capture_files: 2,
+        };
+        let session_manager = SessionManager::new();
+        let capture = Capture::new(config, None, &session_manager);
+        let node_id = NodeId::from([1u8; 20]);
+        let local: SocketAddr = "10.0.0.2:7477".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+
+        capture.start(node_id, Some(4)).unwrap();
+        let filter = capture.filter.load_full().unwrap();
+        filter.peers.store(Arc::new(HashSet::from([peer])));
+        for _ in 0..3 {
+            capture.record(Direction::Incoming, local, peer, &[0; 32]);
+        }
+        capture.record(
+            Direction::Incoming,
+            local,
+            "10.0.0.3:1234".parse().unwrap(),
+            &[0; 32],
+        );
+        drop(filter);
+
+        let status = capture.stop().unwrap();
+        assert_eq!(status.packets, 3);
+        assert_eq!(status.bytes, 96);
+        assert_eq!(status.dropped, 0);
+        assert!(capture.stop().is_none());
+
+        // Idle capture doesn't record anything.
+        capture.record(Direction::Incoming, local, peer, &[0; 32]);
+
+        let files = capture.files().unwrap();
+        assert_eq!(files.len(), 2);
+        let data = capture.read(&files[1].name).unwrap();
+        assert_eq!(data.len(), 24);
+        assert!(capture.read("../sessions.state").is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
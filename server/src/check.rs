@@ -0,0 +1,366 @@
+//! Validating the configuration before the server starts.
+//!
+//! Some misconfigurations would only show up once the server is running, e.g. a state
+//! directory which isn't writable fails saving the state on shutdown, and a key file
+//! which can't be parsed fails the start after the metrics are already registered.
+//! [`check`] finds them upfront, so they can be reported all at once. The server runs
+//! it on start, and exits right after it with `--check`.
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::Path;
+
+use ya_relay_core::server_identity;
+
+use crate::server::{edge_key_from_hex, server_key_path, sessions_path, slots_path};
+use crate::Config;
+
+/// Base difficulty of session challenges, above which clients need minutes to connect.
+const MAX_DIFFICULTY: u64 = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    /// Command line option the problem is about, without the leading dashes.
+    pub option: &'static str,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(option: &'static str, message: impl Into<String>) -> Self {
+        Problem {
+            option,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "--{}: {}", self.option, self.message)
+    }
+}
+
+/// Finds problems with the configuration. Addresses are bound for a moment to find out,
+/// whether they are available, and a file is written to the state directory, but nothing
+/// is sent over the network.
+pub fn check(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    check_addresses(config, &mut problems);
+    if let Some(state_dir) = &config.state_dir {
+        check_state_dir(state_dir, config, &mut problems);
+    }
+    check_limits(config, &mut problems);
+    problems
+}
+
+fn check_addresses(config: &Config, problems: &mut Vec<Problem>) {
+    if let Err(e) = UdpSocket::bind(config.server.address) {
+        problems.push(Problem::new(
+            "listen-on",
+            format!("can't bind {}: {e}", config.server.address),
+        ));
+    }
+
+    let mut http: Vec<(&'static str, SocketAddr)> =
+        vec![("metrics-scrape-addr", config.metrics_scrape_addr)];
+    if let Some(addr) = config.admin_http_addr {
+        http.push(("admin-http-addr", addr));
+    }
+    #[cfg(feature = "grpc-admin")]
+    if let Some(addr) = config.admin_grpc_addr {
+        if config.admin_grpc_token.is_none() && !addr.ip().is_loopback() {
+            problems.push(Problem::new(
+                "admin-grpc-addr",
+                format!("{addr} is not a loopback address, requires --admin-grpc-token"),
+            ));
+        }
+        http.push(("admin-grpc-addr", addr));
+    }
+    if let Some(addr) = config.server.ws_listen_addr {
+        http.push(("ws-listen-addr", addr));
+    }
+    // Listeners are kept until all of them are bound, so overlapping addresses fail.
+    let mut listeners = Vec::new();
+    for (i, &(option, addr)) in http.iter().enumerate() {
+        if addr.port() != 0 {
+            if let Some((other, _)) = http[..i].iter().find(|(_, other)| *other == addr) {
+                problems.push(Problem::new(option, format!("{addr} is used by --{other}")));
+                continue;
+            }
+        }
+        match TcpListener::bind(addr) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => problems.push(Problem::new(option, format!("can't bind {addr}: {e}"))),
+        }
+    }
+
+    let edge = &config.edge;
+    match edge.core_relay {
+        Some(core) if core.ip().is_unspecified() || core.port() == 0 => {
+            problems.push(Problem::new(
+                "core-relay",
+                format!("{core} is not an address of a relay"),
+            ));
+        }
+        Some(_) => {
+            if edge.core_relay_key.is_none() {
+                problems.push(Problem::new(
+                    "core-relay",
+                    "requires --core-relay-key to verify the core relay",
+                ));
+            }
+        }
+        None => {
+            if edge.edge_public_addr.is_some() {
+                problems.push(Problem::new(
+                    "edge-public-addr",
+                    "has no effect without --core-relay",
+                ));
+            }
+        }
+    }
+}
+
+fn check_state_dir(state_dir: &Path, config: &Config, problems: &mut Vec<Problem>) {
+    match fs::metadata(state_dir) {
+        Ok(metadata) if metadata.is_dir() => (),
+        Ok(_) => {
+            problems.push(Problem::new(
+                "state-dir",
+                format!("{} is not a directory", state_dir.display()),
+            ));
+            return;
+        }
+        Err(e) => {
+            problems.push(Problem::new(
+                "state-dir",
+                format!("{}: {e}", state_dir.display()),
+            ));
+            return;
+        }
+    }
+
+    // State is saved on shutdown, when it's too late to fix permissions.
+    let probe = state_dir.join(".check");
+    match fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+        }
+        Err(e) => problems.push(Problem::new(
+            "state-dir",
+            format!("{} is not writable: {e}", state_dir.display()),
+        )),
+    }
+
+    for path in [slots_path(state_dir), sessions_path(state_dir)] {
+        if path.exists() {
+            if let Err(e) = fs::File::open(&path) {
+                problems.push(Problem::new(
+                    "state-dir",
+                    format!("can't read {}: {e}", path.display()),
+                ));
+            }
+        }
+    }
+
+    if config.session_handler.server_key.is_none() {
+        let path = server_key_path(state_dir);
+        check_key_file(&path, problems, |hex_str| {
+            server_identity::secret_key_from_hex(hex_str).map(|_| ())
+        });
+    }
+    if config.edge.core_relay.is_some() && config.edge.edge_key.is_none() {
+        let path = state_dir.join("edge.key");
+        check_key_file(&path, problems, |hex_str| {
+            edge_key_from_hex(hex_str).map(|_| ())
+        });
+    }
+}
+
+/// Key files are generated when missing, but existing ones have to be valid.
+fn check_key_file(
+    path: &Path,
+    problems: &mut Vec<Problem>,
+    parse: impl FnOnce(&str) -> anyhow::Result<()>,
+) {
+    let result = match fs::read_to_string(path) {
+        Ok(hex_str) => parse(hex_str.trim()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        problems.push(Problem::new(
+            "state-dir",
+            format!("invalid key in {}: {e}", path.display()),
+        ));
+    }
+}
+
+fn check_limits(config: &Config, problems: &mut Vec<Problem>) {
+    let server = &config.server;
+    let session_manager = &config.session_manager;
+    let session_handler = &config.session_handler;
+
+    for (option, value) in [
+        ("workers", server.workers),
+        ("tasks-per-worker", server.tasks_per_worker),
+        ("max-pending-forwards", server.max_pending_forwards),
+        ("sse-client-queue", config.sse.sse_client_queue),
+    ] {
+        if value == 0 {
+            problems.push(Problem::new(option, "must be greater than 0"));
+        }
+    }
+
+    for (option, value) in [
+        (
+            "session-cleaner-interval",
+            session_manager.session_cleaner_interval,
+        ),
+        (
+            "session-compaction-interval",
+            session_manager.session_compaction_interval,
+        ),
+        ("handshake-window", session_handler.handshake_window),
+        ("edge-sync-interval", config.edge.edge_sync_interval),
+    ] {
+        if value.is_zero() {
+            problems.push(Problem::new(option, "must be longer than 0s"));
+        }
+    }
+
+    if session_manager.session_purge_timeout <= session_manager.session_cleaner_interval {
+        problems.push(Problem::new(
+            "session-purge-timeout",
+            "must be longer than --session-cleaner-interval",
+        ));
+    }
+    if session_handler.difficulty > MAX_DIFFICULTY {
+        problems.push(Problem::new(
+            "difficulty",
+            format!("must be at most {MAX_DIFFICULTY}"),
+        ));
+    }
+    if session_handler.ip_session_quota > 0 && session_handler.ip_session_window.is_zero() {
+        problems.push(Problem::new(
+            "ip-session-window",
+            "must be longer than 0s, when --ip-session-quota is set",
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config(args: &[&str]) -> Config {
+        let defaults = [
+            "ya-relay-server",
+            "--listen-on",
+            "127.0.0.1:0",
+            "--metrics-scrape-addr",
+            "127.0.0.1:0",
+        ];
+        Config::try_parse_from(defaults.iter().chain(args)).unwrap()
+    }
+
+    fn options(problems: &[Problem]) -> Vec<&'static str> {
+        problems.iter().map(|problem| problem.option).collect()
+    }
+
+    #[test]
+    fn test_valid() {
+        assert_eq!(check(&config(&[])), vec![]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let config = config(&[
+            "--workers",
+            "0",
+            "--session-compaction-interval",
+            "0s",
+            "--session-purge-timeout",
+            "5s",
+            "--difficulty",
+            "64",
+        ]);
+        assert_eq!(
+            options(&check(&config)),
+            vec![
+                "workers",
+                "session-compaction-interval",
+                "session-purge-timeout",
+                "difficulty"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_addresses() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = taken.local_addr().unwrap().to_string();
+        let config = config(&[
+            "--metrics-scrape-addr",
+            &taken,
+            "--admin-http-addr",
+            &taken,
+            "--edge-public-addr",
+            "10.0.0.1:7477",
+        ]);
+        assert_eq!(
+            options(&check(&config)),
+            vec!["metrics-scrape-addr", "admin-http-addr", "edge-public-addr"]
+        );
+    }
+
+    #[cfg(feature = "grpc-admin")]
+    #[test]
+    fn test_admin_grpc_token() {
+        let exposed = config(&["--admin-grpc-addr", "0.0.0.0:0"]);
+        assert_eq!(options(&check(&exposed)), vec!["admin-grpc-addr"]);
+
+        let protected = config(&[
+            "--admin-grpc-addr",
+            "0.0.0.0:0",
+            "--admin-grpc-token",
+            "secret",
+        ]);
+        assert_eq!(check(&protected), vec![]);
+        assert_eq!(
+            check(&config(&["--admin-grpc-addr", "127.0.0.1:0"])),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_core_relay_key() {
+        let unverified = config(&["--core-relay", "10.0.0.1:7477"]);
+        assert_eq!(options(&check(&unverified)), vec!["core-relay"]);
+
+        let key = hex::encode(
+            ya_relay_core::crypto::ed25519::generate()
+                .verifying_key()
+                .as_bytes(),
+        );
+        let verified = config(&["--core-relay", "10.0.0.1:7477", "--core-relay-key", &key]);
+        assert_eq!(check(&verified), vec![]);
+    }
+
+    #[test]
+    fn test_state_dir() {
+        let state_dir =
+            std::env::temp_dir().join(format!("ya-relay-check-{}", rand::random::<u64>()));
+        let missing = config(&["--state-dir", state_dir.to_str().unwrap()]);
+        assert_eq!(options(&check(&missing)), vec!["state-dir"]);
+
+        fs::create_dir(&state_dir).unwrap();
+        assert_eq!(check(&missing), vec![]);
+
+        fs::write(server_key_path(&state_dir), "not a key").unwrap();
+        let problems = check(&missing);
+        fs::remove_dir_all(&state_dir).unwrap();
+        assert_eq!(options(&problems), vec!["state-dir"]);
+    }
+}
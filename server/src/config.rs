@@ -1,3 +1,4 @@
+use crate::access::{parse_token, AccessPolicy, IpNetwork};
 use crate::server::{ServerConfig, SessionHandlerConfig};
 use crate::SessionManagerConfig;
 use clap::Parser;
@@ -8,8 +9,40 @@ use std::path::PathBuf;
 pub struct Config {
     #[arg(long, env, default_value = "127.0.0.1:9000")]
     pub metrics_scrape_addr: std::net::SocketAddr,
+    /// Bearer token required to scrape metrics
+    #[arg(long, env, value_parser = parse_token)]
+    pub metrics_token: Option<String>,
+    /// Networks allowed to scrape metrics, all if empty
+    #[arg(long, env, value_delimiter = ',')]
+    pub metrics_allow: Vec<IpNetwork>,
+    /// Address of the HTTP interface listing sessions and Nodes.
+    /// Served with the metrics and protected the same way if not set
+    #[arg(long, env)]
+    pub admin_http_addr: Option<std::net::SocketAddr>,
+    /// Bearer token required by the HTTP interface at `admin_http_addr`
+    #[arg(long, env, value_parser = parse_token)]
+    pub admin_http_token: Option<String>,
+    /// Networks allowed to use the HTTP interface at `admin_http_addr`, all if empty
+    #[arg(long, env, value_delimiter = ',')]
+    pub admin_http_allow: Vec<IpNetwork>,
     #[arg(long, env = "STATE_DIRECTORY")]
     pub state_dir: Option<PathBuf>,
+    /// Validate the configuration and exit without starting the server
+    #[arg(long)]
+    pub check: bool,
+    /// Populate the server with N synthetic sessions forwarding traffic to each other,
+    /// for developing dashboards and tooling without a live fleet
+    #[arg(long, env, value_name = "N")]
+    pub simulate: Option<usize>,
+    /// Address of the admin gRPC interface, disabled if not set
+    #[cfg(feature = "grpc-admin")]
+    #[arg(long, env)]
+    pub admin_grpc_addr: Option<std::net::SocketAddr>,
+    /// Bearer token required by the admin gRPC interface. Required, unless
+    /// `admin_grpc_addr` is a loopback address
+    #[cfg(feature = "grpc-admin")]
+    #[arg(long, env, value_parser = parse_token)]
+    pub admin_grpc_token: Option<String>,
 
     #[command(flatten)]
     pub server: ServerConfig,
@@ -22,6 +55,30 @@ pub struct Config {
 
     #[command(flatten)]
     pub ip_check: crate::server::IpCheckerConfig,
+
+    #[command(flatten)]
+    pub edge: crate::server::EdgeConfig,
+
+    #[command(flatten)]
+    pub sse: crate::sse::SseConfig,
+
+    #[command(flatten)]
+    pub capture: crate::capture::CaptureConfig,
+}
+
+impl Config {
+    pub fn metrics_access(&self) -> AccessPolicy {
+        AccessPolicy::new(self.metrics_token.clone(), self.metrics_allow.clone())
+    }
+
+    pub fn admin_http_access(&self) -> AccessPolicy {
+        AccessPolicy::new(self.admin_http_token.clone(), self.admin_http_allow.clone())
+    }
+
+    #[cfg(feature = "grpc-admin")]
+    pub fn admin_grpc_access(&self) -> AccessPolicy {
+        AccessPolicy::new(self.admin_grpc_token.clone(), vec![])
+    }
 }
 
 #[test]
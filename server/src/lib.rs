@@ -1,13 +1,26 @@
 #![allow(dead_code)]
+pub mod access;
+#[cfg(feature = "grpc-admin")]
+pub mod admin;
+pub mod capture;
+pub mod check;
 mod config;
 pub mod metrics;
+pub mod plugin;
 mod server;
+pub mod simulate;
+pub mod sse;
 mod state;
 #[cfg(feature = "test-utils")]
 pub mod testing;
 pub mod udp_server;
 
 pub use state::session_manager::*;
+pub use state::forward_limiter::{ForwardLimiter, LimiterStats, QueueState};
+pub use state::history::{HistoryEntry, SessionHistory};
+pub use state::maintenance::{Maintenance, MaintenanceKind, MaintenanceNotice};
+pub use state::traffic::{PairTraffic, TrafficMatrix};
+pub use state::Limits;
 
 pub use config::Config;
 pub use server::run;
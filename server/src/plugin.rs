@@ -0,0 +1,85 @@
+//! Extension points of the packet pipeline.
+//!
+//! Deployments add custom policies, e.g. billing, audit logging or experimental
+//! routing rules, by registering a [`Plugin`] in `Config::server.plugins`. Plugins
+//! are called synchronously by the workers handling packets, so they should return
+//! quickly and move heavier work elsewhere.
+use std::sync::Arc;
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+
+use crate::state::session_manager::{Session, SessionEventKind};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    /// Forward is dropped without notifying the sender.
+    Drop,
+}
+
+/// Forward between two Nodes, about to be sent to the receiving Node.
+#[derive(Clone, Debug)]
+pub struct ForwardInfo<'a> {
+    pub src_node_id: NodeId,
+    pub src_session: SessionId,
+    pub dst_node_id: NodeId,
+    pub dst_session: SessionId,
+    /// Payload as sent, usually encrypted between the Nodes.
+    pub payload: &'a [u8],
+    pub reliable: bool,
+    /// Set for forwards to members of a group.
+    pub group: Option<&'a str>,
+}
+
+pub trait Plugin: Send + Sync {
+    /// Called when a Node completed the handshake and its session was stored.
+    fn on_session_established(&self, _session: &Session) {}
+
+    /// Called for every forward, including each member of a group, before it's
+    /// queued for sending. Forwards failing authentication don't reach plugins.
+    fn on_forward(&self, _forward: &ForwardInfo) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// Called after the session was removed, either [`SessionEventKind::Removed`] on
+    /// the Node's request or when it became unreachable, or [`SessionEventKind::Purged`]
+    /// after it wasn't seen for the purge timeout.
+    fn on_disconnect(&self, _session: &Session, _kind: SessionEventKind) {}
+}
+
+/// Plugins in the order of registration. A forward dropped by a plugin
+/// isn't passed to the following ones.
+#[derive(Clone, Default)]
+pub struct Plugins(Vec<Arc<dyn Plugin>>);
+
+impl Plugins {
+    pub fn add(&mut self, plugin: impl Plugin + 'static) {
+        self.0.push(Arc::new(plugin));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn session_established(&self, session: &Session) {
+        for plugin in &self.0 {
+            plugin.on_session_established(session);
+        }
+    }
+
+    pub(crate) fn forward(&self, forward: &ForwardInfo) -> Verdict {
+        for plugin in &self.0 {
+            if plugin.on_forward(forward) == Verdict::Drop {
+                return Verdict::Drop;
+            }
+        }
+        Verdict::Pass
+    }
+
+    pub(crate) fn disconnect(&self, session: &Session, kind: SessionEventKind) {
+        for plugin in &self.0 {
+            plugin.on_disconnect(session, kind);
+        }
+    }
+}
@@ -1,16 +1,21 @@
+use std::io::Write;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use metrics::Counter;
 use quick_cache::sync::Cache;
 use rand::{thread_rng, Rng};
-use tokio_util::codec::Decoder;
+use tokio_util::codec::{Decoder, Encoder};
 
 use ya_relay_core::challenge;
 use ya_relay_core::challenge::ChallengeDigest;
+use ya_relay_core::crypto::ed25519;
+use ya_relay_core::intercept::{Direction, InterceptorRef, Verdict};
+use ya_relay_core::server_identity::{self, PublicKey, SecretKey};
 use ya_relay_core::server_session::SessionId;
 use ya_relay_proto::codec::datagram::Codec;
 use ya_relay_proto::codec::{BytesMut, PacketKind};
@@ -19,11 +24,29 @@ use ya_relay_proto::proto::{
     StatusCode,
 };
 
+use crate::capture::Capture;
+use crate::plugin::Plugins;
+use crate::simulate::Simulation;
+use crate::state::edge_directory::EdgeDirectory;
+use crate::state::forward_limiter::ForwardLimiter;
+use crate::state::group_manager::GroupManager;
+use crate::state::history::SessionHistory;
+use crate::state::load::RelayLoad;
+use crate::state::replay_guard::ReplayGuard;
+use crate::state::response_cache::{Cached, RequestKey, ResponseCache};
 use crate::state::slot_manager::SlotManager;
-use crate::state::Clock;
+use crate::state::traffic::TrafficMatrix;
+use crate::state::{Clock, Limits};
 use crate::udp_server::{worker_err_fn, PacketType, UdpServer, UdpServerBuilder, UdpSocket};
-use crate::{Config, SessionManager};
+use crate::{Config, Maintenance, SessionManager};
 
+mod addr_refresh;
+
+mod edge;
+mod find_nodes;
+mod locate;
+mod maintenance;
+mod nearest;
 mod neighbours;
 mod session;
 
@@ -33,6 +56,8 @@ mod slot;
 
 mod forward;
 
+mod group;
+
 mod register;
 
 mod reverse_connection;
@@ -41,8 +66,19 @@ mod state_decoder;
 
 mod ip_checker;
 
+mod nat_check;
+
+mod slot_invalidation;
+
+mod ws_gateway;
+
+pub(crate) use edge::edge_key_from_hex;
+pub use edge::{CoreLink, EdgeConfig};
 pub use ip_checker::IpCheckerConfig;
+#[cfg(any(test, feature = "test-utils"))]
+pub(crate) use session::new_session_id;
 pub use session::SessionHandlerConfig;
+use ws_gateway::WsGateway;
 
 #[derive(clap::Args)]
 /// Ip Checker configuration args
@@ -59,6 +95,28 @@ pub struct ServerConfig {
     pub workers: usize,
     #[arg(long, env = "RELAY_TASKS_PER_WORKER", default_value = "32")]
     pub tasks_per_worker: usize,
+    /// Forwards from a single session waiting to be sent. Above the limit forwards are
+    /// dropped. Sender is asked to slow down, when the half of it is reached.
+    #[arg(long, env = "RELAY_MAX_PENDING_FORWARDS", default_value = "256")]
+    pub max_pending_forwards: usize,
+    /// Responses to control requests are kept for that long, so retried requests
+    /// are answered again without handling them twice.
+    #[arg(long, env = "RELAY_RESPONSE_CACHE_TTL", value_parser = humantime::parse_duration, default_value = "10s")]
+    pub response_cache_ttl: Duration,
+    /// Responses kept at most. The oldest ones are forgotten above that.
+    #[arg(long, env = "RELAY_RESPONSE_CACHE_SIZE", default_value = "4096")]
+    pub response_cache_size: usize,
+    /// Address accepting relay connections over WebSocket, e.g. from browsers.
+    /// Disabled if not set.
+    #[arg(long, env = "RELAY_WS_LISTEN_ADDR")]
+    pub ws_listen_addr: Option<SocketAddr>,
+    /// Test hook applied to received packets and to the responses sent back.
+    /// Packets forwarded between Nodes are intercepted only on receipt.
+    #[arg(skip)]
+    pub interceptor: Option<InterceptorRef>,
+    /// Custom policies applied to sessions and forwards, see [`crate::plugin`].
+    #[arg(skip)]
+    pub plugins: Plugins,
 }
 
 fn default_workers() -> usize {
@@ -72,22 +130,74 @@ pub struct Server {
     udp_server: UdpServer,
     pub(crate) session_manager: Arc<SessionManager>,
     slot_manager: Arc<SlotManager>,
+    limits: Arc<Limits>,
+    traffic: Arc<TrafficMatrix>,
+    history: Arc<SessionHistory>,
+    maintenance: Arc<Maintenance>,
+    capture: Arc<Capture>,
+    forward_limiter: Arc<ForwardLimiter>,
+    public_key: PublicKey,
+    history_task: tokio::task::JoinHandle<()>,
+    core_link_tasks: Vec<tokio::task::JoinHandle<()>>,
+    simulation_task: Option<tokio::task::JoinHandle<()>>,
+    ws_gateway: Option<WsGateway>,
 }
 
 #[inline]
-fn slots_path<P: AsRef<Path>>(state_dir: P) -> PathBuf {
+pub(crate) fn slots_path<P: AsRef<Path>>(state_dir: P) -> PathBuf {
     state_dir.as_ref().join("slots.state")
 }
 
 #[inline]
-fn sessions_path<P: AsRef<Path>>(state_dir: P) -> PathBuf {
+pub(crate) fn sessions_path<P: AsRef<Path>>(state_dir: P) -> PathBuf {
     state_dir.as_ref().join("sessions.state")
 }
 
+#[inline]
+pub(crate) fn server_key_path<P: AsRef<Path>>(state_dir: P) -> PathBuf {
+    state_dir.as_ref().join("server.key")
+}
+
+/// Keeps the key in the state directory, so clients trusting it on first use accept
+/// the server after restart.
+fn load_or_generate_server_key(path: &Path) -> anyhow::Result<SecretKey> {
+    match std::fs::read_to_string(path) {
+        Ok(hex_str) => server_identity::secret_key_from_hex(hex_str.trim()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let secret = ed25519::generate();
+            write_secret(path, hex::encode(secret.to_bytes()).as_bytes())?;
+            Ok(secret)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes a key readable only by the owner. Replaces the file in one step, so the key
+/// is never read partially written.
+pub(crate) fn write_secret(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::remove_file(&tmp).ok();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp)?.write_all(contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
 impl Server {
     pub fn save_state(&self, state_dir: &Path) -> anyhow::Result<()> {
+        // Synthetic sessions would be restored as real ones.
+        if self.simulation_task.is_some() {
+            log::warn!("state of a simulation is not saved");
+            return Ok(());
+        }
+        // Slots are never freed, so saving them after taking the snapshot covers
+        // the Nodes of all saved sessions.
+        let sessions = self.session_manager.snapshot();
         self.slot_manager.save(&slots_path(state_dir))?;
-        self.session_manager.save(&sessions_path(state_dir))?;
+        sessions.save(&sessions_path(state_dir))?;
         Ok(())
     }
 
@@ -99,6 +209,45 @@ impl Server {
         self.session_manager.clone()
     }
 
+    pub fn limits(&self) -> Arc<Limits> {
+        self.limits.clone()
+    }
+
+    /// Traffic forwarded between Nodes recently.
+    pub fn traffic(&self) -> Arc<TrafficMatrix> {
+        self.traffic.clone()
+    }
+
+    /// Recent sessions of each Node.
+    pub fn history(&self) -> Arc<SessionHistory> {
+        self.history.clone()
+    }
+
+    /// Announces planned shutdowns and restarts to connected Nodes.
+    pub fn maintenance(&self) -> Arc<Maintenance> {
+        self.maintenance.clone()
+    }
+
+    /// Captures packets exchanged with a single Node, see [`crate::capture`].
+    pub fn capture(&self) -> Arc<Capture> {
+        self.capture.clone()
+    }
+
+    /// Queues of forwards from each session, see [`crate::state::forward_limiter`].
+    pub fn forward_limiter(&self) -> Arc<ForwardLimiter> {
+        self.forward_limiter.clone()
+    }
+
+    /// Key signing session handshake responses.
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Address accepting connections over WebSocket, see [`ServerConfig::ws_listen_addr`].
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws_gateway.as_ref().map(WsGateway::local_addr)
+    }
+
     #[cfg(feature = "test-utils")]
     pub fn stop(&self) {}
 }
@@ -106,31 +255,49 @@ impl Server {
 impl Drop for Server {
     fn drop(&mut self) {
         self.udp_server.stop_internal();
+        self.history_task.abort();
+        for task in &self.core_link_tasks {
+            task.abort();
+        }
+        if let Some(task) = &self.simulation_task {
+            task.abort();
+        }
     }
 }
 
 pub async fn run(config: &Config) -> anyhow::Result<Server> {
     let bind_addr: SocketAddr = config.server.address;
 
-    let slot_manager = config
-        .state_dir
-        .as_ref()
-        .map(slots_path)
-        .and_then(|p| {
-            if p.exists() {
-                SlotManager::load(&p).ok()
-            } else {
-                None
+    let slot_manager = config.state_dir.as_ref().map(slots_path).and_then(|p| {
+        if p.exists() {
+            match SlotManager::load(&p) {
+                Ok(v) => {
+                    log::info!("slots loaded, epoch {}", v.epoch());
+                    Some(v)
+                }
+                Err(e) => {
+                    log::error!("failed to load slots: {:?}", e);
+                    None
+                }
             }
-        })
-        .unwrap_or_else(|| SlotManager::new());
+        } else {
+            None
+        }
+    });
+    let slots_restored = slot_manager.is_some();
+    let slot_manager = slot_manager.unwrap_or_else(|| SlotManager::new());
 
+    // Nodes resuming restored sessions would keep using slots assigned before the restart.
+    // Without them sessions aren't restored, so Nodes register again and learn the new slots.
     let session_manager = config
         .state_dir
         .as_ref()
         .map(sessions_path)
         .and_then(|p| {
-            if p.exists() {
+            if p.exists() && !slots_restored {
+                log::warn!("slots not restored, dropping saved sessions");
+                None
+            } else if p.exists() {
                 match SessionManager::load(&p) {
                     Ok(v) => {
                         log::info!("sessions loaded");
@@ -147,147 +314,316 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
         })
         .unwrap_or_else(|| SessionManager::new());
 
+    let server_key = match (&config.session_handler.server_key, &config.state_dir) {
+        (Some(secret), _) => secret.clone(),
+        (None, Some(state_dir)) => load_or_generate_server_key(&server_key_path(state_dir))?,
+        (None, None) => ed25519::generate(),
+    };
+    let public_key = server_key.verifying_key();
+    log::info!("Server public key: {}", hex::encode(public_key.as_bytes()));
+
     let server_config = &config.server;
     let session_handler_config = config.session_handler.clone();
     let ip_check_config = config.ip_check.clone();
-
-    session_manager.start_cleanup_processor(&config.session_manager);
+    let interceptor = server_config.interceptor.clone();
+    let plugins = server_config.plugins.clone();
+    session_manager.set_plugins(plugins.clone());
+    let forward_limiter = ForwardLimiter::new(
+        server_config.max_pending_forwards,
+        config.session_handler.max_forward_size as usize,
+    );
+
+    let limits = Limits::new(
+        config.session_handler.difficulty,
+        config.session_manager.session_purge_timeout,
+    );
+    session_manager.start_cleanup_processor(&config.session_manager, &limits);
+    session_manager.start_compaction(&config.session_manager);
+    let traffic = TrafficMatrix::new();
+    let history = SessionHistory::new(
+        config.session_manager.session_history_nodes,
+        config.session_manager.session_history_len,
+        config.session_manager.session_reconnect_window,
+    );
+    let history_task = history.start(session_manager.subscribe());
+    let simulation_task = config.simulate.map(|count| {
+        log::warn!("simulating {count} synthetic sessions");
+        Simulation::new(&session_manager, &traffic, count).start()
+    });
 
     let ip_test_cache: IpCache =
         Arc::new(quick_cache::sync::Cache::<SocketAddr, (Instant, bool)>::new(128));
+    let addr_refresh_interval = config.session_manager.addr_refresh_interval;
+    let addr_status_max_age = config.session_manager.addr_status_max_age;
+    // Addresses are re-checked by a single worker.
+    let addr_refresher_started = Arc::new(AtomicBool::new(addr_refresh_interval.is_zero()));
+    // Disconnected Nodes and maintenance notices are announced by a single worker.
+    let slot_invalidator_started = Arc::new(AtomicBool::new(false));
+    let maintenance = Maintenance::new();
+    let capture = Capture::new(
+        config.capture.clone(),
+        config.state_dir.as_deref(),
+        &session_manager,
+    );
+
+    // Groups aren't persisted. Nodes have to join them again after the server restart.
+    let group_manager = GroupManager::new();
+    // Shared by workers, because a replayed packet may reach any of them.
+    let replay_guard = ReplayGuard::new(config.session_handler.handshake_window);
+    // Shared by workers, because a retried request may reach another one.
+    let response_cache = ResponseCache::new(
+        server_config.response_cache_ttl,
+        server_config.response_cache_size,
+    );
+
+    let edge_config = &config.edge;
+    let edge_directory = EdgeDirectory::new(edge_config.edge_ttl);
+    if !edge_config.allowed_edges.is_empty() {
+        edge_directory.start_cleanup();
+    }
+    let allowed_edges = Arc::new(edge_config.allowed_edges.clone());
+    let core_link = match edge_config.core_relay {
+        Some(core) => {
+            let core_key = edge_config
+                .core_relay_key
+                .ok_or_else(|| anyhow::anyhow!("--core-relay requires --core-relay-key"))?;
+            Some(CoreLink::bind(core, core_key).await?)
+        }
+        None => None,
+    };
 
     let server = {
         let session_manager = session_manager.clone();
         let slot_manager = slot_manager.clone();
+        let limits = limits.clone();
+        let traffic = traffic.clone();
+        let maintenance = maintenance.clone();
+        let capture = capture.clone();
+        let forward_limiter = forward_limiter.clone();
+        let core_link = core_link.clone();
 
         UdpServerBuilder::new(move |reply: Rc<UdpSocket>| {
             let session_manager = session_manager.clone();
             let slot_manager = slot_manager.clone();
-            let checker_ip = reply.local_addr()?.ip();
-
-            let session_handler = session::SessionHandler::new(&session_manager, &session_handler_config);
+            let local_addr = reply.local_addr()?;
+            let checker_ip = local_addr.ip();
+            reply.set_send_tap({
+                let capture = capture.clone();
+                Arc::new(move |data: &[u8], dst| capture.record(Direction::Outgoing, local_addr, dst, data))
+            });
+
+            let session_handler = session::SessionHandler::new(&session_manager, &replay_guard, &server_key, &session_handler_config, &limits, &traffic, &reply);
+            let load = RelayLoad::new(&session_handler_config, &session_manager, &traffic);
             let ip_checker = ip_check_config.build(checker_ip)?;
-            let register_handler = register::RegisterHandler::new(&session_manager, &slot_manager, ip_checker, &reply, ip_test_cache.clone());
+            let register_handler = register::RegisterHandler::new(&session_manager, &slot_manager, ip_checker, &reply, ip_test_cache.clone(), &response_cache);
             let neighbours_handler = neighbours::NeighboursHandler::new(&session_manager, &slot_manager);
+            let find_nodes_handler = find_nodes::FindNodesHandler::new(&session_manager, &slot_manager);
+            let nearest_handler = nearest::NearestHandler::new(&session_manager, &slot_manager);
+            let locate_handler = locate::LocateHandler::new(&session_manager, &edge_directory, &core_link, &reply);
+            let edge_summary_handler = edge::EdgeSummaryHandler::new(&session_manager, &edge_directory, &allowed_edges);
             let node_handler = node::NodeHandler::new(&session_manager, &slot_manager);
             let slot_handler = slot::SlotHandler::new(&session_manager, &slot_manager);
-            let forward_handler = forward::ForwardHandler::new(&session_manager, &slot_manager, &reply);
+            let forward_handler = forward::ForwardHandler::new(&session_manager, &slot_manager, &group_manager, &plugins, &traffic, &reply, &forward_limiter);
+            let group_handler = group::GroupHandler::new(&session_manager, &group_manager);
             let rc_handler = reverse_connection::RcHandler::new(&session_manager, &reply);
-
-            worker_err_fn(move |pt, mut packet: BytesMut, src| {
-                let mut codec = Codec;
-                let reply = reply.clone();
-                let p = codec.decode(&mut packet)?.ok_or_else(|| anyhow::anyhow!("invalid packet"))?;
-
-                let clock = Clock::now();
-
-                let response =
-                    match pt {
-                        PacketType::Other => {
-                            log::error!("[{src}] recv unknown error");
-                            None
-                        }
-                        PacketType::Unreachable(reason) => {
-                            match p {
-                                PacketKind::Forward(Forward { session_id, .. }) => {
-                                    let session_id = SessionId::from(session_id);
-                                    if let Some(session_ref) = session_manager.session(&session_id) {
-                                        if session_ref.peer == src && session_ref.addr_status.lock().age() > Duration::from_secs(300) {
-                                            log::info!("[{src}] Unreachable (forward) {reason:?} removing session");
-                                            session_manager.remove_session(&session_id);
-                                        }
+            let nat_check_handler = nat_check::NatCheckHandler::new(&session_manager, checker_ip)?;
+            if !addr_refresher_started.swap(true, Ordering::SeqCst) {
+                let ip_checker = ip_check_config.build(checker_ip)?;
+                addr_refresh::AddrRefresher::new(&session_manager, ip_checker, &reply, ip_test_cache.clone())
+                    .spawn(addr_refresh_interval, addr_status_max_age);
+            }
+            if !slot_invalidator_started.swap(true, Ordering::SeqCst) {
+                slot_invalidation::SlotInvalidator::new(&session_manager, &slot_manager, &traffic, &reply)
+                    .spawn(session_manager.subscribe());
+                maintenance::MaintenanceNotifier::new(&session_manager, &reply).spawn(&maintenance);
+            }
+            let interceptor = interceptor.clone();
+            let response_cache = response_cache.clone();
+            let capture = capture.clone();
+
+            let handle = Rc::new(move |clock: &Clock, pt: PacketType, p: PacketKind, src: SocketAddr| -> Option<(CompletionHandler, Packet)> {
+                match pt {
+                    PacketType::Other => {
+                        log::error!("[{src}] recv unknown error");
+                        None
+                    }
+                    PacketType::Unreachable(reason) => {
+                        match p {
+                            PacketKind::Forward(Forward { session_id, .. }) => {
+                                let session_id = SessionId::from(session_id);
+                                if let Some(session_ref) = session_manager.session(&session_id) {
+                                    if session_ref.peer == src && session_ref.addr_status.lock().age() > Duration::from_secs(300) {
+                                        log::info!("[{src}] Unreachable (forward) {reason:?} removing session");
+                                        session_manager.remove_session(&session_id);
                                     }
-                                    None
-                                }
-                                PacketKind::Packet(Packet { session_id, kind: Some(packet::Kind::Control(Control { kind: Some(control::Kind::ReverseConnection(_)) })) }) => {
-                                    session_id.try_into().ok().and_then(|session_id| session_manager.session(&session_id))
-                                        .and_then(|session_ref| {
-                                            if session_ref.peer == src && session_ref.addr_status.lock().age() > Duration::from_secs(300) {
-                                                log::info!("[{src}] Unreachable (reverse connection) {reason:?} removing session");
-                                                session_manager.remove_session(&session_ref.session_id);
-                                            }
-                                            None
-                                        })
-                                }
-                                _ => {
-                                    None
                                 }
+                                None
+                            }
+                            PacketKind::Packet(Packet { session_id, kind: Some(packet::Kind::Control(Control { kind: Some(control::Kind::ReverseConnection(_)) })) }) => {
+                                session_id.try_into().ok().and_then(|session_id| session_manager.session(&session_id))
+                                    .and_then(|session_ref| {
+                                        if session_ref.peer == src && session_ref.addr_status.lock().age() > Duration::from_secs(300) {
+                                            log::info!("[{src}] Unreachable (reverse connection) {reason:?} removing session");
+                                            session_manager.remove_session(&session_ref.session_id);
+                                        }
+                                        None
+                                    })
+                            }
+                            _ => {
+                                None
                             }
                         }
-                        PacketType::Data => match p {
-                            PacketKind::Packet(Packet { session_id, kind: Some(packet::Kind::Request(Request { request_id, kind: Some(request) })) }) => {
-                                let session_id: Option<SessionId> = session_id.try_into().ok();
-
-                                log::debug!("[{src}] got session_id={:?}: request_id={}: {:?}", session_id, request_id, request);
-
-                                match request {
-                                    request::Kind::Session(session) => {
-                                        session_handler.handle(&clock, src, request_id, session_id, &session)
-                                    }
-                                    request::Kind::Ping(_) => {
-                                        session_id.and_then(|session_id| handle_ping(&clock, src, request_id, session_id, &session_manager))
-                                    }
-                                    request::Kind::Neighbours(neighbours) => {
-                                        session_id.and_then(|session_id|
-                                            neighbours_handler.handle(&clock, src, request_id, session_id, &neighbours))
+                    }
+                    PacketType::Data => match p {
+                        PacketKind::Packet(Packet { session_id, kind: Some(packet::Kind::Request(Request { request_id, kind: Some(request) })) }) => {
+                            let session_id: Option<SessionId> = session_id.try_into().ok();
+
+                            log::debug!("[{src}] got session_id={:?}: request_id={}: {:?}", session_id, request_id, request);
+
+                            let cacheable = session_id
+                                .filter(|_| is_cacheable(&request))
+                                .map(|session_id| RequestKey::new(src, session_id, request_id, &request));
+                            if let Some(key) = &cacheable {
+                                match response_cache.get(key, clock.time()) {
+                                    Some(Cached::Response(packet)) => {
+                                        log::debug!("[{src}] answering retried request_id={request_id} from cache");
+                                        return Some((noop_ack(), packet));
                                     }
-                                    request::Kind::Node(node) => {
-                                        session_id.and_then(|session_id|
-                                            node_handler.handle(&clock, src, request_id, session_id, &node))
+                                    Some(Cached::Pending) => {
+                                        log::debug!("[{src}] dropping retried request_id={request_id}, still handled");
+                                        return None;
                                     }
-                                    request::Kind::Slot(slot) =>
-                                        session_id.and_then(|session_id|
-                                            slot_handler.handle(&clock, src, request_id, session_id, &slot)),
-                                    request::Kind::Register(register) =>
-                                        session_id.and_then(|session_id|
-                                            register_handler.handle(&clock, src, request_id, session_id, &register)),
-                                    request::Kind::ReverseConnection(rc) =>
-                                        session_id.and_then(|session_id| rc_handler.handle(&clock, src, request_id, session_id, &rc)),
+                                    None => (),
                                 }
                             }
-                            PacketKind::Packet(Packet { session_id: _, kind: None }) => {
-                                log::debug!(target: "request::error", "[{src}] unrecognized packet");
-                                None
-                            }
-                            PacketKind::Packet(Packet {
-                                                   session_id,
-                                                   kind: Some(packet::Kind::Control(Control {
-                                                                                        kind: Some(control::Kind::Disconnected(control::Disconnected {
-                                                                                                                                   by: Some(control::disconnected::By::SessionId(_))
-                                                                                                                               }))
-                                                                                    }))
-                                               }) => {
-                                let session_id: Option<SessionId> = session_id.try_into().ok();
-                                if let Some(session_id) = session_id {
-                                    session_manager.remove_session(&session_id);
-                                    log::debug!(target: "request:disconnect", "[{src}] session {session_id} disconnected");
+
+                            let response = match request {
+                                request::Kind::Session(session) => {
+                                    session_handler.handle(clock, src, request_id, session_id, &session)
                                 }
-                                None
-                            }
-                            PacketKind::Packet(Packet { session_id: _, kind: Some(packet::Kind::Control(Control { kind: Some(control::Kind::ResumeForwarding(_)) })) }) => {
-                                // ignore
-                                None
-                            }
-                            PacketKind::Forward(Forward { session_id, slot, flags, payload }) => {
-                                let session_id = session_id.into();
-                                forward_handler.handle(&clock, src, session_id, slot, flags, payload)
+                                request::Kind::Ping(_) => {
+                                    session_id.and_then(|session_id| handle_ping(clock, src, request_id, session_id, &session_manager, &slot_manager, &load))
+                                }
+                                request::Kind::Neighbours(neighbours) => {
+                                    session_id.and_then(|session_id|
+                                        neighbours_handler.handle(clock, src, request_id, session_id, &neighbours))
+                                }
+                                request::Kind::FindNodes(find_nodes) => {
+                                    session_id.and_then(|session_id|
+                                        find_nodes_handler.handle(clock, src, request_id, session_id, &find_nodes))
+                                }
+                                request::Kind::Nearest(nearest) => {
+                                    session_id.and_then(|session_id|
+                                        nearest_handler.handle(clock, src, request_id, session_id, &nearest))
+                                }
+                                request::Kind::Locate(locate) => {
+                                    session_id.and_then(|session_id|
+                                        locate_handler.handle(clock, src, request_id, session_id, &locate))
+                                }
+                                request::Kind::EdgeSummary(summary) => {
+                                    session_id.and_then(|session_id|
+                                        edge_summary_handler.handle(clock, src, request_id, session_id, &summary))
+                                }
+                                request::Kind::Node(node) => {
+                                    session_id.and_then(|session_id|
+                                        node_handler.handle(clock, src, request_id, session_id, &node))
+                                }
+                                request::Kind::Slot(slot) =>
+                                    session_id.and_then(|session_id|
+                                        slot_handler.handle(clock, src, request_id, session_id, &slot)),
+                                request::Kind::Register(register) =>
+                                    session_id.and_then(|session_id|
+                                        register_handler.handle(clock, src, request_id, session_id, &register)),
+                                request::Kind::ReverseConnection(rc) =>
+                                    session_id.and_then(|session_id| rc_handler.handle(clock, src, request_id, session_id, &rc)),
+                                request::Kind::NatCheck(nat_check) =>
+                                    session_id.and_then(|session_id| nat_check_handler.handle(clock, src, request_id, session_id, &nat_check)),
+                                request::Kind::JoinGroup(join) =>
+                                    session_id.and_then(|session_id| group_handler.join(clock, src, request_id, session_id, &join)),
+                                request::Kind::LeaveGroup(leave) =>
+                                    session_id.and_then(|session_id| group_handler.leave(clock, src, request_id, session_id, &leave)),
+                            };
+                            if let (Some(key), Some((_, packet))) = (cacheable, &response) {
+                                response_cache.store(key, packet, clock.time());
                             }
-                            other => {
-                                log::error!("[{src}] unknown packet: {other:?}");
-                                None
+                            response
+                        }
+                        PacketKind::Packet(Packet { session_id: _, kind: None }) => {
+                            log::debug!(target: "request::error", "[{src}] unrecognized packet");
+                            None
+                        }
+                        PacketKind::Packet(Packet {
+                                               session_id,
+                                               kind: Some(packet::Kind::Control(Control {
+                                                                                    kind: Some(control::Kind::Disconnected(control::Disconnected {
+                                                                                                                               by: Some(control::disconnected::By::SessionId(_))
+                                                                                                                           }))
+                                                                                }))
+                                           }) => {
+                            let session_id: Option<SessionId> = session_id.try_into().ok();
+                            if let Some(session_id) = session_id {
+                                session_manager.remove_session(&session_id);
+                                log::debug!(target: "request:disconnect", "[{src}] session {session_id} disconnected");
                             }
+                            None
+                        }
+                        PacketKind::Packet(Packet { session_id: _, kind: Some(packet::Kind::Control(Control { kind: Some(control::Kind::ResumeForwarding(_)) })) }) => {
+                            // ignore
+                            None
+                        }
+                        PacketKind::Packet(Packet { session_id, kind: Some(packet::Kind::Control(Control { kind: Some(control::Kind::ForwardToGroup(param)) })) }) => {
+                            let session_id: Option<SessionId> = session_id.try_into().ok();
+                            session_id.and_then(|session_id| forward_handler.handle_group(clock, src, session_id, param))
+                        }
+                        PacketKind::Forward(Forward { session_id, slot, flags, payload }) => {
+                            let session_id = session_id.into();
+                            forward_handler.handle(clock, src, session_id, slot, flags, payload)
+                        }
+                        other => {
+                            log::error!("[{src}] unknown packet: {other:?}");
+                            None
                         }
-                    };
+                    }
+                }
+            });
 
-                let io_part = response.map(|(ack, p)| (ack, p.encode_to_vec()));
+            worker_err_fn(move |pt, mut packet: BytesMut, src| {
+                let mut codec = Codec;
+                let reply = reply.clone();
+                if let PacketType::Data = pt {
+                    capture.record(Direction::Incoming, local_addr, src, &packet);
+                }
+                let mut p = codec.decode(&mut packet)?.ok_or_else(|| anyhow::anyhow!("invalid packet"))?;
 
-                Ok(async move {
-                    if let Some((ack, bytes)) = io_part {
-                        match reply.send_to(&bytes, src).await {
-                            Ok(_) => ack.done(&clock),
-                            Err(_) => ack.error(&clock)
-                        }
+                let clock = Clock::now();
+
+                let verdict = match (&interceptor, &pt) {
+                    (Some(interceptor), PacketType::Data) => interceptor.intercept(Direction::Incoming, src, &mut p),
+                    _ => Verdict::Pass,
+                };
+
+                let response = match verdict {
+                    Verdict::Pass => handle(&clock, pt, p, src),
+                    Verdict::Drop => None,
+                    Verdict::Delay(delay) => {
+                        // Handled outside of the worker, so the following packets aren't held back.
+                        let handle = handle.clone();
+                        let reply = reply.clone();
+                        let interceptor = interceptor.clone();
+                        tokio::task::spawn_local(async move {
+                            tokio::time::sleep(delay).await;
+                            let clock = Clock::now();
+                            let response = handle(&clock, pt, p, src);
+                            send_response(reply, src, &clock, response, interceptor).await;
+                        });
+                        None
                     }
+                };
+
+                let interceptor = interceptor.clone();
+
+                Ok(async move {
+                    send_response(reply, src, &clock, response, interceptor).await;
                     Ok(())
                 })
             })
@@ -296,19 +632,115 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
             .start(bind_addr).await?
     };
 
+    // Reported to the core relay once the listening port is known.
+    let core_link_tasks = match core_link {
+        Some(core_link) => {
+            let secret = edge::edge_key(edge_config, config.state_dir.as_deref())?;
+            let public_addr = edge_config
+                .edge_public_addr
+                .unwrap_or_else(|| server.bind_addr());
+            core_link.spawn(
+                secret,
+                public_addr,
+                &session_manager,
+                edge_config.edge_sync_interval,
+            )
+        }
+        None => Vec::new(),
+    };
+
+    let ws_gateway = match server_config.ws_listen_addr {
+        Some(addr) => Some(WsGateway::start(addr, server.bind_addr()).await?),
+        None => None,
+    };
+
     Ok(Server {
         udp_server: server,
         session_manager,
         slot_manager,
+        limits,
+        traffic,
+        history,
+        maintenance,
+        capture,
+        forward_limiter,
+        history_task,
+        public_key,
+        core_link_tasks,
+        simulation_task,
+        ws_gateway,
     })
 }
 
+async fn send_response(
+    reply: Rc<UdpSocket>,
+    src: SocketAddr,
+    clock: &Clock,
+    response: Option<(CompletionHandler, Packet)>,
+    interceptor: Option<InterceptorRef>,
+) {
+    let Some((ack, packet)) = response else {
+        return;
+    };
+
+    let bytes = match interceptor {
+        None => packet.encode_to_vec(),
+        Some(interceptor) => {
+            let mut packet = PacketKind::Packet(packet);
+            let verdict = interceptor.intercept(Direction::Outgoing, src, &mut packet);
+
+            let mut bytes = BytesMut::new();
+            if let Err(e) = Codec.encode(packet, &mut bytes) {
+                log::warn!("[{src}] unable to encode intercepted packet: {e}");
+                return;
+            }
+
+            match verdict {
+                Verdict::Pass => bytes.to_vec(),
+                Verdict::Drop => return,
+                Verdict::Delay(delay) => {
+                    tokio::task::spawn_local(async move {
+                        tokio::time::sleep(delay).await;
+                        let clock = Clock::now();
+                        match reply.send_to(&bytes, src).await {
+                            Ok(_) => ack.done(&clock),
+                            Err(_) => ack.error(&clock),
+                        }
+                    });
+                    return;
+                }
+            }
+        }
+    };
+
+    match reply.send_to(&bytes, src).await {
+        Ok(_) => ack.done(clock),
+        Err(_) => ack.error(clock),
+    }
+}
+
+/// Requests answered from [`ResponseCache`], when retried. Handshakes have their own
+/// replay protection, while other responses would be stale or are cheap to compute again.
+fn is_cacheable(request: &request::Kind) -> bool {
+    matches!(
+        request,
+        request::Kind::Register(_)
+            | request::Kind::Node(_)
+            | request::Kind::Slot(_)
+            | request::Kind::FindNodes(_)
+            | request::Kind::Neighbours(_)
+            | request::Kind::Nearest(_)
+    )
+}
+
 fn handle_ping(
     clock: &Clock,
     src: SocketAddr,
     request_id: u64,
     session_id: SessionId,
     session_manager: &SessionManager,
+    slot_manager: &SlotManager,
+    load: &RelayLoad,
 ) -> Option<(CompletionHandler, Packet)> {
     let is_ok = session_manager
         .with_session(&session_id, |session| {
@@ -330,7 +762,10 @@ fn handle_ping(
                 kind: Some(packet::Kind::Response(Response {
                     code: StatusCode::Ok.into(),
                     request_id,
-                    kind: Some(response::Kind::Pong(Default::default())),
+                    kind: Some(response::Kind::Pong(response::Pong {
+                        slot_epoch: slot_manager.epoch(),
+                        load: load.report(),
+                    })),
                 })),
             },
         ))
@@ -375,3 +810,25 @@ fn counter_ack(success: &Counter, error: &Counter) -> CompletionHandler {
 
     Rc::new(CounterAck(success.clone(), error.clone()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_key_saved() {
+        let path = std::env::temp_dir().join(format!("server-{}.key", rand::random::<u64>()));
+
+        let secret = load_or_generate_server_key(&path).unwrap();
+        let loaded = load_or_generate_server_key(&path).unwrap();
+        assert_eq!(secret.to_bytes(), loaded.to_bytes());
+        assert!(!path.with_extension("tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).ok();
+    }
+}
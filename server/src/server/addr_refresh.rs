@@ -0,0 +1,107 @@
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::task::spawn_local;
+
+use ya_relay_proto::proto::{control, Message, Packet};
+
+use crate::server::ip_checker::IpChecker;
+use crate::server::IpCache;
+use crate::udp_server::UdpSocket;
+use crate::{SessionManager, SessionRef};
+
+/// Periodically re-checks addresses of Nodes, which couldn't be reached directly
+/// during registration, e.g. because of a temporary firewall rule or NAT mapping.
+///
+/// Sessions which looked such Node up are sent `ReverseConnection` once the address
+/// turns out public, so they can connect directly instead of relaying.
+pub struct AddrRefresher {
+    session_manager: Arc<SessionManager>,
+    ip_checker: IpChecker,
+    cache: IpCache,
+    reply_socket: Weak<UdpSocket>,
+}
+
+impl AddrRefresher {
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        ip_checker: IpChecker,
+        reply_socket: &Rc<UdpSocket>,
+        cache: IpCache,
+    ) -> Self {
+        Self {
+            session_manager: session_manager.clone(),
+            ip_checker,
+            cache,
+            reply_socket: Rc::downgrade(reply_socket),
+        }
+    }
+
+    /// Runs on the current worker until its socket is dropped.
+    pub fn spawn(self, interval: Duration, max_age: Duration) {
+        spawn_local(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if self.reply_socket.strong_count() == 0 {
+                    break;
+                }
+                self.refresh(max_age);
+            }
+            log::debug!(target: "service::addr_refresh", "stopped");
+        });
+    }
+
+    fn refresh(&self, max_age: Duration) {
+        for session_ref in self.session_manager.stale_addresses(max_age) {
+            log::debug!(target: "service::addr_refresh", "[{}] re-checking address of {}", session_ref.peer, session_ref.node_id);
+
+            let session_manager = self.session_manager.clone();
+            let cache = self.cache.clone();
+            let reply_socket = self.reply_socket.clone();
+            self.ip_checker
+                .check_ip_status(Instant::now(), session_ref, move |valid, session_ref| {
+                    session_ref.addr_status.lock().set_valid(valid);
+                    cache.insert(session_ref.peer, (Instant::now(), valid));
+                    if valid {
+                        log::info!(target: "service::addr_refresh", "[{}] Node {} became reachable", session_ref.peer, session_ref.node_id);
+                        notify_watchers(&session_manager, &session_ref, reply_socket);
+                    }
+                });
+        }
+    }
+}
+
+fn notify_watchers(
+    session_manager: &SessionManager,
+    session_ref: &SessionRef,
+    reply_socket: Weak<UdpSocket>,
+) {
+    let watchers = session_manager.node_reachable(session_ref);
+    let (Some(endpoint), Some(socket)) = (session_ref.endpoint(), reply_socket.upgrade()) else {
+        return;
+    };
+    let node_id = session_ref.node_id.into_array().to_vec();
+    let to_send = watchers
+        .iter()
+        .map(|watcher| {
+            let packet = Packet::control(
+                watcher.session_id.to_vec(),
+                control::ReverseConnection {
+                    node_id: node_id.clone(),
+                    endpoints: vec![endpoint.clone()],
+                },
+            );
+            (packet.encode_to_vec(), watcher.peer)
+        })
+        .collect::<Vec<_>>();
+
+    spawn_local(async move {
+        for (bytes, peer) in to_send {
+            log::debug!(target: "service::addr_refresh", "[{peer}] sending reverse connection");
+            if let Err(e) = socket.send_to(&bytes, peer).await {
+                log::warn!("[{peer}] failed to send reverse connection: {e:?}");
+            }
+        }
+    });
+}
@@ -0,0 +1,449 @@
+//! Hierarchy of relays.
+//!
+//! A relay configured with `--core-relay` becomes an edge relay. It keeps a session with
+//! the core relay and periodically reports Nodes connected to it, so the core relay can
+//! tell other Nodes which relay to look for them at. Lookups of Nodes unknown to the edge
+//! relay are passed to the core relay.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::sync::oneshot;
+use tokio::task::{spawn_local, JoinHandle};
+
+use ya_relay_core::challenge::{self, ChallengeDigest};
+use ya_relay_core::crypto::{CryptoProvider, FallbackCryptoProvider};
+use ya_relay_core::key::{self, SecretKey};
+use ya_relay_core::server_identity;
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::{
+    control, packet, request, response, Control, Message, Packet, Request, Response, StatusCode,
+};
+
+use crate::server::{write_secret, CompletionHandler};
+use crate::state::edge_directory::EdgeDirectory;
+use crate::state::Clock;
+use crate::SessionManager;
+
+/// Nodes reported in a single `EdgeSummary`, so the request fits in a datagram.
+const MAX_SUMMARY_NODES: usize = 50;
+/// Shorter than the client request timeout, so the edge relay answers before clients give up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(clap::Args, Clone)]
+#[command(next_help_heading = "Relay hierarchy options")]
+pub struct EdgeConfig {
+    /// Core relay, at which this relay registers as an edge relay. Lookups of Nodes
+    /// unknown to this relay are passed to it.
+    #[arg(long, env)]
+    pub core_relay: Option<SocketAddr>,
+    /// Ed25519 public key in hex, which the core relay signs its handshake responses with
+    /// (its `--server-key`). Required with `--core-relay`, responses without a valid
+    /// signature are rejected.
+    #[arg(long, env, value_parser = server_identity::public_key_from_hex)]
+    pub core_relay_key: Option<server_identity::PublicKey>,
+    /// Address of this relay reported to the core relay, at which Nodes can reach it.
+    /// Defaults to `--listen-on`, with an unspecified IP replaced by the core relay
+    /// with the one it sees the edge relay at.
+    #[arg(long, env)]
+    pub edge_public_addr: Option<SocketAddr>,
+    /// Secp256k1 secret key in hex identifying this relay at the core relay. If not set,
+    /// the key is kept in the state directory, or generated on each start without one.
+    #[arg(long, env, value_parser = edge_key_from_hex)]
+    pub edge_key: Option<SecretKey>,
+    /// Interval of reporting connected Nodes to the core relay.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "30s")]
+    pub edge_sync_interval: Duration,
+    /// Node ids of edge relays allowed to report their Nodes to this relay.
+    #[arg(long = "allowed-edge", env = "ALLOWED_EDGES", value_delimiter = ',')]
+    pub allowed_edges: Vec<NodeId>,
+    /// Time Nodes reported by edge relays are located at them without being reported again.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "2min")]
+    pub edge_ttl: Duration,
+}
+
+pub fn edge_key_from_hex(hex_str: &str) -> anyhow::Result<SecretKey> {
+    Ok(SecretKey::from_raw(&hex::decode(hex_str)?)?)
+}
+
+/// Keeps the key in the state directory, so the edge relay keeps its Node id after restart.
+pub(crate) fn load_or_generate_edge_key(path: &Path) -> anyhow::Result<SecretKey> {
+    match std::fs::read_to_string(path) {
+        Ok(hex_str) => edge_key_from_hex(hex_str.trim()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let raw = rand::random::<[u8; 32]>();
+            let secret = SecretKey::from_raw(&raw)?;
+            write_secret(path, hex::encode(raw).as_bytes())?;
+            Ok(secret)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+type Pending = Mutex<HashMap<u64, oneshot::Sender<(Vec<u8>, Response)>>>;
+
+/// Session of an edge relay with its core relay.
+pub struct CoreLink {
+    core: SocketAddr,
+    core_key: server_identity::PublicKey,
+    socket: tokio::net::UdpSocket,
+    session_id: Mutex<Option<SessionId>>,
+    pending: Pending,
+}
+
+impl CoreLink {
+    /// Sessions are established only with a relay proving it holds the key of `core_key`.
+    pub async fn bind(
+        core: SocketAddr,
+        core_key: server_identity::PublicKey,
+    ) -> anyhow::Result<Arc<Self>> {
+        let local: SocketAddr = match core {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = tokio::net::UdpSocket::bind(local).await?;
+        socket.connect(core).await?;
+
+        Ok(Arc::new(Self {
+            core,
+            core_key,
+            socket,
+            session_id: Default::default(),
+            pending: Default::default(),
+        }))
+    }
+
+    pub fn core_addr(&self) -> SocketAddr {
+        self.core
+    }
+
+    /// Receives responses of the core relay and reports Nodes of `session_manager`
+    /// reachable at `public_addr` every `interval`. Tasks run on the current `LocalSet`.
+    pub fn spawn(
+        self: &Arc<Self>,
+        secret: SecretKey,
+        public_addr: SocketAddr,
+        session_manager: &Arc<SessionManager>,
+        interval: Duration,
+    ) -> Vec<JoinHandle<()>> {
+        let receiver = {
+            let this = self.clone();
+            spawn_local(async move { this.receive().await })
+        };
+        let sync = {
+            let this = self.clone();
+            let session_manager = session_manager.clone();
+            let crypto = FallbackCryptoProvider::new(secret);
+            spawn_local(async move {
+                let mut interval = tokio::time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = this.sync(&crypto, public_addr, &session_manager).await {
+                        log::warn!(
+                            "Unable to report Nodes to the core relay {}: {e}",
+                            this.core
+                        );
+                        this.session_id.lock().take();
+                    }
+                }
+            })
+        };
+        vec![receiver, sync]
+    }
+
+    /// Relay, which the Node is connected to. `None` if the core relay doesn't know it.
+    pub async fn locate(&self, node_id: NodeId) -> anyhow::Result<Option<SocketAddr>> {
+        let session_id = match *self.session_id.lock() {
+            Some(session_id) => session_id,
+            None => anyhow::bail!("Not registered at the core relay {}", self.core),
+        };
+        let locate = request::Locate {
+            node_id: node_id.into_array().to_vec(),
+        };
+        let (_, response) = self.request(session_id.to_vec(), locate.into()).await?;
+
+        match StatusCode::try_from(response.code) {
+            Ok(StatusCode::Ok) => {}
+            Ok(StatusCode::NotFound) => return Ok(None),
+            _ => anyhow::bail!("Locate failed with code {}", response.code),
+        }
+        match response.kind {
+            Some(response::Kind::Locate(response::Locate { relay: None })) => Ok(Some(self.core)),
+            Some(response::Kind::Locate(response::Locate {
+                relay: Some(endpoint),
+            })) => Ok(Some(SocketAddr::try_from(endpoint)?)),
+            _ => anyhow::bail!("Unexpected response type"),
+        }
+    }
+
+    async fn sync(
+        &self,
+        crypto: &FallbackCryptoProvider,
+        public_addr: SocketAddr,
+        session_manager: &SessionManager,
+    ) -> anyhow::Result<()> {
+        let current = *self.session_id.lock();
+        let session_id = match current {
+            Some(session_id) => session_id,
+            None => {
+                let session_id = self.establish(crypto).await?;
+                log::info!("Registered as an edge relay at {}", self.core);
+                self.session_id.lock().replace(session_id);
+                session_id
+            }
+        };
+
+        let node_ids = session_manager
+            .snapshot()
+            .iter()
+            .map(|session_ref| session_ref.node_id.into_array().to_vec())
+            .collect::<Vec<_>>();
+        log::debug!(
+            "Reporting {} Nodes to the core relay {}",
+            node_ids.len(),
+            self.core
+        );
+
+        // Reported even without Nodes, which keeps the session alive.
+        let mut chunks = node_ids.chunks(MAX_SUMMARY_NODES).peekable();
+        if chunks.peek().is_none() {
+            self.summary(session_id, public_addr, Vec::new()).await?;
+        }
+        for chunk in chunks {
+            self.summary(session_id, public_addr, chunk.to_vec())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn summary(
+        &self,
+        session_id: SessionId,
+        public_addr: SocketAddr,
+        node_ids: Vec<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let summary = request::EdgeSummary {
+            relay: Some(public_addr.into()),
+            node_ids,
+        };
+        let (_, response) = self.request(session_id.to_vec(), summary.into()).await?;
+        if response.code != StatusCode::Ok as i32 {
+            anyhow::bail!("Summary rejected with code {}", response.code);
+        }
+        Ok(())
+    }
+
+    async fn establish(&self, crypto: &FallbackCryptoProvider) -> anyhow::Result<SessionId> {
+        let nonce = server_identity::generate_nonce();
+        let request = request::Session {
+            nonce: nonce.clone(),
+            ..Default::default()
+        };
+        let (session_id, response) = self.request(Vec::new(), request.into()).await?;
+        let (challenge_req, signature) = match response.kind {
+            Some(response::Kind::Session(response::Session {
+                challenge_req: Some(challenge_req),
+                server_signature,
+                ..
+            })) => (challenge_req, server_signature),
+            _ => anyhow::bail!("Expected a challenge, got code {}", response.code),
+        };
+        let message = server_identity::challenge_message(&nonce, &session_id, &challenge_req);
+        self.verify_core(&signature, &message)?;
+
+        let identity = crypto.get(crypto.default_node_id()).await?;
+        let challenge_resp = challenge::solve::<ChallengeDigest, _>(
+            challenge_req.challenge,
+            challenge_req.difficulty,
+            vec![identity],
+        )
+        .await?;
+
+        let session = request::Session {
+            challenge_resp: Some(challenge_resp),
+            nonce: nonce.clone(),
+            ..Default::default()
+        };
+        let (_, response) = self.request(session_id.clone(), session.into()).await?;
+        if response.code != StatusCode::Ok as i32 {
+            anyhow::bail!("Session rejected with code {}", response.code);
+        }
+        let (key_exchange, signature) = match response.kind {
+            Some(response::Kind::Session(response::Session {
+                key_exchange,
+                server_signature,
+                ..
+            })) => (key_exchange, server_signature),
+            _ => anyhow::bail!("Expected a session response"),
+        };
+        let message = server_identity::session_message(&nonce, &session_id, &key_exchange);
+        self.verify_core(&signature, &message)?;
+
+        SessionId::try_from(session_id)
+    }
+
+    fn verify_core(&self, signature: &[u8], message: &[u8]) -> anyhow::Result<()> {
+        let key = server_identity::verify(signature, message)
+            .map_err(|e| anyhow::anyhow!("Core relay {} not verified: {e}", self.core))?;
+        if key != self.core_key {
+            anyhow::bail!(
+                "Core relay {} signed with an unexpected key {}",
+                self.core,
+                hex::encode(key.as_bytes())
+            );
+        }
+        Ok(())
+    }
+
+    async fn request(
+        &self,
+        session_id: Vec<u8>,
+        kind: request::Kind,
+    ) -> anyhow::Result<(Vec<u8>, Response)> {
+        let request = Request::from(kind);
+        let request_id = request.request_id;
+        let packet = Packet {
+            session_id,
+            kind: Some(packet::Kind::Request(request)),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().insert(request_id, tx);
+        let result = async {
+            self.socket.send(&packet.encode_to_vec()).await?;
+            let response = tokio::time::timeout(REQUEST_TIMEOUT, rx)
+                .await
+                .map_err(|_| anyhow::anyhow!("Core relay request timed out"))??;
+            anyhow::Ok(response)
+        }
+        .await;
+        self.pending.lock().remove(&request_id);
+        result
+    }
+
+    async fn receive(&self) {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let size = match self.socket.recv(&mut buf).await {
+                Ok(size) => size,
+                Err(e) => {
+                    // Reported for ICMP errors, while the core relay is unreachable.
+                    log::debug!("Core relay {} receive error: {e}", self.core);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let (session_id, response) = match Packet::decode(&buf[..size]) {
+                Ok(Packet {
+                    session_id,
+                    kind: Some(packet::Kind::Response(response)),
+                }) => (session_id, response),
+                Ok(Packet {
+                    kind:
+                        Some(packet::Kind::Control(Control {
+                            kind: Some(control::Kind::Disconnected(_)),
+                        })),
+                    ..
+                }) => {
+                    // Established again with the next summary.
+                    self.session_id.lock().take();
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if let Some(tx) = self.pending.lock().remove(&response.request_id) {
+                let _ = tx.send((session_id, response));
+            }
+        }
+    }
+}
+
+/// Accepts summaries of edge relays listed in `--allowed-edge`.
+pub struct EdgeSummaryHandler {
+    session_manager: Arc<SessionManager>,
+    edge_directory: Arc<EdgeDirectory>,
+    allowed_edges: Arc<Vec<NodeId>>,
+    ack: CompletionHandler,
+}
+
+impl EdgeSummaryHandler {
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        edge_directory: &Arc<EdgeDirectory>,
+        allowed_edges: &Arc<Vec<NodeId>>,
+    ) -> Self {
+        Self {
+            session_manager: session_manager.clone(),
+            edge_directory: edge_directory.clone(),
+            allowed_edges: allowed_edges.clone(),
+            ack: super::noop_ack(),
+        }
+    }
+
+    pub fn handle(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        param: &request::EdgeSummary,
+    ) -> Option<(CompletionHandler, Packet)> {
+        let respond = |code: StatusCode| {
+            Some((
+                self.ack.clone(),
+                Packet::response(
+                    request_id,
+                    session_id.to_vec(),
+                    code,
+                    response::EdgeSummary {},
+                ),
+            ))
+        };
+
+        let session_ref = match self.session_manager.session(&session_id) {
+            Some(session_ref) if session_ref.peer == src => session_ref,
+            _ => return respond(StatusCode::Unauthorized),
+        };
+        if !self.allowed_edges.contains(&session_ref.node_id) {
+            log::warn!(target: "request::edge_summary", "[{src}] {} isn't an allowed edge relay", session_ref.node_id);
+            return respond(StatusCode::Unauthorized);
+        }
+        clock.touch(&session_ref.ts);
+
+        let mut relay = match param.relay.clone().map(SocketAddr::try_from) {
+            Some(Ok(relay)) => relay,
+            _ => return respond(StatusCode::BadRequest),
+        };
+        if relay.ip().is_unspecified() {
+            relay.set_ip(src.ip());
+        }
+        let node_ids = param
+            .node_ids
+            .iter()
+            .filter_map(|node_id| NodeId::try_from(node_id).ok())
+            .collect::<Vec<_>>();
+
+        log::debug!(target: "request::edge_summary", "[{src}] edge relay {relay} reported {} Nodes", node_ids.len());
+        self.edge_directory.update(relay, node_ids, clock.time());
+
+        respond(StatusCode::Ok)
+    }
+}
+
+/// Identity of this relay at the core relay.
+pub(crate) fn edge_key(config: &EdgeConfig, state_dir: Option<&Path>) -> anyhow::Result<SecretKey> {
+    let secret = match (&config.edge_key, state_dir) {
+        (Some(secret), _) => secret.clone(),
+        (None, Some(state_dir)) => load_or_generate_edge_key(&state_dir.join("edge.key"))?,
+        (None, None) => key::generate(),
+    };
+    log::info!("Edge relay Node id: {}", key::node_id(&secret));
+    Ok(secret)
+}
@@ -0,0 +1,110 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_proto::proto::response::FindNodes;
+use ya_relay_proto::proto::{request, Packet, StatusCode};
+
+use crate::server::CompletionHandler;
+use crate::state::slot_manager::SlotManager;
+use crate::state::Clock;
+use crate::SessionManager;
+
+/// Keeps the response within a single datagram, even with properties of maximum size.
+pub const MAX_FIND_NODES: u32 = 64;
+
+mod metric {
+    use metrics::{recorder, Counter, Key};
+
+    static KEY_START: Key = Key::from_static_name("ya-relay.packet.find-nodes");
+    static KEY_ERROR: Key = Key::from_static_name("ya-relay.packet.find-nodes.error");
+    static KEY_DONE: Key = Key::from_static_name("ya-relay.packet.find-nodes.done");
+
+    #[derive(Clone)]
+    pub struct FindNodesMetric {
+        pub start: Counter,
+        pub done: Counter,
+        pub error: Counter,
+    }
+
+    impl Default for FindNodesMetric {
+        fn default() -> Self {
+            let recorder = recorder();
+            let start = recorder.register_counter(&KEY_START);
+            let done = recorder.register_counter(&KEY_DONE);
+            let error = recorder.register_counter(&KEY_ERROR);
+
+            Self { start, done, error }
+        }
+    }
+}
+
+pub struct FindNodesHandler {
+    session_manager: Arc<SessionManager>,
+    slot_manager: Arc<SlotManager>,
+    metrics: metric::FindNodesMetric,
+    ack: CompletionHandler,
+}
+
+impl FindNodesHandler {
+    pub fn new(session_manager: &Arc<SessionManager>, slot_manager: &Arc<SlotManager>) -> Self {
+        let session_manager = Arc::clone(session_manager);
+        let slot_manager = slot_manager.clone();
+        let metrics = metric::FindNodesMetric::default();
+        let ack = super::counter_ack(&metrics.done, &metrics.error);
+        Self {
+            session_manager,
+            slot_manager,
+            metrics,
+            ack,
+        }
+    }
+
+    pub fn handle(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        param: &request::FindNodes,
+    ) -> Option<(CompletionHandler, Packet)> {
+        self.metrics.start.increment(1);
+
+        let session_ref = match self.session_manager.session(&session_id) {
+            Some(session_ref) if session_ref.peer == src => session_ref,
+            _ => {
+                return Some((
+                    self.ack.clone(),
+                    Packet::response(
+                        request_id,
+                        session_id.to_vec(),
+                        StatusCode::Unauthorized,
+                        FindNodes::default(),
+                    ),
+                ))
+            }
+        };
+        clock.touch(&session_ref.ts);
+
+        let decoder = super::state_decoder::decoder(&self.session_manager, &self.slot_manager);
+        let limit = param.limit.min(MAX_FIND_NODES) as usize;
+        let nodes = self
+            .session_manager
+            .find_nodes(&param.key, &param.value, limit)
+            .into_iter()
+            .map(|session_ref| decoder.to_node_info(&session_ref))
+            .collect::<Vec<_>>();
+
+        log::debug!(target: "request::find_nodes", "[{src}] found {} nodes with {:?}={:?}", nodes.len(), param.key, param.value);
+
+        Some((
+            self.ack.clone(),
+            Packet::response(
+                request_id,
+                session_id.to_vec(),
+                StatusCode::Ok,
+                FindNodes { nodes },
+            ),
+        ))
+    }
+}
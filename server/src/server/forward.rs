@@ -1,5 +1,9 @@
+use crate::plugin::{ForwardInfo, Plugins, Verdict};
 use crate::server::CompletionHandler;
+use crate::state::forward_limiter::{ForwardLimiter, WorkerQueues};
+use crate::state::group_manager::GroupManager;
 use crate::state::slot_manager::{SlotId, SlotManager};
+use crate::state::traffic::TrafficMatrix;
 use crate::state::Clock;
 use crate::SessionManager;
 use bytes::BytesMut;
@@ -8,9 +12,12 @@ use crate::udp_server::UdpSocket;
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Instant;
+use ya_relay_core::forward_auth::{self, ForwardKey};
 use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
 
-use ya_relay_proto::proto::{control, Forward, Packet, Payload};
+use ya_relay_proto::proto::{control, Forward, Packet, Payload, StatusCode};
 
 mod metric {
     use crate::server::DoneAck;
@@ -20,6 +27,11 @@ mod metric {
     static START: Key = Key::from_static_name("ya-relay.packet.forward");
     static ERROR: Key = Key::from_static_name("ya-relay.packet.forward.error");
     static DONE: Key = Key::from_static_name("ya-relay.packet.forward.done");
+    static DROPPED: Key = Key::from_static_name("ya-relay.packet.forward.dropped");
+    static GROUP: Key = Key::from_static_name("ya-relay.packet.forward.group");
+    static SPOOFED: Key = Key::from_static_name("ya-relay.packet.forward.spoofed");
+    static REJECTED: Key = Key::from_static_name("ya-relay.packet.forward.rejected");
+    static OVERSIZED: Key = Key::from_static_name("ya-relay.packet.forward.oversized");
 
     static IN_SIZE: Key = Key::from_static_name("ya-relay.packet.forward.incoming.size");
     static OUT_SIZE: Key = Key::from_static_name("ya-relay.packet.forward.outgoing.size");
@@ -29,6 +41,13 @@ mod metric {
         pub start: Counter,
         pub done: Counter,
         pub error: Counter,
+        pub dropped: Counter,
+        pub group: Counter,
+        pub spoofed: Counter,
+        /// Dropped by a plugin.
+        pub rejected: Counter,
+        /// Dropped for exceeding the payload size limit.
+        pub oversized: Counter,
         pub in_bytes: Counter,
         pub out_bytes: Counter,
     }
@@ -39,12 +58,22 @@ mod metric {
             let start = recorder.register_counter(&START);
             let done = recorder.register_counter(&DONE);
             let error = recorder.register_counter(&ERROR);
+            let dropped = recorder.register_counter(&DROPPED);
+            let group = recorder.register_counter(&GROUP);
+            let spoofed = recorder.register_counter(&SPOOFED);
+            let rejected = recorder.register_counter(&REJECTED);
+            let oversized = recorder.register_counter(&OVERSIZED);
             let in_bytes = recorder.register_counter(&IN_SIZE);
             let out_bytes = recorder.register_counter(&OUT_SIZE);
             Self {
                 start,
                 done,
                 error,
+                dropped,
+                group,
+                spoofed,
+                rejected,
+                oversized,
                 in_bytes,
                 out_bytes,
             }
@@ -65,30 +94,44 @@ mod metric {
 pub struct ForwardHandler {
     session_manager: Arc<SessionManager>,
     slot_manager: Arc<SlotManager>,
+    group_manager: Arc<GroupManager>,
+    plugins: Plugins,
+    traffic: Arc<TrafficMatrix>,
     metrics: metric::ForwardMetric,
     ack: CompletionHandler,
     socket: Rc<UdpSocket>,
+    queues: WorkerQueues,
 }
 
 impl ForwardHandler {
     pub fn new(
         session_manager: &Arc<SessionManager>,
         slot_manager: &Arc<SlotManager>,
+        group_manager: &Arc<GroupManager>,
+        plugins: &Plugins,
+        traffic: &Arc<TrafficMatrix>,
         socket: &Rc<UdpSocket>,
+        limiter: &Arc<ForwardLimiter>,
     ) -> Self {
         let session_manager = Arc::clone(session_manager);
         let slot_manager = slot_manager.clone();
+        let group_manager = group_manager.clone();
         let metrics = metric::ForwardMetric::default();
         let ack = Rc::new(metrics.clone());
         let socket = Rc::clone(socket);
         Self {
             session_manager,
             slot_manager,
+            group_manager,
+            plugins: plugins.clone(),
+            traffic: traffic.clone(),
             metrics,
             ack,
             socket,
+            queues: limiter.worker(),
         }
     }
+
     pub fn handle(
         &self,
         clock: &Clock,
@@ -101,68 +144,67 @@ impl ForwardHandler {
         self.metrics.start.increment(1);
         self.metrics.in_bytes.increment(payload.len() as u64);
 
-        let src_info = self
-            .session_manager
-            .session(&session_id)
-            .and_then(|session_ref| {
-                if session_ref.peer != src {
-                    return None;
-                }
-                let src_node_id = session_ref.node_id;
-                let src_slot = self.slot_manager.slot(src_node_id);
-                clock.touch(&session_ref.ts);
-
-                Some((src_node_id, src_slot))
-            });
+        let src_info = self.src_info(clock, src, session_id);
         let dst_info = self.slot_manager.node(slot).and_then(|node_id| {
             let dst_session = self.session_manager.node_session(node_id)?;
             let dst_addr = dst_session.peer;
 
-            Some((dst_addr, dst_session.session_id))
+            Some((node_id, dst_addr, dst_session.session_id))
         });
 
         match (src_info, dst_info) {
-            (Some((src_node_id, src_slot)), Some((dst_addr, dst_session_id))) => {
-                let payload_size = payload.len();
-                let forward = Forward {
-                    session_id: dst_session_id.to_array(),
-                    slot: src_slot,
+            (
+                Some((src_node_id, src_slot, forward_key)),
+                Some((dst_node_id, dst_addr, dst_session_id)),
+            ) => {
+                let mut forward = Forward {
+                    session_id: session_id.to_array(),
+                    slot,
                     flags,
                     payload,
                 };
-                let mut bytes = BytesMut::new();
-                bytes.reserve(forward.encoded_len());
-                forward.encode(&mut bytes);
-                let socket = self.socket.clone();
-
-                let out_bytes = self.metrics.out_bytes.clone();
-                let done = self.metrics.done.clone();
-                let error = self.metrics.error.clone();
-
-                tokio::task::spawn_local(async move {
-                    match socket.send_to(&bytes, dst_addr).await {
-                        Ok(v) => {
-                            out_bytes.increment(payload_size as u64);
-                            done.increment(1);
-                            log::debug!("forwarded {} bytes from {src}:{src_node_id}:{src_slot} to {dst_addr}:{slot}", v);
-                        }
-                        Err(e) => {
-                            error.increment(1);
-                            log::error!("fail {:?}", e);
-                        }
+                if let Some(key) = forward_key {
+                    // Not answered, so the sender learns nothing about the session.
+                    if !forward_auth::verify(&key, &mut forward) {
+                        log::debug!(
+                            "[{src}] dropping unauthenticated forward from session {session_id}"
+                        );
+                        self.metrics.spoofed.increment(1);
+                        return None;
                     }
-                });
-                None
+                }
+
+                if let Some(rejection) =
+                    self.check_size(src, session_id, slot, forward.payload.len())
+                {
+                    return Some(rejection);
+                }
+
+                let info = ForwardInfo {
+                    src_node_id,
+                    src_session: session_id,
+                    dst_node_id,
+                    dst_session: dst_session_id,
+                    payload: forward.payload.as_ref(),
+                    reliable: forward.is_reliable(),
+                    group: None,
+                };
+                if self.plugins.forward(&info) == Verdict::Drop {
+                    self.metrics.rejected.increment(1);
+                    return None;
+                }
+
+                let (admitted, report) = self.admit(src, session_id, 1);
+                if admitted > 0 {
+                    self.traffic
+                        .record(src_node_id, dst_node_id, forward.payload.len());
+                    forward.session_id = dst_session_id.to_array();
+                    forward.slot = src_slot;
+                    self.send(session_id, src_node_id, forward, dst_addr);
+                }
+                report
             }
-            (None, _) => Some((
-                self.ack.clone(),
-                Packet::control(
-                    session_id.to_vec(),
-                    control::Disconnected {
-                        by: control::disconnected::By::SessionId(Default::default()).into(),
-                    },
-                ),
-            )),
+            (None, _) => Some(self.unknown_session(session_id)),
             (_, None) => Some((
                 self.ack.clone(),
                 Packet::control(
@@ -174,4 +216,205 @@ impl ForwardHandler {
             )),
         }
     }
+
+    /// Sends the payload to all other members of the group, as an unreliable forward
+    /// from the sender's slot. Members receive it the same way as a regular forward.
+    pub fn handle_group(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        session_id: SessionId,
+        param: control::ForwardToGroup,
+    ) -> Option<(CompletionHandler, Packet)> {
+        self.metrics.group.increment(1);
+        self.metrics.in_bytes.increment(param.payload.len() as u64);
+
+        let (src_node_id, src_slot, forward_key) = match self.src_info(clock, src, session_id) {
+            Some(src_info) => src_info,
+            None => return Some(self.unknown_session(session_id)),
+        };
+        if let Some(key) = forward_key {
+            // Not answered, the same as unauthenticated forwards.
+            if !forward_auth::verify_group(&key, &session_id.to_array(), &param) {
+                log::debug!(
+                    "[{src}] dropping unauthenticated group forward from session {session_id}"
+                );
+                self.metrics.spoofed.increment(1);
+                return None;
+            }
+        }
+        // Group forwards aren't addressed to a slot.
+        if let Some(rejection) = self.check_size(src, session_id, 0, param.payload.len()) {
+            return Some(rejection);
+        }
+
+        let mut targets = Vec::new();
+        for node_id in self.group_manager.members(&param.group) {
+            if node_id == src_node_id {
+                continue;
+            }
+            match self.session_manager.node_session(node_id) {
+                Some(dst_session) => {
+                    let info = ForwardInfo {
+                        src_node_id,
+                        src_session: session_id,
+                        dst_node_id: node_id,
+                        dst_session: dst_session.session_id,
+                        payload: &param.payload,
+                        reliable: false,
+                        group: Some(&param.group),
+                    };
+                    match self.plugins.forward(&info) {
+                        Verdict::Pass => {
+                            targets.push((node_id, dst_session.peer, dst_session.session_id))
+                        }
+                        Verdict::Drop => self.metrics.rejected.increment(1),
+                    }
+                }
+                None => {
+                    log::debug!(
+                        "removing {node_id} without a session from group {:?}",
+                        param.group
+                    );
+                    self.group_manager.leave(&param.group, node_id);
+                }
+            }
+        }
+
+        self.metrics.start.increment(targets.len() as u64);
+        let (admitted, report) = self.admit(src, session_id, targets.len());
+        let payload = Payload::from(param.payload);
+        for (dst_node_id, dst_addr, dst_session_id) in targets.into_iter().take(admitted) {
+            self.traffic.record(src_node_id, dst_node_id, payload.len());
+            let forward = Forward::unreliable(dst_session_id.to_array(), src_slot, payload.clone());
+            self.send(session_id, src_node_id, forward, dst_addr);
+        }
+        report
+    }
+
+    fn src_info(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        session_id: SessionId,
+    ) -> Option<(NodeId, SlotId, Option<ForwardKey>)> {
+        self.session_manager
+            .session(&session_id)
+            .and_then(|session_ref| {
+                if session_ref.peer != src {
+                    return None;
+                }
+                let src_node_id = session_ref.node_id;
+                let src_slot = self.slot_manager.slot(src_node_id);
+                clock.touch(&session_ref.ts);
+
+                Some((src_node_id, src_slot, session_ref.forward_key))
+            })
+    }
+
+    /// Reserves place in the session queue for `count` forwards. Returns how many of
+    /// them can be sent, and a `Congestion` report for the sender if one is due.
+    fn admit(
+        &self,
+        src: SocketAddr,
+        session_id: SessionId,
+        count: usize,
+    ) -> (usize, Option<(CompletionHandler, Packet)>) {
+        if count == 0 {
+            return (0, None);
+        }
+
+        let (admitted, report) = self.queues.admit(session_id, count, Instant::now());
+        if admitted < count {
+            self.metrics.dropped.increment((count - admitted) as u64);
+        }
+
+        let report = report.map(|report| {
+            log::debug!("[{src}] reporting congestion of session {session_id}: {report:?}");
+            (
+                self.ack.clone(),
+                Packet::control(session_id.to_vec(), report),
+            )
+        });
+        (admitted, report)
+    }
+
+    /// Sends a forward admitted with [`Self::admit`].
+    fn send(
+        &self,
+        session_id: SessionId,
+        src_node_id: NodeId,
+        forward: Forward,
+        dst_addr: SocketAddr,
+    ) {
+        let payload_size = forward.payload.len();
+        let src_slot = forward.slot;
+        let mut bytes = BytesMut::new();
+        bytes.reserve(forward.encoded_len());
+        forward.encode(&mut bytes);
+        let socket = self.socket.clone();
+
+        let out_bytes = self.metrics.out_bytes.clone();
+        let done = self.metrics.done.clone();
+        let error = self.metrics.error.clone();
+        let queues = self.queues.clone();
+
+        tokio::task::spawn_local(async move {
+            let result = socket.send_to(&bytes, dst_addr).await;
+            queues.release(&session_id);
+            match result {
+                Ok(v) => {
+                    out_bytes.increment(payload_size as u64);
+                    done.increment(1);
+                    log::debug!(
+                        "forwarded {} bytes from {src_node_id}:{src_slot} to {dst_addr}",
+                        v
+                    );
+                }
+                Err(e) => {
+                    error.increment(1);
+                    log::error!("fail {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// `ForwardRejected` for the sender of a payload above the size limit.
+    fn check_size(
+        &self,
+        src: SocketAddr,
+        session_id: SessionId,
+        slot: SlotId,
+        size: usize,
+    ) -> Option<(CompletionHandler, Packet)> {
+        let max = self.queues.limiter().check_size(size).err()?;
+        log::debug!(
+            "[{src}] rejecting forward of {size} B above {max} B from session {session_id}"
+        );
+        self.metrics.oversized.increment(1);
+        Some((
+            self.ack.clone(),
+            Packet::control(
+                session_id.to_vec(),
+                control::ForwardRejected {
+                    slot,
+                    code: StatusCode::PayloadTooLarge.into(),
+                    size: size.try_into().unwrap_or(u32::MAX),
+                    max_size: max.try_into().unwrap_or(u32::MAX),
+                },
+            ),
+        ))
+    }
+
+    fn unknown_session(&self, session_id: SessionId) -> (CompletionHandler, Packet) {
+        (
+            self.ack.clone(),
+            Packet::control(
+                session_id.to_vec(),
+                control::Disconnected {
+                    by: control::disconnected::By::SessionId(Default::default()).into(),
+                },
+            ),
+        )
+    }
 }
@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::{request, response, Packet, StatusCode};
+
+use crate::server::CompletionHandler;
+use crate::state::group_manager::{GroupManager, JoinError, MAX_GROUP_NAME_LEN};
+use crate::state::Clock;
+use crate::SessionManager;
+
+mod metric {
+    use metrics::{recorder, Counter, Key};
+
+    static KEY_START: Key = Key::from_static_name("ya-relay.packet.group");
+    static KEY_ERROR: Key = Key::from_static_name("ya-relay.packet.group.error");
+    static KEY_DONE: Key = Key::from_static_name("ya-relay.packet.group.done");
+
+    #[derive(Clone)]
+    pub struct GroupMetric {
+        pub start: Counter,
+        pub done: Counter,
+        pub error: Counter,
+    }
+
+    impl Default for GroupMetric {
+        fn default() -> Self {
+            let recorder = recorder();
+            let start = recorder.register_counter(&KEY_START);
+            let done = recorder.register_counter(&KEY_DONE);
+            let error = recorder.register_counter(&KEY_ERROR);
+
+            Self { start, done, error }
+        }
+    }
+}
+
+/// Handles `JoinGroup` and `LeaveGroup` requests. Packets sent to a group are
+/// fanned out by [`super::forward::ForwardHandler`].
+pub struct GroupHandler {
+    session_manager: Arc<SessionManager>,
+    group_manager: Arc<GroupManager>,
+    metrics: metric::GroupMetric,
+    ack: CompletionHandler,
+}
+
+impl GroupHandler {
+    pub fn new(session_manager: &Arc<SessionManager>, group_manager: &Arc<GroupManager>) -> Self {
+        let session_manager = Arc::clone(session_manager);
+        let group_manager = Arc::clone(group_manager);
+        let metrics = metric::GroupMetric::default();
+        let ack = super::counter_ack(&metrics.done, &metrics.error);
+        Self {
+            session_manager,
+            group_manager,
+            metrics,
+            ack,
+        }
+    }
+
+    pub fn join(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        param: &request::JoinGroup,
+    ) -> Option<(CompletionHandler, Packet)> {
+        let (code, response) = match self.check(clock, src, session_id, &param.group) {
+            Err(code) => (code, response::JoinGroup::default()),
+            Ok(node_id) => match self.group_manager.join(&param.group, node_id) {
+                Ok(members) => {
+                    log::debug!(target: "request::group", "[{src}] {node_id} joined group {:?} ({members} members)", param.group);
+                    let members = members as u32;
+                    (StatusCode::Ok, response::JoinGroup { members })
+                }
+                Err(JoinError::TooManyGroups) => {
+                    log::debug!(target: "request::group", "[{src}] {node_id} can't join more groups");
+                    (StatusCode::TooManyRequests, Default::default())
+                }
+            },
+        };
+
+        Some((
+            self.ack.clone(),
+            Packet::response(request_id, session_id.to_vec(), code, response),
+        ))
+    }
+
+    pub fn leave(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        param: &request::LeaveGroup,
+    ) -> Option<(CompletionHandler, Packet)> {
+        let code = match self.check(clock, src, session_id, &param.group) {
+            Err(code) => code,
+            Ok(node_id) => match self.group_manager.leave(&param.group, node_id) {
+                true => {
+                    log::debug!(target: "request::group", "[{src}] {node_id} left group {:?}", param.group);
+                    StatusCode::Ok
+                }
+                false => StatusCode::NotFound,
+            },
+        };
+
+        Some((
+            self.ack.clone(),
+            Packet::response(
+                request_id,
+                session_id.to_vec(),
+                code,
+                response::LeaveGroup::default(),
+            ),
+        ))
+    }
+
+    fn check(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        session_id: SessionId,
+        group: &str,
+    ) -> Result<NodeId, StatusCode> {
+        self.metrics.start.increment(1);
+        let session_ref = match self.session_manager.session(&session_id) {
+            Some(session_ref) if session_ref.peer == src => session_ref,
+            _ => return Err(StatusCode::Unauthorized),
+        };
+        clock.touch(&session_ref.ts);
+
+        if group.is_empty() || group.len() > MAX_GROUP_NAME_LEN {
+            return Err(StatusCode::BadRequest);
+        }
+        Ok(session_ref.node_id)
+    }
+}
@@ -0,0 +1,165 @@
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use tokio::task::spawn_local;
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::response::Locate;
+use ya_relay_proto::proto::{request, Message, Packet, StatusCode};
+
+use crate::server::edge::CoreLink;
+use crate::server::CompletionHandler;
+use crate::state::edge_directory::EdgeDirectory;
+use crate::state::Clock;
+use crate::udp_server::UdpSocket;
+use crate::SessionManager;
+
+mod metric {
+    use metrics::{recorder, Counter, Key};
+
+    static KEY_START: Key = Key::from_static_name("ya-relay.packet.locate");
+    static KEY_ERROR: Key = Key::from_static_name("ya-relay.packet.locate.error");
+    static KEY_DONE: Key = Key::from_static_name("ya-relay.packet.locate.done");
+    static KEY_CORE: Key = Key::from_static_name("ya-relay.packet.locate.core");
+
+    #[derive(Clone)]
+    pub struct LocateMetric {
+        pub start: Counter,
+        pub done: Counter,
+        pub error: Counter,
+        pub core: Counter,
+    }
+
+    impl Default for LocateMetric {
+        fn default() -> Self {
+            let recorder = recorder();
+            let start = recorder.register_counter(&KEY_START);
+            let done = recorder.register_counter(&KEY_DONE);
+            let error = recorder.register_counter(&KEY_ERROR);
+            let core = recorder.register_counter(&KEY_CORE);
+
+            Self {
+                start,
+                done,
+                error,
+                core,
+            }
+        }
+    }
+}
+
+/// Finds the relay a Node is connected to: this one, one of its edge relays,
+/// or whichever relay the core relay points to.
+pub struct LocateHandler {
+    session_manager: Arc<SessionManager>,
+    edge_directory: Arc<EdgeDirectory>,
+    core_link: Option<Arc<CoreLink>>,
+    reply_socket: Rc<UdpSocket>,
+    metrics: metric::LocateMetric,
+    ack: CompletionHandler,
+}
+
+impl LocateHandler {
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        edge_directory: &Arc<EdgeDirectory>,
+        core_link: &Option<Arc<CoreLink>>,
+        reply_socket: &Rc<UdpSocket>,
+    ) -> Self {
+        let metrics = metric::LocateMetric::default();
+        let ack = super::counter_ack(&metrics.done, &metrics.error);
+        Self {
+            session_manager: session_manager.clone(),
+            edge_directory: edge_directory.clone(),
+            core_link: core_link.clone(),
+            reply_socket: reply_socket.clone(),
+            metrics,
+            ack,
+        }
+    }
+
+    pub fn handle(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        param: &request::Locate,
+    ) -> Option<(CompletionHandler, Packet)> {
+        self.metrics.start.increment(1);
+
+        let respond = |code: StatusCode, relay: Option<SocketAddr>| {
+            let packet = match code {
+                StatusCode::Ok => Packet::response(
+                    request_id,
+                    session_id.to_vec(),
+                    code,
+                    Locate {
+                        relay: relay.map(Into::into),
+                    },
+                ),
+                _ => Packet::error(request_id, session_id.to_vec(), code),
+            };
+            Some((self.ack.clone(), packet))
+        };
+
+        let session_ref = match self.session_manager.session(&session_id) {
+            Some(session_ref) if session_ref.peer == src => session_ref,
+            _ => return respond(StatusCode::Unauthorized, None),
+        };
+        clock.touch(&session_ref.ts);
+
+        let node_id = match NodeId::try_from(&param.node_id) {
+            Ok(node_id) => node_id,
+            Err(_) => return respond(StatusCode::BadRequest, None),
+        };
+
+        if self.session_manager.node_session(node_id).is_some() {
+            return respond(StatusCode::Ok, None);
+        }
+        if let Some(relay) = self.edge_directory.locate(node_id, clock.time()) {
+            log::debug!(target: "request::locate", "[{src}] {node_id} is at the edge relay {relay}");
+            return respond(StatusCode::Ok, Some(relay));
+        }
+        let core_link = match &self.core_link {
+            Some(core_link) => core_link.clone(),
+            None => return respond(StatusCode::NotFound, None),
+        };
+
+        self.metrics.core.increment(1);
+        let reply_socket = self.reply_socket.clone();
+        let ack = self.ack.clone();
+
+        spawn_local(async move {
+            let packet = match core_link.locate(node_id).await {
+                Ok(Some(relay)) => Packet::response(
+                    request_id,
+                    session_id.to_vec(),
+                    StatusCode::Ok,
+                    Locate {
+                        relay: Some(relay.into()),
+                    },
+                ),
+                Ok(None) => Packet::error(request_id, session_id.to_vec(), StatusCode::NotFound),
+                Err(e) => {
+                    log::warn!(target: "request::locate", "[{src}] unable to locate {node_id} at the core relay: {e}");
+                    Packet::error(request_id, session_id.to_vec(), StatusCode::GatewayTimeout)
+                }
+            };
+
+            let result = reply_socket.send_to(&packet.encode_to_vec(), src).await;
+            let clock = Clock::now();
+            match result {
+                Ok(_) => ack.done(&clock),
+                Err(e) => {
+                    log::error!("[{src}] failed to send response: {e:?}");
+                    ack.error(&clock);
+                }
+            }
+        });
+        None
+    }
+}
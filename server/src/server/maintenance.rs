@@ -0,0 +1,59 @@
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+use tokio::task::spawn_local;
+
+use ya_relay_proto::proto::{control, Message, Packet};
+
+use crate::udp_server::UdpSocket;
+use crate::{Maintenance, SessionManager};
+
+/// Sends announced maintenance notices to all connected Nodes.
+pub struct MaintenanceNotifier {
+    session_manager: Arc<SessionManager>,
+    reply_socket: Weak<UdpSocket>,
+}
+
+impl MaintenanceNotifier {
+    pub fn new(session_manager: &Arc<SessionManager>, reply_socket: &Rc<UdpSocket>) -> Self {
+        Self {
+            session_manager: session_manager.clone(),
+            reply_socket: Rc::downgrade(reply_socket),
+        }
+    }
+
+    /// Runs on the current worker until its socket is dropped.
+    pub fn spawn(self, maintenance: &Maintenance) {
+        let mut notices = maintenance.subscribe();
+        spawn_local(async move {
+            loop {
+                let notice = match notices.recv().await {
+                    Ok(notice) => notice,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!(target: "service::maintenance", "lagged, {n} notices lost");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Some(socket) = self.reply_socket.upgrade() else {
+                    break;
+                };
+
+                let maintenance = control::Maintenance::from(&notice);
+                let sessions = self.session_manager.snapshot();
+                log::info!(target: "service::maintenance", "sending notice to {} sessions", sessions.len());
+                for session in sessions.iter() {
+                    let packet = Packet::control(session.session_id.to_vec(), maintenance.clone());
+                    if let Err(e) = socket.send_to(&packet.encode_to_vec(), session.peer).await {
+                        log::debug!(
+                            "[{}] failed to send maintenance notice: {e:?}",
+                            session.peer
+                        );
+                    }
+                }
+            }
+            log::debug!(target: "service::maintenance", "stopped");
+        });
+    }
+}
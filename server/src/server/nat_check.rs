@@ -0,0 +1,204 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::task::{spawn_local, JoinHandle};
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_proto::proto::response::nat_check::Type as NatType;
+use ya_relay_proto::proto::{packet, request, response, Message, Packet, Request, StatusCode};
+
+use crate::server::CompletionHandler;
+use crate::state::Clock;
+use crate::udp_server::{UdpSocket, UdpSocketConfig};
+use crate::{AddrStatus, Session, SessionManager};
+
+const MAX_REQUEST_SIZE: usize = 100;
+
+mod metric {
+    use metrics::{recorder, Counter, Key};
+
+    static KEY_START: Key = Key::from_static_name("ya-relay.packet.nat-check");
+    static KEY_ERROR: Key = Key::from_static_name("ya-relay.packet.nat-check.error");
+    static KEY_DONE: Key = Key::from_static_name("ya-relay.packet.nat-check.done");
+
+    #[derive(Clone)]
+    pub struct NatCheckMetric {
+        pub start: Counter,
+        pub done: Counter,
+        pub error: Counter,
+    }
+
+    impl Default for NatCheckMetric {
+        fn default() -> Self {
+            let recorder = recorder();
+            let start = recorder.register_counter(&KEY_START);
+            let done = recorder.register_counter(&KEY_DONE);
+            let error = recorder.register_counter(&KEY_ERROR);
+
+            Self { start, done, error }
+        }
+    }
+}
+
+/// Answers the first `NatCheck` request, received on the server address, with the
+/// alternate address to repeat it on.
+///
+/// The repeated request is answered from a socket used only for this purpose, so Nodes
+/// sending to it don't open their NAT for the ip checker probes.
+pub struct NatCheckHandler {
+    session_manager: Arc<SessionManager>,
+    metrics: metric::NatCheckMetric,
+    ack: CompletionHandler,
+    alternate: SocketAddr,
+    alternate_job: JoinHandle<()>,
+}
+
+impl NatCheckHandler {
+    pub fn new(session_manager: &Arc<SessionManager>, ip: IpAddr) -> io::Result<Self> {
+        let session_manager = Arc::clone(session_manager);
+        let metrics = metric::NatCheckMetric::default();
+        let ack = super::counter_ack(&metrics.done, &metrics.error);
+
+        let socket = UdpSocketConfig::new().bind((ip, 0).into())?;
+        let alternate = socket.local_addr()?;
+        let alternate_job = spawn_local(serve_alternate(
+            socket,
+            session_manager.clone(),
+            metrics.clone(),
+        ));
+
+        Ok(Self {
+            session_manager,
+            metrics,
+            ack,
+            alternate,
+            alternate_job,
+        })
+    }
+
+    pub fn handle(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        _param: &request::NatCheck,
+    ) -> Option<(CompletionHandler, Packet)> {
+        self.metrics.start.increment(1);
+        let (code, response) = match self.session_manager.session(&session_id) {
+            Some(session_ref) if session_ref.peer == src => {
+                clock.touch(&session_ref.ts);
+                let response = response::NatCheck {
+                    observed: Some(src.into()),
+                    alternate: Some(self.alternate.into()),
+                    nat_type: NatType::Unknown.into(),
+                };
+                (StatusCode::Ok, response)
+            }
+            _ => (StatusCode::Unauthorized, Default::default()),
+        };
+
+        Some((
+            self.ack.clone(),
+            Packet::response(request_id, session_id.to_vec(), code, response),
+        ))
+    }
+}
+
+impl Drop for NatCheckHandler {
+    fn drop(&mut self) {
+        self.alternate_job.abort();
+    }
+}
+
+async fn serve_alternate(
+    socket: UdpSocket,
+    session_manager: Arc<SessionManager>,
+    metrics: metric::NatCheckMetric,
+) {
+    let mut data = BytesMut::with_capacity(MAX_REQUEST_SIZE);
+    loop {
+        data.reserve(MAX_REQUEST_SIZE);
+        let src = match socket.recv_from(&mut data).await {
+            Ok(src) => src,
+            Err(e) => {
+                log::error!(target: "request::nat_check", "recv {:?}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let (session_id, request_id) = match Packet::decode(&mut data.split()) {
+            Ok(Packet {
+                session_id,
+                kind:
+                    Some(packet::Kind::Request(Request {
+                        request_id,
+                        kind: Some(request::Kind::NatCheck(_)),
+                    })),
+            }) => (session_id, request_id),
+            other => {
+                log::debug!(target: "request::nat_check", "[{src}] invalid packet {:?}", other);
+                continue;
+            }
+        };
+
+        metrics.start.increment(1);
+        let (code, response) = match classify(&session_manager, src, &session_id) {
+            Some(nat_type) => {
+                log::debug!(target: "request::nat_check", "[{src}] classified as {nat_type:?}");
+                let response = response::NatCheck {
+                    observed: Some(src.into()),
+                    alternate: None,
+                    nat_type: nat_type.into(),
+                };
+                (StatusCode::Ok, response)
+            }
+            None => (StatusCode::Unauthorized, Default::default()),
+        };
+
+        let packet = Packet::response(request_id, session_id, code, response);
+        match socket.send_to(&packet.encode_to_vec(), src).await {
+            Ok(_) => metrics.done.increment(1),
+            Err(e) => {
+                log::warn!(target: "request::nat_check", "[{src}] failed to send response: {e:?}");
+                metrics.error.increment(1);
+            }
+        }
+    }
+}
+
+/// Compares the address of the repeated request with the one the session was created
+/// from. Filtering is known from the ip check done during registration.
+fn classify(
+    session_manager: &SessionManager,
+    src: SocketAddr,
+    session_id: &[u8],
+) -> Option<NatType> {
+    let session_id = SessionId::try_from(session_id).ok()?;
+    let session_ref = session_manager.session(&session_id)?;
+    if session_ref.peer.ip() != src.ip() {
+        return None;
+    }
+    if session_ref.peer != src {
+        session_ref.symmetric_nat.store(true, Ordering::Relaxed);
+        return Some(NatType::Symmetric);
+    }
+    Some(nat_type(&session_ref))
+}
+
+/// NAT type of the Node as far as known, also reported to other Nodes looking it up.
+pub(crate) fn nat_type(session: &Session) -> NatType {
+    if session.symmetric_nat.load(Ordering::Relaxed) {
+        return NatType::Symmetric;
+    }
+    match &*session.addr_status.lock() {
+        AddrStatus::Valid(_) => NatType::Cone,
+        AddrStatus::Invalid(_) => NatType::PortRestricted,
+        AddrStatus::Unknown | AddrStatus::Pending(_) => NatType::Unknown,
+    }
+}
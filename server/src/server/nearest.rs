@@ -0,0 +1,107 @@
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::response::Nearest;
+use ya_relay_proto::proto::{request, Packet, StatusCode};
+
+use crate::server::find_nodes::MAX_FIND_NODES;
+use crate::server::CompletionHandler;
+use crate::state::slot_manager::SlotManager;
+use crate::state::Clock;
+use crate::SessionManager;
+
+mod metric {
+    use metrics::{recorder, Counter, Key};
+
+    static KEY_START: Key = Key::from_static_name("ya-relay.packet.nearest");
+    static KEY_ERROR: Key = Key::from_static_name("ya-relay.packet.nearest.error");
+    static KEY_DONE: Key = Key::from_static_name("ya-relay.packet.nearest.done");
+
+    #[derive(Clone)]
+    pub struct NearestMetric {
+        pub start: Counter,
+        pub done: Counter,
+        pub error: Counter,
+    }
+
+    impl Default for NearestMetric {
+        fn default() -> Self {
+            let recorder = recorder();
+            let start = recorder.register_counter(&KEY_START);
+            let done = recorder.register_counter(&KEY_DONE);
+            let error = recorder.register_counter(&KEY_ERROR);
+
+            Self { start, done, error }
+        }
+    }
+}
+
+pub struct NearestHandler {
+    session_manager: Arc<SessionManager>,
+    slot_manager: Arc<SlotManager>,
+    metrics: metric::NearestMetric,
+    ack: CompletionHandler,
+}
+
+impl NearestHandler {
+    pub fn new(session_manager: &Arc<SessionManager>, slot_manager: &Arc<SlotManager>) -> Self {
+        let session_manager = Arc::clone(session_manager);
+        let slot_manager = slot_manager.clone();
+        let metrics = metric::NearestMetric::default();
+        let ack = super::counter_ack(&metrics.done, &metrics.error);
+        Self {
+            session_manager,
+            slot_manager,
+            metrics,
+            ack,
+        }
+    }
+
+    pub fn handle(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        param: &request::Nearest,
+    ) -> Option<(CompletionHandler, Packet)> {
+        self.metrics.start.increment(1);
+
+        let respond = |code: StatusCode, nearest: Nearest| {
+            Some((
+                self.ack.clone(),
+                Packet::response(request_id, session_id.to_vec(), code, nearest),
+            ))
+        };
+
+        let session_ref = match self.session_manager.session(&session_id) {
+            Some(session_ref) if session_ref.peer == src => session_ref,
+            _ => return respond(StatusCode::Unauthorized, Nearest::default()),
+        };
+        clock.touch(&session_ref.ts);
+
+        let target = match param.node_id.is_empty() {
+            true => session_ref.node_id,
+            false => match NodeId::try_from(&param.node_id) {
+                Ok(node_id) => node_id,
+                Err(_) => return respond(StatusCode::BadRequest, Nearest::default()),
+            },
+        };
+
+        let decoder = super::state_decoder::decoder(&self.session_manager, &self.slot_manager);
+        let count = param.count.min(MAX_FIND_NODES) as usize;
+        let nodes = self
+            .session_manager
+            .nearest(target, count, Some(session_ref.node_id))
+            .into_iter()
+            .map(|session_ref| decoder.to_node_info(&session_ref))
+            .collect::<Vec<_>>();
+
+        log::debug!(target: "request::nearest", "[{src}] found {} nodes nearest to {target}", nodes.len());
+
+        respond(StatusCode::Ok, Nearest { nodes })
+    }
+}
@@ -121,7 +121,13 @@ impl NodeHandler {
         };
 
         let node = match self.session_manager.node_session(request_node_id) {
-            Some(it) => decoder.to_node_info(&it),
+            Some(it) => {
+                if it.endpoint().is_none() {
+                    self.session_manager
+                        .watch_node(request_node_id, &session_ref);
+                }
+                decoder.to_node_info(&it)
+            }
             None => {
                 return Some((
                     self.ack.clone(),
@@ -7,15 +7,17 @@ use std::time::Instant;
 use quick_cache::sync::Cache;
 use tokio::task::spawn_local;
 
+use ya_relay_core::properties::verify_properties;
 use ya_relay_core::server_session::SessionId;
 use ya_relay_proto::proto::{request, response, Message, Packet, StatusCode};
 
 use crate::server::ip_checker::IpChecker;
 use crate::server::{counter_ack, noop_ack, CompletionHandler, IpCache};
+use crate::state::response_cache::{RequestKey, ResponseCache};
 use crate::state::slot_manager::SlotManager;
 use crate::state::Clock;
 use crate::udp_server::UdpSocket;
-use crate::{AddrStatus, SessionManager};
+use crate::{AddrStatus, NodeProperties, SessionManager};
 
 mod metric {
     use metrics::{recorder, Counter, Key};
@@ -51,6 +53,7 @@ pub struct RegisterHandler {
     ip_checker: IpChecker,
     cache: Arc<Cache<SocketAddr, (Instant, bool)>>,
     reply_socket: Weak<UdpSocket>,
+    responses: Arc<ResponseCache>,
 }
 
 impl RegisterHandler {
@@ -60,6 +63,7 @@ impl RegisterHandler {
         ip_checker: IpChecker,
         reply_socket: &Rc<UdpSocket>,
         cache: IpCache,
+        responses: &Arc<ResponseCache>,
     ) -> Self {
         let session_manager = Arc::clone(session_manager);
         let slot_manager = slot_manager.clone();
@@ -76,6 +80,7 @@ impl RegisterHandler {
             ip_checker,
             cache,
             reply_socket,
+            responses: responses.clone(),
         }
     }
 
@@ -85,11 +90,12 @@ impl RegisterHandler {
         src: SocketAddr,
         request_id: u64,
         session_id: SessionId,
-        _register: &request::Register,
+        register: &request::Register,
     ) -> Option<(CompletionHandler, Packet)> {
+        // Replayed from another address, it would reset the address status of the session.
         let session_ref = match self.session_manager.session(&session_id) {
-            Some(session_ref) => session_ref,
-            None => {
+            Some(session_ref) if session_ref.peer == src => session_ref,
+            _ => {
                 log::debug!(target: "request::register", "[{src}] session not found {session_id}");
                 self.metrics.error.increment(1);
                 return Some((
@@ -105,6 +111,30 @@ impl RegisterHandler {
         };
         clock.touch(&session_ref.ts);
         self.metrics.start.increment(1);
+
+        if let Some(signed) = &register.properties {
+            match verify_properties(signed, session_ref.node_id) {
+                Ok(values) => {
+                    *session_ref.properties.lock() = Some(NodeProperties {
+                        signed: signed.clone(),
+                        values,
+                    });
+                }
+                Err(e) => {
+                    log::debug!(target: "request::register", "[{src}] rejected properties: {e}");
+                    return Some((
+                        self.ack.clone(),
+                        Packet::response(
+                            request_id,
+                            session_id.to_vec(),
+                            StatusCode::BadRequest,
+                            response::Register::default(),
+                        ),
+                    ));
+                }
+            }
+        }
+
         match self.cache.get(&src) {
             Some((ts, v)) if ts.elapsed() < Duration::from_secs(60) => {
                 log::debug!(target: "request::register", "[{src}] resolving from cache: {v:?}");
@@ -134,6 +164,15 @@ impl RegisterHandler {
             let reply_socket = self.reply_socket.clone();
             let ack = self.ack.clone();
             let sm = self.session_manager.clone();
+            let responses = self.responses.clone();
+            let key = RequestKey::new(
+                src,
+                session_id,
+                request_id,
+                &request::Kind::Register(register.clone()),
+            );
+            // Retries are dropped until the check completes, instead of starting another one.
+            responses.pending(key, clock.time());
             log::debug!(target: "request::register", "[{src}] resolving from ip_checker {session_id}");
             self.ip_checker.check_ip_status(clock.time(), session_ref, move |status, session_ref| {
                 let reply_socket = match reply_socket.upgrade() {
@@ -156,6 +195,7 @@ impl RegisterHandler {
                     StatusCode::Ok,
                     response::Register { endpoints },
                 );
+                responses.store(key, &data, Instant::now());
                 spawn_local(async move {
                     let result = reply_socket.send_to(&data.encode_to_vec(), peer).await;
                     let clock = Clock::now();
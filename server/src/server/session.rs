@@ -1,14 +1,23 @@
 use std::sync::Arc;
-use std::time;
+use std::time::{self, Duration, Instant};
 
 use tiny_keccak::Hasher;
 
 use ya_relay_core::challenge::RawChallenge;
+use ya_relay_core::forward_auth::KeyExchange;
+use ya_relay_core::server_identity::{self, SecretKey};
 
 use crate::server::session::metric::SessionMetric;
+use crate::state::load::RelayLoad;
+use crate::state::replay_guard::ReplayGuard;
+use crate::SessionRef;
 
 use super::*;
 
+/// Nodes which exchanged forwards with a Node within this time are notified,
+/// when it takes over its session from another address.
+const TAKEOVER_NOTIFY_WINDOW: Duration = Duration::from_secs(60);
+
 mod metric {
     use metrics::{recorder, Counter, Key};
 
@@ -18,12 +27,19 @@ mod metric {
         Key::from_static_name("ya-relay.session.establish.challenge.sent");
     static SESSION_EST_CHALLENGE_VALID: Key =
         Key::from_static_name("ya-relay.session.establish.challenge.valid");
+    static SESSION_EST_REPLAY: Key = Key::from_static_name("ya-relay.session.establish.replay");
+    static SESSION_EST_EXPIRED: Key = Key::from_static_name("ya-relay.session.establish.expired");
+    static SESSION_EST_THROTTLED: Key =
+        Key::from_static_name("ya-relay.session.establish.throttled");
 
     pub(super) struct SessionMetric {
         pub start: Counter,
         pub error: Counter,
         pub challenge_sent: Counter,
         pub challenge_valid: Counter,
+        pub replay: Counter,
+        pub expired: Counter,
+        pub throttled: Counter,
     }
 
     impl Default for SessionMetric {
@@ -32,11 +48,17 @@ mod metric {
             let error = recorder().register_counter(&SESSION_EST_ERROR);
             let challenge_sent = recorder().register_counter(&SESSION_EST_CHALLENGE_SENT);
             let challenge_valid = recorder().register_counter(&SESSION_EST_CHALLENGE_VALID);
+            let replay = recorder().register_counter(&SESSION_EST_REPLAY);
+            let expired = recorder().register_counter(&SESSION_EST_EXPIRED);
+            let throttled = recorder().register_counter(&SESSION_EST_THROTTLED);
             Self {
                 start,
                 error,
                 challenge_sent,
                 challenge_valid,
+                replay,
+                expired,
+                throttled,
             }
         }
     }
@@ -50,6 +72,81 @@ pub struct SessionHandlerConfig {
     pub difficulty: u64,
     #[arg(long, env, value_parser = u128_from_hex)]
     pub salt: Option<u128>,
+    /// Time to answer the challenge. Session ids are accepted only once within this window.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "2min")]
+    pub handshake_window: Duration,
+    /// Ed25519 secret key in hex, signing handshake responses. If not set, the key
+    /// is kept in the state directory, or generated on each start without one.
+    #[arg(long, env, value_parser = server_identity::secret_key_from_hex)]
+    pub server_key: Option<SecretKey>,
+    /// Sessions a single IP address can create within `--ip-session-window` at the base
+    /// difficulty. Each further quota of sessions doubles the challenge work. `0` disables it.
+    #[arg(long, env, default_value = "16")]
+    pub ip_session_quota: u32,
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "1min")]
+    pub ip_session_window: Duration,
+    /// Interval of pinging the relay, advertised to clients. `0s` leaves it to them
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "25s")]
+    pub ping_interval: Duration,
+    /// Largest Forward payload in bytes, advertised to clients. `0` advertises no limit
+    #[arg(long, env, default_value = "0")]
+    pub max_forward_size: u32,
+    /// Sessions at full load of the relay. Clients choosing between relays are told
+    /// the load relative to it. `0` doesn't report the session load
+    #[arg(long, env, default_value = "0")]
+    pub session_capacity: usize,
+    /// Bytes forwarded per second at full load of the relay, reported like `--session-capacity`
+    #[arg(long, env, default_value = "0")]
+    pub bandwidth_capacity: u64,
+}
+
+/// Bits of difficulty added on top of the base one at most.
+const MAX_EXTRA_DIFFICULTY: u64 = 32;
+
+/// Session id is the time it was issued, difficulty added to the challenge, a random
+/// nonce and a MAC binding these to the peer address, so the server doesn't have
+/// to keep pending handshakes.
+pub(crate) fn new_session_id(
+    salt: &[u8; 16],
+    difficulty: u64,
+    addr: SocketAddr,
+    ts: u32,
+    extra: u8,
+    nonce: [u8; 3],
+) -> SessionId {
+    let mut id = [0u8; 16];
+    id[..4].copy_from_slice(&ts.to_be_bytes());
+    id[4] = extra;
+    id[5..8].copy_from_slice(&nonce);
+    let mac = session_id_mac(salt, difficulty, addr, &id[..8]);
+    id[8..].copy_from_slice(&mac);
+    id.into()
+}
+
+fn session_id_mac(salt: &[u8; 16], difficulty: u64, addr: SocketAddr, issued: &[u8]) -> [u8; 8] {
+    let mut data = [0u8; 32];
+
+    let mut h = tiny_keccak::Keccak::v256();
+    match addr {
+        SocketAddr::V4(v4) => {
+            h.update(&v4.ip().octets());
+            h.update(&v4.port().to_be_bytes());
+        }
+        SocketAddr::V6(v6) => {
+            h.update(&v6.ip().octets());
+            h.update(&v6.port().to_be_bytes());
+        }
+    }
+    h.update(salt);
+    h.update(&difficulty.to_ne_bytes());
+    h.update(issued);
+    h.finalize(&mut data);
+
+    data[..8].try_into().unwrap()
+}
+
+fn millis(duration: Duration) -> u32 {
+    duration.as_millis().try_into().unwrap_or(u32::MAX)
 }
 
 fn u128_from_hex(hex_str: &str) -> Result<u128, hex::FromHexError> {
@@ -57,18 +154,45 @@ fn u128_from_hex(hex_str: &str) -> Result<u128, hex::FromHexError> {
     Ok(u128::from_le_bytes(bytes))
 }
 
+enum SessionIdCheck {
+    Valid { difficulty: u64 },
+    Expired,
+    Invalid,
+}
+
 pub struct SessionHandler {
-    difficulty: u64,
+    limits: Arc<Limits>,
     salt: [u8; 16],
+    handshake_window: Duration,
+    ip_session_quota: u32,
+    ip_session_window: Duration,
+    ping_interval: Duration,
+    max_forward_size: u32,
+    load: Arc<RelayLoad>,
+    server_key: SecretKey,
     session_manager: Arc<SessionManager>,
+    replay_guard: Arc<ReplayGuard>,
+    traffic: Arc<TrafficMatrix>,
+    socket: Rc<UdpSocket>,
     metrics: SessionMetric,
     challenge_send_ack: CompletionHandler,
     challenge_valid_ack: CompletionHandler,
 }
 
 impl SessionHandler {
-    pub fn new(session_manager: &Arc<SessionManager>, config: &SessionHandlerConfig) -> Self {
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        replay_guard: &Arc<ReplayGuard>,
+        server_key: &SecretKey,
+        config: &SessionHandlerConfig,
+        limits: &Arc<Limits>,
+        traffic: &Arc<TrafficMatrix>,
+        socket: &Rc<UdpSocket>,
+    ) -> Self {
         let session_manager = Arc::clone(session_manager);
+        let replay_guard = Arc::clone(replay_guard);
+        let traffic = Arc::clone(traffic);
+        let socket = socket.clone();
         let metrics = SessionMetric::default();
         let challenge_send_ack = counter_ack(&metrics.challenge_sent, &metrics.error);
         let challenge_valid_ack = counter_ack(&metrics.challenge_valid, &metrics.error);
@@ -76,20 +200,78 @@ impl SessionHandler {
             .salt
             .unwrap_or_else(|| thread_rng().gen())
             .to_ne_bytes();
-        let difficulty = config.difficulty;
+        let handshake_window = config.handshake_window;
+        let ip_session_quota = config.ip_session_quota;
+        let ip_session_window = config.ip_session_window;
+        let ping_interval = config.ping_interval;
+        let max_forward_size = config.max_forward_size;
+        let load = RelayLoad::new(config, &session_manager, &traffic);
+        let server_key = server_key.clone();
+        let limits = limits.clone();
 
         Self {
-            difficulty,
+            limits,
             salt,
+            handshake_window,
+            ip_session_quota,
+            ip_session_window,
+            ping_interval,
+            max_forward_size,
+            load,
+            server_key,
             session_manager,
+            replay_guard,
+            traffic,
+            socket,
             metrics,
             challenge_send_ack,
             challenge_valid_ack,
         }
     }
 
-    fn epoch(&self) -> u64 {
-        time::UNIX_EPOCH.elapsed().unwrap().as_secs() / 600
+    /// Closes sessions the Node established before from other addresses. The Node at the
+    /// old address and Nodes, which exchanged forwards with it recently, are told it
+    /// disconnected, so they drop sessions with the old address and look it up again.
+    fn take_over(&self, session: &SessionRef) {
+        let previous = self.session_manager.take_over(session);
+        if previous.is_empty() {
+            return;
+        }
+
+        let mut packets = Vec::new();
+        for prev in &previous {
+            let by = control::disconnected::By::SessionId(prev.session_id.to_vec());
+            packets.push((
+                prev.peer,
+                Packet::control(
+                    prev.session_id.to_vec(),
+                    control::Disconnected { by: Some(by) },
+                ),
+            ));
+        }
+        for peer_id in self.traffic.peers(session.node_id, TAKEOVER_NOTIFY_WINDOW) {
+            if let Some(peer) = self.session_manager.node_session(peer_id) {
+                let by = control::disconnected::By::NodeId(session.node_id.into_array().to_vec());
+                packets.push((
+                    peer.peer,
+                    Packet::control(
+                        peer.session_id.to_vec(),
+                        control::Disconnected { by: Some(by) },
+                    ),
+                ));
+            }
+        }
+
+        let socket = self.socket.clone();
+        tokio::task::spawn_local(async move {
+            for (addr, packet) in packets {
+                socket.send_to(&packet.encode_to_vec(), addr).await.ok();
+            }
+        });
+    }
+
+    fn unix_time(&self) -> u32 {
+        time::UNIX_EPOCH.elapsed().unwrap().as_secs() as u32
     }
 
     fn session_challenge(&self, session_id: SessionId) -> RawChallenge {
@@ -102,46 +284,100 @@ impl SessionHandler {
         raw_challenge
     }
 
-    fn check_session_id(&self, session_id: SessionId, addr: SocketAddr) -> bool {
-        let epoch = self.epoch();
-        for n in 0..3 {
-            if session_id == self.gen_new_challenge(addr, epoch - n) {
-                return true;
-            }
+    /// Difficulty added for the address, which recently created many sessions.
+    fn extra_difficulty(&self, addr: SocketAddr, now: Instant) -> u64 {
+        if self.ip_session_quota == 0 {
+            return 0;
         }
+        let since = now.checked_sub(self.ip_session_window).unwrap_or(now);
+        let recent = self.session_manager.recent_sessions(addr.ip(), since) as u64;
+        (recent / self.ip_session_quota as u64).min(MAX_EXTRA_DIFFICULTY)
+    }
 
-        false
+    fn new_session_id(&self, addr: SocketAddr, ts: u32, extra: u8, nonce: [u8; 3]) -> SessionId {
+        let difficulty = self.limits.challenge_difficulty();
+        new_session_id(&self.salt, difficulty, addr, ts, extra, nonce)
     }
-    fn gen_new_challenge(&self, addr: SocketAddr, epoch: u64) -> SessionId {
-        let mut data = [0u8; 32];
-        let difficulty = self.difficulty;
 
-        let mut h = tiny_keccak::Keccak::v256();
-        match addr {
-            SocketAddr::V4(v4) => {
-                h.update(&v4.ip().octets());
-                h.update(&v4.port().to_be_bytes());
-            }
-            SocketAddr::V6(v6) => {
-                h.update(&v6.ip().octets());
-                h.update(&v6.port().to_be_bytes());
-            }
+    fn session_id_mac(&self, addr: SocketAddr, issued: &[u8]) -> [u8; 8] {
+        let difficulty = self.limits.challenge_difficulty();
+        session_id_mac(&self.salt, difficulty, addr, issued)
+    }
+
+    fn check_session_id(&self, session_id: SessionId, addr: SocketAddr) -> SessionIdCheck {
+        let id = session_id.to_array();
+        if id[8..] != self.session_id_mac(addr, &id[..8]) {
+            return SessionIdCheck::Invalid;
+        }
+
+        let issued = u32::from_be_bytes(id[..4].try_into().unwrap());
+        let age = Duration::from_secs(self.unix_time().abs_diff(issued).into());
+        if age > self.handshake_window {
+            return SessionIdCheck::Expired;
+        }
+        SessionIdCheck::Valid {
+            difficulty: self.limits.challenge_difficulty() + id[4] as u64,
         }
+    }
+
+    /// Clients verifying the server identity send a nonce to sign.
+    fn server_signature(&self, nonce: &[u8], message: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        match nonce.is_empty() {
+            true => Vec::new(),
+            false => server_identity::sign(&self.server_key, &message()),
+        }
+    }
+
+    /// Derived from the session id, so retransmitted challenge responses get the same key.
+    fn key_exchange(&self, session_id: SessionId) -> KeyExchange {
+        let mut secret = [0u8; 32];
+        let mut h = tiny_keccak::Keccak::v256();
+        h.update(b"forward-key");
         h.update(&self.salt);
-        h.update(&difficulty.to_ne_bytes());
-        //h.update(&request_id.to_ne_bytes());
-        h.update(&epoch.to_ne_bytes());
-        h.finalize(&mut data);
+        h.update(&session_id.to_array());
+        h.finalize(&mut secret);
+        KeyExchange::from_secret(secret)
+    }
 
-        let session_id: [u8; 16] = (&data[..16]).try_into().unwrap();
-        /*let request = proto::ChallengeRequest {
-            version: "0.0.1".to_string(),
-            caps: 0,
-            kind: proto::challenge_request::Kind::Sha3512LeadingZeros as i32,
-            difficulty,
-            challenge: raw_challenge.to_vec(),
-        };*/
-        session_id.into()
+    fn session_accepted(
+        &self,
+        nonce: &[u8],
+        session_id: SessionId,
+        key_exchange: Option<Vec<u8>>,
+    ) -> response::Session {
+        let key_exchange = key_exchange.unwrap_or_default();
+        response::Session {
+            server_signature: self.server_signature(nonce, || {
+                server_identity::session_message(nonce, &session_id.to_vec(), &key_exchange)
+            }),
+            key_exchange,
+            // Purge timeout can change at runtime, so it's read for each session.
+            session_ttl_ms: millis(self.limits.session_purge_timeout()),
+            ping_interval_ms: millis(self.ping_interval),
+            max_forward_size: self.max_forward_size,
+            // Each Forward refreshes the session of its sender.
+            forward_keep_alive: true,
+            load: self.load.report(),
+            ..Default::default()
+        }
+    }
+
+    fn reject(
+        &self,
+        request_id: u64,
+        session_id: SessionId,
+    ) -> Option<(CompletionHandler, Packet)> {
+        Some((
+            noop_ack(),
+            Packet {
+                session_id: session_id.to_vec(),
+                kind: Some(packet::Kind::Response(Response {
+                    code: StatusCode::BadRequest.into(),
+                    request_id,
+                    kind: Some(response::Kind::Session(Default::default())),
+                })),
+            },
+        ))
     }
 
     pub fn handle(
@@ -158,8 +394,24 @@ impl SessionHandler {
                 request::Session {
                     challenge_resp: Some(challenge_resp),
                     supported_encryptions,
+                    nonce,
+                    key_exchange,
                     ..
                 } => {
+                    let difficulty = match self.check_session_id(session_id, src) {
+                        SessionIdCheck::Valid { difficulty } => difficulty,
+                        SessionIdCheck::Expired => {
+                            log::debug!(target: "request::session", "[{src}] handshake window passed for session_id={session_id}");
+                            self.metrics.expired.increment(1);
+                            self.metrics.error.increment(1);
+                            return self.reject(request_id, session_id);
+                        }
+                        SessionIdCheck::Invalid => {
+                            self.metrics.error.increment(1);
+                            return self.reject(request_id, session_id);
+                        }
+                    };
+
                     let challenge = self.session_challenge(session_id);
                     log::info!(
                         "resp session_id={}, request_id={}, challange={}",
@@ -172,7 +424,7 @@ impl SessionHandler {
                         ChallengeDigest,
                     >(
                         &challenge,
-                        self.difficulty,
+                        difficulty,
                         Some(challenge_resp.clone()),
                         None,
                     ) {
@@ -194,21 +446,43 @@ impl SessionHandler {
                         Ok(v) => v,
                     };
 
-                    if !self.check_session_id(session_id, src) {
+                    // Retransmitted responses find the session, which was created on the first one.
+                    if self.session_manager.session(&session_id).is_none()
+                        && !self.replay_guard.consume(session_id, clock.time())
+                    {
+                        log::warn!(target: "request::session", "[{src}] rejected replayed challenge response for session_id={session_id}");
+                        self.metrics.replay.increment(1);
                         self.metrics.error.increment(1);
-                        return Some((
-                            noop_ack(),
-                            Packet {
-                                session_id: session_id.to_vec(),
-                                kind: Some(packet::Kind::Response(Response {
-                                    code: StatusCode::BadRequest.into(),
-                                    request_id,
-                                    kind: Some(response::Kind::Session(Default::default())),
-                                })),
-                            },
-                        ));
+                        return self.reject(request_id, session_id);
                     }
 
+                    let session_key_exchange = self.key_exchange(session_id);
+                    let forward_key = match key_exchange.is_empty() {
+                        true => None,
+                        false => {
+                            match session_key_exchange.derive(key_exchange, &session_id.to_vec()) {
+                                Ok(forward_key) => Some(forward_key),
+                                Err(e) => {
+                                    log::debug!(target: "request::session", "[{src}] invalid key exchange for session_id={session_id}: {e}");
+                                    self.metrics.error.increment(1);
+                                    return self.reject(request_id, session_id);
+                                }
+                            }
+                        }
+                    };
+                    let accepted = Packet {
+                        session_id: session_id.to_vec(),
+                        kind: Some(packet::Kind::Response(Response {
+                            code: StatusCode::Ok.into(),
+                            request_id,
+                            kind: Some(response::Kind::Session(self.session_accepted(
+                                nonce,
+                                session_id,
+                                forward_key.map(|_| session_key_exchange.public_key().to_vec()),
+                            ))),
+                        })),
+                    };
+
                     match self.session_manager.new_session(
                         clock,
                         session_id,
@@ -216,21 +490,17 @@ impl SessionHandler {
                         node_id,
                         keys,
                         supported_encryptions.clone(),
+                        forward_key,
                     ) {
-                        Ok(_) => Some((
-                            self.challenge_valid_ack.clone(),
-                            Packet {
-                                session_id: session_id.to_vec(),
-                                kind: Some(packet::Kind::Response(Response {
-                                    code: StatusCode::Ok.into(),
-                                    request_id,
-                                    kind: Some(response::Kind::Session(Default::default())),
-                                })),
-                            },
-                        )),
+                        Ok(session) => {
+                            self.take_over(&session);
+                            Some((self.challenge_valid_ack.clone(), accepted))
+                        }
                         Err(prev_session_id) => {
-                            if prev_session_id.node_id != node_id {
-                                log::warn!(target: "request::session", "[{src}] conflicting session_id={session_id}, age={:?} old({}) != {node_id}", clock.age(&prev_session_id.ts), prev_session_id.node_id);
+                            if prev_session_id.node_id != node_id
+                                || prev_session_id.forward_key != forward_key
+                            {
+                                log::warn!(target: "request::session", "[{src}] conflicting session_id={session_id}, age={:?} old({}) != {node_id} or key exchange differs", clock.age(&prev_session_id.ts), prev_session_id.node_id);
                                 return Some((
                                     noop_ack(),
                                     Packet {
@@ -242,19 +512,8 @@ impl SessionHandler {
                                         })),
                                     },
                                 ));
-                            } else {
-                                Some((
-                                    self.challenge_valid_ack.clone(),
-                                    Packet {
-                                        session_id: session_id.to_vec(),
-                                        kind: Some(packet::Kind::Response(Response {
-                                            code: StatusCode::Ok.into(),
-                                            request_id,
-                                            kind: Some(response::Kind::Session(Default::default())),
-                                        })),
-                                    },
-                                ))
                             }
+                            Some((self.challenge_valid_ack.clone(), accepted))
                         }
                     }
                 }
@@ -264,11 +523,23 @@ impl SessionHandler {
                 }
             }
         } else {
-            let (mut session, _challenge) = challenge::prepare_challenge_response(self.difficulty);
-            let session_id = self.gen_new_challenge(src, self.epoch());
+            let extra = self.extra_difficulty(src, clock.time());
+            if extra > 0 {
+                log::debug!(target: "request::session", "[{src}] adding {extra} bits of difficulty for recently created sessions");
+                self.metrics.throttled.increment(1);
+            }
+            let (mut session, _challenge) =
+                challenge::prepare_challenge_response(self.limits.challenge_difficulty() + extra);
+            let session_id =
+                self.new_session_id(src, self.unix_time(), extra as u8, thread_rng().gen());
 
+            // Lets clients compare relays before solving the challenge.
+            session.load = self.load.report();
             if let Some(s) = &mut session.challenge_req {
                 s.challenge = self.session_challenge(session_id).to_vec();
+                session.server_signature = self.server_signature(&req_session.nonce, || {
+                    server_identity::challenge_message(&req_session.nonce, &session_id.to_vec(), s)
+                });
                 log::info!(
                     "req session_id={}, request_id={}, challange={}",
                     session_id,
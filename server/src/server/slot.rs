@@ -106,8 +106,12 @@ impl SlotHandler {
 
         let request_node_id: NodeId = self.slot_manager.node(param.slot)?;
         let decoder = decoder(&self.session_manager, &self.slot_manager);
-        if let Some(session_ref) = self.session_manager.node_session(request_node_id) {
-            let node = decoder.to_node_info(&session_ref);
+        if let Some(node_session) = self.session_manager.node_session(request_node_id) {
+            if node_session.endpoint().is_none() {
+                self.session_manager
+                    .watch_node(request_node_id, &session_ref);
+            }
+            let node = decoder.to_node_info(&node_session);
             Some((
                 self.ack.clone(),
                 Packet::response(request_id, session_id.to_vec(), StatusCode::Ok, node),
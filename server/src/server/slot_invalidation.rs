@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+use tokio::task::spawn_local;
+
+use ya_relay_proto::proto::{control, Message, Packet};
+
+use crate::state::slot_manager::SlotManager;
+use crate::state::traffic::TrafficMatrix;
+use crate::udp_server::UdpSocket;
+use crate::{SessionEvent, SessionEventKind, SessionManager};
+
+/// Nodes which forwarded to a Node within this time are notified, when it disconnects.
+const INVALIDATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Tells Nodes, which recently forwarded to a Node that disconnected, that its slot
+/// is no longer valid. They close their connections with the Node right away, instead
+/// of retransmitting to a slot the relay can't deliver to.
+pub struct SlotInvalidator {
+    session_manager: Arc<SessionManager>,
+    slot_manager: Arc<SlotManager>,
+    traffic: Arc<TrafficMatrix>,
+    reply_socket: Weak<UdpSocket>,
+}
+
+impl SlotInvalidator {
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        slot_manager: &Arc<SlotManager>,
+        traffic: &Arc<TrafficMatrix>,
+        reply_socket: &Rc<UdpSocket>,
+    ) -> Self {
+        Self {
+            session_manager: session_manager.clone(),
+            slot_manager: slot_manager.clone(),
+            traffic: traffic.clone(),
+            reply_socket: Rc::downgrade(reply_socket),
+        }
+    }
+
+    /// Runs on the current worker until its socket is dropped.
+    pub fn spawn(self, mut events: broadcast::Receiver<SessionEvent>) {
+        spawn_local(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!(target: "service::slot_invalidation", "lagged, {n} session events lost");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Some(socket) = self.reply_socket.upgrade() else {
+                    break;
+                };
+
+                for (peer, packet) in self.invalidations(&event) {
+                    log::debug!(target: "service::slot_invalidation", "[{peer}] slot of {} invalidated", event.node_id);
+                    if let Err(e) = socket.send_to(&packet.encode_to_vec(), peer).await {
+                        log::warn!("[{peer}] failed to send slot invalidation: {e:?}");
+                    }
+                }
+            }
+            log::debug!(target: "service::slot_invalidation", "stopped");
+        });
+    }
+
+    /// `Disconnected` messages for sessions of Nodes, which forwarded to the Node of
+    /// a removed session. Nothing is sent while the Node still has another session,
+    /// e.g. after reconnecting from a new address.
+    fn invalidations(&self, event: &SessionEvent) -> Vec<(SocketAddr, Packet)> {
+        if !matches!(
+            event.kind,
+            SessionEventKind::Removed | SessionEventKind::Purged
+        ) {
+            return Vec::new();
+        }
+        let connected = self
+            .session_manager
+            .node_sessions(event.node_id)
+            .iter()
+            .any(|session| {
+                session.session_id != event.session_id
+                    && self.session_manager.session(&session.session_id).is_some()
+            });
+        if connected {
+            return Vec::new();
+        }
+
+        let senders = self.traffic.senders(event.node_id, INVALIDATION_WINDOW);
+        if senders.is_empty() {
+            return Vec::new();
+        }
+
+        let slot = self.slot_manager.slot(event.node_id);
+        senders
+            .into_iter()
+            .filter_map(|sender| self.session_manager.node_session(sender))
+            .map(|session| {
+                let packet = Packet::control(
+                    session.session_id.to_vec(),
+                    control::Disconnected {
+                        by: Some(control::disconnected::By::Slot(slot)),
+                    },
+                );
+                (session.peer, packet)
+            })
+            .collect()
+    }
+}
@@ -1,24 +1,30 @@
+use crate::server::nat_check;
 use crate::state::slot_manager::SlotManager;
 use crate::state::TsDecoder;
 use crate::{Session, SessionManager};
+use ya_relay_proto::proto::response::node::Hints;
 use ya_relay_proto::proto::response::Node as NodeInfo;
 
 pub struct Decoder<'a, 'b> {
     _session_manager: &'a SessionManager,
     slot_manager: &'b SlotManager,
     ts_decoder: TsDecoder,
+    /// Counted once for all Nodes in the response.
+    relay_sessions: u32,
 }
 
 pub fn decoder<'a, 'b>(
-    _session_manager: &'a SessionManager,
+    session_manager: &'a SessionManager,
     slot_manager: &'b SlotManager,
 ) -> Decoder<'a, 'b> {
     let ts_decoder = TsDecoder::new();
+    let relay_sessions = session_manager.num_sessions() as u32;
 
     Decoder {
-        _session_manager,
+        _session_manager: session_manager,
         slot_manager,
         ts_decoder,
+        relay_sessions,
     }
 }
 
@@ -32,6 +38,12 @@ impl<'a, 'b> Decoder<'a, 'b> {
             seen_ts: self.ts_decoder.decode(&session.ts),
             slot: self.slot_manager.slot(session.node_id),
             supported_encryptions: session.supported_encryptions.clone(),
+            properties: session.properties.lock().as_ref().map(|p| p.signed.clone()),
+            slot_epoch: self.slot_manager.epoch(),
+            hints: Some(Hints {
+                nat_type: nat_check::nat_type(session).into(),
+                relay_sessions: self.relay_sessions,
+            }),
         }
     }
 }
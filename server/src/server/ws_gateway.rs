@@ -0,0 +1,101 @@
+//! Relay connections over WebSocket.
+//!
+//! Peers which can't send UDP datagrams, e.g. clients running in browsers, connect to
+//! `--ws-listen-addr` instead. Each binary message carries a single datagram. Every
+//! connection gets its own UDP socket relaying datagrams to the server socket, so the
+//! server handles such a peer like any other Node behind NAT.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use ya_relay_proto::codec::MAX_PACKET_SIZE;
+
+pub struct WsGateway {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl WsGateway {
+    /// Accepts WebSocket connections at `listen_addr` and relays them to the server at `relay_addr`.
+    pub async fn start(listen_addr: SocketAddr, relay_addr: SocketAddr) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(listen_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let relay_addr = reachable(relay_addr);
+        log::info!("Accepting WebSocket connections on {local_addr}");
+
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        tokio::spawn(async move {
+                            if let Err(e) = relay(stream, peer, relay_addr).await {
+                                log::debug!("[{peer}] WebSocket connection closed: {e}");
+                            }
+                        });
+                    }
+                    Err(e) => log::warn!("Failed to accept WebSocket connection: {e}"),
+                }
+            }
+        });
+
+        Ok(WsGateway { local_addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for WsGateway {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn relay(stream: TcpStream, peer: SocketAddr, relay_addr: SocketAddr) -> anyhow::Result<()> {
+    let ws = tokio_tungstenite::accept_async(stream).await?;
+    let bind_addr: SocketAddr = match relay_addr.ip() {
+        IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    // Connected, so datagrams from other addresses don't reach the peer. It can't
+    // tell them apart from the ones sent by the relay.
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(relay_addr).await?;
+    log::debug!(
+        "[{peer}] WebSocket connection relayed from {}",
+        socket.local_addr()?
+    );
+
+    let (mut sink, mut stream) = ws.split();
+    let mut buf = vec![0u8; MAX_PACKET_SIZE as usize];
+    loop {
+        tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Binary(data))) => {
+                    socket.send(&data).await?;
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e.into()),
+            },
+            result = socket.recv(&mut buf) => {
+                let size = result?;
+                sink.send(Message::Binary(buf[..size].to_vec())).await?;
+            }
+        }
+    }
+}
+
+fn reachable(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    addr
+}
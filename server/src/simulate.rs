@@ -0,0 +1,215 @@
+//! Synthetic sessions and traffic, for developing dashboards and admin tooling.
+//!
+//! With `--simulate N` the server starts with `N` sessions of made up Nodes. Each of them
+//! keeps forwarding traffic to a few peers, as a bulk transfer, occasional messages or not
+//! at all, and reconnects from time to time. They show up in metrics, the HTTP interface
+//! and the events stream like Nodes of a live fleet. Their addresses are taken from the
+//! documentation ranges (RFC 5737), so address checks of these Nodes reach nobody.
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use ya_relay_core::crypto::ed25519;
+use ya_relay_core::identity::Identity;
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+
+use crate::state::traffic::TrafficMatrix;
+use crate::state::Clock;
+use crate::{AddrStatus, SessionManager, SessionRef};
+
+/// Interval of generating traffic and reconnecting Nodes.
+const TICK: Duration = Duration::from_secs(1);
+/// Nodes each Node forwards to.
+const PEERS: usize = 4;
+/// Chance of a Node reconnecting within a tick.
+const CHURN: f64 = 0.005;
+/// Fraction of Nodes with a public address.
+const PUBLIC: f64 = 0.7;
+const MAX_PACKET: usize = 1400;
+/// TEST-NET-1, TEST-NET-2 and TEST-NET-3.
+const NETWORKS: [[u8; 3]; 3] = [[192, 0, 2], [198, 51, 100], [203, 0, 113]];
+
+#[derive(Clone, Copy, Debug)]
+enum Pattern {
+    /// Steady stream of full packets, e.g. a file transfer.
+    Bulk,
+    /// Occasional small packets, e.g. control messages.
+    Interactive,
+    /// Connected, but not forwarding anything.
+    Idle,
+}
+
+impl Pattern {
+    fn random(rng: &mut impl Rng) -> Self {
+        match rng.gen_range(0..10) {
+            0 => Pattern::Bulk,
+            1..=6 => Pattern::Interactive,
+            _ => Pattern::Idle,
+        }
+    }
+
+    /// Sizes of packets sent to a single peer within a tick.
+    fn packets(self, rng: &mut impl Rng) -> Vec<usize> {
+        match self {
+            Pattern::Bulk => vec![MAX_PACKET; rng.gen_range(16..64)],
+            Pattern::Interactive if rng.gen_bool(0.3) => (0..rng.gen_range(1..4))
+                .map(|_| rng.gen_range(64..MAX_PACKET))
+                .collect(),
+            Pattern::Interactive | Pattern::Idle => Vec::new(),
+        }
+    }
+}
+
+struct Node {
+    session: SessionRef,
+    pattern: Pattern,
+    peers: Vec<NodeId>,
+}
+
+pub struct Simulation {
+    session_manager: Arc<SessionManager>,
+    traffic: Arc<TrafficMatrix>,
+    nodes: Vec<Node>,
+    rng: StdRng,
+}
+
+impl Simulation {
+    /// Creates sessions of `count` synthetic Nodes.
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        traffic: &Arc<TrafficMatrix>,
+        count: usize,
+    ) -> Self {
+        Self::with_rng(session_manager, traffic, count, StdRng::from_entropy())
+    }
+
+    fn with_rng(
+        session_manager: &Arc<SessionManager>,
+        traffic: &Arc<TrafficMatrix>,
+        count: usize,
+        mut rng: StdRng,
+    ) -> Self {
+        let node_ids: Vec<NodeId> = (0..count).map(|_| rng.gen::<[u8; 20]>().into()).collect();
+        let nodes = node_ids
+            .iter()
+            .map(|&node_id| {
+                let peers = node_ids
+                    .choose_multiple(&mut rng, PEERS + 1)
+                    .copied()
+                    .filter(|peer| *peer != node_id)
+                    .take(PEERS)
+                    .collect();
+                Node {
+                    session: connect(session_manager, node_id, &mut rng),
+                    pattern: Pattern::random(&mut rng),
+                    peers,
+                }
+            })
+            .collect();
+
+        Simulation {
+            session_manager: session_manager.clone(),
+            traffic: traffic.clone(),
+            nodes,
+            rng,
+        }
+    }
+
+    /// Keeps generating traffic until the returned task is aborted.
+    pub fn start(mut self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TICK);
+            loop {
+                interval.tick().await;
+                self.tick();
+            }
+        })
+    }
+
+    fn tick(&mut self) {
+        let Self {
+            session_manager,
+            traffic,
+            nodes,
+            rng,
+        } = self;
+
+        for node in nodes.iter_mut() {
+            let node_id = node.session.node_id;
+            if rng.gen_bool(CHURN) {
+                // New session, as after a change of the network.
+                session_manager.remove_session(&node.session.session_id);
+                node.session = connect(session_manager, node_id, rng);
+                continue;
+            }
+            node.session.ts.touch();
+            for peer in &node.peers {
+                for size in node.pattern.packets(rng) {
+                    traffic.record(node_id, *peer, size);
+                }
+            }
+        }
+    }
+}
+
+fn connect(session_manager: &SessionManager, node_id: NodeId, rng: &mut impl Rng) -> SessionRef {
+    let clock = Clock::now();
+    let [a, b, c] = NETWORKS[rng.gen_range(0..NETWORKS.len())];
+    let peer = SocketAddr::from((
+        [a, b, c, rng.gen_range(1..255)],
+        rng.gen_range(1024..u16::MAX),
+    ));
+    let keys = vec![Identity {
+        node_id,
+        public_key: ed25519::generate().verifying_key().into(),
+    }];
+    let session = session_manager
+        .new_session(
+            &clock,
+            SessionId::generate(),
+            peer,
+            node_id,
+            keys,
+            vec![],
+            Some(rng.gen()),
+        )
+        .unwrap_or_else(|existing| existing);
+    session_manager.link_sessions(&session);
+    *session.addr_status.lock() = match rng.gen_bool(PUBLIC) {
+        true => AddrStatus::Valid(clock.time()),
+        false => AddrStatus::Invalid(clock.time()),
+    };
+    session
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Selector;
+
+    #[test]
+    fn test_simulation() {
+        let session_manager = SessionManager::new();
+        let traffic = TrafficMatrix::new();
+        let mut simulation =
+            Simulation::with_rng(&session_manager, &traffic, 20, StdRng::seed_from_u64(1));
+        assert_eq!(session_manager.num_sessions(), 20);
+        assert_eq!(session_manager.nodes_for(Selector::All, 100).len(), 20);
+        assert!(simulation
+            .nodes
+            .iter()
+            .all(|node| node.peers.len() == PEERS));
+
+        for _ in 0..100 {
+            simulation.tick();
+        }
+        // Reconnected Nodes replace their sessions.
+        assert_eq!(session_manager.num_sessions(), 20);
+        assert!(traffic.num_pairs() > 0);
+    }
+}
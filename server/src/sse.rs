@@ -0,0 +1,425 @@
+//! Server-sent events stream of session events and maintenance notices, e.g. for dashboards.
+//!
+//! Each event gets an increasing id. Clients reconnecting with `Last-Event-ID`
+//! receive the events they missed, as long as they are still in the replay buffer.
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::{MaintenanceKind, MaintenanceNotice, SessionEvent, SessionEventKind};
+
+#[derive(clap::Args, Clone, Debug)]
+#[command(next_help_heading = "Events stream options")]
+pub struct SseConfig {
+    /// Recent events kept for clients resuming the stream with `Last-Event-ID`
+    #[arg(long, env, default_value = "1024")]
+    pub sse_replay_capacity: usize,
+    /// Events queued for a single client, which doesn't read them
+    #[arg(long, env, default_value = "256")]
+    pub sse_client_queue: usize,
+    /// What happens to a client with a full queue
+    #[arg(long, env, value_enum, default_value = "disconnect")]
+    pub sse_slow_client: SlowClientPolicy,
+    /// Events in a row a client can miss with the `skip` policy, before it's disconnected
+    #[arg(long, env, default_value = "1024")]
+    pub sse_max_lag: u64,
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        SseConfig {
+            sse_replay_capacity: 1024,
+            sse_client_queue: 256,
+            sse_slow_client: SlowClientPolicy::Disconnect,
+            sse_max_lag: 1024,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Client is disconnected, so it can resume from the replay buffer.
+    Disconnect,
+    /// Events not fitting in the queue are skipped, until the client misses
+    /// more than `sse_max_lag` of them in a row.
+    Skip,
+}
+
+pub type EventId = u64;
+
+mod metric {
+    use metrics::{recorder, Counter, Gauge, Key};
+
+    static CLIENTS: Key = Key::from_static_name("ya-relay.sse.clients");
+    static EVENTS: Key = Key::from_static_name("ya-relay.sse.events");
+    static DROPPED: Key = Key::from_static_name("ya-relay.sse.dropped");
+    static EVICTED: Key = Key::from_static_name("ya-relay.sse.evicted");
+
+    pub struct SseMetrics {
+        pub clients: Gauge,
+        pub events: Counter,
+        /// Events skipped for clients with a full queue.
+        pub dropped: Counter,
+        /// Clients disconnected for not keeping up.
+        pub evicted: Counter,
+    }
+
+    impl Default for SseMetrics {
+        fn default() -> Self {
+            let r = recorder();
+            Self {
+                clients: r.register_gauge(&CLIENTS),
+                events: r.register_counter(&EVENTS),
+                dropped: r.register_counter(&DROPPED),
+                evicted: r.register_counter(&EVICTED),
+            }
+        }
+    }
+}
+
+struct Client {
+    tx: mpsc::Sender<Bytes>,
+    /// Events missed in a row.
+    lag: u64,
+}
+
+struct Inner {
+    next_id: EventId,
+    replay: VecDeque<(EventId, Bytes)>,
+    clients: Vec<Client>,
+}
+
+pub struct SseClients {
+    config: SseConfig,
+    inner: Mutex<Inner>,
+    metrics: metric::SseMetrics,
+}
+
+impl SseClients {
+    pub fn new(config: SseConfig) -> Arc<Self> {
+        Arc::new(SseClients {
+            inner: Mutex::new(Inner {
+                next_id: 1,
+                replay: VecDeque::with_capacity(config.sse_replay_capacity),
+                clients: Vec::new(),
+            }),
+            config,
+            metrics: Default::default(),
+        })
+    }
+
+    /// Publishes events until the sender is dropped.
+    pub fn start(self: &Arc<Self>, events: broadcast::Receiver<SessionEvent>) -> JoinHandle<()> {
+        let this = self.clone();
+        spawn_publisher(events, move |event| {
+            this.publish(&event);
+        })
+    }
+
+    /// Publishes maintenance notices until the sender is dropped.
+    pub fn start_notices(
+        self: &Arc<Self>,
+        notices: broadcast::Receiver<MaintenanceNotice>,
+    ) -> JoinHandle<()> {
+        let this = self.clone();
+        spawn_publisher(notices, move |notice| {
+            this.publish_notice(&notice);
+        })
+    }
+
+    pub fn publish(&self, event: &SessionEvent) -> EventId {
+        self.push(|id| encode(id, event))
+    }
+
+    pub fn publish_notice(&self, notice: &MaintenanceNotice) -> EventId {
+        self.push(|id| encode_notice(id, notice))
+    }
+
+    fn push(&self, encode: impl FnOnce(EventId) -> Bytes) -> EventId {
+        let mut inner = self.inner.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+
+        let message = encode(id);
+        if self.config.sse_replay_capacity > 0 {
+            if inner.replay.len() == self.config.sse_replay_capacity {
+                inner.replay.pop_front();
+            }
+            inner.replay.push_back((id, message.clone()));
+        }
+
+        // Never waits for clients, so a stalled one doesn't hold back the others.
+        let max_lag = match self.config.sse_slow_client {
+            SlowClientPolicy::Disconnect => 0,
+            SlowClientPolicy::Skip => self.config.sse_max_lag,
+        };
+        let (mut dropped, mut evicted) = (0, 0);
+        inner
+            .clients
+            .retain_mut(|client| match client.tx.try_send(message.clone()) {
+                Ok(()) => {
+                    client.lag = 0;
+                    true
+                }
+                Err(e) if e.is_full() => {
+                    client.lag += 1;
+                    if client.lag > max_lag {
+                        log::debug!(
+                            "disconnecting events stream client {} events behind",
+                            client.lag
+                        );
+                        evicted += 1;
+                        false
+                    } else {
+                        dropped += 1;
+                        true
+                    }
+                }
+                Err(_) => false,
+            });
+        self.metrics.events.increment(1);
+        self.metrics.dropped.increment(dropped);
+        self.metrics.evicted.increment(evicted);
+        self.metrics.clients.set(inner.clients.len() as f64);
+        id
+    }
+
+    /// Stream of encoded events. Resumes after `last_event_id`, if given.
+    pub fn connect(&self, last_event_id: Option<EventId>) -> mpsc::Receiver<Bytes> {
+        let mut inner = self.inner.lock();
+        let replay: Vec<Bytes> = match last_event_id {
+            Some(last_id) => {
+                let oldest = inner
+                    .replay
+                    .front()
+                    .map(|(id, _)| *id)
+                    .unwrap_or(inner.next_id);
+                let lost = oldest.saturating_sub(last_id.saturating_add(1));
+                lost_message(lost)
+                    .into_iter()
+                    .chain(
+                        inner
+                            .replay
+                            .iter()
+                            .filter(|(id, _)| *id > last_id)
+                            .map(|(_, message)| message.clone()),
+                    )
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        let (mut tx, rx) = mpsc::channel(self.config.sse_client_queue + replay.len());
+        for message in replay {
+            // Channel fits all of them.
+            let _ = tx.try_send(message);
+        }
+        inner.clients.push(Client { tx, lag: 0 });
+        self.metrics.clients.set(inner.clients.len() as f64);
+        rx
+    }
+
+    pub fn num_clients(&self) -> usize {
+        self.inner.lock().clients.len()
+    }
+}
+
+fn spawn_publisher<T: Clone + Send + 'static>(
+    mut rx: broadcast::Receiver<T>,
+    publish: impl Fn(T) + Send + 'static,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(item) => publish(item),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log::warn!("events stream lagged, {n} events lost");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+fn kind_name(kind: SessionEventKind) -> &'static str {
+    match kind {
+        SessionEventKind::Created => "created",
+        SessionEventKind::Removed => "removed",
+        SessionEventKind::Purged => "purged",
+        SessionEventKind::Reachable => "reachable",
+    }
+}
+
+fn encode(id: EventId, event: &SessionEvent) -> Bytes {
+    format!(
+        "id: {id}\nevent: {}\ndata: {{\"sessionId\":\"{}\",\"nodeId\":\"{}\",\"peer\":\"{}\"}}\n\n",
+        kind_name(event.kind),
+        event.session_id,
+        event.node_id,
+        event.peer
+    )
+    .into()
+}
+
+fn encode_notice(id: EventId, notice: &MaintenanceNotice) -> Bytes {
+    #[derive(serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Data<'a> {
+        kind: &'static str,
+        scheduled_at: String,
+        alternative_relay: Option<&'a str>,
+        message: &'a str,
+    }
+
+    let data = Data {
+        kind: match notice.kind {
+            MaintenanceKind::Shutdown => "shutdown",
+            MaintenanceKind::Restart => "restart",
+        },
+        scheduled_at: notice.scheduled_at.to_rfc3339(),
+        alternative_relay: notice.alternative_relay.as_deref(),
+        message: &notice.message,
+    };
+    // Serializing strings doesn't fail.
+    let data = serde_json::to_string(&data).unwrap_or_default();
+    format!("id: {id}\nevent: maintenance\ndata: {data}\n\n").into()
+}
+
+/// Tells a resuming client, that events older than the replay buffer were lost.
+fn lost_message(lost: u64) -> Option<Bytes> {
+    (lost > 0).then(|| format!("event: lost\ndata: {{\"count\":{lost}}}\n\n").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_core::server_session::SessionId;
+
+    fn event() -> SessionEvent {
+        SessionEvent {
+            kind: SessionEventKind::Created,
+            session_id: SessionId::generate(),
+            node_id: rand::random::<[u8; 20]>().into(),
+            peer: "127.0.0.1:40".parse().unwrap(),
+        }
+    }
+
+    fn ids(rx: &mut mpsc::Receiver<Bytes>) -> Vec<String> {
+        let mut ids = Vec::new();
+        while let Ok(Some(message)) = rx.try_next() {
+            let message = String::from_utf8(message.to_vec()).unwrap();
+            ids.push(
+                message
+                    .lines()
+                    .next()
+                    .unwrap()
+                    .trim_start_matches("id: ")
+                    .to_string(),
+            );
+        }
+        ids
+    }
+
+    #[test]
+    fn test_resume() {
+        let sse = SseClients::new(SseConfig {
+            sse_replay_capacity: 3,
+            ..Default::default()
+        });
+        let mut rx = sse.connect(None);
+        for _ in 0..5 {
+            sse.publish(&event());
+        }
+        assert_eq!(ids(&mut rx), ["1", "2", "3", "4", "5"]);
+
+        let mut rx = sse.connect(Some(3));
+        assert_eq!(ids(&mut rx), ["4", "5"]);
+
+        let mut rx = sse.connect(Some(1));
+        assert_eq!(ids(&mut rx), ["event: lost", "3", "4", "5"]);
+
+        let mut rx = sse.connect(Some(5));
+        assert!(ids(&mut rx).is_empty());
+        sse.publish(&event());
+        assert_eq!(ids(&mut rx), ["6"]);
+    }
+
+    #[test]
+    fn test_maintenance_notice() {
+        let sse = SseClients::new(SseConfig::default());
+        let mut rx = sse.connect(None);
+        sse.publish(&event());
+        sse.publish_notice(&MaintenanceNotice {
+            kind: MaintenanceKind::Restart,
+            scheduled_at: "2024-01-02T03:04:05Z".parse().unwrap(),
+            alternative_relay: Some("udp://127.0.0.1:7477".to_string()),
+            message: "\"upgrade\"".to_string(),
+        });
+
+        rx.try_next().unwrap().unwrap();
+        let message = rx.try_next().unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(message.to_vec()).unwrap(),
+            "id: 2\nevent: maintenance\ndata: {\"kind\":\"restart\",\"scheduledAt\":\"2024-01-02T03:04:05+00:00\",\"alternativeRelay\":\"udp://127.0.0.1:7477\",\"message\":\"\\\"upgrade\\\"\"}\n\n"
+        );
+    }
+
+    #[test]
+    fn test_slow_client() {
+        let sse = SseClients::new(SseConfig {
+            sse_client_queue: 2,
+            ..Default::default()
+        });
+        let mut rx = sse.connect(None);
+        for _ in 0..10 {
+            sse.publish(&event());
+        }
+        assert_eq!(sse.num_clients(), 0);
+        // Remaining events are delivered before the end of the stream.
+        assert!(!ids(&mut rx).is_empty());
+        assert!(matches!(rx.try_next(), Ok(None)));
+
+        let sse = SseClients::new(SseConfig {
+            sse_client_queue: 2,
+            sse_slow_client: SlowClientPolicy::Skip,
+            ..Default::default()
+        });
+        let mut rx = sse.connect(None);
+        for _ in 0..10 {
+            sse.publish(&event());
+        }
+        assert_eq!(sse.num_clients(), 1);
+        assert_eq!(ids(&mut rx), ["1", "2", "3"]);
+        sse.publish(&event());
+        assert_eq!(ids(&mut rx), ["11"]);
+    }
+
+    #[test]
+    fn test_max_lag() {
+        let sse = SseClients::new(SseConfig {
+            sse_client_queue: 0,
+            sse_slow_client: SlowClientPolicy::Skip,
+            sse_max_lag: 2,
+            ..Default::default()
+        });
+        let mut rx = sse.connect(None);
+        // Single message fits, the next two are skipped.
+        for _ in 0..3 {
+            sse.publish(&event());
+        }
+        assert_eq!(sse.num_clients(), 1);
+        // Lag is reset once the client reads.
+        assert_eq!(ids(&mut rx), ["1"]);
+        for _ in 0..3 {
+            sse.publish(&event());
+        }
+        assert_eq!(sse.num_clients(), 1);
+        sse.publish(&event());
+        assert_eq!(sse.num_clients(), 0);
+    }
+}
@@ -1,11 +1,23 @@
 use ya_relay_core::NodeId;
 
+pub mod edge_directory;
+pub mod forward_limiter;
+pub mod group_manager;
+pub mod history;
+pub mod load;
+pub mod maintenance;
+pub mod replay_guard;
+pub mod response_cache;
 pub mod session_manager;
 pub mod slot_manager;
+pub mod traffic;
 
 mod last_seen;
 pub use last_seen::*;
 
+mod limits;
+pub use limits::Limits;
+
 pub fn hamming_distance(id1: NodeId, id2: NodeId) -> u32 {
     let id1 = id1.into_array();
     let id2 = id2.into_array();
@@ -20,9 +32,18 @@ pub fn hamming_distance(id1: NodeId, id2: NodeId) -> u32 {
     hamming
 }
 
+/// Kademlia distance. Compares as a big endian number, so closer ids sort first.
+pub fn xor_distance(id1: NodeId, id2: NodeId) -> [u8; 20] {
+    let mut distance = id1.into_array();
+    for (d, b) in distance.iter_mut().zip(id2.into_array()) {
+        *d ^= b;
+    }
+    distance
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::state::hamming_distance;
+    use crate::state::{hamming_distance, xor_distance};
     use std::str::FromStr;
     use ya_relay_core::NodeId;
 
@@ -40,4 +61,17 @@ mod tests {
         assert_eq!(hamming_distance(id1, id2), 8);
         assert_eq!(hamming_distance(id1, id3), 9);
     }
+
+    #[test]
+    fn test_xor_distance() {
+        let id1 = NodeId::from_str("0xe9ff07613f3a953627e4ce7b41e16a982ae8b471").unwrap();
+        let id2 = NodeId::from_str("0xe90007613f3a953627e4ce7b41e16a982ae8b471").unwrap();
+        let id3 = NodeId::from_str("0x690007613f3a953627e4ce7b41e16a982ae8b471").unwrap();
+
+        assert_eq!(xor_distance(id1, id1), [0u8; 20]);
+        assert_eq!(xor_distance(id1, id2), xor_distance(id2, id1));
+        assert_eq!(xor_distance(id1, id2)[1], 0xff);
+        // Difference in the highest bit outweighs all the lower ones.
+        assert!(xor_distance(id2, id1) < xor_distance(id2, id3));
+    }
 }
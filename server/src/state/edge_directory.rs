@@ -0,0 +1,101 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use ya_relay_core::NodeId;
+
+/// Nodes connected to edge relays, as reported in their summaries.
+///
+/// Edge relays report their Nodes periodically, so entries not reported again
+/// within `ttl` are dropped.
+pub struct EdgeDirectory {
+    ttl: Duration,
+    nodes: DashMap<NodeId, (SocketAddr, Instant)>,
+}
+
+impl EdgeDirectory {
+    pub fn new(ttl: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            ttl,
+            nodes: Default::default(),
+        })
+    }
+
+    /// Records Nodes connected to the edge relay reachable at `relay`.
+    pub fn update(&self, relay: SocketAddr, nodes: impl IntoIterator<Item = NodeId>, now: Instant) {
+        let expires = now + self.ttl;
+        for node_id in nodes {
+            self.nodes.insert(node_id, (relay, expires));
+        }
+    }
+
+    /// Address of the edge relay, which the Node is connected to.
+    pub fn locate(&self, node_id: NodeId, now: Instant) -> Option<SocketAddr> {
+        let (relay, expires) = *self.nodes.get(&node_id)?;
+        if expires <= now {
+            self.nodes
+                .remove_if(&node_id, |_, (_, expires)| *expires <= now);
+            return None;
+        }
+        Some(relay)
+    }
+
+    pub fn clean(&self, now: Instant) {
+        self.nodes.retain(|_, (_, expires)| *expires > now);
+    }
+
+    pub fn start_cleanup(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        let interval = self.ttl;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                match Weak::upgrade(&this) {
+                    Some(this) => this.clean(Instant::now()),
+                    None => break,
+                }
+            }
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate_expires() {
+        let directory = EdgeDirectory::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let relay1: SocketAddr = "127.0.0.1:7477".parse().unwrap();
+        let relay2: SocketAddr = "127.0.0.2:7477".parse().unwrap();
+        let node1 = NodeId::from([1u8; 20]);
+        let node2 = NodeId::from([2u8; 20]);
+
+        directory.update(relay1, [node1, node2], now);
+        assert_eq!(directory.locate(node1, now), Some(relay1));
+
+        // Node moved to another edge relay.
+        directory.update(relay2, [node1], now + Duration::from_secs(30));
+        assert_eq!(
+            directory.locate(node1, now + Duration::from_secs(70)),
+            Some(relay2)
+        );
+        assert_eq!(directory.locate(node2, now + Duration::from_secs(70)), None);
+        assert_eq!(directory.len(), 1);
+
+        directory.clean(now + Duration::from_secs(90));
+        assert!(directory.is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+//! Admission of forwards into per-session queues.
+//!
+//! Each worker admits forwards into its own queues, so the hot path takes only an
+//! uncontended lock. The limit applies to each worker separately. Packets from a peer
+//! address are dispatched to the same worker, so a session is usually throttled by a
+//! single one. The limiter keeps track of all of them, so operators can check the state
+//! of a session's queue and the totals, e.g. when a user reports throttling.
+//!
+//! Forwards with payloads above the configured size are refused before taking place
+//! in a queue, so the sender can be told about the limit.
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_proto::proto::control;
+
+/// Minimal interval between `Congestion` reports sent to a single session.
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Forwards from a session, which were received, but not sent yet.
+#[derive(Default)]
+struct Congestion {
+    pending: usize,
+    dropped: u32,
+    reported: Option<Instant>,
+}
+
+impl Congestion {
+    /// Report is sent while at least half of the queue is used, or forwards were dropped.
+    fn report(&mut self, max_pending: usize, now: Instant) -> Option<control::Congestion> {
+        if self.pending * 2 < max_pending && self.dropped == 0 {
+            return None;
+        }
+        if matches!(self.reported, Some(reported) if now - reported < REPORT_INTERVAL) {
+            return None;
+        }
+
+        self.reported = Some(now);
+        Some(control::Congestion {
+            queue_depth: self.pending as u32,
+            dropped: std::mem::take(&mut self.dropped),
+        })
+    }
+}
+
+type Queues = Mutex<HashMap<SessionId, Congestion>>;
+
+/// Queues of a session in all workers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueState {
+    /// Forwards waiting to be sent, summed over workers.
+    pub pending: usize,
+    /// Forwards, which can be admitted right now by the worker with the fullest queue.
+    pub available: usize,
+    /// Workers with forwards of the session waiting or dropped.
+    pub workers: usize,
+    /// Forwards dropped since the last `Congestion` report.
+    pub dropped: u32,
+    /// Time since the last `Congestion` report.
+    pub reported: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LimiterStats {
+    /// Forwards queued for a single session by each worker at most.
+    pub capacity: usize,
+    /// Largest payload accepted, no limit if `None`.
+    pub max_payload: Option<usize>,
+    pub admitted: u64,
+    pub dropped: u64,
+    /// Forwards refused for the payload size.
+    pub oversized: u64,
+    /// `Congestion` reports sent to the senders.
+    pub reports: u64,
+    /// Sessions with forwards waiting or dropped.
+    pub congested_sessions: usize,
+}
+
+pub struct ForwardLimiter {
+    max_pending: usize,
+    max_payload: Option<usize>,
+    workers: Mutex<Vec<Weak<Queues>>>,
+    admitted: AtomicU64,
+    dropped: AtomicU64,
+    oversized: AtomicU64,
+    reports: AtomicU64,
+}
+
+impl ForwardLimiter {
+    /// `max_payload` of `0` accepts payloads of any size.
+    pub fn new(max_pending: usize, max_payload: usize) -> Arc<Self> {
+        Arc::new(ForwardLimiter {
+            max_pending,
+            max_payload: (max_payload > 0).then_some(max_payload),
+            workers: Default::default(),
+            admitted: Default::default(),
+            dropped: Default::default(),
+            oversized: Default::default(),
+            reports: Default::default(),
+        })
+    }
+
+    /// Queues of a new worker.
+    pub fn worker(self: &Arc<Self>) -> WorkerQueues {
+        let queues = Arc::new(Queues::default());
+        let mut workers = self.workers.lock();
+        workers.retain(|queues| queues.strong_count() > 0);
+        workers.push(Arc::downgrade(&queues));
+        WorkerQueues {
+            limiter: self.clone(),
+            queues,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.max_pending
+    }
+
+    /// Returns the limit, if a payload of `size` bytes exceeds it.
+    pub fn check_size(&self, size: usize) -> Result<(), usize> {
+        match self.max_payload {
+            Some(max) if size > max => {
+                self.oversized.fetch_add(1, Ordering::Relaxed);
+                Err(max)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn queue(&self, session_id: &SessionId) -> QueueState {
+        let now = Instant::now();
+        let mut state = QueueState {
+            available: self.max_pending,
+            ..Default::default()
+        };
+        for queues in self.queues() {
+            if let Some(entry) = queues.lock().get(session_id) {
+                state.workers += 1;
+                state.pending += entry.pending;
+                state.available = state
+                    .available
+                    .min(self.max_pending.saturating_sub(entry.pending));
+                state.dropped += entry.dropped;
+                let reported = entry.reported.map(|reported| now - reported);
+                state.reported = match (state.reported, reported) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+            }
+        }
+        state
+    }
+
+    pub fn stats(&self) -> LimiterStats {
+        let congested: HashSet<SessionId> = self
+            .queues()
+            .iter()
+            .flat_map(|queues| queues.lock().keys().copied().collect::<Vec<_>>())
+            .collect();
+
+        LimiterStats {
+            capacity: self.max_pending,
+            max_payload: self.max_payload,
+            admitted: self.admitted.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            oversized: self.oversized.load(Ordering::Relaxed),
+            reports: self.reports.load(Ordering::Relaxed),
+            congested_sessions: congested.len(),
+        }
+    }
+
+    fn queues(&self) -> Vec<Arc<Queues>> {
+        self.workers
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect()
+    }
+}
+
+/// Queues of sessions handled by a single worker.
+#[derive(Clone)]
+pub struct WorkerQueues {
+    limiter: Arc<ForwardLimiter>,
+    queues: Arc<Queues>,
+}
+
+impl WorkerQueues {
+    pub fn limiter(&self) -> &Arc<ForwardLimiter> {
+        &self.limiter
+    }
+
+    /// Reserves place in the session queue for `count` forwards. Returns how many of
+    /// them can be sent, and a `Congestion` report for the sender if one is due.
+    pub fn admit(
+        &self,
+        session_id: SessionId,
+        count: usize,
+        now: Instant,
+    ) -> (usize, Option<control::Congestion>) {
+        let max_pending = self.limiter.max_pending;
+        let (admitted, report) = {
+            let mut queues = self.queues.lock();
+            let entry = queues.entry(session_id).or_default();
+            let admitted = count.min(max_pending.saturating_sub(entry.pending));
+            entry.pending += admitted;
+            entry.dropped += (count - admitted) as u32;
+            (admitted, entry.report(max_pending, now))
+        };
+
+        let limiter = &self.limiter;
+        limiter
+            .admitted
+            .fetch_add(admitted as u64, Ordering::Relaxed);
+        limiter
+            .dropped
+            .fetch_add((count - admitted) as u64, Ordering::Relaxed);
+        if report.is_some() {
+            limiter.reports.fetch_add(1, Ordering::Relaxed);
+        }
+        (admitted, report)
+    }
+
+    /// Frees place of an admitted forward, after it was sent.
+    pub fn release(&self, session_id: &SessionId) {
+        let mut queues = self.queues.lock();
+        if let Some(entry) = queues.get_mut(session_id) {
+            entry.pending -= 1;
+            if entry.pending == 0 && entry.dropped == 0 {
+                queues.remove(session_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_state() {
+        let limiter = ForwardLimiter::new(4, 0);
+        let worker1 = limiter.worker();
+        let worker2 = limiter.worker();
+        let session_id = SessionId::generate();
+        let now = Instant::now();
+
+        assert_eq!(worker1.admit(session_id, 1, now), (1, None));
+        let (admitted, report) = worker2.admit(session_id, 5, now);
+        assert_eq!(admitted, 4);
+        assert_eq!(report.unwrap().dropped, 1);
+
+        let state = limiter.queue(&session_id);
+        assert_eq!(state.pending, 5);
+        assert_eq!(state.available, 0);
+        assert_eq!(state.workers, 2);
+        assert!(state.reported.is_some());
+
+        let stats = limiter.stats();
+        assert_eq!(stats.admitted, 5);
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.reports, 1);
+        assert_eq!(stats.congested_sessions, 1);
+
+        // Queues of other sessions are separate.
+        let other = SessionId::generate();
+        assert_eq!(worker1.admit(other, 1, now), (1, None));
+        assert_eq!(limiter.queue(&other).available, 3);
+        assert_eq!(limiter.stats().congested_sessions, 2);
+        worker1.release(&other);
+
+        worker1.release(&session_id);
+        let state = limiter.queue(&session_id);
+        assert_eq!((state.pending, state.available, state.workers), (4, 0, 1));
+        assert_eq!(limiter.stats().congested_sessions, 1);
+
+        drop(worker2);
+        assert_eq!(
+            limiter.queue(&session_id),
+            QueueState {
+                available: 4,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_size() {
+        assert_eq!(ForwardLimiter::new(4, 0).check_size(usize::MAX), Ok(()));
+
+        let limiter = ForwardLimiter::new(4, 100);
+        assert_eq!(limiter.check_size(100), Ok(()));
+        assert_eq!(limiter.check_size(101), Err(100));
+        assert_eq!(limiter.stats().oversized, 1);
+        assert_eq!(limiter.stats().max_payload, Some(100));
+    }
+}
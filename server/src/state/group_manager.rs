@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use ya_relay_core::NodeId;
+
+/// Upper bound of the group name length in bytes.
+pub const MAX_GROUP_NAME_LEN: usize = 128;
+/// Groups a single Node can be a member of.
+pub const MAX_GROUPS_PER_NODE: usize = 64;
+
+/// Named groups, which Nodes join to receive packets sent to the group.
+///
+/// Membership is kept by Node id, so it survives re-establishing the session with
+/// the server. Members without a session are removed when a packet is sent to their group.
+#[derive(Default)]
+pub struct GroupManager {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    groups: HashMap<String, HashSet<NodeId>>,
+    memberships: HashMap<NodeId, usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    TooManyGroups,
+}
+
+impl GroupManager {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Default::default())
+    }
+
+    /// Returns number of group members after joining.
+    pub fn join(&self, group: &str, node_id: NodeId) -> Result<usize, JoinError> {
+        let mut inner = self.inner.write();
+        let Inner {
+            groups,
+            memberships,
+        } = &mut *inner;
+
+        if let Some(members) = groups.get(group) {
+            if members.contains(&node_id) {
+                return Ok(members.len());
+            }
+        }
+        let count = memberships.entry(node_id).or_default();
+        if *count >= MAX_GROUPS_PER_NODE {
+            return Err(JoinError::TooManyGroups);
+        }
+        *count += 1;
+
+        let members = groups.entry(group.to_string()).or_default();
+        members.insert(node_id);
+        Ok(members.len())
+    }
+
+    /// Returns `false` if the Node wasn't a member of the group.
+    pub fn leave(&self, group: &str, node_id: NodeId) -> bool {
+        let mut inner = self.inner.write();
+        let removed = match inner.groups.get_mut(group) {
+            Some(members) => {
+                let removed = members.remove(&node_id);
+                if members.is_empty() {
+                    inner.groups.remove(group);
+                }
+                removed
+            }
+            None => false,
+        };
+
+        if removed {
+            if let Some(count) = inner.memberships.get_mut(&node_id) {
+                *count -= 1;
+                if *count == 0 {
+                    inner.memberships.remove(&node_id);
+                }
+            }
+        }
+        removed
+    }
+
+    pub fn members(&self, group: &str) -> Vec<NodeId> {
+        self.inner
+            .read()
+            .groups
+            .get(group)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+
+    use super::*;
+
+    fn node_id() -> NodeId {
+        thread_rng().gen::<[u8; 20]>().into()
+    }
+
+    #[test]
+    fn test_join_leave() {
+        let m = GroupManager::new();
+        let (node1, node2) = (node_id(), node_id());
+
+        assert_eq!(m.join("topic", node1), Ok(1));
+        assert_eq!(m.join("topic", node1), Ok(1));
+        assert_eq!(m.join("topic", node2), Ok(2));
+        assert_eq!(m.members("topic").len(), 2);
+        assert!(m.members("other").is_empty());
+
+        assert!(m.leave("topic", node1));
+        assert!(!m.leave("topic", node1));
+        assert_eq!(m.members("topic"), vec![node2]);
+
+        assert!(m.leave("topic", node2));
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn test_groups_per_node() {
+        let m = GroupManager::new();
+        let node = node_id();
+
+        for i in 0..MAX_GROUPS_PER_NODE {
+            assert_eq!(m.join(&format!("group-{i}"), node), Ok(1));
+        }
+        assert_eq!(m.join("group-last", node), Err(JoinError::TooManyGroups));
+        // Re-joining doesn't count towards the limit.
+        assert_eq!(m.join("group-0", node), Ok(1));
+
+        assert!(m.leave("group-0", node));
+        assert_eq!(m.join("group-last", node), Ok(1));
+    }
+}
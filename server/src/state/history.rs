@@ -0,0 +1,283 @@
+//! Recent sessions of each Node, telling flapping clients apart from real outages.
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+
+use crate::{SessionEvent, SessionEventKind};
+
+mod metric {
+    use metrics::{recorder, Counter, Gauge, Histogram, Key};
+
+    static RECONNECTS: Key = Key::from_static_name("ya-relay.session.reconnect");
+    static LIFETIME: Key = Key::from_static_name("ya-relay.session.lifetime");
+    static NODES: Key = Key::from_static_name("ya-relay.session.history.nodes");
+
+    pub struct HistoryMetrics {
+        /// Sessions created shortly after the previous session of the Node ended.
+        pub reconnects: Counter,
+        /// Seconds from creating to removing a session.
+        pub lifetime: Histogram,
+        pub nodes: Gauge,
+    }
+
+    impl Default for HistoryMetrics {
+        fn default() -> Self {
+            let r = recorder();
+            Self {
+                reconnects: r.register_counter(&RECONNECTS),
+                lifetime: r.register_histogram(&LIFETIME),
+                nodes: r.register_gauge(&NODES),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub session_id: SessionId,
+    pub peer: SocketAddr,
+    pub connected: DateTime<Utc>,
+    pub disconnected: Option<DateTime<Utc>>,
+    /// `Removed` or `Purged`, once the session is gone.
+    pub reason: Option<SessionEventKind>,
+}
+
+struct NodeHistory {
+    entries: VecDeque<HistoryEntry>,
+    /// Order of the last update, for evicting Nodes not seen for the longest time.
+    updated: u64,
+}
+
+struct Inner {
+    nodes: HashMap<NodeId, NodeHistory>,
+    updates: u64,
+}
+
+/// Bounded store of session history. Keeps `max_entries` sessions of at most `max_nodes` Nodes.
+pub struct SessionHistory {
+    max_nodes: usize,
+    max_entries: usize,
+    reconnect_window: Duration,
+    inner: Mutex<Inner>,
+    metrics: metric::HistoryMetrics,
+}
+
+impl SessionHistory {
+    pub fn new(max_nodes: usize, max_entries: usize, reconnect_window: Duration) -> Arc<Self> {
+        Arc::new(SessionHistory {
+            max_nodes,
+            max_entries,
+            reconnect_window,
+            inner: Mutex::new(Inner {
+                nodes: Default::default(),
+                updates: 0,
+            }),
+            metrics: Default::default(),
+        })
+    }
+
+    /// Records events until the sender is dropped.
+    pub fn start(
+        self: &Arc<Self>,
+        mut events: broadcast::Receiver<SessionEvent>,
+    ) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => this.record(&event, Utc::now()),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("session history lagged, {n} events lost");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    pub fn record(&self, event: &SessionEvent, now: DateTime<Utc>) {
+        if self.max_nodes == 0 || self.max_entries == 0 {
+            return;
+        }
+        match event.kind {
+            SessionEventKind::Created => self.connected(event, now),
+            SessionEventKind::Removed | SessionEventKind::Purged => self.disconnected(event, now),
+            SessionEventKind::Reachable => {}
+        }
+    }
+
+    fn connected(&self, event: &SessionEvent, now: DateTime<Utc>) {
+        let mut inner = self.inner.lock();
+        if !inner.nodes.contains_key(&event.node_id) && inner.nodes.len() >= self.max_nodes {
+            inner.evict(self.max_nodes);
+        }
+        inner.updates += 1;
+        let updated = inner.updates;
+        let history = inner
+            .nodes
+            .entry(event.node_id)
+            .or_insert_with(|| NodeHistory {
+                entries: VecDeque::with_capacity(self.max_entries),
+                updated,
+            });
+        history.updated = updated;
+
+        let reconnected = history
+            .entries
+            .back()
+            .map_or(false, |last| match last.disconnected {
+                Some(disconnected) => (now - disconnected)
+                    .to_std()
+                    .map_or(true, |elapsed| elapsed <= self.reconnect_window),
+                // Previous session is still there, e.g. after the Node lost its session id.
+                None => true,
+            });
+        if reconnected {
+            self.metrics.reconnects.increment(1);
+        }
+
+        if history.entries.len() == self.max_entries {
+            history.entries.pop_front();
+        }
+        history.entries.push_back(HistoryEntry {
+            session_id: event.session_id,
+            peer: event.peer,
+            connected: now,
+            disconnected: None,
+            reason: None,
+        });
+        self.metrics.nodes.set(inner.nodes.len() as f64);
+    }
+
+    fn disconnected(&self, event: &SessionEvent, now: DateTime<Utc>) {
+        let mut inner = self.inner.lock();
+        let Some(history) = inner.nodes.get_mut(&event.node_id) else {
+            return;
+        };
+        let Some(entry) = history
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.session_id == event.session_id && entry.disconnected.is_none())
+        else {
+            return;
+        };
+        entry.disconnected = Some(now);
+        entry.reason = Some(event.kind);
+        if let Ok(lifetime) = (now - entry.connected).to_std() {
+            self.metrics.lifetime.record(lifetime.as_secs_f64());
+        }
+    }
+
+    /// Sessions of the Node, oldest first.
+    pub fn node(&self, node_id: NodeId) -> Vec<HistoryEntry> {
+        self.inner
+            .lock()
+            .nodes
+            .get(&node_id)
+            .map(|history| history.entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.inner.lock().nodes.len()
+    }
+}
+
+impl Inner {
+    /// Forgets the eighth of Nodes updated least recently.
+    fn evict(&mut self, max_nodes: usize) {
+        let mut updates: Vec<u64> = self.nodes.values().map(|history| history.updated).collect();
+        let n = (max_nodes / 8).clamp(1, updates.len());
+        let (_, &mut threshold, _) = updates.select_nth_unstable(n - 1);
+        self.nodes.retain(|_, history| history.updated > threshold);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: SessionEventKind, node: u8, session_id: SessionId) -> SessionEvent {
+        SessionEvent {
+            kind,
+            session_id,
+            node_id: [node; 20].into(),
+            peer: "127.0.0.1:40".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let history = SessionHistory::new(10, 2, Duration::from_secs(60));
+        let start = Utc::now();
+        let ids: Vec<SessionId> = (0..3).map(|_| SessionId::generate()).collect();
+
+        history.record(&event(SessionEventKind::Created, 1, ids[0]), start);
+        history.record(
+            &event(SessionEventKind::Removed, 1, ids[0]),
+            start + chrono::Duration::seconds(5),
+        );
+        history.record(
+            &event(SessionEventKind::Created, 1, ids[1]),
+            start + chrono::Duration::seconds(10),
+        );
+        history.record(
+            &event(SessionEventKind::Purged, 1, ids[1]),
+            start + chrono::Duration::seconds(20),
+        );
+
+        let entries = history.node([1; 20].into());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].session_id, ids[0]);
+        assert_eq!(entries[0].reason, Some(SessionEventKind::Removed));
+        assert_eq!(entries[1].reason, Some(SessionEventKind::Purged));
+        assert_eq!(
+            entries[1].disconnected,
+            Some(start + chrono::Duration::seconds(20))
+        );
+
+        // Oldest session is dropped.
+        history.record(&event(SessionEventKind::Created, 1, ids[2]), start);
+        let entries = history.node([1; 20].into());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].session_id, ids[1]);
+        assert_eq!(entries[1].disconnected, None);
+
+        assert!(history.node([2; 20].into()).is_empty());
+    }
+
+    #[test]
+    fn test_evict() {
+        let history = SessionHistory::new(8, 4, Duration::from_secs(60));
+        let now = Utc::now();
+        for node in 0..8 {
+            history.record(
+                &event(SessionEventKind::Created, node, SessionId::generate()),
+                now,
+            );
+        }
+        // Node 0 becomes the most recently updated.
+        history.record(
+            &event(SessionEventKind::Created, 0, SessionId::generate()),
+            now,
+        );
+        history.record(
+            &event(SessionEventKind::Created, 8, SessionId::generate()),
+            now,
+        );
+
+        assert_eq!(history.num_nodes(), 8);
+        assert_eq!(history.node([0; 20].into()).len(), 2);
+        assert!(history.node([1; 20].into()).is_empty());
+        assert_eq!(history.node([8; 20].into()).len(), 1);
+    }
+}
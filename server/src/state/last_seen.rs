@@ -28,6 +28,12 @@ impl Clock {
         Self { now, ts }
     }
 
+    /// Clock for a time given by an external time source.
+    pub fn at(now: Instant) -> Self {
+        let ts = now.saturating_duration_since(*SERVER_START).as_secs() as u32;
+        Self { now, ts }
+    }
+
     pub fn time(&self) -> Instant {
         self.now
     }
@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Limits which can be changed while the server is running.
+pub struct Limits {
+    challenge_difficulty: AtomicU64,
+    session_purge_timeout_ms: AtomicU64,
+}
+
+impl Limits {
+    pub fn new(challenge_difficulty: u64, session_purge_timeout: Duration) -> Arc<Self> {
+        Arc::new(Limits {
+            challenge_difficulty: AtomicU64::new(challenge_difficulty),
+            session_purge_timeout_ms: AtomicU64::new(session_purge_timeout.as_millis() as u64),
+        })
+    }
+
+    pub fn challenge_difficulty(&self) -> u64 {
+        self.challenge_difficulty.load(Ordering::Relaxed)
+    }
+
+    /// Applies to new handshakes. Nodes in the middle of a handshake need to start over.
+    pub fn set_challenge_difficulty(&self, difficulty: u64) {
+        self.challenge_difficulty
+            .store(difficulty, Ordering::Relaxed);
+    }
+
+    pub fn session_purge_timeout(&self) -> Duration {
+        Duration::from_millis(self.session_purge_timeout_ms.load(Ordering::Relaxed))
+    }
+
+    /// Takes effect on the next session cleaner run.
+    pub fn set_session_purge_timeout(&self, timeout: Duration) {
+        self.session_purge_timeout_ms
+            .store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+}
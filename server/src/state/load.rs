@@ -0,0 +1,76 @@
+//! Load of the relay, reported to Nodes in handshake and ping responses.
+//!
+//! Nodes configured with several relays weigh it together with the round-trip time,
+//! when picking a relay or moving to another one. Load is relative to the capacity set
+//! by operators, so relays of different sizes compare fairly.
+use std::sync::Arc;
+use std::time::Duration;
+
+use ya_relay_proto::proto;
+
+use crate::server::SessionHandlerConfig;
+use crate::state::traffic::TrafficMatrix;
+use crate::SessionManager;
+
+/// Forwarded bytes are averaged over this window.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+pub struct RelayLoad {
+    session_capacity: usize,
+    bandwidth_capacity: u64,
+    session_manager: Arc<SessionManager>,
+    traffic: Arc<TrafficMatrix>,
+}
+
+impl RelayLoad {
+    pub fn new(
+        config: &SessionHandlerConfig,
+        session_manager: &Arc<SessionManager>,
+        traffic: &Arc<TrafficMatrix>,
+    ) -> Arc<Self> {
+        Arc::new(RelayLoad {
+            session_capacity: config.session_capacity,
+            bandwidth_capacity: config.bandwidth_capacity,
+            session_manager: session_manager.clone(),
+            traffic: traffic.clone(),
+        })
+    }
+
+    /// `None`, if no capacity is configured.
+    pub fn report(&self) -> Option<proto::Load> {
+        if self.session_capacity == 0 && self.bandwidth_capacity == 0 {
+            return None;
+        }
+        let sessions = match self.session_capacity {
+            0 => 0,
+            capacity => permille(self.session_manager.num_sessions() as u64, capacity as u64),
+        };
+        let bandwidth = match self.bandwidth_capacity {
+            0 => 0,
+            capacity => permille(self.traffic.rate(RATE_WINDOW), capacity),
+        };
+        Some(proto::Load {
+            sessions,
+            bandwidth,
+        })
+    }
+}
+
+fn permille(value: u64, capacity: u64) -> u32 {
+    (value.saturating_mul(1000) / capacity)
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permille() {
+        assert_eq!(permille(0, 100), 0);
+        assert_eq!(permille(25, 100), 250);
+        assert_eq!(permille(150, 100), 1500);
+        assert_eq!(permille(u64::MAX, 1), u32::MAX);
+    }
+}
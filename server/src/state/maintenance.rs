@@ -0,0 +1,131 @@
+//! Planned shutdowns and restarts of the relay, announced ahead of time.
+//!
+//! Notices reach connected Nodes as `Maintenance` control packets and events stream
+//! subscribers as `maintenance` events, so both can move to another relay or wait
+//! for the restart, instead of finding out from expiring sessions.
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+
+use ya_relay_proto::proto::control;
+
+/// Notices waiting for slow subscribers. Announcements are rare.
+const CAPACITY: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaintenanceKind {
+    Shutdown,
+    Restart,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MaintenanceNotice {
+    pub kind: MaintenanceKind,
+    pub scheduled_at: DateTime<Utc>,
+    /// Relay, which Nodes can use in the meantime. Address as accepted by clients,
+    /// e.g. `udp://host:port`.
+    pub alternative_relay: Option<String>,
+    /// Free text for operators of the Nodes.
+    pub message: String,
+}
+
+impl From<&MaintenanceNotice> for control::Maintenance {
+    fn from(notice: &MaintenanceNotice) -> Self {
+        let kind = match notice.kind {
+            MaintenanceKind::Shutdown => control::maintenance::Kind::Shutdown,
+            MaintenanceKind::Restart => control::maintenance::Kind::Restart,
+        };
+        control::Maintenance {
+            kind: kind.into(),
+            scheduled_at_ms: notice.scheduled_at.timestamp_millis().max(0) as u64,
+            alternative_relay: notice.alternative_relay.clone().unwrap_or_default(),
+            message: notice.message.clone(),
+        }
+    }
+}
+
+/// Announced notice and its subscribers.
+pub struct Maintenance {
+    notice: Mutex<Option<MaintenanceNotice>>,
+    notices: broadcast::Sender<MaintenanceNotice>,
+}
+
+impl Maintenance {
+    pub fn new() -> Arc<Self> {
+        let (notices, _) = broadcast::channel(CAPACITY);
+        Arc::new(Maintenance {
+            notice: Default::default(),
+            notices,
+        })
+    }
+
+    /// Replaces the previous notice, if any.
+    pub fn announce(&self, notice: MaintenanceNotice) {
+        log::info!(
+            "[maintenance] {:?} scheduled at {}, alternative relay: {:?}",
+            notice.kind,
+            notice.scheduled_at,
+            notice.alternative_relay
+        );
+        *self.notice.lock() = Some(notice.clone());
+        // Fails only if there are no subscribers.
+        let _ = self.notices.send(notice);
+    }
+
+    /// Last announced notice.
+    pub fn notice(&self) -> Option<MaintenanceNotice> {
+        self.notice.lock().clone()
+    }
+
+    /// Notices announced from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<MaintenanceNotice> {
+        self.notices.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_control() {
+        let notice = MaintenanceNotice {
+            kind: MaintenanceKind::Restart,
+            scheduled_at: Utc.timestamp_millis_opt(1_700_000_000_123).unwrap(),
+            alternative_relay: Some("udp://127.0.0.1:7477".to_string()),
+            message: "upgrade".to_string(),
+        };
+        let control = control::Maintenance::from(&notice);
+        assert_eq!(control.kind(), control::maintenance::Kind::Restart);
+        assert_eq!(control.scheduled_at_ms, 1_700_000_000_123);
+        assert_eq!(control.alternative_relay, "udp://127.0.0.1:7477");
+
+        let notice = MaintenanceNotice {
+            alternative_relay: None,
+            ..notice
+        };
+        assert!(control::Maintenance::from(&notice)
+            .alternative_relay
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let maintenance = Maintenance::new();
+        let mut rx = maintenance.subscribe();
+        assert!(maintenance.notice().is_none());
+
+        let notice = MaintenanceNotice {
+            kind: MaintenanceKind::Shutdown,
+            scheduled_at: Utc::now(),
+            alternative_relay: None,
+            message: String::new(),
+        };
+        maintenance.announce(notice.clone());
+        assert_eq!(maintenance.notice(), Some(notice.clone()));
+        assert_eq!(rx.recv().await.unwrap(), notice);
+    }
+}
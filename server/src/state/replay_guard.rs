@@ -0,0 +1,96 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use ya_relay_core::server_session::SessionId;
+
+/// Session ids already used to establish a session.
+///
+/// Session ids are accepted only within the handshake window after they were issued,
+/// so each one has to be remembered only for that long.
+pub struct ReplayGuard {
+    window: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    used: HashSet<SessionId>,
+    // Ordered by expiration, because all entries live for the same window.
+    expiration: VecDeque<(Instant, SessionId)>,
+}
+
+impl ReplayGuard {
+    pub fn new(window: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            window,
+            inner: Default::default(),
+        })
+    }
+
+    /// Marks `session_id` as used. Returns `false` if it was used before.
+    pub fn consume(&self, session_id: SessionId, now: Instant) -> bool {
+        let mut inner = self.inner.lock();
+        let Inner { used, expiration } = &mut *inner;
+
+        while let Some((expires, _)) = expiration.front() {
+            if *expires > now {
+                break;
+            }
+            if let Some((_, expired)) = expiration.pop_front() {
+                used.remove(&expired);
+            }
+        }
+
+        if !used.insert(session_id) {
+            return false;
+        }
+        expiration.push_back((now + self.window, session_id));
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().used.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consume_once() {
+        let guard = ReplayGuard::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let id1 = SessionId::generate();
+        let id2 = SessionId::generate();
+
+        assert!(guard.consume(id1, now));
+        assert!(!guard.consume(id1, now + Duration::from_secs(1)));
+        assert!(guard.consume(id2, now + Duration::from_secs(1)));
+        assert_eq!(guard.len(), 2);
+    }
+
+    #[test]
+    fn test_forget_after_window() {
+        let guard = ReplayGuard::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let id1 = SessionId::generate();
+        let id2 = SessionId::generate();
+
+        assert!(guard.consume(id1, now));
+        assert!(guard.consume(id2, now + Duration::from_secs(30)));
+
+        let later = now + Duration::from_secs(61);
+        assert!(guard.consume(SessionId::generate(), later));
+        assert_eq!(guard.len(), 2);
+        assert!(!guard.consume(id2, later));
+        assert!(guard.consume(id1, later));
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_proto::proto::{packet, request, Packet};
+
+/// Identifies a request. Request ids alone aren't unique, e.g. a client restarted
+/// with its saved session may count them from the same value again, so the request
+/// body is part of the key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestKey {
+    src: SocketAddr,
+    session_id: SessionId,
+    request_id: u64,
+    digest: u64,
+}
+
+impl RequestKey {
+    pub fn new(
+        src: SocketAddr,
+        session_id: SessionId,
+        request_id: u64,
+        request: &request::Kind,
+    ) -> Self {
+        let mut body = Vec::with_capacity(request.encoded_len());
+        request.encode(&mut body);
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        RequestKey {
+            src,
+            session_id,
+            request_id,
+            digest: hasher.finish(),
+        }
+    }
+}
+
+/// Recent responses to control requests, answering retried requests again
+/// without handling them twice.
+///
+/// Requests are sent over UDP and retried by clients, when the response doesn't arrive
+/// in time. A retry of a request, which was already handled, gets the cached response,
+/// so e.g. slots aren't assigned twice. A retry of a request still being handled is dropped,
+/// since its response is on the way. Entries are keyed by the address of the sender as well,
+/// so responses never go to a different address than the request came from, see [`RequestKey`].
+pub struct ResponseCache {
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cached {
+    /// Response will be sent, when handling the request completes.
+    Pending,
+    Response(Packet),
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<RequestKey, Cached>,
+    // Ordered by expiration, because all entries live for the same time.
+    expiration: VecDeque<(Instant, RequestKey)>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            ttl,
+            capacity,
+            inner: Default::default(),
+        })
+    }
+
+    pub fn get(&self, key: &RequestKey, now: Instant) -> Option<Cached> {
+        let mut inner = self.inner.lock();
+        inner.expire(now);
+        inner.entries.get(key).cloned()
+    }
+
+    /// Marks the request as being handled asynchronously.
+    pub fn pending(&self, key: RequestKey, now: Instant) {
+        self.insert(key, Cached::Pending, now);
+    }
+
+    /// Caches the response to the request. Packets other than responses are ignored.
+    pub fn store(&self, key: RequestKey, response: &Packet, now: Instant) {
+        if let Some(packet::Kind::Response(_)) = &response.kind {
+            self.insert(key, Cached::Response(response.clone()), now);
+        }
+    }
+
+    fn insert(&self, key: RequestKey, cached: Cached, now: Instant) {
+        if self.capacity == 0 || self.ttl.is_zero() {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        inner.expire(now);
+        if inner.entries.insert(key, cached).is_none() {
+            inner.expiration.push_back((now + self.ttl, key));
+        }
+        while inner.entries.len() > self.capacity {
+            match inner.expiration.pop_front() {
+                Some((_, oldest)) => inner.entries.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Inner {
+    fn expire(&mut self, now: Instant) {
+        while let Some((expires, _)) = self.expiration.front() {
+            if *expires > now {
+                break;
+            }
+            if let Some((_, expired)) = self.expiration.pop_front() {
+                self.entries.remove(&expired);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_proto::proto::{response, StatusCode};
+
+    fn response(session_id: SessionId, request_id: u64) -> Packet {
+        Packet::response(
+            request_id,
+            session_id.to_vec(),
+            StatusCode::Ok,
+            response::Register::default(),
+        )
+    }
+
+    fn find_node(node_id: u8) -> request::Kind {
+        request::Kind::Node(request::Node {
+            node_id: vec![node_id; 20],
+            public_key: true,
+        })
+    }
+
+    #[test]
+    fn test_cached_response() {
+        let cache = ResponseCache::new(Duration::from_secs(10), 16);
+        let now = Instant::now();
+        let src: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:4321".parse().unwrap();
+        let session_id = SessionId::generate();
+        let key = |src, request_id| RequestKey::new(src, session_id, request_id, &find_node(1));
+
+        assert_eq!(cache.get(&key(src, 1), now), None);
+        cache.pending(key(src, 1), now);
+        assert_eq!(cache.get(&key(src, 1), now), Some(Cached::Pending));
+
+        cache.store(key(src, 1), &response(session_id, 1), now);
+        assert_eq!(
+            cache.get(&key(src, 1), now),
+            Some(Cached::Response(response(session_id, 1)))
+        );
+        assert_eq!(cache.get(&key(other, 1), now), None);
+        assert_eq!(cache.get(&key(src, 2), now), None);
+        assert_eq!(cache.len(), 1);
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(cache.get(&key(src, 1), later), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_same_request_id() {
+        let cache = ResponseCache::new(Duration::from_secs(10), 16);
+        let now = Instant::now();
+        let src: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let session_id = SessionId::generate();
+
+        let first = RequestKey::new(src, session_id, 1, &find_node(1));
+        cache.store(first, &response(session_id, 1), now);
+
+        // E.g. a restarted client, which continues the session, asks for another Node.
+        let second = RequestKey::new(src, session_id, 1, &find_node(2));
+        assert_eq!(cache.get(&second, now), None);
+        assert!(cache.get(&first, now).is_some());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let cache = ResponseCache::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        let src: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let session_id = SessionId::generate();
+        let key = |request_id| RequestKey::new(src, session_id, request_id, &find_node(1));
+
+        for request_id in 0..3 {
+            cache.store(key(request_id), &response(session_id, request_id), now);
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&key(0), now), None);
+        assert!(cache.get(&key(2), now).is_some());
+    }
+}
@@ -1,26 +1,31 @@
-use crate::state::hamming_distance;
+use crate::plugin::Plugins;
 use crate::state::last_seen::{Clock, LastSeen};
 use crate::state::session_manager::metrics::SessionManagerMetrics;
+use crate::state::Limits;
+use crate::state::{hamming_distance, xor_distance};
 use anyhow::{anyhow, Context};
 use dashmap::DashMap;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 use std::cmp::Reverse;
-use std::collections::hash_map::DefaultHasher;
-use std::collections::{BinaryHeap, HashMap};
-use std::hash::{Hash, Hasher};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::io::{BufRead, Write};
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
 use std::{cmp, fs, io, iter, thread};
-use tokio::time;
-use ya_relay_core::crypto::PublicKey;
-use ya_relay_core::identity::Identity;
+use tokio::sync::broadcast;
+use ya_relay_core::clock::{system_clock, ClockRef};
+use ya_relay_core::crypto::{ed25519, PublicKey};
+use ya_relay_core::forward_auth::ForwardKey;
+use ya_relay_core::identity::{Identity, IdentityKey};
+use ya_relay_core::properties::Properties;
 use ya_relay_core::server_session::SessionId;
 use ya_relay_core::NodeId;
+use ya_relay_proto::proto;
 use ya_relay_proto::proto::Endpoint;
 use ya_relay_proto::proto::Protocol::Udp;
 
@@ -31,6 +36,27 @@ pub struct SessionManagerConfig {
     pub session_cleaner_interval: Duration,
     #[arg(long, env, value_parser = humantime::parse_duration, default_value = "10min")]
     pub session_purge_timeout: Duration,
+    /// Interval of pruning references to removed sessions from the per-Node session lists
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "1min")]
+    pub session_compaction_interval: Duration,
+    /// Interval of re-checking addresses of Nodes, which weren't reachable directly. `0s` disables it.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "1min")]
+    pub addr_refresh_interval: Duration,
+    /// Pending and invalid address statuses are re-checked when older than this.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "5min")]
+    pub addr_status_max_age: Duration,
+    /// Nodes with session history kept. `0` disables the history
+    #[arg(long, env, default_value = "10000")]
+    pub session_history_nodes: usize,
+    /// Sessions kept in the history of a single Node
+    #[arg(long, env, default_value = "16")]
+    pub session_history_len: usize,
+    /// New session of a Node within this time after the previous one ended counts as a reconnect
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "1min")]
+    pub session_reconnect_window: Duration,
+    /// Time source for the session cleaner.
+    #[arg(skip = system_clock())]
+    pub clock: ClockRef,
 }
 
 mod metrics {
@@ -43,6 +69,9 @@ mod metrics {
     static REMOVED: Key = Key::from_static_name("ya_relay.session.removed");
 
     static PURGED: Key = Key::from_static_name("ya_relay.session.purged");
+    static MERGED: Key = Key::from_static_name("ya-relay.session.merged");
+    static TAKEN_OVER: Key = Key::from_static_name("ya-relay.session.taken-over");
+    static PRUNED_REFS: Key = Key::from_static_name("ya-relay.session.pruned-refs");
 
     static PROCESSING: Key = Key::from_static_name("ya-relay.session.cleaner.processing-time");
 
@@ -50,8 +79,11 @@ mod metrics {
         pub created: Counter,
         pub removed: Counter,
         pub purged: Counter,
+        pub merged: Counter,
+        pub taken_over: Counter,
         pub sessions: Gauge,
         pub nodes: Gauge,
+        pub pruned_refs: Gauge,
         pub processing: Histogram,
     }
 
@@ -61,22 +93,35 @@ mod metrics {
             let created = r.register_counter(&CREATED);
             let removed = r.register_counter(&REMOVED);
             let purged = r.register_counter(&PURGED);
+            let merged = r.register_counter(&MERGED);
+            let taken_over = r.register_counter(&TAKEN_OVER);
             let sessions = r.register_gauge(&SESSIONS);
             let nodes = r.register_gauge(&NODES);
+            let pruned_refs = r.register_gauge(&PRUNED_REFS);
             let processing = r.register_histogram(&PROCESSING);
 
             Self {
                 created,
                 removed,
                 purged,
+                merged,
+                taken_over,
                 sessions,
                 nodes,
+                pruned_refs,
                 processing,
             }
         }
     }
 }
 
+/// Number of events kept for subscribers falling behind.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Shortest compaction interval. The server binary refuses to start with 0s, but servers
+/// started from code skip that check, and the compaction task would never yield then.
+const MIN_COMPACTION_INTERVAL: Duration = Duration::from_secs(1);
+
 pub type SessionRef = Arc<Session>;
 
 pub type SessionWeakRef = Weak<Session>;
@@ -137,6 +182,21 @@ pub struct Session {
     pub keys: Vec<Identity>,
     pub supported_encryptions: Vec<String>,
     pub addr_status: Mutex<AddrStatus>,
+    /// Set at registration. Not persisted with the session state.
+    pub properties: Mutex<Option<NodeProperties>>,
+    /// Authenticates Forwards sent by the Node. Not set by older clients.
+    pub forward_key: Option<ForwardKey>,
+    /// Set when `NatCheck` found a separate NAT mapping for each destination.
+    /// Not persisted with the session state.
+    pub symmetric_nat: AtomicBool,
+}
+
+/// Properties published by the Node, already verified.
+#[derive(Clone, Debug)]
+pub struct NodeProperties {
+    /// As received, so other Nodes can check the signature themselves.
+    pub signed: proto::Properties,
+    pub values: Properties,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -145,21 +205,44 @@ struct PubKey {
     inner: [u8; 64],
 }
 
+/// Ed25519 keys are stored in the first 32 bytes, followed by zeros.
+/// Secp256k1 has no point with `y == 0`, so the encoding is unambiguous.
 impl<'a> From<&'a Identity> for PubKey {
     fn from(value: &'a Identity) -> Self {
-        let inner = value.public_key.bytes().clone();
+        let inner = match &value.public_key {
+            IdentityKey::Secp256k1(key) => *key.bytes(),
+            IdentityKey::Ed25519(key) => {
+                let mut inner = [0u8; 64];
+                inner[..ed25519::PUBLIC_KEY_SIZE].copy_from_slice(key.as_bytes());
+                inner
+            }
+        };
         Self { inner }
     }
 }
 
 impl PubKey {
     fn decode(&self) -> Identity {
-        let public_key = PublicKey::from_slice(&self.inner).unwrap();
-        let node_id = NodeId::from(*public_key.address());
-        Identity {
-            node_id,
-            public_key,
-        }
+        let (key, padding) = self.inner.split_at(ed25519::PUBLIC_KEY_SIZE);
+        let public_key = if padding.iter().all(|b| *b == 0) {
+            IdentityKey::Ed25519(ed25519::public_key_from_slice(key).unwrap())
+        } else {
+            IdentityKey::Secp256k1(PublicKey::from_slice(&self.inner).unwrap())
+        };
+        Identity::from(public_key)
+    }
+
+    fn from_forward_key(key: ForwardKey) -> Self {
+        let mut inner = [0u8; 64];
+        inner[..key.len()].copy_from_slice(&key);
+        Self { inner }
+    }
+
+    fn forward_key(&self) -> ForwardKey {
+        let mut key = ForwardKey::default();
+        let len = key.len();
+        key.copy_from_slice(&self.inner[..len]);
+        key
     }
 }
 
@@ -251,54 +334,143 @@ impl AddrStatus {
     }
 }
 
-type NodeSessionSet = Arc<Mutex<Vec<SessionWeakRef>>>;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionEventKind {
+    Created,
+    Removed,
+    /// Removed by the cleaner after `session_purge_timeout`.
+    Purged,
+    /// Address of the Node was found public after re-checking.
+    Reachable,
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionEvent {
+    pub kind: SessionEventKind,
+    pub session_id: SessionId,
+    pub node_id: NodeId,
+    pub peer: SocketAddr,
+}
+
+type NodeSessionSet = Arc<RwLock<Vec<SessionWeakRef>>>;
+
+/// Number of session table shards. Must be a power of two not greater than 256.
+const SESSION_SHARDS: usize = 64;
+
+type SessionShard = RwLock<HashMap<SessionId, SessionRef>>;
 
 pub struct SessionManager {
-    sessions: [Mutex<HashMap<SessionId, SessionRef>>; 16],
+    /// Sharded by the first byte of the MAC ending the `SessionId`, so lookups of
+    /// different sessions rarely wait for each other. Leading bytes are the time
+    /// the id was issued, shared by all sessions established in the same second.
+    sessions: [SessionShard; SESSION_SHARDS],
     node_sessions: DashMap<NodeId, NodeSessionSet>,
+    /// Sessions which looked up a Node without public address.
+    watchers: DashMap<NodeId, NodeSessionSet>,
+    /// Creation times of recent sessions by source IP, see [`SessionManager::recent_sessions`].
+    ip_sessions: DashMap<IpAddr, VecDeque<Instant>>,
+    /// The latest session established from each address, for finding duplicates.
+    peer_sessions: DashMap<SocketAddr, SessionWeakRef>,
     metrics: SessionManagerMetrics,
+    events: broadcast::Sender<SessionEvent>,
+    plugins: RwLock<Plugins>,
 }
 
 impl SessionManager {
     pub fn new() -> Arc<Self> {
-        let sessions: [Mutex<HashMap<SessionId, SessionRef>>; 16] = Default::default();
+        let sessions: [SessionShard; SESSION_SHARDS] = std::array::from_fn(|_| Default::default());
         let node_sessions = Default::default();
+        let watchers = Default::default();
+        let ip_sessions = Default::default();
+        let peer_sessions = Default::default();
         let metrics = Default::default();
-
-        assert_eq!(sessions.len(), 0x10);
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
 
         Arc::new(Self {
             sessions,
             node_sessions,
+            watchers,
+            ip_sessions,
+            peer_sessions,
             metrics,
+            events,
+            plugins: Default::default(),
         })
     }
 
     pub fn num_sessions(&self) -> usize {
-        self.sessions.iter().map(|s| s.lock().len()).sum()
+        self.sessions.iter().map(|s| s.read().len()).sum()
     }
 
-    pub fn nodes_for(
-        &self,
-        selector: Selector,
-        limit: usize,
-    ) -> HashMap<NodeId, Vec<SessionWeakRef>> {
+    /// Sessions of Nodes matching `selector`.
+    pub fn sessions(&self, selector: &Selector, limit: usize) -> Vec<SessionRef> {
+        self.sessions
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .values()
+                    .filter(|session| selector.match_prefix(session.node_id))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// Notifies about created and removed sessions, and Nodes which became reachable, from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Plugins notified about established and removed sessions.
+    pub fn set_plugins(&self, plugins: Plugins) {
+        *self.plugins.write() = plugins;
+    }
+
+    fn emit(&self, kind: SessionEventKind, session: &Session) {
+        match kind {
+            SessionEventKind::Created => self.plugins.read().session_established(session),
+            SessionEventKind::Removed | SessionEventKind::Purged => {
+                self.plugins.read().disconnect(session, kind)
+            }
+            SessionEventKind::Reachable => {}
+        }
+        // Fails only if there are no subscribers.
+        let _ = self.events.send(SessionEvent {
+            kind,
+            session_id: session.session_id,
+            node_id: session.node_id,
+            peer: session.peer,
+        });
+    }
+
+    /// Live sessions of Nodes matching `selector`. Nodes without any are skipped.
+    pub fn nodes_for(&self, selector: Selector, limit: usize) -> HashMap<NodeId, Vec<SessionRef>> {
         self.node_sessions
             .iter()
             .filter(|e| selector.match_prefix(*e.key()))
-            .map(|e| (*e.key(), e.value().lock().clone()))
+            .map(|e| {
+                let sessions: Vec<_> = e.value().read().iter().filter_map(Weak::upgrade).collect();
+                (*e.key(), sessions)
+            })
+            .filter(|(_, sessions)| !sessions.is_empty())
             .take(limit)
             .collect()
     }
 
+    /// Purges sessions not seen for `Limits::session_purge_timeout`.
     pub fn start_cleanup_processor(
         self: &Arc<Self>,
         &SessionManagerConfig {
             session_cleaner_interval,
-            session_purge_timeout,
+            ref clock,
             ..
         }: &SessionManagerConfig,
+        limits: &Arc<Limits>,
     ) {
+        let limits = limits.clone();
+        let time_source = clock.clone();
         let g_nodes = self.metrics.nodes.clone();
         let g_sessions = self.metrics.sessions.clone();
 
@@ -309,30 +481,40 @@ impl SessionManager {
             loop {
                 let start = Instant::now();
                 log::debug!("clean wait");
-                time::sleep(session_cleaner_interval).await;
+                time_source.sleep(session_cleaner_interval).await;
+                let session_purge_timeout = limits.session_purge_timeout();
                 log::debug!("clean start {:?}", session_purge_timeout);
-                let clock = Clock::now();
+                let clock = Clock::at(time_source.now());
                 let sm = match this.upgrade() {
                     Some(sm) => sm,
                     None => break,
                 };
-                //log::debug!("total = {}", sm.sessions.iter().map(|shard| shard.lock().len()).sum::<usize>());
+                //log::debug!("total = {}", sm.sessions.iter().map(|shard| shard.read().len()).sum::<usize>());
 
                 let mut total_clean = 0;
                 let mut total_size = 0;
                 for slot in &sm.sessions {
-                    let mut g = slot.lock();
+                    let mut g = slot.write();
                     let start_size = g.len();
+                    let mut purged = Vec::new();
                     g.retain(|_session_id, session_ref| {
                         let age = clock.age(&session_ref.ts);
 
-                        age <= session_purge_timeout
+                        let keep = age <= session_purge_timeout;
+                        if !keep {
+                            purged.push(session_ref.clone());
+                        }
+                        keep
                     });
                     //fence(Ordering::AcqRel);
                     let end_size = g.len();
                     total_size += end_size;
                     let removed = start_size - end_size;
                     drop(g);
+                    // Outside of the lock, so plugins can look up other sessions.
+                    for session_ref in purged {
+                        sm.emit(SessionEventKind::Purged, &session_ref);
+                    }
 
                     total_clean += removed;
                     if removed > 0 {
@@ -343,13 +525,48 @@ impl SessionManager {
                 }
                 log::debug!("clean end: {total_clean}/{}", total_size + total_clean);
                 g_sessions.set(total_size as f64);
-                sm.clean_node_sessions();
+                if let Some(expired) = clock.time().checked_sub(session_purge_timeout) {
+                    sm.clean_ip_sessions(expired);
+                }
                 g_nodes.set(sm.node_sessions.len() as f64);
                 sm.metrics.processing.record(start.elapsed());
             }
         });
     }
 
+    /// Prunes references to removed sessions every `session_compaction_interval`.
+    pub fn start_compaction(
+        self: &Arc<Self>,
+        &SessionManagerConfig {
+            session_compaction_interval,
+            ref clock,
+            ..
+        }: &SessionManagerConfig,
+    ) {
+        if session_compaction_interval < MIN_COMPACTION_INTERVAL {
+            log::warn!(
+                "session compaction interval {session_compaction_interval:?} too short, using {MIN_COMPACTION_INTERVAL:?}"
+            );
+        }
+        let interval = session_compaction_interval.max(MIN_COMPACTION_INTERVAL);
+        let time_source = clock.clone();
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                time_source.sleep(interval).await;
+                let sm = match this.upgrade() {
+                    Some(sm) => sm,
+                    None => break,
+                };
+                let pruned = sm.compact();
+                if pruned > 0 {
+                    log::debug!("session compaction: {pruned} references pruned");
+                }
+                sm.metrics.nodes.set(sm.node_sessions.len() as f64);
+            }
+        });
+    }
+
     pub fn neighbours(&self, base_node_id: NodeId, count: usize) -> Vec<SessionRef> {
         #[derive(PartialEq, Eq)]
         struct Distance {
@@ -386,17 +603,78 @@ impl SessionManager {
             .filter_map(|d| {
                 self.node_sessions
                     .get(&d.id)
-                    .and_then(|entry| entry.value().lock().iter().filter_map(Weak::upgrade).last())
+                    .and_then(|entry| entry.value().read().iter().filter_map(Weak::upgrade).last())
             })
             .skip(1)
             .take(count)
             .collect()
     }
 
+    /// Most recent sessions of the `count` Nodes closest to `target` by XOR distance,
+    /// closest first. Nodes known to be unreachable directly are left out, since
+    /// they can't take part in the gossip.
+    pub fn nearest(&self, target: NodeId, count: usize, except: Option<NodeId>) -> Vec<SessionRef> {
+        let mut h = self
+            .node_sessions
+            .iter()
+            .map(|entry| Reverse(xor_distance(target, *entry.key())))
+            .collect::<BinaryHeap<_>>();
+
+        // Distances are unique, and the id is the distance XOR the target.
+        iter::from_fn(|| h.pop())
+            .map(|Reverse(distance)| NodeId::from(xor_distance(target, distance.into())))
+            .filter(|id| Some(*id) != except)
+            .filter_map(|id| {
+                let session = self
+                    .node_sessions
+                    .get(&id)?
+                    .value()
+                    .read()
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .last()?;
+                // Secondary identities link the same session.
+                if session.node_id != id {
+                    return None;
+                }
+                let invalid = matches!(*session.addr_status.lock(), AddrStatus::Invalid(_));
+                (!invalid).then_some(session)
+            })
+            .take(count)
+            .collect()
+    }
+
+    /// Most recent sessions of Nodes, which published property `key` set to `value`.
+    pub fn find_nodes(&self, key: &str, value: &str, limit: usize) -> Vec<SessionRef> {
+        self.node_sessions
+            .iter()
+            .filter_map(|entry| {
+                let session = entry
+                    .value()
+                    .read()
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .last()?;
+                // Secondary identities link the same session.
+                if session.node_id != *entry.key() {
+                    return None;
+                }
+                let matches = session
+                    .properties
+                    .lock()
+                    .as_ref()
+                    .and_then(|p| p.values.get(key).map(|v| v == value))
+                    .unwrap_or(false);
+                matches.then_some(session)
+            })
+            .take(limit)
+            .collect()
+    }
+
     pub fn link_session(&self, node_id: NodeId, session: &SessionRef) {
         let session_w = Arc::downgrade(session);
         let entry = self.node_sessions.entry(node_id).or_default();
-        let mut g = entry.lock();
+        let mut g = entry.write();
         g.retain(|s| s.upgrade().is_some());
         g.push(session_w)
     }
@@ -405,7 +683,7 @@ impl SessionManager {
         let session_w = Arc::downgrade(session);
         for id in &session.keys {
             let entry = self.node_sessions.entry(id.node_id).or_default();
-            let mut g = entry.lock();
+            let mut g = entry.write();
             g.retain(|s| s.strong_count() > 0);
             if g.iter().all(|s| !Weak::ptr_eq(s, &session_w)) {
                 g.push(session_w.clone())
@@ -413,27 +691,139 @@ impl SessionManager {
         }
     }
 
+    /// All live sessions of the Node, the most recent last.
+    pub fn node_sessions(&self, node_id: NodeId) -> Vec<SessionRef> {
+        self.node_sessions
+            .get(&node_id)
+            .map(|refs| refs.read().iter().filter_map(Weak::upgrade).collect())
+            .unwrap_or_default()
+    }
+
+    /// Called for every forward. Takes only shared locks of the map shard and of the
+    /// Node's session list, so concurrent forwards, to the same Node as well, don't wait
+    /// for each other. The list is locked exclusively only to pop removed sessions.
     pub fn node_session(&self, node_id: NodeId) -> Option<SessionRef> {
-        if let Some(refs) = self.node_sessions.get_mut(&node_id) {
-            let mut g = refs.value().lock();
-            while let Some(session_wref) = g.last() {
-                if let Some(session_ref) = session_wref.upgrade() {
-                    return Some(session_ref);
-                }
-                g.pop();
+        let refs = self.node_sessions.get(&node_id)?;
+        if let Some(session_ref) = refs.value().read().last().and_then(Weak::upgrade) {
+            return Some(session_ref);
+        }
+        let mut g = refs.value().write();
+        while let Some(session_wref) = g.last() {
+            if let Some(session_ref) = session_wref.upgrade() {
+                return Some(session_ref);
             }
+            g.pop();
         }
         None
     }
 
-    fn clean_node_sessions(&self) {
-        self.node_sessions.retain(|&_node_id, sessions| {
-            let mut g = sessions.lock();
-            g.retain(|s| s.upgrade().is_some());
+    /// Prunes references to removed sessions from the per-Node session lists, watchers
+    /// and peer addresses, dropping Nodes left without sessions. Returns the number
+    /// of references pruned, which is reported as a gauge as well.
+    pub fn compact(&self) -> usize {
+        let mut pruned = 0;
+        let mut retain_live = |_node_id: &NodeId, sessions: &mut NodeSessionSet| {
+            let mut g = sessions.write();
+            let before = g.len();
+            g.retain(|s| s.strong_count() > 0);
+            pruned += before - g.len();
             !g.is_empty()
-        })
+        };
+        self.node_sessions.retain(&mut retain_live);
+        self.watchers.retain(&mut retain_live);
+        self.peer_sessions.retain(|_peer, session| {
+            let live = session.strong_count() > 0;
+            if !live {
+                pruned += 1;
+            }
+            live
+        });
+        self.metrics.pruned_refs.set(pruned as f64);
+        pruned
+    }
+
+    fn clean_ip_sessions(&self, before: Instant) {
+        self.ip_sessions.retain(|_ip, created| {
+            created.retain(|ts| *ts >= before);
+            !created.is_empty()
+        });
+    }
+
+    /// Number of sessions created from `ip` since the given time. IPv6 addresses are
+    /// counted by /64 prefix, since a single host usually gets the whole network.
+    /// Older sessions are forgotten, so `since` shouldn't go back over calls.
+    pub fn recent_sessions(&self, ip: IpAddr, since: Instant) -> usize {
+        let key = ip_session_key(ip);
+        let mut created = match self.ip_sessions.get_mut(&key) {
+            Some(created) => created,
+            None => return 0,
+        };
+        while matches!(created.front(), Some(ts) if *ts < since) {
+            created.pop_front();
+        }
+        let count = created.len();
+        if count == 0 {
+            drop(created);
+            self.ip_sessions
+                .remove_if(&key, |_, created| created.is_empty());
+        }
+        count
+    }
+
+    /// Remembers that `watcher` couldn't reach `node_id` directly, so it can be notified
+    /// when the Node's address turns out public.
+    pub fn watch_node(&self, node_id: NodeId, watcher: &SessionRef) {
+        let watcher_w = Arc::downgrade(watcher);
+        let entry = self.watchers.entry(node_id).or_default();
+        let mut g = entry.write();
+        g.retain(|s| s.strong_count() > 0);
+        if g.iter().all(|s| !Weak::ptr_eq(s, &watcher_w)) {
+            g.push(watcher_w)
+        }
+    }
+
+    /// Sessions with `Pending` or `Invalid` address status older than `max_age`.
+    /// They are marked as `Pending` again, so they are returned once until re-checked.
+    pub fn stale_addresses(&self, max_age: Duration) -> Vec<SessionRef> {
+        let now = Instant::now();
+        self.sessions(&Selector::All, usize::MAX)
+            .into_iter()
+            .filter(|session| {
+                let mut status = session.addr_status.lock();
+                let stale = match &*status {
+                    AddrStatus::Pending(ts) | AddrStatus::Invalid(ts) => {
+                        now.saturating_duration_since(*ts) > max_age
+                    }
+                    AddrStatus::Unknown | AddrStatus::Valid(_) => false,
+                };
+                if stale {
+                    *status = AddrStatus::Pending(now);
+                }
+                stale
+            })
+            .collect()
     }
 
+    /// Emits [`SessionEventKind::Reachable`] and returns sessions waiting for the Node,
+    /// which are forgotten afterwards.
+    pub fn node_reachable(&self, session: &Session) -> Vec<SessionRef> {
+        self.emit(SessionEventKind::Reachable, session);
+
+        let mut watchers: Vec<SessionRef> = Vec::new();
+        let node_ids = iter::once(session.node_id).chain(session.keys.iter().map(|id| id.node_id));
+        for node_id in node_ids {
+            if let Some((_, refs)) = self.watchers.remove(&node_id) {
+                for watcher in refs.read().iter().filter_map(Weak::upgrade) {
+                    if watchers.iter().all(|w| !Arc::ptr_eq(w, &watcher)) {
+                        watchers.push(watcher);
+                    }
+                }
+            }
+        }
+        watchers
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn new_session(
         &self,
         clock: &Clock,
@@ -442,6 +832,7 @@ impl SessionManager {
         node_id: NodeId,
         keys: Vec<Identity>,
         supported_encryptions: Vec<String>,
+        forward_key: Option<ForwardKey>,
     ) -> Result<SessionRef, SessionRef> {
         let addr_status = Mutex::new(AddrStatus::Unknown);
         let ts = clock.last_seen();
@@ -453,9 +844,12 @@ impl SessionManager {
             keys,
             supported_encryptions,
             addr_status,
+            properties: Default::default(),
+            forward_key,
+            symmetric_nat: Default::default(),
         });
 
-        let mut g = self.session_slot(&session_id).lock();
+        let mut g = self.session_slot(&session_id).write();
         let prev = g.insert(session_id, session_ref.clone());
         if let Some(prev) = prev {
             g.insert(session_id, prev.clone());
@@ -463,16 +857,148 @@ impl SessionManager {
             Err(prev)
         } else {
             drop(g);
+            self.ip_sessions
+                .entry(ip_session_key(peer.ip()))
+                .or_default()
+                .push_back(clock.time());
+            self.merge_duplicate(&session_ref);
             self.metrics.created.increment(1);
+            self.emit(SessionEventKind::Created, &session_ref);
             Ok(session_ref)
         }
     }
 
+    /// Closes sessions of the Node established from other addresses, after it
+    /// established `survivor`. Returns the closed sessions.
+    ///
+    /// The new session is created only after the Node signed the challenge with its key,
+    /// which proves its ownership of the NodeId. The survivor is linked to the Node right away,
+    /// so forwards to the Node's slots, which are assigned per NodeId, reach its new address.
+    /// Notifying Nodes forwarding to it is left to the caller.
+    pub fn take_over(&self, survivor: &SessionRef) -> Vec<SessionRef> {
+        let previous: Vec<SessionRef> = self
+            .node_sessions(survivor.node_id)
+            .into_iter()
+            .filter(|prev| {
+                prev.session_id != survivor.session_id
+                    && prev.node_id == survivor.node_id
+                    && prev.peer != survivor.peer
+            })
+            .collect();
+        if previous.is_empty() {
+            return previous;
+        }
+
+        self.link_sessions(survivor);
+        for prev in &previous {
+            {
+                let mut properties = survivor.properties.lock();
+                if properties.is_none() {
+                    *properties = prev.properties.lock().clone();
+                }
+            }
+            self.relink(prev, survivor);
+            log::info!(
+                "[{}] session {} of [{}] taken over from {} ({})",
+                survivor.peer,
+                survivor.session_id,
+                survivor.node_id,
+                prev.peer,
+                prev.session_id
+            );
+            self.metrics.taken_over.increment(1);
+            self.remove_session(&prev.session_id);
+        }
+        previous
+    }
+
+    /// Points links to `previous` at `survivor`, which replaces it.
+    fn relink(&self, previous: &SessionRef, survivor: &SessionRef) {
+        let survivor_w = Arc::downgrade(survivor);
+        let previous_w = Arc::downgrade(previous);
+        let relink = |sessions: &NodeSessionSet| {
+            let mut g = sessions.write();
+            let linked = g.iter().any(|s| Weak::ptr_eq(s, &survivor_w));
+            match g.iter().position(|s| Weak::ptr_eq(s, &previous_w)) {
+                Some(idx) if !linked => g[idx] = survivor_w.clone(),
+                Some(idx) => {
+                    g.remove(idx);
+                }
+                None => (),
+            }
+        };
+        for id in &previous.keys {
+            if let Some(sessions) = self.node_sessions.get(&id.node_id) {
+                relink(sessions.value());
+            }
+        }
+        for watchers in self.watchers.iter() {
+            relink(watchers.value());
+        }
+    }
+
+    /// Replaces the previous session of the same Node from the same address with `survivor`.
+    ///
+    /// Client retrying the handshake, after the response was lost, gets a new session, while
+    /// the server still holds the previous one. The client uses only the latest one, so the
+    /// previous one is removed, passing its registration and links to the survivor. Slots
+    /// are assigned per NodeId, so the survivor keeps using the same ones.
+    fn merge_duplicate(&self, survivor: &SessionRef) -> Option<SessionRef> {
+        let previous = self
+            .peer_sessions
+            .insert(survivor.peer, Arc::downgrade(survivor))
+            .and_then(|prev| prev.upgrade())
+            .filter(|prev| {
+                prev.session_id != survivor.session_id && prev.node_id == survivor.node_id
+            })?;
+        if self.session(&previous.session_id).is_none() {
+            return None;
+        }
+
+        {
+            let mut properties = survivor.properties.lock();
+            if properties.is_none() {
+                *properties = previous.properties.lock().take();
+            }
+        }
+        {
+            let mut status = survivor.addr_status.lock();
+            if matches!(*status, AddrStatus::Unknown) {
+                *status = std::mem::replace(&mut *previous.addr_status.lock(), AddrStatus::Unknown);
+            }
+        }
+        if previous.symmetric_nat.load(Ordering::Relaxed) {
+            survivor.symmetric_nat.store(true, Ordering::Relaxed);
+        }
+
+        self.relink(&previous, survivor);
+
+        log::info!(
+            "[{}] merged duplicate session {} of [{}] into {}",
+            previous.peer,
+            previous.session_id,
+            previous.node_id,
+            survivor.session_id
+        );
+        self.metrics.merged.increment(1);
+        self.remove_session(&previous.session_id)
+    }
+
     #[cfg(test)]
     fn add_dummy_session(&self) -> SessionRef {
-        let session_id = SessionId::generate();
         let ts = LastSeen::now();
         let peer = "127.0.0.1:40".parse().unwrap();
+        // Issued within the same second, like sessions established at once.
+        static NONCE: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let nonce = NONCE.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+        let session_id = crate::server::new_session_id(
+            &[0; 16],
+            0,
+            peer,
+            1_700_000_000,
+            0,
+            nonce[1..].try_into().unwrap(),
+        );
         let session_ref = Arc::new(Session {
             session_id,
             peer,
@@ -481,9 +1007,12 @@ impl SessionManager {
             keys: vec![],
             supported_encryptions: vec![],
             addr_status: Mutex::new(AddrStatus::Unknown),
+            properties: Default::default(),
+            forward_key: None,
+            symmetric_nat: Default::default(),
         });
         self.session_slot(&session_id)
-            .lock()
+            .write()
             .insert(session_id, session_ref.clone());
         session_ref
     }
@@ -501,16 +1030,19 @@ impl SessionManager {
             keys: Default::default(),
             supported_encryptions: Default::default(),
             addr_status: Mutex::new(AddrStatus::Unknown),
+            properties: Default::default(),
+            forward_key: None,
+            symmetric_nat: Default::default(),
         });
         self.session_slot(&session_id)
-            .lock()
+            .write()
             .insert(session_id, session_ref.clone());
         session_ref
     }
 
     pub fn session(&self, session_id: &SessionId) -> Option<SessionRef> {
         self.session_slot(session_id)
-            .lock()
+            .read()
             .get(session_id)
             .cloned()
     }
@@ -524,46 +1056,55 @@ impl SessionManager {
     }
 
     pub fn remove_session(&self, session: &SessionId) -> Option<SessionRef> {
-        let prev = self.session_slot(session).lock().remove(session);
-        if prev.is_some() {
+        let prev = self.session_slot(session).write().remove(session);
+        if let Some(prev) = &prev {
             self.metrics.removed.increment(1);
+            self.emit(SessionEventKind::Removed, prev);
         }
         prev
     }
 
-    fn session_slot(&self, session: &SessionId) -> &Mutex<HashMap<SessionId, SessionRef>> {
-        let mut s = DefaultHasher::new();
-        session.hash(&mut s);
-        let idx = (s.finish() & 0xf) as usize;
+    fn session_slot(&self, session: &SessionId) -> &SessionShard {
+        let idx = session.to_array()[8] as usize & (SESSION_SHARDS - 1);
         &self.sessions[idx]
     }
 
-    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
-        let mut f = io::BufWriter::new(
-            fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(path)?,
-        );
+    /// Sessions and Node links as of now, see [`SessionSnapshot`].
+    pub fn snapshot(&self) -> Arc<SessionSnapshot> {
+        let sessions: Vec<SessionRef> = {
+            // Writers lock a single shard at a time, so taking all of them in order is safe.
+            let shards: Vec<_> = self.sessions.iter().map(|shard| shard.read()).collect();
+            shards
+                .iter()
+                .flat_map(|shard| shard.values().cloned())
+                .collect()
+        };
+
+        let ids: HashSet<SessionId> = sessions.iter().map(|s| s.session_id).collect();
+        let nodes = self
+            .node_sessions
+            .iter()
+            .filter_map(|e| {
+                let linked: Vec<_> = e
+                    .value()
+                    .read()
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .filter(|session| ids.contains(&session.session_id))
+                    .collect();
+                (!linked.is_empty()).then_some((*e.key(), linked))
+            })
+            .collect();
 
-        for shard in &self.sessions {
-            for s in shard.lock().values() {
-                let session_data = SessionData {
-                    session_id: s.session_id,
-                    peer: s.peer,
-                    session_key: None,
-                    flags: 0,
-                    supported_encryptions: s.supported_encryptions.clone(),
-                    keys: s.keys.iter().map(Into::into).collect(),
-                    addr_valid: s.addr_status.lock().is_valid(),
-                };
-                rmp_serde::encode::write(&mut f, &session_data)?;
-            }
-        }
-        f.flush()?;
+        Arc::new(SessionSnapshot {
+            taken_at: Instant::now(),
+            sessions,
+            nodes,
+        })
+    }
 
-        Ok(())
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        self.snapshot().save(path)
     }
 
     pub fn load(path: &Path) -> anyhow::Result<Arc<Self>> {
@@ -589,9 +1130,12 @@ impl SessionManager {
                 keys,
                 supported_encryptions: node_info.supported_encryptions,
                 addr_status: Mutex::new(addr_status),
+                properties: Default::default(),
+                forward_key: node_info.session_key.map(|key| key.forward_key()),
+                symmetric_nat: Default::default(),
             });
             me.session_slot(&session.session_id)
-                .lock()
+                .write()
                 .insert(session.session_id, session.clone());
             me.link_sessions(&session);
         }
@@ -600,6 +1144,101 @@ impl SessionManager {
     }
 }
 
+/// Sessions present at a single point in time, with the Nodes linked to them.
+///
+/// Taken with all shards locked at once, so the sessions, their count and the Node index
+/// agree with each other, unlike separate calls to [`SessionManager`]. Readers share it
+/// without holding any locks. Only the set of sessions is frozen, mutable state of each
+/// session, like `ts` and `addr_status`, is read when used.
+pub struct SessionSnapshot {
+    taken_at: Instant,
+    sessions: Vec<SessionRef>,
+    nodes: HashMap<NodeId, Vec<SessionRef>>,
+}
+
+impl SessionSnapshot {
+    pub fn age(&self) -> Duration {
+        self.taken_at.elapsed()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SessionRef> {
+        self.sessions.iter()
+    }
+
+    /// Sessions of Nodes matching `selector`.
+    pub fn sessions(&self, selector: &Selector, limit: usize) -> Vec<SessionRef> {
+        self.sessions
+            .iter()
+            .filter(|session| selector.match_prefix(session.node_id))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Sessions of the Node, the most recent last.
+    pub fn node_sessions(&self, node_id: NodeId) -> &[SessionRef] {
+        self.nodes
+            .get(&node_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Nodes matching `selector`, with their sessions.
+    pub fn nodes_for(&self, selector: &Selector, limit: usize) -> HashMap<NodeId, Vec<SessionRef>> {
+        self.nodes
+            .iter()
+            .filter(|(node_id, _)| selector.match_prefix(**node_id))
+            .take(limit)
+            .map(|(node_id, sessions)| (*node_id, sessions.clone()))
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut f = io::BufWriter::new(
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?,
+        );
+
+        for s in &self.sessions {
+            let session_data = SessionData {
+                session_id: s.session_id,
+                peer: s.peer,
+                // Field is unused otherwise, so the state stays readable by older versions.
+                session_key: s.forward_key.map(PubKey::from_forward_key),
+                flags: 0,
+                supported_encryptions: s.supported_encryptions.clone(),
+                keys: s.keys.iter().map(Into::into).collect(),
+                addr_valid: s.addr_status.lock().is_valid(),
+            };
+            rmp_serde::encode::write(&mut f, &session_data)?;
+        }
+        f.flush()?;
+
+        Ok(())
+    }
+}
+
+fn ip_session_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => {
+            let prefix = u128::from(v6) & !(u64::MAX as u128);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+    }
+}
+
 fn has_data<R: BufRead>(r: &mut R) -> io::Result<bool> {
     Ok(!r.fill_buf()?.is_empty())
 }
@@ -635,7 +1274,7 @@ mod tests {
         let session_id_1 = s1.session_id;
         drop((s1, s2, s3));
         assert_eq!(sm.node_sessions.len(), 4);
-        sm.clean_node_sessions();
+        sm.compact();
         assert_eq!(sm.node_sessions.len(), 4);
         assert!(sm.node_session(n1).is_some());
         assert!(sm.node_session(n2).is_some());
@@ -651,10 +1290,50 @@ mod tests {
         drop(session_id_3);
         assert!(sm.node_session(n3).is_none());
         assert!(sm.node_session(n4).is_none());
-        sm.clean_node_sessions();
+        sm.compact();
         assert_eq!(sm.node_sessions.len(), 1);
     }
 
+    #[test]
+    fn test_compact() {
+        let sm = SessionManager::new();
+        let (n1, n2, n3) = (gen_node_id(), gen_node_id(), gen_node_id());
+        let (s1, s2) = (sm.add_dummy_session(), sm.add_dummy_session());
+        sm.link_session(n1, &s1);
+        sm.link_session(n2, &s1);
+        sm.link_session(n2, &s2);
+        sm.watch_node(n3, &s1);
+        assert_eq!(sm.compact(), 0);
+
+        sm.remove_session(&s1.session_id);
+        drop(s1);
+        let nodes = sm.nodes_for(Selector::All, 10);
+        assert_eq!(nodes.len(), 1);
+        assert!(Arc::ptr_eq(&nodes[&n2][0], &s2));
+
+        assert_eq!(sm.compact(), 3);
+        assert_eq!(sm.node_sessions.len(), 1);
+        assert!(sm.watchers.is_empty());
+        assert_eq!(sm.compact(), 0);
+    }
+
+    #[test]
+    fn test_session_shards() {
+        let sm = SessionManager::new();
+        let sessions = (0..1000)
+            .map(|_| sm.add_dummy_session())
+            .collect::<Vec<_>>();
+
+        assert_eq!(sm.num_sessions(), sessions.len());
+        assert!(sm.sessions.iter().all(|shard| !shard.read().is_empty()));
+        for session in &sessions {
+            assert!(Arc::ptr_eq(
+                &sm.session(&session.session_id).unwrap(),
+                session
+            ));
+        }
+    }
+
     #[test_log::test]
     fn test_neighbours() {
         let sm = SessionManager::new();
@@ -679,6 +1358,124 @@ mod tests {
         assert_eq!(v1, &v2[1..=10]);
     }
 
+    #[test]
+    fn test_nearest() {
+        let sm = SessionManager::new();
+        let mut ids = Vec::new();
+        for _ in 0..50 {
+            let n = gen_node_id();
+            let s = sm.add_est_session(n);
+            sm.link_session(n, &s);
+            // Secondary identity shouldn't duplicate the Node.
+            sm.link_session(gen_node_id(), &s);
+            ids.push(n);
+        }
+        let target = gen_node_id();
+        ids.sort_by_key(|id| xor_distance(target, *id));
+
+        let nearest = |count, except| {
+            sm.nearest(target, count, except)
+                .into_iter()
+                .map(|s| s.node_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(nearest(5, None), &ids[..5]);
+        assert_eq!(nearest(5, Some(ids[0])), &ids[1..6]);
+
+        let unreachable = sm.node_session(ids[1]).unwrap();
+        *unreachable.addr_status.lock() = AddrStatus::Invalid(Instant::now());
+        assert_eq!(nearest(3, None), [ids[0], ids[2], ids[3]]);
+        assert_eq!(nearest(100, None).len(), 49);
+    }
+
+    #[test_log::test]
+    fn test_find_nodes() {
+        let sm = SessionManager::new();
+        let mut storage = Vec::new();
+        for i in 0..10 {
+            let n = gen_node_id();
+            let s = sm.add_est_session(n);
+            let service = if i % 2 == 0 { "storage" } else { "compute" };
+            *s.properties.lock() = Some(NodeProperties {
+                signed: Default::default(),
+                values: Properties::from([("service".to_string(), service.to_string())]),
+            });
+            sm.link_session(n, &s);
+            // Secondary identity shouldn't duplicate the Node.
+            sm.link_session(gen_node_id(), &s);
+            if i % 2 == 0 {
+                storage.push(n);
+            }
+        }
+        let n = gen_node_id();
+        let s = sm.add_est_session(n);
+        sm.link_session(n, &s);
+
+        let found = sm.find_nodes("service", "storage", 100);
+        assert_eq!(found.len(), storage.len());
+        assert!(found.iter().all(|s| storage.contains(&s.node_id)));
+
+        assert_eq!(sm.find_nodes("service", "storage", 2).len(), 2);
+        assert!(sm.find_nodes("service", "gpu", 100).is_empty());
+        assert!(sm.find_nodes("version", "storage", 100).is_empty());
+    }
+
+    #[test_log::test]
+    fn test_stale_addresses() {
+        let sm = SessionManager::new();
+        let max_age = Duration::from_secs(60);
+        let old = Instant::now() - Duration::from_secs(120);
+
+        let (fresh, invalid, valid, pending) = (
+            sm.add_est_session(gen_node_id()),
+            sm.add_est_session(gen_node_id()),
+            sm.add_est_session(gen_node_id()),
+            sm.add_est_session(gen_node_id()),
+        );
+        fresh.addr_status.lock().set_valid(false);
+        *invalid.addr_status.lock() = AddrStatus::Invalid(old);
+        *valid.addr_status.lock() = AddrStatus::Valid(old);
+        *pending.addr_status.lock() = AddrStatus::Pending(old);
+
+        let mut stale = sm
+            .stale_addresses(max_age)
+            .into_iter()
+            .map(|s| s.session_id)
+            .collect::<Vec<_>>();
+        stale.sort();
+        let mut expected = vec![invalid.session_id, pending.session_id];
+        expected.sort();
+        assert_eq!(stale, expected);
+        assert!(invalid.addr_status.lock().is_pending());
+        // Checked once until resolved.
+        assert!(sm.stale_addresses(max_age).is_empty());
+    }
+
+    #[test_log::test]
+    fn test_node_reachable_watchers() {
+        let sm = SessionManager::new();
+        let node_id = gen_node_id();
+        let node = sm.add_est_session(node_id);
+        let watcher = sm.add_est_session(gen_node_id());
+        let gone = sm.add_est_session(gen_node_id());
+
+        sm.watch_node(node_id, &watcher);
+        sm.watch_node(node_id, &watcher);
+        sm.watch_node(node_id, &gone);
+        sm.remove_session(&gone.session_id);
+        drop(gone);
+
+        let mut events = sm.subscribe();
+        let watchers = sm.node_reachable(&node);
+        assert_eq!(watchers.len(), 1);
+        assert!(Arc::ptr_eq(&watchers[0], &watcher));
+        assert!(sm.node_reachable(&node).is_empty());
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, SessionEventKind::Reachable);
+        assert_eq!(event.node_id, node_id);
+    }
+
     #[test_log::test]
     fn test_save_load() {
         let mut buffer = Vec::new();
@@ -707,4 +1504,191 @@ mod tests {
             log::info!("decoded {}", data.session_id);
         }
     }
+
+    #[test]
+    fn test_save_load_forward_key() {
+        let sm = SessionManager::new();
+        let identity = Identity::from(IdentityKey::Ed25519(ed25519::generate().verifying_key()));
+        let peer = "127.0.0.1:40".parse().unwrap();
+        let forward_key = rand::random::<ForwardKey>();
+        let session_id = SessionId::generate();
+        sm.new_session(
+            &Clock::now(),
+            session_id,
+            peer,
+            identity.node_id,
+            vec![identity],
+            vec![],
+            Some(forward_key),
+        )
+        .ok()
+        .unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("ya-relay-sessions-{}", rand::random::<u64>()));
+        sm.save(&path).unwrap();
+        let loaded = SessionManager::load(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        let session = loaded.session(&session_id).unwrap();
+        assert_eq!(session.forward_key, Some(forward_key));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let sm = SessionManager::new();
+        let (n1, n2) = (gen_node_id(), gen_node_id());
+        let s1 = sm.add_est_session(n1);
+        let s2 = sm.add_est_session(n1);
+        let s3 = sm.add_est_session(n2);
+        sm.link_session(n1, &s1);
+        sm.link_session(n1, &s2);
+        sm.link_session(n2, &s3);
+        // Not registered yet.
+        sm.add_dummy_session();
+
+        let snapshot = sm.snapshot();
+        sm.remove_session(&s2.session_id);
+        let s4 = sm.add_est_session(n2);
+        sm.link_session(n2, &s4);
+
+        assert_eq!(snapshot.len(), 4);
+        assert_eq!(snapshot.sessions(&Selector::All, usize::MAX).len(), 4);
+        assert_eq!(snapshot.sessions(&Selector::All, 2).len(), 2);
+        let ids = |sessions: &[SessionRef]| -> Vec<SessionId> {
+            sessions.iter().map(|s| s.session_id).collect()
+        };
+        assert_eq!(
+            ids(snapshot.node_sessions(n1)),
+            vec![s1.session_id, s2.session_id]
+        );
+        assert_eq!(ids(snapshot.node_sessions(n2)), vec![s3.session_id]);
+        assert_eq!(snapshot.nodes_for(&Selector::All, usize::MAX).len(), 2);
+
+        let snapshot = sm.snapshot();
+        assert_eq!(snapshot.len(), 4);
+        assert_eq!(ids(snapshot.node_sessions(n1)), vec![s1.session_id]);
+        assert_eq!(
+            ids(snapshot.node_sessions(n2)),
+            vec![s3.session_id, s4.session_id]
+        );
+    }
+
+    #[test]
+    fn test_recent_sessions() {
+        let sm = SessionManager::new();
+        let start = Instant::now();
+        let create = |peer: &str, at: Instant| {
+            sm.new_session(
+                &Clock::at(at),
+                SessionId::generate(),
+                peer.parse().unwrap(),
+                NodeId::default(),
+                vec![],
+                vec![],
+                None,
+            )
+            .ok()
+            .unwrap();
+        };
+        create("10.0.0.1:1000", start);
+        create("10.0.0.1:1001", start + Duration::from_secs(10));
+        create("10.0.0.2:1000", start + Duration::from_secs(10));
+        create("[2001:db8::1]:1000", start);
+        create("[2001:db8::2]:1000", start);
+
+        let ip = "10.0.0.1".parse().unwrap();
+        assert_eq!(sm.recent_sessions(ip, start), 2);
+        assert_eq!(sm.recent_sessions(ip, start + Duration::from_secs(5)), 1);
+        assert_eq!(sm.recent_sessions(ip, start + Duration::from_secs(11)), 0);
+        assert_eq!(sm.recent_sessions("10.0.0.3".parse().unwrap(), start), 0);
+        // Hosts of a /64 network share the quota.
+        assert_eq!(sm.recent_sessions("2001:db8::3".parse().unwrap(), start), 2);
+
+        sm.clean_ip_sessions(start + Duration::from_secs(5));
+        assert_eq!(sm.ip_sessions.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_duplicate() {
+        let sm = SessionManager::new();
+        let clock = Clock::now();
+        let node_id = gen_node_id();
+        let peer: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let create = |node_id: NodeId, peer: SocketAddr| {
+            let keys = vec![Identity {
+                node_id,
+                public_key: ed25519::generate().verifying_key().into(),
+            }];
+            sm.new_session(
+                &clock,
+                SessionId::generate(),
+                peer,
+                node_id,
+                keys,
+                vec![],
+                None,
+            )
+            .ok()
+            .unwrap()
+        };
+
+        let first = create(node_id, peer);
+        sm.link_sessions(&first);
+        *first.addr_status.lock() = AddrStatus::Valid(clock.time());
+        let watched = gen_node_id();
+        sm.watch_node(watched, &first);
+
+        // Handshake retried after the response was lost.
+        let second = create(node_id, peer);
+        assert!(sm.session(&first.session_id).is_none());
+        assert!(Arc::ptr_eq(&sm.node_session(node_id).unwrap(), &second));
+        assert!(second.addr_status.lock().is_valid());
+        assert_eq!(sm.node_sessions(node_id).len(), 1);
+        let watchers = sm.node_reachable(&create(watched, "10.0.0.2:1000".parse().unwrap()));
+        assert!(Arc::ptr_eq(&watchers[0], &second));
+
+        // Another Node reusing the address.
+        let other = create(gen_node_id(), peer);
+        assert!(sm.session(&second.session_id).is_some());
+        assert!(sm.session(&other.session_id).is_some());
+    }
+
+    #[test]
+    fn test_take_over() {
+        let sm = SessionManager::new();
+        let clock = Clock::now();
+        let node_id = gen_node_id();
+        let create = |peer: &str| {
+            let keys = vec![Identity {
+                node_id,
+                public_key: ed25519::generate().verifying_key().into(),
+            }];
+            let peer = peer.parse().unwrap();
+            sm.new_session(
+                &clock,
+                SessionId::generate(),
+                peer,
+                node_id,
+                keys,
+                vec![],
+                None,
+            )
+            .ok()
+            .unwrap()
+        };
+
+        let old = create("10.0.0.1:1000");
+        assert!(sm.take_over(&old).is_empty());
+        sm.link_sessions(&old);
+
+        // Node moved to another address.
+        let new = create("10.0.0.2:2000");
+        let previous = sm.take_over(&new);
+        assert_eq!(previous.len(), 1);
+        assert!(Arc::ptr_eq(&previous[0], &old));
+        assert!(sm.session(&old.session_id).is_none());
+        assert!(Arc::ptr_eq(&sm.node_session(node_id).unwrap(), &new));
+        assert_eq!(sm.node_sessions(node_id).len(), 1);
+    }
 }
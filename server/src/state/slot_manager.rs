@@ -1,41 +1,90 @@
-use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::{fs, io};
 
 use ::metrics::Counter;
-use parking_lot::{RwLock, RwLockUpgradableReadGuard};
+use dashmap::DashMap;
+use parking_lot::Mutex;
 
 use ya_relay_core::NodeId;
 
 pub type SlotId = u32;
 
-struct Inner {
-    nodes: HashMap<NodeId, SlotId>,
-    slots: Vec<NodeId>,
+/// Prefix of slot files storing the epoch. Older files hold only slots, starting
+/// with the zeroed NodeId of slot 0.
+const MAGIC: &[u8; 4] = b"YRS1";
+
+/// Size of the first chunk of [`SlotTable`]. Each next chunk is twice as large.
+const CHUNK_BASE: u64 = 1024;
+/// Enough chunks to hold every `SlotId`.
+const CHUNKS: usize = 23;
+
+/// Append-only slot to NodeId mapping. Slots are resolved for every forward, so
+/// reads take no locks: chunks and their entries are written once and never moved.
+struct SlotTable {
+    chunks: [OnceLock<Box<[OnceLock<NodeId>]>>; CHUNKS],
+}
+
+impl SlotTable {
+    fn new() -> Self {
+        Self {
+            chunks: std::array::from_fn(|_| OnceLock::new()),
+        }
+    }
+
+    fn position(slot: SlotId) -> (usize, usize) {
+        let n = slot as u64 / CHUNK_BASE + 1;
+        let chunk = (63 - n.leading_zeros()) as usize;
+        let offset = slot as u64 - CHUNK_BASE * ((1 << chunk) - 1);
+        (chunk, offset as usize)
+    }
+
+    fn get(&self, slot: SlotId) -> Option<NodeId> {
+        let (chunk, offset) = Self::position(slot);
+        self.chunks[chunk].get()?.get(offset)?.get().copied()
+    }
+
+    /// Writers have to be serialized by the caller.
+    fn set(&self, slot: SlotId, node_id: NodeId) {
+        let (chunk, offset) = Self::position(slot);
+        let entries = self.chunks[chunk]
+            .get_or_init(|| (0..CHUNK_BASE << chunk).map(|_| OnceLock::new()).collect());
+        let _ = entries[offset].set(node_id);
+    }
 }
 
 pub struct SlotManager {
-    inner: RwLock<Inner>,
+    nodes: DashMap<NodeId, SlotId>,
+    slots: SlotTable,
+    /// Number of assigned slots. Locked while assigning a new one.
+    len: Mutex<usize>,
+    /// Identifies the slot table. Kept while slots are persisted, so Nodes
+    /// can tell whether slots they know are still valid.
+    epoch: u32,
     created_counter: Counter,
 }
 
+fn new_epoch() -> u32 {
+    // Zero is reserved for responses without the epoch.
+    rand::random::<u32>().max(1)
+}
+
 impl SlotManager {
-    pub fn new() -> Arc<Self> {
-        let mut inner = Inner {
+    fn with_epoch(epoch: u32) -> Self {
+        Self {
             nodes: Default::default(),
-            slots: Default::default(),
-        };
-
-        let node_id = Default::default();
-        inner.slots.push(node_id);
-        inner.nodes.insert(node_id, 0);
-
-        Arc::new(Self {
-            inner: RwLock::new(inner),
+            slots: SlotTable::new(),
+            len: Mutex::new(0),
+            epoch,
             created_counter: metrics::created_counter(),
-        })
+        }
+    }
+
+    pub fn new() -> Arc<Self> {
+        let manager = Self::with_epoch(new_epoch());
+        manager.push(Default::default());
+        Arc::new(manager)
     }
 
     pub fn load(path: &Path) -> io::Result<Arc<Self>> {
@@ -43,6 +92,20 @@ impl SlotManager {
 
         let mut data = [0u8; 20];
         let mut slots = Vec::new();
+        f.read_exact(&mut data)?;
+        let epoch = if data.starts_with(MAGIC) {
+            let epoch = u32::from_be_bytes(data[4..8].try_into().unwrap());
+            if data[8..].iter().any(|&b| b != 0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid slots header",
+                ));
+            }
+            epoch
+        } else {
+            slots.push(data.into());
+            new_epoch()
+        };
         loop {
             let len = f.read(&mut data)?;
             if len == 0 {
@@ -56,17 +119,12 @@ impl SlotManager {
             }
             slots.push(data.into());
         }
-        let nodes = slots
-            .iter()
-            .enumerate()
-            .map(|(idx, &node_id)| (node_id, idx as SlotId))
-            .collect();
-        let inner = Inner { nodes, slots };
-
-        Ok(Arc::new(Self {
-            inner: RwLock::new(inner),
-            created_counter: metrics::created_counter(),
-        }))
+
+        let manager = Self::with_epoch(epoch);
+        for node_id in slots {
+            manager.push(node_id);
+        }
+        Ok(Arc::new(manager))
     }
 
     pub fn save(&self, path: &Path) -> io::Result<()> {
@@ -78,35 +136,53 @@ impl SlotManager {
                 .open(path)?,
         );
 
-        for slot in &self.inner.read().slots {
-            f.write_all(slot.as_ref())?;
+        let mut header = [0u8; 20];
+        header[..4].copy_from_slice(MAGIC);
+        header[4..8].copy_from_slice(&self.epoch.to_be_bytes());
+        f.write_all(&header)?;
+        for slot in 0..self.len() as SlotId {
+            let node_id = self.slots.get(slot).unwrap_or_default();
+            f.write_all(node_id.as_ref())?;
         }
         f.flush()?;
         Ok(())
     }
 
+    /// Assigns the next slot, or returns the existing one. Returns whether the slot is new.
+    fn push(&self, node_id: NodeId) -> (SlotId, bool) {
+        let mut len = self.len.lock();
+        if let Some(slot_id) = self.nodes.get(&node_id) {
+            return (*slot_id, false);
+        }
+        let slot_id = *len as SlotId;
+        // Slot is resolvable before the Node is given it.
+        self.slots.set(slot_id, node_id);
+        self.nodes.insert(node_id, slot_id);
+        *len += 1;
+        (slot_id, true)
+    }
+
     pub fn slot(&self, node_id: NodeId) -> SlotId {
-        let g = self.inner.upgradable_read();
-        if let Some(slot_id) = g.nodes.get(&node_id) {
+        if let Some(slot_id) = self.nodes.get(&node_id) {
             return *slot_id;
         }
-        let mut gw = RwLockUpgradableReadGuard::upgrade(g);
-        let slot_id = gw.slots.len() as SlotId;
-        gw.slots.push(node_id);
-        gw.nodes.insert(node_id, slot_id);
-        debug_assert_eq!(gw.slots.len(), gw.nodes.len());
-        drop(gw);
-        self.created_counter.increment(1);
+        let (slot_id, created) = self.push(node_id);
+        if created {
+            self.created_counter.increment(1);
+        }
         slot_id
     }
 
     pub fn node(&self, slot: SlotId) -> Option<NodeId> {
-        let slot = slot as usize;
-        self.inner.read().slots.get(slot).cloned()
+        self.slots.get(slot)
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
     }
 
     pub fn len(&self) -> usize {
-        self.inner.read().slots.len()
+        *self.len.lock()
     }
 }
 
@@ -158,4 +234,62 @@ mod tests {
             assert_eq!(m.len(), 10);
         }
     }
+
+    #[test]
+    fn test_slot_table_chunks() {
+        let table = SlotTable::new();
+        let node_id = |slot: SlotId| NodeId::from([(slot % 251) as u8; 20]);
+
+        assert_eq!(SlotTable::position(0), (0, 0));
+        assert_eq!(SlotTable::position(1023), (0, 1023));
+        assert_eq!(SlotTable::position(1024), (1, 0));
+        assert_eq!(SlotTable::position(3071), (1, 2047));
+        assert_eq!(SlotTable::position(3072), (2, 0));
+        assert_eq!(SlotTable::position(SlotId::MAX).0, CHUNKS - 1);
+
+        for slot in 0..5000 {
+            table.set(slot, node_id(slot));
+        }
+        for slot in 0..5000 {
+            assert_eq!(table.get(slot), Some(node_id(slot)));
+        }
+        assert_eq!(table.get(5000), None);
+        assert_eq!(table.get(SlotId::MAX), None);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let m = SlotManager::new();
+        let node_ids = (0..5)
+            .map(|_| NodeId::from(rand::random::<[u8; 20]>()))
+            .collect::<Vec<_>>();
+        for node_id in &node_ids {
+            m.slot(*node_id);
+        }
+
+        let path = std::env::temp_dir().join(format!("ya-relay-slots-{}", rand::random::<u64>()));
+        m.save(&path).unwrap();
+        let loaded = SlotManager::load(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.epoch(), m.epoch());
+        assert_eq!(loaded.len(), m.len());
+        for node_id in node_ids {
+            assert_eq!(loaded.slot(node_id), m.slot(node_id));
+        }
+    }
+
+    #[test]
+    fn test_load_without_epoch() {
+        let node_id = NodeId::from(rand::random::<[u8; 20]>());
+        let path = std::env::temp_dir().join(format!("ya-relay-slots-{}", rand::random::<u64>()));
+        let data = [NodeId::default().as_ref(), node_id.as_ref()].concat();
+        fs::write(&path, data).unwrap();
+        let loaded = SlotManager::load(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_ne!(loaded.epoch(), 0);
+        assert_eq!(loaded.slot(NodeId::default()), 0);
+        assert_eq!(loaded.slot(node_id), 1);
+    }
 }
@@ -0,0 +1,363 @@
+//! Forwarded traffic per source and destination Node, over a sliding window.
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use ya_relay_core::NodeId;
+
+/// Granularity of the window.
+pub const BUCKET: Duration = Duration::from_secs(5);
+const BUCKETS: u64 = 36;
+/// Longest window traffic can be summed over.
+pub const MAX_WINDOW: Duration = Duration::from_secs(BUCKET.as_secs() * (BUCKETS - 1));
+/// Pairs tracked at once. Above that, pairs with the least traffic are forgotten.
+const MAX_PAIRS: usize = 16 * 1024;
+
+#[derive(Default)]
+struct Bucket {
+    tick: AtomicU64,
+    bytes: AtomicU64,
+    packets: AtomicU64,
+}
+
+/// Ring of per-tick counters. Concurrent updates of a bucket just being reused for
+/// a new tick may be lost, which is fine for the statistics.
+struct PairWindow {
+    last_tick: AtomicU64,
+    buckets: [Bucket; BUCKETS as usize],
+}
+
+impl Default for PairWindow {
+    fn default() -> Self {
+        PairWindow {
+            last_tick: AtomicU64::new(0),
+            buckets: std::array::from_fn(|_| Bucket::default()),
+        }
+    }
+}
+
+impl PairWindow {
+    fn add(&self, tick: u64, bytes: u64) {
+        let bucket = &self.buckets[(tick % BUCKETS) as usize];
+        let current = bucket.tick.load(Ordering::Acquire);
+        if current != tick
+            && bucket
+                .tick
+                .compare_exchange(current, tick, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            bucket.bytes.store(0, Ordering::Relaxed);
+            bucket.packets.store(0, Ordering::Relaxed);
+        }
+        bucket.bytes.fetch_add(bytes, Ordering::Relaxed);
+        bucket.packets.fetch_add(1, Ordering::Relaxed);
+        self.last_tick.fetch_max(tick, Ordering::Relaxed);
+    }
+
+    /// Totals of the last `ticks` ticks, up to and including `now`.
+    fn sum(&self, now: u64, ticks: u64) -> (u64, u64) {
+        let oldest = now.saturating_sub(ticks.saturating_sub(1));
+        self.buckets
+            .iter()
+            .filter(|bucket| (oldest..=now).contains(&bucket.tick.load(Ordering::Acquire)))
+            .fold((0, 0), |(bytes, packets), bucket| {
+                (
+                    bytes + bucket.bytes.load(Ordering::Relaxed),
+                    packets + bucket.packets.load(Ordering::Relaxed),
+                )
+            })
+    }
+
+    fn is_stale(&self, now: u64) -> bool {
+        self.last_tick.load(Ordering::Relaxed) + BUCKETS <= now
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PairTraffic {
+    pub src: NodeId,
+    pub dst: NodeId,
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+/// Bytes and packets forwarded between each pair of Nodes, shared by the workers.
+pub struct TrafficMatrix {
+    start: Instant,
+    pairs: DashMap<(NodeId, NodeId), PairWindow>,
+    /// All forwarded traffic, including pairs forgotten in the meantime.
+    total: PairWindow,
+}
+
+impl TrafficMatrix {
+    pub fn new() -> Arc<Self> {
+        Arc::new(TrafficMatrix {
+            start: Instant::now(),
+            pairs: Default::default(),
+            total: Default::default(),
+        })
+    }
+
+    pub fn record(&self, src: NodeId, dst: NodeId, bytes: usize) {
+        self.record_at(Instant::now(), src, dst, bytes)
+    }
+
+    fn record_at(&self, now: Instant, src: NodeId, dst: NodeId, bytes: usize) {
+        let tick = self.tick(now);
+        self.total.add(tick, bytes as u64);
+        if let Some(pair) = self.pairs.get(&(src, dst)) {
+            pair.add(tick, bytes as u64);
+            return;
+        }
+        if self.pairs.len() >= MAX_PAIRS {
+            self.make_room(tick);
+        }
+        self.pairs
+            .entry((src, dst))
+            .or_default()
+            .add(tick, bytes as u64);
+    }
+
+    /// Pairs with the most bytes forwarded within the `window`, heaviest first.
+    /// The window is rounded up to whole buckets and capped at `MAX_WINDOW`.
+    pub fn top(&self, window: Duration, limit: usize) -> Vec<PairTraffic> {
+        self.top_at(Instant::now(), window, limit)
+    }
+
+    fn top_at(&self, now: Instant, window: Duration, limit: usize) -> Vec<PairTraffic> {
+        let tick = self.tick(now);
+        let ticks = ticks(window);
+        self.pairs.retain(|_, pair| !pair.is_stale(tick));
+
+        let mut top: Vec<PairTraffic> = self
+            .pairs
+            .iter()
+            .filter_map(|entry| {
+                let (src, dst) = *entry.key();
+                let (bytes, packets) = entry.value().sum(tick, ticks);
+                (packets > 0).then_some(PairTraffic {
+                    src,
+                    dst,
+                    bytes,
+                    packets,
+                })
+            })
+            .collect();
+        top.sort_unstable_by(|a, b| (b.bytes, b.packets).cmp(&(a.bytes, a.packets)));
+        top.truncate(limit);
+        top
+    }
+
+    /// Nodes, which forwarded to or received from `node_id` within the `window`.
+    pub fn peers(&self, node_id: NodeId, window: Duration) -> Vec<NodeId> {
+        self.peers_at(Instant::now(), node_id, window)
+    }
+
+    fn peers_at(&self, now: Instant, node_id: NodeId, window: Duration) -> Vec<NodeId> {
+        let tick = self.tick(now);
+        let ticks = ticks(window);
+        let peers: HashSet<NodeId> = self
+            .pairs
+            .iter()
+            .filter_map(|entry| {
+                let peer = match *entry.key() {
+                    (src, dst) if dst == node_id && src != node_id => src,
+                    (src, dst) if src == node_id && dst != node_id => dst,
+                    _ => return None,
+                };
+                let (_, packets) = entry.value().sum(tick, ticks);
+                (packets > 0).then_some(peer)
+            })
+            .collect();
+        peers.into_iter().collect()
+    }
+
+    /// Nodes, which forwarded to `node_id` within the `window`.
+    pub fn senders(&self, node_id: NodeId, window: Duration) -> Vec<NodeId> {
+        self.senders_at(Instant::now(), node_id, window)
+    }
+
+    fn senders_at(&self, now: Instant, node_id: NodeId, window: Duration) -> Vec<NodeId> {
+        let tick = self.tick(now);
+        let ticks = ticks(window);
+        self.pairs
+            .iter()
+            .filter_map(|entry| {
+                let (src, dst) = *entry.key();
+                if dst != node_id || src == node_id {
+                    return None;
+                }
+                let (_, packets) = entry.value().sum(tick, ticks);
+                (packets > 0).then_some(src)
+            })
+            .collect()
+    }
+
+    /// Bytes forwarded per second, averaged over the `window` rounded up to whole buckets.
+    /// The current bucket counts only for the time elapsed so far.
+    pub fn rate(&self, window: Duration) -> u64 {
+        self.rate_at(Instant::now(), window)
+    }
+
+    fn rate_at(&self, now: Instant, window: Duration) -> u64 {
+        let tick = self.tick(now);
+        let ticks = ticks(window).min(tick);
+        let (bytes, _) = self.total.sum(tick, ticks);
+        let elapsed = now.saturating_duration_since(self.start);
+        let oldest_start = BUCKET * (tick - ticks) as u32;
+        let secs = elapsed.saturating_sub(oldest_start).as_secs_f64().max(1.0);
+        (bytes as f64 / secs) as u64
+    }
+
+    pub fn num_pairs(&self) -> usize {
+        self.pairs.len()
+    }
+
+    /// Drops stale pairs, and the lightest quarter of the rest if that's not enough.
+    fn make_room(&self, tick: u64) {
+        self.pairs.retain(|_, pair| !pair.is_stale(tick));
+        if self.pairs.len() < MAX_PAIRS {
+            return;
+        }
+
+        let mut totals: Vec<((NodeId, NodeId), u64)> = self
+            .pairs
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().sum(tick, BUCKETS).0))
+            .collect();
+        totals.sort_unstable_by_key(|(_, bytes)| *bytes);
+        for (key, _) in totals.into_iter().take(MAX_PAIRS / 4) {
+            self.pairs.remove(&key);
+        }
+    }
+
+    fn tick(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.start).as_secs() / BUCKET.as_secs()) + 1
+    }
+}
+
+fn ticks(window: Duration) -> u64 {
+    let secs = window.as_secs() + u64::from(window.subsec_nanos() > 0);
+    ((secs + BUCKET.as_secs() - 1) / BUCKET.as_secs()).clamp(1, BUCKETS - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(n: u8) -> NodeId {
+        [n; 20].into()
+    }
+
+    #[test]
+    fn test_top() {
+        let matrix = TrafficMatrix::new();
+        let start = matrix.start;
+        for _ in 0..3 {
+            matrix.record_at(start, node(1), node(2), 1000);
+        }
+        matrix.record_at(start, node(2), node(1), 100);
+        matrix.record_at(start + BUCKET * 2, node(3), node(1), 1500);
+
+        let top = matrix.top_at(start + BUCKET * 2, Duration::from_secs(60), 10);
+        assert_eq!(
+            top,
+            [
+                PairTraffic {
+                    src: node(1),
+                    dst: node(2),
+                    bytes: 3000,
+                    packets: 3
+                },
+                PairTraffic {
+                    src: node(3),
+                    dst: node(1),
+                    bytes: 1500,
+                    packets: 1
+                },
+                PairTraffic {
+                    src: node(2),
+                    dst: node(1),
+                    bytes: 100,
+                    packets: 1
+                },
+            ]
+        );
+        assert_eq!(
+            matrix.top_at(start + BUCKET * 2, Duration::from_secs(60), 1)[0].src,
+            node(1)
+        );
+
+        // Only the last bucket is within the window.
+        let top = matrix.top_at(start + BUCKET * 2, BUCKET, 10);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].src, node(3));
+    }
+
+    #[test]
+    fn test_peers() {
+        let matrix = TrafficMatrix::new();
+        let start = matrix.start;
+        matrix.record_at(start, node(1), node(2), 10);
+        matrix.record_at(start, node(2), node(1), 10);
+        matrix.record_at(start + BUCKET * 4, node(1), node(3), 10);
+        matrix.record_at(start, node(4), node(5), 10);
+
+        let mut peers = matrix.peers_at(start + BUCKET * 4, node(1), MAX_WINDOW);
+        peers.sort_by_key(|node_id| node_id.into_array());
+        assert_eq!(peers, vec![node(2), node(3)]);
+        // Only recent traffic.
+        let peers = matrix.peers_at(start + BUCKET * 4, node(1), BUCKET);
+        assert_eq!(peers, vec![node(3)]);
+
+        let senders = matrix.senders_at(start + BUCKET * 4, node(1), MAX_WINDOW);
+        assert_eq!(senders, vec![node(2)]);
+        let senders = matrix.senders_at(start + BUCKET * 4, node(3), MAX_WINDOW);
+        assert_eq!(senders, vec![node(1)]);
+    }
+
+    #[test]
+    fn test_window_slides() {
+        let matrix = TrafficMatrix::new();
+        let start = matrix.start;
+        matrix.record_at(start, node(1), node(2), 10);
+        // Bucket of the first tick is reused a full ring later.
+        let later = start + BUCKET * BUCKETS as u32;
+        matrix.record_at(later, node(1), node(2), 20);
+
+        let top = matrix.top_at(later, MAX_WINDOW, 10);
+        assert_eq!((top[0].bytes, top[0].packets), (20, 1));
+
+        // Idle pairs are forgotten.
+        let top = matrix.top_at(later + BUCKET * BUCKETS as u32, MAX_WINDOW, 10);
+        assert!(top.is_empty());
+        assert_eq!(matrix.num_pairs(), 0);
+    }
+
+    #[test]
+    fn test_ticks() {
+        assert_eq!(ticks(Duration::ZERO), 1);
+        assert_eq!(ticks(Duration::from_secs(5)), 1);
+        assert_eq!(ticks(Duration::from_millis(5001)), 2);
+        assert_eq!(ticks(Duration::from_secs(60)), 12);
+        assert_eq!(ticks(Duration::from_secs(3600)), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_rate() {
+        let matrix = TrafficMatrix::new();
+        let start = matrix.start;
+        matrix.record_at(start, node(1), node(2), 10_000);
+        matrix.record_at(start + BUCKET, node(2), node(1), 10_000);
+
+        assert_eq!(matrix.rate_at(start + BUCKET * 2, BUCKET * 2), 2_000);
+        // Only the current bucket, almost 5 s into it.
+        assert_eq!(
+            matrix.rate_at(start + BUCKET * 2 - Duration::from_millis(1), BUCKET),
+            2_000
+        );
+        assert_eq!(matrix.rate_at(start + BUCKET * 4, BUCKET), 0);
+    }
+}
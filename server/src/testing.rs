@@ -1 +1,16 @@
+pub mod network;
 pub mod server;
+
+use std::net::SocketAddr;
+use std::time::UNIX_EPOCH;
+
+use ya_relay_core::server_session::SessionId;
+
+pub use crate::state::Clock;
+
+/// Session id as issued by the server to `peer` now. Unlike [`SessionId::generate`], ids
+/// issued in the same second share their leading bytes.
+pub fn issued_session_id(peer: SocketAddr) -> SessionId {
+    let ts = UNIX_EPOCH.elapsed().unwrap().as_secs() as u32;
+    crate::server::new_session_id(&rand::random(), 0, peer, ts, 0, rand::random())
+}
@@ -0,0 +1,466 @@
+//! UDP proxy simulating an imperfect network between test clients and the relay server.
+//!
+//! Clients connect to [`NetworkSimulator::url`] instead of the server url. Every client
+//! address gets its own upstream socket, so the server still sees each client as a separate
+//! peer. Conditions are applied independently to each direction and can be changed while
+//! the test is running.
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use ya_relay_core::utils::Url;
+
+const MAX_DATAGRAM_SIZE: usize = 65536;
+/// Lower bound of the additional delay applied to reordered packets.
+const MIN_REORDER_DELAY: Duration = Duration::from_millis(10);
+
+/// Conditions applied to packets sent in a single direction.
+#[derive(Clone, Debug, Default)]
+pub struct LinkConditions {
+    /// Fixed delay of every packet.
+    pub latency: Duration,
+    /// Random delay in `0..=jitter` added on top of `latency`.
+    pub jitter: Duration,
+    /// Probability of dropping a packet.
+    pub loss: f64,
+    /// Probability of holding a packet back, so it arrives after the packets sent later.
+    pub reorder: f64,
+    /// Probability of delivering a packet twice.
+    pub duplicate: f64,
+}
+
+impl LinkConditions {
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn loss(mut self, probability: f64) -> Self {
+        self.loss = probability;
+        self
+    }
+
+    pub fn reorder(mut self, probability: f64) -> Self {
+        self.reorder = probability;
+        self
+    }
+
+    pub fn duplicate(mut self, probability: f64) -> Self {
+        self.duplicate = probability;
+        self
+    }
+
+    fn delay(&self, rng: &mut impl Rng) -> Duration {
+        let mut delay = self.latency;
+        if !self.jitter.is_zero() {
+            delay += rng.gen_range(Duration::ZERO..=self.jitter);
+        }
+        delay
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConditions {
+    pub client_to_server: LinkConditions,
+    pub server_to_client: LinkConditions,
+}
+
+impl NetworkConditions {
+    /// Same conditions in both directions.
+    pub fn symmetric(link: LinkConditions) -> Self {
+        Self {
+            client_to_server: link.clone(),
+            server_to_client: link,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub received: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub client_to_server: LinkStats,
+    pub server_to_client: LinkStats,
+}
+
+#[derive(Default)]
+struct LinkCounters {
+    received: AtomicU64,
+    dropped: AtomicU64,
+    duplicated: AtomicU64,
+    reordered: AtomicU64,
+}
+
+impl LinkCounters {
+    fn snapshot(&self) -> LinkStats {
+        LinkStats {
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            duplicated: self.duplicated.load(Ordering::Relaxed),
+            reordered: self.reordered.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// Observes every packet received by the simulator, before network conditions are applied.
+pub type PacketTap = Box<dyn Fn(Direction, &[u8]) + Send + Sync>;
+
+#[derive(Default)]
+struct State {
+    conditions: Mutex<NetworkConditions>,
+    tap: Mutex<Option<PacketTap>>,
+    client_to_server: LinkCounters,
+    server_to_client: LinkCounters,
+}
+
+impl State {
+    fn link(&self, direction: Direction) -> (LinkConditions, &LinkCounters) {
+        let conditions = self.conditions.lock();
+        match direction {
+            Direction::ClientToServer => {
+                (conditions.client_to_server.clone(), &self.client_to_server)
+            }
+            Direction::ServerToClient => {
+                (conditions.server_to_client.clone(), &self.server_to_client)
+            }
+        }
+    }
+}
+
+pub struct NetworkSimulator {
+    addr: SocketAddr,
+    state: Arc<State>,
+    cancel: CancellationToken,
+}
+
+impl NetworkSimulator {
+    /// Starts proxying packets between clients and `server` on a local port.
+    pub async fn start(
+        server: SocketAddr,
+        conditions: NetworkConditions,
+    ) -> anyhow::Result<NetworkSimulator> {
+        let socket = Rc::new(UdpSocket::bind(local_addr(&server)).await?);
+        let addr = socket.local_addr()?;
+
+        let state = Arc::new(State {
+            conditions: Mutex::new(conditions),
+            ..Default::default()
+        });
+        let cancel = CancellationToken::new();
+
+        tokio::task::spawn_local(proxy_downstream(
+            socket,
+            server,
+            state.clone(),
+            cancel.clone(),
+        ));
+
+        log::debug!("[TEST] Network simulator listening on {addr}, server {server}");
+        Ok(NetworkSimulator {
+            addr,
+            state,
+            cancel,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn url(&self) -> Url {
+        format!("udp://{}", self.addr).parse().unwrap()
+    }
+
+    pub fn conditions(&self) -> NetworkConditions {
+        self.state.conditions.lock().clone()
+    }
+
+    /// Replaces conditions for all packets received from now on.
+    pub fn set_conditions(&self, conditions: NetworkConditions) {
+        *self.state.conditions.lock() = conditions;
+    }
+
+    /// Records traffic passing through the simulator, e.g. for building fuzzing corpora.
+    pub fn set_tap(&self, tap: impl Fn(Direction, &[u8]) + Send + Sync + 'static) {
+        *self.state.tap.lock() = Some(Box::new(tap));
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        NetworkStats {
+            client_to_server: self.state.client_to_server.snapshot(),
+            server_to_client: self.state.server_to_client.snapshot(),
+        }
+    }
+}
+
+impl Drop for NetworkSimulator {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        log::debug!("[TEST] Dropping NetworkSimulator.");
+    }
+}
+
+fn local_addr(remote: &SocketAddr) -> SocketAddr {
+    match remote {
+        SocketAddr::V4(_) => (Ipv4Addr::LOCALHOST, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::LOCALHOST, 0).into(),
+    }
+}
+
+/// Receives packets from clients and passes them to the server through per-client sockets.
+async fn proxy_downstream(
+    socket: Rc<UdpSocket>,
+    server: SocketAddr,
+    state: Arc<State>,
+    cancel: CancellationToken,
+) {
+    let mut upstream: HashMap<SocketAddr, Rc<UdpSocket>> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (size, client) = tokio::select! {
+            _ = cancel.cancelled() => break,
+            result = socket.recv_from(&mut buf) => match result {
+                Ok(received) => received,
+                Err(e) => {
+                    log::debug!("[TEST] Network simulator receive error: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let upstream_socket = match upstream.get(&client) {
+            Some(upstream_socket) => upstream_socket.clone(),
+            None => match UdpSocket::bind(local_addr(&server)).await {
+                Ok(upstream_socket) => {
+                    let upstream_socket = Rc::new(upstream_socket);
+                    tokio::task::spawn_local(proxy_upstream(
+                        upstream_socket.clone(),
+                        socket.clone(),
+                        client,
+                        state.clone(),
+                        cancel.clone(),
+                    ));
+                    upstream.insert(client, upstream_socket.clone());
+                    upstream_socket
+                }
+                Err(e) => {
+                    log::warn!("[TEST] Network simulator failed to bind socket for {client}: {e}");
+                    continue;
+                }
+            },
+        };
+
+        deliver(
+            upstream_socket,
+            server,
+            buf[..size].to_vec(),
+            &state,
+            Direction::ClientToServer,
+        );
+    }
+}
+
+/// Passes packets addressed to a single client back through the listening socket.
+async fn proxy_upstream(
+    upstream: Rc<UdpSocket>,
+    downstream: Rc<UdpSocket>,
+    client: SocketAddr,
+    state: Arc<State>,
+    cancel: CancellationToken,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let size = tokio::select! {
+            _ = cancel.cancelled() => break,
+            result = upstream.recv_from(&mut buf) => match result {
+                Ok((size, _)) => size,
+                Err(e) => {
+                    log::debug!("[TEST] Network simulator receive error: {e}");
+                    continue;
+                }
+            },
+        };
+
+        deliver(
+            downstream.clone(),
+            client,
+            buf[..size].to_vec(),
+            &state,
+            Direction::ServerToClient,
+        );
+    }
+}
+
+fn deliver(
+    socket: Rc<UdpSocket>,
+    to: SocketAddr,
+    packet: Vec<u8>,
+    state: &State,
+    direction: Direction,
+) {
+    if let Some(tap) = state.tap.lock().as_ref() {
+        tap(direction, &packet);
+    }
+
+    let (link, counters) = state.link(direction);
+    let mut rng = rand::thread_rng();
+    counters.received.fetch_add(1, Ordering::Relaxed);
+
+    if rng.gen_bool(link.loss.clamp(0.0, 1.0)) {
+        counters.dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let copies = if rng.gen_bool(link.duplicate.clamp(0.0, 1.0)) {
+        counters.duplicated.fetch_add(1, Ordering::Relaxed);
+        2
+    } else {
+        1
+    };
+
+    for _ in 0..copies {
+        let mut delay = link.delay(&mut rng);
+        if rng.gen_bool(link.reorder.clamp(0.0, 1.0)) {
+            counters.reordered.fetch_add(1, Ordering::Relaxed);
+            delay += (link.latency + link.jitter).max(MIN_REORDER_DELAY);
+        }
+
+        let socket = socket.clone();
+        let packet = packet.clone();
+        tokio::task::spawn_local(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            if let Err(e) = socket.send_to(&packet, to).await {
+                log::debug!("[TEST] Network simulator failed to send to {to}: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn echo_server() -> anyhow::Result<SocketAddr> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = socket.local_addr()?;
+        tokio::task::spawn_local(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            while let Ok((size, from)) = socket.recv_from(&mut buf).await {
+                socket.send_to(&buf[..size], from).await.ok();
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn receive_all(socket: &UdpSocket, timeout: Duration) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut buf = [0u8; 16];
+        while let Ok(Ok(size)) = tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+            received.extend_from_slice(&buf[..size]);
+        }
+        received
+    }
+
+    #[actix_rt::test]
+    async fn loss_and_duplication() -> anyhow::Result<()> {
+        let server = echo_server().await?;
+        let simulator = NetworkSimulator::start(server, NetworkConditions::default()).await?;
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        client.connect(simulator.addr()).await?;
+
+        client.send(&[1]).await?;
+        assert_eq!(receive_all(&client, Duration::from_millis(100)).await, [1]);
+
+        simulator.set_conditions(NetworkConditions {
+            client_to_server: LinkConditions::default().duplicate(1.0),
+            server_to_client: LinkConditions::default(),
+        });
+        client.send(&[2]).await?;
+        assert_eq!(
+            receive_all(&client, Duration::from_millis(100)).await,
+            [2, 2]
+        );
+
+        simulator.set_conditions(NetworkConditions {
+            client_to_server: LinkConditions::default(),
+            server_to_client: LinkConditions::default().loss(1.0),
+        });
+        client.send(&[3]).await?;
+        assert!(receive_all(&client, Duration::from_millis(100))
+            .await
+            .is_empty());
+
+        let stats = simulator.stats();
+        assert_eq!(stats.client_to_server.received, 3);
+        assert_eq!(stats.client_to_server.duplicated, 1);
+        assert_eq!(stats.server_to_client.received, 4);
+        assert_eq!(stats.server_to_client.dropped, 1);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn latency_and_reordering() -> anyhow::Result<()> {
+        let server = echo_server().await?;
+        let link = LinkConditions::default().latency(Duration::from_millis(50));
+        let simulator = NetworkSimulator::start(
+            server,
+            NetworkConditions {
+                client_to_server: link.clone().reorder(1.0),
+                server_to_client: LinkConditions::default(),
+            },
+        )
+        .await?;
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        client.connect(simulator.addr()).await?;
+
+        let started = tokio::time::Instant::now();
+        client.send(&[1]).await?;
+        while simulator.stats().client_to_server.received == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        simulator.set_conditions(NetworkConditions {
+            client_to_server: link,
+            server_to_client: LinkConditions::default(),
+        });
+        client.send(&[2]).await?;
+
+        assert_eq!(
+            receive_all(&client, Duration::from_millis(300)).await,
+            [2, 1]
+        );
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(simulator.stats().client_to_server.reordered, 1);
+        Ok(())
+    }
+}
@@ -1,22 +1,35 @@
 use crate::config::Config;
+use crate::plugin::Plugin;
 
-use crate::server::{IpCheckerConfig, Server, ServerConfig, SessionHandlerConfig};
+use crate::server::{EdgeConfig, IpCheckerConfig, Server, ServerConfig, SessionHandlerConfig};
+use crate::testing::network::{LinkConditions, NetworkConditions, NetworkSimulator};
 use crate::SessionManagerConfig;
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
+use std::future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::rc::Rc;
-use std::{future, net};
+use std::sync::Arc;
 use tokio::time::Duration;
+use ya_relay_core::clock::{system_clock, Clock};
+use ya_relay_core::intercept::Interceptor;
+use ya_relay_core::server_identity::{PublicKey, SecretKey};
 use ya_relay_core::testing::TestServerWrapper;
 use ya_relay_core::utils::Url;
 
 #[derive(Clone)]
 pub struct ServerWrapper {
     pub server: Rc<Server>,
+    /// Present when the server was built with simulated network conditions.
+    pub network: Option<Rc<NetworkSimulator>>,
 }
 
 impl<'a> TestServerWrapper<'a> for ServerWrapper {
     fn url(&self) -> Url {
+        if let Some(network) = &self.network {
+            return network.url();
+        }
         let addr = self.server.bind_addr();
         format!("udp://{addr}").parse().unwrap()
     }
@@ -34,33 +47,289 @@ pub async fn init_test_server() -> anyhow::Result<ServerWrapper> {
 }
 
 pub async fn init_test_server_with_config(config: Config) -> anyhow::Result<ServerWrapper> {
-    let server = Rc::new(crate::run(&config).await?);
+    TestServerBuilder::from_config(config).build().await
+}
+
+/// Builds a test server with [`test_default_config`] defaults, overriding
+/// only the values relevant for a test.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use ya_relay_server::testing::server::TestServerBuilder;
+/// # async fn example() -> anyhow::Result<()> {
+/// let wrapper = TestServerBuilder::new()
+///     .session_purge_timeout(Duration::from_secs(2))
+///     .session_cleaner_interval(Duration::from_millis(500))
+///     .drop_rate(0.1)
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TestServerBuilder {
+    config: Config,
+    network: Option<NetworkConditions>,
+}
+
+impl TestServerBuilder {
+    pub fn new() -> Self {
+        Self::from_config(test_default_config())
+    }
+
+    pub fn from_config(config: Config) -> Self {
+        TestServerBuilder {
+            config,
+            network: None,
+        }
+    }
+
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.server.address = addr;
+        self
+    }
+
+    /// Accepts clients connecting over WebSocket, see [`Server::ws_addr`].
+    pub fn websocket(mut self) -> Self {
+        self.config.server.ws_listen_addr = Some((Ipv4Addr::LOCALHOST, 0).into());
+        self
+    }
+
+    pub fn metrics_scrape_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.metrics_scrape_addr = addr;
+        self
+    }
+
+    pub fn state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.state_dir = Some(dir.into());
+        self
+    }
+
+    /// Limits number of packets processed concurrently to `workers * tasks_per_worker`.
+    pub fn workers(mut self, workers: usize, tasks_per_worker: usize) -> Self {
+        self.config.server.workers = workers;
+        self.config.server.tasks_per_worker = tasks_per_worker;
+        self
+    }
+
+    /// Forwards from a single session, which can wait to be sent, see [`ServerConfig`].
+    pub fn max_pending_forwards(mut self, max: usize) -> Self {
+        self.config.server.max_pending_forwards = max;
+        self
+    }
+
+    pub fn difficulty(mut self, difficulty: u64) -> Self {
+        self.config.session_handler.difficulty = difficulty;
+        self
+    }
+
+    pub fn salt(mut self, salt: u128) -> Self {
+        self.config.session_handler.salt = Some(salt);
+        self
+    }
+
+    /// Key signing session handshake responses, generated if not set.
+    pub fn server_key(mut self, secret: SecretKey) -> Self {
+        self.config.session_handler.server_key = Some(secret);
+        self
+    }
+
+    /// Time to answer the session challenge, see [`SessionHandlerConfig`].
+    pub fn handshake_window(mut self, window: Duration) -> Self {
+        self.config.session_handler.handshake_window = window;
+        self
+    }
+
+    /// Sessions a single IP can create within `window` before challenges get harder.
+    pub fn ip_session_quota(mut self, quota: u32, window: Duration) -> Self {
+        self.config.session_handler.ip_session_quota = quota;
+        self.config.session_handler.ip_session_window = window;
+        self
+    }
+
+    /// Registers the server as an edge relay at `core`, reporting its Nodes every `interval`.
+    /// The core relay has to sign its handshake responses with `core_key`.
+    pub fn core_relay(mut self, core: SocketAddr, core_key: PublicKey, interval: Duration) -> Self {
+        self.config.edge.core_relay = Some(core);
+        self.config.edge.core_relay_key = Some(core_key);
+        self.config.edge.edge_sync_interval = interval;
+        self
+    }
+
+    /// Identity of the server as an edge relay, generated if not set.
+    pub fn edge_key(mut self, secret: ya_relay_core::key::SecretKey) -> Self {
+        self.config.edge.edge_key = Some(secret);
+        self
+    }
+
+    /// Accepts Nodes reported by the edge relay `node_id`.
+    pub fn allowed_edge(mut self, node_id: ya_relay_core::NodeId) -> Self {
+        self.config.edge.allowed_edges.push(node_id);
+        self
+    }
+
+    /// Time without any packet from the Node, after which its session is removed.
+    pub fn session_purge_timeout(mut self, timeout: Duration) -> Self {
+        self.config.session_manager.session_purge_timeout = timeout;
+        self
+    }
+
+    pub fn session_cleaner_interval(mut self, interval: Duration) -> Self {
+        self.config.session_manager.session_cleaner_interval = interval;
+        self
+    }
+
+    /// Addresses with `Pending` or `Invalid` status older than `max_age` are re-checked every `interval`.
+    pub fn addr_refresh(mut self, interval: Duration, max_age: Duration) -> Self {
+        self.config.session_manager.addr_refresh_interval = interval;
+        self.config.session_manager.addr_status_max_age = max_age;
+        self
+    }
+
+    /// Time source for the session cleaner, e.g. `ya_relay_core::clock::MockClock`.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.config.session_manager.clock = Arc::new(clock);
+        self
+    }
+
+    /// Lets the test drop, delay or modify packets handled by the server workers.
+    pub fn intercept(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.config.server.interceptor = Some(Arc::new(interceptor));
+        self
+    }
+
+    pub fn plugin(mut self, plugin: impl Plugin + 'static) -> Self {
+        self.config.server.plugins.add(plugin);
+        self
+    }
+
+    pub fn ip_check(mut self, timeout: Duration, retry_cnt: usize, retry_after: Duration) -> Self {
+        self.config.ip_check = IpCheckerConfig {
+            timeout,
+            retry_cnt,
+            retry_after,
+        };
+        self
+    }
 
-    Ok(ServerWrapper { server })
+    /// Drops packets in both directions with given probability.
+    /// Other simulated network conditions are left unchanged.
+    pub fn drop_rate(mut self, probability: f64) -> Self {
+        let mut network = self.network.take().unwrap_or_default();
+        network.client_to_server.loss = probability;
+        network.server_to_client.loss = probability;
+        self.network = Some(network);
+        self
+    }
+
+    /// Puts a [`NetworkSimulator`] between the server and clients using [`ServerWrapper::url`].
+    pub fn network(mut self, conditions: NetworkConditions) -> Self {
+        self.network = Some(conditions);
+        self
+    }
+
+    pub fn link(self, link: LinkConditions) -> Self {
+        self.network(NetworkConditions::symmetric(link))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub async fn build(self) -> anyhow::Result<ServerWrapper> {
+        let server = Rc::new(crate::run(&self.config).await?);
+        let network = match self.network {
+            Some(conditions) => {
+                let addr = reachable_addr(server.bind_addr());
+                Some(Rc::new(NetworkSimulator::start(addr, conditions).await?))
+            }
+            None => None,
+        };
+
+        Ok(ServerWrapper { server, network })
+    }
+}
+
+impl Default for TestServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn reachable_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    addr
 }
 
 pub fn test_default_config() -> Config {
     Config {
-        metrics_scrape_addr: (net::Ipv4Addr::LOCALHOST, 0).into(),
+        metrics_scrape_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+        metrics_token: None,
+        metrics_allow: vec![],
+        admin_http_addr: None,
+        admin_http_token: None,
+        admin_http_allow: vec![],
         state_dir: None,
+        check: false,
+        simulate: None,
+        #[cfg(feature = "grpc-admin")]
+        admin_grpc_addr: None,
+        #[cfg(feature = "grpc-admin")]
+        admin_grpc_token: None,
         server: ServerConfig {
-            address: (net::Ipv4Addr::LOCALHOST, 0).into(),
+            address: (Ipv4Addr::LOCALHOST, 0).into(),
             workers: 1,
             tasks_per_worker: 1,
+            max_pending_forwards: 256,
+            response_cache_ttl: Duration::from_secs(10),
+            response_cache_size: 4096,
+            ws_listen_addr: None,
+            interceptor: None,
+            plugins: Default::default(),
         },
         session_manager: SessionManagerConfig {
             session_cleaner_interval: Duration::from_secs(10),
             session_purge_timeout: Duration::from_secs(20),
+            session_compaction_interval: Duration::from_secs(60),
+            addr_refresh_interval: Duration::from_secs(60),
+            addr_status_max_age: Duration::from_secs(300),
+            session_history_nodes: 100,
+            session_history_len: 16,
+            session_reconnect_window: Duration::from_secs(60),
+            clock: system_clock(),
         },
         session_handler: SessionHandlerConfig {
             difficulty: 1,
             salt: None,
+            handshake_window: Duration::from_secs(120),
+            server_key: None,
+            ip_session_quota: 0,
+            ip_session_window: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(25),
+            max_forward_size: 0,
+            session_capacity: 0,
+            bandwidth_capacity: 0,
         },
         ip_check: IpCheckerConfig {
             timeout: Duration::from_millis(300),
             retry_cnt: 1,
             retry_after: Duration::from_millis(100),
         },
+        edge: EdgeConfig {
+            core_relay: None,
+            core_relay_key: None,
+            edge_public_addr: None,
+            edge_key: None,
+            edge_sync_interval: Duration::from_secs(30),
+            allowed_edges: Vec::new(),
+            edge_ttl: Duration::from_secs(120),
+        },
+        sse: Default::default(),
+        capture: Default::default(),
     }
 }
 
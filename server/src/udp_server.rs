@@ -12,7 +12,7 @@ use futures::prelude::*;
 use metrics::{Key, Label, Unit};
 use tokio::time;
 
-pub use socket::{PacketType, UdpSocket, UdpSocketConfig};
+pub use socket::{PacketType, SendTap, UdpSocket, UdpSocketConfig};
 
 use crate::metrics::InstanceCountGuard;
 
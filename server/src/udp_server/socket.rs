@@ -1,8 +1,13 @@
 use actix_rt::net::UdpSocket as BaseUpdSocket;
 use bytes::BytesMut;
+use std::cell::OnceCell;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::{io, mem, ptr};
 
+/// Called with each datagram sent through a socket, e.g. to capture it.
+pub type SendTap = Arc<dyn Fn(&[u8], SocketAddr) + Send + Sync>;
+
 pub struct UdpSocketConfig {
     min_recv_buffer: Option<usize>,
     min_send_buffer: Option<usize>,
@@ -12,6 +17,7 @@ pub struct UdpSocketConfig {
 
 pub struct UdpSocket {
     inner: BaseUpdSocket,
+    send_tap: OnceCell<SendTap>,
 }
 
 #[derive(Debug)]
@@ -219,6 +225,7 @@ impl UdpSocketConfig {
 
         Ok(UdpSocket {
             inner: BaseUpdSocket::from_std(s)?,
+            send_tap: OnceCell::new(),
         })
     }
 
@@ -282,6 +289,7 @@ impl UdpSocketConfig {
 
         Ok(UdpSocket {
             inner: BaseUpdSocket::from_std(s)?,
+            send_tap: OnceCell::new(),
         })
     }
 
@@ -324,8 +332,16 @@ impl UdpSocket {
         self.inner.local_addr()
     }
 
+    /// Sets the tap once. Returns `false`, if it was already set.
+    pub fn set_send_tap(&self, tap: SendTap) -> bool {
+        self.send_tap.set(tap).is_ok()
+    }
+
     pub async fn send_to(&self, buffer: &[u8], dst: SocketAddr) -> io::Result<usize> {
         let len = self.inner.send_to(buffer, dst).await?;
+        if let Some(tap) = self.send_tap.get() {
+            tap(buffer, dst);
+        }
         Ok(len)
     }
 
@@ -0,0 +1,406 @@
+//! One test relay server with many clients, each running on its own thread
+//! with a separate runtime and `LocalSet`, as if they were separate processes.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use ya_relay_client::model::TransportType;
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_core::NodeId;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Job = Box<dyn FnOnce(Client) -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+#[derive(Clone, Debug)]
+pub struct Received {
+    pub from: NodeId,
+    pub transport: TransportType,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Inbox {
+    packets: Mutex<Vec<Received>>,
+    notify: Notify,
+}
+
+/// Client running on a dedicated thread. Dropping the handle shuts the client down.
+pub struct NodeHandle {
+    node_id: NodeId,
+    jobs: mpsc::UnboundedSender<Job>,
+    inbox: Arc<Inbox>,
+    finished: Option<oneshot::Receiver<()>>,
+}
+
+impl NodeHandle {
+    async fn spawn(
+        builder: impl FnOnce() -> ClientBuilder + Send + 'static,
+    ) -> anyhow::Result<Self> {
+        let (jobs, mut jobs_rx) = mpsc::unbounded_channel::<Job>();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (finished_tx, finished) = oneshot::channel();
+        let inbox = Arc::new(Inbox::default());
+
+        std::thread::spawn({
+            let inbox = inbox.clone();
+            move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("node runtime");
+                let local_set = tokio::task::LocalSet::new();
+
+                local_set.block_on(&runtime, async move {
+                    let mut client = match builder().connect(FailFast::Yes).build().await {
+                        Ok(client) => client,
+                        Err(e) => {
+                            ready_tx.send(Err(e)).ok();
+                            return;
+                        }
+                    };
+
+                    let mut receiver = client.forward_receiver().await.expect("forward receiver");
+                    tokio::task::spawn_local(async move {
+                        while let Some(forwarded) = receiver.recv().await {
+                            inbox.packets.lock().push(Received {
+                                from: forwarded.node_id,
+                                transport: forwarded.transport,
+                                payload: forwarded.payload.into_vec(),
+                            });
+                            inbox.notify.notify_waiters();
+                        }
+                    });
+
+                    ready_tx.send(Ok(client.node_id())).ok();
+
+                    while let Some(job) = jobs_rx.recv().await {
+                        tokio::task::spawn_local(job(client.clone()));
+                    }
+                    client.shutdown().await.ok();
+                });
+                finished_tx.send(()).ok();
+            }
+        });
+
+        let node_id = ready_rx
+            .await
+            .map_err(|_| anyhow!("Node thread exited before start"))??;
+
+        Ok(NodeHandle {
+            node_id,
+            jobs,
+            inbox,
+            finished: Some(finished),
+        })
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Runs `f` on the Node thread and returns its result.
+    pub async fn run<F, Fut, R>(&self, f: F) -> anyhow::Result<R>
+    where
+        F: FnOnce(Client) -> Fut + Send + 'static,
+        Fut: Future<Output = R> + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |client| {
+            Box::pin(async move {
+                tx.send(f(client).await).ok();
+            })
+        });
+
+        self.jobs
+            .send(job)
+            .map_err(|_| anyhow!("Node [{}] is not running", self.node_id))?;
+        rx.await
+            .map_err(|_| anyhow!("Node [{}] dropped the job", self.node_id))
+    }
+
+    pub fn received(&self) -> Vec<Received> {
+        self.inbox.packets.lock().clone()
+    }
+
+    pub fn received_from(&self, node_id: NodeId) -> Vec<Received> {
+        self.inbox
+            .packets
+            .lock()
+            .iter()
+            .filter(|packet| packet.from == node_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear_received(&self) {
+        self.inbox.packets.lock().clear();
+    }
+
+    /// Waits until the received packets satisfy `condition`.
+    pub async fn wait_for(
+        &self,
+        timeout: Duration,
+        condition: impl Fn(&[Received]) -> bool,
+    ) -> anyhow::Result<()> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let notified = self.inbox.notify.notified();
+                if condition(&self.inbox.packets.lock()) {
+                    return;
+                }
+                notified.await;
+            }
+        })
+        .await
+        .with_context(|| format!("Node [{}] waiting for packets", self.node_id))
+    }
+
+    /// Stops the client and waits until its thread finishes.
+    pub async fn shutdown(mut self) {
+        let (jobs, _) = mpsc::unbounded_channel();
+        drop(std::mem::replace(&mut self.jobs, jobs));
+        if let Some(finished) = self.finished.take() {
+            finished.await.ok();
+        }
+    }
+}
+
+/// Number of packets delivered between each pair of Nodes.
+/// Rows are senders, columns are receivers.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DeliveryMatrix {
+    ids: Vec<NodeId>,
+    counts: Vec<Vec<usize>>,
+}
+
+impl DeliveryMatrix {
+    fn collect(nodes: &[NodeHandle]) -> Self {
+        let ids = nodes.iter().map(|node| node.node_id).collect::<Vec<_>>();
+        let index = ids
+            .iter()
+            .enumerate()
+            .map(|(idx, id)| (*id, idx))
+            .collect::<HashMap<_, _>>();
+
+        let mut counts = vec![vec![0; ids.len()]; ids.len()];
+        for (to, node) in nodes.iter().enumerate() {
+            for packet in node.inbox.packets.lock().iter() {
+                if let Some(&from) = index.get(&packet.from) {
+                    counts[from][to] += 1;
+                }
+            }
+        }
+        DeliveryMatrix { ids, counts }
+    }
+
+    pub fn get(&self, from: usize, to: usize) -> usize {
+        self.counts[from][to]
+    }
+
+    pub fn counts(&self) -> &[Vec<usize>] {
+        &self.counts
+    }
+
+    /// Checks if every Node received exactly `count` packets from every other Node.
+    pub fn is_complete(&self, count: usize) -> bool {
+        self.counts.iter().enumerate().all(|(from, row)| {
+            row.iter()
+                .enumerate()
+                .all(|(to, n)| *n == if from == to { 0 } else { count })
+        })
+    }
+
+    pub fn assert_complete(&self, count: usize) {
+        assert!(
+            self.is_complete(count),
+            "expected {count} packets between each pair of nodes\n{self}"
+        );
+    }
+
+    pub fn assert_eq(&self, expected: &[&[usize]]) {
+        let expected = expected.iter().map(|row| row.to_vec()).collect::<Vec<_>>();
+        assert_eq!(self.counts, expected, "delivery matrix mismatch\n{self}");
+    }
+}
+
+impl fmt::Display for DeliveryMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "from \\ to")?;
+        for (id, row) in self.ids.iter().zip(self.counts.iter()) {
+            let row = row.iter().map(|n| format!("{n:>4}")).collect::<String>();
+            writeln!(f, "{id} {row}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for DeliveryMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+pub struct Harness {
+    server: ServerWrapper,
+    nodes: Vec<NodeHandle>,
+}
+
+impl Harness {
+    pub async fn start(nodes: usize) -> anyhow::Result<Harness> {
+        Self::with_server(init_test_server().await?, nodes).await
+    }
+
+    pub async fn with_server(server: ServerWrapper, nodes: usize) -> anyhow::Result<Harness> {
+        let mut harness = Harness {
+            server,
+            nodes: vec![],
+        };
+        for _ in 0..nodes {
+            harness.add_node().await?;
+        }
+        Ok(harness)
+    }
+
+    pub async fn add_node(&mut self) -> anyhow::Result<NodeId> {
+        let url = self.server.url();
+        self.add_node_with(move || ClientBuilder::from_url(url))
+            .await
+    }
+
+    /// Adds a client created from custom builder. `connect` is called by the harness.
+    pub async fn add_node_with(
+        &mut self,
+        builder: impl FnOnce() -> ClientBuilder + Send + 'static,
+    ) -> anyhow::Result<NodeId> {
+        let node = NodeHandle::spawn(builder).await?;
+        let node_id = node.node_id();
+        self.nodes.push(node);
+        Ok(node_id)
+    }
+
+    pub fn server(&self) -> &ServerWrapper {
+        &self.server
+    }
+
+    pub fn node(&self, idx: usize) -> &NodeHandle {
+        &self.nodes[idx]
+    }
+
+    pub fn nodes(&self) -> &[NodeHandle] {
+        &self.nodes
+    }
+
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.nodes.iter().map(NodeHandle::node_id).collect()
+    }
+
+    /// Stops the Node and removes it from the harness. Indices of following Nodes shift.
+    pub async fn remove_node(&mut self, idx: usize) {
+        self.nodes.remove(idx).shutdown().await;
+    }
+
+    /// Hides public addresses of all Nodes, so they can communicate only through the relay.
+    pub async fn force_relay(&self) -> anyhow::Result<()> {
+        for node in &self.nodes {
+            self.server.remove_node_endpoints(node.node_id()).await;
+            node.run(|client| async move { client.set_public_addr(None).await })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Establishes sessions between every pair of Nodes.
+    pub async fn connect_mesh(&self) -> anyhow::Result<()> {
+        let ids = self.node_ids();
+        for node in &self.nodes {
+            let targets = ids
+                .iter()
+                .copied()
+                .filter(|id| *id != node.node_id())
+                .collect::<Vec<_>>();
+            node.run(move |client| async move {
+                for target in targets {
+                    client
+                        .ping(target)
+                        .await
+                        .with_context(|| format!("connecting to [{target}]"))?;
+                }
+                anyhow::Ok(())
+            })
+            .await??;
+        }
+        Ok(())
+    }
+
+    /// Every Node sends `count` packets to every other Node. Waits until all of them
+    /// are delivered or `DEFAULT_TIMEOUT` passes and returns what was received.
+    pub async fn exchange(
+        &self,
+        transport: TransportType,
+        count: usize,
+    ) -> anyhow::Result<DeliveryMatrix> {
+        self.clear_received();
+
+        let ids = self.node_ids();
+        let sends = self.nodes.iter().map(|node| {
+            let from = node.node_id();
+            let targets = ids
+                .iter()
+                .copied()
+                .filter(|id| *id != from)
+                .collect::<Vec<_>>();
+            node.run(move |client| async move {
+                for target in targets {
+                    let mut tx = match transport {
+                        TransportType::Unreliable => client.forward_unreliable(target).await?,
+                        TransportType::Reliable => client.forward_reliable(target).await?,
+                        TransportType::Transfer => client.forward_transfer(target).await?,
+                    };
+                    for seq in 0..count {
+                        let payload = format!("{from}:{seq}").into_bytes();
+                        tx.send(payload.into()).await?;
+                    }
+                }
+                anyhow::Ok(())
+            })
+        });
+        for result in futures::future::join_all(sends).await {
+            result??;
+        }
+
+        let expected = count * ids.len().saturating_sub(1);
+        let waits = self
+            .nodes
+            .iter()
+            .map(|node| node.wait_for(DEFAULT_TIMEOUT, |packets| packets.len() >= expected));
+        // Incomplete delivery is reported through the returned matrix.
+        futures::future::join_all(waits).await;
+
+        Ok(self.deliveries())
+    }
+
+    pub fn deliveries(&self) -> DeliveryMatrix {
+        DeliveryMatrix::collect(&self.nodes)
+    }
+
+    pub fn clear_received(&self) {
+        self.nodes.iter().for_each(NodeHandle::clear_received);
+    }
+
+    pub async fn shutdown(self) {
+        futures::future::join_all(self.nodes.into_iter().map(NodeHandle::shutdown)).await;
+    }
+}
@@ -1,3 +1,5 @@
+pub mod harness;
+
 use anyhow::{bail, Context};
 use futures::StreamExt;
 use std::rc::Rc;
@@ -5,7 +7,7 @@ use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
 use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::ReceiverStream;
 use ya_relay_core::testing::TestServerWrapper;
 use ya_relay_server::testing::server::ServerWrapper;
 
@@ -21,14 +23,14 @@ pub enum Mode {
 pub fn spawn_receive<T: std::fmt::Debug + 'static>(
     label: &'static str,
     received: Rc<AtomicBool>,
-    rx: mpsc::UnboundedReceiver<T>,
+    rx: mpsc::Receiver<T>,
 ) {
     println!("Spawning {} receiver", label);
 
     tokio::task::spawn_local({
         let received = received;
         async move {
-            UnboundedReceiverStream::new(rx)
+            ReceiverStream::new(rx)
                 .for_each(|item| {
                     let received = received.clone();
                     async move {
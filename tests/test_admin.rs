@@ -0,0 +1,297 @@
+use std::time::Duration;
+
+use tokio_stream::StreamExt;
+
+use ya_relay_client::maintenance::MaintenanceKind;
+use ya_relay_client::{ClientBuilder, FailFast};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::access::AccessPolicy;
+use ya_relay_server::admin::proto::admin_client::AdminClient;
+use ya_relay_server::admin::proto::{
+    announce_maintenance_request, disconnect_request, event, AnnounceMaintenanceRequest,
+    DisconnectRequest, DownloadCaptureRequest, GetCaptureRequest, GetLimitsRequest, GetNodeRequest,
+    ListSessionsRequest, SetLimitsRequest, StartCaptureRequest, StopCaptureRequest,
+    StreamEventsRequest,
+};
+use ya_relay_server::admin::AdminService;
+use ya_relay_server::testing::server::init_test_server;
+
+#[test_log::test(actix_rt::test)]
+async fn test_admin_sessions_and_events() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let server = &wrapper.server;
+    let (addr, _handle) = AdminService::new(
+        server.sessions(),
+        server.limits(),
+        server.maintenance(),
+        server.capture(),
+    )
+    .start("127.0.0.1:0".parse()?)
+    .await?;
+    let mut admin = AdminClient::connect(format!("http://{addr}")).await?;
+
+    let mut events = admin
+        .stream_events(StreamEventsRequest {})
+        .await?
+        .into_inner();
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let node_id = client.node_id().to_string();
+
+    let sessions = admin
+        .list_sessions(ListSessionsRequest::default())
+        .await?
+        .into_inner();
+    assert_eq!(sessions.total, 1);
+    assert_eq!(sessions.sessions[0].node_id, node_id);
+
+    let prefix = node_id[2..5].to_string();
+    let sessions = admin
+        .list_sessions(ListSessionsRequest {
+            node_prefix: prefix,
+            limit: 10,
+        })
+        .await?
+        .into_inner();
+    assert_eq!(sessions.sessions.len(), 1);
+
+    let node = admin
+        .get_node(GetNodeRequest {
+            node_id: node_id.clone(),
+        })
+        .await?
+        .into_inner();
+    assert_eq!(node.sessions.len(), 1);
+
+    let removed = admin
+        .disconnect(DisconnectRequest {
+            target: Some(disconnect_request::Target::NodeId(node_id.clone())),
+        })
+        .await?
+        .into_inner()
+        .removed;
+    assert_eq!(removed, 1);
+    assert_eq!(server.sessions().num_sessions(), 0);
+
+    let status = admin
+        .get_node(GetNodeRequest {
+            node_id: node_id.clone(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+
+    let mut kinds = vec![];
+    while kinds.len() < 2 {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await?
+            .unwrap()?;
+        assert_eq!(event.node_id, node_id);
+        kinds.push(event.kind());
+    }
+    assert_eq!(kinds, vec![event::Kind::Created, event::Kind::Removed]);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_admin_limits() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let server = &wrapper.server;
+    let (addr, _handle) = AdminService::new(
+        server.sessions(),
+        server.limits(),
+        server.maintenance(),
+        server.capture(),
+    )
+    .start("127.0.0.1:0".parse()?)
+    .await?;
+    let mut admin = AdminClient::connect(format!("http://{addr}")).await?;
+
+    let limits = admin.get_limits(GetLimitsRequest {}).await?.into_inner();
+    assert_eq!(limits.challenge_difficulty, 1);
+    assert_eq!(limits.session_purge_timeout_ms, 20_000);
+
+    let limits = admin
+        .set_limits(SetLimitsRequest {
+            challenge_difficulty: Some(2),
+            session_purge_timeout_ms: None,
+        })
+        .await?
+        .into_inner();
+    assert_eq!(limits.challenge_difficulty, 2);
+    assert_eq!(limits.session_purge_timeout_ms, 20_000);
+    assert_eq!(server.limits().challenge_difficulty(), 2);
+
+    // Handshake uses the new difficulty.
+    ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_admin_maintenance() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let server = &wrapper.server;
+    let (addr, _handle) = AdminService::new(
+        server.sessions(),
+        server.limits(),
+        server.maintenance(),
+        server.capture(),
+    )
+    .start("127.0.0.1:0".parse()?)
+    .await?;
+    let mut admin = AdminClient::connect(format!("http://{addr}")).await?;
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let mut notices = client.maintenance_notices().unwrap();
+
+    let sessions = admin
+        .announce_maintenance(AnnounceMaintenanceRequest {
+            kind: announce_maintenance_request::Kind::Restart.into(),
+            scheduled_at_ms: 1_700_000_000_000,
+            alternative_relay: "udp://127.0.0.1:7477".to_string(),
+            message: "upgrade".to_string(),
+        })
+        .await?
+        .into_inner()
+        .sessions;
+    assert_eq!(sessions, 1);
+    assert!(server.maintenance().notice().is_some());
+
+    let notice = tokio::time::timeout(Duration::from_secs(5), notices.recv())
+        .await?
+        .unwrap();
+    assert_eq!(notice.kind, MaintenanceKind::Restart);
+    assert_eq!(
+        notice.alternative_relay.as_deref(),
+        Some("udp://127.0.0.1:7477")
+    );
+    assert_eq!(notice.message, "upgrade");
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_admin_capture() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let server = &wrapper.server;
+    let (addr, _handle) = AdminService::new(
+        server.sessions(),
+        server.limits(),
+        server.maintenance(),
+        server.capture(),
+    )
+    .start("127.0.0.1:0".parse()?)
+    .await?;
+    let mut admin = AdminClient::connect(format!("http://{addr}")).await?;
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let node_id = client.node_id();
+
+    admin
+        .start_capture(StartCaptureRequest {
+            node_id: node_id.to_string(),
+            max_payload: Some(16),
+        })
+        .await?;
+    client.find_node(node_id).await?;
+
+    let status = admin
+        .stop_capture(StopCaptureRequest {})
+        .await?
+        .into_inner();
+    assert_eq!(status.node_id, node_id.to_string());
+    // Request and response.
+    assert!(status.packets >= 2);
+
+    let capture = admin.get_capture(GetCaptureRequest {}).await?.into_inner();
+    assert!(capture.running.is_none());
+    let file = capture
+        .files
+        .iter()
+        .find(|file| file.name.starts_with(&node_id.to_string()))
+        .unwrap();
+
+    let mut chunks = admin
+        .download_capture(DownloadCaptureRequest {
+            name: file.name.clone(),
+        })
+        .await?
+        .into_inner();
+    let mut data = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        data.extend(chunk?.data);
+    }
+    assert_eq!(data.len() as u64, file.size);
+    assert_eq!(&data[..4], &0xa1b2c3d4u32.to_le_bytes());
+
+    let status = admin
+        .download_capture(DownloadCaptureRequest {
+            name: "../server.key".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_admin_token() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let server = &wrapper.server;
+    let service = || {
+        AdminService::new(
+            server.sessions(),
+            server.limits(),
+            server.maintenance(),
+            server.capture(),
+        )
+    };
+
+    // Exposed without a token.
+    assert!(service().start("0.0.0.0:0".parse()?).await.is_err());
+
+    let (addr, _handle) = service()
+        .access(AccessPolicy::new(Some("secret".to_string()), vec![]))
+        .start("127.0.0.1:0".parse()?)
+        .await?;
+    let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))?
+        .connect()
+        .await?;
+    let with_token = |token: &'static str| {
+        move |mut request: tonic::Request<()>| {
+            let value = format!("Bearer {token}").parse().unwrap();
+            request.metadata_mut().insert("authorization", value);
+            Ok(request)
+        }
+    };
+
+    let status = AdminClient::new(channel.clone())
+        .get_limits(GetLimitsRequest {})
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    let status = AdminClient::with_interceptor(channel.clone(), with_token("wrong"))
+        .get_limits(GetLimitsRequest {})
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    let limits = AdminClient::with_interceptor(channel, with_token("secret"))
+        .get_limits(GetLimitsRequest {})
+        .await?
+        .into_inner();
+    assert_eq!(limits.challenge_difficulty, 1);
+    Ok(())
+}
@@ -9,13 +9,17 @@ use anyhow::Context;
 use futures::StreamExt;
 use itertools::Itertools;
 use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::ReceiverStream;
 
 use ya_relay_client::channels::Forwarded;
-use ya_relay_client::{Client, ClientBuilder, FailFast};
+use ya_relay_client::pubsub::Subscription;
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::intercept::{Direction, Verdict};
 use ya_relay_core::testing::TestServerWrapper;
 use ya_relay_core::NodeId;
-use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+use ya_relay_proto::codec::PacketKind;
+use ya_relay_proto::proto::{packet, request, Packet, Request};
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper, TestServerBuilder};
 
 async fn start_clients(wrapper: &ServerWrapper, count: u32) -> Vec<Client> {
     let mut clients = vec![];
@@ -84,11 +88,11 @@ async fn test_broadcast() -> anyhow::Result<()> {
     let wrapper = init_test_server().await.unwrap();
     let mut clients = start_clients(&wrapper, NEIGHBOURHOOD_SIZE + 1).await;
 
-    fn spawn_receive(received: Rc<AtomicUsize>, rx: mpsc::UnboundedReceiver<Forwarded>) {
+    fn spawn_receive(received: Rc<AtomicUsize>, rx: mpsc::Receiver<Forwarded>) {
         tokio::task::spawn_local({
             let received = received;
             async move {
-                UnboundedReceiverStream::new(rx)
+                ReceiverStream::new(rx)
                     .for_each(|item| {
                         let received = received.clone();
                         async move {
@@ -124,3 +128,150 @@ async fn test_broadcast() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[test_log::test(actix_rt::test)]
+async fn test_forward_to_group() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let clients = start_clients(&wrapper, 4).await;
+
+    let mut receivers = vec![];
+    for client in &clients {
+        receivers.push(
+            client
+                .forward_receiver()
+                .await
+                .context("no forward receiver")?,
+        );
+    }
+    for (i, client) in clients.iter().enumerate().skip(1) {
+        assert_eq!(client.join_group("topic").await?, i as u32);
+    }
+    // Joining again doesn't change anything.
+    assert_eq!(clients[1].join_group("topic").await?, 3);
+
+    async fn expect(rx: &mut mpsc::Receiver<Forwarded>, sender: NodeId, data: &[u8]) {
+        let forwarded = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("group packet not received")
+            .expect("receiver closed");
+        assert_eq!(forwarded.node_id, sender);
+        assert_eq!(forwarded.payload.as_ref(), data);
+    }
+    async fn expect_none(rx: &mut mpsc::Receiver<Forwarded>) {
+        let result = tokio::time::timeout(Duration::from_millis(300), rx.recv()).await;
+        assert!(result.is_err(), "unexpected packet: {result:?}");
+    }
+
+    // Sender doesn't have to be a member.
+    clients[0].forward_to_group("topic", vec![1]).await?;
+    for rx in &mut receivers[1..] {
+        expect(rx, clients[0].node_id(), &[1]).await;
+    }
+
+    // Members don't receive their own packets.
+    clients[1].forward_to_group("topic", vec![2]).await?;
+    for rx in &mut receivers[2..] {
+        expect(rx, clients[1].node_id(), &[2]).await;
+    }
+    expect_none(&mut receivers[0]).await;
+    expect_none(&mut receivers[1]).await;
+
+    clients[3].leave_group("topic").await?;
+    assert!(clients[3].leave_group("topic").await.is_err());
+
+    clients[1].forward_to_group("topic", vec![3]).await?;
+    expect(&mut receivers[2], clients[1].node_id(), &[3]).await;
+    expect_none(&mut receivers[3]).await;
+
+    // Nothing happens when sending to a group without members.
+    clients[0].forward_to_group("empty", vec![4]).await?;
+    expect_none(&mut receivers[1]).await;
+    Ok(())
+}
+
+async fn expect_published(subscription: &mut Subscription, publisher: NodeId, data: &[u8]) {
+    let message = tokio::time::timeout(Duration::from_secs(2), subscription.recv())
+        .await
+        .expect("published payload not received")
+        .expect("subscription closed");
+    assert_eq!(message.topic, subscription.topic());
+    assert_eq!(message.node_id, publisher);
+    assert_eq!(message.payload.as_ref(), data);
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_pubsub() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let clients = start_clients(&wrapper, 3).await;
+    let mut rx2 = clients[2]
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+
+    let mut news1 = clients[1].subscribe("news").await?;
+    let mut news2 = clients[2].subscribe("news").await?;
+    let mut sports2 = clients[2].subscribe("sports").await?;
+
+    clients[0].publish("news", vec![1]).await?;
+    expect_published(&mut news1, clients[0].node_id(), &[1]).await;
+    expect_published(&mut news2, clients[0].node_id(), &[1]).await;
+
+    clients[1].publish("sports", vec![2]).await?;
+    expect_published(&mut sports2, clients[1].node_id(), &[2]).await;
+
+    // Published payloads don't reach the regular forward receiver, other packets do.
+    clients[0]
+        .forward_unreliable(clients[2].node_id())
+        .await?
+        .send(vec![3].into())
+        .await?;
+    let forwarded = tokio::time::timeout(Duration::from_secs(2), rx2.recv())
+        .await?
+        .context("receiver closed")?;
+    assert_eq!(forwarded.payload.as_ref(), &[3]);
+
+    clients[2].unsubscribe("news").await?;
+    assert!(news2.recv().await.is_none());
+    clients[0].publish("news", vec![4]).await?;
+    expect_published(&mut news1, clients[0].node_id(), &[4]).await;
+
+    assert!(clients[0].subscribe("").await.is_err());
+    Ok(())
+}
+
+/// Relay server ignoring `JoinGroup` is treated as one without group support.
+#[test_log::test(actix_rt::test)]
+async fn test_pubsub_fanout() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .intercept(|dir: Direction, _, packet: &mut PacketKind| {
+            let join = matches!(
+                packet,
+                PacketKind::Packet(Packet {
+                    kind: Some(packet::Kind::Request(Request {
+                        kind: Some(request::Kind::JoinGroup(_)),
+                        ..
+                    })),
+                    ..
+                })
+            );
+            match dir == Direction::Incoming && join {
+                true => Verdict::Drop,
+                false => Verdict::Pass,
+            }
+        })
+        .build()
+        .await?;
+    let clients = start_clients(&wrapper, 3).await;
+
+    let mut news1 = clients[1].subscribe("news").await?;
+    let mut news2 = clients[2].subscribe("news").await?;
+
+    clients[0].publish("news", vec![1]).await?;
+    // Publisher, which never subscribed, still sends to the relay group. It has no members.
+    let result = tokio::time::timeout(Duration::from_millis(500), news1.recv()).await;
+    assert!(result.is_err(), "unexpected payload: {result:?}");
+
+    clients[1].publish("news", vec![2]).await?;
+    expect_published(&mut news2, clients[1].node_id(), &[2]).await;
+    Ok(())
+}
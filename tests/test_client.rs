@@ -1,11 +1,19 @@
 mod common;
 
+use futures::future::LocalBoxFuture;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use std::time::Duration;
-use ya_relay_client::{ClientBuilder, FailFast};
-use ya_relay_core::crypto::FallbackCryptoProvider;
+use ya_relay_client::model::SessionDesc;
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::crypto::{ed25519, FallbackCryptoProvider};
+use ya_relay_core::key;
+use ya_relay_core::runtime::Spawner;
 use ya_relay_core::testing::TestServerWrapper;
 use ya_relay_core::utils::to_udp_url;
-use ya_relay_server::testing::server::init_test_server;
+use ya_relay_core::NodeId;
+use ya_relay_server::testing::server::{init_test_server, TestServerBuilder};
 
 /// Client should be able to use the same port after it was shutdown.
 /// If it doesn't, it means that socket wasn't dropped correctly.
@@ -46,3 +54,405 @@ async fn test_clean_shutdown() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test_log::test(actix_rt::test)]
+async fn test_sign_verify() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let data = b"out-of-band payload";
+    let signature = client1.sign(data).await?;
+
+    assert!(client2.verify(client1.node_id(), data, &signature).await?);
+    assert!(!client2.verify(client2.node_id(), data, &signature).await?);
+    assert!(
+        !client2
+            .verify(client1.node_id(), b"tampered payload", &signature)
+            .await?
+    );
+
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_node_properties() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .property("service", "storage")
+        .property("version", "1.2.0")
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let properties = client2.node_properties(client1.node_id()).await?;
+    assert_eq!(properties.len(), 2);
+    assert_eq!(properties["service"], "storage");
+    assert_eq!(properties["version"], "1.2.0");
+
+    assert!(client1.node_properties(client2.node_id()).await?.is_empty());
+
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_find_nodes_by() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let mut storage = Vec::new();
+    for _ in 0..3 {
+        let client = ClientBuilder::from_url(wrapper.url())
+            .connect(FailFast::Yes)
+            .property("service", "storage")
+            .build()
+            .await?;
+        storage.push(client);
+    }
+    let _compute = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .property("service", "compute")
+        .build()
+        .await?;
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let found = client.find_nodes_by("service", "storage", 10).await?;
+    let expected = storage.iter().map(|c| c.node_id()).collect::<HashSet<_>>();
+    assert_eq!(found.len(), 3);
+    assert_eq!(found.into_iter().collect::<HashSet<_>>(), expected);
+
+    assert_eq!(
+        client.find_nodes_by("service", "storage", 2).await?.len(),
+        2
+    );
+    assert!(client.find_nodes_by("service", "gpu", 10).await?.is_empty());
+
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_nearest_nodes() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let mut clients = Vec::new();
+    for _ in 0..5 {
+        let client = ClientBuilder::from_url(wrapper.url())
+            .connect(FailFast::Yes)
+            .build()
+            .await?;
+        clients.push(client);
+    }
+
+    let target = clients[1].node_id();
+    let distance = |node_id: NodeId| -> Vec<u8> {
+        let target = target.into_array();
+        node_id
+            .into_array()
+            .iter()
+            .zip(target)
+            .map(|(a, b)| a ^ b)
+            .collect()
+    };
+    let mut expected = clients[1..].iter().map(|c| c.node_id()).collect::<Vec<_>>();
+    expected.sort_by_key(|node_id| distance(*node_id));
+
+    // Requesting Node is left out, the target itself is the closest one.
+    let nearest = clients[0].nearest_nodes(target, 10).await?;
+    assert_eq!(nearest, expected);
+    assert_eq!(nearest[0], target);
+    assert_eq!(clients[0].nearest_nodes(target, 2).await?, &expected[..2]);
+
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_locate_node_in_relay_hierarchy() -> anyhow::Result<()> {
+    let edge_key = key::generate();
+    let core = TestServerBuilder::new()
+        .allowed_edge(key::node_id(&edge_key))
+        .build()
+        .await?;
+    let core_addr = core.server.bind_addr();
+    let core_key = core.server.public_key();
+    let edge = TestServerBuilder::new()
+        .core_relay(core_addr, core_key, Duration::from_millis(200))
+        .edge_key(edge_key.clone())
+        .build()
+        .await?;
+    let edge_addr = edge.server.bind_addr();
+    // Relay without the permission to report its Nodes.
+    let rogue = TestServerBuilder::new()
+        .core_relay(core_addr, core_key, Duration::from_millis(200))
+        .build()
+        .await?;
+    // Allowed relay, which doesn't trust the key of the core relay.
+    let untrusting = TestServerBuilder::new()
+        .core_relay(
+            core_addr,
+            ed25519::generate().verifying_key(),
+            Duration::from_millis(200),
+        )
+        .edge_key(edge_key)
+        .build()
+        .await?;
+
+    let build_client = |url| ClientBuilder::from_url(url).connect(FailFast::Yes).build();
+    let core_client = build_client(core.url()).await?;
+    let edge_client = build_client(edge.url()).await?;
+    let rogue_client = build_client(rogue.url()).await?;
+    let untrusting_client = build_client(untrusting.url()).await?;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let edge_id = edge_client.node_id();
+    let core_id = core_client.node_id();
+    assert_eq!(core_client.locate_node(core_id).await?, core_addr);
+    assert_eq!(core_client.locate_node(edge_id).await?, edge_addr);
+    // Nodes unknown to the edge relay are looked up at the core relay.
+    assert_eq!(edge_client.locate_node(core_id).await?, core_addr);
+    assert_eq!(edge_client.locate_node(edge_id).await?, edge_addr);
+
+    assert!(core_client
+        .locate_node(rogue_client.node_id())
+        .await
+        .is_err());
+    assert!(core_client
+        .locate_node(untrusting_client.node_id())
+        .await
+        .is_err());
+    assert!(edge_client
+        .locate_node(NodeId::from([7u8; 20]))
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_server_key() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .server_key(ed25519::generate())
+        .build()
+        .await?;
+
+    let _client = ClientBuilder::from_url(wrapper.url())
+        .server_key(wrapper.server.public_key())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let result = ClientBuilder::from_url(wrapper.url())
+        .server_key(ed25519::generate().verifying_key())
+        .connect(FailFast::Yes)
+        .build()
+        .await;
+    let error = format!("{:#}", result.err().expect("Untrusted server"));
+    assert!(error.contains("Untrusted relay server"), "{error}");
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_trust_server_on_first_use() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let path = std::env::temp_dir().join(format!(
+        "ya-relay-known-servers-{}.json",
+        rand::random::<u64>()
+    ));
+
+    for _ in 0..2 {
+        let _client = ClientBuilder::from_url(wrapper.url())
+            .trust_server_on_first_use(&path)
+            .connect(FailFast::Yes)
+            .build()
+            .await?;
+    }
+    let known: HashMap<String, String> = serde_json::from_slice(&std::fs::read(&path)?)?;
+    let addr = wrapper.server.bind_addr().to_string();
+    assert_eq!(
+        known.get(&addr),
+        Some(&hex::encode(wrapper.server.public_key().as_bytes()))
+    );
+
+    // Server presenting another key is rejected.
+    let other = hex::encode(ed25519::generate().verifying_key().as_bytes());
+    std::fs::write(&path, serde_json::to_vec(&HashMap::from([(addr, other)]))?)?;
+    let result = ClientBuilder::from_url(wrapper.url())
+        .trust_server_on_first_use(&path)
+        .connect(FailFast::Yes)
+        .build()
+        .await;
+    let error = format!("{:#}", result.err().expect("Untrusted server"));
+    assert!(error.contains("Untrusted relay server"), "{error}");
+
+    std::fs::remove_file(&path).ok();
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_session_desc_serde() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let sessions = client.sessions().await;
+    let json = serde_json::to_string(&sessions)?;
+    let decoded: Vec<SessionDesc> = serde_json::from_str(&json)?;
+
+    assert_eq!(decoded.len(), 1);
+    assert_eq!(decoded[0].remote, sessions[0].remote);
+    assert_eq!(decoded[0].id, sessions[0].id);
+    assert_eq!(decoded[0].last_ping, sessions[0].last_ping);
+    assert!(decoded[0].created <= sessions[0].created + Duration::from_secs(1));
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_diagnostics() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let mut tx = client1.forward_reliable(client2.node_id()).await?;
+    tx.send(vec![1u8].into()).await?;
+
+    let report = client1.diagnostics().await;
+    assert_eq!(report.node_id, client1.node_id());
+    assert_eq!(report.bind_addr, Some(client1.bind_addr().await?));
+    assert_eq!(report.server.addr, wrapper.url().socket_addrs(|| None)?[0]);
+    assert_eq!(
+        report.server.session.map(|s| s.remote),
+        Some(report.server.addr)
+    );
+    assert!(report.server.rtt.is_some());
+    assert!(report.server.error.is_none());
+    assert_eq!(report.sessions.len(), 2);
+    assert!(report
+        .routes
+        .iter()
+        .any(|route| route.node_id == client2.node_id() && route.p2p));
+
+    let node = report
+        .virtual_nodes
+        .iter()
+        .find(|node| node.node_id == client2.node_id())
+        .expect("Virtual node");
+    assert!(node.channels.iter().any(|c| c.state == "Connected"));
+    assert!(report.sockets.iter().any(|s| s.state == "Established"));
+
+    let json: serde_json::Value = serde_json::from_str(&report.to_json()?)?;
+    assert_eq!(json["node_id"], client1.node_id().to_string());
+    assert!(json["server"]["rtt"].is_object());
+    assert!(json["errors"].is_array());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_socket_hook_and_spawner() -> anyhow::Result<()> {
+    struct CountingSpawner(Rc<Cell<usize>>);
+
+    impl Spawner for CountingSpawner {
+        fn spawn(&self, future: LocalBoxFuture<'static, ()>) {
+            self.0.set(self.0.get() + 1);
+            tokio::task::spawn_local(future);
+        }
+    }
+
+    let wrapper = init_test_server().await?;
+    let hooked = Rc::new(Cell::new(None));
+    let spawned = Rc::new(Cell::new(0));
+
+    let hooked_ = hooked.clone();
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .socket_hook(move |socket| {
+            hooked_.set(Some(socket.local_addr()?));
+            Ok(())
+        })
+        .spawner(CountingSpawner(spawned.clone()))
+        .build()
+        .await?;
+
+    assert_eq!(hooked.get(), Some(client.bind_addr().await?));
+    assert!(spawned.get() > 0);
+    assert_eq!(client.sessions().await.len(), 1);
+
+    // Client can't start if the socket can't be protected.
+    let result = ClientBuilder::from_url(wrapper.url())
+        .socket_hook(|_| Err(std::io::ErrorKind::PermissionDenied.into()))
+        .build()
+        .await;
+    assert!(result.is_err());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_hibernate_restore() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let path = std::env::temp_dir().join(format!(
+        "ya-relay-hibernation-{}.json",
+        rand::random::<u64>()
+    ));
+
+    let crypto = FallbackCryptoProvider::default();
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .crypto(crypto.clone())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let received = common::spawn_receive_for_client(&client2, ">> 2").await?;
+
+    let node_id = client1.node_id();
+    let sessions = wrapper.server.sessions();
+    let session_id = sessions.node_session(node_id).expect("session").session_id;
+    client1.hibernate(&path).await?;
+
+    // Relay session is continued, instead of a handshake creating a new one.
+    let client1 = Client::restore(&path, crypto).await?;
+    std::fs::remove_file(&path).ok();
+    assert_eq!(client1.node_id(), node_id);
+    assert_eq!(
+        sessions
+            .node_session(node_id)
+            .map(|session| session.session_id),
+        Some(session_id)
+    );
+    assert_eq!(sessions.num_sessions(), 2);
+
+    let mut tx = client1.forward_unreliable(client2.node_id()).await?;
+    tx.send(vec![1u8].into()).await?;
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while !received.load(std::sync::atomic::Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    assert_eq!(sessions.num_sessions(), 2);
+    Ok(())
+}
@@ -1,15 +1,19 @@
 mod common;
 
+use anyhow::Context;
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
+use std::sync::atomic::Ordering::SeqCst;
 use std::time::Duration;
 use test_case::test_case;
-use ya_relay_client::{ClientBuilder, FailFast};
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
 use ya_relay_core::crypto::{CryptoProvider, FallbackCryptoProvider};
 use ya_relay_core::key::generate;
 use ya_relay_core::testing::TestServerWrapper;
 use ya_relay_server::testing::server::init_test_server;
 
+use common::{hack_make_ip_private, spawn_receive_for_client};
+
 enum Node {
     WithAlias,
     WithoutAlias,
@@ -94,3 +98,51 @@ async fn test_closed_session(node_to_shutdown: Node) -> anyhow::Result<()> {
     assert_eq!(nodes, expected_nodes, "2 does not see 1's default id");
     Ok(())
 }
+
+#[test_log::test(actix_rt::test)]
+async fn test_slot_invalidated_after_node_disconnects() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    // Forwarding goes through the relay, so the server knows who sends to whom.
+    hack_make_ip_private(&wrapper, &client1).await;
+    hack_make_ip_private(&wrapper, &client2).await;
+
+    let received = spawn_receive_for_client(&client2, ">> 2").await?;
+    let mut tx = client1.forward_unreliable(client2.node_id()).await?;
+    tx.send(vec![1u8].into()).await?;
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while !received.load(SeqCst) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    assert!(!client1.is_p2p(client2.node_id()).await);
+
+    let nodes = tuples_vec_to_map(client1.connected_nodes().await);
+    assert!(nodes.contains_key(&client2.node_id()));
+
+    // Node 2 doesn't tell 1 it's gone, only the server notices.
+    let sessions = wrapper.server.sessions();
+    let session = sessions
+        .node_session(client2.node_id())
+        .expect("Node 2 session");
+    sessions.remove_session(&session.session_id);
+
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while tuples_vec_to_map(client1.connected_nodes().await).contains_key(&client2.node_id()) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .context("1 still connected to 2 after its slot was invalidated")?;
+    Ok(())
+}
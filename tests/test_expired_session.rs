@@ -1,14 +1,22 @@
 mod common;
 
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
 use std::time::Duration;
 
 use common::{check_broadcast, check_forwarding, spawn_receive_for_client, Mode};
+use ya_relay_client::resume::ResumeState;
 use ya_relay_client::{ClientBuilder, FailFast};
+use ya_relay_core::clock::MockClock;
 use ya_relay_core::crypto::FallbackCryptoProvider;
+use ya_relay_core::intercept::Verdict;
 use ya_relay_core::testing::TestServerWrapper;
 use ya_relay_core::utils::to_udp_url;
+use ya_relay_proto::codec::PacketKind;
+use ya_relay_server::testing::network::{LinkConditions, NetworkConditions};
 use ya_relay_server::testing::server::{
-    init_test_server, init_test_server_with_config, test_default_config,
+    init_test_server, init_test_server_with_config, test_default_config, TestServerBuilder,
 };
 
 #[test_log::test(actix_rt::test)]
@@ -109,6 +117,100 @@ async fn test_restarting_p2p_session_tcp() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Client restarted after a crash closes sessions peers still have with it and opens
+/// its channels again, without waiting for expiration.
+#[test_log::test(actix_rt::test)]
+async fn test_resume_after_crash() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let path = std::env::temp_dir().join(format!("ya-relay-resume-{}.json", rand::random::<u64>()));
+    let crypto1 = FallbackCryptoProvider::default();
+    let crashed = Arc::new(AtomicBool::new(false));
+
+    let crashed_ = crashed.clone();
+    let mut client1 = ClientBuilder::from_url(wrapper.url())
+        .crypto(crypto1.clone())
+        .connect(FailFast::Yes)
+        .resume_state(&path)
+        .intercept(
+            move |_, _, _: &mut PacketKind| match crashed_.load(SeqCst) {
+                true => Verdict::Drop,
+                false => Verdict::Pass,
+            },
+        )
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let marker2 = spawn_receive_for_client(&client2, "Client2").await?;
+    let keep = check_forwarding(&client1, &client2, marker2.clone(), Mode::Reliable).await?;
+
+    let state = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match ResumeState::load(&path) {
+                Ok(Some(state)) if !state.channels.is_empty() => return state,
+                _ => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    })
+    .await?;
+    assert_eq!(state.sessions.len(), 1);
+    assert_eq!(state.sessions[0].node_id, client2.node_id());
+    assert_eq!(state.channels.len(), 1);
+    assert_eq!(state.channels[0].node_id, client2.node_id());
+
+    // Nothing sent on shutdown reaches the peer.
+    let addr1 = client1.bind_addr().await?;
+    crashed.store(true, SeqCst);
+    client1.shutdown().await?;
+    drop(keep);
+    let stale = client2.sessions().await;
+
+    // Socket is released after the client tasks are dropped. Still much shorter
+    // than the session expiration on the peer.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .listen(to_udp_url(addr1)?)
+        .crypto(crypto1)
+        .connect(FailFast::Yes)
+        .resume_state(&path)
+        .build()
+        .await?;
+
+    // Channel is opened again, though application didn't ask for it yet.
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let report = client1.diagnostics().await;
+            let connected = report.virtual_nodes.iter().any(|node| {
+                node.node_id == client2.node_id()
+                    && node
+                        .channels
+                        .iter()
+                        .any(|c| c.channel == "Messages-Out" && c.state == "Connected")
+            });
+            if connected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+    let sessions = client2.sessions().await;
+    assert!(
+        sessions
+            .iter()
+            .all(|session| stale.iter().all(|stale| stale.id != session.id)
+                || session.remote != addr1)
+    );
+
+    let _keep = check_forwarding(&client1, &client2, marker2, Mode::Reliable).await?;
+    std::fs::remove_file(&path).ok();
+    Ok(())
+}
+
 #[test_log::test(actix_rt::test)]
 async fn test_restarting_p2p_session_unreliable() -> anyhow::Result<()> {
     let wrapper = init_test_server().await?;
@@ -412,3 +514,189 @@ async fn test_fast_restart_unreliable() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Session expiration driven by mock clock, instead of waiting for real timeouts.
+#[test_log::test(actix_rt::test)]
+async fn test_server_session_expiration_mock_clock() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .link(LinkConditions::default())
+        .build()
+        .await?;
+    let network = wrapper.network.clone().unwrap();
+    let clock = MockClock::new();
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .expire_session_after(Duration::from_secs(1))
+        .clock(clock.clone())
+        .build()
+        .await?;
+    assert_eq!(client.sessions().await.len(), 1);
+
+    // Relay server becomes unreachable. With real time the session would be
+    // closed after expiration and ping timeout.
+    network.set_conditions(NetworkConditions::symmetric(
+        LinkConditions::default().loss(1.0),
+    ));
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    assert_eq!(client.sessions().await.len(), 1);
+
+    clock.wait_for_sleepers(1).await;
+    clock.advance(Duration::from_secs(2));
+
+    // Only the ping timeout runs in real time.
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !client.sessions().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+/// Suspended client neither pings nor expires sessions, until resumed.
+#[test_log::test(actix_rt::test)]
+async fn test_suspended_client_keeps_sessions() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .link(LinkConditions::default())
+        .build()
+        .await?;
+    let network = wrapper.network.clone().unwrap();
+    let clock = MockClock::new();
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .expire_session_after(Duration::from_secs(1))
+        .clock(clock.clone())
+        .build()
+        .await?;
+    assert_eq!(client.sessions().await.len(), 1);
+
+    clock.wait_for_sleepers(1).await;
+    client.suspend();
+    assert!(client.is_suspended());
+
+    network.set_conditions(NetworkConditions::symmetric(
+        LinkConditions::default().loss(1.0),
+    ));
+    clock.advance(Duration::from_secs(2));
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    assert_eq!(client.sessions().await.len(), 1);
+
+    // Expiration already passed, so the session is checked immediately.
+    client.resume();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !client.sessions().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+/// Idle client checks sessions after the idle expiration, and with the regular one
+/// after waking up.
+#[test_log::test(actix_rt::test)]
+async fn test_idle_client_lowers_keep_alive() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .link(LinkConditions::default())
+        .build()
+        .await?;
+    let network = wrapper.network.clone().unwrap();
+    let clock = MockClock::new();
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .expire_session_after(Duration::from_secs(1))
+        .expire_idle_session_after(Duration::from_secs(10))
+        .clock(clock.clone())
+        .build()
+        .await?;
+    assert_eq!(client.sessions().await.len(), 1);
+
+    clock.wait_for_sleepers(1).await;
+    client.idle();
+    assert!(client.is_idle());
+    // Let the expiration loop pick up the idle expiration.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    network.set_conditions(NetworkConditions::symmetric(
+        LinkConditions::default().loss(1.0),
+    ));
+    clock.advance(Duration::from_secs(2));
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    assert_eq!(client.sessions().await.len(), 1);
+
+    client.wake();
+    assert!(!client.is_idle());
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !client.sessions().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_server_session_purge_mock_clock() -> anyhow::Result<()> {
+    let clock = MockClock::new();
+    let wrapper = TestServerBuilder::new()
+        .session_cleaner_interval(Duration::from_secs(1))
+        .session_purge_timeout(Duration::from_secs(5))
+        .clock(clock.clone())
+        .build()
+        .await?;
+
+    // Client doesn't send anything on its own before its expiration time.
+    let _client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let sessions = wrapper.server.sessions();
+    assert_eq!(sessions.num_sessions(), 1);
+
+    clock.wait_for_sleepers(1).await;
+    clock.advance(Duration::from_secs(1));
+    clock.wait_for_sleepers(1).await;
+    assert_eq!(sessions.num_sessions(), 1);
+
+    // Last seen timestamps have seconds resolution, so leave a margin.
+    clock.advance(Duration::from_secs(7));
+    clock.wait_for_sleepers(1).await;
+    assert_eq!(sessions.num_sessions(), 0);
+    Ok(())
+}
+
+/// Sessions of Nodes, which stopped sending, are purged with the timeouts set on
+/// the builder.
+#[test_log::test(actix_rt::test)]
+async fn test_builder_session_purge() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .session_cleaner_interval(Duration::from_secs(1))
+        .session_purge_timeout(Duration::from_secs(3))
+        .build()
+        .await?;
+    let silent = Arc::new(AtomicBool::new(false));
+
+    let silent_ = silent.clone();
+    let _client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .intercept(move |_, _, _: &mut PacketKind| match silent_.load(SeqCst) {
+            true => Verdict::Drop,
+            false => Verdict::Pass,
+        })
+        .build()
+        .await?;
+    let sessions = wrapper.server.sessions();
+    assert_eq!(sessions.num_sessions(), 1);
+
+    silent.store(true, SeqCst);
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while sessions.num_sessions() > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
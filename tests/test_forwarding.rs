@@ -2,20 +2,30 @@ mod common;
 
 use anyhow::Context;
 use futures::StreamExt;
+use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::atomic::Ordering::SeqCst;
 use std::sync::atomic::{AtomicBool, AtomicUsize};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
-
-use ya_relay_client::channels::Forwarded;
-use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use tokio_stream::wrappers::ReceiverStream;
+
+use ya_relay_client::channels::{ForwardOptions, Forwarded, PoolConfig};
+use ya_relay_client::diagnostics::Diagnostics;
+use ya_relay_client::model::{NodeId, SessionType, SocketDesc, SocketState, TransportType};
+use ya_relay_client::{
+    Client, ClientBuilder, ClientError, ConnectError, ConnectProgress, ConnectionLimit, FailFast,
+    GenericSender, SenderError,
+};
+use ya_relay_core::crypto::ed25519::{self, Ed25519Crypto};
 use ya_relay_core::testing::TestServerWrapper;
-use ya_relay_server::testing::server::init_test_server;
+use ya_relay_server::testing::network::{LinkConditions, NetworkConditions, NetworkSimulator};
+use ya_relay_server::testing::server::{init_test_server, TestServerBuilder};
+use ya_relay_stack::smoltcp::socket::tcp;
 
 use common::hack_make_ip_private;
 use common::spawn_receive;
+use common::spawn_receive_for_client;
 
 #[test_log::test(actix_rt::test)]
 async fn test_forward_unreliable() -> anyhow::Result<()> {
@@ -114,6 +124,362 @@ async fn test_forward_reliable() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Ed25519 identities register at the relay and exchange data with secp256k1 ones.
+#[test_log::test(actix_rt::test)]
+async fn test_forward_ed25519() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let crypto = Ed25519Crypto::from(ed25519::generate());
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .crypto(crypto.clone())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    assert_eq!(client1.node_id(), crypto.node_id());
+    assert!(wrapper
+        .server
+        .sessions()
+        .node_session(client1.node_id())
+        .is_some());
+
+    hack_make_ip_private(&wrapper, &client1).await;
+    hack_make_ip_private(&wrapper, &client2).await;
+
+    let rx1 = client1
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+
+    let received1 = Rc::new(AtomicBool::new(false));
+    let received2 = Rc::new(AtomicBool::new(false));
+
+    spawn_receive(">> 1", received1.clone(), rx1);
+    spawn_receive(">> 2", received2.clone(), rx2);
+
+    let mut tx1 = client1.forward_unreliable(client2.node_id()).await?;
+    let mut tx2 = client2.forward_unreliable(client1.node_id()).await?;
+
+    tx1.send(vec![1u8].into()).await?;
+    tx2.send(vec![2u8].into()).await?;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(received1.load(SeqCst));
+    assert!(received2.load(SeqCst));
+
+    received1.store(false, SeqCst);
+    received2.store(false, SeqCst);
+
+    let mut tx1 = client1.forward_reliable(client2.node_id()).await?;
+    let mut tx2 = client2.forward_reliable(client1.node_id()).await?;
+
+    tx1.send(vec![3u8].into()).await?;
+    tx2.send(vec![4u8].into()).await?;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(received1.load(SeqCst));
+    assert!(received2.load(SeqCst));
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_forward_reliable_impaired_network() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let link = LinkConditions::default()
+        .latency(Duration::from_millis(20))
+        .jitter(Duration::from_millis(10))
+        .reorder(0.1)
+        .duplicate(0.1);
+    let network = NetworkSimulator::start(
+        wrapper.server.bind_addr(),
+        NetworkConditions::symmetric(link.clone()),
+    )
+    .await?;
+
+    let client1 = ClientBuilder::from_url(network.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(network.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper, &client1).await;
+    hack_make_ip_private(&wrapper, &client2).await;
+
+    let received1 = spawn_receive_for_client(&client1, ">> 1").await?;
+    let received2 = spawn_receive_for_client(&client2, ">> 2").await?;
+
+    let mut tx1 = client1.forward_reliable(client2.node_id()).await?;
+    let mut tx2 = client2.forward_reliable(client1.node_id()).await?;
+
+    // Virtual TCP has to recover from lost packets.
+    network.set_conditions(NetworkConditions::symmetric(link.loss(0.1)));
+
+    tx1.send(vec![1u8].into()).await?;
+    tx2.send(vec![2u8].into()).await?;
+
+    for _ in 0..50 {
+        if received1.load(SeqCst) && received2.load(SeqCst) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    assert!(received1.load(SeqCst));
+    assert!(received2.load(SeqCst));
+
+    let stats = network.stats();
+    assert!(stats.client_to_server.received > 0);
+    assert!(stats.server_to_client.received > 0);
+    Ok(())
+}
+
+/// Virtual TCP connection survives losing the session it was using, even if the
+/// first attempt to establish a new one fails.
+#[test_log::test(actix_rt::test)]
+async fn test_forward_reliable_reroute() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let network = NetworkSimulator::start(
+        wrapper.server.bind_addr(),
+        NetworkConditions::symmetric(LinkConditions::default()),
+    )
+    .await?;
+
+    let client1 = ClientBuilder::from_url(network.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(network.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper, &client1).await;
+    hack_make_ip_private(&wrapper, &client2).await;
+
+    let received2 = spawn_receive_for_client(&client2, ">> 2").await?;
+    let mut tx1 = client1.forward_reliable(client2.node_id()).await?;
+    tx1.send(vec![1u8].into()).await?;
+
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while !received2.load(SeqCst) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    received2.store(false, SeqCst);
+
+    // Relayed routing is gone, and the relay server doesn't respond to the next `FindNode`.
+    client1.disconnect(client2.node_id()).await?;
+    network.set_conditions(NetworkConditions::symmetric(
+        LinkConditions::default().loss(1.0),
+    ));
+    tx1.send(vec![2u8].into()).await?;
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    network.set_conditions(NetworkConditions::symmetric(LinkConditions::default()));
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !received2.load(SeqCst) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+    assert!(!client1.is_p2p(client2.node_id()).await);
+
+    // Connection wasn't closed in the meantime.
+    let report = client1.diagnostics().await;
+    let node = report
+        .virtual_nodes
+        .iter()
+        .find(|node| node.node_id == client2.node_id())
+        .expect("Virtual node");
+    let sockets = report
+        .sockets
+        .iter()
+        .filter(|socket| socket.remote.starts_with(&node.address))
+        .map(|socket| socket.state.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(sockets, vec!["Established"]);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_forward_reliable_progress() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let stages = Rc::new(RefCell::new(Vec::new()));
+    let record = {
+        let stages = stages.clone();
+        move |stage| stages.borrow_mut().push(stage)
+    };
+
+    client1
+        .forward_reliable_with_progress(client2.node_id(), record.clone())
+        .await?;
+    assert_eq!(
+        stages.take(),
+        vec![
+            ConnectProgress::Resolving,
+            ConnectProgress::Registered,
+            ConnectProgress::SynSent,
+            ConnectProgress::Established,
+        ]
+    );
+
+    client1
+        .forward_reliable_with_progress(client2.node_id(), record.clone())
+        .await?;
+    assert_eq!(stages.take(), vec![ConnectProgress::Established]);
+
+    let unknown = NodeId::from([0x2a; 20]);
+    let result = client1
+        .forward_reliable_with_progress(unknown, record)
+        .await;
+    assert!(
+        matches!(result, Err(ConnectError::Resolve(node_id, _)) if node_id == unknown),
+        "{:?}",
+        result.err()
+    );
+    assert_eq!(stages.take(), vec![ConnectProgress::Resolving]);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_virt_connection_limits() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .max_virt_connections(2)
+        .max_virt_connections_per_node(1)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client3 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client4 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    client1
+        .forward_reliable_with_progress(client2.node_id(), |_| ())
+        .await?;
+    let result = client1
+        .forward_transfer_with_progress(client2.node_id(), |_| ())
+        .await;
+    assert_eq!(
+        result.err(),
+        Some(ConnectError::TooManyConnections(
+            client2.node_id(),
+            ConnectionLimit::PerNode(1)
+        ))
+    );
+
+    client1
+        .forward_reliable_with_progress(client3.node_id(), |_| ())
+        .await?;
+    let result = client1
+        .forward_reliable_with_progress(client4.node_id(), |_| ())
+        .await;
+    assert_eq!(
+        result.err(),
+        Some(ConnectError::TooManyConnections(
+            client4.node_id(),
+            ConnectionLimit::Total(2)
+        ))
+    );
+    Ok(())
+}
+
+/// Payloads waiting for a stalled connection are dropped after their TTL.
+#[test_log::test(actix_rt::test)]
+async fn test_forward_reliable_ttl() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let network = NetworkSimulator::start(
+        wrapper.server.bind_addr(),
+        NetworkConditions::symmetric(LinkConditions::default()),
+    )
+    .await?;
+
+    let client1 = ClientBuilder::from_url(network.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(network.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper, &client1).await;
+    hack_make_ip_private(&wrapper, &client2).await;
+
+    let received2 = spawn_receive_for_client(&client2, ">> 2").await?;
+    let mut tx1 = client1.forward_reliable(client2.node_id()).await?;
+    let ttl = Duration::from_millis(300);
+    tx1.send_with_ttl(vec![1u8].into(), ttl).await?;
+
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while !received2.load(SeqCst) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+
+    network.set_conditions(NetworkConditions::symmetric(
+        LinkConditions::default().loss(1.0),
+    ));
+    // Fills socket buffer first, then the send queue.
+    let expired = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            match tx1.send_with_ttl(vec![2u8; 16 * 1024].into(), ttl).await {
+                Ok(()) => continue,
+                Err(e) => return e,
+            }
+        }
+    })
+    .await?;
+    assert!(
+        matches!(expired, SenderError::Expired(t) if t == ttl),
+        "{expired}"
+    );
+
+    network.set_conditions(NetworkConditions::symmetric(LinkConditions::default()));
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while client1.diagnostics().await.expired_payloads == 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
 #[test_log::test(actix_rt::test)]
 async fn test_p2p_unreliable() -> anyhow::Result<()> {
     let wrapper = init_test_server().await?;
@@ -205,6 +571,135 @@ async fn test_p2p_reliable() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test_log::test(actix_rt::test)]
+async fn test_forwarded_metadata() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client3 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client4 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper, &client3).await;
+    hack_make_ip_private(&wrapper, &client4).await;
+
+    let mut rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let mut rx3 = client3
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+
+    let started = SystemTime::now();
+    client1
+        .forward_unreliable(client2.node_id())
+        .await?
+        .send(vec![1u8].into())
+        .await?;
+    let mut reliable = client1.forward_reliable(client2.node_id()).await?;
+    reliable.send(vec![2u8].into()).await?;
+
+    let session_ids = client2
+        .sessions()
+        .await
+        .into_iter()
+        .map(|desc| desc.id)
+        .collect::<Vec<_>>();
+    for transport in [TransportType::Unreliable, TransportType::Reliable] {
+        let forwarded = tokio::time::timeout(Duration::from_secs(1), rx2.recv())
+            .await?
+            .context("receiver closed")?;
+
+        assert_eq!(forwarded.transport, transport);
+        assert_eq!(forwarded.node_id, client1.node_id());
+        assert!(forwarded.is_p2p());
+        let session = forwarded.session.context("no session")?;
+        assert!(session_ids.contains(&session.id));
+        assert!(forwarded.received_at >= started);
+    }
+
+    client4
+        .forward_unreliable(client3.node_id())
+        .await?
+        .send(vec![3u8].into())
+        .await?;
+
+    let forwarded = tokio::time::timeout(Duration::from_secs(1), rx3.recv())
+        .await?
+        .context("receiver closed")?;
+    let session = forwarded.session.context("no session")?;
+    assert_eq!(session.session_type, SessionType::Relay);
+    assert_eq!(
+        Some(session.id),
+        client3.sessions().await.first().map(|desc| desc.id)
+    );
+    Ok(())
+}
+
+/// Relay server asks the Node to slow down, when its forwards pile up.
+#[test_log::test(actix_rt::test)]
+async fn test_congestion_feedback() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .max_pending_forwards(2)
+        .build()
+        .await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper, &client1).await;
+    hack_make_ip_private(&wrapper, &client2).await;
+
+    let server_session = |report: Diagnostics| {
+        report
+            .sessions
+            .into_iter()
+            .find(|session| session.node_id == NodeId::default())
+            .expect("Relay server session")
+    };
+
+    let tx1 = client1.forward_unreliable(client2.node_id()).await?;
+    assert_eq!(
+        server_session(client1.diagnostics().await).congestion_reports,
+        0
+    );
+
+    let burst = (0..200)
+        .map(|_| tx1.clone())
+        .map(|mut tx| async move { tx.send(vec![0u8; 1024].into()).await });
+    for result in futures::future::join_all(burst).await {
+        result?;
+    }
+
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while server_session(client1.diagnostics().await).congestion_reports == 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
 #[test_log::test(actix_rt::test)]
 async fn test_rate_limiter() -> anyhow::Result<()> {
     let wrapper = init_test_server().await?;
@@ -230,12 +725,12 @@ async fn test_rate_limiter() -> anyhow::Result<()> {
     fn spawn_receive_counted(
         label: &'static str,
         received: Rc<AtomicUsize>,
-        rx: mpsc::UnboundedReceiver<Forwarded>,
+        rx: mpsc::Receiver<Forwarded>,
     ) {
         tokio::task::spawn_local({
             let received = received;
             async move {
-                UnboundedReceiverStream::new(rx)
+                ReceiverStream::new(rx)
                     .for_each(|item| {
                         let received = received.clone();
                         async move {
@@ -276,3 +771,209 @@ async fn test_rate_limiter() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+fn established_tcp(client: &Client) -> Vec<SocketDesc> {
+    client
+        .sockets()
+        .into_iter()
+        .filter_map(|(desc, state)| match state {
+            SocketState::Tcp {
+                state: tcp::State::Established,
+                ..
+            } => Some(desc),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_forward_reuses_pooled_connection() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let pool = PoolConfig {
+        max_idle: Some(Duration::from_secs(1)),
+        health_check: true,
+    };
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .connection_pool(pool)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let received = Rc::new(AtomicUsize::new(0));
+    let rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    tokio::task::spawn_local({
+        let received = received.clone();
+        ReceiverStream::new(rx2).for_each(move |item| {
+            received.fetch_add(item.payload.len(), SeqCst);
+            futures::future::ready(())
+        })
+    });
+
+    let node_id = client2.node_id();
+    let mut tx = client1
+        .forward(node_id, TransportType::Reliable, Default::default())
+        .await?;
+    tx.send(vec![1u8].into()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let first = established_tcp(&client1);
+    assert_eq!(first.len(), 1);
+
+    // Healthy connection is reused.
+    let mut tx = client1.forward_reliable(node_id).await?;
+    tx.send(vec![2u8].into()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(established_tcp(&client1), first);
+
+    let mut tx = client1
+        .forward(
+            node_id,
+            TransportType::Reliable,
+            ForwardOptions::force_new(),
+        )
+        .await?;
+    tx.send(vec![3u8].into()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let forced = established_tcp(&client1);
+    assert_eq!(forced.len(), 1);
+    assert_ne!(forced, first);
+
+    // Connection idle for longer than `max_idle` is replaced.
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    let mut tx = client1.forward_reliable(node_id).await?;
+    tx.send(vec![4u8].into()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let renewed = established_tcp(&client1);
+    assert_eq!(renewed.len(), 1);
+    assert_ne!(renewed, forced);
+
+    assert_eq!(received.load(SeqCst), 4);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_forward_to_address() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let received2 = spawn_receive_for_client(&client2, ">> 2").await?;
+
+    // Addresses of Nodes without a session can't be resolved.
+    let address = client1.virtual_address(client2.node_id());
+    assert_eq!(client1.resolve_address(address).await, None);
+    let result = client1
+        .forward_to_address(address, TransportType::Reliable, Default::default())
+        .await;
+    assert!(matches!(result, Err(ClientError::Other(_))));
+
+    let mut tx1 = client1.forward_unreliable(client2.node_id()).await?;
+    tx1.send(vec![1u8].into()).await?;
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while !received2.load(SeqCst) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    received2.store(false, SeqCst);
+    assert_eq!(
+        client1.resolve_address(address).await,
+        Some(client2.node_id())
+    );
+
+    let mut tx1 = client1
+        .forward_to_address(address, TransportType::Reliable, Default::default())
+        .await?;
+    tx1.send(vec![2u8].into()).await?;
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while !received2.load(SeqCst) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+
+    // Node known from its virtual TCP connection resolves back to the same id.
+    assert_eq!(
+        client2
+            .resolve_address(client2.virtual_address(client1.node_id()))
+            .await,
+        Some(client1.node_id())
+    );
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_p2p_survives_relay_restart() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let addr = wrapper.server.bind_addr();
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .expire_session_after(Duration::from_secs(2))
+        .relay_outage_grace(Duration::from_secs(30))
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .expire_session_after(Duration::from_secs(2))
+        .relay_outage_grace(Duration::from_secs(30))
+        .build()
+        .await?;
+
+    let received = Rc::new(AtomicUsize::new(0));
+    let rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    tokio::task::spawn_local({
+        let received = received.clone();
+        ReceiverStream::new(rx2).for_each(move |item| {
+            received.fetch_add(item.payload.len(), SeqCst);
+            futures::future::ready(())
+        })
+    });
+    let wait_for = |expected: usize| {
+        let received = received.clone();
+        tokio::time::timeout(Duration::from_secs(5), async move {
+            while received.load(SeqCst) < expected {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+    };
+
+    let node_id = client2.node_id();
+    let mut tx = client1.forward_reliable(node_id).await?;
+    tx.send(vec![1u8].into()).await?;
+    wait_for(1).await?;
+    assert!(client1.is_p2p(node_id).await);
+    let connections = established_tcp(&client1);
+    assert_eq!(connections.len(), 1);
+
+    // Relay is gone for longer than the session expiration, but within the grace period.
+    drop(wrapper);
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    tx.send(vec![2u8].into()).await?;
+    wait_for(2).await?;
+
+    let _wrapper = TestServerBuilder::new().bind_addr(addr).build().await?;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    tx.send(vec![3u8].into()).await?;
+    wait_for(3).await?;
+
+    assert!(client1.is_p2p(node_id).await);
+    assert_eq!(established_tcp(&client1), connections);
+    Ok(())
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use ya_relay_client::model::TransportType;
+
+use common::harness::Harness;
+
+#[test_log::test(actix_rt::test)]
+async fn test_mesh_exchange_p2p() -> anyhow::Result<()> {
+    let harness = Harness::start(4).await?;
+    harness.connect_mesh().await?;
+
+    let ids = harness.node_ids();
+    for node in harness.nodes() {
+        let peers = ids
+            .iter()
+            .copied()
+            .filter(|id| *id != node.node_id())
+            .collect::<Vec<_>>();
+        let p2p = node
+            .run(|client| async move {
+                let mut p2p = true;
+                for peer in peers {
+                    p2p &= client.is_p2p(peer).await;
+                }
+                p2p
+            })
+            .await?;
+        assert!(p2p);
+    }
+
+    harness
+        .exchange(TransportType::Reliable, 3)
+        .await?
+        .assert_complete(3);
+    harness
+        .exchange(TransportType::Unreliable, 2)
+        .await?
+        .assert_complete(2);
+
+    harness.shutdown().await;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_mesh_exchange_relayed() -> anyhow::Result<()> {
+    let harness = Harness::start(3).await?;
+    harness.force_relay().await?;
+    harness.connect_mesh().await?;
+
+    let ids = harness.node_ids();
+    let node = harness.node(0);
+    for id in &ids[1..] {
+        let id = *id;
+        assert!(
+            !node
+                .run(move |client| async move { client.is_p2p(id).await })
+                .await?
+        );
+    }
+
+    harness
+        .exchange(TransportType::Reliable, 2)
+        .await?
+        .assert_complete(2);
+
+    harness.shutdown().await;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_broadcast_delivery_matrix() -> anyhow::Result<()> {
+    let mut harness = Harness::start(4).await?;
+    harness.add_node().await?;
+
+    harness
+        .node(0)
+        .run(|client| async move { client.broadcast(vec![1u8], 4).await })
+        .await??;
+
+    let peers = harness.node_ids().len() - 1;
+    for node in &harness.nodes()[1..] {
+        node.wait_for(common::harness::DEFAULT_TIMEOUT, |packets| {
+            !packets.is_empty()
+        })
+        .await?;
+    }
+    let mut expected = vec![vec![0; peers + 1]; peers + 1];
+    expected[0][1..].iter_mut().for_each(|n| *n = 1);
+    let expected = expected.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    harness.deliveries().assert_eq(&expected);
+
+    harness.shutdown().await;
+    Ok(())
+}
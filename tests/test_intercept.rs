@@ -0,0 +1,294 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use common::harness::{Harness, DEFAULT_TIMEOUT};
+use ya_relay_client::model::TransportType;
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::forward_auth;
+use ya_relay_core::intercept::{Direction, Verdict};
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_proto::codec::PacketKind;
+use ya_relay_proto::proto::{
+    packet, request, response, Packet, Request, Response, FORWARD_SLOT_ID,
+};
+use ya_relay_server::testing::server::TestServerBuilder;
+use ya_relay_server::SessionManager;
+
+fn is_session_request(packet: &PacketKind) -> bool {
+    matches!(
+        packet,
+        PacketKind::Packet(Packet {
+            kind: Some(packet::Kind::Request(Request {
+                kind: Some(request::Kind::Session(_)),
+                ..
+            })),
+            ..
+        })
+    )
+}
+
+fn is_session_response(packet: &PacketKind) -> bool {
+    matches!(
+        packet,
+        PacketKind::Packet(Packet {
+            kind: Some(packet::Kind::Response(Response {
+                kind: Some(response::Kind::Session(_)),
+                ..
+            })),
+            ..
+        })
+    )
+}
+
+/// Applies `verdict` to the first `count` packets matching `filter` and passes the rest.
+fn first_n(
+    direction: Direction,
+    filter: fn(&PacketKind) -> bool,
+    count: usize,
+    verdict: Verdict,
+) -> (
+    Arc<AtomicUsize>,
+    impl Fn(Direction, SocketAddr, &mut PacketKind) -> Verdict,
+) {
+    let seen = Arc::new(AtomicUsize::new(0));
+    let interceptor = {
+        let seen = seen.clone();
+        move |dir: Direction, _: SocketAddr, packet: &mut PacketKind| {
+            if dir != direction || !filter(packet) {
+                return Verdict::Pass;
+            }
+            match seen.fetch_add(1, SeqCst) < count {
+                true => verdict,
+                false => Verdict::Pass,
+            }
+        }
+    };
+    (seen, interceptor)
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_lost_session_requests_are_retransmitted() -> anyhow::Result<()> {
+    let (seen, interceptor) = first_n(Direction::Incoming, is_session_request, 2, Verdict::Drop);
+    let wrapper = TestServerBuilder::new()
+        .intercept(interceptor)
+        .build()
+        .await?;
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    assert!(seen.load(SeqCst) > 2);
+    assert_eq!(wrapper.server.sessions().num_sessions(), 1);
+    assert!(client.neighbours(5).await.is_ok());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_delayed_session_responses_arrive_twice() -> anyhow::Result<()> {
+    // Client retransmits each request before the delayed response arrives,
+    // so every handshake step is handled and answered twice.
+    let (seen, interceptor) = first_n(
+        Direction::Outgoing,
+        is_session_response,
+        usize::MAX,
+        Verdict::Delay(Duration::from_millis(1000)),
+    );
+    let wrapper = TestServerBuilder::new()
+        .intercept(interceptor)
+        .build()
+        .await?;
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    // Wait for the duplicates.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    assert!(seen.load(SeqCst) > 2);
+    assert_eq!(wrapper.server.sessions().num_sessions(), 1);
+    assert_eq!(client.sessions().await.len(), 1);
+    assert!(client.neighbours(5).await.is_ok());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_out_of_order_handshake() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new().build().await?;
+
+    // The first handshake packet reaches the server after the session was established.
+    let (seen, interceptor) = first_n(
+        Direction::Outgoing,
+        is_session_request,
+        1,
+        Verdict::Delay(Duration::from_millis(2500)),
+    );
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .intercept(interceptor)
+        .build()
+        .await?;
+    assert_eq!(wrapper.server.sessions().num_sessions(), 1);
+
+    tokio::time::sleep(Duration::from_millis(3000)).await;
+
+    assert!(seen.load(SeqCst) > 2);
+    assert_eq!(wrapper.server.sessions().num_sessions(), 1);
+    assert!(client.neighbours(5).await.is_ok());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_server_modifies_forwarded_payload() -> anyhow::Result<()> {
+    let sessions = Arc::new(OnceLock::<Arc<SessionManager>>::new());
+    let wrapper = TestServerBuilder::new()
+        .intercept({
+            let sessions = sessions.clone();
+            move |dir: Direction, _: SocketAddr, packet: &mut PacketKind| {
+                if let (Direction::Incoming, PacketKind::Forward(forward)) = (dir, packet) {
+                    // Forwards are authenticated, so the payload has to be signed again.
+                    let key = sessions.get().and_then(|sessions| {
+                        sessions
+                            .session(&SessionId::from(forward.session_id))?
+                            .forward_key
+                    });
+                    if let Some(key) = key {
+                        if forward_auth::verify(&key, forward) {
+                            forward.payload.as_mut().make_ascii_uppercase();
+                            forward_auth::sign(&key, forward);
+                        }
+                    }
+                }
+                Verdict::Pass
+            }
+        })
+        .build()
+        .await?;
+    sessions.set(wrapper.server.sessions()).ok();
+
+    let harness = Harness::with_server(wrapper, 2).await?;
+    harness.force_relay().await?;
+
+    let receiver = harness.node(1).node_id();
+    harness
+        .node(0)
+        .run(move |client| async move {
+            let mut tx = client.forward_unreliable(receiver).await?;
+            tx.send(b"hello".to_vec().into()).await?;
+            anyhow::Ok(())
+        })
+        .await??;
+
+    let node = harness.node(1);
+    node.wait_for(DEFAULT_TIMEOUT, |packets| !packets.is_empty())
+        .await?;
+    let received = node.received();
+    assert_eq!(received[0].transport, TransportType::Unreliable);
+    assert_eq!(received[0].payload, b"HELLO");
+
+    harness.shutdown().await;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_relay_direct_slot_rejected() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new().build().await?;
+    let url = wrapper.url();
+
+    let mut harness = Harness::with_server(wrapper, 1).await?;
+    // Relay attributes forwarded packets to itself.
+    harness
+        .add_node_with(move || {
+            ClientBuilder::from_url(url).intercept(
+                |dir: Direction, _: SocketAddr, packet: &mut PacketKind| {
+                    if let (Direction::Incoming, PacketKind::Forward(forward)) = (dir, packet) {
+                        forward.slot = FORWARD_SLOT_ID;
+                    }
+                    Verdict::Pass
+                },
+            )
+        })
+        .await?;
+    harness.force_relay().await?;
+
+    let receiver = harness.node(1).node_id();
+    harness
+        .node(0)
+        .run(move |client| async move {
+            let mut tx = client.forward_unreliable(receiver).await?;
+            tx.send(b"hello".to_vec().into()).await?;
+            anyhow::Ok(())
+        })
+        .await??;
+
+    let node = harness.node(1);
+    assert!(node
+        .wait_for(Duration::from_secs(2), |packets| !packets.is_empty())
+        .await
+        .is_err());
+
+    harness.shutdown().await;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_p2p_peer_cannot_forward_for_others() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new().build().await?;
+
+    // Slot of Node impersonated by the sender. Forwards are left intact while 0.
+    let spoofed = Arc::new(AtomicU32::new(0));
+    let interceptor = {
+        let spoofed = spoofed.clone();
+        move |dir: Direction, _: SocketAddr, packet: &mut PacketKind| {
+            let slot = spoofed.load(SeqCst);
+            if let (Direction::Outgoing, PacketKind::Forward(forward)) = (dir, packet) {
+                if slot != 0 {
+                    forward.slot = slot;
+                }
+            }
+            Verdict::Pass
+        }
+    };
+
+    let sender = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .intercept(interceptor)
+        .build()
+        .await?;
+    let receiver = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let impersonated = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let mut rx = receiver.forward_receiver().await.unwrap();
+    let mut tx = sender.forward_unreliable(receiver.node_id()).await?;
+    assert!(sender.is_p2p(receiver.node_id()).await);
+
+    let slot = sender.find_node(impersonated.node_id()).await?.slot;
+    spoofed.store(slot, SeqCst);
+    tx.send(b"spoofed".to_vec().into()).await?;
+    assert!(tokio::time::timeout(Duration::from_secs(2), rx.recv())
+        .await
+        .is_err());
+
+    spoofed.store(0, SeqCst);
+    tx.send(b"genuine".to_vec().into()).await?;
+    let forwarded = tokio::time::timeout(DEFAULT_TIMEOUT, rx.recv())
+        .await?
+        .unwrap();
+    assert_eq!(forwarded.node_id, sender.node_id());
+    assert_eq!(forwarded.payload.as_ref(), b"genuine");
+    Ok(())
+}
@@ -0,0 +1,259 @@
+mod common;
+
+use std::time::Duration;
+
+use ya_relay_client::channels::ForwardReceiver;
+use ya_relay_client::mesh::{Mesh, MeshConfig, MAGIC};
+use ya_relay_client::model::{NodeId, TransportType};
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+async fn start_client(wrapper: &ServerWrapper) -> anyhow::Result<Client> {
+    ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await
+}
+
+async fn next(rx: &mut ForwardReceiver) -> Option<(TransportType, NodeId, Vec<u8>)> {
+    tokio::time::timeout(Duration::from_secs(3), rx.recv())
+        .await
+        .ok()
+        .flatten()
+        .map(|f| (f.transport, f.node_id, f.payload.into_vec()))
+}
+
+/// Routed packet with hop limit 1 and sequence 1, signed by `signer`.
+async fn signed_packet(
+    signer: &Client,
+    origin: NodeId,
+    destination: NodeId,
+    payload: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let hop_limit = 1u8;
+    let sequence = 1u64.to_be_bytes();
+
+    let mut signed = MAGIC.to_vec();
+    signed.push(hop_limit);
+    signed.extend(sequence);
+    signed.extend(origin.into_array());
+    signed.extend(destination.into_array());
+    signed.extend(payload);
+    let signature = signer.sign(&signed).await?;
+
+    let mut packet = MAGIC.to_vec();
+    packet.extend([hop_limit, 0]);
+    packet.extend(sequence);
+    packet.extend(origin.into_array());
+    packet.extend(destination.into_array());
+    packet.extend((signature.len() as u16).to_be_bytes());
+    packet.extend(signature);
+    packet.extend(payload);
+    Ok(packet)
+}
+
+fn forwarder() -> MeshConfig {
+    MeshConfig {
+        forward: true,
+        ..Default::default()
+    }
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_mesh_route_through_forwarder() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let origin = start_client(&wrapper).await?;
+    let via = start_client(&wrapper).await?;
+    let destination = start_client(&wrapper).await?;
+
+    let (origin_mesh, _origin_rx) = Mesh::start(&origin, Default::default()).await?;
+    let (_via_mesh, mut via_rx) = Mesh::start(&via, forwarder()).await?;
+    let (_dst_mesh, mut dst_rx) = Mesh::start(&destination, Default::default()).await?;
+
+    origin_mesh.add_route(destination.node_id(), via.node_id());
+    origin_mesh.send(destination.node_id(), b"routed").await?;
+
+    // Destination sees the packet coming from its origin.
+    assert_eq!(
+        next(&mut dst_rx).await,
+        Some((
+            TransportType::Unreliable,
+            origin.node_id(),
+            b"routed".to_vec()
+        ))
+    );
+    assert_eq!(next(&mut via_rx).await, None);
+
+    // Without route packets are sent directly.
+    origin_mesh.remove_route(destination.node_id());
+    origin_mesh.send(destination.node_id(), b"direct").await?;
+    assert_eq!(
+        next(&mut dst_rx).await,
+        Some((
+            TransportType::Unreliable,
+            origin.node_id(),
+            b"direct".to_vec()
+        ))
+    );
+
+    // Other traffic is passed through.
+    let mut tx = origin.forward_unreliable(destination.node_id()).await?;
+    tx.send(b"plain".to_vec().into()).await?;
+    assert_eq!(
+        next(&mut dst_rx).await,
+        Some((
+            TransportType::Unreliable,
+            origin.node_id(),
+            b"plain".to_vec()
+        ))
+    );
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_mesh_not_forwarding() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let origin = start_client(&wrapper).await?;
+    let via = start_client(&wrapper).await?;
+    let destination = start_client(&wrapper).await?;
+
+    let (origin_mesh, _origin_rx) = Mesh::start(&origin, Default::default()).await?;
+    let (_via_mesh, _via_rx) = Mesh::start(&via, Default::default()).await?;
+    let (_dst_mesh, mut dst_rx) = Mesh::start(&destination, Default::default()).await?;
+
+    origin_mesh.add_route(destination.node_id(), via.node_id());
+    origin_mesh.send(destination.node_id(), b"routed").await?;
+    assert_eq!(next(&mut dst_rx).await, None);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_mesh_hop_limit() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let origin = start_client(&wrapper).await?;
+    let first = start_client(&wrapper).await?;
+    let second = start_client(&wrapper).await?;
+    let destination = start_client(&wrapper).await?;
+
+    let config = MeshConfig {
+        hop_limit: 1,
+        ..Default::default()
+    };
+    let (origin_mesh, _origin_rx) = Mesh::start(&origin, config).await?;
+    let (first_mesh, _first_rx) = Mesh::start(&first, forwarder()).await?;
+    let (second_mesh, _second_rx) = Mesh::start(&second, forwarder()).await?;
+    let (_dst_mesh, mut dst_rx) = Mesh::start(&destination, Default::default()).await?;
+
+    // Single forwarder fits in the limit.
+    origin_mesh.add_route(destination.node_id(), first.node_id());
+    origin_mesh.send(destination.node_id(), b"one hop").await?;
+    assert_eq!(
+        next(&mut dst_rx).await,
+        Some((
+            TransportType::Unreliable,
+            origin.node_id(),
+            b"one hop".to_vec()
+        ))
+    );
+
+    first_mesh.add_route(destination.node_id(), second.node_id());
+    origin_mesh.send(destination.node_id(), b"two hops").await?;
+    assert_eq!(next(&mut dst_rx).await, None);
+
+    // Routing loop is broken by the hop limit too.
+    second_mesh.add_route(destination.node_id(), first.node_id());
+    origin_mesh.send(destination.node_id(), b"loop").await?;
+    assert_eq!(next(&mut dst_rx).await, None);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_mesh_rejects_forged_origin() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let origin = start_client(&wrapper).await?;
+    let forger = start_client(&wrapper).await?;
+    let destination = start_client(&wrapper).await?;
+
+    let (_dst_mesh, mut dst_rx) = Mesh::start(&destination, Default::default()).await?;
+
+    // Well formed packet claiming to be sent by `origin`, but signed by `forger`.
+    let packet = signed_packet(&forger, origin.node_id(), destination.node_id(), b"forged").await?;
+    let mut tx = forger.forward_unreliable(destination.node_id()).await?;
+    tx.send(packet.into()).await?;
+    assert_eq!(next(&mut dst_rx).await, None);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_mesh_drops_replayed_packets() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let origin = start_client(&wrapper).await?;
+    let attacker = start_client(&wrapper).await?;
+    let destination = start_client(&wrapper).await?;
+
+    let (_dst_mesh, mut dst_rx) = Mesh::start(&destination, Default::default()).await?;
+
+    let packet = signed_packet(&origin, origin.node_id(), destination.node_id(), b"once").await?;
+    let mut tx = attacker.forward_unreliable(destination.node_id()).await?;
+
+    tx.send(packet.clone().into()).await?;
+    assert_eq!(
+        next(&mut dst_rx).await,
+        Some((
+            TransportType::Unreliable,
+            origin.node_id(),
+            b"once".to_vec()
+        ))
+    );
+
+    tx.send(packet.into()).await?;
+    assert_eq!(next(&mut dst_rx).await, None);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_mesh_forwarder_cant_reset_hops() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let origin = start_client(&wrapper).await?;
+    let rogue = start_client(&wrapper).await?;
+    let first = start_client(&wrapper).await?;
+    let destination = start_client(&wrapper).await?;
+
+    let config = MeshConfig {
+        hop_limit: 1,
+        ..Default::default()
+    };
+    let (origin_mesh, _origin_rx) = Mesh::start(&origin, config).await?;
+    let (first_mesh, _first_rx) = Mesh::start(&first, forwarder()).await?;
+    let (_dst_mesh, mut dst_rx) = Mesh::start(&destination, Default::default()).await?;
+    // Rogue Node passes packets on without Mesh.
+    let mut rogue_rx = rogue.forward_receiver().await.unwrap();
+
+    // Packets loop between the forwarder and the rogue Node.
+    origin_mesh.add_route(destination.node_id(), rogue.node_id());
+    first_mesh.add_route(destination.node_id(), rogue.node_id());
+
+    origin_mesh.send(destination.node_id(), b"loop").await?;
+    let packet = next(&mut rogue_rx).await.unwrap().2;
+    let mut tx = rogue.forward_unreliable(first.node_id()).await?;
+
+    tx.send(packet.into()).await?;
+    let mut looped = next(&mut rogue_rx).await.unwrap().2;
+    assert_eq!(looped[5], 1);
+
+    // Forwarder passes the packet on once, even with the hop count reset.
+    looped[5] = 0;
+    tx.send(looped.into()).await?;
+    assert_eq!(next(&mut rogue_rx).await, None);
+
+    // Raising the hop limit invalidates the signature.
+    origin_mesh.send(destination.node_id(), b"raised").await?;
+    let mut packet = next(&mut rogue_rx).await.unwrap().2;
+    packet[4] = 10;
+    tx.send(packet.into()).await?;
+    assert_eq!(next(&mut rogue_rx).await, None);
+
+    assert_eq!(next(&mut dst_rx).await, None);
+    Ok(())
+}
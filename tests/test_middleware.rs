@@ -0,0 +1,135 @@
+mod common;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Duration;
+
+use ya_relay_client::channels::Forwarded;
+use ya_relay_client::middleware::{Middleware, Verdict};
+use ya_relay_client::model::{NodeId, TransportType};
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender, SenderError};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_proto::proto::Payload;
+use ya_relay_server::testing::server::init_test_server;
+
+/// Appends a byte to outgoing payloads and rejects empty ones.
+#[derive(Default)]
+struct Tagging {
+    sent: Rc<Cell<usize>>,
+}
+
+impl Middleware for Tagging {
+    fn egress(
+        &self,
+        _node_id: NodeId,
+        _transport: TransportType,
+        payload: &mut Payload,
+    ) -> Verdict {
+        if payload.is_empty() {
+            return Verdict::Reject("empty".to_string());
+        }
+        self.sent.set(self.sent.get() + 1);
+        *payload = [payload.as_ref(), &[0xff]].concat().into();
+        Verdict::Pass
+    }
+}
+
+/// Drops incoming payloads starting with zero.
+#[derive(Default)]
+struct Filtering {
+    seen: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl Middleware for Filtering {
+    fn ingress(&self, forwarded: &mut Forwarded) -> Verdict {
+        self.seen
+            .borrow_mut()
+            .push(forwarded.payload.as_ref().to_vec());
+        match forwarded.payload.as_ref().first() {
+            Some(0) => Verdict::Reject("starts with zero".to_string()),
+            _ => Verdict::Pass,
+        }
+    }
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_middleware_egress_and_ingress() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let tagging = Tagging::default();
+    let sent = tagging.sent.clone();
+    let filtering = Filtering::default();
+    let seen = filtering.seen.clone();
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .middleware(tagging)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .middleware(filtering)
+        .build()
+        .await?;
+    let mut rx = client2.forward_receiver().await.unwrap();
+
+    let mut tx = client1.forward_unreliable(client2.node_id()).await?;
+    assert!(matches!(
+        tx.send(Vec::new().into()).await,
+        Err(SenderError::Rejected(_))
+    ));
+    tx.send(vec![0u8, 1].into()).await?;
+    tx.send(vec![2u8, 3].into()).await?;
+
+    let forwarded = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await?
+        .unwrap();
+    assert_eq!(forwarded.payload.into_vec(), vec![2u8, 3, 0xff]);
+    assert_eq!(sent.get(), 2);
+    assert_eq!(*seen.borrow(), vec![vec![0u8, 1, 0xff], vec![2u8, 3, 0xff]]);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_middleware_order() -> anyhow::Result<()> {
+    struct Push(u8);
+
+    impl Middleware for Push {
+        fn egress(&self, _: NodeId, _: TransportType, payload: &mut Payload) -> Verdict {
+            *payload = [payload.as_ref(), &[self.0]].concat().into();
+            Verdict::Pass
+        }
+
+        fn ingress(&self, forwarded: &mut Forwarded) -> Verdict {
+            // Undoes `egress`, so it only passes if applied in reverse order.
+            let payload = forwarded.payload.as_ref();
+            match payload.split_last() {
+                Some((last, rest)) if *last == self.0 => {
+                    forwarded.payload = rest.to_vec().into();
+                    Verdict::Pass
+                }
+                _ => Verdict::Reject(format!("expected {}", self.0)),
+            }
+        }
+    }
+
+    let wrapper = init_test_server().await?;
+    let build = || {
+        ClientBuilder::from_url(wrapper.url())
+            .connect(FailFast::Yes)
+            .middleware(Push(1))
+            .middleware(Push(2))
+            .build()
+    };
+    let client1 = build().await?;
+    let client2 = build().await?;
+    let mut rx = client2.forward_receiver().await.unwrap();
+
+    let mut tx = client1.forward_unreliable(client2.node_id()).await?;
+    tx.send(vec![7u8].into()).await?;
+
+    let forwarded = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await?
+        .unwrap();
+    assert_eq!(forwarded.payload.into_vec(), vec![7u8]);
+    Ok(())
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::time::Duration;
+
+use ya_relay_client::channels::ForwardReceiver;
+use ya_relay_client::model::{NodeId, TransportType};
+use ya_relay_client::naming::{NameDirectory, NameResolver};
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+async fn start_client(wrapper: &ServerWrapper) -> anyhow::Result<Client> {
+    ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await
+}
+
+async fn next(rx: &mut ForwardReceiver) -> Option<(TransportType, NodeId, Vec<u8>)> {
+    tokio::time::timeout(Duration::from_secs(3), rx.recv())
+        .await
+        .ok()
+        .flatten()
+        .map(|f| (f.transport, f.node_id, f.payload.into_vec()))
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_resolve_name_at_directory() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let directory = start_client(&wrapper).await?;
+    let resolving = start_client(&wrapper).await?;
+    let storage = start_client(&wrapper).await?;
+
+    let (names, mut directory_rx) =
+        NameDirectory::start(&directory, Duration::from_secs(60)).await?;
+    let (resolver, _resolver_rx) = NameResolver::start(&resolving, directory.node_id()).await?;
+    names.insert("storage.example", storage.node_id())?;
+
+    assert_eq!(
+        resolver.resolve("storage.example").await?,
+        Some(storage.node_id())
+    );
+    assert_eq!(resolver.resolve("unknown.example").await?, None);
+
+    // Answers are cached until invalidated.
+    names.insert("storage.example", directory.node_id())?;
+    assert_eq!(
+        resolver.resolve("storage.example").await?,
+        Some(storage.node_id())
+    );
+    resolver.invalidate("storage.example");
+    assert_eq!(
+        resolver.resolve("storage.example").await?,
+        Some(directory.node_id())
+    );
+    assert!(resolver.resolve("").await.is_err());
+
+    // Other reliable traffic reaches the application.
+    let mut tx = resolving.forward_reliable(directory.node_id()).await?;
+    tx.send(b"plain".to_vec().into()).await?;
+    assert_eq!(
+        next(&mut directory_rx).await,
+        Some((
+            TransportType::Reliable,
+            resolving.node_id(),
+            b"plain".to_vec()
+        ))
+    );
+
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_resolve_times_out_without_directory() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let not_directory = start_client(&wrapper).await?;
+    let resolving = start_client(&wrapper).await?;
+
+    let (resolver, _rx) = NameResolver::start(&resolving, not_directory.node_id()).await?;
+    assert!(resolver.resolve("storage.example").await.is_err());
+
+    Ok(())
+}
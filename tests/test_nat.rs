@@ -0,0 +1,167 @@
+mod common;
+
+use std::time::Duration;
+
+use test_case::test_case;
+
+use common::{check_forwarding, spawn_receive_for_client, Mode};
+use ya_relay_client::model::NatType as Classified;
+use ya_relay_client::{Client, ClientBuilder, FailFast};
+use ya_relay_core::testing::nat::{Nat, NatType};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+async fn client_behind(wrapper: &ServerWrapper, nat: &Nat) -> anyhow::Result<Client> {
+    ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .transport(nat.clone())
+        .build()
+        .await
+}
+
+#[test_case(NatType::FullCone, true ; "full cone")]
+#[test_case(NatType::AddressRestricted, true ; "address restricted")]
+#[test_case(NatType::PortRestricted, false ; "port restricted")]
+#[test_case(NatType::Symmetric, false ; "symmetric")]
+#[test_log::test(actix_rt::test)]
+async fn test_public_address_detection(nat_type: NatType, public: bool) -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let nat = Nat::new(nat_type);
+    let client = client_behind(&wrapper, &nat).await?;
+
+    // Server probes the address from a different port than the one Node is talking to.
+    let public_addr = client.public_addr().await;
+    assert_eq!(public_addr.is_some(), public);
+    if let Some(addr) = public_addr {
+        let ports = nat
+            .external_addrs()
+            .iter()
+            .map(|a| a.port())
+            .collect::<Vec<_>>();
+        assert_eq!(ports, vec![addr.port()]);
+    } else {
+        assert!(nat.dropped() > 0);
+    }
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_public_address_change() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let nat = Nat::new(NatType::FullCone);
+    let client = client_behind(&wrapper, &nat).await?;
+
+    let mut changes = client.public_addr_changes();
+    let before = client.public_addr().await.expect("Public address");
+    assert_eq!(*changes.borrow_and_update(), Some(before));
+
+    // Address stays the same, so reconnecting isn't reported.
+    client.reconnect_server().await;
+    assert_eq!(client.public_addr().await, Some(before));
+    assert!(!changes.has_changed()?);
+
+    nat.reset();
+    client.reconnect_server().await;
+    tokio::time::timeout(Duration::from_secs(3), changes.changed()).await??;
+
+    let after = changes.borrow_and_update().expect("Public address");
+    assert_ne!(after.port(), before.port());
+    assert_eq!(
+        nat.external_addrs()
+            .iter()
+            .map(|a| a.port())
+            .collect::<Vec<_>>(),
+        vec![after.port()]
+    );
+    assert_eq!(client.public_addr().await, Some(after));
+    Ok(())
+}
+
+#[test_case(NatType::FullCone, Classified::Cone, true ; "full cone")]
+#[test_case(NatType::AddressRestricted, Classified::Cone, true ; "address restricted")]
+#[test_case(NatType::PortRestricted, Classified::PortRestricted, true ; "port restricted")]
+#[test_case(NatType::Symmetric, Classified::Symmetric, false ; "symmetric")]
+#[test_log::test(actix_rt::test)]
+async fn test_nat_classification(
+    nat_type: NatType,
+    expected: Classified,
+    hairpinning: bool,
+) -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let nat = Nat::new(nat_type);
+    let client = client_behind(&wrapper, &nat).await?;
+    assert_eq!(client.nat_info(), None);
+
+    let info = client.check_nat().await?;
+    assert_eq!(info.nat_type, expected);
+    assert_eq!(info.hairpinning, hairpinning);
+    // Mapping used for the relay server.
+    let ports = nat
+        .external_addrs()
+        .iter()
+        .map(|a| a.port())
+        .collect::<Vec<_>>();
+    assert!(ports.contains(&info.external_addr.port()));
+    assert_eq!(client.nat_info(), Some(info));
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_nat_classification_without_nat() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let info = client.check_nat().await?;
+    assert_eq!(info.nat_type, Classified::Cone);
+    assert!(info.hairpinning);
+    assert_eq!(Some(info.external_addr), client.public_addr().await);
+    Ok(())
+}
+
+#[test_case(NatType::FullCone ; "full cone")]
+#[test_case(NatType::AddressRestricted ; "address restricted")]
+#[test_case(NatType::PortRestricted ; "port restricted")]
+#[test_case(NatType::Symmetric ; "symmetric")]
+#[test_log::test(actix_rt::test)]
+async fn test_p2p_with_public_node(nat_type: NatType) -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let nat = Nat::new(nat_type);
+    let private = client_behind(&wrapper, &nat).await?;
+    let public = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let received = spawn_receive_for_client(&private, "private").await?;
+
+    // Node behind restricting NAT is reached with reverse connection.
+    let _tx = check_forwarding(&public, &private, received, Mode::Unreliable).await?;
+    assert!(public.is_p2p(private.node_id()).await);
+    assert!(private.is_p2p(public.node_id()).await);
+
+    if nat_type == NatType::Symmetric {
+        // Separate mappings for the relay server and the other Node.
+        assert_eq!(nat.external_addrs().len(), 2);
+    }
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_relayed_between_restricted_nats() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client1 = client_behind(&wrapper, &Nat::new(NatType::PortRestricted)).await?;
+    let client2 = client_behind(&wrapper, &Nat::new(NatType::Symmetric)).await?;
+
+    let received = spawn_receive_for_client(&client2, "client2").await?;
+
+    let _tx = check_forwarding(&client1, &client2, received, Mode::Unreliable).await?;
+    assert!(!client1.is_p2p(client2.node_id()).await);
+
+    // Node addresses are unreachable, so the session stays relayed.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!client2.is_p2p(client1.node_id()).await);
+    Ok(())
+}
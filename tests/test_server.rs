@@ -1,10 +1,22 @@
 mod common;
 
 use common::{check_broadcast, spawn_receive_for_client};
+use std::convert::TryFrom;
 use std::net::UdpSocket;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
 use ya_relay_client::{ClientBuilder, FailFast};
+use ya_relay_core::challenge::{self, ChallengeDigest};
+use ya_relay_core::crypto::{CryptoProvider, FallbackCryptoProvider};
+use ya_relay_core::forward_auth::{self, KeyExchange};
+use ya_relay_core::server_session::SessionId;
 use ya_relay_core::testing::TestServerWrapper;
-use ya_relay_server::testing::server::init_test_server;
+use ya_relay_proto::codec::BytesMut;
+use ya_relay_proto::proto::{
+    control, packet, request, response, Forward, Message, Packet, Response, StatusCode,
+};
+use ya_relay_server::testing::server::{init_test_server, TestServerBuilder};
+use ya_relay_server::SessionEventKind;
 
 /// Server should not shutdown when receives junks (single, garbage bytes).
 /// Testing if server does not shutdown when receives junks.
@@ -39,3 +51,340 @@ async fn test_server_junks_received() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Nodes which weren't reachable during registration are checked again, and Nodes which
+/// looked them up are told to connect directly.
+#[test_log::test(actix_rt::test)]
+async fn test_server_refreshes_invalid_addr_status() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .addr_refresh(Duration::from_millis(200), Duration::from_millis(500))
+        .build()
+        .await?;
+    let mut events = wrapper.server.sessions().subscribe();
+
+    let public = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let watcher = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    wrapper.remove_node_endpoints(public.node_id()).await;
+    let node = watcher.find_node(public.node_id()).await?;
+    assert!(node.endpoints.is_empty());
+
+    let reachable = tokio::time::timeout(Duration::from_secs(3), async {
+        loop {
+            let event = events.recv().await?;
+            if event.kind == SessionEventKind::Reachable {
+                return anyhow::Ok(event.node_id);
+            }
+        }
+    })
+    .await??;
+    assert_eq!(reachable, public.node_id());
+
+    let node = watcher.find_node(public.node_id()).await?;
+    assert!(!node.endpoints.is_empty());
+
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while !watcher.is_p2p(public.node_id()).await {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+async fn exchange(socket: &tokio::net::UdpSocket, packet: &Packet) -> anyhow::Result<Packet> {
+    socket.send(&packet.encode_to_vec()).await?;
+    let mut buf = vec![0u8; 65536];
+    let size = tokio::time::timeout(Duration::from_secs(3), socket.recv(&mut buf)).await??;
+    Ok(Packet::decode(&buf[..size])?)
+}
+
+fn status(packet: &Packet) -> anyhow::Result<StatusCode> {
+    match &packet.kind {
+        Some(packet::Kind::Response(response)) => Ok(StatusCode::try_from(response.code)?),
+        _ => anyhow::bail!("Expected response"),
+    }
+}
+
+/// Starts the session with the server and returns the solved challenge, as it would be
+/// captured from the network.
+async fn challenge_response(socket: &tokio::net::UdpSocket) -> anyhow::Result<Packet> {
+    let packet = exchange(
+        socket,
+        &Packet::request(vec![], request::Session::default()),
+    )
+    .await?;
+    let challenge_req = match packet.kind {
+        Some(packet::Kind::Response(Response {
+            kind: Some(response::Kind::Session(response::Session { challenge_req, .. })),
+            ..
+        })) => challenge_req.ok_or_else(|| anyhow::anyhow!("Missing challenge"))?,
+        _ => anyhow::bail!("Expected session response"),
+    };
+
+    let crypto = FallbackCryptoProvider::default();
+    let identity = crypto.get(crypto.default_node_id()).await?;
+    let challenge_resp = challenge::solve::<ChallengeDigest, _>(
+        challenge_req.challenge,
+        challenge_req.difficulty,
+        vec![identity],
+    )
+    .await?;
+
+    Ok(Packet::request(
+        packet.session_id,
+        request::Session {
+            challenge_resp: Some(challenge_resp),
+            ..Default::default()
+        },
+    ))
+}
+
+/// Captured challenge response can be retransmitted while the session exists, but can't
+/// be replayed to establish it again.
+#[test_log::test(actix_rt::test)]
+async fn test_server_rejects_replayed_handshake() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    socket.connect(wrapper.server.bind_addr()).await?;
+
+    let captured = challenge_response(&socket).await?;
+    let session_id = SessionId::try_from(captured.session_id.clone())?;
+    let sessions = wrapper.server.sessions();
+
+    assert_eq!(
+        status(&exchange(&socket, &captured).await?)?,
+        StatusCode::Ok
+    );
+    assert_eq!(
+        status(&exchange(&socket, &captured).await?)?,
+        StatusCode::Ok
+    );
+    assert!(sessions.session(&session_id).is_some());
+
+    sessions.remove_session(&session_id);
+    assert_eq!(
+        status(&exchange(&socket, &captured).await?)?,
+        StatusCode::BadRequest
+    );
+    assert!(sessions.session(&session_id).is_none());
+
+    // Fresh challenge is still accepted from the same address.
+    let captured = challenge_response(&socket).await?;
+    assert_eq!(
+        status(&exchange(&socket, &captured).await?)?,
+        StatusCode::Ok
+    );
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_server_rejects_stale_handshake() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .handshake_window(Duration::from_secs(1))
+        .build()
+        .await?;
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    socket.connect(wrapper.server.bind_addr()).await?;
+
+    let captured = challenge_response(&socket).await?;
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    assert_eq!(
+        status(&exchange(&socket, &captured).await?)?,
+        StatusCode::BadRequest
+    );
+    let session_id = SessionId::try_from(captured.session_id)?;
+    assert!(wrapper.server.sessions().session(&session_id).is_none());
+    Ok(())
+}
+
+/// Forwards from sessions, which exchanged the forward key, are delivered only with a valid tag.
+#[test_log::test(actix_rt::test)]
+async fn test_server_drops_unauthenticated_forwards() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let receiver = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let received = spawn_receive_for_client(&receiver, "Receiver").await?;
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    socket.connect(wrapper.server.bind_addr()).await?;
+
+    let (session_id, key) = keyed_session(&socket).await?;
+
+    let response = exchange(
+        &socket,
+        &Packet::request(
+            session_id.to_vec(),
+            request::Node {
+                node_id: receiver.node_id().into_array().to_vec(),
+                public_key: false,
+            },
+        ),
+    )
+    .await?;
+    let slot = match response.kind {
+        Some(packet::Kind::Response(Response {
+            kind: Some(response::Kind::Node(node)),
+            ..
+        })) => node.slot,
+        _ => anyhow::bail!("Expected node response"),
+    };
+
+    let encode = |forward: Forward| {
+        let mut bytes = BytesMut::new();
+        forward.encode(&mut bytes);
+        bytes
+    };
+
+    let forward = Forward::unreliable(session_id.to_array(), slot, vec![1u8; 16]);
+    socket.send(&encode(forward)).await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!received.load(SeqCst));
+
+    let mut forward = Forward::unreliable(session_id.to_array(), slot, vec![1u8; 16]);
+    forward_auth::sign(&key, &mut forward);
+    socket.send(&encode(forward)).await?;
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while !received.load(SeqCst) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+/// Group forwards are authenticated with the forward key the same way as forwards.
+#[test_log::test(actix_rt::test)]
+async fn test_server_drops_unauthenticated_group_forwards() -> anyhow::Result<()> {
+    const GROUP: &str = "group";
+
+    let wrapper = init_test_server().await?;
+    let receiver = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    receiver.join_group(GROUP).await?;
+    let received = spawn_receive_for_client(&receiver, "Receiver").await?;
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    socket.connect(wrapper.server.bind_addr()).await?;
+    let (session_id, key) = keyed_session(&socket).await?;
+
+    let forward = control::ForwardToGroup {
+        group: GROUP.to_string(),
+        payload: vec![1u8; 16],
+        tag: vec![],
+    };
+    let packet = Packet::control(session_id.to_vec(), forward.clone());
+    socket.send(&packet.encode_to_vec()).await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!received.load(SeqCst));
+
+    let mut forged = forward.clone();
+    forward_auth::sign_group(&[7u8; 32], &session_id.to_array(), &mut forged);
+    let packet = Packet::control(session_id.to_vec(), forged);
+    socket.send(&packet.encode_to_vec()).await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!received.load(SeqCst));
+
+    let mut forward = forward;
+    forward_auth::sign_group(&key, &session_id.to_array(), &mut forward);
+    let packet = Packet::control(session_id.to_vec(), forward);
+    socket.send(&packet.encode_to_vec()).await?;
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while !received.load(SeqCst) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+/// Starts a session exchanging the forward key and registers it.
+async fn keyed_session(
+    socket: &tokio::net::UdpSocket,
+) -> anyhow::Result<(SessionId, forward_auth::ForwardKey)> {
+    let key_exchange = KeyExchange::generate();
+    let mut captured = challenge_response(socket).await?;
+    if let Some(packet::Kind::Request(request)) = &mut captured.kind {
+        if let Some(request::Kind::Session(session)) = &mut request.kind {
+            session.key_exchange = key_exchange.public_key().to_vec();
+        }
+    }
+    let response = exchange(socket, &captured).await?;
+    let server_key_exchange = match response.kind {
+        Some(packet::Kind::Response(Response {
+            kind: Some(response::Kind::Session(session)),
+            ..
+        })) => session.key_exchange,
+        _ => anyhow::bail!("Expected session response"),
+    };
+    let key = key_exchange.derive(&server_key_exchange, &captured.session_id)?;
+    let session_id = SessionId::try_from(captured.session_id.clone())?;
+    let register = Packet::request(session_id.to_vec(), request::Register::default());
+    assert_eq!(status(&exchange(socket, &register).await?)?, StatusCode::Ok);
+    Ok((session_id, key))
+}
+
+async fn challenge_difficulty(socket: &tokio::net::UdpSocket) -> anyhow::Result<u64> {
+    let packet = exchange(
+        socket,
+        &Packet::request(vec![], request::Session::default()),
+    )
+    .await?;
+    match packet.kind {
+        Some(packet::Kind::Response(Response {
+            kind:
+                Some(response::Kind::Session(response::Session {
+                    challenge_req: Some(challenge_req),
+                    ..
+                })),
+            ..
+        })) => Ok(challenge_req.difficulty),
+        _ => anyhow::bail!("Expected session response with a challenge"),
+    }
+}
+
+/// Each quota of sessions created from the same IP adds a bit of difficulty.
+#[test_log::test(actix_rt::test)]
+async fn test_server_scales_difficulty_per_ip() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new()
+        .difficulty(1)
+        .ip_session_quota(2, Duration::from_secs(60))
+        .build()
+        .await?;
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    socket.connect(wrapper.server.bind_addr()).await?;
+
+    for expected in [1, 1, 2, 2, 3] {
+        assert_eq!(challenge_difficulty(&socket).await?, expected);
+        let captured = challenge_response(&socket).await?;
+        assert_eq!(
+            status(&exchange(&socket, &captured).await?)?,
+            StatusCode::Ok
+        );
+    }
+
+    // Added difficulty can't be removed from the session id.
+    let mut captured = challenge_response(&socket).await?;
+    captured.session_id[4] = 0;
+    assert_eq!(
+        status(&exchange(&socket, &captured).await?)?,
+        StatusCode::BadRequest
+    );
+
+    // Other addresses aren't affected.
+    let other = tokio::net::UdpSocket::bind("127.0.0.2:0").await?;
+    other.connect(wrapper.server.bind_addr()).await?;
+    assert_eq!(challenge_difficulty(&other).await?, 1);
+    Ok(())
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_core::NodeId;
+use ya_relay_server::plugin::{ForwardInfo, Plugin, Verdict};
+use ya_relay_server::testing::server::{ServerWrapper, TestServerBuilder};
+use ya_relay_server::{Session, SessionEventKind};
+
+use common::hack_make_ip_private;
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Established(NodeId),
+    Forward(NodeId, NodeId),
+    Disconnect(NodeId, SessionEventKind),
+}
+
+/// Records all calls and drops forwards to `blocked`.
+struct Recorder {
+    events: Arc<Mutex<Vec<Event>>>,
+    blocked: Arc<Mutex<Option<NodeId>>>,
+}
+
+impl Plugin for Recorder {
+    fn on_session_established(&self, session: &Session) {
+        self.events.lock().push(Event::Established(session.node_id));
+    }
+
+    fn on_forward(&self, forward: &ForwardInfo) -> Verdict {
+        self.events
+            .lock()
+            .push(Event::Forward(forward.src_node_id, forward.dst_node_id));
+        match *self.blocked.lock() {
+            Some(blocked) if blocked == forward.dst_node_id => Verdict::Drop,
+            _ => Verdict::Pass,
+        }
+    }
+
+    fn on_disconnect(&self, session: &Session, kind: SessionEventKind) {
+        self.events
+            .lock()
+            .push(Event::Disconnect(session.node_id, kind));
+    }
+}
+
+async fn relayed_client(wrapper: &ServerWrapper) -> anyhow::Result<Client> {
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    hack_make_ip_private(wrapper, &client).await;
+    Ok(client)
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_server_plugin_hooks() -> anyhow::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let blocked = Arc::new(Mutex::new(None));
+    let wrapper = TestServerBuilder::new()
+        .plugin(Recorder {
+            events: events.clone(),
+            blocked: blocked.clone(),
+        })
+        .build()
+        .await?;
+
+    let client1 = relayed_client(&wrapper).await?;
+    let mut client2 = relayed_client(&wrapper).await?;
+    let client3 = relayed_client(&wrapper).await?;
+    *blocked.lock() = Some(client3.node_id());
+
+    for node_id in [client1.node_id(), client2.node_id(), client3.node_id()] {
+        assert!(events.lock().contains(&Event::Established(node_id)));
+    }
+
+    let mut rx2 = client2.forward_receiver().await.unwrap();
+    let mut rx3 = client3.forward_receiver().await.unwrap();
+    let mut tx2 = client1.forward_unreliable(client2.node_id()).await?;
+    let mut tx3 = client1.forward_unreliable(client3.node_id()).await?;
+    tx2.send(vec![1u8].into()).await?;
+    tx3.send(vec![1u8].into()).await?;
+
+    tokio::time::timeout(Duration::from_secs(5), rx2.recv())
+        .await?
+        .unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(500), rx3.recv())
+        .await
+        .is_err());
+    {
+        let events = events.lock();
+        assert!(events.contains(&Event::Forward(client1.node_id(), client2.node_id())));
+        assert!(events.contains(&Event::Forward(client1.node_id(), client3.node_id())));
+    }
+
+    let node_id = client2.node_id();
+    client2.shutdown().await?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(events
+        .lock()
+        .contains(&Event::Disconnect(node_id, SessionEventKind::Removed)));
+    Ok(())
+}
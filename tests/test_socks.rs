@@ -0,0 +1,248 @@
+mod common;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use ya_relay_client::channels::ForwardReceiver;
+use ya_relay_client::model::{NodeId, TransportType};
+use ya_relay_client::socks::{SocksConfig, SocksProxy, MAGIC};
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_proto::codec::forward::decode;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+async fn start_client(wrapper: &ServerWrapper) -> anyhow::Result<Client> {
+    ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await
+}
+
+fn config(expose: HashMap<u16, SocketAddr>) -> SocksConfig {
+    SocksConfig {
+        listen: ([127, 0, 0, 1], 0).into(),
+        expose,
+    }
+}
+
+async fn spawn_echo_server() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::task::spawn_local(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::task::spawn_local(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await.ok();
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Performs SOCKS5 handshake and returns the reply code.
+async fn socks_connect(stream: &mut TcpStream, host: &str, port: u16) -> anyhow::Result<u8> {
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    assert_eq!(method, [5, 0]);
+
+    let mut request = vec![5, 1, 0, 3, host.len() as u8];
+    request.extend(host.as_bytes());
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await?;
+    Ok(reply[1])
+}
+
+fn host(node_id: NodeId) -> String {
+    format!("{node_id}.ya")
+}
+
+const OPEN: u8 = 1;
+const ACCEPT: u8 = 2;
+const DATA: u8 = 3;
+const CLOSE: u8 = 4;
+
+/// Proxy frame sent by the Node, which opened the stream.
+fn frame(kind: u8, stream: u32, body: &[u8]) -> Vec<u8> {
+    let mut frame = MAGIC.to_vec();
+    frame.extend([kind, 1]);
+    frame.extend(stream.to_be_bytes());
+    frame.extend(body);
+    frame
+}
+
+/// Next proxy frame received over the transfer channel.
+async fn next_frame(rx: &mut ForwardReceiver, buf: &mut BytesMut) -> anyhow::Result<BytesMut> {
+    loop {
+        if let Ok(frame) = decode(buf) {
+            return Ok(frame);
+        }
+        let forwarded = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await?
+            .unwrap();
+        buf.extend_from_slice(forwarded.payload.as_ref());
+    }
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_socks_connect_to_exposed_port() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client = start_client(&wrapper).await?;
+    let server = start_client(&wrapper).await?;
+
+    let echo = spawn_echo_server().await?;
+    let (proxy, _rx) = SocksProxy::start(&client, config(Default::default())).await?;
+    let (_server_proxy, _server_rx) =
+        SocksProxy::start(&server, config([(80, echo)].into())).await?;
+
+    // Two connections multiplexed over the same channel.
+    let mut first = TcpStream::connect(proxy.local_addr()).await?;
+    let mut second = TcpStream::connect(proxy.local_addr()).await?;
+    assert_eq!(
+        socks_connect(&mut first, &host(server.node_id()), 80).await?,
+        0
+    );
+    assert_eq!(
+        socks_connect(&mut second, &host(server.node_id()), 80).await?,
+        0
+    );
+
+    second.write_all(b"second").await?;
+    first.write_all(b"first").await?;
+
+    let mut buf = [0u8; 6];
+    second.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"second");
+    let mut buf = [0u8; 5];
+    first.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"first");
+
+    // Closing writing side is propagated, so the echo server closes the connection.
+    first.shutdown().await?;
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), first.read_to_end(&mut rest)).await??;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_socks_rejected_requests() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client = start_client(&wrapper).await?;
+    let server = start_client(&wrapper).await?;
+
+    let unused = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let (proxy, _rx) = SocksProxy::start(&client, config(Default::default())).await?;
+    let (_server_proxy, _server_rx) =
+        SocksProxy::start(&server, config([(81, unused)].into())).await?;
+
+    let connect = |host: String, port: u16| {
+        let addr = proxy.local_addr();
+        async move {
+            let mut stream = TcpStream::connect(addr).await?;
+            socks_connect(&mut stream, &host, port).await
+        }
+    };
+
+    // Not allowed by ruleset.
+    assert_eq!(connect(host(server.node_id()), 80).await?, 2);
+    // Connection refused.
+    assert_eq!(connect(host(server.node_id()), 81).await?, 5);
+    // Host unreachable.
+    assert_eq!(connect("example.com".to_string(), 80).await?, 4);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_socks_passes_through_other_traffic() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client = start_client(&wrapper).await?;
+    let sender = start_client(&wrapper).await?;
+
+    let (_proxy, mut rx) = SocksProxy::start(&client, config(Default::default())).await?;
+
+    let mut tx = sender.forward_reliable(client.node_id()).await?;
+    tx.send(b"reliable".to_vec().into()).await?;
+    let mut tx = sender.forward_unreliable(client.node_id()).await?;
+    tx.send(b"unreliable".to_vec().into()).await?;
+
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        let forwarded = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await?
+            .unwrap();
+        received.push((forwarded.transport, forwarded.payload.into_vec()));
+    }
+    assert!(received.contains(&(TransportType::Reliable, b"reliable".to_vec())));
+    assert!(received.contains(&(TransportType::Unreliable, b"unreliable".to_vec())));
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_socks_transfer_above_window() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client = start_client(&wrapper).await?;
+    let server = start_client(&wrapper).await?;
+
+    let echo = spawn_echo_server().await?;
+    let (proxy, _rx) = SocksProxy::start(&client, config(Default::default())).await?;
+    let (_server_proxy, _server_rx) =
+        SocksProxy::start(&server, config([(80, echo)].into())).await?;
+
+    let mut stream = TcpStream::connect(proxy.local_addr()).await?;
+    assert_eq!(
+        socks_connect(&mut stream, &host(server.node_id()), 80).await?,
+        0
+    );
+
+    // Both proxies have to grant credit to pass it.
+    let data: Vec<u8> = (0..2 * 1024 * 1024).map(|i| i as u8).collect();
+    let (mut reader, mut writer) = stream.into_split();
+    let sending = {
+        let data = data.clone();
+        tokio::task::spawn_local(async move { writer.write_all(&data).await })
+    };
+
+    let mut received = vec![0u8; data.len()];
+    tokio::time::timeout(Duration::from_secs(30), reader.read_exact(&mut received)).await??;
+    assert!(received == data);
+    sending.await??;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_socks_closes_streams_of_misbehaving_node() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let server = start_client(&wrapper).await?;
+    let rogue = start_client(&wrapper).await?;
+
+    let echo = spawn_echo_server().await?;
+    let (_server_proxy, _server_rx) =
+        SocksProxy::start(&server, config([(80, echo)].into())).await?;
+
+    // Rogue Node speaks the proxy protocol without running the proxy.
+    let mut rx = rogue.forward_receiver().await.unwrap();
+    let mut buf = BytesMut::new();
+    let mut tx = rogue.forward_transfer(server.node_id()).await?.framed();
+
+    tx.send(frame(OPEN, 1, &80u16.to_be_bytes()).into()).await?;
+    assert_eq!(next_frame(&mut rx, &mut buf).await?[4], ACCEPT);
+
+    tx.send(frame(DATA, 1, b"echo").into()).await?;
+    let data = next_frame(&mut rx, &mut buf).await?;
+    assert_eq!((data[4], &data[10..]), (DATA, &b"echo"[..]));
+
+    // Larger than any frame sent by the proxy.
+    tx.send(frame(DATA, 1, &[0u8; 64 * 1024]).into()).await?;
+    let close = next_frame(&mut rx, &mut buf).await?;
+    assert_eq!((close[4], close[10]), (CLOSE, 1));
+    Ok(())
+}
@@ -0,0 +1,165 @@
+mod common;
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use ya_relay_client::stream::WINDOW;
+use ya_relay_client::{Client, ClientBuilder, FailFast};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+async fn start_client(wrapper: &ServerWrapper) -> anyhow::Result<Client> {
+    ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await
+}
+
+fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_send_stream() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let sender = start_client(&wrapper).await?;
+    let receiver = start_client(&wrapper).await?;
+    let mut incoming = receiver.incoming_streams().unwrap();
+    assert!(receiver.incoming_streams().is_none());
+
+    // Larger than the window, so the sender has to wait for credit.
+    let data = test_data(3 * WINDOW as usize + 1234);
+
+    let receiving = tokio::task::spawn_local(async move {
+        let mut stream = incoming.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        (stream.node_id(), received)
+    });
+
+    let sent = sender
+        .send_stream(receiver.node_id(), data.as_slice())
+        .await?;
+    assert_eq!(sent, data.len() as u64);
+
+    let (node_id, received) = tokio::time::timeout(Duration::from_secs(10), receiving).await??;
+    assert_eq!(node_id, sender.node_id());
+    assert_eq!(received.len(), data.len());
+    assert!(received == data);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_stream_reply() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client1 = start_client(&wrapper).await?;
+    let client2 = start_client(&wrapper).await?;
+    let mut incoming = client2.incoming_streams().unwrap();
+
+    tokio::task::spawn_local(async move {
+        let mut stream = incoming.accept().await.unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        stream
+            .write_all(&request.len().to_be_bytes())
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let mut stream = client1.open_stream(client2.node_id()).await?;
+    stream.write_all(&test_data(100_000)).await?;
+    stream.shutdown().await?;
+
+    let mut reply = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut reply)).await??;
+    assert_eq!(reply, 100_000usize.to_be_bytes());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_concurrent_streams() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client1 = start_client(&wrapper).await?;
+    let client2 = start_client(&wrapper).await?;
+    let mut incoming = client2.incoming_streams().unwrap();
+
+    // Replies with the length of each request, using `futures` IO traits.
+    tokio::task::spawn_local(async move {
+        while let Some(mut stream) = incoming.accept().await {
+            tokio::task::spawn_local(async move {
+                let mut request = Vec::new();
+                futures::AsyncReadExt::read_to_end(&mut stream, &mut request)
+                    .await
+                    .unwrap();
+                futures::AsyncWriteExt::write_all(&mut stream, &request.len().to_be_bytes())
+                    .await
+                    .unwrap();
+                futures::AsyncWriteExt::close(&mut stream).await.unwrap();
+            });
+        }
+    });
+
+    let mut first = client1.open_stream(client2.node_id()).await?;
+    let mut second = client1.open_stream(client2.node_id()).await?;
+    futures::AsyncWriteExt::write_all(&mut second, &test_data(200_000)).await?;
+    futures::AsyncWriteExt::write_all(&mut first, &test_data(100_000)).await?;
+    futures::AsyncWriteExt::close(&mut first).await?;
+    futures::AsyncWriteExt::close(&mut second).await?;
+
+    for (stream, len) in [(&mut first, 100_000usize), (&mut second, 200_000)] {
+        let mut reply = Vec::new();
+        let reading = futures::AsyncReadExt::read_to_end(stream, &mut reply);
+        tokio::time::timeout(Duration::from_secs(5), reading).await??;
+        assert_eq!(reply, len.to_be_bytes());
+    }
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_dropped_stream_resets_writer() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client1 = start_client(&wrapper).await?;
+    let client2 = start_client(&wrapper).await?;
+    let mut incoming = client2.incoming_streams().unwrap();
+
+    let mut stream = client1.open_stream(client2.node_id()).await?;
+    let accepted = tokio::time::timeout(Duration::from_secs(5), incoming.accept()).await?;
+    drop(accepted);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let result = stream.write_all(&test_data(2 * WINDOW as usize)).await;
+    assert_eq!(
+        result.unwrap_err().kind(),
+        std::io::ErrorKind::ConnectionReset
+    );
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_stream_stops_without_reader() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client1 = start_client(&wrapper).await?;
+    let client2 = start_client(&wrapper).await?;
+    // Listening, but never reading from the accepted stream.
+    let mut incoming = client2.incoming_streams().unwrap();
+    tokio::task::spawn_local(async move {
+        let _stream = incoming.accept().await;
+        tokio::time::sleep(Duration::from_secs(60)).await;
+    });
+
+    let mut stream = client1.open_stream(client2.node_id()).await?;
+    let chunk = test_data(stream.chunk_size());
+    let mut written = 0;
+    loop {
+        match tokio::time::timeout(Duration::from_secs(2), stream.write(&chunk)).await {
+            Ok(result) => written += result?,
+            Err(_) => break,
+        }
+    }
+    assert_eq!(written as u64, WINDOW);
+    Ok(())
+}
@@ -18,3 +18,45 @@ async fn test_virt_node_creation_assumption() {
         assert_eq!(virt.channels[i].channel.index(), i);
     }
 }
+
+/// Nodes with colliding virtual addresses get distinct ones, which stay the same
+/// when the Node is added again.
+#[actix_rt::test]
+async fn test_virt_node_address_collision() {
+    let server = init_test_server().await.unwrap();
+    let mut network = MockSessionNetwork::new(server).unwrap();
+    let layer = network.new_layer().await.unwrap().layer;
+    let registry = TcpRegistry::new(layer);
+
+    // Multicast first byte is replaced with zero.
+    let mut bytes = [0u8; 20];
+    bytes[0] = 0xff;
+    bytes[19] = 1;
+    let first = NodeId::from(bytes);
+    bytes[0] = 0x00;
+    bytes[19] = 2;
+    let second = NodeId::from(bytes);
+    assert_eq!(to_ipv6(first), to_ipv6(second));
+
+    let first_node = registry.add_virt_node(first).await;
+    let second_node = registry.add_virt_node(second).await;
+    assert_eq!(first_node.translated(), None);
+    assert_eq!(second_node.translated(), Some(fallback_ipv6(second, 0)));
+    assert_ne!(first_node.address, second_node.address);
+
+    let first_address = first_node.address.as_bytes().to_vec();
+    let second_address = second_node.address.as_bytes().to_vec();
+    let by_address = registry.get_by_address(&second_address).await.unwrap();
+    assert_eq!(by_address.id(), second);
+    assert_eq!(
+        registry.get_by_address(&first_address).await.unwrap().id(),
+        first
+    );
+
+    let again = registry.add_virt_node(second).await;
+    assert_eq!(again.address, second_node.address);
+    assert_eq!(
+        registry.resolve_ip(second).await.as_ref(),
+        second_address.as_slice()
+    );
+}
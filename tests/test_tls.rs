@@ -0,0 +1,83 @@
+mod common;
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use ya_relay_client::tls::TlsConfig;
+use ya_relay_client::{Client, ClientBuilder, FailFast};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+async fn start_client(wrapper: &ServerWrapper) -> anyhow::Result<Client> {
+    ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_tls_stream() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client1 = start_client(&wrapper).await?;
+    let client2 = start_client(&wrapper).await?;
+    let tls1 = TlsConfig::new(&client1).await?;
+    let tls2 = TlsConfig::new(&client2).await?;
+    let mut incoming = client2.incoming_streams().unwrap();
+
+    let serving = tokio::task::spawn_local(async move {
+        let stream = incoming.accept().await.unwrap();
+        let node_id = stream.node_id();
+        let mut stream = tls2.accept(stream, node_id).await?;
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await?;
+        stream.write_all(&request.len().to_be_bytes()).await?;
+        stream.shutdown().await?;
+        Ok::<_, std::io::Error>((stream.peer_id(), request))
+    });
+
+    let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    let stream = client1.open_stream(client2.node_id()).await?;
+    let mut stream = tls1.connect(stream, client2.node_id()).await?;
+    assert_eq!(stream.peer_id(), client2.node_id());
+    stream.write_all(&data).await?;
+    stream.shutdown().await?;
+
+    let mut reply = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut reply)).await??;
+    assert_eq!(reply, data.len().to_be_bytes());
+
+    let (peer_id, request) = serving.await??;
+    assert_eq!(peer_id, client1.node_id());
+    assert!(request == data);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_tls_rejects_other_node() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client1 = start_client(&wrapper).await?;
+    let client2 = start_client(&wrapper).await?;
+    let client3 = start_client(&wrapper).await?;
+    let tls1 = TlsConfig::new(&client1).await?;
+    let tls2 = TlsConfig::new(&client2).await?;
+    let mut incoming = client2.incoming_streams().unwrap();
+
+    let serving = tokio::task::spawn_local(async move {
+        let stream = incoming.accept().await.unwrap();
+        let node_id = stream.node_id();
+        tls2.accept(stream, node_id).await.map(|_| ())
+    });
+
+    // Connecting to `client2`, but expecting `client3` on the other end.
+    let stream = client1.open_stream(client2.node_id()).await?;
+    let result = tokio::time::timeout(
+        Duration::from_secs(10),
+        tls1.connect(stream, client3.node_id()),
+    )
+    .await?;
+    assert!(result.is_err());
+    assert!(tokio::time::timeout(Duration::from_secs(10), serving)
+        .await??
+        .is_err());
+    Ok(())
+}
@@ -0,0 +1,121 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::net::{Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use ya_relay_client::model::TransportType;
+use ya_relay_client::tun::{TunBridge, TunConfig};
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::init_test_server;
+
+const UDP: u8 = 17;
+
+/// Parses IPv6 packet carrying UDP datagram into (src, dst, payload).
+fn parse_udp(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    if packet[0] >> 4 != 6 || packet[6] != UDP {
+        return None;
+    }
+    let ip = |range: std::ops::Range<usize>| {
+        Ipv6Addr::from(<[u8; 16]>::try_from(&packet[range]).unwrap())
+    };
+    let port = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+    Some((
+        (ip(8..24), port(40)).into(),
+        (ip(24..40), port(42)).into(),
+        &packet[48..],
+    ))
+}
+
+fn build_udp(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let (SocketAddr::V6(src), SocketAddr::V6(dst)) = (src, dst) else {
+        panic!("expected IPv6 addresses")
+    };
+    let udp_len = (8 + payload.len()) as u16;
+
+    let mut udp = Vec::new();
+    udp.extend(src.port().to_be_bytes());
+    udp.extend(dst.port().to_be_bytes());
+    udp.extend(udp_len.to_be_bytes());
+    udp.extend([0, 0]);
+    udp.extend(payload);
+
+    // Checksum over pseudo-header and datagram, mandatory for IPv6.
+    let mut pseudo = Vec::new();
+    pseudo.extend(src.ip().octets());
+    pseudo.extend(dst.ip().octets());
+    pseudo.extend((udp_len as u32).to_be_bytes());
+    pseudo.extend([0, 0, 0, UDP]);
+    pseudo.extend(&udp);
+    if pseudo.len() % 2 == 1 {
+        pseudo.push(0);
+    }
+    let mut sum = pseudo
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    udp[6..8].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+
+    let mut packet = vec![0x60, 0, 0, 0];
+    packet.extend(udp_len.to_be_bytes());
+    packet.extend([UDP, 64]);
+    packet.extend(src.ip().octets());
+    packet.extend(dst.ip().octets());
+    packet.extend(udp);
+    packet
+}
+
+#[test_log::test(actix_rt::test)]
+#[ignore = "requires CAP_NET_ADMIN"]
+async fn test_tun_udp_round_trip() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let peer = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let (bridge, mut passthrough) = TunBridge::start(&client, TunConfig::default()).await?;
+    let peer_ip = bridge.add_peer(peer.node_id())?;
+    let mut peer_rx = peer.forward_receiver().await.unwrap();
+
+    let socket = tokio::net::UdpSocket::bind((bridge.address(), 0)).await?;
+    socket.send_to(b"ping", (peer_ip, 4000)).await?;
+
+    // Kernel routes the datagram through the interface to the peer Node.
+    let forwarded = tokio::time::timeout(Duration::from_secs(5), peer_rx.recv())
+        .await?
+        .unwrap();
+    assert_eq!(forwarded.node_id, client.node_id());
+    assert_eq!(forwarded.transport, TransportType::Unreliable);
+    let (src, dst, payload) = parse_udp(forwarded.payload.as_ref()).unwrap();
+    assert_eq!(src, socket.local_addr()?);
+    assert_eq!(dst, (peer_ip, 4000).into());
+    assert_eq!(payload, b"ping");
+
+    let mut tx = peer.forward_unreliable(client.node_id()).await?;
+    tx.send(build_udp(dst, src, b"pong").into()).await?;
+
+    let mut buf = [0u8; 16];
+    let (len, from) =
+        tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf)).await??;
+    assert_eq!(&buf[..len], b"pong");
+    assert_eq!(from, dst);
+
+    // Packets, which aren't IPv6 frames, are passed through.
+    tx.send(b"not an ip packet".to_vec().into()).await?;
+    let forwarded = tokio::time::timeout(Duration::from_secs(5), passthrough.recv())
+        .await?
+        .unwrap();
+    assert_eq!(forwarded.payload.as_ref(), b"not an ip packet");
+    assert_eq!(forwarded.node_id, peer.node_id());
+    Ok(())
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use anyhow::Context;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::TestServerBuilder;
+
+use common::spawn_receive;
+
+#[test_log::test(actix_rt::test)]
+async fn test_forward_over_websocket() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new().websocket().build().await?;
+    let ws_addr = wrapper.server.ws_addr().context("WebSocket disabled")?;
+
+    let ws_client = ClientBuilder::from_url(wrapper.url())
+        .websocket(format!("ws://{ws_addr}").parse()?)
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let udp_client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    // Datagrams from the IP checker don't pass the WebSocket bridge.
+    assert_eq!(ws_client.public_addr().await, None);
+
+    let rx_ws = ws_client
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let rx_udp = udp_client
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+
+    let received_ws = Rc::new(AtomicBool::new(false));
+    let received_udp = Rc::new(AtomicBool::new(false));
+    spawn_receive(">> ws", received_ws.clone(), rx_ws);
+    spawn_receive(">> udp", received_udp.clone(), rx_udp);
+
+    let mut tx_ws = ws_client.forward_unreliable(udp_client.node_id()).await?;
+    let mut tx_udp = udp_client.forward_unreliable(ws_client.node_id()).await?;
+
+    tx_ws.send(vec![1u8].into()).await?;
+    tx_udp.send(vec![2u8].into()).await?;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(received_ws.load(SeqCst));
+    assert!(received_udp.load(SeqCst));
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_websocket_unreachable() -> anyhow::Result<()> {
+    let wrapper = TestServerBuilder::new().build().await?;
+    let addr = wrapper.server.bind_addr();
+
+    // Nothing listens on TCP at the UDP port of the relay.
+    let result = ClientBuilder::from_url(wrapper.url())
+        .websocket(format!("ws://{addr}").parse()?)
+        .connect(FailFast::Yes)
+        .build()
+        .await;
+
+    assert!(result.is_err());
+    Ok(())
+}